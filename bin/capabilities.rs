@@ -0,0 +1,116 @@
+//! Machine-readable capabilities of binaries
+//!
+//! `--capabilities` prints supported ciphers, transports, local protocols and compile-time features in JSON,
+//! so that orchestration tools could check what a deployed binary supports before pushing configurations.
+
+use std::env;
+
+use serde::Serialize;
+use shadowsocks::crypto::v1::available_ciphers;
+
+/// Protocols for communicating with clients of `sslocal`
+pub const AVAILABLE_PROTOCOLS: &[&str] = &[
+    "socks5",
+    #[cfg(feature = "local-socks4")]
+    "socks4",
+    #[cfg(feature = "local-http")]
+    "http",
+    #[cfg(all(
+        feature = "local-http",
+        any(feature = "local-http-native-tls", feature = "local-http-rustls")
+    ))]
+    "https",
+    #[cfg(feature = "local-http")]
+    "mixed",
+    #[cfg(feature = "local-tunnel")]
+    "tunnel",
+    #[cfg(feature = "local-redir")]
+    "redir",
+    #[cfg(feature = "local-tun")]
+    "tun",
+    #[cfg(feature = "local-dns")]
+    "dns",
+];
+
+/// Transports between local and server, and of manager
+const AVAILABLE_TRANSPORTS: &[&str] = &[
+    "tcp",
+    "udp",
+    "plugin",
+    #[cfg(unix)]
+    "manager-unix",
+    #[cfg(windows)]
+    "manager-named-pipe",
+];
+
+/// Compile-time features enabled
+const ENABLED_FEATURES: &[&str] = &[
+    #[cfg(feature = "single-threaded")]
+    "single-threaded",
+    #[cfg(feature = "trust-dns")]
+    "trust-dns",
+    #[cfg(feature = "dns-over-tls")]
+    "dns-over-tls",
+    #[cfg(feature = "dns-over-https")]
+    "dns-over-https",
+    #[cfg(feature = "local-dns")]
+    "local-dns",
+    #[cfg(feature = "local-flow-stat")]
+    "local-flow-stat",
+    #[cfg(feature = "local-http")]
+    "local-http",
+    #[cfg(feature = "local-http-native-tls")]
+    "local-http-native-tls",
+    #[cfg(feature = "local-http-rustls")]
+    "local-http-rustls",
+    #[cfg(feature = "local-online-config")]
+    "local-online-config",
+    #[cfg(feature = "local-redir")]
+    "local-redir",
+    #[cfg(feature = "local-tun")]
+    "local-tun",
+    #[cfg(feature = "local-tunnel")]
+    "local-tunnel",
+    #[cfg(feature = "local-socks4")]
+    "local-socks4",
+    #[cfg(feature = "manager-http")]
+    "manager-http",
+    #[cfg(feature = "manager-tls")]
+    "manager-tls",
+    #[cfg(feature = "jemalloc")]
+    "jemalloc",
+    #[cfg(feature = "tcmalloc-vendored")]
+    "tcmalloc-vendored",
+];
+
+#[derive(Serialize)]
+struct Capabilities {
+    version: &'static str,
+    ciphers: &'static [&'static str],
+    transports: &'static [&'static str],
+    local_protocols: &'static [&'static str],
+    features: &'static [&'static str],
+}
+
+/// Check if `--capabilities` is in command line arguments
+///
+/// Checked before parsing, because the other required arguments are not needed, just like `--version`
+pub fn requested() -> bool {
+    env::args_os().skip(1).any(|arg| arg == "--capabilities")
+}
+
+/// Print capabilities in JSON to stdout, without any logs
+pub fn print() {
+    let capabilities = Capabilities {
+        version: super::version::VERSION,
+        ciphers: available_ciphers(),
+        transports: AVAILABLE_TRANSPORTS,
+        local_protocols: AVAILABLE_PROTOCOLS,
+        features: ENABLED_FEATURES,
+    };
+
+    match serde_json::to_string_pretty(&capabilities) {
+        Ok(json) => println!("{}", json),
+        Err(err) => eprintln!("failed to serialize capabilities, error: {}", err),
+    }
+}
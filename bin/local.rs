@@ -4,15 +4,17 @@
 //! or you could specify a configuration file. The format of configuration file is defined
 //! in mod `config`.
 
-use std::time::Duration;
+use std::{path::PathBuf, time::Duration};
 
 use clap::{clap_app, Arg};
 use futures::future::{self, Either};
 use log::info;
 use tokio::{self, runtime::Builder};
 
+#[cfg(feature = "local-online-config")]
+use shadowsocks::config::OnlineAclConfig;
 #[cfg(feature = "local-redir")]
-use shadowsocks::config::RedirType;
+use shadowsocks::config::{RedirDnsPolicy, RedirType};
 #[cfg(any(feature = "local-dns", feature = "local-tunnel"))]
 use shadowsocks::relay::socks5::Address;
 use shadowsocks::{
@@ -28,6 +30,7 @@ use shadowsocks::{
 };
 
 mod allocator;
+mod capabilities;
 #[cfg(unix)]
 mod daemonize;
 mod logging;
@@ -35,30 +38,12 @@ mod monitor;
 mod validator;
 mod version;
 
-const AVAILABLE_PROTOCOLS: &[&str] = &[
-    "socks5",
-    #[cfg(feature = "local-socks4")]
-    "socks4",
-    #[cfg(feature = "local-http")]
-    "http",
-    #[cfg(all(
-        feature = "local-http",
-        any(feature = "local-http-native-tls", feature = "local-http-rustls")
-    ))]
-    "https",
-    #[cfg(feature = "local-tunnel")]
-    "tunnel",
-    #[cfg(feature = "local-redir")]
-    "redir",
-    #[cfg(feature = "local-dns")]
-    "dns",
-];
-
 fn main() {
     let mut app = clap_app!(shadowsocks =>
         (version: self::version::VERSION)
         (about: "A fast tunnel proxy that helps you bypass firewalls.")
         (@arg VERBOSE: -v ... "Set the level of debug")
+        (@arg CAPABILITIES: --capabilities "Print supported ciphers, transports, protocols and features in JSON, then exit")
         (@arg UDP_ONLY: -u conflicts_with[TCP_AND_UDP] "Server mode UDP_ONLY")
         (@arg TCP_AND_UDP: -U "Server mode TCP_AND_UDP")
 
@@ -79,14 +64,18 @@ fn main() {
         (@group SERVER_CONFIG =>
             (@attributes +multiple arg[SERVER_ADDR URL]))
 
-        (@arg PROTOCOL: --protocol +takes_value default_value("socks5") possible_values(AVAILABLE_PROTOCOLS) +next_line_help "Protocol that for communicating with clients")
+        (@arg PROTOCOL: --protocol +takes_value default_value("socks5") possible_values(capabilities::AVAILABLE_PROTOCOLS) +next_line_help "Protocol that for communicating with clients")
 
         (@arg NO_DELAY: --("no-delay") !takes_value "Set TCP_NODELAY option for socket")
         (@arg NOFILE: -n --nofile +takes_value "Set RLIMIT_NOFILE with both soft and hard limit (only for *nix systems)")
-        (@arg ACL: --acl +takes_value "Path to ACL (Access Control List)")
+        (@arg BYPASS_LAN: --("bypass-lan") "Connect to loopback, private and link-local IP addresses directly")
+        (@arg ACL: --acl +takes_value "Path to ACL (Access Control List), or an HTTPS URL for fetching it periodically")
+        (@arg GEOIP_DATABASE: --("geoip-database") +takes_value "Path to MaxMind GeoIP database (mmdb) for `geoip:` rules in ACL")
+        (@arg GEOSITE_DATABASE: --("geosite-database") +takes_value "Path to v2ray's geosite.dat for `geosite:` rules in ACL")
 
         (@arg LOG_WITHOUT_TIME: --("log-without-time") "Log without datetime prefix")
         (@arg LOG_CONFIG: --("log-config") +takes_value "log4rs configuration file")
+        (@arg NO_BANNER: --("no-banner") "Don't log the version banner on startup")
 
         (@arg UDP_TIMEOUT: --("udp-timeout") +takes_value {validator::validate_u64} "Timeout seconds for UDP relay")
         (@arg UDP_MAX_ASSOCIATIONS: --("udp-max-associations") +takes_value {validator::validate_u64} "Maximum associations to be kept simultaneously for UDP relay")
@@ -104,7 +93,7 @@ fn main() {
     #[cfg(feature = "local-tunnel")]
     {
         app = clap_app!(@app (app)
-            (@arg FORWARD_ADDR: -f --("forward-addr") +takes_value {validator::validate_address} required_if("PROTOCOL", "tunnel") "Forwarding data directly to this address (for tunnel)")
+            (@arg FORWARD_ADDR: -f --("forward-addr") +takes_value {validator::validate_address} "Forwarding data directly to this address (for tunnel)")
         );
     }
 
@@ -130,6 +119,18 @@ fn main() {
                 (@arg UDP_REDIR: --("udp-redir") +takes_value possible_values(&available_redir_types) default_value(RedirType::udp_default().name()) "UDP redir (transparent proxy) type")
             );
         }
+
+        app = clap_app!(@app (app)
+            (@arg REDIR_DNS_POLICY: --("redir-dns-policy") +takes_value possible_values(RedirDnsPolicy::available_policies()) "Policy of DNS traffic (port 53 and 853) captured by redir, follows ACL rules by default")
+        );
+    }
+
+    #[cfg(feature = "local-tun")]
+    {
+        app = clap_app!(@app (app)
+            (@arg TUN_INTERFACE_NAME: --("tun-interface-name") +takes_value "Name of the TUN interface (for tun)")
+            (@arg TUN_INTERFACE_ADDRESS: --("tun-interface-address") +takes_value "Bring the TUN interface up with this IPv4 address and prefix, like 10.255.0.1/24 (for tun)")
+        );
     }
 
     #[cfg(target_os = "android")]
@@ -179,6 +180,11 @@ fn main() {
         );
     }
 
+    if capabilities::requested() {
+        capabilities::print();
+        return;
+    }
+
     let matches = app.get_matches();
     // drop(available_ciphers);
 
@@ -202,10 +208,14 @@ fn main() {
             any(feature = "local-http-native-tls", feature = "local-http-rustls")
         ))]
         Some("https") => ConfigType::HttpsLocal,
+        #[cfg(feature = "local-http")]
+        Some("mixed") => ConfigType::MixedLocal,
         #[cfg(feature = "local-tunnel")]
         Some("tunnel") => ConfigType::TunnelLocal,
         #[cfg(feature = "local-redir")]
         Some("redir") => ConfigType::RedirLocal,
+        #[cfg(feature = "local-tun")]
+        Some("tun") => ConfigType::TunLocal,
         #[cfg(feature = "local-dns")]
         Some("dns") => ConfigType::DnsLocal,
         Some(p) => panic!("not supported `protocol` \"{}\"", p),
@@ -323,8 +333,28 @@ fn main() {
         config.nofile = Some(nofile.parse::<u64>().expect("an unsigned integer for `nofile`"));
     }
 
-    if let Some(acl_file) = matches.value_of("ACL") {
-        let acl = match AccessControl::load_from_file(acl_file) {
+    if let Some(geoip_database) = matches.value_of("GEOIP_DATABASE") {
+        config.geoip_database = Some(PathBuf::from(geoip_database));
+    }
+
+    if let Some(geosite_database) = matches.value_of("GEOSITE_DATABASE") {
+        config.geosite_database = Some(PathBuf::from(geosite_database));
+    }
+
+    #[cfg(feature = "local-online-config")]
+    let acl_file = match matches.value_of("ACL") {
+        Some(acl_url) if acl_url.starts_with("https://") => {
+            let acl_url = acl_url.parse().expect("acl url");
+            config.online_acl = Some(OnlineAclConfig::new(acl_url));
+            None
+        }
+        acl_file => acl_file,
+    };
+    #[cfg(not(feature = "local-online-config"))]
+    let acl_file = matches.value_of("ACL");
+
+    if let Some(acl_file) = acl_file {
+        let acl = match AccessControl::load_from_file_with_databases(acl_file, &config.acl_databases()) {
             Ok(acl) => acl,
             Err(err) => {
                 panic!("loading ACL \"{}\", {}", acl_file, err);
@@ -333,6 +363,10 @@ fn main() {
         config.acl = Some(acl);
     }
 
+    if matches.is_present("BYPASS_LAN") {
+        config.bypass_lan = true;
+    }
+
     if matches.is_present("IPV6_FIRST") {
         config.ipv6_first = true;
     }
@@ -352,6 +386,21 @@ fn main() {
         if let Some(udp_redir) = matches.value_of("UDP_REDIR") {
             config.udp_redir = udp_redir.parse::<RedirType>().expect("UDP redir type");
         }
+
+        if let Some(dns_policy) = matches.value_of("REDIR_DNS_POLICY") {
+            config.redir_dns_policy = dns_policy.parse::<RedirDnsPolicy>().expect("redir DNS policy");
+        }
+    }
+
+    #[cfg(feature = "local-tun")]
+    {
+        if let Some(name) = matches.value_of("TUN_INTERFACE_NAME") {
+            config.tun_interface_name = Some(name.to_owned());
+        }
+
+        if let Some(addr) = matches.value_of("TUN_INTERFACE_ADDRESS") {
+            config.tun_interface_address = Some(addr.parse().expect("tun-interface-address"));
+        }
     }
 
     #[cfg(feature = "local-http-native-tls")]
@@ -421,7 +470,9 @@ fn main() {
         daemonize::daemonize(matches.value_of("DAEMONIZE_PID_PATH"));
     }
 
-    info!("shadowsocks {}", self::version::VERSION);
+    if !matches.is_present("NO_BANNER") {
+        info!("shadowsocks {}", self::version::VERSION);
+    }
 
     let mut builder = if cfg!(feature = "single-threaded") {
         Builder::new_current_thread()
@@ -6,13 +6,24 @@ use log4rs::{
     append::console::{ConsoleAppender, Target},
     config::{Appender, Config, Logger, Root},
     encode::pattern::PatternEncoder,
+    file::Deserializers,
 };
 
+#[cfg(unix)]
+mod syslog;
+
 pub fn init_with_file<P>(path: P)
 where
     P: AsRef<Path>,
 {
-    log4rs::init_file(path, Default::default()).expect("init logging with file");
+    #[allow(unused_mut)]
+    let mut deserializers = Deserializers::default();
+
+    // Access logs of tenants could be sent to syslog with different facilities
+    #[cfg(unix)]
+    deserializers.insert("syslog", syslog::SyslogAppenderDeserializer);
+
+    log4rs::init_file(path, deserializers).expect("init logging with file");
 }
 
 pub fn init_with_config(bin_name: &str, matches: &ArgMatches) {
@@ -0,0 +1,144 @@
+//! log4rs appender sending records to the local syslog daemon
+//!
+//! Available as `kind: syslog` in log4rs configuration files, with options
+//!
+//! - `facility`: Syslog facility, like `daemon`, `local0` ... `local7`, `user` by default
+//! - `path`: Path to the syslog daemon's socket, `/dev/log` by default
+//! - `ident`: Tag of records, name of the current executable by default
+
+use std::{env, error::Error, fmt, os::unix::net::UnixDatagram, path::PathBuf, process};
+
+use log::{Level, Record};
+use log4rs::{
+    append::Append,
+    file::{Deserialize, Deserializers},
+};
+
+const DEFAULT_SYSLOG_PATH: &str = "/dev/log";
+
+/// Parse facility name into its code (RFC 5424)
+fn parse_facility(name: &str) -> Option<u8> {
+    let code = match name {
+        "kern" => 0,
+        "user" => 1,
+        "mail" => 2,
+        "daemon" => 3,
+        "auth" => 4,
+        "syslog" => 5,
+        "lpr" => 6,
+        "news" => 7,
+        "uucp" => 8,
+        "cron" => 9,
+        "authpriv" => 10,
+        "ftp" => 11,
+        "local0" => 16,
+        "local1" => 17,
+        "local2" => 18,
+        "local3" => 19,
+        "local4" => 20,
+        "local5" => 21,
+        "local6" => 22,
+        "local7" => 23,
+        _ => return None,
+    };
+    Some(code)
+}
+
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Appender writing records to a UNIX datagram socket of the syslog daemon
+pub struct SyslogAppender {
+    socket: UnixDatagram,
+    path: PathBuf,
+    facility: u8,
+    ident: String,
+}
+
+impl fmt::Debug for SyslogAppender {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SyslogAppender")
+            .field("path", &self.path)
+            .field("facility", &self.facility)
+            .field("ident", &self.ident)
+            .finish()
+    }
+}
+
+impl Append for SyslogAppender {
+    fn append(&self, record: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let message = format!(
+            "<{}>{}[{}]: {}",
+            self.facility * 8 + severity(record.level()),
+            self.ident,
+            process::id(),
+            record.args()
+        );
+
+        if self.socket.send(message.as_bytes()).is_err() {
+            // Syslog daemon may have been restarted
+            self.socket.connect(&self.path)?;
+            self.socket.send(message.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+/// Configuration of `SyslogAppender`
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyslogAppenderConfig {
+    facility: Option<String>,
+    path: Option<PathBuf>,
+    ident: Option<String>,
+}
+
+/// Deserializer for `SyslogAppender`
+pub struct SyslogAppenderDeserializer;
+
+impl Deserialize for SyslogAppenderDeserializer {
+    type Config = SyslogAppenderConfig;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: SyslogAppenderConfig,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        let facility = match config.facility {
+            Some(name) => match parse_facility(&name) {
+                Some(f) => f,
+                None => return Err(format!("unknown syslog facility \"{}\"", name).into()),
+            },
+            None => 1,
+        };
+
+        let ident = match config.ident {
+            Some(ident) => ident,
+            None => env::current_exe()
+                .ok()
+                .and_then(|p| p.file_name().map(|n| n.to_string_lossy().into_owned()))
+                .unwrap_or_else(|| "shadowsocks".to_owned()),
+        };
+
+        let path = config.path.unwrap_or_else(|| PathBuf::from(DEFAULT_SYSLOG_PATH));
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(&path)?;
+
+        Ok(Box::new(SyslogAppender {
+            socket,
+            path,
+            facility,
+            ident,
+        }))
+    }
+}
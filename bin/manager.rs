@@ -9,6 +9,7 @@
 
 use std::{
     net::{IpAddr, SocketAddr},
+    path::PathBuf,
     time::Duration,
 };
 
@@ -31,6 +32,7 @@ use shadowsocks::{
 };
 
 mod allocator;
+mod capabilities;
 #[cfg(unix)]
 mod daemonize;
 mod logging;
@@ -44,6 +46,7 @@ fn main() {
         (version: self::version::VERSION)
         (about: "A fast tunnel proxy that helps you bypass firewalls.")
         (@arg VERBOSE: -v ... "Set the level of debug")
+        (@arg CAPABILITIES: --capabilities "Print supported ciphers, transports, protocols and features in JSON, then exit")
         (@arg UDP_ONLY: -u conflicts_with[TCP_AND_UDP] "Server mode UDP_ONLY")
         (@arg TCP_AND_UDP: -U conflicts_with[UDP_ONLY] "Server mode TCP_AND_UDP")
 
@@ -60,12 +63,20 @@ fn main() {
         (@arg MANAGER_ADDRESS: --("manager-address") +takes_value {validator::validate_manager_addr} "ShadowSocks Manager (ssmgr) address, could be ip:port, domain:port or /path/to/unix.sock")
         (@arg ENCRYPT_METHOD: -m --("encrypt-method") +takes_value possible_values(available_ciphers()) +next_line_help "Default encryption method")
         (@arg TIMEOUT: --timeout +takes_value {validator::validate_u64} "Default timeout seconds for TCP relay")
+        (@arg MANAGER_SECRET: --("manager-secret") +takes_value "Shared secret for signing manager commands, unsigned commands are rejected")
+        (@arg MANAGER_EVENTS_ADDRESS: --("manager-events-address") +takes_value {validator::validate_socket_addr} "Push manager events to TCP clients connected to this address")
+        (@arg MANAGER_FEDERATION_ADDRESS: --("manager-federation-address") +takes_value {validator::validate_socket_addr} "Accept other managers (agents) connecting to this address")
+        (@arg MANAGER_UPSTREAM: --("manager-upstream") +takes_value "Connect to the central manager on this address (host:port) as an agent")
+        (@arg MANAGER_NODE_NAME: --("manager-node-name") +takes_value "Name of this manager, registered to the central manager")
 
         (@arg NOFILE: -n --nofile +takes_value "Set RLIMIT_NOFILE with both soft and hard limit (only for *nix systems)")
         (@arg ACL: --acl +takes_value "Path to ACL (Access Control List)")
+        (@arg GEOIP_DATABASE: --("geoip-database") +takes_value "Path to MaxMind GeoIP database (mmdb) for `geoip:` rules in ACL")
+        (@arg GEOSITE_DATABASE: --("geosite-database") +takes_value "Path to v2ray's geosite.dat for `geosite:` rules in ACL")
 
         (@arg LOG_WITHOUT_TIME: --("log-without-time") "Log without datetime prefix")
         (@arg LOG_CONFIG: --("log-config") +takes_value "log4rs configuration file")
+        (@arg NO_BANNER: --("no-banner") "Don't log the version banner on startup")
     );
 
     #[cfg(unix)]
@@ -76,6 +87,23 @@ fn main() {
         );
     }
 
+    #[cfg(feature = "manager-http")]
+    {
+        app = clap_app!(@app (app)
+            (@arg MANAGER_HTTP_ADDRESS: --("manager-http-address") +takes_value {validator::validate_socket_addr} "Serve manager commands as HTTP REST API on this address")
+            (@arg MANAGER_HTTP_TOKEN: --("manager-http-token") +takes_value "Bearer token required by the manager HTTP REST API")
+        );
+    }
+
+    #[cfg(feature = "manager-tls")]
+    {
+        app = clap_app!(@app (app)
+            (@arg MANAGER_TLS_ADDRESS: --("manager-tls-address") +takes_value {validator::validate_socket_addr} "Serve manager commands over TLS on this address")
+            (@arg MANAGER_TLS_CERT: --("manager-tls-cert") +takes_value "Certificate chain (PEM) of the manager TLS channel")
+            (@arg MANAGER_TLS_KEY: --("manager-tls-key") +takes_value "Private key (PEM) of the manager TLS channel")
+        );
+    }
+
     #[cfg(any(target_os = "linux", target_os = "android"))]
     {
         app = clap_app!(@app (app)
@@ -83,6 +111,11 @@ fn main() {
         );
     }
 
+    if capabilities::requested() {
+        capabilities::print();
+        return;
+    }
+
     let matches = app
         .arg(
             Arg::with_name("IPV6_FIRST")
@@ -162,14 +195,68 @@ fn main() {
         if let Some(sh) = matches.value_of("SERVER_HOST") {
             manager_config.server_host = sh.parse::<ManagerServerHost>().unwrap();
         }
+
+        if let Some(secret) = matches.value_of("MANAGER_SECRET") {
+            manager_config.secret = Some(secret.to_owned());
+        }
+
+        if let Some(addr) = matches.value_of("MANAGER_EVENTS_ADDRESS") {
+            manager_config.events_addr = Some(addr.parse::<SocketAddr>().expect("manager-events-address"));
+        }
+
+        if let Some(addr) = matches.value_of("MANAGER_FEDERATION_ADDRESS") {
+            manager_config.federation_addr = Some(addr.parse::<SocketAddr>().expect("manager-federation-address"));
+        }
+
+        if let Some(upstream) = matches.value_of("MANAGER_UPSTREAM") {
+            manager_config.upstream = Some(upstream.to_owned());
+        }
+
+        if let Some(node_name) = matches.value_of("MANAGER_NODE_NAME") {
+            manager_config.node_name = Some(node_name.to_owned());
+        }
+
+        #[cfg(feature = "manager-http")]
+        {
+            if let Some(addr) = matches.value_of("MANAGER_HTTP_ADDRESS") {
+                manager_config.http_addr = Some(addr.parse::<SocketAddr>().expect("manager-http-address"));
+            }
+
+            if let Some(token) = matches.value_of("MANAGER_HTTP_TOKEN") {
+                manager_config.http_token = Some(token.to_owned());
+            }
+        }
+
+        #[cfg(feature = "manager-tls")]
+        {
+            if let Some(addr) = matches.value_of("MANAGER_TLS_ADDRESS") {
+                manager_config.tls_addr = Some(addr.parse::<SocketAddr>().expect("manager-tls-address"));
+            }
+
+            if let Some(cert) = matches.value_of("MANAGER_TLS_CERT") {
+                manager_config.tls_cert = Some(PathBuf::from(cert));
+            }
+
+            if let Some(key) = matches.value_of("MANAGER_TLS_KEY") {
+                manager_config.tls_key = Some(PathBuf::from(key));
+            }
+        }
     }
 
     if let Some(nofile) = matches.value_of("NOFILE") {
         config.nofile = Some(nofile.parse::<u64>().expect("an unsigned integer for `nofile`"));
     }
 
+    if let Some(geoip_database) = matches.value_of("GEOIP_DATABASE") {
+        config.geoip_database = Some(PathBuf::from(geoip_database));
+    }
+
+    if let Some(geosite_database) = matches.value_of("GEOSITE_DATABASE") {
+        config.geosite_database = Some(PathBuf::from(geosite_database));
+    }
+
     if let Some(acl_file) = matches.value_of("ACL") {
-        let acl = match AccessControl::load_from_file(acl_file) {
+        let acl = match AccessControl::load_from_file_with_databases(acl_file, &config.acl_databases()) {
             Ok(acl) => acl,
             Err(err) => {
                 panic!("loading ACL \"{}\", {}", acl_file, err);
@@ -204,7 +291,9 @@ fn main() {
         daemonize::daemonize(matches.value_of("DAEMONIZE_PID_PATH"));
     }
 
-    info!("shadowsocks {}", self::version::VERSION);
+    if !matches.is_present("NO_BANNER") {
+        info!("shadowsocks {}", self::version::VERSION);
+    }
 
     let mut builder = if cfg!(feature = "single-threaded") {
         Builder::new_current_thread()
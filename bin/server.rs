@@ -9,6 +9,7 @@
 
 use std::{
     net::{IpAddr, SocketAddr},
+    path::PathBuf,
     time::Duration,
 };
 
@@ -32,6 +33,7 @@ use shadowsocks::{
 };
 
 mod allocator;
+mod capabilities;
 #[cfg(unix)]
 mod daemonize;
 mod logging;
@@ -45,6 +47,7 @@ fn main() {
         (version: self::version::VERSION)
         (about: "A fast tunnel proxy that helps you bypass firewalls.")
         (@arg VERBOSE: -v ... "Set the level of debug")
+        (@arg CAPABILITIES: --capabilities "Print supported ciphers, transports, protocols and features in JSON, then exit")
         (@arg UDP_ONLY: -u conflicts_with[TCP_AND_UDP] "Server mode UDP_ONLY")
         (@arg TCP_AND_UDP: -U "Server mode TCP_AND_UDP")
 
@@ -61,13 +64,17 @@ fn main() {
         (@arg PLUGIN_OPT: --("plugin-opts") +takes_value requires[PLUGIN] "Set SIP003 plugin options")
 
         (@arg MANAGER_ADDRESS: --("manager-address") +takes_value "ShadowSocks Manager (ssmgr) address, could be \"IP:Port\", \"Domain:Port\" or \"/path/to/unix.sock\"")
+        (@arg MANAGER_SECRET: --("manager-secret") +takes_value "Shared secret for signing statistic reports sent to the manager")
 
         (@arg NO_DELAY: --("no-delay") !takes_value "Set TCP_NODELAY option for socket")
         (@arg NOFILE: -n --nofile +takes_value "Set RLIMIT_NOFILE with both soft and hard limit (only for *nix systems)")
         (@arg ACL: --acl +takes_value "Path to ACL (Access Control List)")
+        (@arg GEOIP_DATABASE: --("geoip-database") +takes_value "Path to MaxMind GeoIP database (mmdb) for `geoip:` rules in ACL")
+        (@arg GEOSITE_DATABASE: --("geosite-database") +takes_value "Path to v2ray's geosite.dat for `geosite:` rules in ACL")
 
         (@arg LOG_WITHOUT_TIME: --("log-without-time") "Log without datetime prefix")
         (@arg LOG_CONFIG: --("log-config") +takes_value "log4rs configuration file")
+        (@arg NO_BANNER: --("no-banner") "Don't log the version banner on startup")
 
         (@arg UDP_TIMEOUT: --("udp-timeout") +takes_value {validator::validate_u64} "Timeout seconds for UDP relay")
         (@arg UDP_MAX_ASSOCIATIONS: --("udp-max-associations") +takes_value {validator::validate_u64} "Maximum associations to be kept simultaneously for UDP relay")
@@ -88,6 +95,11 @@ fn main() {
         );
     }
 
+    if capabilities::requested() {
+        capabilities::print();
+        return;
+    }
+
     let matches = app
         .arg(
             Arg::with_name("IPV6_FIRST")
@@ -179,12 +191,26 @@ fn main() {
         config.manager = Some(ManagerConfig::new(m.parse::<ManagerAddr>().expect("manager address")));
     }
 
+    if let Some(secret) = matches.value_of("MANAGER_SECRET") {
+        if let Some(ref mut manager_config) = config.manager {
+            manager_config.secret = Some(secret.to_owned());
+        }
+    }
+
     if let Some(nofile) = matches.value_of("NOFILE") {
         config.nofile = Some(nofile.parse::<u64>().expect("an unsigned integer for `nofile`"));
     }
 
+    if let Some(geoip_database) = matches.value_of("GEOIP_DATABASE") {
+        config.geoip_database = Some(PathBuf::from(geoip_database));
+    }
+
+    if let Some(geosite_database) = matches.value_of("GEOSITE_DATABASE") {
+        config.geosite_database = Some(PathBuf::from(geosite_database));
+    }
+
     if let Some(acl_file) = matches.value_of("ACL") {
-        let acl = match AccessControl::load_from_file(acl_file) {
+        let acl = match AccessControl::load_from_file_with_databases(acl_file, &config.acl_databases()) {
             Ok(acl) => acl,
             Err(err) => {
                 panic!("loading ACL \"{}\", {}", acl_file, err);
@@ -228,7 +254,9 @@ fn main() {
         daemonize::daemonize(matches.value_of("DAEMONIZE_PID_PATH"));
     }
 
-    info!("shadowsocks {}", self::version::VERSION);
+    if !matches.is_present("NO_BANNER") {
+        info!("shadowsocks {}", self::version::VERSION);
+    }
 
     let mut builder = if cfg!(feature = "single-threaded") {
         Builder::new_current_thread()
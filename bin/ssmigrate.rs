@@ -0,0 +1,94 @@
+//! Migrate configurations from other shadowsocks implementations
+//!
+//! Reads shadowsocks-libev, go-shadowsocks2 or legacy (Python) shadowsocks configurations
+//! and prints them in this project's configuration format.
+
+use std::{
+    fs,
+    io::{self, Read},
+};
+
+use clap::{clap_app, Arg, ArgMatches, SubCommand};
+
+use shadowsocks::{
+    config::ConfigType,
+    migrate::{migrate, SourceFormat},
+};
+
+mod allocator;
+mod version;
+
+fn read_input(path: &str) -> io::Result<String> {
+    if path == "-" {
+        let mut content = String::new();
+        io::stdin().read_to_string(&mut content)?;
+        Ok(content)
+    } else {
+        fs::read_to_string(path)
+    }
+}
+
+fn run(format: SourceFormat, sub_matches: &ArgMatches, config_type: ConfigType) {
+    let path = sub_matches.value_of("INPUT").expect("INPUT");
+
+    let input = match read_input(path) {
+        Ok(i) => i,
+        Err(err) => panic!("reading \"{}\", {}", path, err),
+    };
+
+    let migrated = match migrate(format, &input, config_type) {
+        Ok(m) => m,
+        Err(err) => {
+            eprintln!("failed to migrate \"{}\", {}", path, err);
+            return;
+        }
+    };
+
+    for warning in &migrated.warnings {
+        eprintln!("warning: {}", warning);
+    }
+
+    println!("{}", migrated.config);
+}
+
+fn main() {
+    let app = clap_app!(ssmigrate =>
+        (version: self::version::VERSION)
+        (about: "Migrate configurations of other ShadowSocks implementations")
+        (@setting SubcommandRequiredElseHelp)
+        (@arg LOCAL: -l --local "Migrate a client's configuration, server's configuration by default")
+        (@subcommand libev =>
+            (about: "Migrate shadowsocks-libev's JSON configuration")
+            (@arg INPUT: +required "Path to the configuration file, - for stdin")
+        )
+        (@subcommand legacy =>
+            (about: "Migrate legacy (Python) shadowsocks' JSON configuration")
+            (@arg INPUT: +required "Path to the configuration file, - for stdin")
+        )
+    )
+    // Name with hyphen couldn't be declared in `clap_app!`
+    .subcommand(
+        SubCommand::with_name("go-ss2")
+            .about("Migrate go-shadowsocks2's command line arguments, saved in a file")
+            .arg(
+                Arg::with_name("INPUT")
+                    .required(true)
+                    .help("Path to the file containing arguments, - for stdin"),
+            ),
+    );
+
+    let matches = app.get_matches();
+
+    let config_type = if matches.is_present("LOCAL") {
+        ConfigType::Socks5Local
+    } else {
+        ConfigType::Server
+    };
+
+    let (name, sub_matches) = matches.subcommand();
+    let format = name.parse::<SourceFormat>().expect("subcommand");
+
+    if let Some(sub_matches) = sub_matches {
+        run(format, sub_matches, config_type);
+    }
+}
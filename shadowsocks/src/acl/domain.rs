@@ -0,0 +1,277 @@
+//! Rules for matching domain names, which are all converted into regular expressions,
+//! then compiled into `HostRules`
+//!
+//! - `full:www.example.com` - Exactly the domain name
+//! - `domain:example.com` - The domain name and all its subdomains
+//! - `regexp:^[a-z]+\.example\.com$` - Regular expression, validated while loading
+//! - `*.example.com`, `wildcard:img*.example.com` - Wildcards, `*.` at the beginning matches subdomains of any depth
+//!   (but not the domain itself), `*` elsewhere matches characters in one label. Wildcards without the leading `*.`
+//!   must be marked by `wildcard:`, because they couldn't be told apart from regular expressions like `google.*`
+//! - Anything else is a regular expression, like `(^|\.)example\.com$`
+
+use std::{collections::HashSet, fmt};
+
+use regex::{Regex, RegexSet, RegexSetBuilder};
+
+/// Convert a domain rule line into a regular expression
+///
+/// Returns `Err(..)` if it is an invalid rule
+pub fn rule_to_regex(line: &str) -> Result<String, String> {
+    if let Some(domain) = line.strip_prefix("full:") {
+        Ok(format!("^{}$", regex::escape(domain)))
+    } else if let Some(domain) = line.strip_prefix("domain:") {
+        Ok(format!("(^|\\.){}$", regex::escape(domain)))
+    } else if let Some(pattern) = line.strip_prefix("regexp:") {
+        match Regex::new(pattern) {
+            Ok(..) => Ok(pattern.to_owned()),
+            Err(err) => Err(format!("invalid regular expression \"{}\", {}", pattern, err)),
+        }
+    } else if let Some(pattern) = line.strip_prefix("wildcard:") {
+        if is_wildcard(pattern) {
+            Ok(wildcard_to_regex(pattern))
+        } else {
+            Err(format!("invalid wildcard \"{}\"", pattern))
+        }
+    } else if line.starts_with("*.") && is_wildcard(line) {
+        // Not a valid regular expression, a repetition must follow something
+        Ok(wildcard_to_regex(line))
+    } else {
+        Ok(line.to_owned())
+    }
+}
+
+/// Wildcards are domain names with `*`, like `*.example.com` or `img*.example.com`
+fn is_wildcard(line: &str) -> bool {
+    line.contains('*')
+        && line
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '*')
+}
+
+fn wildcard_to_regex(line: &str) -> String {
+    let (prefix, rest) = match line.strip_prefix("*.") {
+        Some(rest) => ("^.+\\.", rest),
+        None => ("^", line),
+    };
+
+    let labels = rest.split('*').map(regex::escape).collect::<Vec<_>>().join("[^.]*");
+
+    format!("{}{}$", prefix, labels)
+}
+
+/// Limit of the compiled size of regular expressions, rules could be huge
+const REGEX_SIZE_LIMIT: usize = usize::max_value();
+
+/// Compiled rules for matching domain names
+///
+/// Rules of plain domain names (`(^|\.)example\.com$` or `^www\.example\.com$`, which `full:`, `domain:` and
+/// geosite rules are compiled into) are indexed by hash sets, so a lookup costs one probe per label of the host.
+/// Only the other rules are compiled into a `RegexSet`.
+#[derive(Clone)]
+pub struct HostRules {
+    /// Domain names that match themselves and all their subdomains
+    suffixes: HashSet<Box<str>>,
+    /// Domain names that match exactly
+    full: HashSet<Box<str>>,
+    regex: RegexSet,
+}
+
+impl HostRules {
+    /// Compile rules in regular expressions
+    pub fn build(rules: Vec<String>) -> Result<HostRules, regex::Error> {
+        let mut suffixes = HashSet::new();
+        let mut full = HashSet::new();
+        let mut patterns = Vec::new();
+
+        for rule in rules {
+            if let Some(domain) = literal_domain(&rule, &["(^|\\.)", "(?:^|\\.)"]) {
+                suffixes.insert(domain.into_boxed_str());
+            } else if let Some(domain) = literal_domain(&rule, &["^"]) {
+                full.insert(domain.into_boxed_str());
+            } else {
+                patterns.push(rule);
+            }
+        }
+
+        let regex = RegexSetBuilder::new(patterns).size_limit(REGEX_SIZE_LIMIT).build()?;
+
+        Ok(HostRules { suffixes, full, regex })
+    }
+
+    /// Check if `host` matches any rules
+    pub fn is_match(&self, host: &str) -> bool {
+        self.matched_domain(host).is_some() || self.regex.is_match(host)
+    }
+
+    /// The rule that `host` matches, as `domain:NAME`, `full:NAME` or the regular expression
+    pub fn matched_rule(&self, host: &str) -> Option<String> {
+        if let Some(rule) = self.matched_domain(host) {
+            return Some(rule);
+        }
+        let idx = self.regex.matches(host).into_iter().next()?;
+        Some(self.regex.patterns()[idx].clone())
+    }
+
+    fn matched_domain(&self, host: &str) -> Option<String> {
+        if self.full.contains(host) {
+            return Some(format!("full:{}", host));
+        }
+
+        if self.suffixes.is_empty() {
+            return None;
+        }
+        let mut suffix = host;
+        loop {
+            if self.suffixes.contains(suffix) {
+                return Some(format!("domain:{}", suffix));
+            }
+            match suffix.find('.') {
+                Some(pos) => suffix = &suffix[pos + 1..],
+                None => return None,
+            }
+        }
+    }
+
+    /// Domain names that match themselves and all their subdomains
+    pub fn suffixes(&self) -> impl Iterator<Item = &str> {
+        self.suffixes.iter().map(AsRef::as_ref)
+    }
+
+    /// Domain names that match exactly
+    pub fn full_domains(&self) -> impl Iterator<Item = &str> {
+        self.full.iter().map(AsRef::as_ref)
+    }
+
+    /// Regular expressions of the other rules
+    pub fn patterns(&self) -> &[String] {
+        self.regex.patterns()
+    }
+
+    /// Number of rules
+    pub fn len(&self) -> usize {
+        self.suffixes.len() + self.full.len() + self.regex.len()
+    }
+
+    /// Check if there are no rules
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl fmt::Debug for HostRules {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "HostRules {{ suffixes: {}, full: {}, regex: [",
+            self.suffixes.len(),
+            self.full.len()
+        )?;
+
+        let max_len = 2;
+        let has_more = self.regex.len() > max_len;
+
+        for (idx, r) in self.regex.patterns().iter().take(max_len).enumerate() {
+            if idx > 0 {
+                f.write_str(", ")?;
+            }
+            f.write_str(r)?;
+        }
+
+        if has_more {
+            f.write_str(", ...")?;
+        }
+
+        f.write_str("] }")
+    }
+}
+
+/// The domain name in a regular expression like `PREFIX` + escaped domain name + `$`
+///
+/// Returns `None` if the regular expression matches anything else
+fn literal_domain(rule: &str, prefixes: &[&str]) -> Option<String> {
+    let escaped = prefixes
+        .iter()
+        .find_map(|prefix| rule.strip_prefix(prefix))?
+        .strip_suffix('$')?;
+
+    let mut domain = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(c @ '.') | Some(c @ '-') => domain.push(c),
+                _ => return None,
+            },
+            c if c.is_ascii_alphanumeric() || c == '-' || c == '_' => domain.push(c),
+            _ => return None,
+        }
+    }
+
+    if domain.is_empty() {
+        None
+    } else {
+        Some(domain)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn build(rules: &[&str]) -> HostRules {
+        let rules = rules.iter().map(|r| rule_to_regex(r).unwrap()).collect();
+        HostRules::build(rules).unwrap()
+    }
+
+    #[test]
+    fn legacy_regex_rules() {
+        let rules = build(&["google.*"]);
+        assert!(rules.is_match("google.com"));
+        assert!(rules.is_match("www.google.co.jp"));
+        assert!(!rules.is_match("example.com"));
+
+        let rules = build(&[".*"]);
+        assert!(rules.is_match("example.com"));
+
+        let rules = build(&["(^|\\.)example\\.com$"]);
+        assert!(rules.is_match("example.com"));
+        assert!(rules.is_match("www.example.com"));
+        assert!(!rules.is_match("example.com.cn"));
+        assert!(!rules.is_match("myexample.com"));
+    }
+
+    #[test]
+    fn wildcard_rules() {
+        let rules = build(&["*.example.com"]);
+        assert!(rules.is_match("www.example.com"));
+        assert!(rules.is_match("a.b.example.com"));
+        assert!(!rules.is_match("example.com"));
+        assert!(!rules.is_match("www.example.org"));
+
+        let rules = build(&["wildcard:img*.example.com"]);
+        assert!(rules.is_match("img.example.com"));
+        assert!(rules.is_match("img01.example.com"));
+        assert!(!rules.is_match("img.cdn.example.com"));
+        assert!(!rules.is_match("www.example.com"));
+
+        let rules = build(&["wildcard:*.cdn*.example.com"]);
+        assert!(rules.is_match("a.cdn1.example.com"));
+        assert!(!rules.is_match("cdn1.example.com"));
+    }
+
+    #[test]
+    fn invalid_rules() {
+        assert!(rule_to_regex("wildcard:(^|\\.)example\\.com$").is_err());
+        assert!(rule_to_regex("regexp:(example").is_err());
+    }
+
+    #[test]
+    fn marked_domain_rules() {
+        let rules = build(&["full:www.example.com", "domain:example.org"]);
+        assert!(rules.is_match("www.example.com"));
+        assert!(!rules.is_match("img.www.example.com"));
+        assert!(rules.is_match("example.org"));
+        assert!(rules.is_match("www.example.org"));
+        assert_eq!(rules.matched_rule("www.example.org").unwrap(), "domain:example.org");
+    }
+}
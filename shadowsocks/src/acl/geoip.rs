@@ -0,0 +1,60 @@
+//! Rules for matching IP addresses by countries, backed by a MaxMind database (mmdb)
+//!
+//! Rules are formatted as `geoip:CC`, where `CC` is an ISO 3166-1 country code, like `geoip:cn` or `geoip:us`
+
+use std::{
+    fmt,
+    io::{self, Error, ErrorKind},
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use maxminddb::{geoip2, Reader};
+
+/// Parse rule lines like `geoip:cn`, returns the lowercased country code
+///
+/// Returns `None` if the line is not a GeoIP rule, `Some(Err(..))` if it is malformed
+pub fn parse_rule(line: &str) -> Option<Result<String, ()>> {
+    let code = line.strip_prefix("geoip:")?.trim();
+    if code.len() == 2 && code.chars().all(|c| c.is_ascii_alphabetic()) {
+        Some(Ok(code.to_ascii_lowercase()))
+    } else {
+        Some(Err(()))
+    }
+}
+
+/// MaxMind GeoIP2 / GeoLite2 Country (or City) database
+pub struct GeoIpDatabase {
+    reader: Reader<Vec<u8>>,
+    path: PathBuf,
+}
+
+impl GeoIpDatabase {
+    /// Open the database file, which is read into memory
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<GeoIpDatabase> {
+        let path = path.as_ref();
+        match Reader::open_readfile(path) {
+            Ok(reader) => Ok(GeoIpDatabase {
+                reader,
+                path: path.to_owned(),
+            }),
+            Err(err) => Err(Error::new(
+                ErrorKind::Other,
+                format!("failed to open GeoIP database {}, {}", path.display(), err),
+            )),
+        }
+    }
+
+    /// Lowercased country code of `addr`, `None` if it is not in the database
+    pub fn lookup_country(&self, addr: IpAddr) -> Option<String> {
+        let country: geoip2::Country = self.reader.lookup(addr).ok()?;
+        let code = country.country?.iso_code?;
+        Some(code.to_ascii_lowercase())
+    }
+}
+
+impl fmt::Debug for GeoIpDatabase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("GeoIpDatabase").field("path", &self.path).finish()
+    }
+}
@@ -0,0 +1,247 @@
+//! Rules for matching domain names by categories in v2ray's `geosite.dat`
+//!
+//! Rules are formatted as `geosite:CATEGORY`, like `geosite:google` or `geosite:cn`.
+//! Domains of a category could be filtered by an attribute, like `geosite:google@cn`.
+//!
+//! `geosite.dat` is a protobuf encoded `GeoSiteList`, only the fields used here are decoded:
+//!
+//! ```plain
+//! message Domain {
+//!     enum Type { Plain = 0; Regex = 1; Domain = 2; Full = 3; }
+//!     Type type = 1;
+//!     string value = 2;
+//!     repeated Attribute attribute = 3; // message Attribute { string key = 1; ... }
+//! }
+//! message GeoSite { string country_code = 1; repeated Domain domain = 2; }
+//! message GeoSiteList { repeated GeoSite entry = 1; }
+//! ```
+
+use std::{
+    collections::HashMap,
+    fmt,
+    fs,
+    io::{self, Error, ErrorKind},
+    ops::Range,
+    path::Path,
+    str,
+};
+
+/// Parse rule lines like `geosite:google` or `geosite:google@cn`, returns the category and the attribute
+///
+/// Returns `None` if the line is not a geosite rule, `Some(Err(..))` if it is malformed
+pub fn parse_rule(line: &str) -> Option<Result<(String, Option<String>), ()>> {
+    let rule = line.strip_prefix("geosite:")?.trim();
+
+    let (category, attribute) = match rule.find('@') {
+        Some(pos) => (&rule[..pos], Some(rule[pos + 1..].to_ascii_lowercase())),
+        None => (rule, None),
+    };
+
+    if category.is_empty() || attribute.as_ref().map(String::is_empty).unwrap_or(false) {
+        return Some(Err(()));
+    }
+
+    Some(Ok((category.to_ascii_lowercase(), attribute)))
+}
+
+/// v2ray's `geosite.dat`
+///
+/// Only categories are indexed while opening, domains are decoded when their categories are referred by rules
+pub struct GeoSiteDatabase {
+    data: Vec<u8>,
+    categories: HashMap<String, Range<usize>>,
+}
+
+impl GeoSiteDatabase {
+    /// Open the database file, which is read into memory
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<GeoSiteDatabase> {
+        let path = path.as_ref();
+        let data = fs::read(path)?;
+
+        let categories = match index_categories(&data) {
+            Ok(c) => c,
+            Err(err) => {
+                let err = Error::new(
+                    ErrorKind::Other,
+                    format!("failed to load geosite database {}, {}", path.display(), err),
+                );
+                return Err(err);
+            }
+        };
+
+        Ok(GeoSiteDatabase { data, categories })
+    }
+
+    /// Domains of `category` (with `attribute` if specified) as regular expressions
+    ///
+    /// Returns `None` if `category` is not in the database
+    pub fn category_rules(&self, category: &str, attribute: Option<&str>) -> Option<io::Result<Vec<String>>> {
+        let range = self.categories.get(category)?;
+        Some(decode_category(&self.data[range.clone()], attribute))
+    }
+}
+
+impl fmt::Debug for GeoSiteDatabase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("GeoSiteDatabase")
+            .field("categories", &self.categories.len())
+            .finish()
+    }
+}
+
+fn index_categories(data: &[u8]) -> io::Result<HashMap<String, Range<usize>>> {
+    let mut categories = HashMap::new();
+
+    let mut list = ProtoReader::new(data);
+    while let Some((field, value)) = list.next_field()? {
+        let entry = match (field, value) {
+            (1, Field::Bytes(entry)) => entry,
+            _ => continue,
+        };
+
+        let mut site = ProtoReader::new(entry);
+        while let Some((field, value)) = site.next_field()? {
+            if let (1, Field::Bytes(code)) = (field, value) {
+                let code = str::from_utf8(code).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+
+                // The entry was just read, ending at the current position of list
+                categories.insert(code.to_ascii_lowercase(), list.pos - entry.len()..list.pos);
+                break;
+            }
+        }
+    }
+
+    Ok(categories)
+}
+
+fn decode_category(entry: &[u8], attribute: Option<&str>) -> io::Result<Vec<String>> {
+    let mut rules = Vec::new();
+
+    let mut site = ProtoReader::new(entry);
+    while let Some((field, value)) = site.next_field()? {
+        let domain = match (field, value) {
+            (2, Field::Bytes(domain)) => domain,
+            _ => continue,
+        };
+
+        let mut domain_type = 0;
+        let mut domain_value = "";
+        let mut has_attribute = attribute.is_none();
+
+        let mut reader = ProtoReader::new(domain);
+        while let Some((field, value)) = reader.next_field()? {
+            match (field, value) {
+                (1, Field::Varint(t)) => domain_type = t,
+                (2, Field::Bytes(v)) => {
+                    domain_value = str::from_utf8(v).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+                }
+                (3, Field::Bytes(attr)) => {
+                    let mut attr_reader = ProtoReader::new(attr);
+                    while let Some((field, value)) = attr_reader.next_field()? {
+                        if let (1, Field::Bytes(key)) = (field, value) {
+                            if Some(key) == attribute.map(str::as_bytes) {
+                                has_attribute = true;
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if !has_attribute || domain_value.is_empty() {
+            continue;
+        }
+
+        let rule = match domain_type {
+            // Plain, keyword matching
+            0 => regex::escape(domain_value),
+            // Regex
+            1 => domain_value.to_owned(),
+            // Domain, matching itself and all its subdomains
+            2 => format!("(^|\\.){}$", regex::escape(domain_value)),
+            // Full
+            3 => format!("^{}$", regex::escape(domain_value)),
+            _ => continue,
+        };
+        rules.push(rule);
+    }
+
+    Ok(rules)
+}
+
+/// Value of a protobuf field
+enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+/// Minimal protobuf decoder, reading fields one by one
+struct ProtoReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ProtoReader<'a> {
+    fn new(buf: &'a [u8]) -> ProtoReader<'a> {
+        ProtoReader { buf, pos: 0 }
+    }
+
+    fn read_varint(&mut self) -> io::Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = match self.buf.get(self.pos) {
+                Some(b) => *b,
+                None => return Err(Error::new(ErrorKind::UnexpectedEof, "truncated varint")),
+            };
+            self.pos += 1;
+
+            value |= u64::from(b & 0x7F) << shift;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(Error::new(ErrorKind::InvalidData, "varint too long"))
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() - self.pos < len {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "truncated field"));
+        }
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn next_field(&mut self) -> io::Result<Option<(u64, Field<'a>)>> {
+        if self.pos >= self.buf.len() {
+            return Ok(None);
+        }
+
+        let key = self.read_varint()?;
+        let value = match key & 0x07 {
+            0 => Field::Varint(self.read_varint()?),
+            1 => {
+                self.take(8)?;
+                Field::Fixed
+            }
+            2 => {
+                let len = self.read_varint()? as usize;
+                Field::Bytes(self.take(len)?)
+            }
+            5 => {
+                self.take(4)?;
+                Field::Fixed
+            }
+            t => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unsupported protobuf wire type {}", t),
+                ))
+            }
+        };
+
+        Ok(Some((key >> 3, value)))
+    }
+}
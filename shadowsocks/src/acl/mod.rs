@@ -7,15 +7,32 @@ use std::{
     fs::File,
     io::{self, BufRead, BufReader, Error, ErrorKind},
     net::{IpAddr, SocketAddr},
-    path::Path,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 
 use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use iprange::IpRange;
-use regex::{RegexSet, RegexSetBuilder};
 
 use crate::{context::Context, relay::socks5::Address};
 
+use self::{
+    domain::HostRules,
+    geoip::GeoIpDatabase,
+    geosite::GeoSiteDatabase,
+    udp::{UdpProtocol, UdpRule},
+};
+
+pub use self::schedule::TimeWindow;
+
+mod domain;
+mod geoip;
+mod geosite;
+mod pac;
+mod schedule;
+mod udp;
+
 /// Strategy mode that ACL is running
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Mode {
@@ -25,48 +42,217 @@ pub enum Mode {
     WhiteList,
 }
 
-#[derive(Clone)]
-struct Rules {
-    ipv4: IpRange<Ipv4Net>,
-    ipv6: IpRange<Ipv6Net>,
-    rule: RegexSet,
+/// Databases that rules could refer to
+#[derive(Debug, Clone, Default)]
+pub struct RuleDatabases {
+    /// MaxMind database (mmdb) for `geoip:CC` rules
+    pub geoip: Option<PathBuf>,
+    /// v2ray's `geosite.dat` for `geosite:CATEGORY` rules
+    pub geosite: Option<PathBuf>,
 }
 
-impl fmt::Debug for Rules {
+/// Target of `[route:TARGET]` sections, where connections to the matched addresses go (for client)
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RouteTarget {
+    /// `direct`, connecting directly, like `[bypass_list]`
+    Direct,
+    /// `reject`, refusing connections
+    Reject,
+    /// `proxy` or `proxy:GROUP`, connecting through servers in the default group, or in group `GROUP`
+    Proxy(Option<String>),
+}
+
+impl RouteTarget {
+    fn parse(target: &str) -> Option<RouteTarget> {
+        match target {
+            "direct" => Some(RouteTarget::Direct),
+            "reject" => Some(RouteTarget::Reject),
+            "proxy" => Some(RouteTarget::Proxy(None)),
+            _ => match target.strip_prefix("proxy:") {
+                Some(group) if !group.is_empty() => Some(RouteTarget::Proxy(Some(group.to_owned()))),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl fmt::Display for RouteTarget {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Rules {{ ipv4: {:?}, ipv6: {:?}, rule: [", self.ipv4, self.ipv6)?;
+        match *self {
+            RouteTarget::Direct => f.write_str("direct"),
+            RouteTarget::Reject => f.write_str("reject"),
+            RouteTarget::Proxy(None) => f.write_str("proxy"),
+            RouteTarget::Proxy(Some(ref group)) => write!(f, "proxy:{}", group),
+        }
+    }
+}
+
+/// How connections to an address are handled, and which rule decided it, reported by `explain_target` (for client)
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Explanation {
+    /// Where connections go, the group is reported if proxied by a `[proxy_group:NAME]` or `[route:proxy:NAME]`
+    pub target: RouteTarget,
+    /// Section of the matched rule, or `[proxy_all]` / `[bypass_all]` if decided by the mode
+    pub section: String,
+    /// The matched rule, `None` if decided by the mode
+    ///
+    /// Domain name rules are reported as `domain:NAME`, `full:NAME` or regular expressions,
+    /// IP rules as networks merged while loading
+    pub rule: Option<String>,
+    /// The resolved address that matched, if a domain name is matched by IP rules
+    pub resolved: Option<IpAddr>,
+}
+
+/// Upstreams of `[dns:UPSTREAM]` sections are checked while loading, so they could be created later
+#[cfg(feature = "trust-dns")]
+fn check_dns_upstream(upstream: &str) -> io::Result<()> {
+    match crate::config::parse_dns_servers(upstream) {
+        Ok(Some(..)) => Ok(()),
+        Ok(None) => Err(Error::new(
+            ErrorKind::Other,
+            format!("invalid section \"[dns:{}]\"", upstream),
+        )),
+        Err(err) => Err(Error::new(
+            ErrorKind::Other,
+            format!("invalid section \"[dns:{}]\", {}", upstream, err),
+        )),
+    }
+}
+
+#[cfg(not(feature = "trust-dns"))]
+fn check_dns_upstream(upstream: &str) -> io::Result<()> {
+    Err(Error::new(
+        ErrorKind::Other,
+        format!("section \"[dns:{}]\" requires feature \"trust-dns\"", upstream),
+    ))
+}
+
+/// Process names are matched case-insensitively, without the `.exe` extension
+fn normalize_process_name(name: &str) -> Option<String> {
+    let name = name.trim().to_ascii_lowercase();
+    let name = match name.strip_suffix(".exe") {
+        Some(stem) => stem.to_owned(),
+        None => name,
+    };
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
 
-        let max_len = 2;
-        let has_more = self.rule.len() > max_len;
+/// Policy for connecting to outbound addresses, attached by `[connect_policy:...]` sections
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ConnectPolicy {
+    /// Timeout of each attempt, overriding the server's `timeout`
+    pub timeout: Option<Duration>,
+    /// Times to retry after the first attempt failed
+    pub retries: u32,
+}
+
+impl ConnectPolicy {
+    /// Parse parameters of a section, like `timeout=5,retry=2`
+    fn parse(params: &str) -> Option<ConnectPolicy> {
+        let mut policy = ConnectPolicy {
+            timeout: None,
+            retries: 0,
+        };
 
-        for (idx, r) in self.rule.patterns().iter().take(max_len).enumerate() {
-            if idx > 0 {
-                f.write_str(", ")?;
+        for param in params.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let mut kv = param.splitn(2, '=');
+            let (key, value) = (kv.next()?.trim(), kv.next()?.trim());
+            match key {
+                "timeout" => policy.timeout = Some(Duration::from_secs(value.parse().ok()?)),
+                "retry" => policy.retries = value.parse().ok()?,
+                _ => return None,
             }
-            f.write_str(r)?;
         }
 
-        if has_more {
-            f.write_str(", ...")?;
-        }
+        Some(policy)
+    }
+}
 
-        f.write_str("] }")
+#[derive(Clone)]
+struct Rules {
+    ipv4: IpRange<Ipv4Net>,
+    ipv6: IpRange<Ipv6Net>,
+    rule: HostRules,
+    udp: Vec<UdpRule>,
+    geoip: Vec<String>,
+    geoip_db: Option<Arc<GeoIpDatabase>>,
+    window: Option<TimeWindow>,
+    processes: Vec<String>,
+}
+
+impl fmt::Debug for Rules {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Rules {{ ipv4: {:?}, ipv6: {:?}, udp: {:?}, geoip: {:?}, rule: {:?}, window: {:?}, processes: {:?} }}",
+            self.ipv4, self.ipv6, self.udp, self.geoip, self.rule, self.window, self.processes
+        )
     }
 }
 
 impl Rules {
     /// Create a new rule
-    fn new(mut ipv4: IpRange<Ipv4Net>, mut ipv6: IpRange<Ipv6Net>, rule: RegexSet) -> Rules {
+    fn new(mut ipv4: IpRange<Ipv4Net>, mut ipv6: IpRange<Ipv6Net>, rule: HostRules, udp: Vec<UdpRule>) -> Rules {
         // Optimization, merging networks
         ipv4.simplify();
         ipv6.simplify();
 
-        Rules { ipv4, ipv6, rule }
+        Rules {
+            ipv4,
+            ipv6,
+            rule,
+            udp,
+            geoip: Vec::new(),
+            geoip_db: None,
+            window: None,
+            processes: Vec::new(),
+        }
+    }
+
+    /// Match IP addresses in countries `geoip` by looking up in `geoip_db`
+    fn with_geoip(mut self, geoip: Vec<String>, geoip_db: Option<Arc<GeoIpDatabase>>) -> Rules {
+        if !geoip.is_empty() {
+            self.geoip = geoip;
+            self.geoip_db = geoip_db;
+        }
+        self
+    }
+
+    /// Rules only apply during `window` in local time
+    fn with_window(mut self, window: Option<TimeWindow>) -> Rules {
+        self.window = window;
+        self
+    }
+
+    /// Match connections from processes named in `processes`
+    fn with_processes(mut self, processes: Vec<String>) -> Rules {
+        self.processes = processes;
+        self
     }
 
-    /// Check if the specified address matches these rules
-    #[allow(dead_code)]
+    /// Check if the process name (normalized by `normalize_process_name`) matches any rules, during the window
+    fn check_process_matched(&self, name: &str) -> bool {
+        self.is_active() && self.processes.iter().any(|p| p == name)
+    }
+
+    /// Check if the current local time is in the window, always `true` if there is no window
+    fn is_active(&self) -> bool {
+        match self.window {
+            Some(ref window) => window.is_active(),
+            None => true,
+        }
+    }
+
+    /// Check if the specified address matches these rules, during the window
     fn check_address_matched(&self, addr: &Address) -> bool {
+        if !self.is_active() {
+            return false;
+        }
+
         match *addr {
             Address::SocketAddress(ref saddr) => self.check_ip_matched(&saddr.ip()),
             Address::DomainNameAddress(ref domain, ..) => self.check_host_matched(domain),
@@ -75,9 +261,21 @@ impl Rules {
 
     /// Check if the specified address matches any rules
     fn check_ip_matched(&self, addr: &IpAddr) -> bool {
-        match addr {
+        let matched = match addr {
             IpAddr::V4(v4) => self.ipv4.contains(v4),
             IpAddr::V6(v6) => self.ipv6.contains(v6),
+        };
+        matched || self.check_geoip_matched(addr)
+    }
+
+    /// Check if the specified address is in any countries of GeoIP rules
+    fn check_geoip_matched(&self, addr: &IpAddr) -> bool {
+        match self.geoip_db {
+            Some(ref db) if !self.geoip.is_empty() => match db.lookup_country(*addr) {
+                Some(country) => self.geoip.contains(&country),
+                None => false,
+            },
+            _ => false,
         }
     }
 
@@ -86,14 +284,55 @@ impl Rules {
         self.rule.is_match(host)
     }
 
+    /// Check if the UDP flow matches any UDP rules
+    fn check_udp_matched(&self, port: u16, protocol: Option<UdpProtocol>) -> bool {
+        self.udp.iter().any(|r| r.matches(port, protocol))
+    }
+
+    /// The rule that the specified address matches during the window, for explaining
+    fn matched_rule(&self, addr: &Address) -> Option<String> {
+        if !self.is_active() {
+            return None;
+        }
+
+        match *addr {
+            Address::SocketAddress(ref saddr) => self.matched_ip_rule(&saddr.ip()),
+            Address::DomainNameAddress(ref domain, ..) => self.matched_host_rule(domain),
+        }
+    }
+
+    /// The network or GeoIP rule that the specified address matches, for explaining
+    fn matched_ip_rule(&self, addr: &IpAddr) -> Option<String> {
+        let network = match *addr {
+            IpAddr::V4(v4) => self.ipv4.iter().find(|n| n.contains(&v4)).map(|n| n.to_string()),
+            IpAddr::V6(v6) => self.ipv6.iter().find(|n| n.contains(&v6)).map(|n| n.to_string()),
+        };
+        if network.is_some() {
+            return network;
+        }
+
+        match self.geoip_db {
+            Some(ref db) if !self.geoip.is_empty() => match db.lookup_country(*addr) {
+                Some(country) if self.geoip.contains(&country) => Some(format!("geoip:{}", country)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// The regex rule that the specified host matches, for explaining
+    fn matched_host_rule(&self, host: &str) -> Option<String> {
+        self.rule.matched_rule(host)
+    }
+
     /// Check if there are no rules for IP addresses
     fn is_ip_empty(&self) -> bool {
-        self.ipv4.is_empty() && self.ipv6.is_empty()
+        self.ipv4.is_empty() && self.ipv6.is_empty() && self.geoip.is_empty()
     }
 
     /// Check if there are no rules for domain names
     fn is_host_empty(&self) -> bool {
-        self.rule.len() == 0
+        self.rule.is_empty()
     }
 }
 
@@ -123,12 +362,26 @@ impl Rules {
 ///     * `[proxy_all]` - ACL runs in `WhiteList` mode.
 ///     * `[bypass_list]` - Rules for connecting directly
 ///     * `[proxy_list]` - Rules for connecting through proxies
+///     * `[proxy_group:NAME]` - Rules for connecting through servers in group `NAME`, if proxied
+///     * `[route:TARGET]` - Rules for connecting to `TARGET`, which is `direct`, `reject`, `proxy` or `proxy:NAME`.
+///       Checked before the other sections, the first section that matches wins
+///     * `[route:TARGET@WINDOW]` - Rules that only apply during a window in local time, like
+///       `[route:reject@mon-fri,09:00-17:00]`, `[route:direct@sat,sun]` or `[route:reject@22:00-06:00]`
+/// - For both
+///     * `[dns:UPSTREAM]` - Domain names resolved by `UPSTREAM` instead of `dns`, in the same format as `dns`, like
+///       `[dns:223.5.5.5]` or `[dns:https://cloudflare-dns.com/dns-query]`. The first section that matches wins,
+///       requires feature `trust-dns`
 /// - For remote servers (`ssserver`)
 ///     * `[reject_all]` - ACL runs in `BlackList` mode.
 ///     * `[accept_all]` - ACL runs in `WhiteList` mode.
 ///     * `[black_list]` - Rules for rejecting
 ///     * `[white_list]` - Rules for allowing
 ///     * `[outbound_block_list]` - Rules for blocking outbound addresses.
+///     * `[outbound_allow_list]` - Rules for allowing outbound addresses. If this section is present,
+///       all the other outbound addresses are blocked. Domain names are allowed if they match domain rules,
+///       or all their resolved addresses match IP rules
+///     * `[connect_policy:timeout=SECS,retry=N]` - Rules for connecting to outbound addresses
+///       with another timeout and retries, both parameters are optional
 ///
 /// ## Mode
 ///
@@ -143,36 +396,109 @@ impl Rules {
 ///
 /// - CIDR form network addresses, like `10.9.0.32/16`
 /// - IP addresses, like `127.0.0.1` or `::1`
-/// - Regular Expression for matching hosts, like `(^|\.)gmail\.com$`
+/// - Regular Expression for matching hosts, like `(^|\.)gmail\.com$`, or explicitly `regexp:^mail\.google\.com$`
+/// - Domain names, exactly `full:www.google.com`, or with all subdomains `domain:google.com`
+/// - Wildcard domain names, like `*.google.com` (subdomains of any depth) or `wildcard:mail*.google.com` (in one label)
+/// - GeoIP rules, matching IP addresses in countries like `geoip:cn`, which requires a MaxMind database (mmdb)
+/// - geosite rules, matching domain names in categories of v2ray's `geosite.dat` like `geosite:google`
+/// - UDP flow rules (in `[bypass_list]` and `[proxy_list]`), matching destination ports like `port:3478-3481`,
+///   or protocols detected from the first packet like `proto:quic`, `proto:stun` and `proto:dns`
+/// - Process rules (in `[route:TARGET]`), matching TCP connections from local processes like `process:firefox`,
+///   on platforms where the owner of sockets could be found (Linux, Windows)
+///
+/// Plain domain names (`full:`, `domain:`, geosite rules, or regular expressions like `(^|\.)gmail\.com$` and
+/// `^mail\.google\.com$`) are indexed in hash sets instead of being compiled into regular expressions,
+/// so huge lists (like china-list) are cheap to load and match.
 #[derive(Debug, Clone)]
 pub struct AccessControl {
     outbound_block: Rules,
+    outbound_allow: Option<Rules>,
     black_list: Rules,
     white_list: Rules,
+    groups: Vec<(String, Rules)>,
+    connect_policies: Vec<(ConnectPolicy, Rules)>,
+    routes: Vec<(RouteTarget, Rules)>,
+    dns_upstreams: Vec<(String, Rules)>,
     mode: Mode,
+    file_path: PathBuf,
+    databases: RuleDatabases,
+}
+
+/// Rules of a `[proxy_group:NAME]`, `[connect_policy:...]`, `[route:TARGET]` or `[dns:UPSTREAM]` section while loading
+struct GroupRules {
+    name: String,
+    ipv4: IpRange<Ipv4Net>,
+    ipv6: IpRange<Ipv6Net>,
+    rules: Vec<String>,
+    geoip: Vec<String>,
+    processes: Vec<String>,
 }
 
 impl AccessControl {
     /// Load ACL rules from a file
     pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<AccessControl> {
+        AccessControl::load_from_file_with_databases(p, &RuleDatabases::default())
+    }
+
+    /// Load ACL rules from a file, GeoIP and geosite rules are matched by `databases`
+    ///
+    /// The geosite database is only loaded if there are geosite rules
+    pub fn load_from_file_with_databases<P: AsRef<Path>>(p: P, databases: &RuleDatabases) -> io::Result<AccessControl> {
+        let file_path = p.as_ref().to_owned();
         let fp = File::open(p)?;
-        let r = BufReader::new(fp);
+        AccessControl::load_from_reader(BufReader::new(fp), file_path, databases)
+    }
+
+    /// Load ACL rules from `r`, which are also saved in `file_path`
+    ///
+    /// `file_path` is reported by `file_path()`, and used for reloading
+    pub fn load_from_reader<R: BufRead, P: AsRef<Path>>(
+        r: R,
+        file_path: P,
+        databases: &RuleDatabases,
+    ) -> io::Result<AccessControl> {
+        let geoip_db = match databases.geoip {
+            Some(ref path) => Some(Arc::new(GeoIpDatabase::open(path)?)),
+            None => None,
+        };
+        let mut geosite_db: Option<GeoSiteDatabase> = None;
+
+        let file_path = file_path.as_ref().to_owned();
 
         let mut mode = Mode::BlackList;
 
         let mut outbound_block_ipv4 = IpRange::new();
         let mut outbound_block_ipv6 = IpRange::new();
         let mut outbound_block_rules = Vec::new();
+        let mut outbound_block_geoip = Vec::new();
+        let mut has_outbound_allow = false;
+        let mut outbound_allow_ipv4 = IpRange::new();
+        let mut outbound_allow_ipv6 = IpRange::new();
+        let mut outbound_allow_rules = Vec::new();
+        let mut outbound_allow_geoip = Vec::new();
         let mut bypass_ipv4 = IpRange::new();
         let mut bypass_ipv6 = IpRange::new();
         let mut bypass_rules = Vec::new();
+        let mut bypass_geoip = Vec::new();
+        let mut bypass_udp = Vec::new();
         let mut proxy_ipv4 = IpRange::new();
         let mut proxy_ipv6 = IpRange::new();
         let mut proxy_rules = Vec::new();
+        let mut proxy_geoip = Vec::new();
+        let mut proxy_udp = Vec::new();
+        let mut groups: Vec<GroupRules> = Vec::new();
+        let mut policies: Vec<(ConnectPolicy, GroupRules)> = Vec::new();
+        let mut routes: Vec<(RouteTarget, Option<TimeWindow>, GroupRules)> = Vec::new();
+        let mut dns_upstreams: Vec<GroupRules> = Vec::new();
 
         let mut curr_ipv4 = &mut bypass_ipv4;
         let mut curr_ipv6 = &mut bypass_ipv6;
         let mut curr_rules = &mut bypass_rules;
+        let mut curr_geoip = &mut bypass_geoip;
+        // UDP rules are only supported in bypass and proxy lists
+        let mut curr_udp = Some(&mut bypass_udp);
+        // Process rules are only supported in route sections
+        let mut curr_processes: Option<&mut Vec<String>> = None;
 
         for line in r.lines() {
             let line = line?;
@@ -196,16 +522,248 @@ impl AccessControl {
                     curr_ipv4 = &mut outbound_block_ipv4;
                     curr_ipv6 = &mut outbound_block_ipv6;
                     curr_rules = &mut outbound_block_rules;
+                    curr_geoip = &mut outbound_block_geoip;
+                    curr_udp = None;
+                    curr_processes = None;
+                }
+                "[outbound_allow_list]" => {
+                    has_outbound_allow = true;
+                    curr_ipv4 = &mut outbound_allow_ipv4;
+                    curr_ipv6 = &mut outbound_allow_ipv6;
+                    curr_rules = &mut outbound_allow_rules;
+                    curr_geoip = &mut outbound_allow_geoip;
+                    curr_udp = None;
+                    curr_processes = None;
                 }
                 "[black_list]" | "[bypass_list]" => {
                     curr_ipv4 = &mut bypass_ipv4;
                     curr_ipv6 = &mut bypass_ipv6;
                     curr_rules = &mut bypass_rules;
+                    curr_geoip = &mut bypass_geoip;
+                    curr_udp = Some(&mut bypass_udp);
+                    curr_processes = None;
                 }
                 "[white_list]" | "[proxy_list]" => {
                     curr_ipv4 = &mut proxy_ipv4;
                     curr_ipv6 = &mut proxy_ipv6;
                     curr_rules = &mut proxy_rules;
+                    curr_geoip = &mut proxy_geoip;
+                    curr_udp = Some(&mut proxy_udp);
+                    curr_processes = None;
+                }
+                section if section.starts_with("[proxy_group:") && section.ends_with(']') => {
+                    let name = &section["[proxy_group:".len()..section.len() - 1];
+
+                    let idx = match groups.iter().position(|g| g.name == name) {
+                        Some(idx) => idx,
+                        None => {
+                            groups.push(GroupRules {
+                                name: name.to_owned(),
+                                ipv4: IpRange::new(),
+                                ipv6: IpRange::new(),
+                                rules: Vec::new(),
+                                geoip: Vec::new(),
+                                processes: Vec::new(),
+                            });
+                            groups.len() - 1
+                        }
+                    };
+
+                    let group = &mut groups[idx];
+                    curr_ipv4 = &mut group.ipv4;
+                    curr_ipv6 = &mut group.ipv6;
+                    curr_rules = &mut group.rules;
+                    curr_geoip = &mut group.geoip;
+                    curr_udp = None;
+                    curr_processes = None;
+                }
+                section if section.starts_with("[connect_policy:") && section.ends_with(']') => {
+                    let params = &section["[connect_policy:".len()..section.len() - 1];
+
+                    let policy = match ConnectPolicy::parse(params) {
+                        Some(p) => p,
+                        None => {
+                            let err = Error::new(ErrorKind::Other, format!("invalid section \"{}\"", section));
+                            return Err(err);
+                        }
+                    };
+
+                    policies.push((
+                        policy,
+                        GroupRules {
+                            name: params.to_owned(),
+                            ipv4: IpRange::new(),
+                            ipv6: IpRange::new(),
+                            rules: Vec::new(),
+                            geoip: Vec::new(),
+                            processes: Vec::new(),
+                        },
+                    ));
+
+                    let (_, group) = policies.last_mut().expect("pushed connect policy");
+                    curr_ipv4 = &mut group.ipv4;
+                    curr_ipv6 = &mut group.ipv6;
+                    curr_rules = &mut group.rules;
+                    curr_geoip = &mut group.geoip;
+                    curr_udp = None;
+                    curr_processes = None;
+                }
+                section if section.starts_with("[route:") && section.ends_with(']') => {
+                    let target = &section["[route:".len()..section.len() - 1];
+
+                    // `[route:TARGET@WINDOW]` only applies during the time window
+                    let (route, window) = match target.find('@') {
+                        Some(pos) => (
+                            RouteTarget::parse(&target[..pos]),
+                            TimeWindow::parse(&target[pos + 1..]).map(Some),
+                        ),
+                        None => (RouteTarget::parse(target), Some(None)),
+                    };
+                    let (route, window) = match (route, window) {
+                        (Some(r), Some(w)) => (r, w),
+                        _ => {
+                            let err = Error::new(ErrorKind::Other, format!("invalid section \"{}\"", section));
+                            return Err(err);
+                        }
+                    };
+
+                    routes.push((
+                        route,
+                        window,
+                        GroupRules {
+                            name: target.to_owned(),
+                            ipv4: IpRange::new(),
+                            ipv6: IpRange::new(),
+                            rules: Vec::new(),
+                            geoip: Vec::new(),
+                            processes: Vec::new(),
+                        },
+                    ));
+
+                    let (_, _, group) = routes.last_mut().expect("pushed route");
+                    curr_ipv4 = &mut group.ipv4;
+                    curr_ipv6 = &mut group.ipv6;
+                    curr_rules = &mut group.rules;
+                    curr_geoip = &mut group.geoip;
+                    curr_udp = None;
+                    curr_processes = Some(&mut group.processes);
+                }
+                section if section.starts_with("[dns:") && section.ends_with(']') => {
+                    let upstream = &section["[dns:".len()..section.len() - 1];
+                    check_dns_upstream(upstream)?;
+
+                    dns_upstreams.push(GroupRules {
+                        name: upstream.to_owned(),
+                        ipv4: IpRange::new(),
+                        ipv6: IpRange::new(),
+                        rules: Vec::new(),
+                        geoip: Vec::new(),
+                        processes: Vec::new(),
+                    });
+
+                    // Only domain name rules are matched, IP rules are accepted but never match
+                    let group = dns_upstreams.last_mut().expect("pushed DNS upstream");
+                    curr_ipv4 = &mut group.ipv4;
+                    curr_ipv6 = &mut group.ipv6;
+                    curr_rules = &mut group.rules;
+                    curr_geoip = &mut group.geoip;
+                    curr_udp = None;
+                    curr_processes = None;
+                }
+                _ if geoip::parse_rule(&line).is_some() => {
+                    if geoip_db.is_none() {
+                        let err = Error::new(
+                            ErrorKind::Other,
+                            format!("GeoIP rule \"{}\" requires a GeoIP database", line),
+                        );
+                        return Err(err);
+                    }
+
+                    match geoip::parse_rule(&line) {
+                        Some(Ok(country)) => curr_geoip.push(country),
+                        _ => {
+                            let err = Error::new(ErrorKind::Other, format!("invalid GeoIP rule \"{}\"", line));
+                            return Err(err);
+                        }
+                    }
+                }
+                _ if geosite::parse_rule(&line).is_some() => {
+                    let (category, attribute) = match geosite::parse_rule(&line) {
+                        Some(Ok(r)) => r,
+                        _ => {
+                            let err = Error::new(ErrorKind::Other, format!("invalid geosite rule \"{}\"", line));
+                            return Err(err);
+                        }
+                    };
+
+                    if geosite_db.is_none() {
+                        let path = match databases.geosite {
+                            Some(ref path) => path,
+                            None => {
+                                let err = Error::new(
+                                    ErrorKind::Other,
+                                    format!("geosite rule \"{}\" requires a geosite database", line),
+                                );
+                                return Err(err);
+                            }
+                        };
+                        geosite_db = Some(GeoSiteDatabase::open(path)?);
+                    }
+
+                    let db = geosite_db.as_ref().expect("geosite database loaded");
+                    match db.category_rules(&category, attribute.as_deref()) {
+                        Some(rules) => curr_rules.extend(rules?),
+                        None => {
+                            let err = Error::new(
+                                ErrorKind::Other,
+                                format!("geosite category \"{}\" is not found", category),
+                            );
+                            return Err(err);
+                        }
+                    }
+                }
+                _ if line.starts_with("process:") => {
+                    let processes = match curr_processes {
+                        Some(ref mut processes) => processes,
+                        None => {
+                            let err = Error::new(
+                                ErrorKind::Other,
+                                format!("process rule \"{}\" is only allowed in [route:TARGET] sections", line),
+                            );
+                            return Err(err);
+                        }
+                    };
+
+                    match normalize_process_name(&line["process:".len()..]) {
+                        Some(name) => processes.push(name),
+                        None => {
+                            let err = Error::new(ErrorKind::Other, format!("invalid process rule \"{}\"", line));
+                            return Err(err);
+                        }
+                    }
+                }
+                _ if UdpRule::parse(&line).is_some() => {
+                    let udp = match curr_udp {
+                        Some(ref mut udp) => udp,
+                        None => {
+                            let err = Error::new(
+                                ErrorKind::Other,
+                                format!(
+                                    "UDP rule \"{}\" is only allowed in [bypass_list] and [proxy_list]",
+                                    line
+                                ),
+                            );
+                            return Err(err);
+                        }
+                    };
+
+                    match UdpRule::parse(&line) {
+                        Some(Ok(rule)) => udp.push(rule),
+                        _ => {
+                            let err = Error::new(ErrorKind::Other, format!("invalid UDP rule \"{}\"", line));
+                            return Err(err);
+                        }
+                    }
                 }
                 _ => {
                     match line.parse::<IpNet>() {
@@ -226,7 +784,10 @@ impl AccessControl {
                                 }
                                 Err(..) => {
                                     // FIXME: If this line is not a valid regex, how can we know without actually compile it?
-                                    curr_rules.push(line);
+                                    match domain::rule_to_regex(&line) {
+                                        Ok(rule) => curr_rules.push(rule),
+                                        Err(err) => return Err(Error::new(ErrorKind::Other, err)),
+                                    }
                                 }
                             }
                         }
@@ -235,12 +796,7 @@ impl AccessControl {
             }
         }
 
-        const REGEX_SIZE_LIMIT: usize = usize::max_value();
-
-        let outbound_block_regex = match RegexSetBuilder::new(outbound_block_rules)
-            .size_limit(REGEX_SIZE_LIMIT)
-            .build()
-        {
+        let outbound_block_regex = match HostRules::build(outbound_block_rules) {
             Ok(r) => r,
             Err(err) => {
                 let err = Error::new(ErrorKind::Other, format!("[outbound_block_list] regex error: {}", err));
@@ -248,7 +804,22 @@ impl AccessControl {
             }
         };
 
-        let bypass_regex = match RegexSetBuilder::new(bypass_rules).size_limit(REGEX_SIZE_LIMIT).build() {
+        let outbound_allow = if has_outbound_allow {
+            let regex = match HostRules::build(outbound_allow_rules) {
+                Ok(r) => r,
+                Err(err) => {
+                    let err = Error::new(ErrorKind::Other, format!("[outbound_allow_list] regex error: {}", err));
+                    return Err(err);
+                }
+            };
+            let rules = Rules::new(outbound_allow_ipv4, outbound_allow_ipv6, regex, Vec::new())
+                .with_geoip(outbound_allow_geoip, geoip_db.clone());
+            Some(rules)
+        } else {
+            None
+        };
+
+        let bypass_regex = match HostRules::build(bypass_rules) {
             Ok(r) => r,
             Err(err) => {
                 let err = Error::new(
@@ -259,7 +830,7 @@ impl AccessControl {
             }
         };
 
-        let proxy_regex = match RegexSetBuilder::new(proxy_rules).size_limit(REGEX_SIZE_LIMIT).build() {
+        let proxy_regex = match HostRules::build(proxy_rules) {
             Ok(r) => r,
             Err(err) => {
                 let err = Error::new(
@@ -270,14 +841,99 @@ impl AccessControl {
             }
         };
 
+        let mut group_rules = Vec::with_capacity(groups.len());
+        for group in groups {
+            let regex = match HostRules::build(group.rules) {
+                Ok(r) => r,
+                Err(err) => {
+                    let err = Error::new(
+                        ErrorKind::Other,
+                        format!("[proxy_group:{}] regex error: {}", group.name, err),
+                    );
+                    return Err(err);
+                }
+            };
+            let rules = Rules::new(group.ipv4, group.ipv6, regex, Vec::new()).with_geoip(group.geoip, geoip_db.clone());
+            group_rules.push((group.name, rules));
+        }
+
+        let mut connect_policies = Vec::with_capacity(policies.len());
+        for (policy, group) in policies {
+            let regex = match HostRules::build(group.rules) {
+                Ok(r) => r,
+                Err(err) => {
+                    let err = Error::new(
+                        ErrorKind::Other,
+                        format!("[connect_policy:{}] regex error: {}", group.name, err),
+                    );
+                    return Err(err);
+                }
+            };
+            let rules = Rules::new(group.ipv4, group.ipv6, regex, Vec::new()).with_geoip(group.geoip, geoip_db.clone());
+            connect_policies.push((policy, rules));
+        }
+
+        let mut route_rules = Vec::with_capacity(routes.len());
+        for (route, window, group) in routes {
+            let regex = match HostRules::build(group.rules) {
+                Ok(r) => r,
+                Err(err) => {
+                    let err = Error::new(ErrorKind::Other, format!("[route:{}] regex error: {}", group.name, err));
+                    return Err(err);
+                }
+            };
+            let rules = Rules::new(group.ipv4, group.ipv6, regex, Vec::new())
+                .with_geoip(group.geoip, geoip_db.clone())
+                .with_window(window)
+                .with_processes(group.processes);
+            route_rules.push((route, rules));
+        }
+
+        let mut dns_upstream_rules = Vec::with_capacity(dns_upstreams.len());
+        for group in dns_upstreams {
+            let regex = match HostRules::build(group.rules) {
+                Ok(r) => r,
+                Err(err) => {
+                    let err = Error::new(ErrorKind::Other, format!("[dns:{}] regex error: {}", group.name, err));
+                    return Err(err);
+                }
+            };
+            dns_upstream_rules.push((group.name, Rules::new(group.ipv4, group.ipv6, regex, Vec::new())));
+        }
+
         Ok(AccessControl {
-            outbound_block: Rules::new(outbound_block_ipv4, outbound_block_ipv6, outbound_block_regex),
-            black_list: Rules::new(bypass_ipv4, bypass_ipv6, bypass_regex),
-            white_list: Rules::new(proxy_ipv4, proxy_ipv6, proxy_regex),
+            outbound_block: Rules::new(
+                outbound_block_ipv4,
+                outbound_block_ipv6,
+                outbound_block_regex,
+                Vec::new(),
+            )
+            .with_geoip(outbound_block_geoip, geoip_db.clone()),
+            outbound_allow,
+            black_list: Rules::new(bypass_ipv4, bypass_ipv6, bypass_regex, bypass_udp)
+                .with_geoip(bypass_geoip, geoip_db.clone()),
+            white_list: Rules::new(proxy_ipv4, proxy_ipv6, proxy_regex, proxy_udp)
+                .with_geoip(proxy_geoip, geoip_db.clone()),
+            groups: group_rules,
+            connect_policies,
+            routes: route_rules,
+            dns_upstreams: dns_upstream_rules,
             mode,
+            file_path,
+            databases: databases.clone(),
         })
     }
 
+    /// Databases that rules are matched by
+    pub fn databases(&self) -> &RuleDatabases {
+        &self.databases
+    }
+
+    /// Path of the file that rules are loaded from
+    pub fn file_path(&self) -> &Path {
+        &self.file_path
+    }
+
     /// Check if domain name is in proxy_list.
     /// If so, it should be resolved from remote (for Android's DNS relay)
     ///
@@ -334,6 +990,12 @@ impl AccessControl {
     ///
     /// This function may perform a DNS resolution
     pub async fn check_target_bypassed(&self, context: &Context, addr: &Address) -> bool {
+        match self.check_target_route(addr) {
+            Some(RouteTarget::Direct) => return true,
+            Some(RouteTarget::Proxy(..)) => return false,
+            _ => {}
+        }
+
         match *addr {
             Address::SocketAddress(ref addr) => !self.check_ip_in_proxy_list(&addr.ip()),
             // Resolve hostname and check the list
@@ -356,6 +1018,206 @@ impl AccessControl {
         }
     }
 
+    /// Explain how connections to target address are handled, and which rule decided it (for client)
+    ///
+    /// Follows `check_target_route`, `check_target_bypassed` and `check_target_group`,
+    /// so this function may perform a DNS resolution
+    pub async fn explain_target(&self, context: &Context, addr: &Address) -> Explanation {
+        for (route, rules) in &self.routes {
+            if let Some(rule) = rules.matched_rule(addr) {
+                let section = match rules.window {
+                    Some(ref window) => format!("[route:{}@{}]", route, window),
+                    None => format!("[route:{}]", route),
+                };
+                return Explanation {
+                    target: route.clone(),
+                    section,
+                    rule: Some(rule),
+                    resolved: None,
+                };
+            }
+        }
+
+        let mut explanation = match *addr {
+            Address::SocketAddress(ref saddr) => self.explain_ip(&saddr.ip()),
+            Address::DomainNameAddress(ref host, port) => {
+                if let Some(rule) = self.white_list.matched_host_rule(host) {
+                    self.explanation(true, "[proxy_list]", Some(rule))
+                } else if let Some(rule) = self.black_list.matched_host_rule(host) {
+                    self.explanation(false, "[bypass_list]", Some(rule))
+                } else if self.is_ip_empty() {
+                    self.explanation(self.is_default_in_proxy_list(), "", None)
+                } else {
+                    // Bypassed if any of the resolved addresses is bypassed, like `check_target_bypassed`
+                    let mut explanation = self.explanation(true, "", None);
+                    if let Ok(vaddr) = context.dns_resolve(host, port).await {
+                        for addr in vaddr {
+                            let mut e = self.explain_ip(&addr.ip());
+                            e.resolved = Some(addr.ip());
+                            if e.target == RouteTarget::Direct {
+                                explanation = e;
+                                break;
+                            } else if explanation.resolved.is_none() {
+                                explanation = e;
+                            }
+                        }
+                    }
+                    explanation
+                }
+            }
+        };
+
+        if explanation.target == RouteTarget::Proxy(None) {
+            if let Some(group) = self.check_target_group(addr) {
+                explanation.target = RouteTarget::Proxy(Some(group.to_owned()));
+            }
+        }
+
+        explanation
+    }
+
+    fn explain_ip(&self, ip: &IpAddr) -> Explanation {
+        match self.mode {
+            Mode::BlackList => {
+                let rule = self.black_list.matched_ip_rule(ip);
+                self.explanation(rule.is_none(), "[bypass_list]", rule)
+            }
+            Mode::WhiteList => {
+                let rule = self.white_list.matched_ip_rule(ip);
+                self.explanation(rule.is_some(), "[proxy_list]", rule)
+            }
+        }
+    }
+
+    /// Explanation in `section` if `rule` matched, otherwise decided by the mode
+    fn explanation(&self, proxied: bool, section: &str, rule: Option<String>) -> Explanation {
+        let section = match (&rule, self.mode) {
+            (Some(..), _) => section.to_owned(),
+            (None, Mode::BlackList) => "[proxy_all]".to_owned(),
+            (None, Mode::WhiteList) => "[bypass_all]".to_owned(),
+        };
+
+        Explanation {
+            target: if proxied {
+                RouteTarget::Proxy(None)
+            } else {
+                RouteTarget::Direct
+            },
+            section,
+            rule,
+            resolved: None,
+        }
+    }
+
+    /// If there are UDP flow rules
+    pub fn has_udp_rules(&self) -> bool {
+        !self.black_list.udp.is_empty() || !self.white_list.udp.is_empty()
+    }
+
+    /// Check if UDP flow should be bypassed by UDP flow rules, with the first packet of the flow (for client)
+    ///
+    /// Return
+    /// - `Some(true)` if the flow matches `[bypass_list]` (should be bypassed)
+    /// - `Some(false)` if the flow matches `[proxy_list]` (should be proxied)
+    /// - `None` if the flow doesn't match any UDP rules, it should be checked by the target address
+    pub fn check_udp_flow_bypassed(&self, target: &Address, payload: &[u8]) -> Option<bool> {
+        if !self.has_udp_rules() {
+            return None;
+        }
+
+        let port = target.port();
+        let protocol = UdpProtocol::detect(port, payload);
+
+        // Flows in proxy_list will be proxied
+        if self.white_list.check_udp_matched(port, protocol) {
+            return Some(false);
+        }
+        // Flows in bypass_list will be bypassed
+        if self.black_list.check_udp_matched(port, protocol) {
+            return Some(true);
+        }
+        None
+    }
+
+    /// Names of server groups that have rules
+    pub fn group_names(&self) -> impl Iterator<Item = &str> {
+        let routed = self.routes.iter().filter_map(|(route, _)| match *route {
+            RouteTarget::Proxy(Some(ref name)) => Some(name.as_str()),
+            _ => None,
+        });
+        self.groups.iter().map(|(name, _)| name.as_str()).chain(routed)
+    }
+
+    /// Find the route of target address by `[route:TARGET]` sections (for client)
+    ///
+    /// The first section that matches wins. Domain names are only matched by regex rules.
+    pub fn check_target_route(&self, addr: &Address) -> Option<&RouteTarget> {
+        self.routes
+            .iter()
+            .find(|(_, rules)| rules.check_address_matched(addr))
+            .map(|(route, _)| route)
+    }
+
+    /// If there are `process:NAME` rules
+    pub fn has_process_rules(&self) -> bool {
+        self.routes.iter().any(|(_, rules)| !rules.processes.is_empty())
+    }
+
+    /// Find the route of connections from process `name` by `process:NAME` rules in `[route:TARGET]` sections
+    /// (for client)
+    ///
+    /// The first section that matches wins, checked before the target address
+    pub fn check_process_route(&self, name: &str) -> Option<&RouteTarget> {
+        let name = normalize_process_name(name)?;
+        self.routes
+            .iter()
+            .find(|(_, rules)| rules.check_process_matched(&name))
+            .map(|(route, _)| route)
+    }
+
+    /// Check if connections to target address should be refused by `[route:reject]` sections (for client)
+    pub fn check_target_rejected(&self, addr: &Address) -> bool {
+        matches!(self.check_target_route(addr), Some(RouteTarget::Reject))
+    }
+
+    /// Find the server group for proxying target address (for client)
+    ///
+    /// `[route:proxy:NAME]` sections are checked first, then the first `[proxy_group:NAME]` section that matches wins.
+    /// Domain names are only matched by regex rules.
+    pub fn check_target_group(&self, addr: &Address) -> Option<&str> {
+        if let Some(route) = self.check_target_route(addr) {
+            return match *route {
+                RouteTarget::Proxy(Some(ref name)) => Some(name.as_str()),
+                _ => None,
+            };
+        }
+
+        self.groups
+            .iter()
+            .find(|(_, rules)| rules.check_address_matched(addr))
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Find the policy for connecting to outbound address
+    ///
+    /// The first `[connect_policy:...]` section that matches wins. Domain names are only matched by regex rules.
+    pub fn check_connect_policy(&self, addr: &Address) -> Option<ConnectPolicy> {
+        self.connect_policies
+            .iter()
+            .find(|(_, rules)| rules.check_address_matched(addr))
+            .map(|(policy, _)| *policy)
+    }
+
+    /// Find the upstream resolving `host`, in the same format as `dns`
+    ///
+    /// The first `[dns:UPSTREAM]` section that matches wins, `None` if `host` is resolved by `dns`
+    pub fn check_dns_upstream(&self, host: &str) -> Option<&str> {
+        self.dns_upstreams
+            .iter()
+            .find(|(_, rules)| rules.check_host_matched(host))
+            .map(|(upstream, _)| upstream.as_str())
+    }
+
     /// Check if client address should be blocked (for server)
     pub fn check_client_blocked(&self, addr: &SocketAddr) -> bool {
         match self.mode {
@@ -372,9 +1234,14 @@ impl AccessControl {
 
     /// Check if outbound address is blocked (for server)
     ///
-    /// NOTE: `Address::DomainName` is only validated by regex rules,
-    ///       resolved addresses are checked in the `lookup_outbound_then!` macro
+    /// NOTE: `Address::DomainName` is checked by domain rules, then its resolved addresses by IP rules
     pub async fn check_outbound_blocked(&self, context: &Context, outbound: &Address) -> bool {
+        if let Some(ref allow) = self.outbound_allow {
+            if !self.check_outbound_allowed(allow, context, outbound).await {
+                return true;
+            }
+        }
+
         match outbound {
             Address::SocketAddress(saddr) => self.outbound_block.check_ip_matched(&saddr.ip()),
             Address::DomainNameAddress(host, port) => {
@@ -394,4 +1261,35 @@ impl AccessControl {
             }
         }
     }
+
+    /// Check if outbound address is in `[outbound_allow_list]`
+    async fn check_outbound_allowed(&self, allow: &Rules, context: &Context, outbound: &Address) -> bool {
+        match outbound {
+            Address::SocketAddress(saddr) => allow.check_ip_matched(&saddr.ip()),
+            Address::DomainNameAddress(host, port) => {
+                if allow.check_host_matched(host) {
+                    return true;
+                }
+
+                if allow.is_ip_empty() {
+                    return false;
+                }
+
+                // Allowed only if all resolved addresses are allowed
+                match context.dns_resolve(host, *port).await {
+                    Ok(vaddr) => {
+                        let mut resolved = false;
+                        for addr in vaddr {
+                            if !allow.check_ip_matched(&addr.ip()) {
+                                return false;
+                            }
+                            resolved = true;
+                        }
+                        resolved
+                    }
+                    Err(..) => false,
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,149 @@
+//! Proxy auto-config (PAC) script generated from rules
+//!
+//! Only domain name rules of `[proxy_list]` and `[bypass_list]`, and IPv4 rules for hosts in IPv4 addresses are
+//! translated. Browsers couldn't resolve host names without blocking, so hosts that don't match any domain name
+//! rules take the default of the ACL's mode. Regular expressions that are not compatible with JavaScript are
+//! skipped.
+
+use std::fmt::Write;
+
+use log::debug;
+
+use super::{domain::HostRules, AccessControl, Mode, Rules};
+
+const PAC_FUNCTIONS: &str = r#"
+function matchHost(rules, host) {
+    if (rules.full.hasOwnProperty(host)) {
+        return true;
+    }
+    var suffix = host;
+    while (true) {
+        if (rules.suffixes.hasOwnProperty(suffix)) {
+            return true;
+        }
+        var pos = suffix.indexOf(".");
+        if (pos < 0) {
+            break;
+        }
+        suffix = suffix.substring(pos + 1);
+    }
+    for (var i = 0; i < rules.regex.length; i++) {
+        if (rules.regex[i].test(host)) {
+            return true;
+        }
+    }
+    return false;
+}
+
+function matchIp(rules, host) {
+    for (var i = 0; i < rules.ipv4.length; i++) {
+        if (isInNet(host, rules.ipv4[i][0], rules.ipv4[i][1])) {
+            return true;
+        }
+    }
+    return false;
+}
+
+function FindProxyForURL(url, host) {
+    host = host.toLowerCase();
+    if (matchHost(proxyList, host)) {
+        return proxy;
+    }
+    if (matchHost(bypassList, host)) {
+        return "DIRECT";
+    }
+    if (/^\d+\.\d+\.\d+\.\d+$/.test(host)) {
+        if (defaultProxied) {
+            return matchIp(bypassList, host) ? "DIRECT" : proxy;
+        }
+        return matchIp(proxyList, host) ? proxy : "DIRECT";
+    }
+    return defaultProxied ? proxy : "DIRECT";
+}
+"#;
+
+/// Check if a regular expression means the same in JavaScript
+///
+/// Inline flags, named groups and Unicode classes of Rust's `regex` are not supported
+fn is_js_compatible(pattern: &str) -> bool {
+    let mut rest = pattern;
+    while let Some(pos) = rest.find("(?") {
+        if !rest[pos + 2..].starts_with(':') {
+            return false;
+        }
+        rest = &rest[pos + 2..];
+    }
+    !pattern.contains("\\p") && !pattern.contains("\\P") && !pattern.contains("\\A") && !pattern.contains("\\z")
+}
+
+fn js_string(s: &str) -> String {
+    serde_json::to_string(s).expect("serialize string")
+}
+
+fn write_host_set<'a, I: Iterator<Item = &'a str>>(out: &mut String, hosts: I) {
+    let mut hosts = hosts.collect::<Vec<_>>();
+    hosts.sort_unstable();
+
+    out.push('{');
+    for (idx, host) in hosts.iter().enumerate() {
+        if idx > 0 {
+            out.push_str(", ");
+        }
+        let _ = write!(out, "{}: 1", js_string(host));
+    }
+    out.push('}');
+}
+
+fn write_rules(out: &mut String, name: &str, rules: &Rules) {
+    let host_rules: &HostRules = &rules.rule;
+
+    let _ = write!(out, "var {} = {{\n    suffixes: ", name);
+    write_host_set(out, host_rules.suffixes());
+    out.push_str(",\n    full: ");
+    write_host_set(out, host_rules.full_domains());
+
+    out.push_str(",\n    regex: [");
+    let mut skipped = 0;
+    let mut first = true;
+    for pattern in host_rules.patterns() {
+        if !is_js_compatible(pattern) {
+            skipped += 1;
+            continue;
+        }
+        if !first {
+            out.push_str(", ");
+        }
+        first = false;
+        let _ = write!(out, "new RegExp({})", js_string(pattern));
+    }
+    if skipped > 0 {
+        debug!(
+            "PAC skipped {} regular expressions of {} not supported by JavaScript",
+            skipped, name
+        );
+    }
+
+    out.push_str("],\n    ipv4: [");
+    for (idx, net) in rules.ipv4.iter().enumerate() {
+        if idx > 0 {
+            out.push_str(", ");
+        }
+        let _ = write!(out, "[\"{}\", \"{}\"]", net.network(), net.netmask());
+    }
+    out.push_str("]\n};\n");
+}
+
+impl AccessControl {
+    /// Generate a PAC script, hosts that should be proxied go to `proxy` (like `SOCKS5 127.0.0.1:1080`)
+    pub fn pac_script(&self, proxy: &str) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(out, "var proxy = {};", js_string(proxy));
+        let _ = writeln!(out, "var defaultProxied = {};", self.mode == Mode::BlackList);
+        write_rules(&mut out, "proxyList", &self.white_list);
+        write_rules(&mut out, "bypassList", &self.black_list);
+        out.push_str(PAC_FUNCTIONS);
+
+        out
+    }
+}
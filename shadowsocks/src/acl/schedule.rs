@@ -0,0 +1,113 @@
+//! Time windows of sections, rules only apply during the window in local time
+//!
+//! Windows are formatted as comma separated days and an optional time range, like `mon-fri,09:00-17:00`,
+//! `sat,sun`, or `22:00-06:00` (crossing midnight, every day).
+
+use std::fmt;
+
+use chrono::{Datelike, Local, Timelike};
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// Local time window, like `mon-fri,09:00-17:00`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TimeWindow {
+    /// Bit 0 is Monday, bit 6 is Sunday
+    days: u8,
+    /// Minutes since midnight, `start > end` if the window crosses midnight
+    start: u32,
+    end: u32,
+    spec: String,
+}
+
+impl TimeWindow {
+    /// Parse a window like `mon-fri,09:00-17:00`
+    pub fn parse(spec: &str) -> Option<TimeWindow> {
+        let mut days = 0u8;
+        let mut range = None;
+
+        for item in spec.split(',').map(str::trim) {
+            if item.contains(':') {
+                if range.is_some() {
+                    return None;
+                }
+                let mut sp = item.splitn(2, '-');
+                let start = parse_minutes(sp.next()?)?;
+                let end = parse_minutes(sp.next()?)?;
+                if start == end || start >= MINUTES_PER_DAY {
+                    return None;
+                }
+                range = Some((start, end));
+            } else {
+                let mut sp = item.splitn(2, '-');
+                let first = parse_day(sp.next()?)?;
+                let last = match sp.next() {
+                    Some(d) => parse_day(d)?,
+                    None => first,
+                };
+                // Ranges like `sat-mon` wrap around the week
+                let mut day = first;
+                loop {
+                    days |= 1 << day;
+                    if day == last {
+                        break;
+                    }
+                    day = (day + 1) % 7;
+                }
+            }
+        }
+
+        if days == 0 && range.is_none() {
+            return None;
+        }
+
+        let (start, end) = range.unwrap_or((0, MINUTES_PER_DAY));
+        Some(TimeWindow {
+            days: if days == 0 { 0x7F } else { days },
+            start,
+            end,
+            spec: spec.to_owned(),
+        })
+    }
+
+    /// Check if the current local time is in the window
+    pub fn is_active(&self) -> bool {
+        let now = Local::now();
+        self.contains(now.weekday().num_days_from_monday(), now.hour() * 60 + now.minute())
+    }
+
+    /// Check if the `minute` of `day` (0 is Monday) is in the window
+    fn contains(&self, day: u32, minute: u32) -> bool {
+        let has_day = |day: u32| self.days & (1 << day) != 0;
+
+        if self.start < self.end {
+            has_day(day) && self.start <= minute && minute < self.end
+        } else {
+            // Crossing midnight, the part after midnight belongs to the previous day
+            (has_day(day) && minute >= self.start) || (has_day((day + 6) % 7) && minute < self.end)
+        }
+    }
+}
+
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.spec)
+    }
+}
+
+fn parse_day(day: &str) -> Option<u32> {
+    let day = day.trim().to_ascii_lowercase();
+    DAYS.iter().position(|d| *d == day).map(|d| d as u32)
+}
+
+/// `HH:MM` in minutes since midnight, `24:00` is the end of a day
+fn parse_minutes(time: &str) -> Option<u32> {
+    let mut sp = time.trim().splitn(2, ':');
+    let hour: u32 = sp.next()?.parse().ok()?;
+    let minute: u32 = sp.next()?.parse().ok()?;
+    if minute >= 60 || hour * 60 + minute > MINUTES_PER_DAY {
+        return None;
+    }
+    Some(hour * 60 + minute)
+}
@@ -0,0 +1,125 @@
+//! Rules for classifying UDP flows
+//!
+//! UDP flows are matched by destination port, or by the protocol detected from the first packet of the flow
+
+use std::{fmt, str::FromStr};
+
+use byteorder::{BigEndian, ByteOrder};
+
+/// Protocols that could be detected from a UDP packet
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum UdpProtocol {
+    /// DNS query
+    Dns,
+    /// QUIC, detected by long header packets (Initial, ...)
+    Quic,
+    /// STUN (RFC 5389), used by WebRTC for NAT traversal
+    Stun,
+}
+
+impl UdpProtocol {
+    /// Detect protocol of a packet sent to `port`
+    pub fn detect(port: u16, payload: &[u8]) -> Option<UdpProtocol> {
+        if is_stun(payload) {
+            Some(UdpProtocol::Stun)
+        } else if is_quic(payload) {
+            Some(UdpProtocol::Quic)
+        } else if is_dns(port, payload) {
+            Some(UdpProtocol::Dns)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for UdpProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UdpProtocol::Dns => f.write_str("dns"),
+            UdpProtocol::Quic => f.write_str("quic"),
+            UdpProtocol::Stun => f.write_str("stun"),
+        }
+    }
+}
+
+impl FromStr for UdpProtocol {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dns" => Ok(UdpProtocol::Dns),
+            "quic" => Ok(UdpProtocol::Quic),
+            "stun" | "webrtc" => Ok(UdpProtocol::Stun),
+            _ => Err(()),
+        }
+    }
+}
+
+/// STUN message: 20 bytes header, leading 2 bits are zero, magic cookie, length of attributes matches
+fn is_stun(payload: &[u8]) -> bool {
+    const STUN_MAGIC_COOKIE: u32 = 0x2112_A442;
+
+    payload.len() >= 20
+        && payload[0] & 0xC0 == 0
+        && BigEndian::read_u32(&payload[4..8]) == STUN_MAGIC_COOKIE
+        && BigEndian::read_u16(&payload[2..4]) as usize + 20 == payload.len()
+}
+
+/// QUIC long header: header form and fixed bits are set, followed by a known version
+fn is_quic(payload: &[u8]) -> bool {
+    if payload.len() < 5 || payload[0] & 0xC0 != 0xC0 {
+        return false;
+    }
+
+    match BigEndian::read_u32(&payload[1..5]) {
+        // Version 1 (RFC 9000), version 2 (RFC 9369)
+        0x0000_0001 | 0x6b33_43cf => true,
+        // IETF drafts
+        v if v & 0xFFFF_FF00 == 0xFF00_0000 => true,
+        _ => false,
+    }
+}
+
+/// DNS query to the standard port: QR bit is clear and there is at least one question
+fn is_dns(port: u16, payload: &[u8]) -> bool {
+    port == 53 && payload.len() >= 12 && payload[2] & 0x80 == 0 && BigEndian::read_u16(&payload[4..6]) > 0
+}
+
+/// A rule for matching UDP flows
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum UdpRule {
+    /// Destination port in range (inclusive)
+    Port(u16, u16),
+    /// Detected protocol
+    Protocol(UdpProtocol),
+}
+
+impl UdpRule {
+    /// Parse rule lines like `port:443`, `port:3478-3481` or `proto:quic`
+    ///
+    /// Returns `None` if the line is not a UDP rule, `Some(Err(..))` if it is malformed
+    pub fn parse(line: &str) -> Option<Result<UdpRule, ()>> {
+        if let Some(ports) = line.strip_prefix("port:") {
+            let rule = match ports.find('-') {
+                Some(pos) => match (ports[..pos].parse::<u16>(), ports[pos + 1..].parse::<u16>()) {
+                    (Ok(start), Ok(end)) if start <= end => Ok(UdpRule::Port(start, end)),
+                    _ => Err(()),
+                },
+                None => ports.parse::<u16>().map(|p| UdpRule::Port(p, p)).map_err(|_| ()),
+            };
+            Some(rule)
+        } else if let Some(proto) = line.strip_prefix("proto:") {
+            Some(proto.parse::<UdpProtocol>().map(UdpRule::Protocol))
+        } else {
+            None
+        }
+    }
+
+    /// Check if the flow to `port`, with `protocol` detected from its first packet, matches this rule
+    pub fn matches(&self, port: u16, protocol: Option<UdpProtocol>) -> bool {
+        match *self {
+            UdpRule::Port(start, end) => start <= port && port <= end,
+            UdpRule::Protocol(p) => protocol == Some(p),
+        }
+    }
+}
@@ -42,35 +42,49 @@
 //! These defined server will be used with a load balancing algorithm.
 
 use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashSet},
     convert::{From, Infallible},
     default::Default,
     error,
     fmt::{self, Debug, Display, Formatter},
     fs::OpenOptions,
+    hash::{Hash, Hasher},
     io::{self, Read},
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
     option::Option,
     path::{Path, PathBuf},
     str::FromStr,
     string::ToString,
-    time::Duration,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use base64::{decode_config, encode_config, URL_SAFE_NO_PAD};
 use bytes::Bytes;
 use cfg_if::cfg_if;
+#[cfg(feature = "local-tun")]
+use ipnet::Ipv4Net;
+use ipnet::{IpNet, Ipv6Net};
+use lazy_static::lazy_static;
 use log::error;
+use lru_time_cache::LruCache;
 use serde::{Deserialize, Serialize};
+use spin::Mutex as SpinMutex;
 #[cfg(feature = "trust-dns")]
 use trust_dns_resolver::config::{NameServerConfig, Protocol, ResolverConfig};
 use url::{self, Url};
 
+#[cfg(windows)]
+use crate::relay::sys::NAMED_PIPE_PREFIX;
 use crate::{
-    acl::AccessControl,
+    acl::{AccessControl, RuleDatabases},
     context::Context,
-    crypto::v1::{openssl_bytes_to_key, CipherKind},
+    crypto::v1::{openssl_bytes_to_key, CipherCategory, CipherKind},
     plugin::PluginConfig,
-    relay::{dns_resolver::resolve_bind_addr, socks5::Address},
+    relay::{dns_resolver::resolve_bind_addr, socks5::Address, speed_limit::SpeedLimiter},
 };
 
 #[cfg(feature = "trust-dns")]
@@ -78,9 +92,18 @@ use crate::{
 #[serde(untagged)]
 enum SSDnsConfig {
     Simple(String),
+    /// Upstreams with their own resolvers, in order of failover
+    Multiple(Vec<SSDnsConfig>),
     TrustDns(ResolverConfig),
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+enum SSHostsValue {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 struct SSConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -91,13 +114,58 @@ struct SSConfig {
     local_address: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     local_port: Option<u16>,
+    #[cfg(feature = "local-tunnel")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tunnels: Option<Vec<SSTunnelConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    socks5_auth: Option<SSLocalAuthConfig>,
+    #[cfg(feature = "local-http")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    http_auth: Option<SSLocalAuthConfig>,
     #[serde(skip_serializing_if = "Option::is_none")]
     manager_address: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     manager_port: Option<u16>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    manager_drain_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manager_secret: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manager_events_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manager_events_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manager_federation_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manager_upstream: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manager_node_name: Option<String>,
+    #[cfg(feature = "manager-http")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manager_http_address: Option<String>,
+    #[cfg(feature = "manager-http")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manager_http_token: Option<String>,
+    #[cfg(feature = "manager-tls")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manager_tls_address: Option<String>,
+    #[cfg(feature = "manager-tls")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manager_tls_cert: Option<String>,
+    #[cfg(feature = "manager-tls")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manager_tls_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     password: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    previous_password: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rotate_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fallback_passwords: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kdf: Option<SSKdfConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     method: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     plugin: Option<String>,
@@ -112,18 +180,110 @@ struct SSConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     udp_max_associations: Option<usize>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    udp_egress_pool_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     servers: Option<Vec<SSServerExtConfig>>,
     #[cfg(feature = "trust-dns")]
     #[serde(skip_serializing_if = "Option::is_none")]
     dns: Option<SSDnsConfig>,
+    #[cfg(feature = "trust-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_strategy: Option<String>,
+    #[cfg(feature = "trust-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_timeout: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     mode: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     no_delay: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    keep_busy_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tcp_defer_accept: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    egress_limit: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stats_export: Option<SSStatsExportConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination_stats_capacity: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flow_stats_persist_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     nofile: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    geoip_database: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    geosite_database: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bypass_lan: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     ipv6_first: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_cache: Option<SSDnsCacheConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hosts: Option<BTreeMap<String, SSHostsValue>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hosts_file: Option<String>,
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fake_dns_range: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    memory_pressure_threshold: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipv6_prefer_temporary: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipv6_outbound_prefix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outbound_address_pool: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outbound_address_rotation: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replay_filter_capacity: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replay_filter_fp_rate: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replay_filter_rotate_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replay_filter_per_listener: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replay_filter_persist_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    security_level: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    balancer_probe: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    groups: Option<BTreeMap<String, SSServerGroupConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    balancer_failure_threshold: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    balancer_recovery_threshold: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    balancer_race: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    balancer_query_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    balancer_control_address: Option<String>,
+    #[cfg(feature = "local-http")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pac_address: Option<String>,
+    #[cfg(feature = "local-online-config")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    online_config: Option<SSOnlineConfig>,
+    #[cfg(feature = "local-online-config")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    online_acl: Option<SSOnlineAcl>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    health_check_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    health_check_proxy_protocol: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sampling_rate: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sampling_clients: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    access_log: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    users: Option<Vec<SSServerUserConfig>>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -136,6 +296,14 @@ struct SSServerExtConfig {
     #[serde(alias = "port")]
     server_port: u16,
     password: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous_password: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rotate_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fallback_passwords: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kdf: Option<SSKdfConfig>,
     method: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     plugin: Option<String>,
@@ -149,6 +317,128 @@ struct SSServerExtConfig {
     remarks: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    weight: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_connections: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quota: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    speed_limit: Option<SSSpeedLimitConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    users: Option<Vec<SSServerUserConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    acl: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SSSpeedLimitConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    up: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    down: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SSServerGroupConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    strategy: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sticky_exclude: Option<Vec<String>>,
+}
+
+#[cfg(feature = "local-online-config")]
+#[derive(Serialize, Deserialize, Debug)]
+struct SSOnlineConfig {
+    config_url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    update_interval: Option<u64>,
+}
+
+#[cfg(feature = "local-online-config")]
+#[derive(Serialize, Deserialize, Debug)]
+struct SSOnlineAcl {
+    acl_url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    public_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    update_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_path: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SSStatsExportConfig {
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SSDnsCacheConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    capacity: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_ttl: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_ttl: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    negative_ttl: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SSServerUserConfig {
+    name: String,
+    password: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    acl: Option<String>,
+}
+
+#[cfg(feature = "local-tunnel")]
+#[derive(Serialize, Deserialize, Debug)]
+struct SSTunnelConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_address: Option<String>,
+    local_port: u16,
+    #[serde(alias = "forward_addr")]
+    forward_address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SSLocalAuthConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    users: Option<Vec<SSLocalUserConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    users_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scheme: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SSLocalUserConfig {
+    username: String,
+    password: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SSKdfConfig {
+    algorithm: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    salt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    memory_cost: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time_cost: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parallelism: Option<u32>,
 }
 
 /// Server address
@@ -275,6 +565,273 @@ impl From<&ServerAddr> for Address {
     }
 }
 
+/// Default memory cost of Argon2id, in KiB
+pub const DEFAULT_ARGON2_MEMORY_COST: u32 = 64 * 1024;
+/// Default number of passes of Argon2id
+pub const DEFAULT_ARGON2_TIME_COST: u32 = 3;
+/// Default degree of parallelism (lanes) of Argon2id
+pub const DEFAULT_ARGON2_PARALLELISM: u32 = 4;
+
+// Number of keys derived by Argon2id kept in `ARGON2_KEYS`
+const ARGON2_KEY_CACHE_CAPACITY: usize = 256;
+
+// (salt, memory_cost, time_cost, parallelism, password, key length)
+type Argon2KeyCacheKey = (String, u32, u32, u32, Vec<u8>, usize);
+
+lazy_static! {
+    // Argon2id is too expensive to run again for the same password, e.g. in `ServerConfig::set_method`,
+    // or when the same servers are loaded again
+    static ref ARGON2_KEYS: SpinMutex<LruCache<Argon2KeyCacheKey, Vec<u8>>> =
+        SpinMutex::new(LruCache::with_capacity(ARGON2_KEY_CACHE_CAPACITY));
+}
+
+/// Parameters of Argon2id, must be the same in clients and servers
+#[derive(Clone, Debug, PartialEq)]
+pub struct Argon2Params {
+    /// Salt, at least 8 bytes
+    pub salt: String,
+    /// Memory size in KiB
+    pub memory_cost: u32,
+    /// Number of passes
+    pub time_cost: u32,
+    /// Degree of parallelism
+    pub parallelism: u32,
+}
+
+impl Argon2Params {
+    /// Create with default costs
+    pub fn new(salt: String) -> Argon2Params {
+        Argon2Params {
+            salt,
+            memory_cost: DEFAULT_ARGON2_MEMORY_COST,
+            time_cost: DEFAULT_ARGON2_TIME_COST,
+            parallelism: DEFAULT_ARGON2_PARALLELISM,
+        }
+    }
+
+    fn derive(&self, password: &[u8], key: &mut [u8]) {
+        let cache_key = (
+            self.salt.clone(),
+            self.memory_cost,
+            self.time_cost,
+            self.parallelism,
+            password.to_vec(),
+            key.len(),
+        );
+        if let Some(cached) = ARGON2_KEYS.lock().get(&cache_key) {
+            key.copy_from_slice(cached);
+            return;
+        }
+
+        let config = argon2::Config {
+            variant: argon2::Variant::Argon2id,
+            version: argon2::Version::Version13,
+            mem_cost: self.memory_cost,
+            time_cost: self.time_cost,
+            lanes: self.parallelism,
+            thread_mode: argon2::ThreadMode::Sequential,
+            secret: &[],
+            ad: &[],
+            hash_length: key.len() as u32,
+        };
+
+        // Parameters are checked while loading configuration
+        let hash = argon2::hash_raw(password, self.salt.as_bytes(), &config).expect("invalid argon2id parameters");
+        key.copy_from_slice(&hash);
+
+        ARGON2_KEYS.lock().insert(cache_key, hash);
+    }
+}
+
+/// Key derivation function for deriving encryption keys from passwords
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeyDerivation {
+    /// OpenSSL's `EVP_BytesToKey`, compatible with all shadowsocks implementations
+    BytesToKey,
+    /// Argon2id (RFC 9106), memory-hard
+    Argon2id(Argon2Params),
+}
+
+impl Default for KeyDerivation {
+    fn default() -> KeyDerivation {
+        KeyDerivation::BytesToKey
+    }
+}
+
+impl KeyDerivation {
+    /// Derive the key for `method` from `password`
+    pub fn derive_key(&self, password: &str, method: CipherKind) -> Bytes {
+        let mut key = vec![0u8; method.key_len()];
+        if key.is_empty() {
+            // Method `none` doesn't have a key
+            return Bytes::new();
+        }
+
+        match *self {
+            KeyDerivation::BytesToKey => openssl_bytes_to_key(password.as_bytes(), &mut key),
+            KeyDerivation::Argon2id(ref params) => params.derive(password.as_bytes(), &mut key),
+        }
+
+        Bytes::from(key)
+    }
+}
+
+/// A user identity of a multi-user server
+///
+/// Every user has its own password, clients are identified by the key that decrypts their handshake.
+#[derive(Clone, Debug)]
+pub struct ServerUser {
+    name: String,
+    password: String,
+    key: Bytes,
+    acl: Option<Arc<AccessControl>>,
+}
+
+impl ServerUser {
+    /// Create a user and derive its key for `method` with `kdf`
+    pub fn new(name: String, password: String, method: CipherKind, kdf: &KeyDerivation) -> ServerUser {
+        let key = kdf.derive_key(&password, method);
+        ServerUser {
+            name,
+            password,
+            key,
+            acl: None,
+        }
+    }
+
+    /// Name of user
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Password of user
+    pub fn password(&self) -> &str {
+        &self.password
+    }
+
+    /// Encryption key of user
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Clone encryption key
+    pub fn clone_key(&self) -> Bytes {
+        self.key.clone()
+    }
+
+    /// Outbound ACL of this user, overriding the server's
+    pub fn acl(&self) -> Option<&Arc<AccessControl>> {
+        self.acl.as_ref()
+    }
+
+    /// Set outbound ACL of this user
+    pub fn set_acl(&mut self, acl: Arc<AccessControl>) {
+        self.acl = Some(acl);
+    }
+}
+
+/// Handshakes failed with distinct destinations before a client switches to the next candidate password
+///
+/// Failures of requests to the same destination may be caused by the destination itself, like an unreachable host
+const MAX_PASSWORD_HANDSHAKE_FAILURES: usize = 5;
+
+/// Failures are only counted within this duration since the first one
+const PASSWORD_FAILURE_WINDOW: Duration = Duration::from_secs(60);
+
+/// A password is kept for this duration after it has completed a handshake
+const PASSWORD_SUCCESS_GRACE: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Default)]
+struct PasswordFailures {
+    last_success: Option<Instant>,
+    window_start: Option<Instant>,
+    destinations: HashSet<String>,
+}
+
+/// Ordered candidate passwords of a server for clients
+///
+/// Clients start with the first one and switch to the next when handshakes keep failing.
+#[derive(Debug)]
+pub struct ServerPasswords {
+    candidates: Vec<(String, Bytes)>,
+    active: AtomicUsize,
+    failures: SpinMutex<PasswordFailures>,
+}
+
+impl ServerPasswords {
+    fn new(method: CipherKind, kdf: &KeyDerivation, passwords: Vec<String>) -> ServerPasswords {
+        let candidates = passwords
+            .into_iter()
+            .map(|pwd| {
+                let key = kdf.derive_key(&pwd, method);
+                (pwd, key)
+            })
+            .collect();
+
+        ServerPasswords {
+            candidates,
+            active: AtomicUsize::new(0),
+            failures: SpinMutex::new(PasswordFailures::default()),
+        }
+    }
+
+    /// Index of the password in use
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Acquire)
+    }
+
+    /// Password in use
+    pub fn active_password(&self) -> &str {
+        &self.candidates[self.active()].0
+    }
+
+    fn active_key(&self) -> &Bytes {
+        &self.candidates[self.active()].1
+    }
+
+    /// Report a successful handshake with the password in use
+    pub fn report_success(&self) {
+        let mut failures = self.failures.lock();
+        failures.last_success = Some(Instant::now());
+        failures.window_start = None;
+        failures.destinations.clear();
+    }
+
+    /// Report a failed handshake of a request to `destination` with the password in use
+    ///
+    /// Returns the new index if it has switched to the next password
+    pub fn report_failure(&self, destination: &str) -> Option<usize> {
+        let now = Instant::now();
+        let mut failures = self.failures.lock();
+
+        if let Some(t) = failures.last_success {
+            if now.duration_since(t) < PASSWORD_SUCCESS_GRACE {
+                return None;
+            }
+        }
+
+        match failures.window_start {
+            Some(t) if now.duration_since(t) < PASSWORD_FAILURE_WINDOW => {}
+            _ => {
+                failures.window_start = Some(now);
+                failures.destinations.clear();
+            }
+        }
+
+        failures.destinations.insert(destination.to_owned());
+        if failures.destinations.len() < MAX_PASSWORD_HANDSHAKE_FAILURES {
+            return None;
+        }
+
+        failures.window_start = None;
+        failures.destinations.clear();
+
+        let next = (self.active() + 1) % self.candidates.len();
+        self.active.store(next, Ordering::Release);
+        Some(next)
+    }
+}
+
 /// Configuration for a server
 #[derive(Clone, Debug)]
 pub struct ServerConfig {
@@ -297,6 +854,31 @@ pub struct ServerConfig {
     remarks: Option<String>,
     /// ID (SIP008) is a random generated UUID
     id: Option<String>,
+    /// Users sharing this server with their own keys
+    users: Vec<ServerUser>,
+    /// Password before key rotation, still accepted until `rotate_at`
+    previous_password: Option<String>,
+    previous_key: Option<Bytes>,
+    /// End of the key rotation overlap window
+    rotate_at: Option<SystemTime>,
+    /// Candidate passwords for clients, the first one is `password`
+    passwords: Option<Arc<ServerPasswords>>,
+    /// Key derivation function for all passwords of this server
+    kdf: KeyDerivation,
+    /// Weight in the load balancer's traffic share
+    weight: Option<u32>,
+    /// Maximum concurrent TCP connections through this server from the load balancer
+    max_connections: Option<usize>,
+    /// Traffic quota in bytes, new connections are rejected after it is reached
+    quota: Option<u64>,
+    /// Speed limits of clients of this server
+    speed_limit: Option<SpeedLimit>,
+    /// Token buckets of `speed_limit`, shared by clones of this server
+    speed_limiter: SpeedLimiter,
+    /// Group of servers that this server belongs to, `default` if not set
+    group: Option<String>,
+    /// Outbound ACL of this server, checked besides the global one
+    acl: Option<Arc<AccessControl>>,
 }
 
 impl ServerConfig {
@@ -308,9 +890,7 @@ impl ServerConfig {
         timeout: Option<Duration>,
         plugin: Option<PluginConfig>,
     ) -> ServerConfig {
-        let mut key = vec![0u8; method.key_len()];
-        openssl_bytes_to_key(password.as_bytes(), &mut key);
-        let enc_key = Bytes::copy_from_slice(&key);
+        let enc_key = KeyDerivation::BytesToKey.derive_key(&password, method);
 
         ServerConfig {
             addr,
@@ -322,6 +902,19 @@ impl ServerConfig {
             plugin_addr: None,
             remarks: None,
             id: None,
+            users: Vec::new(),
+            previous_password: None,
+            previous_key: None,
+            rotate_at: None,
+            passwords: None,
+            kdf: KeyDerivation::BytesToKey,
+            weight: None,
+            max_connections: None,
+            quota: None,
+            speed_limit: None,
+            speed_limiter: SpeedLimiter::default(),
+            group: None,
+            acl: None,
         }
     }
 
@@ -334,11 +927,35 @@ impl ServerConfig {
     pub fn set_method(&mut self, method: CipherKind, password: String) {
         self.method = method;
 
-        let mut key = vec![0u8; method.key_len()];
-        openssl_bytes_to_key(password.as_bytes(), &mut key);
-
+        self.enc_key = self.kdf.derive_key(&password, method);
         self.password = password;
-        self.enc_key = Bytes::copy_from_slice(&key);
+
+        // Keys of users depend on the method
+        for user in &mut self.users {
+            let acl = user.acl.take();
+            *user = ServerUser::new(user.name.clone(), user.password.clone(), method, &self.kdf);
+            user.acl = acl;
+        }
+
+        if let Some(previous_password) = self.previous_password.take() {
+            self.set_previous_password(previous_password);
+        }
+
+        let fallback_passwords = self.fallback_passwords();
+        if !fallback_passwords.is_empty() {
+            self.set_fallback_passwords(fallback_passwords);
+        }
+    }
+
+    /// Set key derivation function, keys of all passwords are derived again
+    pub fn set_kdf(&mut self, kdf: KeyDerivation) {
+        self.kdf = kdf;
+        self.set_method(self.method, self.password.clone());
+    }
+
+    /// Get key derivation function
+    pub fn kdf(&self) -> &KeyDerivation {
+        &self.kdf
     }
 
     /// Set plugin
@@ -357,13 +974,48 @@ impl ServerConfig {
     }
 
     /// Get encryption key
+    ///
+    /// For clients, it is the key of the candidate password in use
     pub fn key(&self) -> &[u8] {
-        &self.enc_key[..]
+        match self.passwords {
+            Some(ref p) => &p.active_key()[..],
+            None => &self.enc_key[..],
+        }
     }
 
     /// Clone encryption key
     pub fn clone_key(&self) -> Bytes {
-        self.enc_key.clone()
+        match self.passwords {
+            Some(ref p) => p.active_key().clone(),
+            None => self.enc_key.clone(),
+        }
+    }
+
+    /// Set passwords for clients to try in order after `password` when handshakes keep failing
+    pub fn set_fallback_passwords(&mut self, passwords: Vec<String>) {
+        if passwords.is_empty() {
+            self.passwords = None;
+            return;
+        }
+
+        let mut candidates = Vec::with_capacity(passwords.len() + 1);
+        candidates.push(self.password.clone());
+        candidates.extend(passwords);
+
+        self.passwords = Some(Arc::new(ServerPasswords::new(self.method, &self.kdf, candidates)));
+    }
+
+    /// Get passwords tried after `password`
+    pub fn fallback_passwords(&self) -> Vec<String> {
+        match self.passwords {
+            Some(ref p) => p.candidates[1..].iter().map(|(pwd, _)| pwd.clone()).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Get candidate passwords state, `None` if there is no fallback password
+    pub fn passwords(&self) -> Option<&Arc<ServerPasswords>> {
+        self.passwords.as_ref()
     }
 
     /// Get password
@@ -421,6 +1073,146 @@ impl ServerConfig {
         self.id = Some(id)
     }
 
+    /// Get server's weight in the load balancer
+    ///
+    /// New connections are distributed to healthy servers in proportion to their weights,
+    /// if any of the servers has a weight. `0` receives no new connections unless all the others are down.
+    pub fn weight(&self) -> Option<u32> {
+        self.weight
+    }
+
+    /// Set server's weight in the load balancer
+    pub fn set_weight(&mut self, weight: u32) {
+        self.weight = Some(weight)
+    }
+
+    /// Get maximum concurrent TCP connections through this server from the load balancer
+    ///
+    /// New connections overflow to the other servers of the group if it is reached,
+    /// and are rejected if all of them are reached.
+    pub fn max_connections(&self) -> Option<usize> {
+        self.max_connections
+    }
+
+    /// Set maximum concurrent TCP connections through this server from the load balancer
+    pub fn set_max_connections(&mut self, max_connections: usize) {
+        self.max_connections = Some(max_connections)
+    }
+
+    /// Get traffic quota in bytes
+    ///
+    /// New connections (and UDP associations) are rejected after bytes transferred by this server reached it,
+    /// established ones are kept.
+    pub fn quota(&self) -> Option<u64> {
+        self.quota
+    }
+
+    /// Set traffic quota in bytes
+    pub fn set_quota(&mut self, quota: u64) {
+        self.quota = Some(quota)
+    }
+
+    /// Get speed limits
+    pub fn speed_limit(&self) -> Option<SpeedLimit> {
+        self.speed_limit
+    }
+
+    /// Set speed limits, with new token buckets
+    pub fn set_speed_limit(&mut self, speed_limit: SpeedLimit) {
+        self.speed_limit = Some(speed_limit);
+        self.speed_limiter = SpeedLimiter::new(speed_limit);
+    }
+
+    /// Token buckets enforcing the speed limits
+    pub(crate) fn speed_limiter(&self) -> &SpeedLimiter {
+        &self.speed_limiter
+    }
+
+    /// Get name of the group that this server belongs to
+    pub fn group(&self) -> &str {
+        self.group.as_deref().unwrap_or(DEFAULT_SERVER_GROUP)
+    }
+
+    /// Set name of the group that this server belongs to
+    pub fn set_group(&mut self, group: String) {
+        self.group = Some(group)
+    }
+
+    /// Get users sharing this server
+    pub fn users(&self) -> &[ServerUser] {
+        &self.users
+    }
+
+    /// Add a user with its own password
+    pub fn add_user(&mut self, name: String, password: String) -> &mut ServerUser {
+        let user = ServerUser::new(name, password, self.method, &self.kdf);
+        self.users.push(user);
+        self.users.last_mut().expect("user added")
+    }
+
+    /// Replace all users of this server
+    pub fn set_users(&mut self, users: Vec<ServerUser>) {
+        self.users = users;
+    }
+
+    /// Outbound ACL of this server, checked besides the global one
+    pub fn acl(&self) -> Option<&Arc<AccessControl>> {
+        self.acl.as_ref()
+    }
+
+    /// Set outbound ACL of this server
+    pub fn set_acl(&mut self, acl: Arc<AccessControl>) {
+        self.acl = Some(acl);
+    }
+
+    /// Outbound ACL for clients identified as the `user_index`th user, or the server's if the user doesn't have one
+    pub fn outbound_acl(&self, user_index: Option<usize>) -> Option<&Arc<AccessControl>> {
+        user_index
+            .and_then(|idx| self.users.get(idx))
+            .and_then(ServerUser::acl)
+            .or_else(|| self.acl())
+    }
+
+    /// Set password before key rotation, clients using it are still accepted until `rotate_at`
+    pub fn set_previous_password(&mut self, password: String) {
+        self.previous_key = Some(self.kdf.derive_key(&password, self.method));
+        self.previous_password = Some(password);
+    }
+
+    /// Get password before key rotation
+    pub fn previous_password(&self) -> Option<&str> {
+        self.previous_password.as_ref().map(AsRef::as_ref)
+    }
+
+    /// Set end of the key rotation overlap window, `None` for accepting the previous password until it is removed
+    pub fn set_rotate_at(&mut self, rotate_at: Option<SystemTime>) {
+        self.rotate_at = rotate_at;
+    }
+
+    /// Get end of the key rotation overlap window
+    pub fn rotate_at(&self) -> Option<SystemTime> {
+        self.rotate_at
+    }
+
+    /// Key of the previous password, `None` if there is no one or the overlap window is over
+    pub fn previous_key(&self) -> Option<&[u8]> {
+        match self.rotate_at {
+            Some(t) if SystemTime::now() >= t => None,
+            _ => self.previous_key.as_ref().map(AsRef::as_ref),
+        }
+    }
+
+    /// Keys accepted besides the server's key, in the order of `users()` and then the previous key
+    ///
+    /// Index in this list could be mapped to a user by `users().get(idx)`
+    pub fn accepted_keys(&self) -> Vec<Bytes> {
+        let mut keys = self.users.iter().map(ServerUser::clone_key).collect::<Vec<_>>();
+        if let Some(key) = self.previous_key() {
+            keys.push(Bytes::copy_from_slice(key));
+        }
+        keys
+    }
+
     /// Get URL for QRCode
     /// ```plain
     /// ss:// + base64(method:password@host:port)
@@ -548,6 +1340,9 @@ pub enum ManagerAddr {
     /// Unix socket path
     #[cfg(unix)]
     UnixSocketAddr(PathBuf),
+    /// Named pipe path, like `\\.\pipe\shadowsocks-manager`
+    #[cfg(windows)]
+    NamedPipe(String),
 }
 
 /// Error for parsing `ManagerAddr`
@@ -580,6 +1375,8 @@ impl FromStr for ManagerAddr {
                 // Must be a unix socket path
                 Ok(ManagerAddr::UnixSocketAddr(PathBuf::from(s)))
             }
+            #[cfg(windows)]
+            None if s.starts_with(NAMED_PIPE_PREFIX) => Ok(ManagerAddr::NamedPipe(s.to_owned())),
             #[cfg(not(unix))]
             None => Err(ManagerAddrError),
         }
@@ -593,6 +1390,8 @@ impl Display for ManagerAddr {
             ManagerAddr::DomainName(ref dname, port) => write!(f, "{}:{}", dname, port),
             #[cfg(unix)]
             ManagerAddr::UnixSocketAddr(ref path) => fmt::Display::fmt(&path.display(), f),
+            #[cfg(windows)]
+            ManagerAddr::NamedPipe(ref path) => f.write_str(path),
         }
     }
 }
@@ -762,6 +1561,12 @@ pub enum ConfigType {
     ))]
     HttpsLocal,
 
+    /// Config for a local server accepting both SOCKS5 and HTTP protocols on the same port
+    ///
+    /// Requires `local` configuration
+    #[cfg(feature = "local-http")]
+    MixedLocal,
+
     /// Config for tunnel local
     ///
     /// Requires `local` and `forward` configuration
@@ -780,6 +1585,12 @@ pub enum ConfigType {
     #[cfg(feature = "local-dns")]
     DnsLocal,
 
+    /// Config for TUN device local
+    ///
+    /// Doesn't require `local` configuration
+    #[cfg(feature = "local-tun")]
+    TunLocal,
+
     /// Config for server
     Server,
 
@@ -805,8 +1616,12 @@ impl ConfigType {
                 any(feature = "local-http-native-tls", feature = "local-http-rustls")
             ))]
             ConfigType::HttpsLocal => true,
+            #[cfg(feature = "local-http")]
+            ConfigType::MixedLocal => true,
             #[cfg(feature = "local-redir")]
             ConfigType::RedirLocal => true,
+            #[cfg(feature = "local-tun")]
+            ConfigType::TunLocal => true,
             ConfigType::Server | ConfigType::Manager => false,
         }
     }
@@ -828,8 +1643,12 @@ impl ConfigType {
                 any(feature = "local-http-native-tls", feature = "local-http-rustls")
             ))]
             ConfigType::HttpsLocal => false,
+            #[cfg(feature = "local-http")]
+            ConfigType::MixedLocal => false,
             #[cfg(feature = "local-redir")]
             ConfigType::RedirLocal => false,
+            #[cfg(feature = "local-tun")]
+            ConfigType::TunLocal => false,
             ConfigType::Manager => false,
             ConfigType::Server => true,
         }
@@ -882,769 +1701,2732 @@ impl FromStr for Mode {
     }
 }
 
-cfg_if! {
-    if #[cfg(feature = "local-redir")] {
-        use strum::IntoEnumIterator;
-        use strum_macros::EnumIter;
+/// Default consecutive failed probes before a server is marked down
+pub const DEFAULT_BALANCER_FAILURE_THRESHOLD: u32 = 3;
+/// Default consecutive succeeded probes before a down server is marked up
+pub const DEFAULT_BALANCER_RECOVERY_THRESHOLD: u32 = 2;
 
-        /// Transparent Proxy type
-        #[derive(Clone, Copy, Debug, Eq, PartialEq, EnumIter)]
-        pub enum RedirType {
-            /// For not supported platforms
-            NotSupported,
+/// Group of servers without `group`
+pub const DEFAULT_SERVER_GROUP: &str = "default";
 
-            /// For Linux-like systems' Netfilter `REDIRECT`. Only for TCP connections.
-            ///
-            /// This is supported from Linux 2.4 Kernel. Document: https://www.netfilter.org/documentation/index.html#documentation-howto
-            ///
-            /// NOTE: Filter rule `REDIRECT` can only be applied to TCP connections.
-            #[cfg(any(target_os = "linux", target_os = "android"))]
-            Redirect,
+/// How a group of servers is balanced
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BalancerStrategy {
+    /// The healthy server with the best probing score, or in proportion to weights
+    Latency,
+    /// Healthy servers in turn
+    RoundRobin,
+    /// Always the first server in the group
+    Fixed,
+    /// Destinations are hashed to healthy servers, so that each host always egresses from the same server
+    Sticky,
+}
 
-            /// For Linux-like systems' Netfilter TPROXY rule.
-            ///
-            /// NOTE: Filter rule `TPROXY` can be applied to TCP and UDP connections.
-            #[cfg(any(target_os = "linux", target_os = "android"))]
-            TProxy,
+impl Default for BalancerStrategy {
+    fn default() -> BalancerStrategy {
+        BalancerStrategy::Latency
+    }
+}
 
-            /// Packet Filter (pf)
-            ///
-            /// Supported by OpenBSD 3.0+, FreeBSD 5.3+, NetBSD 3.0+, Solaris 11.3+, macOS 10.7+, iOS, QNX
-            ///
-            /// Document: https://www.freebsd.org/doc/handbook/firewalls-pf.html
-            #[cfg(any(
-                target_os = "openbsd",
-                target_os = "freebsd",
-                target_os = "netbsd",
-                target_os = "solaris",
-                target_os = "macos",
-                target_os = "ios"
-            ))]
-            PacketFilter,
+impl fmt::Display for BalancerStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BalancerStrategy::Latency => f.write_str("latency"),
+            BalancerStrategy::RoundRobin => f.write_str("round_robin"),
+            BalancerStrategy::Fixed => f.write_str("fixed"),
+            BalancerStrategy::Sticky => f.write_str("sticky"),
+        }
+    }
+}
 
-            /// IPFW
-            ///
-            /// Supported by FreeBSD, macOS 10.6- (Have been removed completely on macOS 10.10)
-            ///
-            /// Document: https://www.freebsd.org/doc/handbook/firewalls-ipfw.html
-            #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "ios"))]
-            IpFirewall,
+impl FromStr for BalancerStrategy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "latency" => Ok(BalancerStrategy::Latency),
+            "round_robin" => Ok(BalancerStrategy::RoundRobin),
+            "fixed" => Ok(BalancerStrategy::Fixed),
+            "sticky" => Ok(BalancerStrategy::Sticky),
+            _ => Err(()),
         }
+    }
+}
 
-        impl RedirType {
-            cfg_if! {
-                if #[cfg(any(target_os = "linux", target_os = "android"))] {
-                    /// Default TCP transparent proxy solution on this platform
-                    pub fn tcp_default() -> RedirType {
-                        RedirType::Redirect
-                    }
+/// Speed limits of a server in bytes per second, `None` for unlimited
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SpeedLimit {
+    /// Traffic from clients
+    pub up: Option<u64>,
+    /// Traffic to clients
+    pub down: Option<u64>,
+    /// Traffic limited together
+    pub scope: SpeedLimitScope,
+}
 
-                    /// Default UDP transparent proxy solution on this platform
-                    pub fn udp_default() -> RedirType {
-                        RedirType::TProxy
-                    }
-                } else if #[cfg(any(target_os = "openbsd", target_os = "freebsd"))] {
-                    /// Default TCP transparent proxy solution on this platform
-                    pub fn tcp_default() -> RedirType {
-                        RedirType::PacketFilter
-                    }
+/// Scope of speed limits
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SpeedLimitScope {
+    /// All clients of the server share the limits
+    Server,
+    /// Connections (and UDP associations) from the same client IP share the limits
+    Client,
+    /// Each connection (or UDP association) is limited separately
+    Connection,
+}
 
-                    /// Default UDP transparent proxy solution on this platform
-                    pub fn udp_default() -> RedirType {
-                        RedirType::PacketFilter
-                    }
-                } else if #[cfg(any(target_os = "netbsd", target_os = "solaris", target_os = "macos", target_os = "ios"))] {
-                    /// Default TCP transparent proxy solution on this platform
-                    pub fn tcp_default() -> RedirType {
-                        RedirType::PacketFilter
-                    }
+impl Default for SpeedLimitScope {
+    fn default() -> SpeedLimitScope {
+        SpeedLimitScope::Server
+    }
+}
 
-                    /// Default UDP transparent proxy solution on this platform
-                    pub fn udp_default() -> RedirType {
-                        RedirType::NotSupported
-                    }
-                } else {
-                    /// Default TCP transparent proxy solution on this platform
-                    pub fn tcp_default() -> RedirType {
-                        RedirType::NotSupported
-                    }
+impl fmt::Display for SpeedLimitScope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SpeedLimitScope::Server => f.write_str("server"),
+            SpeedLimitScope::Client => f.write_str("client"),
+            SpeedLimitScope::Connection => f.write_str("connection"),
+        }
+    }
+}
 
-                    /// Default UDP transparent proxy solution on this platform
-                    pub fn udp_default() -> RedirType {
-                        RedirType::NotSupported
-                    }
-                }
-            }
+impl FromStr for SpeedLimitScope {
+    type Err = ();
 
-            /// Check if transparent proxy is supported on this platform
-            pub fn is_supported(self) -> bool {
-                self != RedirType::NotSupported
-            }
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "server" => Ok(SpeedLimitScope::Server),
+            "client" => Ok(SpeedLimitScope::Client),
+            "connection" => Ok(SpeedLimitScope::Connection),
+            _ => Err(()),
+        }
+    }
+}
 
-            /// Name of redirect type (transparent proxy type)
-            pub fn name(self) -> &'static str {
-                match self {
-                    // Dummy, shouldn't be used in any useful situations
-                    RedirType::NotSupported => "not_supported",
+/// Configuration of a group of servers
+#[derive(Clone, Debug, Default)]
+pub struct ServerGroupConfig {
+    /// How servers in this group are balanced
+    pub strategy: BalancerStrategy,
+    /// Hosts (and their subdomains) that are balanced by latency instead, for `Sticky`
+    ///
+    /// Lowercased while loading
+    pub sticky_exclude: Vec<String>,
+}
 
-                    #[cfg(any(target_os = "linux", target_os = "android"))]
-                    RedirType::Redirect => "redirect",
+impl ServerGroupConfig {
+    /// Check if `host` is excluded from `Sticky` balancing
+    pub fn is_sticky_excluded(&self, host: &str) -> bool {
+        self.sticky_exclude.iter().any(|e| {
+            host.ends_with(e.as_str()) && (host.len() == e.len() || host[..host.len() - e.len()].ends_with('.'))
+        })
+    }
+}
 
-                    #[cfg(any(target_os = "linux", target_os = "android"))]
-                    RedirType::TProxy => "tproxy",
+/// Default interval of fetching online configuration (in seconds)
+#[cfg(feature = "local-online-config")]
+pub const DEFAULT_ONLINE_CONFIG_UPDATE_INTERVAL: u64 = 3600;
 
-                    #[cfg(any(
-                        target_os = "openbsd",
-                        target_os = "freebsd",
-                        target_os = "netbsd",
-                        target_os = "solaris",
-                        target_os = "macos",
-                        target_os = "ios"
-                    ))]
-                    RedirType::PacketFilter => "pf",
+/// Servers delivered online, in [SIP008](https://github.com/shadowsocks/shadowsocks-org/issues/89) format
+#[cfg(feature = "local-online-config")]
+#[derive(Clone, Debug)]
+pub struct OnlineConfig {
+    /// HTTPS URL of the SIP008 document
+    pub config_url: Url,
+    /// Interval of fetching the document
+    pub update_interval: Duration,
+}
 
-                    #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "ios"))]
-                    RedirType::IpFirewall => "ipfw",
-                }
-            }
+/// ACL rules delivered online, shared by a fleet of clients
+#[cfg(feature = "local-online-config")]
+#[derive(Clone, Debug)]
+pub struct OnlineAclConfig {
+    /// HTTPS URL of the ACL file
+    pub acl_url: Url,
+    /// Ed25519 public key (32 bytes), the ACL file must be signed with a detached signature at `acl_url` + `.sig`
+    pub public_key: Option<Vec<u8>>,
+    /// Interval of checking updates, with `If-None-Match`
+    pub update_interval: Duration,
+    /// The latest fetched rules are saved here, and loaded on startup before the first fetch succeeds
+    pub cache_path: PathBuf,
+}
 
-            /// Get all available types
-            pub fn available_types() -> Vec<&'static str> {
-                let mut v = Vec::new();
-                for e in Self::iter() {
-                    match e {
-                        RedirType::NotSupported => continue,
-                        #[allow(unreachable_patterns)]
-                        _ => v.push(e.name()),
-                    }
-                }
-                v
-            }
+#[cfg(feature = "local-online-config")]
+impl OnlineAclConfig {
+    /// Create with default settings, without signature verification
+    pub fn new(acl_url: Url) -> OnlineAclConfig {
+        let cache_path = OnlineAclConfig::default_cache_path(&acl_url);
+        OnlineAclConfig {
+            acl_url,
+            public_key: None,
+            update_interval: Duration::from_secs(DEFAULT_ONLINE_CONFIG_UPDATE_INTERVAL),
+            cache_path,
         }
+    }
 
-        impl Display for RedirType {
-            fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-                f.write_str(self.name())
-            }
-        }
+    /// `shadowsocks-acl-HOST.acl` in the temporary directory
+    pub fn default_cache_path(acl_url: &Url) -> PathBuf {
+        let host = acl_url.host_str().unwrap_or("unknown");
+        std::env::temp_dir().join(format!("shadowsocks-acl-{}.acl", host))
+    }
+}
 
-        /// Error type for `RedirType`'s `FromStr::Err`
-        #[derive(Debug)]
-        pub struct InvalidRedirType;
+/// Default number of host names kept in the DNS cache
+pub const DEFAULT_DNS_CACHE_CAPACITY: usize = 1024;
+/// Default lower bound of TTLs in the DNS cache (in seconds)
+pub const DEFAULT_DNS_CACHE_MIN_TTL: u64 = 30;
+/// Default upper bound of TTLs in the DNS cache (in seconds)
+pub const DEFAULT_DNS_CACHE_MAX_TTL: u64 = 3600;
+/// Default TTL of failed resolutions in the DNS cache (in seconds)
+pub const DEFAULT_DNS_CACHE_NEGATIVE_TTL: u64 = 10;
 
-        impl FromStr for RedirType {
-            type Err = InvalidRedirType;
+/// In-process cache of resolved host names, in front of the DNS resolver
+#[derive(Clone, Copy, Debug)]
+pub struct DnsCacheConfig {
+    /// Host names kept, the least recently used ones are evicted
+    pub capacity: usize,
+    /// TTLs of records are clamped into `[min_ttl, max_ttl]`
+    ///
+    /// Results of the system resolver don't carry TTLs, they are kept for `min_ttl`
+    pub min_ttl: Duration,
+    pub max_ttl: Duration,
+    /// Names without records (NXDOMAIN or NODATA) are kept for this duration, 0 disables negative caching
+    pub negative_ttl: Duration,
+}
 
-            fn from_str(s: &str) -> Result<RedirType, InvalidRedirType> {
-                match s {
-                    #[cfg(any(target_os = "linux", target_os = "android"))]
-                    "redirect" => Ok(RedirType::Redirect),
+impl Default for DnsCacheConfig {
+    fn default() -> DnsCacheConfig {
+        DnsCacheConfig {
+            capacity: DEFAULT_DNS_CACHE_CAPACITY,
+            min_ttl: Duration::from_secs(DEFAULT_DNS_CACHE_MIN_TTL),
+            max_ttl: Duration::from_secs(DEFAULT_DNS_CACHE_MAX_TTL),
+            negative_ttl: Duration::from_secs(DEFAULT_DNS_CACHE_NEGATIVE_TTL),
+        }
+    }
+}
 
-                    #[cfg(any(target_os = "linux", target_os = "android"))]
-                    "tproxy" => Ok(RedirType::TProxy),
-
-                    #[cfg(any(
-                        target_os = "openbsd",
-                        target_os = "freebsd",
-                        target_os = "netbsd",
-                        target_os = "solaris",
-                        target_os = "macos",
-                        target_os = "ios",
-                    ))]
-                    "pf" => Ok(RedirType::PacketFilter),
+/// Default interval of exporting statistics (in seconds)
+pub const DEFAULT_STATS_EXPORT_INTERVAL: u64 = 10;
 
-                    #[cfg(any(
-                        target_os = "freebsd",
-                        target_os = "macos",
-                        target_os = "ios",
-                        target_os = "dragonfly"
-                    ))]
-                    "ipfw" => Ok(RedirType::IpFirewall),
+/// Flow statistics exported to a time-series backend periodically
+#[derive(Clone, Debug)]
+pub struct StatsExportConfig {
+    /// Backend, by scheme:
+    ///
+    /// - `udp://HOST:PORT` - InfluxDB line protocol over UDP
+    /// - `http://HOST:PORT/write?db=DB` - InfluxDB line protocol over HTTP, with optional basic authentication
+    /// - `statsd://HOST:PORT` - StatsD gauges over UDP, with DogStatsD tags
+    pub url: Url,
+    /// Interval of exporting
+    pub interval: Duration,
+    /// Tags attached to every metric, like the hostname
+    pub tags: BTreeMap<String, String>,
+}
 
-                    _ => Err(InvalidRedirType),
-                }
-            }
-        }
-    }
+/// How servers are probed by the load balancer
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BalancerProbe {
+    /// Send a request to a well-known website through the server, measures the whole path
+    Request,
+    /// Establish a TCP connection with the server only, nothing is sent through it
+    ///
+    /// Connects to the server's address directly, not through plugins. UDP servers are always probed by requests.
+    Connect,
 }
 
-/// Host for servers to bind
-///
-/// Servers will bind to a port of this host
-#[derive(Clone, Debug)]
-pub enum ManagerServerHost {
-    /// Domain name
-    Domain(String),
-    /// IP address
-    Ip(IpAddr),
+impl Default for BalancerProbe {
+    fn default() -> BalancerProbe {
+        BalancerProbe::Request
+    }
 }
 
-impl Default for ManagerServerHost {
-    fn default() -> ManagerServerHost {
-        ManagerServerHost::Ip(Ipv4Addr::UNSPECIFIED.into())
+impl fmt::Display for BalancerProbe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BalancerProbe::Request => f.write_str("request"),
+            BalancerProbe::Connect => f.write_str("connect"),
+        }
     }
 }
 
-impl FromStr for ManagerServerHost {
-    type Err = Infallible;
+impl FromStr for BalancerProbe {
+    type Err = ();
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.parse::<IpAddr>() {
-            Ok(s) => Ok(ManagerServerHost::Ip(s)),
-            Err(..) => Ok(ManagerServerHost::Domain(s.to_owned())),
+        match s {
+            "request" => Ok(BalancerProbe::Request),
+            "connect" => Ok(BalancerProbe::Connect),
+            _ => Err(()),
         }
     }
 }
 
-impl ManagerServerHost {
-    /// Get resolved socket address for servers to bind
-    pub async fn bind_addr(&self, ctx: &Context, port: u16) -> io::Result<SocketAddr> {
+/// Policy for weak methods, which are stream ciphers and `none`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SecurityLevel {
+    /// Weak methods are allowed
+    Permissive,
+    /// Weak methods are allowed with warnings
+    Warn,
+    /// Servers with weak methods are not started while the others are, clients refuse to start
+    Reject,
+    /// Refuse to start with weak methods
+    Strict,
+}
+
+impl Default for SecurityLevel {
+    fn default() -> SecurityLevel {
+        SecurityLevel::Permissive
+    }
+}
+
+impl fmt::Display for SecurityLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            ManagerServerHost::Domain(ref domain) => {
-                let vaddrs = ctx.dns_resolve(domain, port).await?;
-                Ok(vaddrs[0])
-            }
-            ManagerServerHost::Ip(ip) => Ok(SocketAddr::new(ip, port)),
+            SecurityLevel::Permissive => f.write_str("permissive"),
+            SecurityLevel::Warn => f.write_str("warn"),
+            SecurityLevel::Reject => f.write_str("reject"),
+            SecurityLevel::Strict => f.write_str("strict"),
         }
     }
 }
 
-/// Configuration for Manager
-#[derive(Clone, Debug)]
-pub struct ManagerConfig {
-    /// Address of `ss-manager`. Send servers' statistic data to the manager server
-    pub addr: ManagerAddr,
-    /// Manager's default method
-    pub method: Option<CipherKind>,
-    /// Timeout for TCP connections, setting to manager's created servers
-    pub timeout: Option<Duration>,
-    /// IP/Host for servers to bind (inbound)
-    ///
-    /// Note: Outbound address is defined in Config.local_addr
-    pub server_host: ManagerServerHost,
-}
+impl FromStr for SecurityLevel {
+    type Err = ();
 
-impl ManagerConfig {
-    /// Create a ManagerConfig with default options
-    pub fn new(addr: ManagerAddr) -> ManagerConfig {
-        ManagerConfig {
-            addr,
-            method: None,
-            timeout: None,
-            server_host: ManagerServerHost::default(),
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "permissive" => Ok(SecurityLevel::Permissive),
+            "warn" => Ok(SecurityLevel::Warn),
+            "reject" => Ok(SecurityLevel::Reject),
+            "strict" => Ok(SecurityLevel::Strict),
+            _ => Err(()),
         }
     }
+}
 
-    /// Get resolved socket address for servers to bind
-    pub async fn bind_addr(&self, ctx: &Context, port: u16) -> io::Result<SocketAddr> {
-        self.server_host.bind_addr(ctx, port).await
+impl SecurityLevel {
+    /// Check if servers with `method` mustn't be started
+    pub fn rejects(self, method: CipherKind) -> bool {
+        self == SecurityLevel::Reject && is_weak_method(method)
     }
 }
 
-/// Configuration
-#[derive(Clone, Debug)]
-pub struct Config {
-    /// Remote ShadowSocks server configurations
-    pub server: Vec<ServerConfig>,
-    /// Local server's bind address, or ShadowSocks server's outbound address
-    pub local_addr: Option<ClientConfig>,
-    /// Destination address for tunnel
-    #[cfg(feature = "local-tunnel")]
-    pub forward: Option<Address>,
-    /// DNS configuration, uses system-wide DNS configuration by default
-    ///
-    /// Value could be a `IpAddr`, uses UDP DNS protocol with port `53`. For example: `8.8.8.8`
-    ///
-    /// Also Value could be some pre-defined DNS server names:
-    ///
-    /// - `google`
-    /// - `cloudflare`, `cloudflare_tls`, `cloudflare_https`
-    /// - `quad9`, `quad9_tls`
-    #[cfg(feature = "trust-dns")]
-    pub dns: Option<ResolverConfig>,
-    /// Server mode, `tcp_only`, `tcp_and_udp`, and `udp_only`
-    pub mode: Mode,
-    /// Set `TCP_NODELAY` socket option
-    pub no_delay: bool,
-    /// Set `SO_MARK` socket option for outbound sockets
-    #[cfg(any(target_os = "linux", target_os = "android"))]
-    pub outbound_fwmark: Option<u32>,
-    /// Manager's configuration
-    pub manager: Option<ManagerConfig>,
-    /// Config is for Client or Server
-    pub config_type: ConfigType,
-    /// Timeout for UDP Associations, default is 5 minutes
-    pub udp_timeout: Option<Duration>,
-    /// Maximum number of UDP Associations, default is unconfigured
-    pub udp_max_associations: Option<usize>,
-    /// UDP relay's bind address, it uses `local_addr` by default
-    ///
-    /// Resolving Android's issue: https://github.com/shadowsocks/shadowsocks-android/issues/2571
-    pub udp_bind_addr: Option<ClientConfig>,
-    /// `RLIMIT_NOFILE` option for *nix systems
-    pub nofile: Option<u64>,
-    /// ACL configuration
-    pub acl: Option<AccessControl>,
-    /// TCP Transparent Proxy type
-    #[cfg(feature = "local-redir")]
-    pub tcp_redir: RedirType,
-    /// UDP Transparent Proxy type
-    #[cfg(feature = "local-redir")]
-    pub udp_redir: RedirType,
-    /// Flow statistic report Unix socket path (only for Android)
-    #[cfg(feature = "local-flow-stat")]
-    pub stat_path: Option<PathBuf>,
-    /// Path to protect callback unix address, only for Android
-    #[cfg(target_os = "android")]
-    pub protect_path: Option<PathBuf>,
-    /// Internal DNS's bind address
-    #[cfg(feature = "local-dns")]
-    pub dns_bind_addr: Option<ClientConfig>,
-    /// Local DNS's address
-    ///
-    /// Sending DNS query directly to this address
-    #[cfg(feature = "local-dns")]
-    pub local_dns_addr: Option<LocalDnsAddr>,
-    /// Remote DNS's address
-    ///
-    /// Sending DNS query through proxy to this address
-    #[cfg(feature = "local-dns")]
-    pub remote_dns_addr: Option<Address>,
-    /// Uses IPv6 addresses first
-    ///
-    /// Set to `true` if you want to query IPv6 addresses before IPv4
-    pub ipv6_first: bool,
-    /// TLS cryptographic identity (X509), PKCS #12 format
-    #[cfg(feature = "local-http-native-tls")]
-    pub tls_identity_path: Option<PathBuf>,
-    /// TLS cryptographic identity's password
-    #[cfg(feature = "local-http-native-tls")]
-    pub tls_identity_password: Option<String>,
-    /// TLS cryptographic identity, certificate file path (PEM)
-    #[cfg(feature = "local-http-rustls")]
-    pub tls_identity_certificate_path: Option<PathBuf>,
-    /// TLS cryptographic identity, private keys (PEM), RSA or PKCS #8
-    #[cfg(feature = "local-http-rustls")]
-    pub tls_identity_private_key_path: Option<PathBuf>,
+/// Stream ciphers and `none` are weak, they are not authenticated
+pub fn is_weak_method(method: CipherKind) -> bool {
+    method.category() != CipherCategory::Aead
 }
 
-/// Configuration parsing error kind
-#[derive(Copy, Clone, Debug)]
-pub enum ErrorKind {
-    /// Missing required fields in JSON configuration
-    MissingField,
-    /// Missing some keys that must be provided together
-    Malformed,
-    /// Invalid value of some configuration keys
-    Invalid,
-    /// Invalid JSON
-    JsonParsingError,
-    /// `std::io::Error`
-    IoError,
+fn kdf_to_ssconfig(kdf: &KeyDerivation) -> Option<SSKdfConfig> {
+    match *kdf {
+        // Default, omitted
+        KeyDerivation::BytesToKey => None,
+        KeyDerivation::Argon2id(ref params) => Some(SSKdfConfig {
+            algorithm: "argon2id".to_owned(),
+            salt: Some(params.salt.clone()),
+            memory_cost: Some(params.memory_cost),
+            time_cost: Some(params.time_cost),
+            parallelism: Some(params.parallelism),
+        }),
+    }
 }
 
-/// Configuration parsing error
-pub struct Error {
-    pub kind: ErrorKind,
-    pub desc: &'static str,
-    pub detail: Option<String>,
+fn unix_timestamp(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
 }
 
-impl Error {
-    pub fn new(kind: ErrorKind, desc: &'static str, detail: Option<String>) -> Error {
-        Error { kind, desc, detail }
-    }
-}
+/// Resolver of `dns`, a pre-defined DNS server name, `host[:port][,host[:port]]...`, or a DNS-over-HTTPS URL
+///
+/// `None` if there are no name servers
+#[cfg(feature = "trust-dns")]
+pub fn parse_dns_servers(ds: &str) -> Result<Option<ResolverConfig>, Error> {
+    let c = match ds {
+        "google" => Some(ResolverConfig::google()),
+
+        "cloudflare" => Some(ResolverConfig::cloudflare()),
+        #[cfg(feature = "dns-over-tls")]
+        "cloudflare_tls" => Some(ResolverConfig::cloudflare_tls()),
+        #[cfg(feature = "dns-over-https")]
+        "cloudflare_https" => Some(ResolverConfig::cloudflare_https()),
+
+        "quad9" => Some(ResolverConfig::quad9()),
+        #[cfg(feature = "dns-over-tls")]
+        "quad9_tls" => Some(ResolverConfig::quad9_tls()),
+
+        #[cfg(feature = "dns-over-https")]
+        doh if doh.starts_with("https://") => match Url::parse(doh) {
+            Ok(url) => Some(doh_resolver_config(&url)?),
+            Err(..) => {
+                let e = Error::new(ErrorKind::Malformed, "malformed DNS-over-HTTPS URL in `dns`", None);
+                return Err(e);
+            }
+        },
+
+        nameservers => {
+            // Set ips directly
+            // Similar to shadowsocks-libev's `ares_set_servers_ports_csv`
+            //
+            // ```
+            // host[:port][,host[:port]]...
+            // ```
+            //
+            // For example:
+            //     `192.168.1.100,192.168.1.101,3.4.5.6`
+            let mut c = ResolverConfig::new();
+            for part in nameservers.split(',') {
+                let socket_addr = if let Ok(socket_addr) = part.parse::<SocketAddr>() {
+                    socket_addr
+                } else if let Ok(ipaddr) = part.parse::<IpAddr>() {
+                    SocketAddr::new(ipaddr, 53)
+                } else {
+                    let e = Error::new(
+                        ErrorKind::Invalid,
+                        "invalid `dns` value, can only be host[:port][,host[:port]]...",
+                        None,
+                    );
+                    return Err(e);
+                };
 
-macro_rules! impl_from {
-    ($error:ty, $kind:expr, $desc:expr) => {
-        impl From<$error> for Error {
-            fn from(err: $error) -> Self {
-                Error::new($kind, $desc, Some(format!("{:?}", err)))
+                c.add_name_server(NameServerConfig {
+                    socket_addr,
+                    protocol: Protocol::Udp,
+                    tls_dns_name: None,
+                    trust_nx_responses: false,
+                    #[cfg(any(feature = "dns-over-tls", feature = "dns-over-https"))]
+                    tls_config: None,
+                });
+                c.add_name_server(NameServerConfig {
+                    socket_addr,
+                    protocol: Protocol::Tcp,
+                    tls_dns_name: None,
+                    trust_nx_responses: false,
+                    #[cfg(any(feature = "dns-over-tls", feature = "dns-over-https"))]
+                    tls_config: None,
+                });
+            }
+
+            if c.name_servers().is_empty() {
+                None
+            } else {
+                Some(c)
             }
         }
     };
+    Ok(c)
 }
 
-impl_from!(::std::io::Error, ErrorKind::IoError, "error while reading file");
-impl_from!(json5::Error, ErrorKind::JsonParsingError, "json parse error");
+/// Resolver of a DNS-over-HTTPS `dns`, `https://HOST[:PORT]/dns-query`
+///
+/// HOST is verified by the server's certificate. A domain name is resolved once by the system resolver while loading,
+/// an IP address has to be in the certificate by itself.
+#[cfg(feature = "dns-over-https")]
+fn doh_resolver_config(url: &Url) -> Result<ResolverConfig, Error> {
+    use std::net::ToSocketAddrs;
+
+    // IPv6 addresses are bracketed
+    let host = match url.host_str() {
+        Some(h) => h.trim_start_matches('[').trim_end_matches(']'),
+        None => {
+            let e = Error::new(
+                ErrorKind::Malformed,
+                "missing host in DNS-over-HTTPS URL in `dns`",
+                None,
+            );
+            return Err(e);
+        }
+    };
 
-impl Debug for Error {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        match self.detail {
-            None => write!(f, "{}", self.desc),
-            Some(ref det) => write!(f, "{} {}", self.desc, det),
+    // Path of requests is fixed by trust-dns
+    if !matches!(url.path(), "" | "/" | "/dns-query") {
+        let e = Error::new(
+            ErrorKind::Invalid,
+            "invalid DNS-over-HTTPS URL in `dns`, only path /dns-query is supported",
+            Some(url.path().to_owned()),
+        );
+        return Err(e);
+    }
+
+    let port = url.port_or_known_default().unwrap_or(443);
+    let addrs = match (host, port).to_socket_addrs() {
+        Ok(addrs) => addrs,
+        Err(err) => {
+            let e = Error::new(
+                ErrorKind::Invalid,
+                "failed to resolve host of DNS-over-HTTPS URL in `dns`",
+                Some(err.to_string()),
+            );
+            return Err(e);
         }
+    };
+
+    let mut c = ResolverConfig::new();
+    for socket_addr in addrs {
+        c.add_name_server(NameServerConfig {
+            socket_addr,
+            protocol: Protocol::Https,
+            tls_dns_name: Some(host.to_owned()),
+            trust_nx_responses: true,
+            tls_config: None,
+        });
     }
+    Ok(c)
 }
 
-impl Display for Error {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        match self.detail {
-            None => f.write_str(self.desc),
-            Some(ref d) => write!(f, "{}, {}", self.desc, d),
+/// Default timeout of queries to each upstream of `dns` (in seconds)
+#[cfg(feature = "trust-dns")]
+pub const DEFAULT_DNS_TIMEOUT: u64 = 3;
+
+/// Strategy of sending queries to upstreams of `dns`
+#[cfg(feature = "trust-dns")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DnsStrategy {
+    /// Upstreams are queried one by one in order, until one of them answers
+    Failover,
+    /// All upstreams are queried at the same time, the first answer is taken
+    Parallel,
+}
+
+#[cfg(feature = "trust-dns")]
+impl Default for DnsStrategy {
+    fn default() -> DnsStrategy {
+        DnsStrategy::Failover
+    }
+}
+
+#[cfg(feature = "trust-dns")]
+impl fmt::Display for DnsStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DnsStrategy::Failover => f.write_str("failover"),
+            DnsStrategy::Parallel => f.write_str("parallel"),
         }
     }
 }
 
-impl Config {
-    /// Creates an empty configuration
-    pub fn new(config_type: ConfigType) -> Config {
-        Config {
-            server: Vec::new(),
-            local_addr: None,
-            #[cfg(feature = "local-tunnel")]
-            forward: None,
-            #[cfg(feature = "trust-dns")]
-            dns: None,
-            mode: Mode::TcpOnly,
-            no_delay: false,
+#[cfg(feature = "trust-dns")]
+impl FromStr for DnsStrategy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "failover" => Ok(DnsStrategy::Failover),
+            "parallel" => Ok(DnsStrategy::Parallel),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Strategy for choosing a local address from `OutboundAddressPool`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OutboundRotation {
+    /// Every new connection uses the next address
+    RoundRobin,
+    /// Connections to the same destination always use the same address
+    DestinationHash,
+}
+
+impl Default for OutboundRotation {
+    fn default() -> OutboundRotation {
+        OutboundRotation::RoundRobin
+    }
+}
+
+impl fmt::Display for OutboundRotation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OutboundRotation::RoundRobin => f.write_str("round_robin"),
+            OutboundRotation::DestinationHash => f.write_str("destination_hash"),
+        }
+    }
+}
+
+impl FromStr for OutboundRotation {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round_robin" => Ok(OutboundRotation::RoundRobin),
+            "destination_hash" => Ok(OutboundRotation::DestinationHash),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Pool of local addresses for binding outbound connections, for servers with multiple public IPs
+#[derive(Clone, Debug)]
+pub struct OutboundAddressPool {
+    addrs: Vec<IpAddr>,
+    rotation: OutboundRotation,
+    next: Arc<AtomicUsize>,
+}
+
+impl OutboundAddressPool {
+    /// Create a pool with `addrs`
+    pub fn new(addrs: Vec<IpAddr>, rotation: OutboundRotation) -> OutboundAddressPool {
+        OutboundAddressPool {
+            addrs,
+            rotation,
+            next: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// All addresses in the pool
+    pub fn addrs(&self) -> &[IpAddr] {
+        &self.addrs
+    }
+
+    /// Rotation strategy
+    pub fn rotation(&self) -> OutboundRotation {
+        self.rotation
+    }
+
+    /// Choose a local address for connecting to `target`
+    ///
+    /// Only addresses in the same family as `target` are candidates, returns `None` if there is no one.
+    pub fn pick(&self, target: &SocketAddr) -> Option<IpAddr> {
+        let candidates = self
+            .addrs
+            .iter()
+            .filter(|a| a.is_ipv4() == target.is_ipv4())
+            .collect::<Vec<_>>();
+
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let idx = match self.rotation {
+            OutboundRotation::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+            OutboundRotation::DestinationHash => {
+                let mut hasher = DefaultHasher::new();
+                target.ip().hash(&mut hasher);
+                hasher.finish() as usize
+            }
+        };
+
+        Some(*candidates[idx % candidates.len()])
+    }
+}
+
+/// Parameters of the IV/salt replay filter
+///
+/// Fields left `None` use the built-in defaults, which are different for clients and servers.
+#[derive(Clone, Debug, Default)]
+pub struct ReplayFilterConfig {
+    /// Number of IVs/salts remembered
+    pub capacity: Option<usize>,
+    /// False positive rate of the bloom filters
+    pub fp_rate: Option<f64>,
+    /// Forget the older half of the remembered IVs/salts after this interval, even if the filter is not full
+    pub rotate_interval: Option<Duration>,
+    /// Use a separated filter for each server key, instead of one for the whole process
+    pub per_listener: bool,
+    /// Save the filter into this file periodically, and restore it on startup
+    pub persist_path: Option<PathBuf>,
+}
+
+/// Connections logged in detail for debugging
+///
+/// Logs of sampled connections are emitted in `Info` level with target `shadowsocks::sampled`, whatever their
+/// original levels are, so they can be seen without raising the verbosity of the whole process.
+#[derive(Clone, Debug, Default)]
+pub struct SamplingConfig {
+    /// Percentage of connections sampled randomly, in `[0, 100]`
+    pub rate: f64,
+    /// Connections from these clients are always sampled
+    pub clients: Vec<IpNet>,
+}
+
+impl SamplingConfig {
+    /// Check if the connection from `ip` should be sampled
+    pub fn sample(&self, ip: &IpAddr) -> bool {
+        // Clients connected to dual-stack listeners are in IPv4-mapped IPv6 addresses
+        let mapped = match *ip {
+            IpAddr::V6(ref v6) => v6.to_ipv4().map(IpAddr::V4),
+            IpAddr::V4(..) => None,
+        };
+
+        let matched = self
+            .clients
+            .iter()
+            .any(|net| net.contains(ip) || mapped.map(|m| net.contains(&m)).unwrap_or(false));
+
+        matched || (self.rate > 0.0 && rand::random::<f64>() * 100.0 < self.rate)
+    }
+}
+
+cfg_if! {
+    if #[cfg(feature = "local-redir")] {
+        use strum::IntoEnumIterator;
+        use strum_macros::EnumIter;
+
+        /// Transparent Proxy type
+        #[derive(Clone, Copy, Debug, Eq, PartialEq, EnumIter)]
+        pub enum RedirType {
+            /// For not supported platforms
+            NotSupported,
+
+            /// For Linux-like systems' Netfilter `REDIRECT`. Only for TCP connections.
+            ///
+            /// This is supported from Linux 2.4 Kernel. Document: https://www.netfilter.org/documentation/index.html#documentation-howto
+            ///
+            /// NOTE: Filter rule `REDIRECT` can only be applied to TCP connections.
             #[cfg(any(target_os = "linux", target_os = "android"))]
-            outbound_fwmark: None,
-            manager: None,
-            config_type,
-            udp_timeout: None,
-            udp_max_associations: None,
-            udp_bind_addr: None,
-            nofile: None,
-            acl: None,
-            #[cfg(feature = "local-redir")]
-            tcp_redir: RedirType::tcp_default(),
-            #[cfg(feature = "local-redir")]
-            udp_redir: RedirType::udp_default(),
-            #[cfg(feature = "local-flow-stat")]
-            stat_path: None,
-            #[cfg(target_os = "android")]
-            protect_path: None,
-            #[cfg(feature = "local-dns")]
-            dns_bind_addr: None,
-            #[cfg(feature = "local-dns")]
-            local_dns_addr: None,
-            #[cfg(feature = "local-dns")]
-            remote_dns_addr: None,
-            ipv6_first: false,
-            #[cfg(feature = "local-http-native-tls")]
-            tls_identity_path: None,
-            #[cfg(feature = "local-http-native-tls")]
-            tls_identity_password: None,
-            #[cfg(feature = "local-http-rustls")]
-            tls_identity_certificate_path: None,
-            #[cfg(feature = "local-http-rustls")]
-            tls_identity_private_key_path: None,
+            Redirect,
+
+            /// For Linux-like systems' Netfilter TPROXY rule.
+            ///
+            /// NOTE: Filter rule `TPROXY` can be applied to TCP and UDP connections.
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            TProxy,
+
+            /// Packet Filter (pf)
+            ///
+            /// Supported by OpenBSD 3.0+, FreeBSD 5.3+, NetBSD 3.0+, Solaris 11.3+, macOS 10.7+, iOS, QNX
+            ///
+            /// Document: https://www.freebsd.org/doc/handbook/firewalls-pf.html
+            #[cfg(any(
+                target_os = "openbsd",
+                target_os = "freebsd",
+                target_os = "netbsd",
+                target_os = "solaris",
+                target_os = "macos",
+                target_os = "ios"
+            ))]
+            PacketFilter,
+
+            /// IPFW
+            ///
+            /// Supported by FreeBSD, macOS 10.6- (Have been removed completely on macOS 10.10)
+            ///
+            /// Document: https://www.freebsd.org/doc/handbook/firewalls-ipfw.html
+            #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "ios"))]
+            IpFirewall,
+        }
+
+        impl RedirType {
+            cfg_if! {
+                if #[cfg(any(target_os = "linux", target_os = "android"))] {
+                    /// Default TCP transparent proxy solution on this platform
+                    pub fn tcp_default() -> RedirType {
+                        RedirType::Redirect
+                    }
+
+                    /// Default UDP transparent proxy solution on this platform
+                    pub fn udp_default() -> RedirType {
+                        RedirType::TProxy
+                    }
+                } else if #[cfg(any(target_os = "openbsd", target_os = "freebsd"))] {
+                    /// Default TCP transparent proxy solution on this platform
+                    pub fn tcp_default() -> RedirType {
+                        RedirType::PacketFilter
+                    }
+
+                    /// Default UDP transparent proxy solution on this platform
+                    pub fn udp_default() -> RedirType {
+                        RedirType::PacketFilter
+                    }
+                } else if #[cfg(any(target_os = "netbsd", target_os = "solaris", target_os = "macos", target_os = "ios"))] {
+                    /// Default TCP transparent proxy solution on this platform
+                    pub fn tcp_default() -> RedirType {
+                        RedirType::PacketFilter
+                    }
+
+                    /// Default UDP transparent proxy solution on this platform
+                    pub fn udp_default() -> RedirType {
+                        RedirType::NotSupported
+                    }
+                } else {
+                    /// Default TCP transparent proxy solution on this platform
+                    pub fn tcp_default() -> RedirType {
+                        RedirType::NotSupported
+                    }
+
+                    /// Default UDP transparent proxy solution on this platform
+                    pub fn udp_default() -> RedirType {
+                        RedirType::NotSupported
+                    }
+                }
+            }
+
+            /// Check if transparent proxy is supported on this platform
+            pub fn is_supported(self) -> bool {
+                self != RedirType::NotSupported
+            }
+
+            /// Name of redirect type (transparent proxy type)
+            pub fn name(self) -> &'static str {
+                match self {
+                    // Dummy, shouldn't be used in any useful situations
+                    RedirType::NotSupported => "not_supported",
+
+                    #[cfg(any(target_os = "linux", target_os = "android"))]
+                    RedirType::Redirect => "redirect",
+
+                    #[cfg(any(target_os = "linux", target_os = "android"))]
+                    RedirType::TProxy => "tproxy",
+
+                    #[cfg(any(
+                        target_os = "openbsd",
+                        target_os = "freebsd",
+                        target_os = "netbsd",
+                        target_os = "solaris",
+                        target_os = "macos",
+                        target_os = "ios"
+                    ))]
+                    RedirType::PacketFilter => "pf",
+
+                    #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "ios"))]
+                    RedirType::IpFirewall => "ipfw",
+                }
+            }
+
+            /// Get all available types
+            pub fn available_types() -> Vec<&'static str> {
+                let mut v = Vec::new();
+                for e in Self::iter() {
+                    match e {
+                        RedirType::NotSupported => continue,
+                        #[allow(unreachable_patterns)]
+                        _ => v.push(e.name()),
+                    }
+                }
+                v
+            }
+        }
+
+        impl Display for RedirType {
+            fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+                f.write_str(self.name())
+            }
+        }
+
+        /// Error type for `RedirType`'s `FromStr::Err`
+        #[derive(Debug)]
+        pub struct InvalidRedirType;
+
+        impl FromStr for RedirType {
+            type Err = InvalidRedirType;
+
+            fn from_str(s: &str) -> Result<RedirType, InvalidRedirType> {
+                match s {
+                    #[cfg(any(target_os = "linux", target_os = "android"))]
+                    "redirect" => Ok(RedirType::Redirect),
+
+                    #[cfg(any(target_os = "linux", target_os = "android"))]
+                    "tproxy" => Ok(RedirType::TProxy),
+
+                    #[cfg(any(
+                        target_os = "openbsd",
+                        target_os = "freebsd",
+                        target_os = "netbsd",
+                        target_os = "solaris",
+                        target_os = "macos",
+                        target_os = "ios",
+                    ))]
+                    "pf" => Ok(RedirType::PacketFilter),
+
+                    #[cfg(any(
+                        target_os = "freebsd",
+                        target_os = "macos",
+                        target_os = "ios",
+                        target_os = "dragonfly"
+                    ))]
+                    "ipfw" => Ok(RedirType::IpFirewall),
+
+                    _ => Err(InvalidRedirType),
+                }
+            }
+        }
+
+        /// Policy of DNS traffic captured by transparent proxy
+        ///
+        /// Applications with hardcoded resolvers may send DNS queries (port `53`) or DNS-over-TLS (port `853`)
+        /// to addresses that are bypassed by ACL rules, which will leak queries to the local network.
+        #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+        pub enum RedirDnsPolicy {
+            /// DNS traffic follows ACL rules, just like other traffic
+            Acl,
+            /// DNS traffic is always sent through proxy servers
+            Proxy,
+            /// DNS traffic is dropped
+            Block,
+        }
+
+        impl RedirDnsPolicy {
+            /// Name of the policy
+            pub fn name(self) -> &'static str {
+                match self {
+                    RedirDnsPolicy::Acl => "acl",
+                    RedirDnsPolicy::Proxy => "proxy",
+                    RedirDnsPolicy::Block => "block",
+                }
+            }
+
+            /// Get all available policies
+            pub fn available_policies() -> &'static [&'static str] {
+                &["acl", "proxy", "block"]
+            }
+
+            /// Check if `port` is a well-known port of DNS (`53`) or DNS-over-TLS (`853`)
+            pub fn is_dns_port(port: u16) -> bool {
+                port == 53 || port == 853
+            }
+        }
+
+        impl Default for RedirDnsPolicy {
+            fn default() -> RedirDnsPolicy {
+                RedirDnsPolicy::Acl
+            }
+        }
+
+        impl Display for RedirDnsPolicy {
+            fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+                f.write_str(self.name())
+            }
+        }
+
+        /// Error type for `RedirDnsPolicy`'s `FromStr::Err`
+        #[derive(Debug)]
+        pub struct InvalidRedirDnsPolicy;
+
+        impl FromStr for RedirDnsPolicy {
+            type Err = InvalidRedirDnsPolicy;
+
+            fn from_str(s: &str) -> Result<RedirDnsPolicy, InvalidRedirDnsPolicy> {
+                match s {
+                    "acl" => Ok(RedirDnsPolicy::Acl),
+                    "proxy" => Ok(RedirDnsPolicy::Proxy),
+                    "block" => Ok(RedirDnsPolicy::Block),
+                    _ => Err(InvalidRedirDnsPolicy),
+                }
+            }
+        }
+    }
+}
+
+/// Host for servers to bind
+///
+/// Servers will bind to a port of this host
+#[derive(Clone, Debug)]
+pub enum ManagerServerHost {
+    /// Domain name
+    Domain(String),
+    /// IP address
+    Ip(IpAddr),
+}
+
+impl Default for ManagerServerHost {
+    fn default() -> ManagerServerHost {
+        ManagerServerHost::Ip(Ipv4Addr::UNSPECIFIED.into())
+    }
+}
+
+impl FromStr for ManagerServerHost {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<IpAddr>() {
+            Ok(s) => Ok(ManagerServerHost::Ip(s)),
+            Err(..) => Ok(ManagerServerHost::Domain(s.to_owned())),
+        }
+    }
+}
+
+impl ManagerServerHost {
+    /// Get resolved socket address for servers to bind
+    pub async fn bind_addr(&self, ctx: &Context, port: u16) -> io::Result<SocketAddr> {
+        match *self {
+            ManagerServerHost::Domain(ref domain) => {
+                let vaddrs = ctx.dns_resolve(domain, port).await?;
+                Ok(vaddrs[0])
+            }
+            ManagerServerHost::Ip(ip) => Ok(SocketAddr::new(ip, port)),
+        }
+    }
+}
+
+/// Configuration for Manager
+#[derive(Clone, Debug)]
+pub struct ManagerConfig {
+    /// Address of `ss-manager`. Send servers' statistic data to the manager server
+    pub addr: ManagerAddr,
+    /// Manager's default method
+    pub method: Option<CipherKind>,
+    /// Timeout for TCP connections, setting to manager's created servers
+    pub timeout: Option<Duration>,
+    /// IP/Host for servers to bind (inbound)
+    ///
+    /// Note: Outbound address is defined in Config.local_addr
+    pub server_host: ManagerServerHost,
+    /// Removed servers stop accepting, established connections are aborted after this timeout
+    ///
+    /// `None` for removing immediately, unless `drain` is specified in the `remove` request
+    pub drain_timeout: Option<Duration>,
+    /// Shared secret for signing commands sent to the manager, unsigned commands are rejected if it is set
+    pub secret: Option<String>,
+    /// Address of the event stream, which is disabled if it is `None`
+    pub events_addr: Option<SocketAddr>,
+    /// Interval of `traffic` events in the event stream
+    pub events_interval: Option<Duration>,
+    /// Address for agents to connect to, which is disabled if it is `None`
+    pub federation_addr: Option<SocketAddr>,
+    /// Address (`host:port`) of the central manager, this manager connects to it as an agent if it is set
+    pub upstream: Option<String>,
+    /// Name of this manager, registered to the central manager
+    pub node_name: Option<String>,
+    /// Address of the HTTP REST API, which is disabled if it is `None`
+    #[cfg(feature = "manager-http")]
+    pub http_addr: Option<SocketAddr>,
+    /// Bearer token required by the HTTP REST API
+    #[cfg(feature = "manager-http")]
+    pub http_token: Option<String>,
+    /// Address of the TLS channel, which is disabled if it is `None`
+    #[cfg(feature = "manager-tls")]
+    pub tls_addr: Option<SocketAddr>,
+    /// Certificate chain of the TLS channel in PEM
+    #[cfg(feature = "manager-tls")]
+    pub tls_cert: Option<PathBuf>,
+    /// Private key of the TLS channel in PEM
+    #[cfg(feature = "manager-tls")]
+    pub tls_key: Option<PathBuf>,
+}
+
+impl ManagerConfig {
+    /// Create a ManagerConfig with default options
+    pub fn new(addr: ManagerAddr) -> ManagerConfig {
+        ManagerConfig {
+            addr,
+            method: None,
+            timeout: None,
+            server_host: ManagerServerHost::default(),
+            drain_timeout: None,
+            secret: None,
+            events_addr: None,
+            events_interval: None,
+            federation_addr: None,
+            upstream: None,
+            node_name: None,
+            #[cfg(feature = "manager-http")]
+            http_addr: None,
+            #[cfg(feature = "manager-http")]
+            http_token: None,
+            #[cfg(feature = "manager-tls")]
+            tls_addr: None,
+            #[cfg(feature = "manager-tls")]
+            tls_cert: None,
+            #[cfg(feature = "manager-tls")]
+            tls_key: None,
+        }
+    }
+
+    /// Get resolved socket address for servers to bind
+    pub async fn bind_addr(&self, ctx: &Context, port: u16) -> io::Result<SocketAddr> {
+        self.server_host.bind_addr(ctx, port).await
+    }
+}
+
+/// A forwarding of tunnel local, besides `local_addr` and `forward`
+#[cfg(feature = "local-tunnel")]
+#[derive(Clone, Debug)]
+pub struct TunnelConfig {
+    /// Listen address
+    pub local_addr: ClientConfig,
+    /// Destination address
+    pub forward_addr: Address,
+    /// Relays TCP and (or) UDP, `mode` of the configuration if `None`
+    pub mode: Option<Mode>,
+}
+
+/// A user of local proxies
+#[derive(Clone)]
+pub struct LocalUser {
+    pub username: String,
+    pub password: String,
+}
+
+impl fmt::Debug for LocalUser {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LocalUser").field("username", &self.username).finish()
+    }
+}
+
+/// Users required to authenticate on local proxies
+#[derive(Clone, Debug, Default)]
+pub struct LocalAuthConfig {
+    /// Users in the configuration
+    pub users: Vec<LocalUser>,
+    /// File of users, lines of `USERNAME:PASSWORD` with `#` comments
+    pub users_file: Option<PathBuf>,
+}
+
+impl LocalAuthConfig {
+    fn from_ssconfig(auth: SSLocalAuthConfig, key: &str) -> Result<LocalAuthConfig, Error> {
+        if auth.scheme.is_some() {
+            let e = Error::new(
+                ErrorKind::Invalid,
+                "`scheme` is only for `http_auth`",
+                Some(key.to_owned()),
+            );
+            return Err(e);
+        }
+
+        let mut nauth = LocalAuthConfig::default();
+        for user in auth.users.unwrap_or_default() {
+            if user.username.is_empty() || user.username.len() > 255 || user.password.len() > 255 {
+                let e = Error::new(
+                    ErrorKind::Invalid,
+                    "invalid user, username must not be empty, username and password must be at most 255 bytes",
+                    Some(format!("`{}` user \"{}\"", key, user.username)),
+                );
+                return Err(e);
+            }
+            nauth.users.push(LocalUser {
+                username: user.username,
+                password: user.password,
+            });
+        }
+        nauth.users_file = auth.users_file.map(PathBuf::from);
+
+        if nauth.users.is_empty() && nauth.users_file.is_none() {
+            let e = Error::new(
+                ErrorKind::MissingField,
+                "missing `users` or `users_file`",
+                Some(key.to_owned()),
+            );
+            return Err(e);
+        }
+        Ok(nauth)
+    }
+
+    fn to_ssconfig(&self) -> SSLocalAuthConfig {
+        let users = self
+            .users
+            .iter()
+            .map(|u| SSLocalUserConfig {
+                username: u.username.clone(),
+                password: u.password.clone(),
+            })
+            .collect::<Vec<_>>();
+        SSLocalAuthConfig {
+            users: if users.is_empty() { None } else { Some(users) },
+            users_file: self.users_file.as_ref().map(|p| p.to_string_lossy().into_owned()),
+            scheme: None,
+        }
+    }
+}
+
+/// Authentication scheme of the HTTP local server
+#[cfg(feature = "local-http")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HttpAuthScheme {
+    /// Basic (RFC7617), passwords are sent in plain text
+    Basic,
+    /// Digest (RFC7616), with `SHA-256` and `MD5` algorithms
+    Digest,
+}
+
+#[cfg(feature = "local-http")]
+impl Default for HttpAuthScheme {
+    fn default() -> HttpAuthScheme {
+        HttpAuthScheme::Basic
+    }
+}
+
+#[cfg(feature = "local-http")]
+impl fmt::Display for HttpAuthScheme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HttpAuthScheme::Basic => f.write_str("basic"),
+            HttpAuthScheme::Digest => f.write_str("digest"),
+        }
+    }
+}
+
+#[cfg(feature = "local-http")]
+impl FromStr for HttpAuthScheme {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "basic" => Ok(HttpAuthScheme::Basic),
+            "digest" => Ok(HttpAuthScheme::Digest),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Users required to authenticate on the HTTP local server
+#[cfg(feature = "local-http")]
+#[derive(Clone, Debug)]
+pub struct HttpAuthConfig {
+    pub users: LocalAuthConfig,
+    pub scheme: HttpAuthScheme,
+}
+
+/// Configuration
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Remote ShadowSocks server configurations
+    pub server: Vec<ServerConfig>,
+    /// Local server's bind address, or ShadowSocks server's outbound address
+    pub local_addr: Option<ClientConfig>,
+    /// Destination address for tunnel
+    #[cfg(feature = "local-tunnel")]
+    pub forward: Option<Address>,
+    /// More forwardings of tunnel local, running in the same process
+    #[cfg(feature = "local-tunnel")]
+    pub tunnels: Vec<TunnelConfig>,
+    /// Username/password authentication (RFC1929) of the SOCKS5 local server, anonymous clients are accepted if `None`
+    pub socks5_auth: Option<LocalAuthConfig>,
+    /// Authentication of the HTTP (HTTPS) local server, anonymous clients are accepted if `None`
+    #[cfg(feature = "local-http")]
+    pub http_auth: Option<HttpAuthConfig>,
+    /// DNS configuration, uses system-wide DNS configuration by default
+    ///
+    /// Value could be a `IpAddr`, uses UDP DNS protocol with port `53`. For example: `8.8.8.8`
+    ///
+    /// Also Value could be some pre-defined DNS server names:
+    ///
+    /// - `google`
+    /// - `cloudflare`, `cloudflare_tls`, `cloudflare_https`
+    /// - `quad9`, `quad9_tls`
+    ///
+    /// Or a DNS-over-HTTPS URL, like `https://cloudflare-dns.com/dns-query`, with feature `dns-over-https`
+    ///
+    /// A list of them are upstreams with their own resolvers, chosen by `dns_strategy`
+    #[cfg(feature = "trust-dns")]
+    pub dns: Vec<ResolverConfig>,
+    /// How queries are sent to upstreams of `dns`
+    #[cfg(feature = "trust-dns")]
+    pub dns_strategy: DnsStrategy,
+    /// Timeout of queries to each upstream of `dns`
+    #[cfg(feature = "trust-dns")]
+    pub dns_timeout: Duration,
+    /// Server mode, `tcp_only`, `tcp_and_udp`, and `udp_only`
+    pub mode: Mode,
+    /// Set `TCP_NODELAY` socket option
+    pub no_delay: bool,
+    /// Send an empty chunk into encrypted TCP connections after they have been idle for this interval
+    ///
+    /// Only for AEAD ciphers. Keeps NAT and middlebox states alive where TCP keepalive doesn't get through (like plugins)
+    pub keep_busy_interval: Option<Duration>,
+    /// Accept TCP connections only after data has arrived, or this timeout elapsed
+    ///
+    /// `TCP_DEFER_ACCEPT` on Linux, `dataready` accept filter on FreeBSD (without timeout). Server only
+    pub tcp_defer_accept: Option<Duration>,
+    /// Ceiling of bytes per second sent to remote hosts by all TCP and UDP relays of this instance
+    ///
+    /// Local: traffic to servers (and bypassed targets). Server: traffic to targets and clients
+    pub egress_limit: Option<u64>,
+    /// Export flow statistics to a time-series backend, server and manager only
+    pub stats_export: Option<StatsExportConfig>,
+    /// Aggregate transferred bytes of servers by destination hosts, keeping this many most recently used hosts
+    ///
+    /// Server and manager only
+    pub destination_stats_capacity: Option<usize>,
+    /// Save traffic counters of servers (and their users) into this file periodically, and restore them on startup
+    ///
+    /// Server and manager only
+    pub flow_stats_persist_path: Option<PathBuf>,
+    /// Set `SO_MARK` socket option for outbound sockets
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub outbound_fwmark: Option<u32>,
+    /// Manager's configuration
+    pub manager: Option<ManagerConfig>,
+    /// Config is for Client or Server
+    pub config_type: ConfigType,
+    /// Timeout for UDP Associations, default is 5 minutes
+    pub udp_timeout: Option<Duration>,
+    /// Maximum number of UDP Associations, default is unconfigured
+    pub udp_max_associations: Option<usize>,
+    /// Keep at most this number of idle outbound UDP sockets (for each address family) for reusing in new associations
+    ///
+    /// Server only, disabled by default
+    pub udp_egress_pool_size: Option<usize>,
+    /// UDP relay's bind address, it uses `local_addr` by default
+    ///
+    /// Resolving Android's issue: https://github.com/shadowsocks/shadowsocks-android/issues/2571
+    pub udp_bind_addr: Option<ClientConfig>,
+    /// `RLIMIT_NOFILE` option for *nix systems
+    pub nofile: Option<u64>,
+    /// ACL configuration
+    pub acl: Option<AccessControl>,
+    /// MaxMind database (mmdb) for matching `geoip:CC` rules in ACL
+    pub geoip_database: Option<PathBuf>,
+    /// v2ray's `geosite.dat` for matching `geosite:CATEGORY` rules in ACL
+    pub geosite_database: Option<PathBuf>,
+    /// TCP Transparent Proxy type
+    #[cfg(feature = "local-redir")]
+    pub tcp_redir: RedirType,
+    /// UDP Transparent Proxy type
+    #[cfg(feature = "local-redir")]
+    pub udp_redir: RedirType,
+    /// Policy of DNS traffic captured by Transparent Proxy
+    #[cfg(feature = "local-redir")]
+    pub redir_dns_policy: RedirDnsPolicy,
+    /// Name of the TUN interface, picked by the system if it is not specified
+    #[cfg(feature = "local-tun")]
+    pub tun_interface_name: Option<String>,
+    /// Address of the TUN interface, the interface is brought up with it
+    ///
+    /// The interface should be configured externally if it is not specified
+    #[cfg(feature = "local-tun")]
+    pub tun_interface_address: Option<Ipv4Net>,
+    /// Flow statistic report Unix socket path (only for Android)
+    #[cfg(feature = "local-flow-stat")]
+    pub stat_path: Option<PathBuf>,
+    /// Path to protect callback unix address, only for Android
+    #[cfg(target_os = "android")]
+    pub protect_path: Option<PathBuf>,
+    /// Internal DNS's bind address
+    #[cfg(feature = "local-dns")]
+    pub dns_bind_addr: Option<ClientConfig>,
+    /// Local DNS's address
+    ///
+    /// Sending DNS query directly to this address
+    #[cfg(feature = "local-dns")]
+    pub local_dns_addr: Option<LocalDnsAddr>,
+    /// Remote DNS's address
+    ///
+    /// Sending DNS query through proxy to this address
+    #[cfg(feature = "local-dns")]
+    pub remote_dns_addr: Option<Address>,
+    /// Fake addresses answered by the DNS relay for proxied domain names
+    ///
+    /// Connections to these addresses accepted by redir are sent to the domain names they were allocated for
+    #[cfg(feature = "local-dns")]
+    pub fake_dns_range: Option<IpNet>,
+    /// Connect to loopback, private (RFC 1918, IPv6 unique local) and link-local addresses directly, client only
+    ///
+    /// Checked before ACL rules
+    pub bypass_lan: bool,
+    /// Uses IPv6 addresses first
+    ///
+    /// Set to `true` if you want to query IPv6 addresses before IPv4
+    pub ipv6_first: bool,
+    /// Cache resolved host names in process, honoring TTLs of records
+    pub dns_cache: Option<DnsCacheConfig>,
+    /// Static addresses of host names (in lower case), resolved before asking the resolver
+    pub hosts: BTreeMap<String, Vec<IpAddr>>,
+    /// Hosts file (like `/etc/hosts`) read for static addresses, entries of `hosts` take precedence
+    pub hosts_file: Option<PathBuf>,
+    /// Pause accepting new connections if memory pressure of the system exceeds this value (in percentage)
+    ///
+    /// - Linux: PSI (Pressure Stall Information) `some avg10` of memory
+    /// - Windows: Memory load
+    pub memory_pressure_threshold: Option<f64>,
+    /// Prefer temporary (privacy) IPv6 addresses as source addresses of outbound connections
+    ///
+    /// Currently only supported on Linux
+    pub ipv6_prefer_temporary: bool,
+    /// Bind outbound IPv6 connections to a random address in this prefix, for servers with a routed prefix (like `/64`)
+    ///
+    /// Requires `IP_FREEBIND`, currently only supported on Linux
+    pub ipv6_outbound_prefix: Option<Ipv6Net>,
+    /// Bind outbound connections to addresses in this pool, used only if `local_addr` is not set
+    pub outbound_address_pool: Option<OutboundAddressPool>,
+    /// Replay filter for detecting repeated IVs/salts
+    pub replay_filter: ReplayFilterConfig,
+    /// Connections logged in detail
+    pub sampling: SamplingConfig,
+    /// Log every closed TCP connection with target `shadowsocks::access::PORT[::USER]`
+    pub access_log: bool,
+    /// Policy for weak methods
+    pub security_level: SecurityLevel,
+    /// How servers are probed by the load balancer
+    pub balancer_probe: BalancerProbe,
+    /// Groups of servers, with their own balancing strategies
+    ///
+    /// Servers are grouped by their `group`, groups not configured here use the default strategy
+    pub server_groups: BTreeMap<String, ServerGroupConfig>,
+    /// Mark a server down after this many consecutive failed probes
+    pub balancer_failure_threshold: u32,
+    /// Mark a down server up after this many consecutive succeeded probes
+    pub balancer_recovery_threshold: u32,
+    /// Race connections to the top-2 ranked servers, the slower one is aborted
+    pub balancer_race: bool,
+    /// Serve probing results of the load balancer on this address, in JSON
+    pub balancer_query_addr: Option<ClientConfig>,
+    /// Serve commands for adding, removing, disabling servers of the load balancer on this address
+    pub balancer_control_addr: Option<ClientConfig>,
+    /// Serve a PAC file generated from ACL on this address, proxied hosts go to the local server
+    #[cfg(feature = "local-http")]
+    pub pac_addr: Option<ClientConfig>,
+    /// Servers delivered online, replacing servers of load balancers without restarting
+    #[cfg(feature = "local-online-config")]
+    pub online_config: Option<OnlineConfig>,
+    /// ACL rules delivered online, replacing `acl` without restarting
+    #[cfg(feature = "local-online-config")]
+    pub online_acl: Option<OnlineAclConfig>,
+    /// Plaintext health-check listener for load balancers, server only
+    pub health_check_addr: Option<ClientConfig>,
+    /// Health-check connections begin with PROXY protocol (v1 or v2) headers
+    pub health_check_proxy_protocol: bool,
+    /// TLS cryptographic identity (X509), PKCS #12 format
+    #[cfg(feature = "local-http-native-tls")]
+    pub tls_identity_path: Option<PathBuf>,
+    /// TLS cryptographic identity's password
+    #[cfg(feature = "local-http-native-tls")]
+    pub tls_identity_password: Option<String>,
+    /// TLS cryptographic identity, certificate file path (PEM)
+    #[cfg(feature = "local-http-rustls")]
+    pub tls_identity_certificate_path: Option<PathBuf>,
+    /// TLS cryptographic identity, private keys (PEM), RSA or PKCS #8
+    #[cfg(feature = "local-http-rustls")]
+    pub tls_identity_private_key_path: Option<PathBuf>,
+}
+
+/// Configuration parsing error kind
+#[derive(Copy, Clone, Debug)]
+pub enum ErrorKind {
+    /// Missing required fields in JSON configuration
+    MissingField,
+    /// Missing some keys that must be provided together
+    Malformed,
+    /// Invalid value of some configuration keys
+    Invalid,
+    /// Invalid JSON
+    JsonParsingError,
+    /// `std::io::Error`
+    IoError,
+}
+
+/// Configuration parsing error
+pub struct Error {
+    pub kind: ErrorKind,
+    pub desc: &'static str,
+    pub detail: Option<String>,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind, desc: &'static str, detail: Option<String>) -> Error {
+        Error { kind, desc, detail }
+    }
+}
+
+macro_rules! impl_from {
+    ($error:ty, $kind:expr, $desc:expr) => {
+        impl From<$error> for Error {
+            fn from(err: $error) -> Self {
+                Error::new($kind, $desc, Some(format!("{:?}", err)))
+            }
+        }
+    };
+}
+
+impl_from!(::std::io::Error, ErrorKind::IoError, "error while reading file");
+impl_from!(json5::Error, ErrorKind::JsonParsingError, "json parse error");
+
+impl Debug for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.detail {
+            None => write!(f, "{}", self.desc),
+            Some(ref det) => write!(f, "{} {}", self.desc, det),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.detail {
+            None => f.write_str(self.desc),
+            Some(ref d) => write!(f, "{}, {}", self.desc, d),
+        }
+    }
+}
+
+impl Config {
+    /// Creates an empty configuration
+    pub fn new(config_type: ConfigType) -> Config {
+        Config {
+            server: Vec::new(),
+            local_addr: None,
+            #[cfg(feature = "local-tunnel")]
+            forward: None,
+            #[cfg(feature = "local-tunnel")]
+            tunnels: Vec::new(),
+            socks5_auth: None,
+            #[cfg(feature = "local-http")]
+            http_auth: None,
+            #[cfg(feature = "trust-dns")]
+            dns: Vec::new(),
+            #[cfg(feature = "trust-dns")]
+            dns_strategy: DnsStrategy::default(),
+            #[cfg(feature = "trust-dns")]
+            dns_timeout: Duration::from_secs(DEFAULT_DNS_TIMEOUT),
+            mode: Mode::TcpOnly,
+            no_delay: false,
+            keep_busy_interval: None,
+            tcp_defer_accept: None,
+            egress_limit: None,
+            stats_export: None,
+            destination_stats_capacity: None,
+            flow_stats_persist_path: None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            outbound_fwmark: None,
+            manager: None,
+            config_type,
+            udp_timeout: None,
+            udp_max_associations: None,
+            udp_egress_pool_size: None,
+            udp_bind_addr: None,
+            nofile: None,
+            acl: None,
+            geoip_database: None,
+            geosite_database: None,
+            #[cfg(feature = "local-redir")]
+            tcp_redir: RedirType::tcp_default(),
+            #[cfg(feature = "local-redir")]
+            udp_redir: RedirType::udp_default(),
+            #[cfg(feature = "local-redir")]
+            redir_dns_policy: RedirDnsPolicy::default(),
+            #[cfg(feature = "local-tun")]
+            tun_interface_name: None,
+            #[cfg(feature = "local-tun")]
+            tun_interface_address: None,
+            #[cfg(feature = "local-flow-stat")]
+            stat_path: None,
+            #[cfg(target_os = "android")]
+            protect_path: None,
+            #[cfg(feature = "local-dns")]
+            dns_bind_addr: None,
+            #[cfg(feature = "local-dns")]
+            local_dns_addr: None,
+            #[cfg(feature = "local-dns")]
+            remote_dns_addr: None,
+            #[cfg(feature = "local-dns")]
+            fake_dns_range: None,
+            bypass_lan: false,
+            ipv6_first: false,
+            dns_cache: None,
+            hosts: BTreeMap::new(),
+            hosts_file: None,
+            memory_pressure_threshold: None,
+            ipv6_prefer_temporary: false,
+            ipv6_outbound_prefix: None,
+            outbound_address_pool: None,
+            replay_filter: ReplayFilterConfig::default(),
+            sampling: SamplingConfig::default(),
+            access_log: false,
+            security_level: SecurityLevel::default(),
+            balancer_probe: BalancerProbe::default(),
+            server_groups: BTreeMap::new(),
+            balancer_failure_threshold: DEFAULT_BALANCER_FAILURE_THRESHOLD,
+            balancer_recovery_threshold: DEFAULT_BALANCER_RECOVERY_THRESHOLD,
+            balancer_race: false,
+            balancer_query_addr: None,
+            balancer_control_addr: None,
+            #[cfg(feature = "local-http")]
+            pac_addr: None,
+            #[cfg(feature = "local-online-config")]
+            online_config: None,
+            #[cfg(feature = "local-online-config")]
+            online_acl: None,
+            health_check_addr: None,
+            health_check_proxy_protocol: false,
+            #[cfg(feature = "local-http-native-tls")]
+            tls_identity_path: None,
+            #[cfg(feature = "local-http-native-tls")]
+            tls_identity_password: None,
+            #[cfg(feature = "local-http-rustls")]
+            tls_identity_certificate_path: None,
+            #[cfg(feature = "local-http-rustls")]
+            tls_identity_private_key_path: None,
+        }
+    }
+
+    fn load_from_ssconfig(config: SSConfig, config_type: ConfigType) -> Result<Config, Error> {
+        let mut nconfig = Config::new(config_type);
+
+        // Databases for ACL of servers and users
+        let acl_databases = RuleDatabases {
+            geoip: config.geoip_database.as_ref().map(PathBuf::from),
+            geosite: config.geosite_database.as_ref().map(PathBuf::from),
+        };
+
+        // Standard config
+        // Client
+        //
+        // local_address is allowed to be NULL, which means to bind to ::1 or 127.0.0.1
+        //
+        // https://shadowsocks.org/en/config/quick-guide.html
+        match config.local_address {
+            Some(la) => {
+                let local_port = if config_type.is_local() {
+                    let local_port = config.local_port.unwrap_or(0);
+                    if local_port == 0 {
+                        let err = Error::new(ErrorKind::MissingField, "missing `local_port`", None);
+                        return Err(err);
+                    }
+                    local_port
+                } else if config_type.is_server() || config_type.is_manager() {
+                    // server's local_port is ignored
+                    0
+                } else {
+                    config.local_port.unwrap_or(0)
+                };
+
+                let local_addr = match la.parse::<IpAddr>() {
+                    Ok(ip) => ServerAddr::from(SocketAddr::new(ip, local_port)),
+                    Err(..) => {
+                        // treated as domain
+                        ServerAddr::from((la, local_port))
+                    }
+                };
+                nconfig.local_addr = Some(local_addr);
+            }
+            None => {
+                if config_type.is_local() && config.local_port.is_some() {
+                    // Implementation note: This is not implemented like libev which will choose IPv6 or IPv6 LoopBack address
+                    // by checking all its remote servers if all of them supports IPv6.
+                    let ip = if config.ipv6_first.unwrap_or(false) {
+                        Ipv6Addr::LOCALHOST.into()
+                    } else {
+                        Ipv4Addr::LOCALHOST.into()
+                    };
+
+                    let local_port = config.local_port.unwrap_or(0);
+                    if local_port == 0 {
+                        let err = Error::new(ErrorKind::MissingField, "`local_port` shouldn't be 0", None);
+                        return Err(err);
+                    }
+
+                    let local_addr = ServerAddr::from(SocketAddr::new(ip, local_port));
+                    nconfig.local_addr = Some(local_addr);
+                }
+            }
+        };
+
+        #[cfg(feature = "local-tunnel")]
+        if let Some(tunnels) = config.tunnels {
+            for tunnel in tunnels {
+                if tunnel.local_port == 0 {
+                    let e = Error::new(ErrorKind::Malformed, "`local_port` of `tunnels` shouldn't be 0", None);
+                    return Err(e);
+                }
+
+                let local_addr = match tunnel.local_address {
+                    Some(la) => match la.parse::<IpAddr>() {
+                        Ok(ip) => ServerAddr::from(SocketAddr::new(ip, tunnel.local_port)),
+                        Err(..) => ServerAddr::from((la, tunnel.local_port)),
+                    },
+                    None if config.ipv6_first.unwrap_or(false) => {
+                        ServerAddr::from(SocketAddr::new(Ipv6Addr::LOCALHOST.into(), tunnel.local_port))
+                    }
+                    None => ServerAddr::from(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), tunnel.local_port)),
+                };
+
+                let forward_addr = match tunnel.forward_address.parse::<Address>() {
+                    Ok(a) => a,
+                    Err(..) => {
+                        let e = Error::new(
+                            ErrorKind::Malformed,
+                            "malformed `forward_address` of `tunnels`",
+                            Some(tunnel.forward_address),
+                        );
+                        return Err(e);
+                    }
+                };
+
+                let mode = match tunnel.mode {
+                    None => None,
+                    Some(m) => match m.parse::<Mode>() {
+                        Ok(m) => Some(m),
+                        Err(..) => {
+                            let e = Error::new(ErrorKind::Malformed, "malformed `mode` of `tunnels`", Some(m));
+                            return Err(e);
+                        }
+                    },
+                };
+
+                nconfig.tunnels.push(TunnelConfig {
+                    local_addr,
+                    forward_addr,
+                    mode,
+                });
+            }
+        }
+
+        if let Some(auth) = config.socks5_auth {
+            nconfig.socks5_auth = Some(LocalAuthConfig::from_ssconfig(auth, "socks5_auth")?);
+        }
+
+        #[cfg(feature = "local-http")]
+        if let Some(mut auth) = config.http_auth {
+            let scheme = match auth.scheme.take() {
+                None => HttpAuthScheme::default(),
+                Some(scheme) => match scheme.parse::<HttpAuthScheme>() {
+                    Ok(s) => s,
+                    Err(..) => {
+                        let e = Error::new(
+                            ErrorKind::Invalid,
+                            "invalid `scheme` in `http_auth`, must be one of `basic` and `digest`",
+                            Some(scheme),
+                        );
+                        return Err(e);
+                    }
+                },
+            };
+            nconfig.http_auth = Some(HttpAuthConfig {
+                users: LocalAuthConfig::from_ssconfig(auth, "http_auth")?,
+                scheme,
+            });
+        }
+
+        // Standard config
+        // Server
+        match (config.server, config.server_port, config.password, config.method) {
+            (Some(address), Some(port), Some(pwd), Some(m)) => {
+                let addr = match address.parse::<Ipv4Addr>() {
+                    Ok(v4) => ServerAddr::SocketAddr(SocketAddr::V4(SocketAddrV4::new(v4, port))),
+                    Err(..) => match address.parse::<Ipv6Addr>() {
+                        Ok(v6) => ServerAddr::SocketAddr(SocketAddr::V6(SocketAddrV6::new(v6, port, 0, 0))),
+                        Err(..) => ServerAddr::DomainName(address, port),
+                    },
+                };
+
+                let method = match m.parse::<CipherKind>() {
+                    Ok(m) => m,
+                    Err(..) => {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "unsupported method",
+                            Some(format!("`{}` is not a supported method", m)),
+                        );
+                        return Err(err);
+                    }
+                };
+
+                let plugin = match config.plugin {
+                    None => None,
+                    Some(p) => {
+                        if p.is_empty() {
+                            // SIP008 allows "plugin" to be an empty string
+                            // Empty string implies "no plugin"
+                            None
+                        } else {
+                            Some(PluginConfig {
+                                plugin: p,
+                                plugin_opts: config.plugin_opts,
+                                plugin_args: config.plugin_args.unwrap_or_default(),
+                            })
+                        }
+                    }
+                };
+
+                let timeout = config.timeout.map(Duration::from_secs);
+                let mut nsvr = ServerConfig::new(addr, pwd, method, timeout, plugin);
+
+                if let Some(kdf) = config.kdf {
+                    nsvr.set_kdf(Config::parse_kdf(kdf)?);
+                }
+
+                if let Some(users) = config.users {
+                    for user in users {
+                        let nuser = nsvr.add_user(user.name, user.password);
+                        if let Some(acl) = user.acl {
+                            nuser.set_acl(Config::load_server_acl(acl, &acl_databases)?);
+                        }
+                    }
+                }
+
+                Config::set_key_rotation(&mut nsvr, config.previous_password, config.rotate_at)?;
+
+                if let Some(passwords) = config.fallback_passwords {
+                    nsvr.set_fallback_passwords(passwords);
+                }
+
+                nconfig.server.push(nsvr);
+            }
+            (None, None, None, None) => (),
+            _ => {
+                let err = Error::new(
+                    ErrorKind::Malformed,
+                    "`server`, `server_port`, `method`, `password` must be provided together",
+                    None,
+                );
+                return Err(err);
+            }
+        }
+
+        // Ext servers
+        if let Some(servers) = config.servers {
+            for svr in servers {
+                let address = svr.server;
+                let port = svr.server_port;
+
+                let addr = match address.parse::<Ipv4Addr>() {
+                    Ok(v4) => ServerAddr::SocketAddr(SocketAddr::V4(SocketAddrV4::new(v4, port))),
+                    Err(..) => match address.parse::<Ipv6Addr>() {
+                        Ok(v6) => ServerAddr::SocketAddr(SocketAddr::V6(SocketAddrV6::new(v6, port, 0, 0))),
+                        Err(..) => ServerAddr::DomainName(address, port),
+                    },
+                };
+
+                let method = match svr.method.parse::<CipherKind>() {
+                    Ok(m) => m,
+                    Err(..) => {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "unsupported method",
+                            Some(format!("`{}` is not a supported method", svr.method)),
+                        );
+                        return Err(err);
+                    }
+                };
+
+                let plugin = match svr.plugin {
+                    None => None,
+                    Some(p) => {
+                        if p.is_empty() {
+                            // SIP008 allows "plugin" to be an empty string
+                            // Empty string implies "no plugin"
+                            None
+                        } else {
+                            Some(PluginConfig {
+                                plugin: p,
+                                plugin_opts: svr.plugin_opts,
+                                plugin_args: svr.plugin_args.unwrap_or_default(),
+                            })
+                        }
+                    }
+                };
+
+                let timeout = svr.timeout.or(config.timeout).map(Duration::from_secs);
+                let mut nsvr = ServerConfig::new(addr, svr.password, method, timeout, plugin);
+
+                if let Some(kdf) = svr.kdf {
+                    nsvr.set_kdf(Config::parse_kdf(kdf)?);
+                }
+
+                nsvr.remarks = svr.remarks;
+                nsvr.id = svr.id;
+                nsvr.weight = svr.weight;
+                nsvr.group = svr.group;
+
+                if let Some(m) = svr.max_connections {
+                    if m == 0 {
+                        let e = Error::new(
+                            ErrorKind::Invalid,
+                            "invalid `max_connections`, must be greater than 0",
+                            None,
+                        );
+                        return Err(e);
+                    }
+                    nsvr.max_connections = Some(m);
+                }
+
+                if let Some(q) = svr.quota {
+                    if q == 0 {
+                        let e = Error::new(ErrorKind::Invalid, "invalid `quota`, must be greater than 0", None);
+                        return Err(e);
+                    }
+                    nsvr.quota = Some(q);
+                }
+
+                if let Some(limit) = svr.speed_limit {
+                    if limit.up == Some(0) || limit.down == Some(0) {
+                        let e = Error::new(
+                            ErrorKind::Invalid,
+                            "invalid `speed_limit`, must be greater than 0",
+                            None,
+                        );
+                        return Err(e);
+                    }
+                    let scope = match limit.scope {
+                        None => SpeedLimitScope::default(),
+                        Some(scope) => match scope.parse::<SpeedLimitScope>() {
+                            Ok(s) => s,
+                            Err(..) => {
+                                let e = Error::new(
+                                    ErrorKind::Invalid,
+                                    "invalid `speed_limit.scope`, must be server, client or connection",
+                                    None,
+                                );
+                                return Err(e);
+                            }
+                        },
+                    };
+                    nsvr.set_speed_limit(SpeedLimit {
+                        up: limit.up,
+                        down: limit.down,
+                        scope,
+                    });
+                }
+
+                if let Some(users) = svr.users {
+                    for user in users {
+                        let nuser = nsvr.add_user(user.name, user.password);
+                        if let Some(acl) = user.acl {
+                            nuser.set_acl(Config::load_server_acl(acl, &acl_databases)?);
+                        }
+                    }
+                }
+
+                if let Some(acl) = svr.acl {
+                    nsvr.set_acl(Config::load_server_acl(acl, &acl_databases)?);
+                }
+
+                Config::set_key_rotation(&mut nsvr, svr.previous_password, svr.rotate_at)?;
+
+                if let Some(passwords) = svr.fallback_passwords {
+                    nsvr.set_fallback_passwords(passwords);
+                }
+
+                nconfig.server.push(nsvr);
+            }
+        }
+
+        // Set timeout globally
+        if let Some(timeout) = config.timeout {
+            let timeout = Duration::from_secs(timeout);
+            // Set as a default timeout
+            for svr in &mut nconfig.server {
+                if svr.timeout.is_none() {
+                    svr.timeout = Some(timeout);
+                }
+            }
+        }
+
+        // Manager Address
+        if let Some(ma) = config.manager_address {
+            let manager = match config.manager_port {
+                Some(port) => {
+                    match ma.parse::<IpAddr>() {
+                        Ok(ip) => ManagerAddr::from(SocketAddr::new(ip, port)),
+                        Err(..) => {
+                            // treated as domain
+                            ManagerAddr::from((ma, port))
+                        }
+                    }
+                }
+                #[cfg(unix)]
+                None => ManagerAddr::from(PathBuf::from(ma)),
+                #[cfg(windows)]
+                None if ma.starts_with(NAMED_PIPE_PREFIX) => ManagerAddr::NamedPipe(ma),
+                #[cfg(not(unix))]
+                None => {
+                    let e = Error::new(ErrorKind::MissingField, "missing `manager_port`", None);
+                    return Err(e);
+                }
+            };
+
+            let mut manager_config = ManagerConfig::new(manager);
+
+            if let Some(t) = config.manager_drain_timeout {
+                if t == 0 {
+                    let e = Error::new(
+                        ErrorKind::Invalid,
+                        "invalid `manager_drain_timeout`, must be greater than 0",
+                        None,
+                    );
+                    return Err(e);
+                }
+                manager_config.drain_timeout = Some(Duration::from_secs(t));
+            }
+
+            if let Some(secret) = config.manager_secret {
+                if secret.is_empty() {
+                    let e = Error::new(ErrorKind::Invalid, "invalid `manager_secret`, must not be empty", None);
+                    return Err(e);
+                }
+                manager_config.secret = Some(secret);
+            }
+
+            if let Some(events_addr) = config.manager_events_address {
+                match events_addr.parse::<SocketAddr>() {
+                    Ok(a) => manager_config.events_addr = Some(a),
+                    Err(..) => {
+                        let e = Error::new(
+                            ErrorKind::Invalid,
+                            "invalid `manager_events_address`, must be ip:port",
+                            None,
+                        );
+                        return Err(e);
+                    }
+                }
+            }
+
+            if let Some(t) = config.manager_events_interval {
+                if t == 0 {
+                    let e = Error::new(
+                        ErrorKind::Invalid,
+                        "invalid `manager_events_interval`, must be greater than 0",
+                        None,
+                    );
+                    return Err(e);
+                }
+                manager_config.events_interval = Some(Duration::from_secs(t));
+            }
+
+            if let Some(federation_addr) = config.manager_federation_address {
+                match federation_addr.parse::<SocketAddr>() {
+                    Ok(a) => manager_config.federation_addr = Some(a),
+                    Err(..) => {
+                        let e = Error::new(
+                            ErrorKind::Invalid,
+                            "invalid `manager_federation_address`, must be ip:port",
+                            None,
+                        );
+                        return Err(e);
+                    }
+                }
+            }
+
+            manager_config.upstream = config.manager_upstream;
+            manager_config.node_name = config.manager_node_name;
+
+            #[cfg(feature = "manager-http")]
+            {
+                if let Some(http_addr) = config.manager_http_address {
+                    match http_addr.parse::<SocketAddr>() {
+                        Ok(a) => manager_config.http_addr = Some(a),
+                        Err(..) => {
+                            let e = Error::new(
+                                ErrorKind::Invalid,
+                                "invalid `manager_http_address`, must be ip:port",
+                                None,
+                            );
+                            return Err(e);
+                        }
+                    }
+                }
+                manager_config.http_token = config.manager_http_token;
+            }
+
+            #[cfg(feature = "manager-tls")]
+            {
+                if let Some(tls_addr) = config.manager_tls_address {
+                    match tls_addr.parse::<SocketAddr>() {
+                        Ok(a) => manager_config.tls_addr = Some(a),
+                        Err(..) => {
+                            let e = Error::new(
+                                ErrorKind::Invalid,
+                                "invalid `manager_tls_address`, must be ip:port",
+                                None,
+                            );
+                            return Err(e);
+                        }
+                    }
+                }
+                manager_config.tls_cert = config.manager_tls_cert.map(PathBuf::from);
+                manager_config.tls_key = config.manager_tls_key.map(PathBuf::from);
+            }
+
+            nconfig.manager = Some(manager_config);
+        }
+
+        // DNS
+        #[cfg(feature = "trust-dns")]
+        {
+            let upstreams = match config.dns {
+                Some(SSDnsConfig::Multiple(v)) => v,
+                Some(c) => vec![c],
+                None => Vec::new(),
+            };
+            for upstream in upstreams {
+                match upstream {
+                    SSDnsConfig::Simple(ds) => nconfig.dns.extend(parse_dns_servers(&ds)?),
+                    SSDnsConfig::TrustDns(c) => nconfig.dns.push(c),
+                    SSDnsConfig::Multiple(..) => {
+                        let e = Error::new(ErrorKind::Invalid, "nested lists in `dns` are not supported", None);
+                        return Err(e);
+                    }
+                }
+            }
+
+            if let Some(st) = config.dns_strategy {
+                match st.parse::<DnsStrategy>() {
+                    Ok(st) => nconfig.dns_strategy = st,
+                    Err(..) => {
+                        let e = Error::new(
+                            ErrorKind::Invalid,
+                            "invalid `dns_strategy`, expecting `failover` or `parallel`",
+                            Some(st),
+                        );
+                        return Err(e);
+                    }
+                }
+            }
+
+            if let Some(t) = config.dns_timeout {
+                if t == 0 {
+                    let e = Error::new(
+                        ErrorKind::Invalid,
+                        "invalid `dns_timeout`, must be greater than 0",
+                        None,
+                    );
+                    return Err(e);
+                }
+                nconfig.dns_timeout = Duration::from_secs(t);
+            }
+        }
+
+        // Mode
+        if let Some(m) = config.mode {
+            match m.parse::<Mode>() {
+                Ok(xm) => nconfig.mode = xm,
+                Err(..) => {
+                    let e = Error::new(
+                        ErrorKind::Malformed,
+                        "malformed `mode`, must be one of `tcp_only`, `udp_only` and `tcp_and_udp`",
+                        None,
+                    );
+                    return Err(e);
+                }
+            }
+        }
+
+        // TCP nodelay
+        if let Some(b) = config.no_delay {
+            nconfig.no_delay = b;
+        }
+
+        // Heartbeat for idle connections
+        if let Some(t) = config.keep_busy_interval {
+            if t == 0 {
+                let e = Error::new(
+                    ErrorKind::Invalid,
+                    "invalid `keep_busy_interval`, must be greater than 0",
+                    None,
+                );
+                return Err(e);
+            }
+            nconfig.keep_busy_interval = Some(Duration::from_secs(t));
+        }
+
+        if let Some(t) = config.tcp_defer_accept {
+            if t == 0 {
+                let e = Error::new(
+                    ErrorKind::Invalid,
+                    "invalid `tcp_defer_accept`, must be greater than 0",
+                    None,
+                );
+                return Err(e);
+            }
+            nconfig.tcp_defer_accept = Some(Duration::from_secs(t));
+        }
+
+        if let Some(limit) = config.egress_limit {
+            if limit == 0 {
+                let e = Error::new(
+                    ErrorKind::Invalid,
+                    "invalid `egress_limit`, must be greater than 0",
+                    None,
+                );
+                return Err(e);
+            }
+            nconfig.egress_limit = Some(limit);
+        }
+
+        if let Some(se) = config.stats_export {
+            let url = match Url::parse(&se.url) {
+                Ok(u) if matches!(u.scheme(), "udp" | "http" | "statsd") && u.host_str().is_some() => u,
+                _ => {
+                    let e = Error::new(
+                        ErrorKind::Malformed,
+                        "invalid `url` in `stats_export`, must be an udp://, http:// or statsd:// URL",
+                        Some(se.url),
+                    );
+                    return Err(e);
+                }
+            };
+
+            let interval = se.interval.unwrap_or(DEFAULT_STATS_EXPORT_INTERVAL);
+            if interval == 0 {
+                let e = Error::new(
+                    ErrorKind::Invalid,
+                    "invalid `interval` in `stats_export`, must be greater than 0",
+                    None,
+                );
+                return Err(e);
+            }
+
+            nconfig.stats_export = Some(StatsExportConfig {
+                url,
+                interval: Duration::from_secs(interval),
+                tags: se.tags.unwrap_or_default(),
+            });
+        }
+
+        if let Some(capacity) = config.destination_stats_capacity {
+            if capacity == 0 {
+                let e = Error::new(
+                    ErrorKind::Invalid,
+                    "invalid `destination_stats_capacity`, must be greater than 0",
+                    None,
+                );
+                return Err(e);
+            }
+            nconfig.destination_stats_capacity = Some(capacity);
+        }
+
+        if let Some(p) = config.flow_stats_persist_path {
+            nconfig.flow_stats_persist_path = Some(PathBuf::from(p));
+        }
+
+        // UDP
+        nconfig.udp_timeout = config.udp_timeout.map(Duration::from_secs);
+
+        // Maximum associations to be kept simultaneously
+        nconfig.udp_max_associations = config.udp_max_associations;
+
+        // Reuse outbound sockets of expired associations
+        nconfig.udp_egress_pool_size = config.udp_egress_pool_size;
+
+        // RLIMIT_NOFILE
+        nconfig.nofile = config.nofile;
+
+        // GeoIP and geosite databases for ACL
+        nconfig.geoip_database = config.geoip_database.map(PathBuf::from);
+        nconfig.geosite_database = config.geosite_database.map(PathBuf::from);
+
+        if let Some(b) = config.bypass_lan {
+            nconfig.bypass_lan = b;
+        }
+
+        // Uses IPv6 first
+        if let Some(f) = config.ipv6_first {
+            nconfig.ipv6_first = f;
+        }
+
+        if let Some(dc) = config.dns_cache {
+            let mut dns_cache = DnsCacheConfig::default();
+            if let Some(capacity) = dc.capacity {
+                if capacity == 0 {
+                    let e = Error::new(
+                        ErrorKind::Invalid,
+                        "invalid `capacity` in `dns_cache`, must be greater than 0",
+                        None,
+                    );
+                    return Err(e);
+                }
+                dns_cache.capacity = capacity;
+            }
+            if let Some(t) = dc.min_ttl {
+                dns_cache.min_ttl = Duration::from_secs(t);
+            }
+            if let Some(t) = dc.max_ttl {
+                dns_cache.max_ttl = Duration::from_secs(t);
+            }
+            if dns_cache.min_ttl > dns_cache.max_ttl {
+                let e = Error::new(
+                    ErrorKind::Invalid,
+                    "invalid `min_ttl` in `dns_cache`, must not be greater than `max_ttl`",
+                    None,
+                );
+                return Err(e);
+            }
+            if let Some(t) = dc.negative_ttl {
+                dns_cache.negative_ttl = Duration::from_secs(t);
+            }
+            nconfig.dns_cache = Some(dns_cache);
         }
-    }
-
-    fn load_from_ssconfig(config: SSConfig, config_type: ConfigType) -> Result<Config, Error> {
-        let mut nconfig = Config::new(config_type);
 
-        // Standard config
-        // Client
-        //
-        // local_address is allowed to be NULL, which means to bind to ::1 or 127.0.0.1
-        //
-        // https://shadowsocks.org/en/config/quick-guide.html
-        match config.local_address {
-            Some(la) => {
-                let local_port = if config_type.is_local() {
-                    let local_port = config.local_port.unwrap_or(0);
-                    if local_port == 0 {
-                        let err = Error::new(ErrorKind::MissingField, "missing `local_port`", None);
-                        return Err(err);
-                    }
-                    local_port
-                } else if config_type.is_server() || config_type.is_manager() {
-                    // server's local_port is ignored
-                    0
-                } else {
-                    config.local_port.unwrap_or(0)
+        if let Some(hosts) = config.hosts {
+            for (name, value) in hosts {
+                let addrs = match value {
+                    SSHostsValue::Single(a) => vec![a],
+                    SSHostsValue::Multiple(a) => a,
                 };
 
-                let local_addr = match la.parse::<IpAddr>() {
-                    Ok(ip) => ServerAddr::from(SocketAddr::new(ip, local_port)),
-                    Err(..) => {
-                        // treated as domain
-                        ServerAddr::from((la, local_port))
+                let mut ips = Vec::with_capacity(addrs.len());
+                for addr in addrs {
+                    match addr.parse::<IpAddr>() {
+                        Ok(ip) => ips.push(ip),
+                        Err(..) => {
+                            let e = Error::new(
+                                ErrorKind::Invalid,
+                                "invalid address in `hosts`, must be an IP address",
+                                Some(addr),
+                            );
+                            return Err(e);
+                        }
                     }
-                };
-                nconfig.local_addr = Some(local_addr);
+                }
+                if ips.is_empty() {
+                    let e = Error::new(ErrorKind::Invalid, "empty addresses in `hosts`", Some(name));
+                    return Err(e);
+                }
+
+                nconfig.hosts.insert(name.to_ascii_lowercase(), ips);
             }
-            None => {
-                if config_type.is_local() && config.local_port.is_some() {
-                    // Implementation note: This is not implemented like libev which will choose IPv6 or IPv6 LoopBack address
-                    // by checking all its remote servers if all of them supports IPv6.
-                    let ip = if config.ipv6_first.unwrap_or(false) {
-                        Ipv6Addr::LOCALHOST.into()
-                    } else {
-                        Ipv4Addr::LOCALHOST.into()
-                    };
+        }
 
-                    let local_port = config.local_port.unwrap_or(0);
-                    if local_port == 0 {
-                        let err = Error::new(ErrorKind::MissingField, "`local_port` shouldn't be 0", None);
-                        return Err(err);
-                    }
+        if let Some(p) = config.hosts_file {
+            nconfig.hosts_file = Some(PathBuf::from(p));
+        }
 
-                    let local_addr = ServerAddr::from(SocketAddr::new(ip, local_port));
-                    nconfig.local_addr = Some(local_addr);
+        #[cfg(feature = "local-dns")]
+        if let Some(range) = config.fake_dns_range {
+            let net = match range.parse::<IpNet>() {
+                Ok(n) => n.trunc(),
+                Err(..) => {
+                    let e = Error::new(ErrorKind::Malformed, "malformed `fake_dns_range`", Some(range));
+                    return Err(e);
                 }
+            };
+            // Network and broadcast addresses are never allocated
+            if net.max_prefix_len() - net.prefix_len() < 2 {
+                let e = Error::new(
+                    ErrorKind::Invalid,
+                    "invalid `fake_dns_range`, prefix is too long to allocate addresses",
+                    Some(range),
+                );
+                return Err(e);
             }
-        };
+            nconfig.fake_dns_range = Some(net);
+        }
 
-        // Standard config
-        // Server
-        match (config.server, config.server_port, config.password, config.method) {
-            (Some(address), Some(port), Some(pwd), Some(m)) => {
-                let addr = match address.parse::<Ipv4Addr>() {
-                    Ok(v4) => ServerAddr::SocketAddr(SocketAddr::V4(SocketAddrV4::new(v4, port))),
-                    Err(..) => match address.parse::<Ipv6Addr>() {
-                        Ok(v6) => ServerAddr::SocketAddr(SocketAddr::V6(SocketAddrV6::new(v6, port, 0, 0))),
-                        Err(..) => ServerAddr::DomainName(address, port),
-                    },
-                };
+        // Memory pressure
+        if let Some(t) = config.memory_pressure_threshold {
+            if !(t > 0.0 && t <= 100.0) {
+                let e = Error::new(
+                    ErrorKind::Invalid,
+                    "invalid `memory_pressure_threshold`, must be in range (0, 100]",
+                    None,
+                );
+                return Err(e);
+            }
+            nconfig.memory_pressure_threshold = Some(t);
+        }
 
-                let method = match m.parse::<CipherKind>() {
-                    Ok(m) => m,
+        // IPv6 outbound source addresses
+        if let Some(b) = config.ipv6_prefer_temporary {
+            nconfig.ipv6_prefer_temporary = b;
+        }
+
+        if let Some(prefix) = config.ipv6_outbound_prefix {
+            match prefix.parse::<Ipv6Net>() {
+                Ok(p) => nconfig.ipv6_outbound_prefix = Some(p.trunc()),
+                Err(..) => {
+                    let e = Error::new(ErrorKind::Malformed, "malformed `ipv6_outbound_prefix`", Some(prefix));
+                    return Err(e);
+                }
+            }
+        }
+
+        // Outbound address pool
+        let rotation = match config.outbound_address_rotation {
+            None => OutboundRotation::default(),
+            Some(r) => match r.parse::<OutboundRotation>() {
+                Ok(r) => r,
+                Err(..) => {
+                    let e = Error::new(
+                        ErrorKind::Invalid,
+                        "invalid `outbound_address_rotation`, expecting `round_robin` or `destination_hash`",
+                        Some(r),
+                    );
+                    return Err(e);
+                }
+            },
+        };
+
+        if let Some(pool) = config.outbound_address_pool {
+            let mut addrs = Vec::with_capacity(pool.len());
+            for addr in pool {
+                match addr.parse::<IpAddr>() {
+                    Ok(a) => addrs.push(a),
                     Err(..) => {
-                        let err = Error::new(
-                            ErrorKind::Invalid,
-                            "unsupported method",
-                            Some(format!("`{}` is not a supported method", m)),
-                        );
-                        return Err(err);
+                        let e = Error::new(ErrorKind::Malformed, "malformed `outbound_address_pool`", Some(addr));
+                        return Err(e);
                     }
-                };
+                }
+            }
 
-                let plugin = match config.plugin {
-                    None => None,
-                    Some(p) => {
-                        if p.is_empty() {
-                            // SIP008 allows "plugin" to be an empty string
-                            // Empty string implies "no plugin"
-                            None
-                        } else {
-                            Some(PluginConfig {
-                                plugin: p,
-                                plugin_opts: config.plugin_opts,
-                                plugin_args: config.plugin_args.unwrap_or_default(),
-                            })
-                        }
-                    }
-                };
+            if !addrs.is_empty() {
+                nconfig.outbound_address_pool = Some(OutboundAddressPool::new(addrs, rotation));
+            }
+        }
 
-                let timeout = config.timeout.map(Duration::from_secs);
-                let nsvr = ServerConfig::new(addr, pwd, method, timeout, plugin);
+        // Replay filter
+        if let Some(c) = config.replay_filter_capacity {
+            // Filter is split into 2 halves
+            if c < 2 {
+                let e = Error::new(
+                    ErrorKind::Invalid,
+                    "invalid `replay_filter_capacity`, must be at least 2",
+                    None,
+                );
+                return Err(e);
+            }
+            nconfig.replay_filter.capacity = Some(c);
+        }
 
-                nconfig.server.push(nsvr);
+        if let Some(p) = config.replay_filter_fp_rate {
+            if !(p > 0.0 && p < 1.0) {
+                let e = Error::new(
+                    ErrorKind::Invalid,
+                    "invalid `replay_filter_fp_rate`, must be in range (0, 1)",
+                    None,
+                );
+                return Err(e);
             }
-            (None, None, None, None) => (),
-            _ => {
-                let err = Error::new(
-                    ErrorKind::Malformed,
-                    "`server`, `server_port`, `method`, `password` must be provided together",
+            nconfig.replay_filter.fp_rate = Some(p);
+        }
+
+        if let Some(t) = config.replay_filter_rotate_interval {
+            if t == 0 {
+                let e = Error::new(
+                    ErrorKind::Invalid,
+                    "invalid `replay_filter_rotate_interval`, must be greater than 0",
                     None,
                 );
-                return Err(err);
+                return Err(e);
             }
+            nconfig.replay_filter.rotate_interval = Some(Duration::from_secs(t));
         }
 
-        // Ext servers
-        if let Some(servers) = config.servers {
-            for svr in servers {
-                let address = svr.server;
-                let port = svr.server_port;
+        if let Some(b) = config.replay_filter_per_listener {
+            nconfig.replay_filter.per_listener = b;
+        }
 
-                let addr = match address.parse::<Ipv4Addr>() {
-                    Ok(v4) => ServerAddr::SocketAddr(SocketAddr::V4(SocketAddrV4::new(v4, port))),
-                    Err(..) => match address.parse::<Ipv6Addr>() {
-                        Ok(v6) => ServerAddr::SocketAddr(SocketAddr::V6(SocketAddrV6::new(v6, port, 0, 0))),
-                        Err(..) => ServerAddr::DomainName(address, port),
-                    },
-                };
+        if let Some(p) = config.replay_filter_persist_path {
+            nconfig.replay_filter.persist_path = Some(PathBuf::from(p));
+        }
 
-                let method = match svr.method.parse::<CipherKind>() {
-                    Ok(m) => m,
-                    Err(..) => {
-                        let err = Error::new(
-                            ErrorKind::Invalid,
-                            "unsupported method",
-                            Some(format!("`{}` is not a supported method", svr.method)),
-                        );
-                        return Err(err);
-                    }
-                };
+        if let Some(l) = config.security_level {
+            match l.parse::<SecurityLevel>() {
+                Ok(l) => nconfig.security_level = l,
+                Err(..) => {
+                    let e = Error::new(
+                        ErrorKind::Invalid,
+                        "invalid `security_level`, must be one of `permissive`, `warn`, `reject` and `strict`",
+                        Some(l),
+                    );
+                    return Err(e);
+                }
+            }
+        }
 
-                let plugin = match svr.plugin {
-                    None => None,
-                    Some(p) => {
-                        if p.is_empty() {
-                            // SIP008 allows "plugin" to be an empty string
-                            // Empty string implies "no plugin"
-                            None
-                        } else {
-                            Some(PluginConfig {
-                                plugin: p,
-                                plugin_opts: svr.plugin_opts,
-                                plugin_args: svr.plugin_args.unwrap_or_default(),
-                            })
+        if let Some(p) = config.balancer_probe {
+            match p.parse::<BalancerProbe>() {
+                Ok(p) => nconfig.balancer_probe = p,
+                Err(..) => {
+                    let e = Error::new(
+                        ErrorKind::Invalid,
+                        "invalid `balancer_probe`, must be one of `request` and `connect`",
+                        Some(p),
+                    );
+                    return Err(e);
+                }
+            }
+        }
+
+        if let Some(groups) = config.groups {
+            for (name, group) in groups {
+                let mut ngroup = ServerGroupConfig::default();
+
+                if let Some(s) = group.strategy {
+                    match s.parse::<BalancerStrategy>() {
+                        Ok(s) => ngroup.strategy = s,
+                        Err(..) => {
+                            let e = Error::new(
+                                ErrorKind::Invalid,
+                                "invalid `strategy` in `groups`, must be `latency`, `round_robin`, `fixed` or `sticky`",
+                                Some(s),
+                            );
+                            return Err(e);
                         }
                     }
-                };
+                }
 
-                let timeout = svr.timeout.or(config.timeout).map(Duration::from_secs);
-                let mut nsvr = ServerConfig::new(addr, svr.password, method, timeout, plugin);
+                if let Some(exclude) = group.sticky_exclude {
+                    ngroup.sticky_exclude = exclude
+                        .into_iter()
+                        .map(|h| h.trim_end_matches('.').to_ascii_lowercase())
+                        .collect();
+                }
 
-                nsvr.remarks = svr.remarks;
-                nsvr.id = svr.id;
+                if !nconfig.server.iter().any(|s| s.group() == name) {
+                    let e = Error::new(
+                        ErrorKind::Invalid,
+                        "group in `groups` doesn't have any servers",
+                        Some(name),
+                    );
+                    return Err(e);
+                }
 
-                nconfig.server.push(nsvr);
+                nconfig.server_groups.insert(name, ngroup);
             }
         }
 
-        // Set timeout globally
-        if let Some(timeout) = config.timeout {
-            let timeout = Duration::from_secs(timeout);
-            // Set as a default timeout
-            for svr in &mut nconfig.server {
-                if svr.timeout.is_none() {
-                    svr.timeout = Some(timeout);
+        if let Some(t) = config.balancer_failure_threshold {
+            if t == 0 {
+                let e = Error::new(
+                    ErrorKind::Invalid,
+                    "invalid `balancer_failure_threshold`, must be greater than 0",
+                    None,
+                );
+                return Err(e);
+            }
+            nconfig.balancer_failure_threshold = t;
+        }
+
+        if let Some(t) = config.balancer_recovery_threshold {
+            if t == 0 {
+                let e = Error::new(
+                    ErrorKind::Invalid,
+                    "invalid `balancer_recovery_threshold`, must be greater than 0",
+                    None,
+                );
+                return Err(e);
+            }
+            nconfig.balancer_recovery_threshold = t;
+        }
+
+        nconfig.balancer_race = config.balancer_race.unwrap_or(false);
+
+        if let Some(a) = config.balancer_query_address {
+            match a.parse::<ServerAddr>() {
+                Ok(addr) => nconfig.balancer_query_addr = Some(addr),
+                Err(..) => {
+                    let e = Error::new(ErrorKind::Malformed, "invalid `balancer_query_address`", Some(a));
+                    return Err(e);
                 }
             }
         }
 
-        // Manager Address
-        if let Some(ma) = config.manager_address {
-            let manager = match config.manager_port {
-                Some(port) => {
-                    match ma.parse::<IpAddr>() {
-                        Ok(ip) => ManagerAddr::from(SocketAddr::new(ip, port)),
-                        Err(..) => {
-                            // treated as domain
-                            ManagerAddr::from((ma, port))
-                        }
-                    }
+        if let Some(a) = config.balancer_control_address {
+            match a.parse::<ServerAddr>() {
+                Ok(addr) => nconfig.balancer_control_addr = Some(addr),
+                Err(..) => {
+                    let e = Error::new(ErrorKind::Malformed, "invalid `balancer_control_address`", Some(a));
+                    return Err(e);
                 }
-                #[cfg(unix)]
-                None => ManagerAddr::from(PathBuf::from(ma)),
-                #[cfg(not(unix))]
-                None => {
-                    let e = Error::new(ErrorKind::MissingField, "missing `manager_port`", None);
+            }
+        }
+
+        #[cfg(feature = "local-http")]
+        if let Some(a) = config.pac_address {
+            match a.parse::<ServerAddr>() {
+                Ok(addr) => nconfig.pac_addr = Some(addr),
+                Err(..) => {
+                    let e = Error::new(ErrorKind::Malformed, "invalid `pac_address`", Some(a));
+                    return Err(e);
+                }
+            }
+        }
+
+        #[cfg(feature = "local-online-config")]
+        if let Some(oc) = config.online_config {
+            let config_url = match Url::parse(&oc.config_url) {
+                Ok(u) if u.scheme() == "https" && u.host_str().is_some() => u,
+                _ => {
+                    let e = Error::new(
+                        ErrorKind::Malformed,
+                        "invalid `config_url` in `online_config`, must be an HTTPS URL",
+                        Some(oc.config_url),
+                    );
                     return Err(e);
                 }
             };
 
-            let manager_config = ManagerConfig::new(manager);
-            nconfig.manager = Some(manager_config);
+            let update_interval = oc.update_interval.unwrap_or(DEFAULT_ONLINE_CONFIG_UPDATE_INTERVAL);
+            if update_interval == 0 {
+                let e = Error::new(
+                    ErrorKind::Invalid,
+                    "invalid `update_interval` in `online_config`, must be greater than 0",
+                    None,
+                );
+                return Err(e);
+            }
+
+            nconfig.online_config = Some(OnlineConfig {
+                config_url,
+                update_interval: Duration::from_secs(update_interval),
+            });
         }
 
-        // DNS
-        #[cfg(feature = "trust-dns")]
-        {
-            nconfig.dns = match config.dns {
-                Some(SSDnsConfig::Simple(ds)) => {
-                    match &ds[..] {
-                        "google" => Some(ResolverConfig::google()),
-
-                        "cloudflare" => Some(ResolverConfig::cloudflare()),
-                        #[cfg(feature = "dns-over-tls")]
-                        "cloudflare_tls" => Some(ResolverConfig::cloudflare_tls()),
-                        #[cfg(feature = "dns-over-https")]
-                        "cloudflare_https" => Some(ResolverConfig::cloudflare_https()),
-
-                        "quad9" => Some(ResolverConfig::quad9()),
-                        #[cfg(feature = "dns-over-tls")]
-                        "quad9_tls" => Some(ResolverConfig::quad9_tls()),
-
-                        nameservers => {
-                            // Set ips directly
-                            // Similar to shadowsocks-libev's `ares_set_servers_ports_csv`
-                            //
-                            // ```
-                            // host[:port][,host[:port]]...
-                            // ```
-                            //
-                            // For example:
-                            //     `192.168.1.100,192.168.1.101,3.4.5.6`
-                            let mut c = ResolverConfig::new();
-                            for part in nameservers.split(',') {
-                                let socket_addr = if let Ok(socket_addr) = part.parse::<SocketAddr>() {
-                                    socket_addr
-                                } else if let Ok(ipaddr) = part.parse::<IpAddr>() {
-                                    SocketAddr::new(ipaddr, 53)
-                                } else {
-                                    let e = Error::new(
-                                        ErrorKind::Invalid,
-                                        "invalid `dns` value, can only be host[:port][,host[:port]]...",
-                                        None,
-                                    );
-                                    return Err(e);
-                                };
-
-                                c.add_name_server(NameServerConfig {
-                                    socket_addr,
-                                    protocol: Protocol::Udp,
-                                    tls_dns_name: None,
-                                    trust_nx_responses: false,
-                                    #[cfg(feature = "dns-over-tls")]
-                                    tls_config: None,
-                                });
-                                c.add_name_server(NameServerConfig {
-                                    socket_addr,
-                                    protocol: Protocol::Tcp,
-                                    tls_dns_name: None,
-                                    trust_nx_responses: false,
-                                    #[cfg(feature = "dns-over-tls")]
-                                    tls_config: None,
-                                });
-                            }
+        #[cfg(feature = "local-online-config")]
+        if let Some(oa) = config.online_acl {
+            let acl_url = match Url::parse(&oa.acl_url) {
+                Ok(u) if u.scheme() == "https" && u.host_str().is_some() => u,
+                _ => {
+                    let e = Error::new(
+                        ErrorKind::Malformed,
+                        "invalid `acl_url` in `online_acl`, must be an HTTPS URL",
+                        Some(oa.acl_url),
+                    );
+                    return Err(e);
+                }
+            };
 
-                            if c.name_servers().is_empty() {
-                                None
-                            } else {
-                                Some(c)
-                            }
-                        }
+            let mut online_acl = OnlineAclConfig::new(acl_url);
+
+            if let Some(public_key) = oa.public_key {
+                match base64::decode(&public_key) {
+                    Ok(k) if k.len() == 32 => online_acl.public_key = Some(k),
+                    _ => {
+                        let e = Error::new(
+                            ErrorKind::Malformed,
+                            "invalid `public_key` in `online_acl`, must be a base64 encoded Ed25519 public key",
+                            Some(public_key),
+                        );
+                        return Err(e);
                     }
                 }
-                Some(SSDnsConfig::TrustDns(c)) => Some(c),
-                None => None,
-            };
-        }
+            }
 
-        // Mode
-        if let Some(m) = config.mode {
-            match m.parse::<Mode>() {
-                Ok(xm) => nconfig.mode = xm,
-                Err(..) => {
+            if let Some(update_interval) = oa.update_interval {
+                if update_interval == 0 {
                     let e = Error::new(
-                        ErrorKind::Malformed,
-                        "malformed `mode`, must be one of `tcp_only`, `udp_only` and `tcp_and_udp`",
+                        ErrorKind::Invalid,
+                        "invalid `update_interval` in `online_acl`, must be greater than 0",
                         None,
                     );
                     return Err(e);
                 }
+                online_acl.update_interval = Duration::from_secs(update_interval);
+            }
+
+            if let Some(cache_path) = oa.cache_path {
+                online_acl.cache_path = PathBuf::from(cache_path);
             }
+
+            nconfig.online_acl = Some(online_acl);
         }
 
-        // TCP nodelay
-        if let Some(b) = config.no_delay {
-            nconfig.no_delay = b;
+        if let Some(a) = config.health_check_address {
+            match a.parse::<ServerAddr>() {
+                Ok(addr) => nconfig.health_check_addr = Some(addr),
+                Err(..) => {
+                    let e = Error::new(ErrorKind::Malformed, "invalid `health_check_address`", Some(a));
+                    return Err(e);
+                }
+            }
         }
+        nconfig.health_check_proxy_protocol = config.health_check_proxy_protocol.unwrap_or(false);
 
-        // UDP
-        nconfig.udp_timeout = config.udp_timeout.map(Duration::from_secs);
+        if let Some(r) = config.sampling_rate {
+            if !(0.0..=100.0).contains(&r) {
+                let e = Error::new(
+                    ErrorKind::Invalid,
+                    "invalid `sampling_rate`, must be a percentage between 0 and 100",
+                    None,
+                );
+                return Err(e);
+            }
+            nconfig.sampling.rate = r;
+        }
 
-        // Maximum associations to be kept simultaneously
-        nconfig.udp_max_associations = config.udp_max_associations;
+        if let Some(clients) = config.sampling_clients {
+            for client in clients {
+                // Accepts both single addresses and CIDRs
+                let net = match client.parse::<IpNet>() {
+                    Ok(n) => n,
+                    Err(..) => match client.parse::<IpAddr>() {
+                        Ok(ip) => {
+                            let prefix_len = if ip.is_ipv4() { 32 } else { 128 };
+                            IpNet::new(ip, prefix_len).expect("max prefix length")
+                        }
+                        Err(..) => {
+                            let e = Error::new(ErrorKind::Malformed, "invalid `sampling_clients`", Some(client));
+                            return Err(e);
+                        }
+                    },
+                };
+                nconfig.sampling.clients.push(net);
+            }
+        }
 
-        // RLIMIT_NOFILE
-        nconfig.nofile = config.nofile;
+        nconfig.access_log = config.access_log.unwrap_or(false);
 
-        // Uses IPv6 first
-        if let Some(f) = config.ipv6_first {
-            nconfig.ipv6_first = f;
+        Ok(nconfig)
+    }
+
+    fn set_key_rotation(
+        svr: &mut ServerConfig,
+        previous_password: Option<String>,
+        rotate_at: Option<u64>,
+    ) -> Result<(), Error> {
+        match (previous_password, rotate_at) {
+            (Some(pwd), rotate_at) => {
+                svr.set_previous_password(pwd);
+                svr.set_rotate_at(rotate_at.map(|t| UNIX_EPOCH + Duration::from_secs(t)));
+            }
+            (None, Some(..)) => {
+                let err = Error::new(
+                    ErrorKind::MissingField,
+                    "`rotate_at` requires `previous_password`",
+                    None,
+                );
+                return Err(err);
+            }
+            (None, None) => {}
         }
 
-        Ok(nconfig)
+        Ok(())
+    }
+
+    fn load_server_acl(path: String, databases: &RuleDatabases) -> Result<Arc<AccessControl>, Error> {
+        match AccessControl::load_from_file_with_databases(&path, databases) {
+            Ok(acl) => Ok(Arc::new(acl)),
+            Err(err) => {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "invalid `acl` of server",
+                    Some(format!("loading ACL \"{}\", {}", path, err)),
+                );
+                Err(err)
+            }
+        }
+    }
+
+    fn parse_kdf(kdf: SSKdfConfig) -> Result<KeyDerivation, Error> {
+        match kdf.algorithm.as_str() {
+            "bytes_to_key" => Ok(KeyDerivation::BytesToKey),
+            "argon2id" => {
+                let salt = match kdf.salt {
+                    Some(salt) if salt.len() >= 8 => salt,
+                    _ => {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "`kdf.salt` of argon2id is required and must be at least 8 bytes",
+                            None,
+                        );
+                        return Err(err);
+                    }
+                };
+
+                let mut params = Argon2Params::new(salt);
+                if let Some(t) = kdf.time_cost {
+                    params.time_cost = t;
+                }
+                if let Some(p) = kdf.parallelism {
+                    params.parallelism = p;
+                }
+                if let Some(m) = kdf.memory_cost {
+                    params.memory_cost = m;
+                }
+
+                if params.time_cost < 1 || params.parallelism < 1 || params.parallelism > 0xFF_FFFF {
+                    let err = Error::new(ErrorKind::Invalid, "invalid `kdf.time_cost` or `kdf.parallelism`", None);
+                    return Err(err);
+                }
+
+                // Argon2 requires at least 8 KiB for each lane
+                if params.memory_cost < 8 * params.parallelism {
+                    let err = Error::new(
+                        ErrorKind::Invalid,
+                        "invalid `kdf.memory_cost`, must be at least 8 * `kdf.parallelism` KiB",
+                        None,
+                    );
+                    return Err(err);
+                }
+
+                Ok(KeyDerivation::Argon2id(params))
+            }
+            _ => {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "invalid `kdf.algorithm`, expecting `bytes_to_key` or `argon2id`",
+                    Some(kdf.algorithm),
+                );
+                Err(err)
+            }
+        }
     }
 
     /// Load Config from a `str`
@@ -1663,8 +4445,12 @@ impl Config {
 
     #[cfg(feature = "trust-dns")]
     /// Get `trust-dns`'s `ResolverConfig` by DNS configuration string
-    pub(crate) fn get_dns_config(&self) -> Option<ResolverConfig> {
-        self.dns.clone()
+    /// Databases for loading ACL rules
+    pub fn acl_databases(&self) -> RuleDatabases {
+        RuleDatabases {
+            geoip: self.geoip_database.clone(),
+            geosite: self.geosite_database.clone(),
+        }
     }
 
     /// Check if there are any plugin are enabled with servers
@@ -1680,7 +4466,18 @@ impl Config {
     /// Check if all required fields are already set
     pub fn check_integrity(&self) -> Result<(), Error> {
         if self.config_type.is_local() {
+            // TUN device doesn't listen
+            #[cfg(feature = "local-tun")]
+            let requires_local_addr = self.config_type != ConfigType::TunLocal;
+            #[cfg(not(feature = "local-tun"))]
+            let requires_local_addr = true;
+            // Tunnel local may only listen on addresses of `tunnels`
+            #[cfg(feature = "local-tunnel")]
+            let requires_local_addr = requires_local_addr
+                && !(self.config_type == ConfigType::TunnelLocal && self.forward.is_none() && !self.tunnels.is_empty());
+
             match self.local_addr {
+                None if !requires_local_addr => {}
                 None => {
                     let err = Error::new(
                         ErrorKind::MissingField,
@@ -1743,6 +4540,54 @@ impl Config {
                 return Err(err);
             }
 
+            if let Some(ref manager) = self.manager {
+                let has_node_name = manager.node_name.as_ref().map_or(false, |n| !n.trim().is_empty());
+                if manager.upstream.is_some() && !has_node_name {
+                    let err = Error::new(
+                        ErrorKind::MissingField,
+                        "missing `manager_node_name`, which is required by `manager_upstream`",
+                        None,
+                    );
+                    return Err(err);
+                }
+
+                // Anyone could register as a node or send commands to agents without signing
+                let has_secret = manager.secret.as_ref().map_or(false, |s| !s.is_empty());
+                if (manager.federation_addr.is_some() || manager.upstream.is_some()) && !has_secret {
+                    let err = Error::new(
+                        ErrorKind::MissingField,
+                        "missing `manager_secret`, which is required by `manager_federation_address` and `manager_upstream`",
+                        None,
+                    );
+                    return Err(err);
+                }
+            }
+
+            #[cfg(feature = "manager-http")]
+            if let Some(ref manager) = self.manager {
+                let has_token = manager.http_token.as_ref().map_or(false, |t| !t.is_empty());
+                if manager.http_addr.is_some() && !has_token {
+                    let err = Error::new(
+                        ErrorKind::MissingField,
+                        "missing `manager_http_token`, which is required by `manager_http_address`",
+                        None,
+                    );
+                    return Err(err);
+                }
+            }
+
+            #[cfg(feature = "manager-tls")]
+            if let Some(ref manager) = self.manager {
+                if manager.tls_addr.is_some() && (manager.tls_cert.is_none() || manager.tls_key.is_none()) {
+                    let err = Error::new(
+                        ErrorKind::MissingField,
+                        "missing `manager_tls_cert` or `manager_tls_key`, which are required by `manager_tls_address`",
+                        None,
+                    );
+                    return Err(err);
+                }
+            }
+
             if let Some(ref addr) = self.local_addr {
                 if addr.port() != 0 {
                     let err = Error::new(
@@ -1764,6 +4609,72 @@ impl Config {
                 }
             }
 
+            if server.passwords().is_some() && !self.config_type.is_local() {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`fallback_passwords` is only for clients",
+                    Some("servers could accept an old password with `previous_password`".to_owned()),
+                );
+                return Err(err);
+            }
+
+            let forbidden = match self.security_level {
+                SecurityLevel::Strict => true,
+                // Clients couldn't refuse inbound connections for their servers
+                SecurityLevel::Reject => self.config_type.is_local(),
+                SecurityLevel::Permissive | SecurityLevel::Warn => false,
+            };
+            if forbidden && is_weak_method(server.method()) {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "weak method is forbidden by `security_level`",
+                    Some(format!("`{}` of server {}", server.method(), server.addr())),
+                );
+                return Err(err);
+            }
+
+            if server.previous_password().is_some() && server.method().category() != CipherCategory::Aead {
+                // Keys are chosen by trying them on the first AEAD chunk
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`previous_password` requires an AEAD method",
+                    Some(format!("`{}` couldn't accept multiple keys", server.method())),
+                );
+                return Err(err);
+            }
+
+            if !server.users().is_empty() {
+                // Users are identified by trying their keys on the first AEAD chunk
+                if server.method().category() != CipherCategory::Aead {
+                    let err = Error::new(
+                        ErrorKind::Invalid,
+                        "`users` requires an AEAD method",
+                        Some(format!("`{}` couldn't identify users", server.method())),
+                    );
+                    return Err(err);
+                }
+
+                for (idx, user) in server.users().iter().enumerate() {
+                    if user.name().is_empty() || user.password().is_empty() {
+                        let err = Error::new(
+                            ErrorKind::Malformed,
+                            "`name` and `password` of user shouldn't be empty",
+                            None,
+                        );
+                        return Err(err);
+                    }
+
+                    if server.users()[..idx].iter().any(|u| u.name() == user.name()) {
+                        let err = Error::new(
+                            ErrorKind::Malformed,
+                            "duplicated user",
+                            Some(format!("user `{}` is defined more than once", user.name())),
+                        );
+                        return Err(err);
+                    }
+                }
+            }
+
             // Server's domain name shouldn't be an empty string
             match server.addr() {
                 ServerAddr::SocketAddr(sa) => {
@@ -1854,8 +4765,12 @@ impl Config {
 
         #[cfg(feature = "local-tunnel")]
         if self.config_type == ConfigType::TunnelLocal {
-            if self.forward.is_none() {
-                let err = Error::new(ErrorKind::MissingField, "missing `forward` in configuration", None);
+            if self.forward.is_none() && self.tunnels.is_empty() {
+                let err = Error::new(
+                    ErrorKind::MissingField,
+                    "missing `forward` or `tunnels` in configuration",
+                    None,
+                );
                 return Err(err);
             }
         }
@@ -1863,6 +4778,23 @@ impl Config {
         Ok(())
     }
 
+    /// All forwardings of tunnel local, `local_addr` to `forward` with `mode`, and `tunnels`
+    #[cfg(feature = "local-tunnel")]
+    pub(crate) fn tunnel_forwards(&self) -> Vec<(ClientConfig, Address, Mode)> {
+        let mut forwards = Vec::with_capacity(self.tunnels.len() + 1);
+        if let (Some(local_addr), Some(forward)) = (&self.local_addr, &self.forward) {
+            forwards.push((local_addr.clone(), forward.clone(), self.mode));
+        }
+        for tunnel in &self.tunnels {
+            forwards.push((
+                tunnel.local_addr.clone(),
+                tunnel.forward_addr.clone(),
+                tunnel.mode.unwrap_or(self.mode),
+            ));
+        }
+        forwards
+    }
+
     /// Check if DNS Relay is enabled
     #[cfg(feature = "local-dns")]
     pub(crate) fn is_local_dns_relay(&self) -> bool {
@@ -1889,11 +4821,48 @@ impl fmt::Display for Config {
             }
         }
 
+        #[cfg(feature = "local-tunnel")]
+        if !self.tunnels.is_empty() {
+            let tunnels = self
+                .tunnels
+                .iter()
+                .map(|t| SSTunnelConfig {
+                    local_address: Some(match t.local_addr {
+                        ServerAddr::SocketAddr(ref sa) => sa.ip().to_string(),
+                        ServerAddr::DomainName(ref dname, ..) => dname.clone(),
+                    }),
+                    local_port: t.local_addr.port(),
+                    forward_address: t.forward_addr.to_string(),
+                    mode: t.mode.map(|m| m.to_string()),
+                })
+                .collect();
+            jconf.tunnels = Some(tunnels);
+        }
+
+        jconf.socks5_auth = self.socks5_auth.as_ref().map(LocalAuthConfig::to_ssconfig);
+        #[cfg(feature = "local-http")]
+        {
+            jconf.http_auth = self.http_auth.as_ref().map(|auth| {
+                let mut jauth = auth.users.to_ssconfig();
+                jauth.scheme = Some(auth.scheme.to_string());
+                jauth
+            });
+        }
+
         // Servers
         // For 1 servers, uses standard configure format
         match self.server.len() {
             0 => {}
-            1 if self.server[0].id().is_none() && self.server[0].remarks.is_none() => {
+            1 if self.server[0].id().is_none()
+                && self.server[0].remarks.is_none()
+                && self.server[0].weight.is_none()
+                && self.server[0].max_connections.is_none()
+                && self.server[0].quota.is_none()
+                && self.server[0].speed_limit.is_none()
+                && self.server[0].group.is_none()
+                && self.server[0].users.is_empty()
+                && self.server[0].acl.is_none() =>
+            {
                 let svr = &self.server[0];
 
                 jconf.server = Some(match *svr.addr() {
@@ -1906,6 +4875,10 @@ impl fmt::Display for Config {
                 });
                 jconf.method = Some(svr.method().to_string());
                 jconf.password = Some(svr.password().to_string());
+                jconf.previous_password = svr.previous_password().map(ToOwned::to_owned);
+                jconf.rotate_at = svr.rotate_at().map(unix_timestamp);
+                jconf.fallback_passwords = svr.passwords().map(|_| svr.fallback_passwords());
+                jconf.kdf = kdf_to_ssconfig(svr.kdf());
                 jconf.plugin = svr.plugin().map(|p| p.plugin.to_string());
                 jconf.plugin_opts = svr.plugin().and_then(|p| p.plugin_opts.clone());
                 jconf.plugin_args = svr.plugin().and_then(|p| {
@@ -1931,6 +4904,10 @@ impl fmt::Display for Config {
                             ServerAddr::DomainName(.., port) => port,
                         },
                         password: svr.password().to_string(),
+                        previous_password: svr.previous_password().map(ToOwned::to_owned),
+                        rotate_at: svr.rotate_at().map(unix_timestamp),
+                        fallback_passwords: svr.passwords().map(|_| svr.fallback_passwords()),
+                        kdf: kdf_to_ssconfig(svr.kdf()),
                         method: svr.method().to_string(),
                         plugin: svr.plugin().map(|p| p.plugin.to_string()),
                         plugin_opts: svr.plugin().and_then(|p| p.plugin_opts.clone()),
@@ -1944,6 +4921,30 @@ impl fmt::Display for Config {
                         timeout: svr.timeout().map(|t| t.as_secs()),
                         remarks: svr.remarks.clone(),
                         id: svr.id.clone(),
+                        weight: svr.weight,
+                        max_connections: svr.max_connections,
+                        quota: svr.quota,
+                        speed_limit: svr.speed_limit.map(|l| SSSpeedLimitConfig {
+                            up: l.up,
+                            down: l.down,
+                            scope: Some(l.scope.to_string()),
+                        }),
+                        group: svr.group.clone(),
+                        users: if svr.users.is_empty() {
+                            None
+                        } else {
+                            Some(
+                                svr.users
+                                    .iter()
+                                    .map(|u| SSServerUserConfig {
+                                        name: u.name.clone(),
+                                        password: u.password.clone(),
+                                        acl: u.acl.as_ref().map(|a| a.file_path().display().to_string()),
+                                    })
+                                    .collect(),
+                            )
+                        },
+                        acl: svr.acl.as_ref().map(|a| a.file_path().display().to_string()),
                     });
                 }
 
@@ -1957,6 +4958,8 @@ impl fmt::Display for Config {
                 ManagerAddr::DomainName(ref dname, ..) => dname.clone(),
                 #[cfg(unix)]
                 ManagerAddr::UnixSocketAddr(ref path) => path.display().to_string(),
+                #[cfg(windows)]
+                ManagerAddr::NamedPipe(ref path) => path.clone(),
             });
 
             jconf.manager_port = match m.addr {
@@ -1964,7 +4967,30 @@ impl fmt::Display for Config {
                 ManagerAddr::DomainName(.., port) => Some(port),
                 #[cfg(unix)]
                 ManagerAddr::UnixSocketAddr(..) => None,
+                #[cfg(windows)]
+                ManagerAddr::NamedPipe(..) => None,
             };
+
+            jconf.manager_drain_timeout = m.drain_timeout.map(|t| t.as_secs());
+            jconf.manager_secret = m.secret.clone();
+            jconf.manager_events_address = m.events_addr.map(|a| a.to_string());
+            jconf.manager_events_interval = m.events_interval.map(|t| t.as_secs());
+            jconf.manager_federation_address = m.federation_addr.map(|a| a.to_string());
+            jconf.manager_upstream = m.upstream.clone();
+            jconf.manager_node_name = m.node_name.clone();
+
+            #[cfg(feature = "manager-http")]
+            {
+                jconf.manager_http_address = m.http_addr.map(|a| a.to_string());
+                jconf.manager_http_token = m.http_token.clone();
+            }
+
+            #[cfg(feature = "manager-tls")]
+            {
+                jconf.manager_tls_address = m.tls_addr.map(|a| a.to_string());
+                jconf.manager_tls_cert = m.tls_cert.as_ref().map(|p| p.to_string_lossy().into_owned());
+                jconf.manager_tls_key = m.tls_key.as_ref().map(|p| p.to_string_lossy().into_owned());
+            }
         }
 
         jconf.mode = Some(self.mode.to_string());
@@ -1973,21 +4999,192 @@ impl fmt::Display for Config {
             jconf.no_delay = Some(self.no_delay);
         }
 
+        jconf.keep_busy_interval = self.keep_busy_interval.map(|t| t.as_secs());
+        jconf.tcp_defer_accept = self.tcp_defer_accept.map(|t| t.as_secs());
+        jconf.egress_limit = self.egress_limit;
+        jconf.destination_stats_capacity = self.destination_stats_capacity;
+        jconf.flow_stats_persist_path = self
+            .flow_stats_persist_path
+            .as_ref()
+            .map(|p| p.to_string_lossy().into_owned());
+        jconf.stats_export = self.stats_export.as_ref().map(|c| SSStatsExportConfig {
+            url: c.url.to_string(),
+            interval: if c.interval.as_secs() != DEFAULT_STATS_EXPORT_INTERVAL {
+                Some(c.interval.as_secs())
+            } else {
+                None
+            },
+            tags: if c.tags.is_empty() { None } else { Some(c.tags.clone()) },
+        });
+
         #[cfg(feature = "trust-dns")]
-        if let Some(ref dns) = self.dns {
-            jconf.dns = Some(SSDnsConfig::TrustDns(dns.clone()));
+        {
+            jconf.dns = match self.dns.len() {
+                0 => None,
+                1 => Some(SSDnsConfig::TrustDns(self.dns[0].clone())),
+                _ => Some(SSDnsConfig::Multiple(
+                    self.dns.iter().cloned().map(SSDnsConfig::TrustDns).collect(),
+                )),
+            };
+            if self.dns_strategy != DnsStrategy::default() {
+                jconf.dns_strategy = Some(self.dns_strategy.to_string());
+            }
+            if self.dns_timeout != Duration::from_secs(DEFAULT_DNS_TIMEOUT) {
+                jconf.dns_timeout = Some(self.dns_timeout.as_secs());
+            }
         }
 
         jconf.udp_timeout = self.udp_timeout.map(|t| t.as_secs());
 
         jconf.udp_max_associations = self.udp_max_associations;
+        jconf.udp_egress_pool_size = self.udp_egress_pool_size;
 
         jconf.nofile = self.nofile;
+        jconf.geoip_database = self.geoip_database.as_ref().map(|p| p.to_string_lossy().into_owned());
+        jconf.geosite_database = self.geosite_database.as_ref().map(|p| p.to_string_lossy().into_owned());
+
+        if self.bypass_lan {
+            jconf.bypass_lan = Some(true);
+        }
 
         if self.ipv6_first {
             jconf.ipv6_first = Some(self.ipv6_first);
         }
 
+        if !self.hosts.is_empty() {
+            let hosts = self
+                .hosts
+                .iter()
+                .map(|(name, ips)| {
+                    let value = match ips[..] {
+                        [ip] => SSHostsValue::Single(ip.to_string()),
+                        _ => SSHostsValue::Multiple(ips.iter().map(ToString::to_string).collect()),
+                    };
+                    (name.clone(), value)
+                })
+                .collect();
+            jconf.hosts = Some(hosts);
+        }
+        jconf.hosts_file = self.hosts_file.as_ref().map(|p| p.to_string_lossy().into_owned());
+
+        #[cfg(feature = "local-dns")]
+        {
+            jconf.fake_dns_range = self.fake_dns_range.as_ref().map(ToString::to_string);
+        }
+
+        jconf.dns_cache = self.dns_cache.map(|c| SSDnsCacheConfig {
+            capacity: Some(c.capacity),
+            min_ttl: Some(c.min_ttl.as_secs()),
+            max_ttl: Some(c.max_ttl.as_secs()),
+            negative_ttl: Some(c.negative_ttl.as_secs()),
+        });
+
+        jconf.memory_pressure_threshold = self.memory_pressure_threshold;
+
+        if self.ipv6_prefer_temporary {
+            jconf.ipv6_prefer_temporary = Some(self.ipv6_prefer_temporary);
+        }
+
+        jconf.ipv6_outbound_prefix = self.ipv6_outbound_prefix.as_ref().map(ToString::to_string);
+
+        if let Some(ref pool) = self.outbound_address_pool {
+            jconf.outbound_address_pool = Some(pool.addrs().iter().map(ToString::to_string).collect());
+            jconf.outbound_address_rotation = Some(pool.rotation().to_string());
+        }
+
+        jconf.replay_filter_capacity = self.replay_filter.capacity;
+        jconf.replay_filter_fp_rate = self.replay_filter.fp_rate;
+        jconf.replay_filter_rotate_interval = self.replay_filter.rotate_interval.map(|t| t.as_secs());
+        if self.replay_filter.per_listener {
+            jconf.replay_filter_per_listener = Some(self.replay_filter.per_listener);
+        }
+        jconf.replay_filter_persist_path = self
+            .replay_filter
+            .persist_path
+            .as_ref()
+            .map(|p| p.to_string_lossy().into_owned());
+
+        if self.balancer_probe != BalancerProbe::Request {
+            jconf.balancer_probe = Some(self.balancer_probe.to_string());
+        }
+        if !self.server_groups.is_empty() {
+            jconf.groups = Some(
+                self.server_groups
+                    .iter()
+                    .map(|(name, group)| {
+                        let group = SSServerGroupConfig {
+                            strategy: Some(group.strategy.to_string()),
+                            sticky_exclude: if group.sticky_exclude.is_empty() {
+                                None
+                            } else {
+                                Some(group.sticky_exclude.clone())
+                            },
+                        };
+                        (name.clone(), group)
+                    })
+                    .collect(),
+            );
+        }
+        if self.balancer_failure_threshold != DEFAULT_BALANCER_FAILURE_THRESHOLD {
+            jconf.balancer_failure_threshold = Some(self.balancer_failure_threshold);
+        }
+        if self.balancer_recovery_threshold != DEFAULT_BALANCER_RECOVERY_THRESHOLD {
+            jconf.balancer_recovery_threshold = Some(self.balancer_recovery_threshold);
+        }
+        if self.balancer_race {
+            jconf.balancer_race = Some(true);
+        }
+        jconf.balancer_query_address = self.balancer_query_addr.as_ref().map(ToString::to_string);
+        jconf.balancer_control_address = self.balancer_control_addr.as_ref().map(ToString::to_string);
+        #[cfg(feature = "local-http")]
+        {
+            jconf.pac_address = self.pac_addr.as_ref().map(ToString::to_string);
+        }
+        #[cfg(feature = "local-online-config")]
+        {
+            jconf.online_config = self.online_config.as_ref().map(|c| SSOnlineConfig {
+                config_url: c.config_url.to_string(),
+                update_interval: if c.update_interval.as_secs() != DEFAULT_ONLINE_CONFIG_UPDATE_INTERVAL {
+                    Some(c.update_interval.as_secs())
+                } else {
+                    None
+                },
+            });
+            jconf.online_acl = self.online_acl.as_ref().map(|c| SSOnlineAcl {
+                acl_url: c.acl_url.to_string(),
+                public_key: c.public_key.as_ref().map(base64::encode),
+                update_interval: if c.update_interval.as_secs() != DEFAULT_ONLINE_CONFIG_UPDATE_INTERVAL {
+                    Some(c.update_interval.as_secs())
+                } else {
+                    None
+                },
+                cache_path: if c.cache_path != OnlineAclConfig::default_cache_path(&c.acl_url) {
+                    Some(c.cache_path.display().to_string())
+                } else {
+                    None
+                },
+            });
+        }
+
+        jconf.health_check_address = self.health_check_addr.as_ref().map(ToString::to_string);
+        if self.health_check_proxy_protocol {
+            jconf.health_check_proxy_protocol = Some(true);
+        }
+
+        if self.security_level != SecurityLevel::Permissive {
+            jconf.security_level = Some(self.security_level.to_string());
+        }
+
+        if self.sampling.rate > 0.0 {
+            jconf.sampling_rate = Some(self.sampling.rate);
+        }
+        if !self.sampling.clients.is_empty() {
+            jconf.sampling_clients = Some(self.sampling.clients.iter().map(ToString::to_string).collect());
+        }
+        if self.access_log {
+            jconf.access_log = Some(true);
+        }
+
         write!(f, "{}", json5::to_string(&jconf).unwrap())
     }
 }
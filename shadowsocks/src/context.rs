@@ -1,39 +1,55 @@
 //! Shadowsocks Server Context
 
-#[cfg(feature = "local-dns")]
-use std::net::IpAddr;
-#[cfg(feature = "local-dns")]
-use std::time::Duration;
 use std::{
-    io,
-    net::SocketAddr,
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    fs,
+    io::{self, BufReader, ErrorKind, Read, Write},
+    net::{IpAddr, SocketAddr},
+    path::Path,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
+    time::{Duration, Instant},
 };
 
 use bloomfilter::Bloom;
-use log::{log_enabled, warn};
+use bytes::Bytes;
+use hmac::{Hmac, Mac, NewMac};
+use log::{debug, info, log_enabled, warn};
 #[cfg(feature = "local-dns")]
 use lru_time_cache::LruCache;
+use sha2::Sha256;
 use spin::Mutex as SpinMutex;
 #[cfg(feature = "local-dns")]
 use tokio::sync::Mutex as AsyncMutex;
+use tokio::{sync::watch, time};
 #[cfg(feature = "trust-dns")]
 use trust_dns_resolver::TokioAsyncResolver;
 
-#[cfg(feature = "trust-dns")]
-use crate::relay::dns_resolver::create_resolver;
 #[cfg(feature = "local-dns")]
-use crate::relay::dnsrelay::upstream::LocalUpstream;
+use crate::relay::dnsrelay::{fake::FakeDns, upstream::LocalUpstream};
 #[cfg(feature = "local-flow-stat")]
 use crate::relay::flow::ServerFlowStatistic;
 use crate::{
-    acl::AccessControl,
-    config::{Config, ConfigType, ServerConfig},
+    acl::{AccessControl, RouteTarget},
+    config::{Config, ConfigType, ReplayFilterConfig, ServerConfig},
     crypto::v1::CipherKind,
-    relay::{dns_resolver::resolve, socks5::Address},
+    relay::{
+        dns_resolver::{resolve, resolve_with_ttl, DnsCache, StaticHosts},
+        loadbalancing::server::{BalancerStatus, ServerProbeStatus, ServerType},
+        selfcheck::SelfCheckReport,
+        server_dns::ServerAddrCache,
+        socks5::Address,
+        speed_limit::TokenBucket,
+        sys::tcp_peer_process_name,
+    },
+};
+#[cfg(feature = "trust-dns")]
+use crate::{
+    config::parse_dns_servers,
+    relay::dns_resolver::{create_resolver, FailoverResolver},
 };
 
 // Entries for server's bloom filter
@@ -56,6 +72,9 @@ const BF_ERROR_RATE_FOR_SERVER: f64 = 1e-6;
 // Borrowed from shadowsocks-libev's default value
 const BF_ERROR_RATE_FOR_CLIENT: f64 = 1e-15;
 
+// Magic header of the persisted replay filter file
+const PPBLOOM_PERSIST_MAGIC: &[u8; 8] = b"SSPPBLM2";
+
 // A bloom filter borrowed from shadowsocks-libev's `ppbloom`
 //
 // It contains 2 bloom filters and each one holds 1/2 entries.
@@ -65,16 +84,23 @@ struct PingPongBloom {
     bloom_count: [usize; 2],
     item_count: usize,
     current: usize,
+    rotate_interval: Option<Duration>,
+    rotated_at: Instant,
 }
 
 impl PingPongBloom {
-    fn new(ty: ConfigType) -> PingPongBloom {
+    fn new(ty: ConfigType, filter_config: &ReplayFilterConfig) -> PingPongBloom {
         let (mut item_count, fp_p) = if ty.is_local() {
             (BF_NUM_ENTRIES_FOR_CLIENT, BF_ERROR_RATE_FOR_CLIENT)
         } else {
             (BF_NUM_ENTRIES_FOR_SERVER, BF_ERROR_RATE_FOR_SERVER)
         };
 
+        if let Some(c) = filter_config.capacity {
+            item_count = c;
+        }
+        let fp_p = filter_config.fp_rate.unwrap_or(fp_p);
+
         item_count /= 2;
 
         PingPongBloom {
@@ -85,6 +111,8 @@ impl PingPongBloom {
             bloom_count: [0, 0],
             item_count,
             current: 0,
+            rotate_interval: filter_config.rotate_interval,
+            rotated_at: Instant::now(),
         }
     }
 
@@ -100,14 +128,20 @@ impl PingPongBloom {
             }
         }
 
-        if self.bloom_count[self.current] >= self.item_count {
-            // Current bloom filter is full,
+        let expired = match self.rotate_interval {
+            Some(interval) => self.rotated_at.elapsed() >= interval,
+            None => false,
+        };
+
+        if self.bloom_count[self.current] >= self.item_count || expired {
+            // Current bloom filter is full (or has been used long enough),
             // Create a new one and use that one as current.
 
             self.current = (self.current + 1) % 2;
 
             self.bloom_count[self.current] = 0;
             self.blooms[self.current].clear();
+            self.rotated_at = Instant::now();
         }
 
         // Cannot be optimized by `check_and_set`
@@ -117,6 +151,140 @@ impl PingPongBloom {
 
         false
     }
+
+    // Serialize states of both bloom filters into `w`
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&(self.item_count as u64).to_be_bytes())?;
+        w.write_all(&(self.current as u64).to_be_bytes())?;
+
+        for (bloom, &count) in self.blooms.iter().zip(self.bloom_count.iter()) {
+            w.write_all(&(count as u64).to_be_bytes())?;
+            w.write_all(&bloom.number_of_bits().to_be_bytes())?;
+            w.write_all(&bloom.number_of_hash_functions().to_be_bytes())?;
+            for &(k0, k1) in bloom.sip_keys().iter() {
+                w.write_all(&k0.to_be_bytes())?;
+                w.write_all(&k1.to_be_bytes())?;
+            }
+
+            let bitmap = bloom.bitmap();
+            w.write_all(&(bitmap.len() as u64).to_be_bytes())?;
+            w.write_all(&bitmap)?;
+        }
+
+        Ok(())
+    }
+
+    // Deserialize from `r`, which is written by `write_to`
+    //
+    // Bitmaps are limited to `max_bits`, the size of filters with the configured capacity and false positive rate,
+    // so a corrupted file couldn't make it allocate arbitrary memory
+    fn read_from<R: Read>(r: &mut R, rotate_interval: Option<Duration>, max_bits: u64) -> io::Result<PingPongBloom> {
+        let item_count = read_u64(r)? as usize;
+        let current = read_u64(r)? as usize;
+        if current > 1 {
+            return Err(io::Error::new(ErrorKind::InvalidData, "invalid current filter index"));
+        }
+
+        let mut blooms = Vec::with_capacity(2);
+        let mut bloom_count = [0usize; 2];
+
+        for count in bloom_count.iter_mut() {
+            *count = read_u64(r)? as usize;
+            let bits = read_u64(r)?;
+            if bits > max_bits {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "bitmap is larger than the configured capacity and false positive rate",
+                ));
+            }
+            let k_num = read_u32(r)?;
+            let sip_keys = [(read_u64(r)?, read_u64(r)?), (read_u64(r)?, read_u64(r)?)];
+
+            let len = read_u64(r)?;
+            if len != (bits + 7) / 8 {
+                return Err(io::Error::new(ErrorKind::InvalidData, "bitmap length mismatch"));
+            }
+            let mut bitmap = vec![0u8; len as usize];
+            r.read_exact(&mut bitmap)?;
+
+            blooms.push(Bloom::from_existing(&bitmap, bits, k_num, sip_keys));
+        }
+
+        let second = blooms.pop().unwrap();
+        let first = blooms.pop().unwrap();
+
+        Ok(PingPongBloom {
+            blooms: [first, second],
+            bloom_count,
+            item_count,
+            current,
+            rotate_interval,
+            rotated_at: Instant::now(),
+        })
+    }
+
+    // Size of each bloom filter, in bits
+    fn max_bits(&self) -> u64 {
+        self.blooms.iter().map(|b| b.number_of_bits()).max().unwrap_or(0)
+    }
+
+    // Check if `other` is created with the same capacity and false positive rate
+    fn is_compatible_with(&self, other: &PingPongBloom) -> bool {
+        self.item_count == other.item_count
+            && self.blooms.iter().zip(other.blooms.iter()).all(|(a, b)| {
+                a.number_of_bits() == b.number_of_bits() && a.number_of_hash_functions() == b.number_of_hash_functions()
+            })
+    }
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+// Identifies shards in the persisted file without writing keys into it
+//
+// HMAC-SHA256 keyed by the key itself, so IDs are stable across builds and couldn't be used for guessing keys
+fn replay_filter_shard_id(key: &[u8]) -> u64 {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts keys in any length");
+    mac.update(b"shadowsocks replay filter shard");
+    let digest = mac.finalize().into_bytes();
+    u64::from_be_bytes(digest[..8].try_into().expect("digest is longer than 8 bytes"))
+}
+
+/// Servers of load balancers updated or mutated at runtime
+#[derive(Default)]
+struct RuntimeServers {
+    // `None` if servers in `config` are used
+    servers: Option<Vec<ServerConfig>>,
+    // Addresses of disabled servers
+    disabled: HashSet<String>,
+}
+
+fn server_not_found(addr: &str) -> io::Error {
+    io::Error::new(ErrorKind::NotFound, format!("server {} doesn't exist", addr))
+}
+
+/// Loopback, private (RFC 1918, IPv6 unique local) and link-local addresses, for `bypass_lan`
+fn is_lan_addr(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(v4) => v4.is_loopback() || v4.is_private() || v4.is_link_local(),
+        IpAddr::V6(v6) => match v6.to_ipv4() {
+            // IPv4-mapped addresses, `::ffff:a.b.c.d`
+            Some(v4) if v6.segments()[5] == 0xFFFF => is_lan_addr(&IpAddr::V4(v4)),
+            _ => {
+                let first = v6.segments()[0];
+                v6.is_loopback() || (first & 0xFE00) == 0xFC00 || (first & 0xFFC0) == 0xFE80
+            }
+        },
+    }
 }
 
 /// Server's global running status
@@ -124,7 +292,26 @@ impl PingPongBloom {
 /// Shared between UDP and TCP servers
 pub struct ServerState {
     #[cfg(feature = "trust-dns")]
-    dns_resolver: Option<TokioAsyncResolver>,
+    dns_resolver: Option<FailoverResolver>,
+
+    // Resolvers of `[dns:UPSTREAM]` sections in ACL, created on first use
+    #[cfg(feature = "trust-dns")]
+    split_dns_resolvers: SpinMutex<HashMap<String, TokioAsyncResolver>>,
+
+    // System is under memory pressure, accepting new connections should be paused
+    memory_pressured: AtomicBool,
+
+    // ACL rules, swapped when they are reloaded
+    acl: SpinMutex<Option<Arc<AccessControl>>>,
+
+    // Shared by all relays, for `egress_limit`
+    egress_limiter: Option<TokenBucket>,
+
+    // Resolved host names, for `dns_cache`
+    dns_cache: Option<DnsCache>,
+
+    // Static addresses of `hosts` and `hosts_file`
+    static_hosts: Option<StaticHosts>,
 }
 
 #[cfg(feature = "trust-dns")]
@@ -132,17 +319,23 @@ impl ServerState {
     /// Create a global shared server state
     pub async fn new_shared(config: &Config) -> SharedServerState {
         let state = ServerState {
-            dns_resolver: match create_resolver(config.get_dns_config(), config.ipv6_first).await {
+            dns_resolver: match FailoverResolver::new(config).await {
                 Ok(resolver) => Some(resolver),
                 Err(..) => None,
             },
+            split_dns_resolvers: SpinMutex::new(HashMap::new()),
+            memory_pressured: AtomicBool::new(false),
+            acl: SpinMutex::new(config.acl.clone().map(Arc::new)),
+            egress_limiter: config.egress_limit.map(TokenBucket::new),
+            dns_cache: config.dns_cache.map(DnsCache::new),
+            static_hosts: StaticHosts::load(config),
         };
 
         Arc::new(state)
     }
 
     /// Get the global shared resolver
-    pub fn dns_resolver(&self) -> Option<&TokioAsyncResolver> {
+    pub fn dns_resolver(&self) -> Option<&FailoverResolver> {
         self.dns_resolver.as_ref()
     }
 }
@@ -150,8 +343,51 @@ impl ServerState {
 #[cfg(not(feature = "trust-dns"))]
 impl ServerState {
     /// Create a global shared server state
-    pub async fn new_shared(_config: &Config) -> SharedServerState {
-        Arc::new(ServerState {})
+    pub async fn new_shared(config: &Config) -> SharedServerState {
+        Arc::new(ServerState {
+            memory_pressured: AtomicBool::new(false),
+            acl: SpinMutex::new(config.acl.clone().map(Arc::new)),
+            egress_limiter: config.egress_limit.map(TokenBucket::new),
+            dns_cache: config.dns_cache.map(DnsCache::new),
+            static_hosts: StaticHosts::load(config),
+        })
+    }
+}
+
+impl ServerState {
+    /// Check if the system is under memory pressure
+    pub fn memory_pressured(&self) -> bool {
+        self.memory_pressured.load(Ordering::Acquire)
+    }
+
+    /// Set memory pressure status
+    pub fn set_memory_pressured(&self, pressured: bool) {
+        self.memory_pressured.store(pressured, Ordering::Release)
+    }
+
+    /// Current ACL rules
+    pub fn acl(&self) -> Option<Arc<AccessControl>> {
+        self.acl.lock().clone()
+    }
+
+    /// Swap ACL rules, established connections are not affected
+    pub fn set_acl(&self, acl: AccessControl) {
+        *self.acl.lock() = Some(Arc::new(acl));
+    }
+
+    /// Token bucket of `egress_limit`, shared by all TCP and UDP relays
+    pub(crate) fn egress_limiter(&self) -> Option<&TokenBucket> {
+        self.egress_limiter.as_ref()
+    }
+
+    /// Cache of resolved host names, shared by all relays
+    pub(crate) fn dns_cache(&self) -> Option<&DnsCache> {
+        self.dns_cache.as_ref()
+    }
+
+    /// Static addresses of host names
+    pub(crate) fn static_hosts(&self) -> Option<&StaticHosts> {
+        self.static_hosts.as_ref()
     }
 }
 
@@ -173,6 +409,24 @@ pub struct Context {
     // https://github.com/shadowsocks/shadowsocks-org/issues/44
     nonce_ppbloom: SpinMutex<PingPongBloom>,
 
+    // Separated bloom filters for each server's key, if `replay_filter.per_listener` is enabled
+    //
+    // Keyed by key instead of address, because a replayed IV/salt is valid on every server with the same key
+    nonce_ppbloom_shards: HashMap<Bytes, SpinMutex<PingPongBloom>>,
+
+    // Result of the startup self-check
+    self_check_report: SpinMutex<Option<SelfCheckReport>>,
+
+    // Latest probing results of load balancers
+    balancer_status: SpinMutex<BalancerStatus>,
+
+    // Servers replacing `config.server` in load balancers, like servers delivered online
+    servers_tx: watch::Sender<Option<Arc<Vec<ServerConfig>>>>,
+    servers_rx: watch::Receiver<Option<Arc<Vec<ServerConfig>>>>,
+
+    // Servers updated or mutated at runtime, including disabled ones
+    runtime_servers: SpinMutex<RuntimeServers>,
+
     // For Android's flow stat report
     #[cfg(feature = "local-flow-stat")]
     local_flow_statistic: ServerFlowStatistic,
@@ -184,6 +438,16 @@ pub struct Context {
     // For local DNS upstream
     #[cfg(feature = "local-dns")]
     local_dns: Option<LocalUpstream>,
+
+    // Fake addresses answered by DNS relay, for `fake_dns_range`
+    #[cfg(feature = "local-dns")]
+    fake_dns: Option<FakeDns>,
+
+    // Prefetched addresses of servers with domain names
+    server_addr_cache: ServerAddrCache,
+
+    // Client IPs holding authenticated UDP ASSOCIATE connections, for `socks5_auth`
+    socks5_udp_clients: SpinMutex<HashMap<IpAddr, usize>>,
 }
 
 /// Unique context thw whole server
@@ -218,19 +482,37 @@ impl Context {
             }
         }
 
-        let nonce_ppbloom = SpinMutex::new(PingPongBloom::new(config.config_type));
+        let nonce_ppbloom = SpinMutex::new(PingPongBloom::new(config.config_type, &config.replay_filter));
+        let mut nonce_ppbloom_shards = HashMap::new();
+        if config.replay_filter.per_listener {
+            for server in &config.server {
+                nonce_ppbloom_shards
+                    .entry(server.clone_key())
+                    .or_insert_with(|| SpinMutex::new(PingPongBloom::new(config.config_type, &config.replay_filter)));
+            }
+        }
         #[cfg(feature = "local-dns")]
         let local_dns = if config.local_dns_addr.is_some() {
             Some(LocalUpstream::new(&config))
         } else {
             None
         };
+        #[cfg(feature = "local-dns")]
+        let fake_dns = config.fake_dns_range.map(FakeDns::new);
 
-        Context {
+        let (servers_tx, servers_rx) = watch::channel(None);
+
+        let context = Context {
             config,
             server_state,
             server_running: AtomicBool::new(true),
             nonce_ppbloom,
+            nonce_ppbloom_shards,
+            self_check_report: SpinMutex::new(None),
+            balancer_status: SpinMutex::new(BalancerStatus::default()),
+            servers_tx,
+            servers_rx,
+            runtime_servers: SpinMutex::new(RuntimeServers::default()),
             #[cfg(feature = "local-flow-stat")]
             local_flow_statistic: ServerFlowStatistic::new(),
             #[cfg(feature = "local-dns")]
@@ -239,7 +521,21 @@ impl Context {
             ))),
             #[cfg(feature = "local-dns")]
             local_dns,
+            #[cfg(feature = "local-dns")]
+            fake_dns,
+            server_addr_cache: ServerAddrCache::new(),
+            socks5_udp_clients: SpinMutex::new(HashMap::new()),
+        };
+
+        if let Some(path) = context.replay_filter_persist_path() {
+            match context.load_replay_filter(path) {
+                Ok(()) => info!("replay filter restored from {}", path.display()),
+                Err(ref err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => warn!("failed to restore replay filter from {}, {}", path.display(), err),
+            }
         }
+
+        context
     }
 
     /// Create a shared `Context`, wrapped in `Arc`
@@ -283,14 +579,47 @@ impl Context {
 
     #[cfg(feature = "trust-dns")]
     /// Get the global shared resolver
-    pub fn dns_resolver(&self) -> Option<&TokioAsyncResolver> {
+    pub fn dns_resolver(&self) -> Option<&FailoverResolver> {
         self.server_state.dns_resolver()
     }
 
+    #[cfg(feature = "trust-dns")]
+    /// Get the resolver of a `[dns:UPSTREAM]` section in ACL, created on first use
+    pub(crate) async fn split_dns_resolver(&self, upstream: &str) -> Option<TokioAsyncResolver> {
+        if let Some(resolver) = self.server_state.split_dns_resolvers.lock().get(upstream) {
+            return Some(resolver.clone());
+        }
+
+        // Checked while loading ACL
+        let config = match parse_dns_servers(upstream) {
+            Ok(Some(c)) => c,
+            _ => return None,
+        };
+        match create_resolver(Some(config), self.config.ipv6_first).await {
+            Ok(resolver) => {
+                let mut resolvers = self.server_state.split_dns_resolvers.lock();
+                Some(resolvers.entry(upstream.to_owned()).or_insert(resolver).clone())
+            }
+            Err(err) => {
+                warn!("failed to create DNS resolver of [dns:{}], error: {}", upstream, err);
+                None
+            }
+        }
+    }
+
+    /// Cache of resolved host names, if `dns_cache` is enabled
+    pub(crate) fn dns_cache(&self) -> Option<&DnsCache> {
+        self.server_state.dns_cache()
+    }
+
+    /// Static addresses of host names, if `hosts` or `hosts_file` is configured
+    pub(crate) fn static_hosts(&self) -> Option<&StaticHosts> {
+        self.server_state.static_hosts()
+    }
+
     /// Perform a DNS resolution
     pub async fn dns_resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
         if log_enabled!(log::Level::Debug) {
-            use log::debug;
             use std::time::Instant;
 
             let start = Instant::now();
@@ -325,6 +654,56 @@ impl Context {
         resolve(self, host, port).await
     }
 
+    /// Perform a DNS resolution bypassing `dns_cache`, returns addresses with their remaining TTL if it is known
+    pub(crate) async fn dns_resolve_with_ttl(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> io::Result<(Vec<SocketAddr>, Option<Duration>)> {
+        #[cfg(feature = "local-dns")]
+        if let Some(ref local_dns) = self.local_dns {
+            return local_dns.lookup_ip(self, host, port).await.map(|addrs| (addrs, None));
+        }
+
+        resolve_with_ttl(self, host, port).await
+    }
+
+    /// Prefetched addresses of servers with domain names (for client)
+    pub(crate) fn server_addr_cache(&self) -> &ServerAddrCache {
+        &self.server_addr_cache
+    }
+
+    /// Resolve the domain name of a server, prefetched addresses are used if there are any (for client)
+    pub(crate) async fn resolve_server_addr(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        match self.server_addr_cache.get(host, port) {
+            Some(addrs) => Ok(addrs),
+            None => self.dns_resolve(host, port).await,
+        }
+    }
+
+    /// Accept UDP packets from `ip` on the SOCKS5 local server, until the UDP ASSOCIATE connection is closed
+    pub(crate) fn add_socks5_udp_client(&self, ip: IpAddr) {
+        *self.socks5_udp_clients.lock().entry(ip).or_insert(0) += 1;
+    }
+
+    /// Remove a client added by `add_socks5_udp_client`
+    pub(crate) fn remove_socks5_udp_client(&self, ip: IpAddr) {
+        let mut clients = self.socks5_udp_clients.lock();
+        if let Some(n) = clients.get_mut(&ip) {
+            *n -= 1;
+            if *n == 0 {
+                clients.remove(&ip);
+            }
+        }
+    }
+
+    /// Check if UDP packets from `ip` are accepted on the SOCKS5 local server
+    ///
+    /// All clients are accepted if `socks5_auth` is not configured
+    pub(crate) fn is_socks5_udp_client(&self, ip: IpAddr) -> bool {
+        self.config.socks5_auth.is_none() || self.socks5_udp_clients.lock().contains_key(&ip)
+    }
+
     /// Check if the server is still in running state
     pub fn server_running(&self) -> bool {
         self.server_running.load(Ordering::Acquire)
@@ -335,20 +714,116 @@ impl Context {
         self.server_running.store(false, Ordering::Release)
     }
 
+    /// Wait until the system is not under memory pressure
+    ///
+    /// Accept loops call this before accepting new connections
+    pub async fn wait_memory_available(&self) {
+        while self.server_state.memory_pressured() && self.server_running() {
+            time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
     /// Check if nonce exist or not
     ///
-    /// If not, set into the current bloom filter
-    pub fn check_nonce_and_set(&self, nonce: &[u8]) -> bool {
+    /// If not, set into the current bloom filter. `key` is the key of the server, for choosing the bloom filter
+    /// if `replay_filter.per_listener` is enabled.
+    pub fn check_nonce_and_set(&self, key: &[u8], nonce: &[u8]) -> bool {
         // Plain cipher doesn't have a nonce
         // Always treated as non-duplicated
         if nonce.is_empty() {
             return false;
         }
 
-        let mut ppbloom = self.nonce_ppbloom.lock();
+        let mut ppbloom = match self.nonce_ppbloom_shards.get(key) {
+            Some(shard) => shard.lock(),
+            None => self.nonce_ppbloom.lock(),
+        };
         ppbloom.check_and_set(nonce)
     }
 
+    /// Save all replay filters into `replay_filter.persist_path`
+    ///
+    /// Written into a temporary file and then renamed, so a crash while saving won't leave a broken file
+    pub fn save_replay_filter(&self) -> io::Result<()> {
+        let path = match self.replay_filter_persist_path() {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+
+        // Serialize into memory first, filters are locked as shortly as possible
+        let mut buf = Vec::new();
+        buf.extend_from_slice(PPBLOOM_PERSIST_MAGIC);
+        buf.extend_from_slice(&(self.nonce_ppbloom_shards.len() as u32).to_be_bytes());
+
+        self.nonce_ppbloom.lock().write_to(&mut buf)?;
+        for (key, shard) in &self.nonce_ppbloom_shards {
+            buf.extend_from_slice(&replay_filter_shard_id(key).to_be_bytes());
+            shard.lock().write_to(&mut buf)?;
+        }
+
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+
+        fs::write(&tmp_path, &buf)?;
+        fs::rename(&tmp_path, path)
+    }
+
+    // Only servers relay with the filter, manager's own context doesn't
+    fn replay_filter_persist_path(&self) -> Option<&Path> {
+        if !self.config.config_type.is_server() {
+            return None;
+        }
+        self.config.replay_filter.persist_path.as_deref()
+    }
+
+    fn load_replay_filter(&self, path: &Path) -> io::Result<()> {
+        let mut r = BufReader::new(fs::File::open(path)?);
+
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != PPBLOOM_PERSIST_MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not a replay filter file"));
+        }
+
+        let rotate_interval = self.config.replay_filter.rotate_interval;
+        let restore = |current: &SpinMutex<PingPongBloom>, restored: PingPongBloom| -> io::Result<()> {
+            let mut current = current.lock();
+            if !current.is_compatible_with(&restored) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "replay filter parameters changed",
+                ));
+            }
+            *current = restored;
+            Ok(())
+        };
+
+        // Shards are created with the same parameters
+        let max_bits = self.nonce_ppbloom.lock().max_bits();
+
+        let num_shards = read_u32(&mut r)?;
+        restore(
+            &self.nonce_ppbloom,
+            PingPongBloom::read_from(&mut r, rotate_interval, max_bits)?,
+        )?;
+
+        for _ in 0..num_shards {
+            let id = read_u64(&mut r)?;
+            let restored = PingPongBloom::read_from(&mut r, rotate_interval, max_bits)?;
+
+            // Shards of removed keys are dropped
+            let shard = self
+                .nonce_ppbloom_shards
+                .iter()
+                .find(|(key, _)| replay_filter_shard_id(key) == id);
+            if let Some((_, shard)) = shard {
+                restore(shard, restored)?;
+            }
+        }
+
+        Ok(())
+    }
+
     /// Check client ACL (for server)
     pub async fn check_client_blocked(&self, addr: &SocketAddr) -> bool {
         match self.acl() {
@@ -393,8 +868,10 @@ impl Context {
     }
 
     /// Get ACL control instance
-    pub fn acl(&self) -> Option<&AccessControl> {
-        self.config.acl.as_ref()
+    ///
+    /// Rules may be reloaded, so the returned instance shouldn't be kept for long
+    pub fn acl(&self) -> Option<Arc<AccessControl>> {
+        self.server_state.acl()
     }
 
     /// Get local DNS connector
@@ -403,14 +880,53 @@ impl Context {
         &self.local_dns.as_ref().expect("local DNS uninitialized")
     }
 
+    /// Get allocator of fake addresses, if `fake_dns_range` is configured
+    #[cfg(feature = "local-dns")]
+    pub fn fake_dns(&self) -> Option<&FakeDns> {
+        self.fake_dns.as_ref()
+    }
+
+    /// Map a destination of redir or TUN back to the domain name, if it is a fake address answered by DNS relay
+    ///
+    /// Fake addresses that are not allocated (or have been recycled) couldn't be connected
+    pub fn map_fake_addr(&self, addr: SocketAddr) -> io::Result<Address> {
+        #[cfg(feature = "local-dns")]
+        if let Some(ref fake_dns) = self.fake_dns {
+            if fake_dns.net().contains(&addr.ip()) {
+                return match fake_dns.lookup(&addr.ip()) {
+                    Some(name) => Ok(Address::DomainNameAddress(name, addr.port())),
+                    None => Err(io::Error::new(
+                        ErrorKind::NotFound,
+                        format!("fake address {} is not allocated", addr.ip()),
+                    )),
+                };
+            }
+        }
+
+        Ok(Address::SocketAddress(addr))
+    }
+
     /// Check target address ACL (for client)
     pub async fn check_target_bypassed(&self, target: &Address) -> bool {
+        if self.config.bypass_lan {
+            if let Address::SocketAddress(ref saddr) = *target {
+                if is_lan_addr(&saddr.ip()) {
+                    return true;
+                }
+            }
+        }
+
         match self.acl() {
             // Proxy everything by default
             None => false,
             Some(a) => {
                 #[cfg(feature = "local-dns")]
                 {
+                    // `[route:TARGET]` rules take precedence over the reverse lookup cache
+                    if a.check_target_route(target).is_some() {
+                        return self.check_target_bypassed_with_acl(&a, target).await;
+                    }
+
                     if let Address::SocketAddress(ref saddr) = target {
                         // do the reverse lookup in our local cache
                         let mut reverse_lookup_cache = self.reverse_lookup_cache.lock().await;
@@ -421,11 +937,63 @@ impl Context {
                     }
                 }
 
-                self.check_target_bypassed_with_acl(a, target).await
+                self.check_target_bypassed_with_acl(&a, target).await
             }
         }
     }
 
+    /// Find the route of a TCP connection accepted by a local listener by ACL's `process:NAME` rules (for client)
+    ///
+    /// The owner process of `peer_addr` (on this host) is only looked up if there are process rules.
+    pub async fn check_process_route(&self, peer_addr: SocketAddr) -> Option<RouteTarget> {
+        let acl = self.acl()?;
+        if !acl.has_process_rules() {
+            return None;
+        }
+
+        let lookup = move || tcp_peer_process_name(peer_addr);
+        let name = match tokio::task::spawn_blocking(lookup).await {
+            Ok(Ok(Some(name))) => name,
+            Ok(Ok(None)) => {
+                debug!("owner process of connection from {} is not found", peer_addr);
+                return None;
+            }
+            Ok(Err(err)) => {
+                debug!("failed to find owner process of connection from {}, {}", peer_addr, err);
+                return None;
+            }
+            Err(err) => {
+                debug!("failed to find owner process of connection from {}, {}", peer_addr, err);
+                return None;
+            }
+        };
+
+        let route = acl.check_process_route(&name).cloned();
+        if let Some(ref route) = route {
+            debug!("connection {} from process {} is routed to {}", peer_addr, name, route);
+        }
+        route
+    }
+
+    /// Check if connections to `target` should be refused by ACL's `[route:reject]` rules (for client)
+    pub fn check_target_rejected(&self, target: &Address) -> bool {
+        match self.acl() {
+            None => false,
+            Some(a) => a.check_target_rejected(target),
+        }
+    }
+
+    /// Check if UDP flow to `target` should be bypassed, with the first packet of the flow
+    ///
+    /// UDP flow rules (ports, protocols) are checked before the target address
+    pub async fn check_udp_target_bypassed(&self, target: &Address, payload: &[u8]) -> bool {
+        if let Some(bypassed) = self.acl().and_then(|a| a.check_udp_flow_bypassed(target, payload)) {
+            return bypassed;
+        }
+
+        self.check_target_bypassed(target).await
+    }
+
     #[inline(always)]
     async fn check_target_bypassed_with_acl(&self, a: &AccessControl, target: &Address) -> bool {
         a.check_target_bypassed(self, target).await
@@ -436,4 +1004,139 @@ impl Context {
     pub fn local_flow_statistic(&self) -> &ServerFlowStatistic {
         &self.local_flow_statistic
     }
+
+    /// Set result of the startup self-check
+    pub fn set_self_check_report(&self, report: SelfCheckReport) {
+        *self.self_check_report.lock() = Some(report);
+    }
+
+    /// Result of the startup self-check, `None` if it hasn't finished yet
+    pub fn self_check_report(&self) -> Option<SelfCheckReport> {
+        self.self_check_report.lock().clone()
+    }
+
+    /// Set the latest probing results of the `server_type` load balancer
+    pub fn set_balancer_status(&self, server_type: ServerType, servers: Vec<ServerProbeStatus>) {
+        let mut status = self.balancer_status.lock();
+        match server_type {
+            ServerType::Tcp => status.tcp = servers,
+            ServerType::Udp => status.udp = servers,
+        }
+    }
+
+    /// Latest probing results of load balancers, empty if servers are not probed
+    pub fn balancer_status(&self) -> BalancerStatus {
+        self.balancer_status.lock().clone()
+    }
+
+    /// Replace servers of load balancers without restarting, like servers delivered online
+    ///
+    /// Servers in `config` are kept for the other purposes, like plugins and self-checking.
+    /// Servers disabled by `set_server_enabled` are still disabled if they are in `servers`
+    pub fn update_servers(&self, servers: Vec<ServerConfig>) {
+        let mut runtime = self.runtime_servers.lock();
+        runtime.servers = Some(servers);
+        self.publish_servers(&runtime);
+    }
+
+    /// Servers of load balancers, with whether they are enabled
+    pub fn server_list(&self) -> Vec<(ServerConfig, bool)> {
+        let runtime = self.runtime_servers.lock();
+        let servers = runtime.servers.as_ref().unwrap_or(&self.config.server);
+        servers
+            .iter()
+            .map(|svr_cfg| (svr_cfg.clone(), !runtime.disabled.contains(&svr_cfg.addr().to_string())))
+            .collect()
+    }
+
+    /// Add a server to load balancers without restarting
+    ///
+    /// Servers with plugins are not supported, because plugins are only started with the process
+    pub fn add_server(&self, svr_cfg: ServerConfig) -> io::Result<()> {
+        if svr_cfg.plugin().is_some() {
+            let err = io::Error::new(
+                ErrorKind::InvalidInput,
+                "servers with plugins couldn't be added at runtime",
+            );
+            return Err(err);
+        }
+
+        self.mutate_servers(|servers, _| {
+            let addr = svr_cfg.addr().to_string();
+            if servers.iter().any(|s| s.addr().to_string() == addr) {
+                let err = io::Error::new(ErrorKind::AlreadyExists, format!("server {} already exists", addr));
+                return Err(err);
+            }
+
+            servers.push(svr_cfg);
+            Ok(())
+        })
+    }
+
+    /// Remove the server with address `addr` from load balancers without restarting
+    pub fn remove_server(&self, addr: &str) -> io::Result<()> {
+        self.mutate_servers(|servers, disabled| {
+            let before = servers.len();
+            servers.retain(|s| s.addr().to_string() != addr);
+            if servers.len() == before {
+                return Err(server_not_found(addr));
+            }
+
+            disabled.remove(addr);
+            Ok(())
+        })
+    }
+
+    /// Enable or disable the server with address `addr`, disabled servers are kept but not balanced
+    pub fn set_server_enabled(&self, addr: &str, enabled: bool) -> io::Result<()> {
+        self.mutate_servers(|servers, disabled| {
+            if !servers.iter().any(|s| s.addr().to_string() == addr) {
+                return Err(server_not_found(addr));
+            }
+
+            if enabled {
+                disabled.remove(addr);
+            } else {
+                disabled.insert(addr.to_owned());
+            }
+            Ok(())
+        })
+    }
+
+    /// Apply `f` to the server list, which is published only if at least one server is enabled after that
+    fn mutate_servers<F>(&self, f: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut Vec<ServerConfig>, &mut HashSet<String>) -> io::Result<()>,
+    {
+        let mut runtime = self.runtime_servers.lock();
+
+        let mut servers = runtime.servers.clone().unwrap_or_else(|| self.config.server.clone());
+        let mut disabled = runtime.disabled.clone();
+        f(&mut servers, &mut disabled)?;
+
+        if servers.iter().all(|s| disabled.contains(&s.addr().to_string())) {
+            let err = io::Error::new(ErrorKind::InvalidInput, "at least one server must be enabled");
+            return Err(err);
+        }
+
+        runtime.servers = Some(servers);
+        runtime.disabled = disabled;
+        self.publish_servers(&runtime);
+        Ok(())
+    }
+
+    fn publish_servers(&self, runtime: &RuntimeServers) {
+        let servers = runtime.servers.as_ref().unwrap_or(&self.config.server);
+        let enabled = servers
+            .iter()
+            .filter(|s| !runtime.disabled.contains(&s.addr().to_string()))
+            .cloned()
+            .collect();
+        let _ = self.servers_tx.send(Some(Arc::new(enabled)));
+    }
+
+    /// Subscribe servers replaced by `update_servers`, `None` if servers in `config` are used
+    pub fn subscribe_servers(&self) -> watch::Receiver<Option<Arc<Vec<ServerConfig>>>> {
+        self.servers_rx.clone()
+    }
 }
@@ -91,6 +91,7 @@ pub use shadowsocks_crypto as crypto;
 pub mod acl;
 pub mod config;
 pub mod context;
+pub mod migrate;
 pub mod plugin;
 pub mod relay;
 
@@ -0,0 +1,570 @@
+//! Migrating configurations from other shadowsocks implementations
+//!
+//! Supported sources:
+//!
+//! - shadowsocks-libev's JSON configuration
+//! - go-shadowsocks2's command line arguments
+//! - Legacy (Python) shadowsocks' JSON configuration, including `port_password`
+//!
+//! Sources are translated to this crate's configuration format and then loaded by `Config::load_from_str`,
+//! so the result is validated just like a handwritten configuration.
+
+use std::str::FromStr;
+
+use serde_json::{Map, Value};
+
+use crate::config::{Config, ConfigType, Error, ErrorKind};
+
+/// Format of the configuration to be migrated
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SourceFormat {
+    /// shadowsocks-libev's JSON configuration
+    Libev,
+    /// go-shadowsocks2's command line arguments
+    GoShadowsocks2,
+    /// Legacy (Python) shadowsocks' JSON configuration
+    Legacy,
+}
+
+impl FromStr for SourceFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<SourceFormat, ()> {
+        match s {
+            "libev" | "shadowsocks-libev" => Ok(SourceFormat::Libev),
+            "go-ss2" | "go-shadowsocks2" => Ok(SourceFormat::GoShadowsocks2),
+            "legacy" | "python" => Ok(SourceFormat::Legacy),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Result of a migration
+#[derive(Debug)]
+pub struct Migrated {
+    /// Configuration in this crate's format
+    pub config: Config,
+    /// Options that couldn't be migrated and were dropped
+    pub warnings: Vec<String>,
+}
+
+/// Plugins that have different executables for clients and servers, (client, server)
+const PLUGIN_PAIRS: &[(&str, &str)] = &[
+    ("obfs-local", "obfs-server"),
+    ("gq-client", "gq-server"),
+    ("ck-client", "ck-server"),
+    ("kcptun-client", "kcptun-server"),
+];
+
+/// Migrate configuration `input` of `format` to a `Config` of `config_type`
+pub fn migrate(format: SourceFormat, input: &str, config_type: ConfigType) -> Result<Migrated, Error> {
+    let mut warnings = Vec::new();
+
+    let jconf = match format {
+        SourceFormat::Libev => from_libev(input, config_type, &mut warnings)?,
+        SourceFormat::GoShadowsocks2 => from_go_shadowsocks2(input, config_type, &mut warnings)?,
+        SourceFormat::Legacy => from_legacy(input, config_type, &mut warnings)?,
+    };
+
+    let config = Config::load_from_str(&Value::Object(jconf).to_string(), config_type)?;
+    Ok(Migrated { config, warnings })
+}
+
+fn load_object(input: &str) -> Result<Map<String, Value>, Error> {
+    match json5::from_str::<Value>(input)? {
+        Value::Object(o) => Ok(o),
+        _ => Err(Error::new(
+            ErrorKind::Malformed,
+            "configuration must be a JSON object",
+            None,
+        )),
+    }
+}
+
+/// Collect server entries shared by shadowsocks-libev and legacy configurations
+///
+/// Both of them accept `server` as a string or an array of hosts, and a `port_password` map instead of
+/// `server_port` and `password`.
+fn collect_servers(
+    src: &mut Map<String, Value>,
+    config_type: ConfigType,
+    jconf: &mut Map<String, Value>,
+) -> Result<(), Error> {
+    let hosts = match src.remove("server") {
+        Some(Value::String(s)) => vec![s],
+        Some(Value::Array(a)) => {
+            let mut hosts = Vec::with_capacity(a.len());
+            for h in a {
+                match h {
+                    Value::String(s) => hosts.push(s),
+                    _ => return Err(Error::new(ErrorKind::Malformed, "`server` must be strings", None)),
+                }
+            }
+            hosts
+        }
+        Some(..) => return Err(Error::new(ErrorKind::Malformed, "`server` must be strings", None)),
+        // Servers listen on all interfaces by default
+        None if config_type.is_server() => vec!["0.0.0.0".to_owned()],
+        None => return Err(Error::new(ErrorKind::MissingField, "missing `server`", None)),
+    };
+
+    let mut accounts = Vec::new();
+    match src.remove("port_password") {
+        Some(Value::Object(pp)) => {
+            for (port, password) in pp {
+                let port = match port.parse::<u16>() {
+                    Ok(p) => p,
+                    Err(..) => {
+                        return Err(Error::new(
+                            ErrorKind::Malformed,
+                            "invalid port in `port_password`",
+                            Some(port),
+                        ))
+                    }
+                };
+                match password {
+                    Value::String(p) => accounts.push((Value::from(port), Value::String(p))),
+                    _ => {
+                        return Err(Error::new(
+                            ErrorKind::Malformed,
+                            "passwords in `port_password` must be strings",
+                            None,
+                        ))
+                    }
+                }
+            }
+
+            // Ignored if `port_password` presents, the same as the origin implementations
+            src.remove("server_port");
+            src.remove("password");
+        }
+        Some(..) => {
+            return Err(Error::new(
+                ErrorKind::Malformed,
+                "`port_password` must be an object",
+                None,
+            ))
+        }
+        None => match (src.remove("server_port"), src.remove("password")) {
+            (Some(port), Some(password)) => accounts.push((port, password)),
+            (None, _) => return Err(Error::new(ErrorKind::MissingField, "missing `server_port`", None)),
+            (_, None) => return Err(Error::new(ErrorKind::MissingField, "missing `password`", None)),
+        },
+    }
+
+    let method = src.remove("method");
+    let timeout = src.remove("timeout");
+    let plugin = match src.remove("plugin") {
+        Some(Value::String(p)) if !p.is_empty() => Some(Value::String(translate_plugin(&p, config_type))),
+        _ => None,
+    };
+    let plugin_opts = src.remove("plugin_opts");
+
+    let mut servers = Vec::with_capacity(hosts.len() * accounts.len());
+    for host in &hosts {
+        for &(ref port, ref password) in &accounts {
+            let mut svr = Map::new();
+            svr.insert("address".to_owned(), Value::String(host.clone()));
+            svr.insert("port".to_owned(), port.clone());
+            svr.insert("password".to_owned(), password.clone());
+            insert_opt(&mut svr, "method", method.clone());
+            insert_opt(&mut svr, "timeout", timeout.clone());
+            insert_opt(&mut svr, "plugin", plugin.clone());
+            if plugin.is_some() {
+                insert_opt(&mut svr, "plugin_opts", plugin_opts.clone());
+            }
+            servers.push(Value::Object(svr));
+        }
+    }
+    jconf.insert("servers".to_owned(), Value::Array(servers));
+
+    Ok(())
+}
+
+fn from_libev(input: &str, config_type: ConfigType, warnings: &mut Vec<String>) -> Result<Map<String, Value>, Error> {
+    let mut src = load_object(input)?;
+    let mut jconf = Map::new();
+
+    collect_servers(&mut src, config_type, &mut jconf)?;
+
+    // Options with the same meaning
+    for key in &[
+        "local_address",
+        "local_port",
+        "mode",
+        "no_delay",
+        "ipv6_first",
+        "nofile",
+    ] {
+        insert_opt(&mut jconf, key, src.remove(*key));
+    }
+    insert_opt(&mut jconf, "dns", src.remove("nameserver"));
+
+    unsupported_options(src, warnings);
+    Ok(jconf)
+}
+
+fn from_legacy(input: &str, config_type: ConfigType, warnings: &mut Vec<String>) -> Result<Map<String, Value>, Error> {
+    let mut src = load_object(input)?;
+    let mut jconf = Map::new();
+
+    // One time auth was removed from shadowsocks, servers and clients must be upgraded together
+    let ota = matches!(src.remove("one_time_auth"), Some(Value::Bool(true)))
+        || matches!(src.get("method"), Some(Value::String(m)) if m.ends_with("-auth"));
+    if ota {
+        return Err(Error::new(
+            ErrorKind::Invalid,
+            "one time auth is not supported, use an AEAD method instead",
+            None,
+        ));
+    }
+
+    collect_servers(&mut src, config_type, &mut jconf)?;
+
+    insert_opt(&mut jconf, "local_address", src.remove("local_address"));
+    insert_opt(&mut jconf, "local_port", src.remove("local_port"));
+    insert_opt(&mut jconf, "ipv6_first", src.remove("prefer_ipv6"));
+
+    match src.remove("dns_server") {
+        Some(Value::Array(servers)) => {
+            let servers: Vec<&str> = servers.iter().filter_map(Value::as_str).collect();
+            if !servers.is_empty() {
+                jconf.insert("dns".to_owned(), Value::String(servers.join(",")));
+            }
+        }
+        Some(Value::String(s)) => {
+            jconf.insert("dns".to_owned(), Value::String(s));
+        }
+        _ => {}
+    }
+
+    unsupported_options(src, warnings);
+    Ok(jconf)
+}
+
+fn from_go_shadowsocks2(
+    input: &str,
+    config_type: ConfigType,
+    warnings: &mut Vec<String>,
+) -> Result<Map<String, Value>, Error> {
+    let mut args = split_args(input).into_iter().peekable();
+
+    // Skip the executable's path, if any
+    if let Some(first) = args.peek() {
+        if !first.starts_with('-') {
+            args.next();
+        }
+    }
+
+    let mut server_url = None;
+    let mut client_url = None;
+    let mut cipher = None;
+    let mut password = None;
+    let mut socks = None;
+    let mut plugin = None;
+    let mut plugin_opts = None;
+    let mut udp = false;
+    let mut udp_timeout = None;
+
+    while let Some(arg) = args.next() {
+        // Go's flag package accepts both `-flag` and `--flag`, with value in the next argument or after `=`
+        let flag = arg.trim_start_matches('-');
+        let (name, inline_value) = match flag.find('=') {
+            Some(pos) => (&flag[..pos], Some(flag[pos + 1..].to_owned())),
+            None => (flag, None),
+        };
+
+        match name {
+            "u" | "udpsocks" => {
+                udp = inline_value.map(|v| v != "false").unwrap_or(true);
+                continue;
+            }
+            "verbose" => continue,
+            _ => {}
+        }
+
+        let value = match inline_value.or_else(|| args.next()) {
+            Some(v) => v,
+            None => return Err(Error::new(ErrorKind::Malformed, "missing value of flag", Some(arg))),
+        };
+
+        match name {
+            "s" => server_url = Some(value),
+            "c" => client_url = Some(value),
+            "cipher" => cipher = Some(value),
+            "password" => password = Some(value),
+            "socks" => socks = Some(value),
+            "plugin" => plugin = Some(value),
+            "plugin-opts" => plugin_opts = Some(value),
+            "udptimeout" => match parse_go_duration(&value) {
+                Some(secs) => udp_timeout = Some(secs),
+                None => return Err(Error::new(ErrorKind::Malformed, "invalid `-udptimeout`", Some(value))),
+            },
+            "key" => {
+                return Err(Error::new(
+                    ErrorKind::Invalid,
+                    "raw keys couldn't be migrated, use `-password` instead of `-key`",
+                    None,
+                ))
+            }
+            "keygen" => {}
+            _ => warnings.push(format!("flag \"-{}\" is not supported, ignored", name)),
+        }
+    }
+
+    let url = if config_type.is_server() {
+        server_url
+    } else {
+        client_url
+    };
+    let url = match url {
+        Some(u) => u,
+        None if config_type.is_server() => return Err(Error::new(ErrorKind::MissingField, "missing `-s`", None)),
+        None => return Err(Error::new(ErrorKind::MissingField, "missing `-c`", None)),
+    };
+
+    let (url_cipher, url_password, host, port) = parse_go_url(&url)?;
+    let cipher = match url_cipher.or(cipher) {
+        Some(c) => c,
+        None => return Err(Error::new(ErrorKind::MissingField, "missing cipher", None)),
+    };
+    let password = match url_password.or(password) {
+        Some(p) => p,
+        None => return Err(Error::new(ErrorKind::MissingField, "missing password", None)),
+    };
+
+    let mut svr = Map::new();
+    svr.insert("address".to_owned(), Value::String(host));
+    svr.insert("port".to_owned(), Value::from(port));
+    svr.insert("method".to_owned(), Value::String(translate_go_cipher(&cipher)));
+    svr.insert("password".to_owned(), Value::String(password));
+    if let Some(p) = plugin {
+        svr.insert("plugin".to_owned(), Value::String(translate_plugin(&p, config_type)));
+        insert_opt(&mut svr, "plugin_opts", plugin_opts.map(Value::String));
+    }
+
+    let mut jconf = Map::new();
+    jconf.insert("servers".to_owned(), Value::Array(vec![Value::Object(svr)]));
+
+    if config_type.is_local() {
+        let socks = match socks {
+            Some(s) => s,
+            None => return Err(Error::new(ErrorKind::MissingField, "missing `-socks`", None)),
+        };
+        let (host, port) = split_host_port(&socks)?;
+        jconf.insert("local_address".to_owned(), Value::String(host));
+        jconf.insert("local_port".to_owned(), Value::from(port));
+    }
+
+    if udp {
+        jconf.insert("mode".to_owned(), Value::String("tcp_and_udp".to_owned()));
+    }
+    insert_opt(&mut jconf, "udp_timeout", udp_timeout.map(Value::from));
+
+    Ok(jconf)
+}
+
+fn insert_opt(m: &mut Map<String, Value>, key: &str, value: Option<Value>) {
+    if let Some(v) = value {
+        m.insert(key.to_owned(), v);
+    }
+}
+
+fn unsupported_options(src: Map<String, Value>, warnings: &mut Vec<String>) {
+    for key in src.keys() {
+        warnings.push(format!("option \"{}\" is not supported, ignored", key));
+    }
+}
+
+/// Translate plugin's executable name to the one for `config_type`
+fn translate_plugin(plugin: &str, config_type: ConfigType) -> String {
+    // Plugin may be configured with its path
+    let (dir, name) = match plugin.rfind('/') {
+        Some(pos) => plugin.split_at(pos + 1),
+        None => ("", plugin),
+    };
+
+    for &(client, server) in PLUGIN_PAIRS {
+        if name == client || name == server {
+            let name = if config_type.is_server() { server } else { client };
+            return format!("{}{}", dir, name);
+        }
+    }
+
+    plugin.to_owned()
+}
+
+/// go-shadowsocks2 accepts its own cipher names, case insensitive, and aliases of the standard names
+fn translate_go_cipher(cipher: &str) -> String {
+    match cipher.to_uppercase().as_str() {
+        "AEAD_CHACHA20_POLY1305" | "CHACHA20-IETF-POLY1305" => "chacha20-ietf-poly1305".to_owned(),
+        "AEAD_AES_128_GCM" | "AES-128-GCM" => "aes-128-gcm".to_owned(),
+        "AEAD_AES_256_GCM" | "AES-256-GCM" => "aes-256-gcm".to_owned(),
+        "DUMMY" => "none".to_owned(),
+        _ => cipher.to_lowercase(),
+    }
+}
+
+/// Parse go-shadowsocks2's URL, `ss://[CIPHER:PASSWORD@]HOST:PORT` or just `HOST:PORT`
+fn parse_go_url(url: &str) -> Result<(Option<String>, Option<String>, String, u16), Error> {
+    let rest = url.strip_prefix("ss://").unwrap_or(url);
+    let rest = rest.trim_end_matches('/');
+
+    let (userinfo, addr) = match rest.rfind('@') {
+        Some(pos) => (Some(&rest[..pos]), &rest[pos + 1..]),
+        None => (None, rest),
+    };
+
+    let (cipher, password) = match userinfo {
+        Some(ui) => match ui.find(':') {
+            Some(pos) => (Some(percent_decode(&ui[..pos])), Some(percent_decode(&ui[pos + 1..]))),
+            None => (Some(percent_decode(ui)), None),
+        },
+        None => (None, None),
+    };
+
+    let (host, port) = split_host_port(addr)?;
+    Ok((cipher, password, host, port))
+}
+
+/// Split Go's listen address, `:PORT` listens on all interfaces
+fn split_host_port(addr: &str) -> Result<(String, u16), Error> {
+    let pos = match addr.rfind(':') {
+        Some(p) => p,
+        None => {
+            return Err(Error::new(
+                ErrorKind::Malformed,
+                "missing port in address",
+                Some(addr.to_owned()),
+            ))
+        }
+    };
+
+    let port = match addr[pos + 1..].parse::<u16>() {
+        Ok(p) => p,
+        Err(..) => {
+            return Err(Error::new(
+                ErrorKind::Malformed,
+                "invalid port in address",
+                Some(addr.to_owned()),
+            ))
+        }
+    };
+
+    let host = addr[..pos].trim_start_matches('[').trim_end_matches(']');
+    let host = if host.is_empty() { "0.0.0.0" } else { host };
+
+    Ok((host.to_owned(), port))
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(b) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                decoded.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Parse Go's `time.Duration` string, like `5m0s`, in seconds
+fn parse_go_duration(s: &str) -> Option<u64> {
+    let mut total = 0f64;
+    let mut rest = s;
+
+    if rest.is_empty() {
+        return None;
+    }
+
+    while !rest.is_empty() {
+        let num_len = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or_else(|| rest.len());
+        let value = rest[..num_len].parse::<f64>().ok()?;
+        rest = &rest[num_len..];
+
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or_else(|| rest.len());
+        let scale = match &rest[..unit_len] {
+            "h" => 3600.0,
+            "m" => 60.0,
+            "s" => 1.0,
+            "ms" => 0.001,
+            "us" | "µs" => 0.000_001,
+            "ns" => 0.000_000_001,
+            // Only "0" could be written without unit
+            "" if value == 0.0 => 0.0,
+            _ => return None,
+        };
+        rest = &rest[unit_len..];
+
+        total += value * scale;
+    }
+
+    Some(total as u64)
+}
+
+/// Split command line arguments like a POSIX shell, supporting quotes and line continuations
+fn split_args(input: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_arg = false;
+    let mut quote = None;
+
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"'), '\\') => match chars.next() {
+                Some(n @ '"') | Some(n @ '\\') | Some(n @ '$') | Some(n @ '`') => current.push(n),
+                Some(n) => {
+                    current.push('\\');
+                    current.push(n);
+                }
+                None => current.push('\\'),
+            },
+            (Some(..), c) => current.push(c),
+            (None, '\'') | (None, '"') => {
+                quote = Some(c);
+                in_arg = true;
+            }
+            (None, '\\') => match chars.next() {
+                // Line continuation
+                Some('\n') => {}
+                Some(n) => {
+                    current.push(n);
+                    in_arg = true;
+                }
+                None => {}
+            },
+            (None, c) if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            (None, c) => {
+                current.push(c);
+                in_arg = true;
+            }
+        }
+    }
+
+    if in_arg {
+        args.push(current);
+    }
+
+    args
+}
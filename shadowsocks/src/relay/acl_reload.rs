@@ -0,0 +1,91 @@
+//! Reloading ACL rules without restarting
+
+use std::{
+    fs,
+    io,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+#[cfg(unix)]
+use futures::future::{self, Either};
+use log::{error, info};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::{task, time};
+
+use crate::{acl::AccessControl, context::SharedServerState};
+
+const ACL_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+fn file_modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Reload ACL rules of `state` if the ACL file is modified, or SIGHUP is received (*nix only)
+///
+/// Rules are swapped atomically, established connections are kept.
+/// Current rules are kept if the file couldn't be loaded.
+pub async fn acl_reload_task(state: SharedServerState) -> io::Result<()> {
+    let (path, databases) = match state.acl() {
+        Some(acl) => (acl.file_path().to_owned(), acl.databases().clone()),
+        None => return Ok(()),
+    };
+    let mut modified = file_modified(&path);
+
+    #[cfg(unix)]
+    let mut sighup = signal(SignalKind::hangup())?;
+
+    loop {
+        #[cfg(unix)]
+        let hangup = {
+            let hangup = sighup.recv();
+            let sleep = time::sleep(ACL_CHECK_INTERVAL);
+            tokio::pin!(hangup);
+            tokio::pin!(sleep);
+
+            matches!(future::select(hangup, sleep).await, Either::Left(..))
+        };
+        #[cfg(not(unix))]
+        let hangup = {
+            time::sleep(ACL_CHECK_INTERVAL).await;
+            false
+        };
+
+        let current_modified = file_modified(&path);
+        if !hangup && current_modified == modified {
+            continue;
+        }
+        modified = current_modified;
+
+        if hangup {
+            info!("received SIGHUP, reloading ACL {}", path.display());
+        } else {
+            info!("ACL {} is modified, reloading", path.display());
+        }
+
+        let load_path = path.clone();
+        let load_databases = databases.clone();
+        let load = move || AccessControl::load_from_file_with_databases(load_path, &load_databases);
+        match task::spawn_blocking(load).await {
+            Ok(Ok(acl)) => {
+                state.set_acl(acl);
+                info!("reloaded ACL {}", path.display());
+            }
+            Ok(Err(err)) => {
+                error!(
+                    "failed to reload ACL {}, current rules are kept, error: {}",
+                    path.display(),
+                    err
+                );
+            }
+            Err(err) => {
+                error!(
+                    "failed to reload ACL {}, current rules are kept, error: {}",
+                    path.display(),
+                    err
+                );
+            }
+        }
+    }
+}
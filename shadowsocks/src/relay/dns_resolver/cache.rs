@@ -0,0 +1,174 @@
+//! In-process cache of resolved host names
+
+use std::{
+    io::{self, Error, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use lru_time_cache::LruCache;
+use spin::Mutex as SpinMutex;
+
+use crate::config::DnsCacheConfig;
+
+enum CachedResult {
+    Resolved(Vec<IpAddr>),
+    /// Message of the resolution error
+    Failed(String),
+}
+
+struct CacheEntry {
+    result: CachedResult,
+    expire_at: Instant,
+}
+
+/// A cached host name, for inspection
+#[derive(Debug, Clone)]
+pub struct DnsCacheEntry {
+    pub host: String,
+    /// Resolved addresses, or message of the resolution error
+    pub result: Result<Vec<IpAddr>, String>,
+    /// Remaining time before it expires
+    pub ttl: Duration,
+}
+
+/// Cache of resolved host names, keyed by host name without port
+pub struct DnsCache {
+    config: DnsCacheConfig,
+    entries: SpinMutex<LruCache<String, CacheEntry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl DnsCache {
+    /// Create an empty cache
+    pub fn new(config: DnsCacheConfig) -> DnsCache {
+        DnsCache {
+            config,
+            entries: SpinMutex::new(LruCache::with_capacity(config.capacity)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Cached result of `host`, with `port` attached to addresses, `None` if it is not cached or has expired
+    pub fn get(&self, host: &str, port: u16) -> Option<io::Result<Vec<SocketAddr>>> {
+        let mut entries = self.entries.lock();
+
+        let expired = match entries.peek(host) {
+            Some(entry) => entry.expire_at <= Instant::now(),
+            None => true,
+        };
+        if expired {
+            entries.remove(host);
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        match entries.get(host).map(|e| &e.result) {
+            Some(CachedResult::Resolved(ips)) => Some(Ok(ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect())),
+            Some(CachedResult::Failed(msg)) => Some(Err(Error::new(ErrorKind::NotFound, format!("{} (cached)", msg)))),
+            None => None,
+        }
+    }
+
+    /// Cache resolved `addrs` of `host`, `ttl` is clamped into `[min_ttl, max_ttl]`
+    ///
+    /// `None` if TTL is unknown, which is cached for `min_ttl`
+    pub fn insert_resolved(&self, host: &str, addrs: &[SocketAddr], ttl: Option<Duration>) {
+        let ttl = match ttl {
+            Some(ttl) => ttl.max(self.config.min_ttl).min(self.config.max_ttl),
+            None => self.config.min_ttl,
+        };
+        if ttl == Duration::from_secs(0) || addrs.is_empty() {
+            return;
+        }
+
+        let entry = CacheEntry {
+            result: CachedResult::Resolved(addrs.iter().map(SocketAddr::ip).collect()),
+            expire_at: Instant::now() + ttl,
+        };
+        self.entries.lock().insert(host.to_owned(), entry);
+    }
+
+    /// Cache the failed resolution of `host` for `negative_ttl`
+    ///
+    /// Only missing records (NXDOMAIN or NODATA, `ErrorKind::NotFound`) are cached, other failures like timeouts and
+    /// unreachable upstreams are transient, they are retried by the next lookup
+    pub fn insert_failed(&self, host: &str, err: &io::Error) {
+        if self.config.negative_ttl == Duration::from_secs(0) || err.kind() != ErrorKind::NotFound {
+            return;
+        }
+
+        let entry = CacheEntry {
+            result: CachedResult::Failed(err.to_string()),
+            expire_at: Instant::now() + self.config.negative_ttl,
+        };
+        self.entries.lock().insert(host.to_owned(), entry);
+    }
+
+    /// Number of lookups answered by the cache, and not
+    pub fn hits_and_misses(&self) -> (u64, u64) {
+        (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed))
+    }
+
+    /// Unexpired entries, sorted by host names
+    pub fn entries(&self) -> Vec<DnsCacheEntry> {
+        let now = Instant::now();
+        let entries = self.entries.lock();
+
+        let mut result = Vec::with_capacity(entries.len());
+        for (host, entry) in entries.peek_iter() {
+            if entry.expire_at <= now {
+                continue;
+            }
+            let r = match entry.result {
+                CachedResult::Resolved(ref ips) => Ok(ips.clone()),
+                CachedResult::Failed(ref msg) => Err(msg.clone()),
+            };
+            result.push(DnsCacheEntry {
+                host: host.clone(),
+                result: r,
+                ttl: entry.expire_at - now,
+            });
+        }
+        result.sort_by(|a, b| a.host.cmp(&b.host));
+        result
+    }
+
+    /// Remove `host` from the cache, or all entries if `host` is `None`
+    ///
+    /// Returns number of removed entries
+    pub fn flush(&self, host: Option<&str>) -> usize {
+        let mut entries = self.entries.lock();
+        match host {
+            Some(host) => entries.remove(host).map_or(0, |_| 1),
+            None => {
+                let n = entries.len();
+                entries.clear();
+                n
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn negative_cache_missing_records_only() {
+        let cache = DnsCache::new(DnsCacheConfig::default());
+
+        let timeout = Error::new(ErrorKind::TimedOut, "upstream timed out");
+        cache.insert_failed("timeout.example.com", &timeout);
+        assert!(cache.get("timeout.example.com", 80).is_none());
+
+        let nxdomain = Error::new(ErrorKind::NotFound, "no records found");
+        cache.insert_failed("nxdomain.example.com", &nxdomain);
+        let err = cache.get("nxdomain.example.com", 80).unwrap().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,166 @@
+//! Failover between upstreams of `dns`
+//!
+//! Every upstream has its own resolver. Failures (errors other than missing records, and timeouts) are tracked
+//! passively by queries, an upstream failing `DNS_UPSTREAM_MAX_FAILURES` times in a row is skipped for
+//! `DNS_UPSTREAM_DOWN_DURATION`, and then is tried again by the next query.
+
+use std::{
+    io::{self, Error, ErrorKind},
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+use futures::future::{self, FutureExt};
+use log::{debug, warn};
+use spin::Mutex as SpinMutex;
+use trust_dns_resolver::{
+    config::ResolverConfig,
+    error::{ResolveError, ResolveErrorKind},
+    lookup_ip::LookupIp,
+    TokioAsyncResolver,
+};
+
+use super::trust_dns_resolver::{create_resolver, resolve_error_to_io};
+use crate::config::{Config, DnsStrategy};
+
+/// Consecutive failures before an upstream is skipped
+const DNS_UPSTREAM_MAX_FAILURES: u32 = 3;
+
+/// Duration of skipping a failing upstream
+const DNS_UPSTREAM_DOWN_DURATION: Duration = Duration::from_secs(30);
+
+/// Answer of an upstream, `Err` if the name doesn't have records
+type Answer = Result<LookupIp, ResolveError>;
+
+struct Upstream {
+    name: String,
+    resolver: TokioAsyncResolver,
+    failures: AtomicU32,
+    down_until: SpinMutex<Option<Instant>>,
+}
+
+impl Upstream {
+    fn new(name: String, resolver: TokioAsyncResolver) -> Upstream {
+        Upstream {
+            name,
+            resolver,
+            failures: AtomicU32::new(0),
+            down_until: SpinMutex::new(None),
+        }
+    }
+
+    fn is_down(&self, now: Instant) -> bool {
+        matches!(*self.down_until.lock(), Some(t) if t > now)
+    }
+
+    /// Query `host`, `Err` if the upstream failed to answer
+    async fn lookup(&self, host: &str, timeout: Duration) -> io::Result<Answer> {
+        let err = match tokio::time::timeout(timeout, self.resolver.lookup_ip(host)).await {
+            Ok(Ok(lookup)) => {
+                self.record_success();
+                return Ok(Ok(lookup));
+            }
+            Ok(Err(err)) if matches!(err.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {
+                self.record_success();
+                return Ok(Err(err));
+            }
+            Ok(Err(err)) => Error::new(ErrorKind::Other, format!("upstream {} failed, {}", self.name, err)),
+            Err(..) => Error::new(ErrorKind::TimedOut, format!("upstream {} timed out", self.name)),
+        };
+
+        self.record_failure();
+        Err(err)
+    }
+
+    fn record_success(&self) {
+        if self.failures.swap(0, Ordering::Relaxed) >= DNS_UPSTREAM_MAX_FAILURES {
+            debug!("DNS upstream {} recovered", self.name);
+        }
+        *self.down_until.lock() = None;
+    }
+
+    fn record_failure(&self) {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= DNS_UPSTREAM_MAX_FAILURES {
+            if failures == DNS_UPSTREAM_MAX_FAILURES {
+                warn!(
+                    "DNS upstream {} failed {} times in a row, skipped for {}s",
+                    self.name,
+                    failures,
+                    DNS_UPSTREAM_DOWN_DURATION.as_secs()
+                );
+            }
+            *self.down_until.lock() = Some(Instant::now() + DNS_UPSTREAM_DOWN_DURATION);
+        }
+    }
+}
+
+/// Resolver of all upstreams in `dns`
+pub struct FailoverResolver {
+    upstreams: Vec<Upstream>,
+    strategy: DnsStrategy,
+    timeout: Duration,
+}
+
+impl FailoverResolver {
+    /// Create resolvers of upstreams in `config.dns`, or the system's configuration if it is empty
+    pub async fn new(config: &Config) -> io::Result<FailoverResolver> {
+        let mut upstreams = Vec::with_capacity(config.dns.len());
+        if config.dns.is_empty() {
+            let resolver = create_resolver(None, config.ipv6_first).await?;
+            upstreams.push(Upstream::new("system".to_owned(), resolver));
+        }
+        for dns in &config.dns {
+            let resolver = create_resolver(Some(dns.clone()), config.ipv6_first).await?;
+            upstreams.push(Upstream::new(upstream_name(dns), resolver));
+        }
+
+        Ok(FailoverResolver {
+            upstreams,
+            strategy: config.dns_strategy,
+            timeout: config.dns_timeout,
+        })
+    }
+
+    /// Look up addresses of `host`
+    pub async fn lookup_ip(&self, host: &str) -> io::Result<LookupIp> {
+        // Upstreams that are down are tried only if all of them are down
+        let now = Instant::now();
+        let mut upstreams = self.upstreams.iter().filter(|u| !u.is_down(now)).collect::<Vec<_>>();
+        if upstreams.is_empty() {
+            upstreams = self.upstreams.iter().collect();
+        }
+
+        let answer = match self.strategy {
+            DnsStrategy::Failover => {
+                let mut result = Err(Error::new(ErrorKind::Other, "no DNS upstream"));
+                for upstream in upstreams {
+                    result = upstream.lookup(host, self.timeout).await;
+                    if result.is_ok() {
+                        break;
+                    }
+                }
+                result?
+            }
+            DnsStrategy::Parallel => {
+                let queries = upstreams.into_iter().map(|u| u.lookup(host, self.timeout).boxed());
+                let (answer, ..) = future::select_ok(queries).await?;
+                answer
+            }
+        };
+
+        answer.map_err(resolve_error_to_io)
+    }
+}
+
+/// Name servers of `config`, for logging
+fn upstream_name(config: &ResolverConfig) -> String {
+    let mut addrs = Vec::new();
+    for ns in config.name_servers() {
+        let addr = ns.socket_addr.to_string();
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+    addrs.join(",")
+}
@@ -0,0 +1,77 @@
+//! Static addresses of host names, from `hosts` and `hosts_file`
+
+use std::{collections::HashMap, fs, io, net::IpAddr, path::Path};
+
+use log::{debug, warn};
+
+use crate::config::Config;
+
+/// Static addresses of host names, consulted before the resolver
+pub struct StaticHosts {
+    hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+impl StaticHosts {
+    /// Load `hosts_file` and `hosts` of `config`, `None` if neither of them is configured
+    ///
+    /// A hosts file that couldn't be read is skipped with a warning
+    pub fn load(config: &Config) -> Option<StaticHosts> {
+        if config.hosts.is_empty() && config.hosts_file.is_none() {
+            return None;
+        }
+
+        let mut hosts = HashMap::new();
+        if let Some(ref path) = config.hosts_file {
+            match read_hosts_file(path) {
+                Ok(h) => {
+                    debug!("loaded {} host names from {}", h.len(), path.display());
+                    hosts = h;
+                }
+                Err(err) => warn!("failed to read hosts file {}, {}", path.display(), err),
+            }
+        }
+        for (name, ips) in &config.hosts {
+            hosts.insert(name.clone(), ips.clone());
+        }
+
+        Some(StaticHosts { hosts })
+    }
+
+    /// Static addresses of `host`, case-insensitive
+    pub fn lookup(&self, host: &str) -> Option<&[IpAddr]> {
+        let host = host.trim_end_matches('.');
+        let ips = match self.hosts.get(host) {
+            Some(ips) => Some(ips),
+            None if host.bytes().any(|b| b.is_ascii_uppercase()) => self.hosts.get(&host.to_ascii_lowercase()),
+            None => None,
+        };
+        ips.map(Vec::as_slice)
+    }
+}
+
+/// Read a hosts file, lines of `IP NAME [NAME...]` with `#` comments
+///
+/// Addresses of a name in multiple lines are all kept, in order
+fn read_hosts_file(path: &Path) -> io::Result<HashMap<String, Vec<IpAddr>>> {
+    let content = fs::read_to_string(path)?;
+
+    let mut hosts: HashMap<String, Vec<IpAddr>> = HashMap::new();
+    for line in content.lines() {
+        let line = match line.find('#') {
+            Some(pos) => &line[..pos],
+            None => line,
+        };
+
+        let mut fields = line.split_whitespace();
+        let ip = match fields.next().map(str::parse::<IpAddr>) {
+            Some(Ok(ip)) => ip,
+            // Empty lines, or addresses with zone IDs like `fe80::1%lo0`
+            _ => continue,
+        };
+        for name in fields {
+            hosts.entry(name.to_ascii_lowercase()).or_default().push(ip);
+        }
+    }
+
+    Ok(hosts)
+}
@@ -1,25 +1,79 @@
 //! Asynchronous DNS resolver
 #![macro_use]
 
-use std::{io, net::SocketAddr};
+use std::{io, net::SocketAddr, time::Duration};
 
 use cfg_if::cfg_if;
+use log::trace;
 
 use crate::{config::ServerAddr, context::Context};
 
+pub use self::{
+    cache::{DnsCache, DnsCacheEntry},
+    hosts::StaticHosts,
+};
+
+mod cache;
+mod hosts;
 mod tokio_dns_resolver;
 
 cfg_if! {
     if #[cfg(feature = "trust-dns")] {
+        mod failover;
         mod trust_dns_resolver;
 
         /// Use trust-dns DNS resolver (with DNS cache)
-        pub use self::trust_dns_resolver::{create_resolver, resolve};
+        pub use self::{failover::FailoverResolver, trust_dns_resolver::create_resolver};
+        use self::trust_dns_resolver::lookup;
     } else {
 
         /// Use tokio's builtin DNS resolver
-        pub use self::tokio_dns_resolver::resolve;
+        use self::tokio_dns_resolver::lookup;
+    }
+}
+
+/// Perform a DNS resolution, through `dns_cache` if it is enabled
+///
+/// Static addresses of `hosts` and `hosts_file` are returned without asking the resolver
+pub async fn resolve(context: &Context, addr: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    if let Some(ips) = context.static_hosts().and_then(|h| h.lookup(addr)) {
+        trace!("DNS resolved {}:{} from hosts", addr, port);
+        return Ok(ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect());
     }
+
+    let cache = match context.dns_cache() {
+        Some(c) => c,
+        None => return lookup(context, addr, port).await.map(|(addrs, _)| addrs),
+    };
+
+    if let Some(result) = cache.get(addr, port) {
+        trace!("DNS resolved {}:{} from cache", addr, port);
+        return result;
+    }
+
+    match lookup(context, addr, port).await {
+        Ok((addrs, ttl)) => {
+            cache.insert_resolved(addr, &addrs, ttl);
+            Ok(addrs)
+        }
+        Err(err) => {
+            cache.insert_failed(addr, &err);
+            Err(err)
+        }
+    }
+}
+
+/// Perform a DNS resolution without `dns_cache`, returns addresses with their remaining TTL if it is known
+pub async fn resolve_with_ttl(
+    context: &Context,
+    addr: &str,
+    port: u16,
+) -> io::Result<(Vec<SocketAddr>, Option<Duration>)> {
+    if let Some(ips) = context.static_hosts().and_then(|h| h.lookup(addr)) {
+        return Ok((ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect(), None));
+    }
+
+    lookup(context, addr, port).await
 }
 
 /// Helper macro for resolving host and then process each addresses
@@ -2,6 +2,7 @@ use std::{
     io::{self, Error, ErrorKind},
     net::SocketAddr,
     sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
 };
 
 use log::{trace, warn};
@@ -9,8 +10,8 @@ use tokio::net::lookup_host;
 
 use crate::context::Context;
 
-/// Perform a DNS resolution
-pub async fn resolve(_: &Context, addr: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+/// Perform a DNS resolution, TTL is unknown
+pub async fn lookup(_: &Context, addr: &str, port: u16) -> io::Result<(Vec<SocketAddr>, Option<Duration>)> {
     static TOKIO_USED: AtomicBool = AtomicBool::new(false);
     if !TOKIO_USED.swap(true, Ordering::Relaxed) {
         warn!("Tokio resolver is used. Performance might deteriorate.");
@@ -21,7 +22,7 @@ pub async fn resolve(_: &Context, addr: &str, port: u16) -> io::Result<Vec<Socke
     }
 
     match lookup_host((addr, port)).await {
-        Ok(v) => Ok(v.collect()),
+        Ok(v) => Ok((v.collect(), None)),
         Err(err) => {
             let err = Error::new(
                 ErrorKind::Other,
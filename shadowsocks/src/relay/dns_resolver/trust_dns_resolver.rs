@@ -3,17 +3,27 @@
 use std::{
     io::{self, Error, ErrorKind},
     net::SocketAddr,
+    time::{Duration, Instant},
 };
 
 use log::{error, trace};
 use trust_dns_resolver::{
     config::{LookupIpStrategy, ResolverConfig, ResolverOpts},
+    error::{ResolveError, ResolveErrorKind},
     TokioAsyncResolver,
 };
 
-use super::tokio_dns_resolver::resolve as tokio_resolve;
+use super::tokio_dns_resolver::lookup as tokio_lookup;
 use crate::context::Context;
 
+/// Convert a resolution error, missing records (NXDOMAIN or NODATA) are `ErrorKind::NotFound`
+pub(super) fn resolve_error_to_io(err: ResolveError) -> Error {
+    match err.kind() {
+        ResolveErrorKind::NoRecordsFound { .. } => Error::new(ErrorKind::NotFound, err),
+        _ => From::from(err),
+    }
+}
+
 /// Create a `trust-dns` asynchronous DNS resolver
 pub async fn create_resolver(dns: Option<ResolverConfig>, ipv6_first: bool) -> io::Result<TokioAsyncResolver> {
     let mut resolver_opts = ResolverOpts::default();
@@ -80,28 +90,42 @@ pub async fn create_resolver(dns: Option<ResolverConfig>, ipv6_first: bool) -> i
     .map_err(From::from)
 }
 
-/// Perform a DNS resolution
-pub async fn resolve(context: &Context, addr: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
-    match context.dns_resolver() {
-        Some(resolver) => {
-            trace!("DNS resolving {}:{} with trust-dns", addr, port);
-
-            match resolver.lookup_ip(addr).await {
-                Ok(lookup_result) => Ok(lookup_result.iter().map(|ip| SocketAddr::new(ip, port)).collect()),
-                Err(err) => {
-                    let err = Error::new(
-                        ErrorKind::Other,
-                        format!("dns resolve {}:{} error: {}", addr, port, err),
-                    );
-                    Err(err)
-                }
-            }
-        }
+/// Perform a DNS resolution, returns addresses with their remaining TTL
+pub async fn lookup(context: &Context, addr: &str, port: u16) -> io::Result<(Vec<SocketAddr>, Option<Duration>)> {
+    // `[dns:UPSTREAM]` sections in ACL take precedence over `dns`
+    let split_resolver = match context.acl() {
+        Some(acl) => match acl.check_dns_upstream(addr) {
+            Some(upstream) => context.split_dns_resolver(upstream).await,
+            None => None,
+        },
+        None => None,
+    };
+
+    let result = if let Some(resolver) = split_resolver {
+        trace!("DNS resolving {}:{} with trust-dns (split)", addr, port);
+
+        resolver.lookup_ip(addr).await.map_err(resolve_error_to_io)
+    } else if let Some(resolver) = context.dns_resolver() {
+        trace!("DNS resolving {}:{} with trust-dns", addr, port);
+
+        resolver.lookup_ip(addr).await
+    } else {
         // Fallback to tokio's DNS resolver
-        None => {
-            trace!("DNS resolving {}:{} with tokio (fallback)", addr, port);
+        trace!("DNS resolving {}:{} with tokio (fallback)", addr, port);
 
-            tokio_resolve(context, addr, port).await
+        return tokio_lookup(context, addr, port).await;
+    };
+
+    match result {
+        Ok(lookup_result) => {
+            let ttl = lookup_result.valid_until().saturating_duration_since(Instant::now());
+            let addrs = lookup_result.iter().map(|ip| SocketAddr::new(ip, port)).collect();
+            Ok((addrs, Some(ttl)))
+        }
+        Err(err) => {
+            // Kind is kept, only missing records are cached by `dns_cache`
+            let err = Error::new(err.kind(), format!("dns resolve {}:{} error: {}", addr, port, err));
+            Err(err)
         }
     }
 }
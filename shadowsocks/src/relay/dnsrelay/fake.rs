@@ -0,0 +1,93 @@
+//! Fake addresses of proxied domain names
+//!
+//! The DNS relay answers proxied domain names with addresses allocated from `fake_dns_range`. Connections to these
+//! addresses accepted by redir are sent to the domain names they were allocated for, so proxied names are only
+//! resolved by the remote server, without leaking queries or resolving twice.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use ipnet::IpNet;
+use spin::Mutex as SpinMutex;
+
+/// Allocated addresses are limited to this number, for large IPv6 ranges
+const MAX_FAKE_DNS_POOL_SIZE: u128 = 1 << 18;
+
+struct FakeDnsPool {
+    names: HashMap<String, IpAddr>,
+    addrs: HashMap<IpAddr, String>,
+    /// Offset of the next allocated address in the range
+    next: u128,
+}
+
+/// Allocator of fake addresses
+pub struct FakeDns {
+    net: IpNet,
+    size: u128,
+    pool: SpinMutex<FakeDnsPool>,
+}
+
+impl FakeDns {
+    /// Create an allocator of addresses in `net`
+    pub fn new(net: IpNet) -> FakeDns {
+        let host_bits = u32::from(net.max_prefix_len() - net.prefix_len());
+        // Network and broadcast addresses are never allocated
+        let size = match 1u128.checked_shl(host_bits) {
+            Some(n) => (n - 2).min(MAX_FAKE_DNS_POOL_SIZE),
+            None => MAX_FAKE_DNS_POOL_SIZE,
+        };
+
+        FakeDns {
+            net,
+            size,
+            pool: SpinMutex::new(FakeDnsPool {
+                names: HashMap::new(),
+                addrs: HashMap::new(),
+                next: 0,
+            }),
+        }
+    }
+
+    /// Range of fake addresses
+    pub fn net(&self) -> &IpNet {
+        &self.net
+    }
+
+    /// Fake address of `name`, allocated if it doesn't have one yet
+    ///
+    /// Addresses are allocated in order, the oldest allocation is recycled if the range is exhausted
+    pub fn allocate(&self, name: &str) -> IpAddr {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+
+        let mut pool = self.pool.lock();
+        if let Some(addr) = pool.names.get(&name) {
+            return *addr;
+        }
+
+        let addr = self.addr_at(pool.next);
+        pool.next = (pool.next + 1) % self.size;
+        if let Some(old_name) = pool.addrs.insert(addr, name.clone()) {
+            pool.names.remove(&old_name);
+        }
+        pool.names.insert(name, addr);
+
+        addr
+    }
+
+    /// Domain name which `addr` was allocated for
+    ///
+    /// `None` if `addr` is not allocated, or has been recycled for another name
+    pub fn lookup(&self, addr: &IpAddr) -> Option<String> {
+        self.pool.lock().addrs.get(addr).cloned()
+    }
+
+    fn addr_at(&self, offset: u128) -> IpAddr {
+        // Skips the network address
+        match self.net {
+            IpNet::V4(ref n) => IpAddr::V4(Ipv4Addr::from(u32::from(n.network()) + offset as u32 + 1)),
+            IpNet::V6(ref n) => IpAddr::V6(Ipv6Addr::from(u128::from(n.network()) + offset + 1)),
+        }
+    }
+}
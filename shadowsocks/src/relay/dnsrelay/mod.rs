@@ -7,11 +7,12 @@ use std::{
     time::Duration,
 };
 
+use ipnet::IpNet;
 use log::{debug, error, info, trace, warn};
 use tokio::{net::TcpListener, select, time};
 use trust_dns_proto::{
     op::{header::MessageType, response_code::ResponseCode, Message, OpCode, Query},
-    rr::{DNSClass, Name, RData, RecordType},
+    rr::{DNSClass, Name, RData, Record, RecordType},
 };
 
 use crate::{
@@ -23,8 +24,12 @@ use crate::{
 
 use self::upstream::{ProxyUpstream, Upstream};
 
+pub(crate) mod fake;
 pub(crate) mod upstream;
 
+/// TTL of fake addresses, they are kept allocated as long as possible, but clients shouldn't cache them for long
+const FAKE_DNS_TTL: u32 = 1;
+
 fn should_forward_by_ptr_name(acl: &AccessControl, name: &Name) -> bool {
     let mut iter = name.iter().rev();
     let mut next = || match iter.next() {
@@ -212,7 +217,7 @@ where
         let remote_response_fut = try_timeout(remote.lookup(&self.context, query), Some(Duration::from_secs(5)));
         let local_response_fut = try_timeout(local.lookup(&self.context, query), Some(Duration::from_secs(5)));
 
-        match should_forward_by_query(acl, query) {
+        match should_forward_by_query(acl.as_deref(), query) {
             Some(true) => {
                 let remote_response = remote_response_fut.await;
                 trace!("pick remote response (query): {:?}", remote_response);
@@ -228,7 +233,7 @@ where
 
         let decider = async {
             let local_response = local_response_fut.await;
-            if should_forward_by_response(acl, &local_response, query) {
+            if should_forward_by_response(acl.as_deref(), &local_response, query) {
                 None
             } else {
                 Some(local_response)
@@ -263,6 +268,38 @@ where
         }
     }
 
+    /// Answer proxied domain names with fake addresses, if `fake_dns_range` is configured
+    ///
+    /// Queries of the other address family are answered without records, so clients will use the fake ones
+    fn fake_lookup(&self, query: &Query) -> Option<Vec<Record>> {
+        let fake_dns = self.context.fake_dns()?;
+
+        if query.query_class() != DNSClass::IN || !matches!(query.query_type(), RecordType::A | RecordType::AAAA) {
+            return None;
+        }
+        let acl = self.context.acl();
+        if should_forward_by_query(acl.as_deref(), query) != Some(true) {
+            return None;
+        }
+
+        let same_family = matches!(
+            (query.query_type(), fake_dns.net()),
+            (RecordType::A, IpNet::V4(..)) | (RecordType::AAAA, IpNet::V6(..))
+        );
+        if !same_family {
+            return Some(Vec::new());
+        }
+
+        let addr = fake_dns.allocate(&query.name().to_ascii());
+        debug!("DNS fake answer {} for {}", addr, query.name());
+
+        let rdata = match addr {
+            IpAddr::V4(ip) => RData::A(ip),
+            IpAddr::V6(ip) => RData::AAAA(ip),
+        };
+        Some(vec![Record::from_rdata(query.name().clone(), FAKE_DNS_TTL, rdata)])
+    }
+
     async fn resolve(&self, request: Message) -> Message {
         let mut message = Message::new();
         message.set_id(request.id());
@@ -274,6 +311,9 @@ where
             message.set_response_code(ResponseCode::NotImp);
         } else if request.op_code() != OpCode::Query || request.message_type() != MessageType::Query {
             message.set_response_code(ResponseCode::NotImp);
+        } else if let Some(answers) = request.queries().first().and_then(|q| self.fake_lookup(q)) {
+            message.add_query(request.queries()[0].clone());
+            message.add_answers(answers);
         } else if request.query_count() > 0 {
             let (r, forward) = self.acl_lookup(&request.queries()[0]).await;
             if let Ok(result) = r {
@@ -1,16 +1,28 @@
 //! Server network flow statistic
 
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    mem,
+    net::SocketAddr,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use crate::config::Config;
+use futures::future::AbortHandle;
+use lru_time_cache::LruCache;
+use serde::Serialize;
+use spin::Mutex as SpinMutex;
+use tokio::{sync::broadcast, time};
 
-/// Flow statistic for one server
+use crate::{config::Config, relay::socks5::Address};
+
+/// Flow statistic updated by a few connections, like a connection itself or a destination host
+///
+/// Counters of servers and users are `ShardedFlowStatistic`, which are updated by all of their connections
 pub struct FlowStatistic {
     tx: AtomicUsize,
     rx: AtomicUsize,
@@ -52,10 +64,507 @@ impl Default for FlowStatistic {
     }
 }
 
+/// Shared reference for FlowStatistic
+pub type SharedFlowStatistic = Arc<FlowStatistic>;
+
+/// Number of shards of `ShardedFlowStatistic`, threads more than this share shards
+const FLOW_SHARDS: usize = 16;
+
+static NEXT_FLOW_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // Runtime threads are assigned to shards in turn, so worker threads rarely share one
+    static FLOW_SHARD: usize = NEXT_FLOW_SHARD.fetch_add(1, Ordering::Relaxed) % FLOW_SHARDS;
+}
+
+/// Counters of a shard, aligned to a cache line to avoid false sharing
+#[derive(Default)]
+#[repr(align(64))]
+struct FlowStatisticShard {
+    tx: AtomicUsize,
+    rx: AtomicUsize,
+}
+
+/// Flow statistic updated by many connections at the same time
+///
+/// Each thread adds to its own shard, shards are summed on read. Reads are slower, and are not a consistent
+/// snapshot of all shards, which is fine for statistics.
+pub struct ShardedFlowStatistic {
+    shards: [FlowStatisticShard; FLOW_SHARDS],
+}
+
+impl ShardedFlowStatistic {
+    /// Create an empty statistic
+    pub fn new() -> ShardedFlowStatistic {
+        ShardedFlowStatistic {
+            shards: Default::default(),
+        }
+    }
+
+    fn shard(&self) -> &FlowStatisticShard {
+        let idx = FLOW_SHARD.with(|idx| *idx);
+        &self.shards[idx]
+    }
+
+    /// Total bytes transferred
+    pub fn tx(&self) -> usize {
+        self.shards.iter().map(|s| s.tx.load(Ordering::Relaxed)).sum()
+    }
+
+    /// Add bytes transferred
+    pub fn incr_tx(&self, tx: usize) {
+        self.shard().tx.fetch_add(tx, Ordering::Relaxed);
+    }
+
+    /// Total bytes received
+    pub fn rx(&self) -> usize {
+        self.shards.iter().map(|s| s.rx.load(Ordering::Relaxed)).sum()
+    }
+
+    /// Add bytes received
+    pub fn incr_rx(&self, rx: usize) {
+        self.shard().rx.fetch_add(rx, Ordering::Relaxed);
+    }
+}
+
+impl Default for ShardedFlowStatistic {
+    fn default() -> ShardedFlowStatistic {
+        ShardedFlowStatistic::new()
+    }
+}
+
+/// Transferred bytes by destination hosts, only the most recently used hosts are kept
+pub struct DestinationStatistic {
+    hosts: SpinMutex<LruCache<String, SharedFlowStatistic>>,
+}
+
+impl DestinationStatistic {
+    /// Create with at most `capacity` hosts
+    pub fn new(capacity: usize) -> DestinationStatistic {
+        DestinationStatistic {
+            hosts: SpinMutex::new(LruCache::with_capacity(capacity)),
+        }
+    }
+
+    /// Flow statistic of the host of `addr` (domain name or IP, without port), created on first use
+    pub fn host(&self, addr: &Address) -> SharedFlowStatistic {
+        let host = match *addr {
+            Address::SocketAddress(ref sa) => sa.ip().to_string(),
+            Address::DomainNameAddress(ref dname, _) => dname.clone(),
+        };
+
+        let mut hosts = self.hosts.lock();
+        if let Some(stat) = hosts.get(&host) {
+            return stat.clone();
+        }
+        let stat = Arc::new(FlowStatistic::new());
+        hosts.insert(host, stat.clone());
+        stat
+    }
+
+    /// `n` hosts with the most transferred bytes
+    pub fn top(&self, n: usize) -> Vec<DestinationSnapshot> {
+        let mut hosts = self
+            .hosts
+            .lock()
+            .peek_iter()
+            .map(|(host, stat)| DestinationSnapshot {
+                host: host.clone(),
+                tx: stat.tx(),
+                rx: stat.rx(),
+            })
+            .collect::<Vec<_>>();
+        hosts.sort_by(|a, b| (b.tx + b.rx).cmp(&(a.tx + a.rx)));
+        hosts.truncate(n);
+        hosts
+    }
+}
+
+/// Snapshot of a destination host's flow statistic
+#[derive(Debug, Clone, Serialize)]
+pub struct DestinationSnapshot {
+    /// Domain name or IP address
+    pub host: String,
+    /// Bytes received from the host, which are sent to clients
+    pub tx: usize,
+    /// Bytes sent to the host
+    pub rx: usize,
+}
+
+/// Reason of a relay connection being terminated
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CloseReason {
+    /// Client closed the connection
+    ClientEof,
+    /// Remote closed the connection
+    RemoteEof,
+    /// No data transferred in `timeout`
+    IdleTimeout,
+    /// Rejected by ACL rules
+    AclReject,
+    /// Failed to decrypt data, may be wrong method or key, or replay attack
+    CryptoError,
+    /// Failed to connect to remote
+    ConnectError,
+    /// Traffic quota exceeded
+    Quota,
+    /// Server is shutting down
+    Shutdown,
+    /// Other I/O errors
+    IoError,
+}
+
+impl CloseReason {
+    /// All available reasons
+    pub const ALL: [CloseReason; 9] = [
+        CloseReason::ClientEof,
+        CloseReason::RemoteEof,
+        CloseReason::IdleTimeout,
+        CloseReason::AclReject,
+        CloseReason::CryptoError,
+        CloseReason::ConnectError,
+        CloseReason::Quota,
+        CloseReason::Shutdown,
+        CloseReason::IoError,
+    ];
+
+    /// Label of this reason, used in logs and exported counters
+    #[rustfmt::skip]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CloseReason::ClientEof    => "client_eof",
+            CloseReason::RemoteEof    => "remote_eof",
+            CloseReason::IdleTimeout  => "idle_timeout",
+            CloseReason::AclReject    => "acl_reject",
+            CloseReason::CryptoError  => "crypto_error",
+            CloseReason::ConnectError => "connect_error",
+            CloseReason::Quota        => "quota",
+            CloseReason::Shutdown     => "shutdown",
+            CloseReason::IoError      => "io_error",
+        }
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Counters of connections closed, labeled by `CloseReason`
+pub struct CloseStatistic {
+    counters: [AtomicUsize; CloseReason::ALL.len()],
+}
+
+impl CloseStatistic {
+    /// Create an empty statistic
+    pub fn new() -> CloseStatistic {
+        CloseStatistic {
+            counters: Default::default(),
+        }
+    }
+
+    /// Record a closed connection
+    pub fn incr(&self, reason: CloseReason) {
+        self.counters[reason as usize].fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Total connections closed with `reason`
+    pub fn get(&self, reason: CloseReason) -> usize {
+        self.counters[reason as usize].load(Ordering::Acquire)
+    }
+
+    /// Iterate all `(reason, count)` pairs
+    pub fn iter(&self) -> impl Iterator<Item = (CloseReason, usize)> + '_ {
+        CloseReason::ALL.iter().map(move |r| (*r, self.get(*r)))
+    }
+}
+
+impl Default for CloseStatistic {
+    fn default() -> CloseStatistic {
+        CloseStatistic::new()
+    }
+}
+
+/// Flow statistic for one user of a multi-user server
+pub struct UserFlowStatistic {
+    tcp: ShardedFlowStatistic,
+    udp: ShardedFlowStatistic,
+}
+
+/// Shared reference for UserFlowStatistic
+pub type SharedUserFlowStatistic = Arc<UserFlowStatistic>;
+
+impl UserFlowStatistic {
+    /// Create an empty statistic
+    pub fn new() -> UserFlowStatistic {
+        UserFlowStatistic {
+            tcp: ShardedFlowStatistic::new(),
+            udp: ShardedFlowStatistic::new(),
+        }
+    }
+
+    /// TCP relay flow statistic of this user
+    pub fn tcp(&self) -> &ShardedFlowStatistic {
+        &self.tcp
+    }
+
+    /// UDP relay flow statistic of this user
+    pub fn udp(&self) -> &ShardedFlowStatistic {
+        &self.udp
+    }
+
+    /// Transmission statistic for manager
+    pub fn trans_stat(&self) -> usize {
+        self.tcp.tx() + self.tcp.rx() + self.udp.tx() + self.udp.rx()
+    }
+}
+
+impl Default for UserFlowStatistic {
+    fn default() -> UserFlowStatistic {
+        UserFlowStatistic::new()
+    }
+}
+
+/// Upper bounds (in milliseconds) of buckets of handshake and first byte latencies
+const LATENCY_BUCKETS: &[u64] = &[10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// Upper bounds (in milliseconds) of buckets of connection durations, from 1 second to 4 hours
+const DURATION_BUCKETS: &[u64] = &[
+    1000, 5000, 10000, 30000, 60000, 300_000, 900_000, 1_800_000, 3_600_000, 14_400_000,
+];
+
+/// Histogram of durations, counted in fixed buckets
+pub struct Histogram {
+    bounds: &'static [u64],
+    /// Observations in each bucket, larger than the previous bound and not larger than its bound
+    counts: Vec<AtomicUsize>,
+    count: AtomicUsize,
+    /// Milliseconds
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    /// Create an empty histogram with upper bounds of buckets in milliseconds
+    pub fn new(bounds: &'static [u64]) -> Histogram {
+        Histogram {
+            bounds,
+            counts: bounds.iter().map(|_| AtomicUsize::new(0)).collect(),
+            count: AtomicUsize::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    /// Record a duration
+    pub fn observe(&self, d: Duration) {
+        let ms = d.as_millis() as u64;
+        // Observations larger than all bounds are only counted in `count`
+        if let Some(idx) = self.bounds.iter().position(|b| ms <= *b) {
+            self.counts[idx].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(ms, Ordering::Relaxed);
+    }
+
+    /// Take a snapshot with cumulative buckets
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let mut cumulative = 0;
+        let buckets = self
+            .bounds
+            .iter()
+            .zip(self.counts.iter())
+            .map(|(le, count)| {
+                cumulative += count.load(Ordering::Relaxed);
+                HistogramBucket {
+                    le: *le,
+                    count: cumulative,
+                }
+            })
+            .collect();
+
+        HistogramSnapshot {
+            buckets,
+            count: self.count.load(Ordering::Relaxed),
+            sum: self.sum.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Bucket of a histogram snapshot
+#[derive(Debug, Clone, Serialize)]
+pub struct HistogramBucket {
+    /// Upper bound in milliseconds
+    pub le: u64,
+    /// Observations not larger than `le`
+    pub count: usize,
+}
+
+/// Snapshot of a histogram, in the same form as Prometheus
+#[derive(Debug, Clone, Serialize)]
+pub struct HistogramSnapshot {
+    /// Cumulative buckets, ordered by `le`
+    pub buckets: Vec<HistogramBucket>,
+    /// All observations, including those larger than the last bucket
+    pub count: usize,
+    /// Sum of observations in milliseconds
+    pub sum: u64,
+}
+
+/// Latencies and durations of a server's TCP connections
+pub struct LatencyStatistic {
+    handshake: Histogram,
+    first_byte: Histogram,
+    duration: Histogram,
+}
+
+impl LatencyStatistic {
+    /// Create empty histograms
+    pub fn new() -> LatencyStatistic {
+        LatencyStatistic {
+            handshake: Histogram::new(LATENCY_BUCKETS),
+            first_byte: Histogram::new(LATENCY_BUCKETS),
+            duration: Histogram::new(DURATION_BUCKETS),
+        }
+    }
+
+    /// From accepting a connection until the client's request header is decrypted
+    pub fn handshake(&self) -> &Histogram {
+        &self.handshake
+    }
+
+    /// From accepting a connection until the first byte of the target's response is sent to the client
+    pub fn first_byte(&self) -> &Histogram {
+        &self.first_byte
+    }
+
+    /// From accepting a connection until it is closed
+    pub fn duration(&self) -> &Histogram {
+        &self.duration
+    }
+}
+
+impl Default for LatencyStatistic {
+    fn default() -> LatencyStatistic {
+        LatencyStatistic::new()
+    }
+}
+
+/// Snapshot of a server's latency histograms
+#[derive(Debug, Clone, Serialize)]
+pub struct LatencySnapshot {
+    pub handshake: HistogramSnapshot,
+    pub first_byte: HistogramSnapshot,
+    pub duration: HistogramSnapshot,
+}
+
+impl LatencySnapshot {
+    /// Take a snapshot of `stat`
+    pub fn new(stat: &LatencyStatistic) -> LatencySnapshot {
+        LatencySnapshot {
+            handshake: stat.handshake.snapshot(),
+            first_byte: stat.first_byte.snapshot(),
+            duration: stat.duration.snapshot(),
+        }
+    }
+}
+
+/// Live information of an established TCP connection
+pub struct ConnectionInfo {
+    peer_addr: SocketAddr,
+    established: Instant,
+    /// Known after the client's handshake
+    target: SpinMutex<Option<String>>,
+    user: SpinMutex<Option<String>>,
+    flow: FlowStatistic,
+}
+
+/// Shared reference for ConnectionInfo
+pub type SharedConnectionInfo = Arc<ConnectionInfo>;
+
+impl ConnectionInfo {
+    /// Create for a connection accepted from `peer_addr`
+    pub fn new_shared(peer_addr: SocketAddr) -> SharedConnectionInfo {
+        Arc::new(ConnectionInfo {
+            peer_addr,
+            established: Instant::now(),
+            target: SpinMutex::new(None),
+            user: SpinMutex::new(None),
+            flow: FlowStatistic::new(),
+        })
+    }
+
+    /// Set the target address requested by the client
+    pub fn set_target(&self, target: String) {
+        *self.target.lock() = Some(target);
+    }
+
+    /// Set the identified user of a multi-user server
+    pub fn set_user(&self, user: String) {
+        *self.user.lock() = Some(user);
+    }
+
+    /// Bytes sent to (`tx`) and received from (`rx`) the client
+    pub fn flow(&self) -> &FlowStatistic {
+        &self.flow
+    }
+
+    /// Time since the connection is accepted
+    pub fn elapsed(&self) -> Duration {
+        self.established.elapsed()
+    }
+}
+
+/// Snapshot of an established TCP connection
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionSnapshot {
+    /// Address of the client
+    pub peer_addr: SocketAddr,
+    /// Target address requested by the client
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// User of a multi-user server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Bytes sent to the client
+    pub tx: usize,
+    /// Bytes received from the client
+    pub rx: usize,
+    /// Seconds since the connection is established
+    pub age: u64,
+}
+
+impl ConnectionSnapshot {
+    /// Take a snapshot of a connection
+    pub fn new(info: &ConnectionInfo) -> ConnectionSnapshot {
+        ConnectionSnapshot {
+            peer_addr: info.peer_addr,
+            target: info.target.lock().clone(),
+            user: info.user.lock().clone(),
+            tx: info.flow.tx(),
+            rx: info.flow.rx(),
+            age: info.established.elapsed().as_secs(),
+        }
+    }
+}
+
+struct TrackedConnection {
+    handle: AbortHandle,
+    info: SharedConnectionInfo,
+}
+
 /// Shadowsocks Server flow statistic
 pub struct ServerFlowStatistic {
-    tcp: FlowStatistic,
-    udp: FlowStatistic,
+    tcp: ShardedFlowStatistic,
+    udp: ShardedFlowStatistic,
+    close: CloseStatistic,
+    latency: LatencyStatistic,
+    users: SpinMutex<BTreeMap<String, SharedUserFlowStatistic>>,
+    /// Enabled by `destination_stats_capacity`
+    destinations: Option<DestinationStatistic>,
+    /// Established TCP connections, could be aborted while draining
+    connections: SpinMutex<HashMap<usize, TrackedConnection>>,
+    next_connection_id: AtomicUsize,
+    udp_associations: AtomicUsize,
 }
 
 /// Shared reference for ServerFlowStatistic
@@ -65,8 +574,15 @@ impl ServerFlowStatistic {
     /// Create a new ServerFlowStatistic
     pub fn new() -> ServerFlowStatistic {
         ServerFlowStatistic {
-            tcp: FlowStatistic::new(),
-            udp: FlowStatistic::new(),
+            tcp: ShardedFlowStatistic::new(),
+            udp: ShardedFlowStatistic::new(),
+            close: CloseStatistic::new(),
+            latency: LatencyStatistic::new(),
+            users: SpinMutex::new(BTreeMap::new()),
+            destinations: None,
+            connections: SpinMutex::new(HashMap::new()),
+            next_connection_id: AtomicUsize::new(0),
+            udp_associations: AtomicUsize::new(0),
         }
     }
 
@@ -75,20 +591,124 @@ impl ServerFlowStatistic {
         Arc::new(ServerFlowStatistic::new())
     }
 
+    /// Create a new ServerFlowStatistic, which also aggregates traffic of up to `capacity` destination hosts
+    pub fn with_destination_capacity(capacity: usize) -> ServerFlowStatistic {
+        let mut stat = ServerFlowStatistic::new();
+        stat.destinations = Some(DestinationStatistic::new(capacity));
+        stat
+    }
+
     /// TCP relay server flow statistic
-    pub fn tcp(&self) -> &FlowStatistic {
+    pub fn tcp(&self) -> &ShardedFlowStatistic {
         &self.tcp
     }
 
     /// UDP relay server flow statistic
-    pub fn udp(&self) -> &FlowStatistic {
+    pub fn udp(&self) -> &ShardedFlowStatistic {
         &self.udp
     }
 
+    /// Closed connections statistic
+    pub fn close(&self) -> &CloseStatistic {
+        &self.close
+    }
+
+    /// Latencies and durations of TCP connections
+    pub fn latency(&self) -> &LatencyStatistic {
+        &self.latency
+    }
+
     /// Transmission statistic for manager
     pub fn trans_stat(&self) -> usize {
         self.tcp().tx() + self.tcp().rx() + self.udp().tx() + self.udp.rx()
     }
+
+    /// Check if bytes transferred reached `quota`
+    pub fn quota_exceeded(&self, quota: Option<u64>) -> bool {
+        match quota {
+            Some(quota) => self.trans_stat() as u64 >= quota,
+            None => false,
+        }
+    }
+
+    /// Flow statistic of user `name`, created on first use
+    ///
+    /// User's traffic is also counted in this server's statistic
+    pub fn user(&self, name: &str) -> SharedUserFlowStatistic {
+        let mut users = self.users.lock();
+        if let Some(stat) = users.get(name) {
+            return stat.clone();
+        }
+
+        let stat = Arc::new(UserFlowStatistic::new());
+        users.insert(name.to_owned(), stat.clone());
+        stat
+    }
+
+    /// Flow statistic of the destination host of `addr`, if destination statistics are enabled
+    pub fn destination(&self, addr: &Address) -> Option<SharedFlowStatistic> {
+        self.destinations.as_ref().map(|d| d.host(addr))
+    }
+
+    /// `n` destination hosts with the most transferred bytes, `None` if destination statistics are disabled
+    pub fn top_destinations(&self, n: usize) -> Option<Vec<DestinationSnapshot>> {
+        self.destinations.as_ref().map(|d| d.top(n))
+    }
+
+    /// Snapshot of all users' flow statistic, ordered by name
+    pub fn users(&self) -> Vec<(String, SharedUserFlowStatistic)> {
+        self.users.lock().iter().map(|(n, s)| (n.clone(), s.clone())).collect()
+    }
+
+    /// Track an established TCP connection, returns an ID for `untrack_connection`
+    pub fn track_connection(&self, handle: AbortHandle, info: SharedConnectionInfo) -> usize {
+        let id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        self.connections.lock().insert(id, TrackedConnection { handle, info });
+        id
+    }
+
+    /// Stop tracking a closed TCP connection
+    pub fn untrack_connection(&self, id: usize) {
+        self.connections.lock().remove(&id);
+    }
+
+    /// Number of established TCP connections
+    pub fn connections(&self) -> usize {
+        self.connections.lock().len()
+    }
+
+    /// Snapshots of established TCP connections, in no particular order
+    pub fn connection_snapshots(&self) -> Vec<ConnectionSnapshot> {
+        self.connections
+            .lock()
+            .values()
+            .map(|c| ConnectionSnapshot::new(&c.info))
+            .collect()
+    }
+
+    /// Record a created UDP association
+    pub fn incr_udp_associations(&self) {
+        self.udp_associations.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Record an expired UDP association
+    pub fn decr_udp_associations(&self) {
+        self.udp_associations.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    /// Number of active UDP associations
+    pub fn udp_associations(&self) -> usize {
+        self.udp_associations.load(Ordering::Acquire)
+    }
+
+    /// Abort all established TCP connections, returns how many were aborted
+    pub fn abort_connections(&self) -> usize {
+        let connections = mem::take(&mut *self.connections.lock());
+        for conn in connections.values() {
+            conn.handle.abort();
+        }
+        connections.len()
+    }
 }
 
 impl Default for ServerFlowStatistic {
@@ -97,6 +717,100 @@ impl Default for ServerFlowStatistic {
     }
 }
 
+/// Snapshot of a server's flow statistic
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerFlowSnapshot {
+    /// Port of the server
+    pub port: u16,
+    /// TCP bytes transferred
+    pub tcp_tx: usize,
+    /// TCP bytes received
+    pub tcp_rx: usize,
+    /// UDP bytes transferred
+    pub udp_tx: usize,
+    /// UDP bytes received
+    pub udp_rx: usize,
+    /// Established TCP connections
+    pub connections: usize,
+    /// Active UDP associations
+    pub udp_associations: usize,
+    /// Users of a multi-user server
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub users: BTreeMap<String, UserFlowSnapshot>,
+    /// Latencies and durations of TCP connections
+    pub latency: LatencySnapshot,
+}
+
+impl ServerFlowSnapshot {
+    /// Take a snapshot of the server listening on `port`
+    pub fn new(port: u16, stat: &ServerFlowStatistic) -> ServerFlowSnapshot {
+        ServerFlowSnapshot {
+            port,
+            tcp_tx: stat.tcp().tx(),
+            tcp_rx: stat.tcp().rx(),
+            udp_tx: stat.udp().tx(),
+            udp_rx: stat.udp().rx(),
+            connections: stat.connections(),
+            udp_associations: stat.udp_associations(),
+            users: stat
+                .users()
+                .into_iter()
+                .map(|(name, stat)| (name, UserFlowSnapshot::new(&stat)))
+                .collect(),
+            latency: LatencySnapshot::new(stat.latency()),
+        }
+    }
+}
+
+/// Snapshot of a user's flow statistic
+#[derive(Debug, Clone, Serialize)]
+pub struct UserFlowSnapshot {
+    /// TCP bytes transferred
+    pub tcp_tx: usize,
+    /// TCP bytes received
+    pub tcp_rx: usize,
+    /// UDP bytes transferred
+    pub udp_tx: usize,
+    /// UDP bytes received
+    pub udp_rx: usize,
+}
+
+impl UserFlowSnapshot {
+    /// Take a snapshot of a user
+    pub fn new(stat: &UserFlowStatistic) -> UserFlowSnapshot {
+        UserFlowSnapshot {
+            tcp_tx: stat.tcp().tx(),
+            tcp_rx: stat.tcp().rx(),
+            udp_tx: stat.udp().tx(),
+            udp_rx: stat.udp().rx(),
+        }
+    }
+}
+
+/// Snapshot of all servers' flow statistic
+#[derive(Debug, Clone, Serialize)]
+pub struct FlowSnapshot {
+    /// When the snapshot was taken, UNIX timestamp in seconds
+    pub timestamp: u64,
+    /// Servers ordered by port
+    pub servers: Vec<ServerFlowSnapshot>,
+}
+
+impl FlowSnapshot {
+    /// Snapshot of `servers`, stamped with the current time
+    pub fn new(servers: Vec<ServerFlowSnapshot>) -> FlowSnapshot {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        FlowSnapshot { timestamp, servers }
+    }
+}
+
+/// Snapshots kept for slow subscribers, older ones are dropped
+const SNAPSHOT_CHANNEL_CAPACITY: usize = 16;
+
 /// FlowStatic for multiple servers
 pub struct MultiServerFlowStatistic {
     servers: BTreeMap<u16, SharedServerFlowStatistic>,
@@ -110,7 +824,11 @@ impl MultiServerFlowStatistic {
     pub fn new(config: &Config) -> MultiServerFlowStatistic {
         let mut servers = BTreeMap::new();
         for svr_cfg in &config.server {
-            servers.insert(svr_cfg.addr().port(), ServerFlowStatistic::new_shared());
+            let stat = match config.destination_stats_capacity {
+                Some(capacity) => ServerFlowStatistic::with_destination_capacity(capacity),
+                None => ServerFlowStatistic::new(),
+            };
+            servers.insert(svr_cfg.addr().port(), Arc::new(stat));
         }
 
         MultiServerFlowStatistic { servers }
@@ -125,4 +843,43 @@ impl MultiServerFlowStatistic {
     pub fn get(&self, port: u16) -> Option<&SharedServerFlowStatistic> {
         self.servers.get(&port)
     }
+
+    /// Replace ServerFlowStatistic of port, for keeping statistic across server restarts
+    pub fn set(&mut self, port: u16, flow_stat: SharedServerFlowStatistic) {
+        self.servers.insert(port, flow_stat);
+    }
+
+    /// Take a snapshot of all servers
+    pub fn snapshot(&self) -> FlowSnapshot {
+        let servers = self
+            .servers
+            .iter()
+            .map(|(port, stat)| ServerFlowSnapshot::new(*port, stat))
+            .collect();
+
+        FlowSnapshot::new(servers)
+    }
+
+    /// Subscribe snapshots of all servers, taken every `interval`
+    ///
+    /// Snapshots are sent by a background task, which exits after all receivers are dropped.
+    /// Receivers falling behind get `RecvError::Lagged`, and continue with the oldest snapshot kept.
+    pub fn subscribe(self: &Arc<Self>, interval: Duration) -> broadcast::Receiver<FlowSnapshot> {
+        let (tx, rx) = broadcast::channel(SNAPSHOT_CHANNEL_CAPACITY);
+
+        let flow_stat = self.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(interval);
+            loop {
+                interval.tick().await;
+
+                if tx.send(flow_stat.snapshot()).is_err() {
+                    // All receivers are dropped
+                    break;
+                }
+            }
+        });
+
+        rx
+    }
 }
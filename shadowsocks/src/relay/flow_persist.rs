@@ -0,0 +1,144 @@
+//! Persisting traffic counters across restarts
+//!
+//! Counters of servers and their users are saved into `flow_stats_persist_path` in JSON, keyed by port:
+//!
+//! ```plain
+//! {"8388":{"tcp_tx":1024,"tcp_rx":512,"udp_tx":0,"udp_rx":0,"users":{"alice":{"tcp_tx":768,...}}}}
+//! ```
+//!
+//! Restored counters are added to the new statistic of the server, so quotas keep counting from where they were.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Error, ErrorKind},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{task, time};
+
+use crate::relay::flow::{FlowSnapshot, ServerFlowSnapshot, ServerFlowStatistic, UserFlowSnapshot};
+
+/// Interval of saving counters into `flow_stats_persist_path`
+const FLOW_STATS_PERSIST_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Persisted counters of a user
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PersistedUserFlow {
+    pub tcp_tx: u64,
+    pub tcp_rx: u64,
+    pub udp_tx: u64,
+    pub udp_rx: u64,
+}
+
+impl From<&UserFlowSnapshot> for PersistedUserFlow {
+    fn from(s: &UserFlowSnapshot) -> PersistedUserFlow {
+        PersistedUserFlow {
+            tcp_tx: s.tcp_tx as u64,
+            tcp_rx: s.tcp_rx as u64,
+            udp_tx: s.udp_tx as u64,
+            udp_rx: s.udp_rx as u64,
+        }
+    }
+}
+
+/// Persisted counters of a server
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PersistedServerFlow {
+    pub tcp_tx: u64,
+    pub tcp_rx: u64,
+    pub udp_tx: u64,
+    pub udp_rx: u64,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub users: BTreeMap<String, PersistedUserFlow>,
+}
+
+impl PersistedServerFlow {
+    /// Add counters to `stat`, which is usually a fresh one
+    pub fn restore(&self, stat: &ServerFlowStatistic) {
+        stat.tcp().incr_tx(self.tcp_tx as usize);
+        stat.tcp().incr_rx(self.tcp_rx as usize);
+        stat.udp().incr_tx(self.udp_tx as usize);
+        stat.udp().incr_rx(self.udp_rx as usize);
+
+        for (name, user) in &self.users {
+            let user_stat = stat.user(name);
+            user_stat.tcp().incr_tx(user.tcp_tx as usize);
+            user_stat.tcp().incr_rx(user.tcp_rx as usize);
+            user_stat.udp().incr_tx(user.udp_tx as usize);
+            user_stat.udp().incr_rx(user.udp_rx as usize);
+        }
+    }
+}
+
+impl From<&ServerFlowSnapshot> for PersistedServerFlow {
+    fn from(s: &ServerFlowSnapshot) -> PersistedServerFlow {
+        PersistedServerFlow {
+            tcp_tx: s.tcp_tx as u64,
+            tcp_rx: s.tcp_rx as u64,
+            udp_tx: s.udp_tx as u64,
+            udp_rx: s.udp_rx as u64,
+            users: s.users.iter().map(|(n, u)| (n.clone(), u.into())).collect(),
+        }
+    }
+}
+
+/// Persisted counters of all servers, keyed by port
+pub type PersistedFlow = BTreeMap<u16, PersistedServerFlow>;
+
+/// Counters of all servers in `snapshot`
+pub fn from_snapshot(snapshot: &FlowSnapshot) -> PersistedFlow {
+    snapshot.servers.iter().map(|s| (s.port, s.into())).collect()
+}
+
+/// Load counters from `path`, empty if the file doesn't exist yet
+pub fn load(path: &Path) -> io::Result<PersistedFlow> {
+    let buf = match fs::read(path) {
+        Ok(b) => b,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(PersistedFlow::new()),
+        Err(err) => return Err(err),
+    };
+
+    serde_json::from_slice(&buf).map_err(|err| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid traffic counters file, {}", err),
+        )
+    })
+}
+
+/// Save counters into `path`
+///
+/// Written into a temporary file and then renamed, so a crash while saving won't leave a broken file
+pub fn save(path: &Path, flow: &PersistedFlow) -> io::Result<()> {
+    let buf = serde_json::to_vec(flow).expect("convert traffic counters into JSON");
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    fs::write(&tmp_path, &buf)?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Save counters taken by `counters` into `path` periodically
+///
+/// Traffic since the last saving is lost if the process exits
+pub async fn flow_stats_persist_task<F>(path: PathBuf, counters: F)
+where
+    F: Fn() -> PersistedFlow,
+{
+    loop {
+        time::sleep(FLOW_STATS_PERSIST_INTERVAL).await;
+
+        let flow = counters();
+        let path = path.clone();
+        match task::spawn_blocking(move || save(&path, &flow)).await {
+            Ok(Ok(())) => debug!("traffic counters saved"),
+            Ok(Err(err)) => warn!("failed to save traffic counters, {}", err),
+            Err(err) => warn!("failed to save traffic counters, {}", err),
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Health-check listener
+//!
+//! A plaintext TCP listener separated from the encrypted service ports, for load balancers' probing.
+//! Every connection is answered with an HTTP response, `200 OK` if the server is healthy and
+//! `503 Service Unavailable` otherwise, with details in a JSON body. So it could be probed by both TCP and HTTP checks.
+//!
+//! Load balancers that send PROXY protocol (v1 or v2) headers are supported with `health_check_proxy_protocol`.
+
+use std::{
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use byteorder::{BigEndian, ByteOrder};
+use log::{debug, error, info, trace};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time,
+};
+
+use crate::{context::SharedContext, relay::selfcheck::CheckItem};
+
+/// Signature of PROXY protocol v2
+const PROXY_V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Maximum length of a PROXY protocol v1 header, including CRLF
+const PROXY_V1_MAX_LENGTH: usize = 107;
+
+/// Requests are read (and ignored) for at most this duration before responding
+const REQUEST_READ_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Serialize)]
+struct HealthReport<'a> {
+    healthy: bool,
+    /// Server is running and accepting new connections
+    accepting: bool,
+    /// Results of the startup self-check, including listeners and plugins
+    checks: &'a [CheckItem],
+}
+
+/// Runs the health-check listener on `health_check_addr`
+pub async fn run(context: SharedContext) -> io::Result<()> {
+    let addr = context
+        .config()
+        .health_check_addr
+        .as_ref()
+        .expect("health_check_addr must be provided");
+    let bind_addr = addr.bind_addr(&context).await?;
+
+    let listener = TcpListener::bind(bind_addr).await?;
+    let local_addr = listener.local_addr().expect("determine port bound to");
+    info!("shadowsocks health-check listening on {}", local_addr);
+
+    while context.server_running() {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(s) => s,
+            Err(err) => {
+                error!("health-check accept failed with error: {}", err);
+                time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let context = context.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_check(&context, stream, peer_addr).await {
+                debug!("health-check from {} failed, {}", peer_addr, err);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn handle_check(context: &SharedContext, mut stream: TcpStream, peer_addr: SocketAddr) -> io::Result<()> {
+    if context.config().health_check_proxy_protocol {
+        match read_proxy_header(&mut stream).await? {
+            Some(client_addr) => trace!("health-check from {} (proxied by {})", client_addr, peer_addr),
+            None => trace!("health-check from {} (PROXY LOCAL)", peer_addr),
+        }
+    } else {
+        trace!("health-check from {}", peer_addr);
+    }
+
+    // Consume the HTTP request if there is one, closing with unread data would reset the connection
+    let mut request = [0u8; 1024];
+    let _ = time::timeout(REQUEST_READ_TIMEOUT, stream.read(&mut request)).await;
+
+    let report = context.self_check_report();
+    let accepting = context.server_running() && !context.server_state().memory_pressured();
+    let checks = report.as_ref().map(|r| r.items()).unwrap_or(&[]);
+    let healthy = accepting && report.as_ref().map(|r| r.passed()).unwrap_or(false);
+
+    let body = serde_json::to_string(&HealthReport {
+        healthy,
+        accepting,
+        checks,
+    })
+    .expect("serialize health report");
+
+    let status = if healthy { "200 OK" } else { "503 Service Unavailable" };
+    let response = format!(
+        "HTTP/1.0 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Read PROXY protocol header, returns the original client's address
+///
+/// `None` if the header doesn't carry an address, like `PROXY UNKNOWN` or v2's `LOCAL` command
+async fn read_proxy_header(stream: &mut TcpStream) -> io::Result<Option<SocketAddr>> {
+    let mut prefix = [0u8; 5];
+    stream.read_exact(&mut prefix).await?;
+
+    if &prefix == b"PROXY" {
+        read_proxy_v1(stream).await
+    } else if prefix[..] == PROXY_V2_SIGNATURE[..prefix.len()] {
+        read_proxy_v2(stream).await
+    } else {
+        Err(io::Error::new(ErrorKind::InvalidData, "missing PROXY protocol header"))
+    }
+}
+
+async fn read_proxy_v1(stream: &mut TcpStream) -> io::Result<Option<SocketAddr>> {
+    // "PROXY" has been read, read until CRLF
+    let mut line = Vec::with_capacity(PROXY_V1_MAX_LENGTH);
+    line.extend_from_slice(b"PROXY");
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= PROXY_V1_MAX_LENGTH {
+            return Err(io::Error::new(ErrorKind::InvalidData, "PROXY v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = match std::str::from_utf8(&line[..line.len() - 2]) {
+        Ok(l) => l,
+        Err(..) => return Err(io::Error::new(ErrorKind::InvalidData, "invalid PROXY v1 header")),
+    };
+
+    // PROXY TCP4|TCP6|UNKNOWN SRC_ADDR DST_ADDR SRC_PORT DST_PORT
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.get(1) {
+        Some(&"UNKNOWN") => Ok(None),
+        Some(&"TCP4") | Some(&"TCP6") if parts.len() == 6 => {
+            match (parts[2].parse::<IpAddr>(), parts[4].parse::<u16>()) {
+                (Ok(ip), Ok(port)) => Ok(Some(SocketAddr::new(ip, port))),
+                _ => Err(io::Error::new(ErrorKind::InvalidData, "invalid PROXY v1 address")),
+            }
+        }
+        _ => Err(io::Error::new(ErrorKind::InvalidData, "invalid PROXY v1 header")),
+    }
+}
+
+async fn read_proxy_v2(stream: &mut TcpStream) -> io::Result<Option<SocketAddr>> {
+    // Rest of the signature, version and command, address family and protocol, length of addresses
+    let mut header = [0u8; 16];
+    stream.read_exact(&mut header[5..]).await?;
+
+    if header[5..12] != PROXY_V2_SIGNATURE[5..] {
+        return Err(io::Error::new(ErrorKind::InvalidData, "invalid PROXY v2 signature"));
+    }
+
+    let version_command = header[12];
+    if version_command >> 4 != 2 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "unsupported PROXY protocol version",
+        ));
+    }
+
+    let family = header[13];
+    let len = BigEndian::read_u16(&header[14..16]) as usize;
+
+    let mut addrs = vec![0u8; len];
+    stream.read_exact(&mut addrs).await?;
+
+    // LOCAL command, health checks from the proxy itself
+    if version_command & 0x0F == 0 {
+        return Ok(None);
+    }
+
+    match family >> 4 {
+        // AF_INET: src_addr(4) dst_addr(4) src_port(2) dst_port(2)
+        0x1 if len >= 12 => {
+            let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V4(ip),
+                BigEndian::read_u16(&addrs[8..10]),
+            )))
+        }
+        // AF_INET6: src_addr(16) dst_addr(16) src_port(2) dst_port(2)
+        0x2 if len >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addrs[..16]);
+            let ip = Ipv6Addr::from(octets);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(ip),
+                BigEndian::read_u16(&addrs[32..34]),
+            )))
+        }
+        // AF_UNSPEC, AF_UNIX
+        _ => Ok(None),
+    }
+}
@@ -0,0 +1,229 @@
+//! Control API of load balancers
+//!
+//! A plain TCP listener, every line received is a command, which is answered with a line.
+//!
+//! - `list`: Servers in JSON, like `[{"server":"127.0.0.1:8388","enabled":true}]`
+//! - `add: ss://...`: Add a server in SIP002 URL
+//! - `remove: ADDR`, `disable: ADDR`, `enable: ADDR`: Mutate the server with address `ADDR`, like `127.0.0.1:8388`
+//! - `explain: TARGET`: How connections to `TARGET` (like `www.example.com:443` or `1.2.3.4`) are handled by ACL,
+//!   in JSON, like `{"target":"direct","section":"[bypass_list]","rule":"(^|\.)example\.com$","resolved":null}`
+//! - `dns_cache`: Counters and entries of `dns_cache` in JSON, like
+//!   `{"hits":12,"misses":3,"entries":[{"host":"example.com","addrs":["1.2.3.4"],"error":null,"ttl":25}]}`
+//! - `dns_flush`, `dns_flush: HOST`: Remove all entries, or `HOST`, from `dns_cache`
+//!
+//! Mutating commands are answered with `ok`, or `error: REASON` if failed.
+
+use std::{
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use log::{debug, error, info};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    time,
+};
+
+use crate::{
+    acl::Explanation,
+    config::ServerConfig,
+    context::{Context, SharedContext},
+    relay::{dns_resolver::DnsCacheEntry, socks5::Address},
+};
+
+#[derive(Serialize)]
+struct ServerEntry {
+    server: String,
+    enabled: bool,
+}
+
+#[derive(Serialize)]
+struct ExplainEntry {
+    target: String,
+    section: String,
+    rule: Option<String>,
+    resolved: Option<String>,
+}
+
+impl From<Explanation> for ExplainEntry {
+    fn from(e: Explanation) -> ExplainEntry {
+        ExplainEntry {
+            target: e.target.to_string(),
+            section: e.section,
+            rule: e.rule,
+            resolved: e.resolved.map(|ip| ip.to_string()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DnsCacheItem {
+    host: String,
+    addrs: Vec<String>,
+    error: Option<String>,
+    ttl: u64,
+}
+
+impl From<DnsCacheEntry> for DnsCacheItem {
+    fn from(e: DnsCacheEntry) -> DnsCacheItem {
+        let (addrs, error) = match e.result {
+            Ok(ips) => (ips.iter().map(ToString::to_string).collect(), None),
+            Err(msg) => (Vec::new(), Some(msg)),
+        };
+        DnsCacheItem {
+            host: e.host,
+            addrs,
+            error,
+            ttl: e.ttl.as_secs(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DnsCacheReport {
+    hits: u64,
+    misses: u64,
+    entries: Vec<DnsCacheItem>,
+}
+
+/// Runs the control API listener on `balancer_control_addr`
+pub async fn run(context: SharedContext) -> io::Result<()> {
+    let addr = context
+        .config()
+        .balancer_control_addr
+        .as_ref()
+        .expect("balancer_control_addr must be provided");
+    let bind_addr = addr.bind_addr(&context).await?;
+
+    let listener = TcpListener::bind(bind_addr).await?;
+    let local_addr = listener.local_addr().expect("determine port bound to");
+    info!("shadowsocks balancer control API listening on {}", local_addr);
+
+    while context.server_running() {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(s) => s,
+            Err(err) => {
+                error!("balancer control API accept failed with error: {}", err);
+                time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let context = context.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_client(&context, stream, peer_addr).await {
+                debug!("balancer control API client {} closed with error: {}", peer_addr, err);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn handle_client(context: &Context, mut stream: TcpStream, peer_addr: SocketAddr) -> io::Result<()> {
+    let (r, mut w) = stream.split();
+    let mut lines = BufReader::new(r).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let command = line.trim();
+        if command.is_empty() {
+            continue;
+        }
+
+        let reply = handle_command(context, command).await;
+        debug!(
+            "balancer control API client {} command: {}, reply: {}",
+            peer_addr, command, reply
+        );
+
+        w.write_all(reply.as_bytes()).await?;
+        w.write_all(b"\n").await?;
+    }
+
+    Ok(())
+}
+
+async fn handle_command(context: &Context, command: &str) -> String {
+    let (cmd, arg) = match command.find(':') {
+        Some(pos) => (&command[..pos], command[pos + 1..].trim()),
+        None => (command, ""),
+    };
+
+    let result = match cmd {
+        "list" => {
+            let servers: Vec<ServerEntry> = context
+                .server_list()
+                .into_iter()
+                .map(|(svr_cfg, enabled)| ServerEntry {
+                    server: svr_cfg.addr().to_string(),
+                    enabled,
+                })
+                .collect();
+            return serde_json::to_string(&servers).expect("serialize server list");
+        }
+        "add" => match ServerConfig::from_url(arg) {
+            Ok(svr_cfg) => context.add_server(svr_cfg),
+            Err(err) => Err(io::Error::new(ErrorKind::InvalidInput, err)),
+        },
+        "remove" => context.remove_server(arg),
+        "disable" => context.set_server_enabled(arg, false),
+        "enable" => context.set_server_enabled(arg, true),
+        "explain" => match explain_target(context, arg).await {
+            Ok(entry) => return serde_json::to_string(&entry).expect("serialize explanation"),
+            Err(err) => Err(err),
+        },
+        "dns_cache" => match context.dns_cache() {
+            Some(cache) => {
+                let (hits, misses) = cache.hits_and_misses();
+                let report = DnsCacheReport {
+                    hits,
+                    misses,
+                    entries: cache.entries().into_iter().map(From::from).collect(),
+                };
+                return serde_json::to_string(&report).expect("serialize DNS cache");
+            }
+            None => Err(io::Error::new(ErrorKind::Other, "dns_cache is not enabled")),
+        },
+        "dns_flush" => match context.dns_cache() {
+            Some(cache) => {
+                let n = cache.flush(if arg.is_empty() { None } else { Some(arg) });
+                debug!("flushed {} entries of DNS cache", n);
+                Ok(())
+            }
+            None => Err(io::Error::new(ErrorKind::Other, "dns_cache is not enabled")),
+        },
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("unknown command \"{}\"", cmd),
+        )),
+    };
+
+    match result {
+        Ok(..) => "ok".to_owned(),
+        Err(err) => format!("error: {}", err),
+    }
+}
+
+async fn explain_target(context: &Context, target: &str) -> io::Result<ExplainEntry> {
+    let acl = match context.acl() {
+        Some(acl) => acl,
+        None => return Err(io::Error::new(ErrorKind::Other, "ACL is not configured")),
+    };
+
+    // Bare IP addresses, without ports
+    let addr = match target.parse::<IpAddr>() {
+        Ok(ip) => Address::SocketAddress(SocketAddr::new(ip, 0)),
+        Err(..) => match target.parse::<Address>() {
+            Ok(addr) => addr,
+            Err(..) => {
+                let err = io::Error::new(ErrorKind::InvalidInput, format!("invalid address \"{}\"", target));
+                return Err(err);
+            }
+        },
+    };
+
+    Ok(acl.explain_target(context, &addr).await.into())
+}
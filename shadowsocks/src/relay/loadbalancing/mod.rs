@@ -1,3 +1,5 @@
 //! Load balancer
 
+pub mod control;
+pub mod query;
 pub mod server;
@@ -0,0 +1,46 @@
+//! Query API of load balancers
+//!
+//! A plain TCP listener, replies the latest probing results of all servers in JSON and closes the connection.
+
+use std::{io, time::Duration};
+
+use log::{debug, error, info};
+use tokio::{io::AsyncWriteExt, net::TcpListener, time};
+
+use crate::context::SharedContext;
+
+/// Runs the query API listener on `balancer_query_addr`
+pub async fn run(context: SharedContext) -> io::Result<()> {
+    let addr = context
+        .config()
+        .balancer_query_addr
+        .as_ref()
+        .expect("balancer_query_addr must be provided");
+    let bind_addr = addr.bind_addr(&context).await?;
+
+    let listener = TcpListener::bind(bind_addr).await?;
+    let local_addr = listener.local_addr().expect("determine port bound to");
+    info!("shadowsocks balancer query API listening on {}", local_addr);
+
+    while context.server_running() {
+        let (mut stream, peer_addr) = match listener.accept().await {
+            Ok(s) => s,
+            Err(err) => {
+                error!("balancer query API accept failed with error: {}", err);
+                time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let body = serde_json::to_vec(&context.balancer_status()).expect("serialize balancer status");
+        tokio::spawn(async move {
+            if let Err(err) = stream.write_all(&body).await {
+                debug!("failed to reply balancer status to {}, {}", peer_addr, err);
+                return;
+            }
+            let _ = stream.shutdown().await;
+        });
+    }
+
+    Ok(())
+}
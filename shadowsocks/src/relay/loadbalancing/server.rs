@@ -1,27 +1,35 @@
 use std::{
-    collections::VecDeque,
+    cmp,
+    collections::{hash_map::DefaultHasher, VecDeque},
     fmt,
+    hash::{Hash, Hasher},
     io,
+    iter,
+    mem,
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    ops::Deref,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc,
     },
     time::{Duration, Instant},
 };
 
 use crate::{
-    config::{Config, ServerConfig},
+    config::{BalancerProbe, BalancerStrategy, Config, ServerConfig, ServerGroupConfig, DEFAULT_SERVER_GROUP},
     context::{Context, SharedContext},
     relay::{
         socks5::Address,
-        tcprelay::client::ServerClient as TcpServerClient,
+        tcprelay::{client::ServerClient as TcpServerClient, probe_proxy_server},
         udprelay::client::ServerClient as UdpServerClient,
     },
 };
 
 use byte_string::ByteStr;
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
+use rand::Rng;
+use serde::Serialize;
+use spin::Mutex as SpinMutex;
 use tokio::{
     self,
     io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
@@ -33,6 +41,11 @@ const MAX_LATENCY_QUEUE_SIZE: usize = 99;
 const DEFAULT_CHECK_INTERVAL_SEC: u64 = 6;
 const DEFAULT_CHECK_TIMEOUT_SEC: u64 = 2; // Latency shouldn't greater than 2 secs, that's too long
 const MAX_SERVER_RTT: u64 = DEFAULT_CHECK_TIMEOUT_SEC * 1000;
+const MAX_DOWN_CHECK_INTERVAL_SEC: u64 = 120; // Down servers are re-probed with exponential backoff up to this
+const DEFAULT_SERVER_WEIGHT: u32 = 1; // Weight of servers without `weight`, if the others have
+const MAX_TRAFFIC_QUEUE_SIZE: usize = 99;
+const MIN_GOODPUT_SAMPLE_BYTES: u64 = 256 * 1024; // Smaller transfers are dominated by latency, not bandwidth
+const GOODPUT_REFERENCE: f64 = 1024.0 * 1024.0; // Goodput (bytes per sec) scored as the half of the worst
 
 /// Identifier of a valid server
 pub trait ServerData: Send + Sync {
@@ -54,6 +67,20 @@ struct ServerStatisticData {
     latency_stdev: f64,
     /// Score's average
     latency_mean: f64,
+    /// Probes failed in a row
+    consecutive_failures: u32,
+    /// Probes succeeded in a row
+    consecutive_successes: u32,
+    /// Marked down by health checking, new connections won't be sent to it if there are any other servers up
+    down: bool,
+    /// Interval of re-probing while the server is down
+    down_check_interval: Duration,
+    /// Errored / Total of recently relayed connections
+    traffic_fail_rate: f64,
+    /// Median of goodput of recently relayed connections (bytes per sec), `None` if not measured yet
+    goodput: Option<f64>,
+    /// Recently relayed connections
+    traffic_queue: VecDeque<Traffic>,
 }
 
 fn max_latency_stdev() -> f64 {
@@ -73,6 +100,13 @@ impl ServerStatisticData {
             latency_queue: VecDeque::new(),
             latency_stdev: 0.0,
             latency_mean: 0.0,
+            consecutive_failures: 0,
+            consecutive_successes: 0,
+            down: false,
+            down_check_interval: Duration::from_secs(DEFAULT_CHECK_INTERVAL_SEC),
+            traffic_fail_rate: 0.0,
+            goodput: None,
+            traffic_queue: VecDeque::new(),
         }
     }
 
@@ -83,22 +117,87 @@ impl ServerStatisticData {
         // Normalize stdev
         let nstdev = self.latency_stdev / max_latency_stdev();
 
+        // Normalize goodput, servers haven't been measured are treated as moderate ones
+        let ngoodput = match self.goodput {
+            Some(goodput) => GOODPUT_REFERENCE / (GOODPUT_REFERENCE + goodput),
+            None => 0.5,
+        };
+
         const SCORE_RTT_WEIGHT: f64 = 1.0;
         const SCORE_FAIL_WEIGHT: f64 = 3.0;
         const SCORE_STDEV_WEIGHT: f64 = 1.0;
+        const SCORE_TRAFFIC_FAIL_WEIGHT: f64 = 2.0;
+        const SCORE_GOODPUT_WEIGHT: f64 = 2.0;
 
-        // Score = (norm_lat * 1.0 + prop_err * 3.0 + stdev * 1.0) / 5.0
+        // Score = (norm_lat * 1.0 + prop_err * 3.0 + stdev * 1.0 + traffic_err * 2.0 + norm_goodput * 2.0) / 9.0
         //
         // 1. The lower latency, the better
         // 2. The lower errored count, the better
         // 3. The lower latency's stdev, the better
-        let score = (nrtt * SCORE_RTT_WEIGHT + self.fail_rate * SCORE_FAIL_WEIGHT + nstdev * SCORE_STDEV_WEIGHT)
-            / (SCORE_RTT_WEIGHT + SCORE_FAIL_WEIGHT + SCORE_STDEV_WEIGHT);
+        // 4. The lower errored count of relayed connections, the better
+        // 5. The higher goodput of relayed connections, the better
+        let score = (nrtt * SCORE_RTT_WEIGHT
+            + self.fail_rate * SCORE_FAIL_WEIGHT
+            + nstdev * SCORE_STDEV_WEIGHT
+            + self.traffic_fail_rate * SCORE_TRAFFIC_FAIL_WEIGHT
+            + ngoodput * SCORE_GOODPUT_WEIGHT)
+            / (SCORE_RTT_WEIGHT
+                + SCORE_FAIL_WEIGHT
+                + SCORE_STDEV_WEIGHT
+                + SCORE_TRAFFIC_FAIL_WEIGHT
+                + SCORE_GOODPUT_WEIGHT);
 
         // Times 1000 converts to u64, for 0.001 precision
         (score * 1000.0) as u64
     }
 
+    /// A server is healthy if it isn't marked down
+    fn healthy(&self) -> bool {
+        !self.down
+    }
+
+    /// Update health state with a probe result
+    ///
+    /// A server is marked down after `failure_threshold` consecutive failures,
+    /// and marked up again after `recovery_threshold` consecutive successes.
+    /// Returns the new state if it has been changed.
+    fn update_health(&mut self, succeeded: bool, failure_threshold: u32, recovery_threshold: u32) -> Option<bool> {
+        if succeeded {
+            self.consecutive_failures = 0;
+            self.consecutive_successes = self.consecutive_successes.saturating_add(1);
+
+            if self.down && self.consecutive_successes >= recovery_threshold {
+                self.down = false;
+                self.down_check_interval = Duration::from_secs(DEFAULT_CHECK_INTERVAL_SEC);
+                return Some(true);
+            }
+        } else {
+            self.consecutive_successes = 0;
+            self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+
+            if self.down {
+                // Still down, back off
+                let max_interval = Duration::from_secs(MAX_DOWN_CHECK_INTERVAL_SEC);
+                self.down_check_interval = (self.down_check_interval * 2).min(max_interval);
+            } else if self.consecutive_failures >= failure_threshold {
+                self.down = true;
+                return Some(false);
+            }
+        }
+
+        None
+    }
+
+    /// Interval before the next probe
+    fn check_interval(&self) -> Duration {
+        if self.down && self.consecutive_successes == 0 {
+            self.down_check_interval
+        } else {
+            // Confirm recovery quickly
+            Duration::from_secs(DEFAULT_CHECK_INTERVAL_SEC)
+        }
+    }
+
     fn push_score(&mut self, score: Score) -> u64 {
         self.latency_queue.push_back(score);
 
@@ -164,6 +263,56 @@ impl ServerStatisticData {
     pub fn report_failure(&mut self) -> u64 {
         self.push_score(Score::Errored)
     }
+
+    fn push_traffic(&mut self, traffic: Traffic) -> u64 {
+        self.traffic_queue.push_back(traffic);
+
+        // Only records recently MAX_TRAFFIC_QUEUE_SIZE relayed connections
+        if self.traffic_queue.len() > MAX_TRAFFIC_QUEUE_SIZE {
+            self.traffic_queue.pop_front();
+        }
+
+        let mut vgoodput = Vec::with_capacity(self.traffic_queue.len());
+        let mut cerr = 0;
+        for t in &self.traffic_queue {
+            match *t {
+                Traffic::Errored => cerr += 1,
+                Traffic::Completed(Some(goodput)) => vgoodput.push(goodput),
+                Traffic::Completed(None) => {}
+            }
+        }
+
+        self.traffic_fail_rate = cerr as f64 / self.traffic_queue.len() as f64;
+
+        if !vgoodput.is_empty() {
+            vgoodput.sort_by(|a, b| a.partial_cmp(b).unwrap_or(cmp::Ordering::Equal));
+
+            // Find median of goodput
+            let mid = vgoodput.len() / 2;
+
+            self.goodput = Some(if vgoodput.len() % 2 == 0 {
+                (vgoodput[mid] + vgoodput[mid - 1]) / 2.0
+            } else {
+                vgoodput[mid]
+            });
+        }
+
+        self.score()
+    }
+
+    pub fn report_transfer(&mut self, bytes: u64, elapsed: Duration) -> u64 {
+        let secs = elapsed.as_secs_f64();
+        let goodput = if bytes >= MIN_GOODPUT_SAMPLE_BYTES && secs > 0.0 {
+            Some(bytes as f64 / secs)
+        } else {
+            None
+        };
+        self.push_traffic(Traffic::Completed(goodput))
+    }
+
+    pub fn report_relay_error(&mut self) -> u64 {
+        self.push_traffic(Traffic::Errored)
+    }
 }
 
 /// Shared handle for mutating server's statistic data
@@ -185,11 +334,36 @@ impl SharedServerStatisticData {
         data.push_score(score)
     }
 
+    pub async fn report_transfer(&self, bytes: u64, elapsed: Duration) -> u64 {
+        let mut data = self.0.lock().await;
+        data.report_transfer(bytes, elapsed)
+    }
+
+    pub async fn report_relay_error(&self) -> u64 {
+        let mut data = self.0.lock().await;
+        data.report_relay_error()
+    }
+
     pub async fn score(&self) -> u64 {
         let data = self.0.lock().await;
         data.score()
     }
 
+    async fn update_health(&self, succeeded: bool, failure_threshold: u32, recovery_threshold: u32) -> Option<bool> {
+        let mut data = self.0.lock().await;
+        data.update_health(succeeded, failure_threshold, recovery_threshold)
+    }
+
+    async fn check_interval(&self) -> Duration {
+        let data = self.0.lock().await;
+        data.check_interval()
+    }
+
+    async fn score_and_health(&self) -> (u64, bool) {
+        let data = self.0.lock().await;
+        (data.score(), data.healthy())
+    }
+
     async fn debug_string(&self) -> String {
         format!("{:?}", self.0.lock().await)
     }
@@ -201,6 +375,8 @@ pub struct ServerStatistic<S: ServerData> {
     context: SharedContext,
     server_idx: usize,
     data: SharedServerStatisticData,
+    /// TCP connections holding a `ServerSlot` of this server
+    connections: AtomicUsize,
 }
 
 pub type SharedServerStatistic<S> = Arc<ServerStatistic<S>>;
@@ -214,6 +390,7 @@ impl<S: ServerData> ServerStatistic<S> {
             context,
             server_idx,
             data,
+            connections: AtomicUsize::new(0),
         }
     }
 
@@ -254,17 +431,113 @@ impl<S: ServerData> ServerStatistic<S> {
         self.data.report_failure().await
     }
 
+    /// Report a relayed connection completed, with `bytes` received from the server in `elapsed`
+    pub async fn report_transfer(&self, bytes: u64, elapsed: Duration) -> u64 {
+        self.data.report_transfer(bytes, elapsed).await
+    }
+
+    /// Report a relayed connection broken by errors, like being reset
+    pub async fn report_relay_error(&self) -> u64 {
+        self.data.report_relay_error().await
+    }
+
+    /// Count of TCP connections relaying through this server
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
     async fn data_debug_string(&self) -> String {
         self.data.debug_string().await
     }
 }
 
+/// A TCP connection through a server, counted in the server's `max_connections` until it is dropped
+pub struct ServerSlot<S: ServerData> {
+    server: SharedServerStatistic<S>,
+}
+
+impl<S: ServerData> ServerSlot<S> {
+    /// Take a slot of `server`, `None` if it has reached its `max_connections`
+    fn acquire(server: &SharedServerStatistic<S>) -> Option<ServerSlot<S>> {
+        let prev = server.connections.fetch_add(1, Ordering::AcqRel);
+
+        if let Some(max_connections) = server.server_config().max_connections() {
+            if prev >= max_connections {
+                server.connections.fetch_sub(1, Ordering::AcqRel);
+                return None;
+            }
+        }
+
+        Some(ServerSlot { server: server.clone() })
+    }
+}
+
+impl<S: ServerData> Deref for ServerSlot<S> {
+    type Target = SharedServerStatistic<S>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.server
+    }
+}
+
+impl<S: ServerData> Drop for ServerSlot<S> {
+    fn drop(&mut self) {
+        self.server.connections.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Probing result of a server, for querying
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerProbeStatus {
+    /// Server's address
+    pub server: String,
+    /// Group of the server
+    pub group: String,
+    /// Score, the lower the better
+    pub score: u64,
+    /// Median of latency (in millisec)
+    pub rtt: u64,
+    /// Rate of failed probes
+    pub fail_rate: f64,
+    /// Rate of relayed connections broken by errors
+    pub traffic_fail_rate: f64,
+    /// Median of goodput of relayed connections (bytes per sec), if measured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub goodput: Option<u64>,
+    /// Whether the server is up, it is marked down after consecutive failed probes
+    pub healthy: bool,
+    /// Probes failed in a row
+    pub consecutive_failures: u32,
+    /// Weight in traffic share, if servers are weighted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
+    /// Whether new connections are routed to this server
+    pub selected: bool,
+}
+
+/// Probing results of load balancers
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BalancerStatus {
+    /// Servers of the TCP load balancer
+    pub tcp: Vec<ServerProbeStatus>,
+    /// Servers of the UDP load balancer
+    pub udp: Vec<ServerProbeStatus>,
+}
+
 #[derive(Debug, Copy, Clone)]
 enum Score {
     Latency(u64),
     Errored,
 }
 
+/// Outcome of a relayed connection
+#[derive(Debug, Copy, Clone)]
+enum Traffic {
+    /// Completed, with goodput (bytes per sec) if it transferred enough data to be measured
+    Completed(Option<f64>),
+    Errored,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum ServerType {
     Tcp,
@@ -281,27 +554,200 @@ impl fmt::Display for ServerType {
 }
 
 struct BestServer<S: ServerData> {
+    /// Name of the group
+    name: String,
+    config: ServerGroupConfig,
     servers: Vec<SharedServerStatistic<S>>,
     best_idx: AtomicUsize,
+    /// The second best server, raced with the picked one if `balancer_race` is enabled
+    runner_up_idx: AtomicUsize,
+    /// Next server for `RoundRobin`
+    next_idx: AtomicUsize,
+    /// Weights of servers, `None` if none of the servers has a weight
+    weights: Option<Vec<u32>>,
+    /// Health of servers, updated while choosing the best server
+    healthy: Vec<AtomicBool>,
 }
 
 type SharedBestServer<S> = Arc<BestServer<S>>;
 
 impl<S: ServerData> BestServer<S> {
-    fn new(servers: Vec<SharedServerStatistic<S>>) -> BestServer<S> {
+    fn new(name: String, config: ServerGroupConfig, servers: Vec<SharedServerStatistic<S>>) -> BestServer<S> {
+        let weights = if servers.iter().any(|s| s.server_config().weight().is_some()) {
+            Some(
+                servers
+                    .iter()
+                    .map(|s| s.server_config().weight().unwrap_or(DEFAULT_SERVER_WEIGHT))
+                    .collect(),
+            )
+        } else {
+            None
+        };
+        let healthy = servers.iter().map(|_| AtomicBool::new(true)).collect();
+
         BestServer {
+            name,
+            config,
             servers,
             best_idx: AtomicUsize::new(0),
+            runner_up_idx: AtomicUsize::new(0),
+            next_idx: AtomicUsize::new(0),
+            weights,
+            healthy,
+        }
+    }
+
+    fn new_shared(
+        name: String,
+        config: ServerGroupConfig,
+        servers: Vec<SharedServerStatistic<S>>,
+    ) -> SharedBestServer<S> {
+        Arc::new(BestServer::new(name, config, servers))
+    }
+
+    /// Pick a server for proxying `addr`, or for any destinations if `addr` is unknown
+    fn pick_server(&self, addr: Option<&Address>) -> SharedServerStatistic<S> {
+        self.servers[self.pick_server_idx(addr)].clone()
+    }
+
+    /// Take a slot of the server picked for proxying `addr`
+    ///
+    /// Overflows to the best, the second best and then the other healthy servers if the picked one is full.
+    /// `None` if all of them are full.
+    fn acquire_server(&self, addr: &Address) -> Option<(usize, ServerSlot<S>)> {
+        let idx = self.pick_server_idx(Some(addr));
+
+        let candidates = iter::once(idx)
+            .chain(iter::once(self.best_idx.load(Ordering::Relaxed)))
+            .chain(iter::once(self.runner_up_idx.load(Ordering::Relaxed)))
+            .chain((0..self.servers.len()).filter(|&i| self.healthy[i].load(Ordering::Relaxed)));
+
+        for candidate in candidates {
+            if let Some(slot) = ServerSlot::acquire(&self.servers[candidate]) {
+                if candidate != idx {
+                    debug!(
+                        "server {} reached max_connections, overflowed to {}",
+                        self.servers[idx].server_config().addr(),
+                        slot.server_config().addr()
+                    );
+                }
+                return Some((candidate, slot));
+            }
+        }
+
+        None
+    }
+
+    /// Take slots of the server picked for proxying `addr`, and another healthy one in the top-2 ranked servers
+    /// for racing if it is not full
+    fn acquire_servers_raced(&self, addr: &Address) -> Option<(ServerSlot<S>, Option<ServerSlot<S>>)> {
+        let (idx, slot) = self.acquire_server(addr)?;
+
+        let best_idx = self.best_idx.load(Ordering::Relaxed);
+        let other_idx = if best_idx != idx {
+            best_idx
+        } else {
+            self.runner_up_idx.load(Ordering::Relaxed)
+        };
+
+        let other = if other_idx != idx && self.healthy[other_idx].load(Ordering::Relaxed) {
+            ServerSlot::acquire(&self.servers[other_idx])
+        } else {
+            None
+        };
+
+        Some((slot, other))
+    }
+
+    fn pick_server_idx(&self, addr: Option<&Address>) -> usize {
+        match self.config.strategy {
+            BalancerStrategy::Latency => match self.pick_weighted() {
+                Some(idx) => idx,
+                None => self.best_idx.load(Ordering::Relaxed),
+            },
+            BalancerStrategy::RoundRobin => self.pick_round_robin(),
+            BalancerStrategy::Fixed => 0,
+            BalancerStrategy::Sticky => {
+                let host = addr.map(|a| match *a {
+                    Address::SocketAddress(ref sa) => sa.ip().to_string(),
+                    Address::DomainNameAddress(ref dname, _) => dname.trim_end_matches('.').to_ascii_lowercase(),
+                });
+
+                match host {
+                    Some(ref host) if !self.config.is_sticky_excluded(host) => self.pick_sticky(host),
+                    _ => self.best_idx.load(Ordering::Relaxed),
+                }
+            }
         }
     }
 
-    fn new_shared(servers: Vec<SharedServerStatistic<S>>) -> SharedBestServer<S> {
-        Arc::new(BestServer::new(servers))
+    /// Pick a healthy server by rendezvous hashing of `host`, or among all servers if all of them are down
+    ///
+    /// Hosts of a server that goes down are moved to the others, the rest of the hosts stay where they are
+    fn pick_sticky(&self, host: &str) -> usize {
+        let any_healthy = self.healthy.iter().any(|h| h.load(Ordering::Relaxed));
+
+        self.servers
+            .iter()
+            .enumerate()
+            .filter(|&(idx, _)| !any_healthy || self.healthy[idx].load(Ordering::Relaxed))
+            .max_by_key(|(_, svr)| {
+                let mut hasher = DefaultHasher::new();
+                host.hash(&mut hasher);
+                svr.server_config().addr().to_string().hash(&mut hasher);
+                hasher.finish()
+            })
+            .map(|(idx, _)| idx)
+            .unwrap_or(0)
+    }
+
+    /// Pick healthy servers in turn, or all servers in turn if all of them are down
+    fn pick_round_robin(&self) -> usize {
+        let count = self.servers.len();
+        let start = self.next_idx.fetch_add(1, Ordering::Relaxed);
+
+        (0..count)
+            .map(|i| start.wrapping_add(i) % count)
+            .find(|&idx| self.healthy[idx].load(Ordering::Relaxed))
+            .unwrap_or(start % count)
     }
 
-    fn pick_server(&self) -> SharedServerStatistic<S> {
-        let idx = self.best_idx.load(Ordering::Relaxed);
-        self.servers[idx].clone()
+    /// Total weight of healthy servers
+    fn healthy_weight(&self, weights: &[u32]) -> u64 {
+        weights
+            .iter()
+            .zip(&self.healthy)
+            .filter(|(_, h)| h.load(Ordering::Relaxed))
+            .map(|(w, _)| u64::from(*w))
+            .sum()
+    }
+
+    /// Pick a healthy server randomly in proportion to weights
+    ///
+    /// `None` if servers are not weighted, or none of the healthy servers has a positive weight
+    fn pick_weighted(&self) -> Option<usize> {
+        let weights = self.weights.as_ref()?;
+
+        let total = self.healthy_weight(weights);
+        if total == 0 {
+            return None;
+        }
+
+        let mut point = rand::thread_rng().gen_range(0, total);
+        for (idx, (w, h)) in weights.iter().zip(&self.healthy).enumerate() {
+            if !h.load(Ordering::Relaxed) {
+                continue;
+            }
+
+            let w = u64::from(*w);
+            if point < w {
+                return Some(idx);
+            }
+            point -= w;
+        }
+
+        // Health changed while picking
+        None
     }
 
     async fn recalculate_best_server(&self) -> Option<(usize, usize)> {
@@ -309,15 +755,46 @@ impl<S: ServerData> BestServer<S> {
 
         let mut best_idx = 0;
         let mut best_score = u64::max_value();
+        let mut best_healthy = false;
+        let mut runner_up_idx = 0;
+        let mut runner_up_score = u64::max_value();
+        let mut runner_up_healthy = false;
+
+        // Healthy servers are always preferred, no matter how good the scores of unhealthy ones are
+        let is_better = |score: u64, healthy: bool, than_score: u64, than_healthy: bool| {
+            if healthy != than_healthy {
+                healthy
+            } else {
+                score < than_score
+            }
+        };
 
         for (idx, svr) in self.servers.iter().enumerate() {
-            let score = svr.score().await;
-            if score < best_score {
+            let (score, healthy) = svr.data.score_and_health().await;
+            self.healthy[idx].store(healthy, Ordering::Relaxed);
+
+            if is_better(score, healthy, best_score, best_healthy) {
+                runner_up_idx = best_idx;
+                runner_up_score = best_score;
+                runner_up_healthy = best_healthy;
+
                 best_idx = idx;
                 best_score = score;
+                best_healthy = healthy;
+            } else if is_better(score, healthy, runner_up_score, runner_up_healthy) {
+                runner_up_idx = idx;
+                runner_up_score = score;
+                runner_up_healthy = healthy;
             }
         }
 
+        self.runner_up_idx.store(runner_up_idx, Ordering::Relaxed);
+
+        // Only servers' health matters for the other strategies, `Sticky` uses the best server for unknown destinations
+        if self.config.strategy != BalancerStrategy::Latency && self.config.strategy != BalancerStrategy::Sticky {
+            return None;
+        }
+
         if best_idx != current_best_idx {
             self.best_idx.store(best_idx, Ordering::Relaxed);
 
@@ -327,22 +804,142 @@ impl<S: ServerData> BestServer<S> {
         }
     }
 
+    fn full_error(&self) -> io::Error {
+        let msg = format!("all servers of group \"{}\" reached max_connections", self.name);
+        io::Error::new(io::ErrorKind::Other, msg)
+    }
+
     fn best_server_idx(&self) -> usize {
         self.best_idx.load(Ordering::Relaxed)
     }
+
+    async fn probe_status(&self) -> Vec<ServerProbeStatus> {
+        let best_idx = self.best_server_idx();
+        // Weighted picking is effective only if there are healthy servers with positive weights
+        let weighted = match self.weights {
+            Some(ref weights) => self.healthy_weight(weights) > 0,
+            None => false,
+        };
+        let any_healthy = self.healthy.iter().any(|h| h.load(Ordering::Relaxed));
+
+        let mut status = Vec::with_capacity(self.servers.len());
+        for (idx, svr) in self.servers.iter().enumerate() {
+            let data = svr.data.0.lock().await;
+            let weight = self.weights.as_ref().map(|w| w[idx]);
+            let selected = match self.config.strategy {
+                BalancerStrategy::Latency if weighted => data.healthy() && weight.unwrap_or(0) > 0,
+                BalancerStrategy::Latency => idx == best_idx,
+                BalancerStrategy::RoundRobin | BalancerStrategy::Sticky => data.healthy() || !any_healthy,
+                BalancerStrategy::Fixed => idx == 0,
+            };
+            status.push(ServerProbeStatus {
+                server: svr.server_config().addr().to_string(),
+                group: self.name.clone(),
+                score: data.score(),
+                rtt: data.rtt,
+                fail_rate: data.fail_rate,
+                traffic_fail_rate: data.traffic_fail_rate,
+                goodput: data.goodput.map(|g| g as u64),
+                healthy: data.healthy(),
+                consecutive_failures: data.consecutive_failures,
+                weight,
+                selected,
+            });
+        }
+        status
+    }
 }
 
+/// Servers balanced by `PingBalancer`, replaced as a whole when servers are updated
+struct BalancerServers<S: ServerData> {
+    /// Groups of servers, the default group is the first one
+    groups: Vec<SharedBestServer<S>>,
+    /// Set after being replaced, probing tasks of these servers exit
+    retired: Arc<AtomicBool>,
+}
+
+type SharedBalancerServers<S> = Arc<BalancerServers<S>>;
+
 /// Load balancer based on pinging latencies of all servers
+///
+/// Servers are balanced in groups, each group has its own strategy
 #[derive(Clone)]
 pub struct PingBalancer<S: ServerData> {
-    best: SharedBestServer<S>,
+    context: SharedContext,
+    servers: Arc<SpinMutex<SharedBalancerServers<S>>>,
 }
 
 impl<S: ServerData + 'static> PingBalancer<S> {
     /// Create a PingBalancer
+    ///
+    /// Servers are replaced by the ones updated with `Context::update_servers`
     pub async fn new(context: SharedContext, server_type: ServerType) -> PingBalancer<S> {
-        let server_count = context.config().server.len();
+        let mut servers_rx = context.subscribe_servers();
+
+        // Servers may have been updated before this balancer is created
+        let updated_servers = servers_rx.borrow().clone();
+        let servers = match updated_servers {
+            Some(servers) => PingBalancer::<S>::create_servers_with(&context, &servers, server_type).await,
+            None => PingBalancer::<S>::create_servers(&context, context.clone(), server_type).await,
+        };
+
+        let balancer = PingBalancer {
+            context,
+            servers: Arc::new(SpinMutex::new(servers)),
+        };
+
+        {
+            let balancer = balancer.clone();
+            tokio::spawn(async move {
+                while servers_rx.changed().await.is_ok() {
+                    let updated_servers = servers_rx.borrow().clone();
+                    if let Some(servers) = updated_servers {
+                        balancer.replace_servers(&servers, server_type).await;
+                    }
+                }
+            });
+        }
+
+        balancer
+    }
+
+    /// Start balancing `servers` instead of the current ones
+    async fn replace_servers(&self, servers: &[ServerConfig], server_type: ServerType) {
+        let new_servers = PingBalancer::<S>::create_servers_with(&self.context, servers, server_type).await;
+        let old_servers = mem::replace(&mut *self.servers.lock(), new_servers);
+        old_servers.retired.store(true, Ordering::Relaxed);
+
+        info!(
+            "{} load balancer replaced servers with {} servers",
+            server_type,
+            servers.len()
+        );
+    }
+
+    /// Create servers with a copy of `context`, which has `servers` instead of `config.server`
+    async fn create_servers_with(
+        context: &SharedContext,
+        servers: &[ServerConfig],
+        server_type: ServerType,
+    ) -> SharedBalancerServers<S> {
+        let mut config = context.config().clone();
+        config.server = servers.to_vec();
+        let svr_context = Context::new_with_state_shared(config, context.server_state().clone());
+
+        PingBalancer::<S>::create_servers(context, svr_context, server_type).await
+    }
+
+    /// Create servers in `svr_context.config().server`, and start probing them
+    ///
+    /// Probing results are reported to `context`
+    async fn create_servers(
+        context: &SharedContext,
+        svr_context: SharedContext,
+        server_type: ServerType,
+    ) -> SharedBalancerServers<S> {
+        let server_count = svr_context.config().server.len();
         let mut servers = Vec::with_capacity(server_count);
+        let retired = Arc::new(AtomicBool::new(false));
 
         // Check only required if servers count > 1, otherwise, always use the first one
         let check_required = server_count > 1;
@@ -350,11 +947,12 @@ impl<S: ServerData + 'static> PingBalancer<S> {
         let check_barrier = Arc::new(Barrier::new(1 + server_count));
 
         for idx in 0..server_count {
-            let stat = ServerStatistic::<S>::new_shared(context.clone(), idx);
+            let stat = ServerStatistic::<S>::new_shared(svr_context.clone(), idx);
 
             if check_required {
                 let stat = stat.clone();
                 let context = context.clone();
+                let retired = retired.clone();
                 let check_barrier = check_barrier.clone();
 
                 // Start a background task for probing
@@ -370,9 +968,9 @@ impl<S: ServerData + 'static> PingBalancer<S> {
 
                     check_barrier.wait().await;
 
-                    while context.server_running() {
+                    while context.server_running() && !retired.load(Ordering::Relaxed) {
                         PingBalancer::<S>::check_update_score(&stat, server_type).await;
-                        time::sleep(Duration::from_secs(DEFAULT_CHECK_INTERVAL_SEC)).await;
+                        time::sleep(stat.data.check_interval().await).await;
                     }
 
                     debug!(
@@ -386,7 +984,28 @@ impl<S: ServerData + 'static> PingBalancer<S> {
             servers.push(stat);
         }
 
-        let best = BestServer::new_shared(servers);
+        // Group servers in the order of their first appearance
+        let mut grouped: Vec<(String, Vec<SharedServerStatistic<S>>)> = Vec::new();
+        for stat in servers {
+            let name = stat.server_config().group().to_owned();
+            match grouped.iter_mut().find(|(n, _)| *n == name) {
+                Some((_, group)) => group.push(stat),
+                None => grouped.push((name, vec![stat])),
+            }
+        }
+        // Servers without `group` are the default, or the first group if all servers have groups
+        if let Some(pos) = grouped.iter().position(|(n, _)| n == DEFAULT_SERVER_GROUP) {
+            let default_group = grouped.remove(pos);
+            grouped.insert(0, default_group);
+        }
+
+        let groups: Vec<SharedBestServer<S>> = grouped
+            .into_iter()
+            .map(|(name, servers)| {
+                let config = context.config().server_groups.get(&name).cloned().unwrap_or_default();
+                BestServer::new_shared(name, config, servers)
+            })
+            .collect();
 
         if check_required {
             // Wait all tasks start (run at least one round)
@@ -395,32 +1014,45 @@ impl<S: ServerData + 'static> PingBalancer<S> {
 
             // Reinitialize a Barrier for waiting choosing task
             let check_barrier = Arc::new(Barrier::new(2));
-            let best = best.clone();
+            let groups = groups.clone();
 
             {
                 let context = context.clone();
+                let retired = retired.clone();
                 let check_barrier = check_barrier.clone();
 
                 tokio::spawn(async move {
                     // Check once for initializing data
-                    best.recalculate_best_server().await;
-
-                    trace!(
-                        "started best server choosing task, chosen server index {}",
-                        best.best_server_idx()
-                    );
+                    let mut status = Vec::new();
+                    for best in groups.iter() {
+                        best.recalculate_best_server().await;
+                        status.extend(best.probe_status().await);
+
+                        trace!(
+                            "started best server choosing task, chosen server index {} in group {}",
+                            best.best_server_idx(),
+                            best.name
+                        );
+                    }
+                    context.set_balancer_status(server_type, status);
 
                     check_barrier.wait().await;
 
-                    while context.server_running() {
-                        if let Some((old_idx, new_idx)) = best.recalculate_best_server().await {
-                            info!(
-                                "switched {} server from {} to {}",
-                                server_type,
-                                context.server_config(old_idx).addr(),
-                                context.server_config(new_idx).addr()
-                            );
+                    while context.server_running() && !retired.load(Ordering::Relaxed) {
+                        let mut status = Vec::new();
+                        for best in groups.iter() {
+                            if let Some((old_idx, new_idx)) = best.recalculate_best_server().await {
+                                info!(
+                                    "switched {} server of group {} from {} to {}",
+                                    server_type,
+                                    best.name,
+                                    best.servers[old_idx].server_config().addr(),
+                                    best.servers[new_idx].server_config().addr()
+                                );
+                            }
+                            status.extend(best.probe_status().await);
                         }
+                        context.set_balancer_status(server_type, status);
 
                         time::sleep(Duration::from_secs(DEFAULT_CHECK_INTERVAL_SEC)).await;
                     }
@@ -431,15 +1063,32 @@ impl<S: ServerData + 'static> PingBalancer<S> {
             check_barrier.wait().await;
         }
 
-        PingBalancer { best }
+        Arc::new(BalancerServers { groups, retired })
     }
 
     async fn check_update_score(stat: &ServerStatistic<S>, server_type: ServerType) {
-        let score = match PingBalancer::<S>::check_delay(stat, server_type).await {
-            Ok(d) => stat.push_score(Score::Latency(d)).await,
-            Err(..) => stat.push_score(Score::Errored).await, // Penalty
+        let (score, succeeded) = match PingBalancer::<S>::check_delay(stat, server_type).await {
+            Ok(d) => (stat.push_score(Score::Latency(d)).await, true),
+            Err(..) => (stat.push_score(Score::Errored).await, false), // Penalty
         };
 
+        let failure_threshold = stat.config().balancer_failure_threshold;
+        let recovery_threshold = stat.config().balancer_recovery_threshold;
+        match stat
+            .data
+            .update_health(succeeded, failure_threshold, recovery_threshold)
+            .await
+        {
+            Some(true) => info!("remote {} server {} is up", server_type, stat.server_config().addr()),
+            Some(false) => warn!(
+                "remote {} server {} is down after {} consecutive failed probes",
+                server_type,
+                stat.server_config().addr(),
+                failure_threshold
+            ),
+            None => {}
+        }
+
         debug!(
             "updated remote {} server {} (score: {})",
             server_type,
@@ -560,6 +1209,9 @@ impl<S: ServerData + 'static> PingBalancer<S> {
 
     async fn check_request(stat: &ServerStatistic<S>, server_type: ServerType) -> io::Result<()> {
         match server_type {
+            ServerType::Tcp if stat.config().balancer_probe == BalancerProbe::Connect => {
+                probe_proxy_server(stat.context(), stat.server_config()).await
+            }
             ServerType::Tcp => PingBalancer::<S>::check_request_tcp_firefox(stat).await,
             ServerType::Udp => PingBalancer::<S>::check_request_udp(stat).await,
         }
@@ -598,15 +1250,15 @@ impl<S: ServerData + 'static> PingBalancer<S> {
             }
             Err(..) => {
                 // Timeout
-                trace!(
+                debug!(
                     "checked remote {} server {} latency timeout, elapsed {} ms",
                     server_type,
                     stat.server_config().addr(),
                     elapsed
                 );
 
-                // NOTE: timeout is still available, but server is too slow
-                Ok(elapsed)
+                // NOTE: servers which drop packets silently are timed out, they are as bad as refusing connections
+                Err(io::Error::new(io::ErrorKind::TimedOut, "probe timed out"))
             }
         }
     }
@@ -617,7 +1269,56 @@ impl<S: ServerData> PingBalancer<S> {
     ///
     /// Return a `Arc` shared server statistic reference
     pub fn pick_server(&self) -> SharedServerStatistic<S> {
-        self.best.pick_server()
+        let servers = self.servers.lock().clone();
+        servers.groups[0].pick_server(None)
+    }
+
+    /// Pick a server for proxying `addr`, from the group chosen by ACL's `[proxy_group:NAME]` rules
+    ///
+    /// Servers in the default group are picked if `addr` doesn't match any groups
+    pub fn pick_server_for(&self, addr: &Address) -> SharedServerStatistic<S> {
+        self.group_for(addr).pick_server(Some(addr))
+    }
+
+    /// Pick a server for a TCP connection to `addr` like `pick_server_for`, holding a slot of its `max_connections`
+    ///
+    /// Fails if all servers of the group reached their `max_connections`
+    pub fn acquire_server_for(&self, addr: &Address) -> io::Result<ServerSlot<S>> {
+        let group = self.group_for(addr);
+        match group.acquire_server(addr) {
+            Some((_, slot)) => Ok(slot),
+            None => Err(group.full_error()),
+        }
+    }
+
+    /// Pick servers for a TCP connection to `addr` like `acquire_server_for`, with another server for racing
+    ///
+    /// The other one is the best, or the second best if the picked one is the best, of the same group.
+    /// It is `None` if `balancer_race` is disabled, or there is no other healthy server that is not full.
+    pub fn acquire_servers_raced_for(&self, addr: &Address) -> io::Result<(ServerSlot<S>, Option<ServerSlot<S>>)> {
+        if !self.context.config().balancer_race {
+            return self.acquire_server_for(addr).map(|slot| (slot, None));
+        }
+
+        let group = self.group_for(addr);
+        match group.acquire_servers_raced(addr) {
+            Some(slots) => Ok(slots),
+            None => Err(group.full_error()),
+        }
+    }
+
+    /// Group chosen by ACL's `[proxy_group:NAME]` rules for `addr`, or the default group
+    fn group_for(&self, addr: &Address) -> SharedBestServer<S> {
+        let servers = self.servers.lock().clone();
+
+        let acl = self.context.acl();
+        if let Some(name) = acl.as_ref().and_then(|acl| acl.check_target_group(addr)) {
+            if let Some(best) = servers.groups.iter().find(|g| g.name == name) {
+                return best.clone();
+            }
+        }
+
+        servers.groups[0].clone()
     }
 }
 
@@ -8,10 +8,19 @@ use log::{debug, error, trace, warn};
 #[cfg(feature = "local-flow-stat")]
 use crate::context::SharedContext;
 use crate::{
-    config::{Config, ConfigType},
+    config::{Config, ConfigType, ServerAddr},
     context::{Context, ServerState},
     plugin::{PluginMode, Plugins},
-    relay::{tcprelay::local::run as run_tcp, udprelay::local::run as run_udp, utils::set_nofile},
+    relay::{
+        acl_reload::acl_reload_task,
+        loadbalancing::{control::run as run_balancer_control, query::run as run_balancer_query},
+        memory::memory_monitor_task,
+        selfcheck::{run_self_check, Listeners},
+        server_dns::server_dns_prefetch_task,
+        tcprelay::local::run as run_tcp,
+        udprelay::local::run as run_udp,
+        utils::set_nofile,
+    },
 };
 
 /// Relay server running under local environment.
@@ -45,6 +54,8 @@ pub async fn run(mut config: Config) -> io::Result<()> {
 
     let config_type = config.config_type;
     let mode = config.mode;
+    #[cfg(feature = "local-tunnel")]
+    let tunnel_forwards = config.tunnel_forwards();
 
     // Create a context containing a DNS resolver and server running state flag.
     let state = ServerState::new_shared(&config).await;
@@ -63,9 +74,9 @@ pub async fn run(mut config: Config) -> io::Result<()> {
         #[cfg(feature = "local-socks4")]
         ConfigType::Socks4Local => true,
 
-        // Tunnel mode controlled by this flag
+        // Tunnel mode controlled by modes of forwardings
         #[cfg(feature = "local-tunnel")]
-        ConfigType::TunnelLocal => mode.enable_tcp(),
+        ConfigType::TunnelLocal => tunnel_forwards.iter().any(|(_, _, m)| m.enable_tcp()),
 
         // HTTP must be TCP
         #[cfg(feature = "local-http")]
@@ -78,10 +89,18 @@ pub async fn run(mut config: Config) -> io::Result<()> {
         ))]
         ConfigType::HttpsLocal => true,
 
+        // SOCKS5 and HTTP on the same port, always true like Socks5
+        #[cfg(feature = "local-http")]
+        ConfigType::MixedLocal => true,
+
         // Redir mode controlled by this flag
         #[cfg(feature = "local-redir")]
         ConfigType::RedirLocal => mode.enable_tcp(),
 
+        // TUN device relays UDP too, controlled by mode in itself
+        #[cfg(feature = "local-tun")]
+        ConfigType::TunLocal => true,
+
         _ => false,
     };
 
@@ -106,15 +125,67 @@ pub async fn run(mut config: Config) -> io::Result<()> {
         Context::new_with_state_shared(config, state)
     };
 
+    if context.config().memory_pressure_threshold.is_some() {
+        tokio::spawn(memory_monitor_task(context.clone()));
+    }
+
+    if context.acl().is_some() {
+        tokio::spawn(acl_reload_task(context.server_state().clone()));
+    }
+
+    // Servers may be replaced by `online_config` later
+    let has_domain_server = context
+        .config()
+        .server
+        .iter()
+        .any(|svr_cfg| matches!(svr_cfg.addr(), ServerAddr::DomainName(..)));
+    if has_domain_server || context.config().online_config.is_some() {
+        tokio::spawn(server_dns_prefetch_task(context.clone()));
+    }
+
     let enable_udp = match config_type {
         ConfigType::Socks5Local => mode.enable_udp(),
+        #[cfg(feature = "local-http")]
+        ConfigType::MixedLocal => mode.enable_udp(),
         #[cfg(feature = "local-tunnel")]
-        ConfigType::TunnelLocal => mode.enable_udp(),
+        ConfigType::TunnelLocal => tunnel_forwards.iter().any(|(_, _, m)| m.enable_udp()),
         #[cfg(feature = "local-redir")]
         ConfigType::RedirLocal => mode.enable_udp(),
         _ => false,
     };
 
+    // Relay servers are not polled yet, so listen addresses are still available
+    let mut listeners = Listeners::default();
+    match config_type {
+        // Each forwarding listens on its own address
+        #[cfg(feature = "local-tunnel")]
+        ConfigType::TunnelLocal => {
+            for (local_addr, _, m) in &tunnel_forwards {
+                if m.enable_tcp() {
+                    listeners.tcp.push(local_addr);
+                }
+                if m.enable_udp() {
+                    listeners.udp.push(local_addr);
+                }
+            }
+        }
+        _ => {
+            if let Some(ref local_addr) = context.config().local_addr {
+                if enable_tcp {
+                    listeners.tcp.push(local_addr);
+                }
+                if enable_udp {
+                    listeners
+                        .udp
+                        .push(context.config().udp_bind_addr.as_ref().unwrap_or(local_addr));
+                }
+            }
+        }
+    }
+    let report = run_self_check(&context, listeners).await;
+    report.log();
+    context.set_self_check_report(report);
+
     if enable_udp {
         // Run UDP relay before starting plugins
         // Because plugins doesn't support UDP relay
@@ -131,6 +202,42 @@ pub async fn run(mut config: Config) -> io::Result<()> {
         vf.push(dns_relay.boxed());
     }
 
+    if context.config().balancer_query_addr.is_some() {
+        let query_fut = run_balancer_query(context.clone());
+        vf.push(query_fut.boxed());
+    }
+
+    if context.config().balancer_control_addr.is_some() {
+        let control_fut = run_balancer_control(context.clone());
+        vf.push(control_fut.boxed());
+    }
+
+    #[cfg(feature = "local-http")]
+    if context.config().pac_addr.is_some() {
+        use crate::relay::pac::run as run_pac;
+
+        let pac_fut = run_pac(context.clone());
+        vf.push(pac_fut.boxed());
+    }
+
+    #[cfg(feature = "local-online-config")]
+    if context.config().online_config.is_some() {
+        use crate::relay::online_config::run as run_online_config;
+
+        // Replaces servers of balancers periodically
+        let online_config_fut = run_online_config(context.clone());
+        vf.push(online_config_fut.boxed());
+    }
+
+    #[cfg(feature = "local-online-config")]
+    if context.config().online_acl.is_some() {
+        use crate::relay::online_acl::run as run_online_acl;
+
+        // Replaces ACL rules periodically
+        let online_acl_fut = run_online_acl(context.clone());
+        vf.push(online_acl_fut.boxed());
+    }
+
     #[cfg(feature = "local-flow-stat")]
     if context.config().stat_path.is_some() {
         // For Android's flow statistic
@@ -0,0 +1,121 @@
+//! Authentication of clients of local proxies
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+use log::debug;
+
+use crate::{config::LocalAuthConfig, relay::utils::constant_time_eq};
+
+/// Users accepted by a local proxy
+pub struct LocalAuthenticator {
+    users: HashMap<String, String>,
+}
+
+impl LocalAuthenticator {
+    /// Load users of `config`, users in the configuration take precedence over the same names in `users_file`
+    ///
+    /// Fails if `users_file` couldn't be read, the proxy shouldn't be opened to everyone by accident
+    pub fn load(config: &LocalAuthConfig) -> io::Result<LocalAuthenticator> {
+        let mut users = HashMap::new();
+        if let Some(ref path) = config.users_file {
+            users = read_users_file(path)?;
+            debug!("loaded {} users from {}", users.len(), path.display());
+        }
+        for user in &config.users {
+            users.insert(user.username.clone(), user.password.clone());
+        }
+
+        if users.is_empty() {
+            let err = io::Error::new(ErrorKind::InvalidInput, "no users are configured for authentication");
+            return Err(err);
+        }
+
+        Ok(LocalAuthenticator { users })
+    }
+
+    /// Check credentials of a client
+    pub fn check(&self, username: &[u8], password: &[u8]) -> bool {
+        let expected = match std::str::from_utf8(username).ok().and_then(|u| self.users.get(u)) {
+            Some(p) => p,
+            None => return false,
+        };
+        constant_time_eq(expected.as_bytes(), password)
+    }
+
+    /// Password of `username`, for authentication schemes that don't send passwords
+    pub fn password(&self, username: &str) -> Option<&str> {
+        self.users.get(username).map(String::as_str)
+    }
+}
+
+/// Read a users file, lines of `USERNAME:PASSWORD` with `#` comments
+fn read_users_file(path: &Path) -> io::Result<HashMap<String, String>> {
+    let content = fs::read_to_string(path)?;
+
+    let mut users = HashMap::new();
+    for (idx, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut sp = line.splitn(2, ':');
+        match (sp.next(), sp.next()) {
+            (Some(username), Some(password)) if !username.is_empty() => {
+                users.insert(username.to_owned(), password.to_owned());
+            }
+            _ => {
+                let err = io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("malformed user in line {} of {}", idx + 1, path.display()),
+                );
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(users)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::config::LocalUser;
+
+    fn authenticator() -> LocalAuthenticator {
+        let config = LocalAuthConfig {
+            users: vec![LocalUser {
+                username: "user".to_owned(),
+                password: "password".to_owned(),
+            }],
+            users_file: None,
+        };
+        LocalAuthenticator::load(&config).unwrap()
+    }
+
+    #[test]
+    fn check_credentials() {
+        let auth = authenticator();
+        assert!(auth.check(b"user", b"password"));
+        assert!(!auth.check(b"user", b"wrong-password"));
+        assert!(!auth.check(b"user", b""));
+        assert!(!auth.check(b"nobody", b"password"));
+        assert!(!auth.check(b"", b"password"));
+        assert!(!auth.check(b"\xff", b"password"));
+    }
+
+    #[test]
+    fn load_without_users() {
+        let config = LocalAuthConfig {
+            users: Vec::new(),
+            users_file: None,
+        };
+        assert!(LocalAuthenticator::load(&config).is_err());
+    }
+}
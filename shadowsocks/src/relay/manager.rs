@@ -3,27 +3,70 @@
 //! Service for managing multiple relay servers
 
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fmt,
     io::{self, Error, ErrorKind},
     net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
     str,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use byte_string::ByteStr;
-use futures::future::{self, AbortHandle};
+use futures::future::{self, AbortHandle, BoxFuture, FutureExt};
 use log::{debug, error, info, trace, warn};
+use spin::Mutex as SpinMutex;
 #[cfg(unix)]
 use tokio::net::{unix::SocketAddr as UnixSocketAddr, UnixDatagram};
-use tokio::{self, net::UdpSocket};
+use tokio::{
+    self,
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt},
+    net::{TcpListener, UdpSocket},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time,
+};
 
+#[cfg(feature = "manager-http")]
+use crate::relay::manager_http::{self, ManagerCommand};
+#[cfg(feature = "manager-tls")]
+use crate::relay::manager_tls;
+#[cfg(windows)]
+use crate::relay::sys::{send_to_named_pipe, NamedPipeListener, NamedPipePeer};
 use crate::{
-    config::{Config, ConfigType, ManagerAddr, Mode, ServerAddr, ServerConfig},
+    acl::AccessControl,
+    config::{
+        is_weak_method,
+        Config,
+        ConfigType,
+        ManagerAddr,
+        Mode,
+        ReplayFilterConfig,
+        SecurityLevel,
+        ServerAddr,
+        ServerConfig,
+        SpeedLimit,
+        SpeedLimitScope,
+    },
     context::{Context, ServerState, SharedContext, SharedServerState},
     crypto::v1::CipherKind,
     plugin::PluginConfig,
     relay::{
-        flow::{MultiServerFlowStatistic, SharedServerFlowStatistic},
+        acl_reload::acl_reload_task,
+        flow::{
+            CloseStatistic,
+            ConnectionSnapshot,
+            LatencySnapshot,
+            MultiServerFlowStatistic,
+            SharedServerFlowStatistic,
+        },
+        flow_persist::{self, flow_stats_persist_task, PersistedFlow},
+        manager_auth::CommandVerifier,
+        manager_events::{ManagerEvent, ManagerEvents, DEFAULT_TRAFFIC_INTERVAL},
+        manager_federation::{self, Federation},
+        memory::memory_monitor_task,
+        stats_export,
         sys::create_udp_socket,
         udprelay::MAXIMUM_UDP_PAYLOAD_SIZE,
         utils::set_nofile,
@@ -33,6 +76,8 @@ use crate::{
 use super::server;
 
 mod protocol {
+    use std::collections::BTreeMap;
+
     use serde::{Deserialize, Serialize};
 
     #[derive(Serialize, Deserialize, Debug)]
@@ -47,13 +92,269 @@ mod protocol {
         pub plugin: Option<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         pub plugin_opts: Option<String>,
+        /// Timeout seconds of TCP relay, overrides the manager's default
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub timeout: Option<u64>,
+        /// Traffic quota in bytes, new connections are rejected after it is reached
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub quota: Option<u64>,
+        /// Speed limits in bytes per second, shared by all clients of this server
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub speed_limit: Option<SpeedLimit>,
         #[serde(skip_serializing_if = "Option::is_none")]
         pub mode: Option<String>,
+        /// Outbound ACL of this server, checked besides the global one
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub acl: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct SpeedLimit {
+        /// Traffic from clients
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub up: Option<u64>,
+        /// Traffic to clients
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub down: Option<u64>,
+        /// `server`, `client` or `connection`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub scope: Option<String>,
     }
 
     #[derive(Deserialize, Debug)]
     pub struct RemoveRequest {
         pub server_port: u16,
+        /// Stop accepting, and wait up to N seconds for established connections before aborting them
+        #[serde(default)]
+        pub drain: Option<u64>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct RestartRequest {
+        pub server_port: u16,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct ConfigRequest {
+        pub server_port: u16,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct TopDestinationsRequest {
+        pub server_port: u16,
+        pub limit: Option<usize>,
+    }
+
+    #[derive(Deserialize, Debug, Default)]
+    pub struct ListConnectionsRequest {
+        /// All servers if not specified
+        pub server_port: Option<u16>,
+        /// Connections listed for each server, the busiest ones first
+        pub limit: Option<usize>,
+    }
+
+    /// Effective configuration of a running server
+    #[derive(Serialize, Debug)]
+    pub struct ServerConfigExport {
+        pub server_port: u16,
+        pub bind_address: String,
+        pub password: String,
+        pub method: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub plugin: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub plugin_opts: Option<String>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        pub plugin_args: Vec<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub timeout: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub udp_timeout: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub udp_max_associations: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub quota: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub speed_limit: Option<SpeedLimit>,
+        pub mode: String,
+        pub no_delay: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub outbound_fwmark: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub local_address: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub acl: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub geoip_database: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub geosite_database: Option<String>,
+    }
+
+    /// Live metrics of a running server
+    #[derive(Serialize, Debug)]
+    pub struct ServerMetrics {
+        /// Established TCP connections
+        pub connections: usize,
+        /// Active UDP associations
+        pub udp_associations: usize,
+        /// Bytes sent to clients, TCP and UDP
+        pub tx: usize,
+        /// Bytes received from clients, TCP and UDP
+        pub rx: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub quota: Option<u64>,
+        /// New connections are rejected
+        pub quota_exceeded: bool,
+        /// Traffic of users, for multi-user servers
+        #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+        pub users: BTreeMap<String, UserMetrics>,
+    }
+
+    /// Traffic of a user of a multi-user server
+    #[derive(Serialize, Debug)]
+    pub struct UserMetrics {
+        /// Bytes sent to the user, TCP and UDP
+        pub tx: usize,
+        /// Bytes received from the user, TCP and UDP
+        pub rx: usize,
+    }
+
+    /// Running server in `list`
+    #[derive(Serialize, Debug)]
+    pub struct ServerStatus {
+        pub server_port: u16,
+        pub password: String,
+        pub method: String,
+        #[serde(flatten)]
+        pub metrics: ServerMetrics,
+    }
+
+    /// Progress of a removed server which is draining
+    #[derive(Serialize, Debug)]
+    pub struct DrainStatus {
+        /// Established connections left
+        pub connections: usize,
+        /// Seconds before the remaining connections are aborted
+        pub remaining: u64,
+    }
+}
+
+/// Connections listed for each server by `list-connections`, keeps the reply in a datagram
+const DEFAULT_LIST_CONNECTIONS_LIMIT: usize = 100;
+
+/// Destination hosts listed by `top-destinations`
+const DEFAULT_TOP_DESTINATIONS_LIMIT: usize = 10;
+
+/// Pending requests from the HTTP REST API, TLS connections and the upstream manager
+const REQUEST_CHANNEL_SIZE: usize = 64;
+
+/// A packet received from a stream, the same as a packet from the manager socket
+pub(crate) struct ManagerPacket {
+    pub pkt: Vec<u8>,
+    pub reply: oneshot::Sender<Option<Vec<u8>>>,
+}
+
+/// Request forwarded to the manager, which is handled in `ManagerService::serve`
+pub(crate) enum ManagerRequest {
+    /// Command from the HTTP REST API, which is already authenticated
+    #[cfg(feature = "manager-http")]
+    Command(ManagerCommand),
+    /// Packet from a TLS connection or the upstream manager, handled the same as packets from the manager socket
+    Packet(ManagerPacket),
+}
+
+pub(crate) type ManagerRequestSender = mpsc::Sender<ManagerRequest>;
+
+/// Reply of a command
+enum CommandReply {
+    Ready(Option<Vec<u8>>),
+    /// Handled by other nodes, replied later without blocking other commands
+    Pending(BoxFuture<'static, Option<Vec<u8>>>),
+}
+
+/// Read a packet from a stream of lines, which is the signature line and the command line if `signed`
+///
+/// Returns `None` if the stream is closed
+pub(crate) async fn read_packet<R>(reader: &mut R, signed: bool) -> io::Result<Option<Vec<u8>>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut pkt = String::new();
+    let lines = if signed { 2 } else { 1 };
+    for _ in 0..lines {
+        // Commands are no longer than packets of the manager socket
+        let limit = MAXIMUM_UDP_PAYLOAD_SIZE as u64;
+        let n = (&mut *reader).take(limit).read_line(&mut pkt).await?;
+        if !pkt.ends_with('\n') {
+            if n as u64 == limit {
+                return Err(Error::new(ErrorKind::InvalidData, "command too long"));
+            }
+            return Ok(None);
+        }
+    }
+
+    let len = pkt.trim_end().len();
+    pkt.truncate(len);
+    Ok(Some(pkt.into_bytes()))
+}
+
+/// Split the node from parameters like `{"server_port":8388,"node":"NAME"}`, which the command is forwarded to
+///
+/// Returns the node and the parameters without it, `*` for all nodes
+fn split_target_node(param: &str) -> Option<(String, String)> {
+    if !param.contains("\"node\"") {
+        return None;
+    }
+
+    let mut map: serde_json::Map<String, serde_json::Value> = serde_json::from_str(param).ok()?;
+    match map.remove("node")? {
+        serde_json::Value::String(node) => Some((node, serde_json::Value::Object(map).to_string())),
+        _ => None,
+    }
+}
+
+/// Check interval of draining servers
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A removed server, waiting for its established connections to finish
+struct DrainingServer {
+    flow_stat: SharedServerFlowStatistic,
+    deadline: Instant,
+}
+
+type SharedDrainingServers = Arc<SpinMutex<HashMap<u16, DrainingServer>>>;
+
+/// Wait until all connections of a removed server are closed, or abort them after the deadline
+async fn drain_server(
+    server_port: u16,
+    flow_stat: SharedServerFlowStatistic,
+    deadline: Instant,
+    draining: SharedDrainingServers,
+) {
+    loop {
+        if flow_stat.connections() == 0 {
+            info!("server listening on port {} drained", server_port);
+            break;
+        }
+
+        if Instant::now() >= deadline {
+            let aborted = flow_stat.abort_connections();
+            warn!(
+                "server listening on port {} drain timed out, {} connections aborted",
+                server_port, aborted
+            );
+            break;
+        }
+
+        time::sleep(DRAIN_CHECK_INTERVAL).await;
+    }
+
+    // The port may have been removed again with a new instance
+    let mut draining = draining.lock();
+    if let Some(d) = draining.get(&server_port) {
+        if Arc::ptr_eq(&d.flow_stat, &flow_stat) {
+            draining.remove(&server_port);
+        }
     }
 }
 
@@ -61,6 +362,7 @@ struct ServerInstance {
     config: Config,
     flow_stat: SharedServerFlowStatistic,
     watcher: AbortHandle,
+    server_task: Option<JoinHandle<()>>,
 }
 
 impl Drop for ServerInstance {
@@ -70,12 +372,21 @@ impl Drop for ServerInstance {
 }
 
 impl ServerInstance {
-    async fn start_server(config: Config, server_state: SharedServerState) -> io::Result<ServerInstance> {
+    async fn start_server(
+        config: Config,
+        server_state: SharedServerState,
+        prev_flow_stat: Option<SharedServerFlowStatistic>,
+    ) -> io::Result<ServerInstance> {
         let server_port = config.server[0].addr().port();
 
-        let flow_stat = MultiServerFlowStatistic::new_shared(&config);
+        let mut flow_stat = MultiServerFlowStatistic::new(&config);
+        if let Some(prev_flow_stat) = prev_flow_stat {
+            // Keep statistic data of the previous instance
+            flow_stat.set(server_port, prev_flow_stat);
+        }
+        let flow_stat = Arc::new(flow_stat);
 
-        let watcher = {
+        let (watcher, server_task) = {
             // Run server in current process, sharing the same tokio runtime
             //
             // NOTE: This may make different users interfere with each other,
@@ -86,7 +397,7 @@ impl ServerInstance {
 
             let (server, watcher) = future::abortable(server::run_with(config, flow_stat, server_state));
 
-            tokio::spawn(async move {
+            let server_task = tokio::spawn(async move {
                 match server.await {
                     Ok(unexpected_exit) => {
                         error!(
@@ -100,7 +411,7 @@ impl ServerInstance {
                 }
             });
 
-            watcher
+            (watcher, server_task)
         };
 
         let flow_stat = flow_stat
@@ -114,21 +425,68 @@ impl ServerInstance {
             config,
             flow_stat,
             watcher,
+            server_task: Some(server_task),
         })
     }
 
+    /// Stop listening and wait until all the listeners are closed
+    ///
+    /// Established connections are not interrupted, they will be closed by themselves
+    async fn stop(mut self) {
+        let server_task = self.server_task.take();
+        self.watcher.abort();
+
+        if let Some(server_task) = server_task {
+            let _ = server_task.await;
+        }
+    }
+
     fn flow_trans_stat(&self) -> usize {
         self.flow_stat.trans_stat()
     }
+
+    fn flow_close_stat(&self) -> &CloseStatistic {
+        self.flow_stat.close()
+    }
+
+    fn metrics(&self) -> protocol::ServerMetrics {
+        let flow_stat = &self.flow_stat;
+        let quota = self.config.server[0].quota();
+        protocol::ServerMetrics {
+            connections: flow_stat.connections(),
+            udp_associations: flow_stat.udp_associations(),
+            tx: flow_stat.tcp().tx() + flow_stat.udp().tx(),
+            rx: flow_stat.tcp().rx() + flow_stat.udp().rx(),
+            quota,
+            quota_exceeded: flow_stat.quota_exceeded(quota),
+            users: flow_stat
+                .users()
+                .into_iter()
+                .map(|(name, stat)| {
+                    let metrics = protocol::UserMetrics {
+                        tx: stat.tcp().tx() + stat.udp().tx(),
+                        rx: stat.tcp().rx() + stat.udp().rx(),
+                    };
+                    (name, metrics)
+                })
+                .collect(),
+        }
+    }
 }
 
 /// Datagram socket for manager
 ///
-/// For *nix system, this is a wrapper for both UDP socket and Unix socket
+/// For *nix system, this is a wrapper for both UDP socket and Unix socket.
+/// For Windows, this is a wrapper for both UDP socket and named pipe, every message in the pipe is a datagram
 pub enum ManagerDatagram {
     UdpDatagram(UdpSocket),
     #[cfg(unix)]
     UnixDatagram(UnixDatagram),
+    #[cfg(windows)]
+    NamedPipe(NamedPipeListener),
+    /// For sending to a named pipe, every message is sent through a new connection
+    #[cfg(windows)]
+    NamedPipeClient,
 }
 
 impl ManagerDatagram {
@@ -150,6 +508,8 @@ impl ManagerDatagram {
 
                 Ok(ManagerDatagram::UnixDatagram(UnixDatagram::bind(path)?))
             }
+            #[cfg(windows)]
+            ManagerAddr::NamedPipe(ref path) => Ok(ManagerDatagram::NamedPipe(NamedPipeListener::bind(path)?)),
         }
     }
 
@@ -165,6 +525,8 @@ impl ManagerDatagram {
             // For unix socket, it doesn't need to bind to any valid address
             // Because manager won't response to you
             ManagerAddr::UnixSocketAddr(..) => Ok(ManagerDatagram::UnixDatagram(UnixDatagram::unbound()?)),
+            #[cfg(windows)]
+            ManagerAddr::NamedPipe(..) => Ok(ManagerDatagram::NamedPipeClient),
         }
     }
 
@@ -180,6 +542,16 @@ impl ManagerDatagram {
                 let (s, addr) = unix.recv_from(buf).await?;
                 Ok((s, ManagerSocketAddr::UnixSocketAddr(addr)))
             }
+            #[cfg(windows)]
+            ManagerDatagram::NamedPipe(ref mut pipe) => {
+                let (s, peer) = pipe.recv_from(buf).await?;
+                Ok((s, ManagerSocketAddr::NamedPipe(peer)))
+            }
+            #[cfg(windows)]
+            ManagerDatagram::NamedPipeClient => {
+                let err = Error::new(ErrorKind::InvalidInput, "named pipe client couldn't receive");
+                Err(err)
+            }
         }
     }
 
@@ -193,6 +565,11 @@ impl ManagerDatagram {
                     let err = Error::new(ErrorKind::InvalidInput, "udp datagram requires IP address target");
                     Err(err)
                 }
+                #[cfg(windows)]
+                ManagerSocketAddr::NamedPipe(..) => {
+                    let err = Error::new(ErrorKind::InvalidInput, "udp datagram requires IP address target");
+                    Err(err)
+                }
             },
             #[cfg(unix)]
             ManagerDatagram::UnixDatagram(ref mut unix) => match *target {
@@ -208,6 +585,20 @@ impl ManagerDatagram {
                     Err(err)
                 }
             },
+            #[cfg(windows)]
+            ManagerDatagram::NamedPipe(..) => match *target {
+                // Replied through the pipe instance that the peer is connected to
+                ManagerSocketAddr::NamedPipe(ref peer) => peer.send(buf),
+                ManagerSocketAddr::SocketAddr(..) => {
+                    let err = Error::new(ErrorKind::InvalidInput, "named pipe requires named pipe peer target");
+                    Err(err)
+                }
+            },
+            #[cfg(windows)]
+            ManagerDatagram::NamedPipeClient => {
+                let err = Error::new(ErrorKind::InvalidInput, "named pipe client could only send to manager");
+                Err(err)
+            }
         }
     }
 
@@ -225,6 +616,11 @@ impl ManagerDatagram {
                     let err = Error::new(ErrorKind::InvalidInput, "udp datagram requires IP address target");
                     Err(err)
                 }
+                #[cfg(windows)]
+                ManagerAddr::NamedPipe(..) => {
+                    let err = Error::new(ErrorKind::InvalidInput, "udp datagram requires IP address target");
+                    Err(err)
+                }
             },
             #[cfg(unix)]
             ManagerDatagram::UnixDatagram(ref mut unix) => match *target {
@@ -234,6 +630,19 @@ impl ManagerDatagram {
                     Err(err)
                 }
             },
+            #[cfg(windows)]
+            ManagerDatagram::NamedPipe(..) => {
+                let err = Error::new(ErrorKind::InvalidInput, "named pipe listener couldn't send to manager");
+                Err(err)
+            }
+            #[cfg(windows)]
+            ManagerDatagram::NamedPipeClient => match *target {
+                ManagerAddr::NamedPipe(ref path) => send_to_named_pipe(path, buf).await,
+                ManagerAddr::SocketAddr(..) | ManagerAddr::DomainName(..) => {
+                    let err = Error::new(ErrorKind::InvalidInput, "named pipe client requires named pipe target");
+                    Err(err)
+                }
+            },
         }
     }
 
@@ -243,6 +652,16 @@ impl ManagerDatagram {
             ManagerDatagram::UdpDatagram(ref socket) => socket.local_addr().map(ManagerSocketAddr::SocketAddr),
             #[cfg(unix)]
             ManagerDatagram::UnixDatagram(ref dgram) => dgram.local_addr().map(ManagerSocketAddr::UnixSocketAddr),
+            #[cfg(windows)]
+            ManagerDatagram::NamedPipe(ref pipe) => Ok(ManagerSocketAddr::NamedPipe(pipe.local_addr())),
+            #[cfg(windows)]
+            ManagerDatagram::NamedPipeClient => {
+                let err = Error::new(
+                    ErrorKind::InvalidInput,
+                    "named pipe client doesn't have a local address",
+                );
+                Err(err)
+            }
         }
     }
 }
@@ -253,15 +672,19 @@ pub enum ManagerSocketAddr {
     SocketAddr(SocketAddr),
     #[cfg(unix)]
     UnixSocketAddr(UnixSocketAddr),
+    #[cfg(windows)]
+    NamedPipe(NamedPipePeer),
 }
 
 impl ManagerSocketAddr {
-    /// Check if it is unnamed (not binded to any valid address), only valid for `UnixSocketAddr`
+    /// Check if it is unnamed (not binded to any valid address), only valid for `UnixSocketAddr` and `NamedPipe`
     pub fn is_unnamed(&self) -> bool {
         match *self {
             ManagerSocketAddr::SocketAddr(..) => false,
             #[cfg(unix)]
             ManagerSocketAddr::UnixSocketAddr(ref s) => s.is_unnamed(),
+            #[cfg(windows)]
+            ManagerSocketAddr::NamedPipe(ref p) => p.is_unnamed(),
         }
     }
 }
@@ -272,6 +695,8 @@ impl fmt::Display for ManagerSocketAddr {
             ManagerSocketAddr::SocketAddr(ref saddr) => fmt::Display::fmt(saddr, f),
             #[cfg(unix)]
             ManagerSocketAddr::UnixSocketAddr(ref saddr) => fmt::Debug::fmt(saddr, f),
+            #[cfg(windows)]
+            ManagerSocketAddr::NamedPipe(ref peer) => fmt::Display::fmt(peer, f),
         }
     }
 }
@@ -279,17 +704,47 @@ impl fmt::Display for ManagerSocketAddr {
 struct ManagerService {
     socket: ManagerDatagram,
     servers: HashMap<u16, ServerInstance>,
+    draining: SharedDrainingServers,
     context: SharedContext,
+    /// Verifier of signed commands, if `manager_secret` is set
+    verifier: Option<CommandVerifier>,
+    events: ManagerEvents,
+    /// Agents connected to this manager
+    federation: Federation,
+    /// Counters restored from `flow_stats_persist_path`, of servers which are not added yet
+    restored_flow: Arc<SpinMutex<PersistedFlow>>,
+    // Sender is kept, so receiving never ends even if nothing else sends requests
+    request_tx: ManagerRequestSender,
+    request_rx: mpsc::Receiver<ManagerRequest>,
+    // Replies of commands forwarded to other nodes, which are sent back to the manager socket
+    reply_tx: mpsc::Sender<(ManagerSocketAddr, Vec<u8>)>,
+    reply_rx: mpsc::Receiver<(ManagerSocketAddr, Vec<u8>)>,
 }
 
 impl ManagerService {
     async fn bind(bind_addr: &ManagerAddr, context: SharedContext) -> io::Result<ManagerService> {
         let socket = ManagerDatagram::bind(bind_addr, &context).await?;
 
+        let secret = context.config().manager.as_ref().and_then(|m| m.secret.as_deref());
+        let verifier = secret.map(CommandVerifier::new);
+        let federation = Federation::new(secret);
+
+        let (request_tx, request_rx) = mpsc::channel(REQUEST_CHANNEL_SIZE);
+        let (reply_tx, reply_rx) = mpsc::channel(REQUEST_CHANNEL_SIZE);
+
         Ok(ManagerService {
             socket,
             servers: HashMap::new(),
+            draining: Arc::new(SpinMutex::new(HashMap::new())),
             context,
+            verifier,
+            events: ManagerEvents::new(),
+            federation,
+            restored_flow: Arc::new(SpinMutex::new(PersistedFlow::new())),
+            request_tx,
+            request_rx,
+            reply_tx,
+            reply_rx,
         })
     }
 
@@ -297,47 +752,115 @@ impl ManagerService {
         self.socket.local_addr()
     }
 
+    /// Sender of requests from the HTTP REST API, TLS connections and the upstream manager, handled in `serve`
+    fn request_sender(&self) -> ManagerRequestSender {
+        self.request_tx.clone()
+    }
+
     async fn serve(&mut self) -> io::Result<()> {
         let mut buf = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
 
         loop {
-            let (recv_len, src_addr) = self.socket.recv_from(&mut buf).await?;
+            let (recv_len, src_addr) = tokio::select! {
+                r = self.socket.recv_from(&mut buf) => r?,
+                Some(req) = self.request_rx.recv() => {
+                    self.handle_request(req).await;
+                    continue;
+                }
+                Some((src_addr, resp_pkt)) = self.reply_rx.recv() => {
+                    self.send_response(&resp_pkt, &src_addr).await;
+                    continue;
+                }
+            };
             let pkt = &buf[..recv_len];
 
             let resp_pkt = match self.handle_packet(pkt).await {
-                Some(p) => p,
-                None => continue,
+                CommandReply::Ready(Some(p)) => p,
+                CommandReply::Ready(None) => continue,
+                CommandReply::Pending(fut) => {
+                    let reply_tx = self.reply_tx.clone();
+                    tokio::spawn(async move {
+                        if let Some(resp_pkt) = fut.await {
+                            let _ = reply_tx.send((src_addr, resp_pkt)).await;
+                        }
+                    });
+                    continue;
+                }
             };
 
-            if src_addr.is_unnamed() {
-                trace!(
-                    "received a packet ({} bytes) from an unnamed unix-socket client, \
-                     unsound because we are unable to send response back to it",
-                    recv_len
-                );
-                continue;
+            self.send_response(&resp_pkt, &src_addr).await;
+        }
+    }
+
+    async fn send_response(&self, resp_pkt: &[u8], src_addr: &ManagerSocketAddr) {
+        if src_addr.is_unnamed() {
+            trace!(
+                "response ({} bytes) to an unnamed unix-socket client, \
+                 unsound because we are unable to send response back to it",
+                resp_pkt.len()
+            );
+            return;
+        }
+
+        let n = match self.socket.send_to(resp_pkt, src_addr).await {
+            Ok(n) => n,
+            Err(err) => {
+                debug!("response send_to failed, destination: {:?}, error: {}", src_addr, err);
+                return;
             }
+        };
 
-            let n = match self.socket.send_to(&resp_pkt, &src_addr).await {
-                Ok(n) => n,
-                Err(err) => {
-                    debug!("response send_to failed, destination: {:?}, error: {}", src_addr, err);
-                    continue;
-                }
-            };
+        if n != resp_pkt.len() {
+            warn!(
+                "response packet truncated, packet: {}, sent: {}, destination: {:?}",
+                resp_pkt.len(),
+                n,
+                src_addr
+            );
+        }
+    }
 
-            if n != resp_pkt.len() {
-                warn!(
-                    "response packet truncated, packet: {}, sent: {}, destination: {:?}",
-                    resp_pkt.len(),
-                    n,
-                    src_addr
-                );
+    async fn handle_request(&mut self, req: ManagerRequest) {
+        match req {
+            #[cfg(feature = "manager-http")]
+            ManagerRequest::Command(cmd) => {
+                let action = cmd.action;
+                let reply = cmd.reply;
+                let fut = match self.federated_command(action, &cmd.param) {
+                    Some(fut) => fut,
+                    None => {
+                        let result = self.dispatch_command(action, &cmd.param).await;
+                        if let Err(ref err) = result {
+                            error!("failed to handle action \"{}\" from HTTP API, error: {}", action, err);
+                        }
+                        let _ = reply.send(result);
+                        return;
+                    }
+                };
+
+                tokio::spawn(async move {
+                    let result = fut.await;
+                    if let Err(ref err) = result {
+                        error!("failed to handle action \"{}\" from HTTP API, error: {}", action, err);
+                    }
+                    let _ = reply.send(result);
+                });
             }
+            ManagerRequest::Packet(p) => match self.handle_packet(&p.pkt).await {
+                CommandReply::Ready(resp) => {
+                    let _ = p.reply.send(resp);
+                }
+                CommandReply::Pending(fut) => {
+                    let reply = p.reply;
+                    tokio::spawn(async move {
+                        let _ = reply.send(fut.await);
+                    });
+                }
+            },
         }
     }
 
-    async fn handle_packet(&mut self, pkt: &[u8]) -> Option<Vec<u8>> {
+    async fn handle_packet(&mut self, pkt: &[u8]) -> CommandReply {
         trace!("REQUEST: {:?}", ByteStr::new(pkt));
 
         // Payload must be UTF-8 encoded, or JSON decode will fail
@@ -346,10 +869,22 @@ impl ManagerService {
             Err(..) => {
                 error!("received non-UTF8 encoded packet: {:?}", ByteStr::new(pkt));
 
-                return Some(b"invalid encoding".to_vec());
+                return CommandReply::Ready(Some(b"invalid encoding".to_vec()));
             }
         };
 
+        let pkt = match self.verifier {
+            Some(ref mut verifier) => match verifier.verify(pkt) {
+                Ok(command) => command,
+                Err(err) => {
+                    warn!("rejected manager command, {}", err);
+
+                    return CommandReply::Ready(Some(Vec::from(err.to_string())));
+                }
+            },
+            None => pkt,
+        };
+
         let (action, param) = match pkt.find(':') {
             None => (pkt.trim(), ""),
             Some(idx) => {
@@ -358,14 +893,62 @@ impl ManagerService {
             }
         };
 
-        match self.dispatch_command(action, param).await {
+        if let Some(fut) = self.federated_command(action, param) {
+            let action = action.to_owned();
+            let fut = async move {
+                match fut.await {
+                    Ok(v) => v,
+                    Err(err) => {
+                        error!("failed to handle action \"{}\", error: {}", action, err);
+
+                        Some(Vec::from(err.to_string()))
+                    }
+                }
+            };
+            return CommandReply::Pending(fut.boxed());
+        }
+
+        let reply = match self.dispatch_command(action, param).await {
             Ok(v) => v,
             Err(err) => {
                 error!("failed to handle action \"{}\", error: {}", action, err);
 
                 Some(Vec::from(err.to_string()))
             }
+        };
+        CommandReply::Ready(reply)
+    }
+
+    /// Commands for other nodes are forwarded to their agents, which may take a while
+    fn federated_command(&self, action: &str, param: &str) -> Option<BoxFuture<'static, io::Result<Option<Vec<u8>>>>> {
+        let federation = self.federation.clone();
+
+        if action == "nodes" {
+            let fut = async move {
+                let mut buf = federation.forward_all("stat", "").await.to_string();
+                buf += "\n";
+
+                trace!("ACTION \"nodes\" returns {:?}", ByteStr::new(buf.as_bytes()));
+
+                Ok(Some(buf.into_bytes()))
+            };
+            return Some(fut.boxed());
         }
+
+        let (node, param) = split_target_node(param)?;
+        let action = action.to_owned();
+        trace!("ACTION \"{}\" forwarded to node \"{}\"", action, node);
+
+        let fut = async move {
+            if node == "*" {
+                let mut buf = federation.forward_all(&action, &param).await.to_string();
+                buf += "\n";
+                return Ok(Some(buf.into_bytes()));
+            }
+
+            federation.forward(&node, &action, &param).await
+        };
+        Some(fut.boxed())
     }
 
     async fn dispatch_command(&mut self, action: &str, param: &str) -> io::Result<Option<Vec<u8>>> {
@@ -392,8 +975,60 @@ impl ManagerService {
 
                 self.handle_remove(&p).await
             }
+            "restart" => {
+                let p: protocol::RestartRequest = match serde_json::from_str(param) {
+                    Ok(p) => p,
+                    Err(err) => {
+                        let err = Error::new(ErrorKind::InvalidData, err);
+                        return Err(err);
+                    }
+                };
+
+                self.handle_restart(&p).await
+            }
+            "config" => {
+                let p: protocol::ConfigRequest = match serde_json::from_str(param) {
+                    Ok(p) => p,
+                    Err(err) => {
+                        let err = Error::new(ErrorKind::InvalidData, err);
+                        return Err(err);
+                    }
+                };
+
+                self.handle_config(&p).await
+            }
             "list" => self.handle_list().await,
             "ping" => self.handle_ping().await,
+            "closes" => self.handle_closes().await,
+            "latency" => self.handle_latency().await,
+            "drains" => self.handle_drains().await,
+            "list-connections" => {
+                let p: protocol::ListConnectionsRequest = if param.is_empty() {
+                    protocol::ListConnectionsRequest::default()
+                } else {
+                    match serde_json::from_str(param) {
+                        Ok(p) => p,
+                        Err(err) => {
+                            let err = Error::new(ErrorKind::InvalidData, err);
+                            return Err(err);
+                        }
+                    }
+                };
+
+                self.handle_list_connections(&p).await
+            }
+            "top-destinations" => {
+                let p: protocol::TopDestinationsRequest = match serde_json::from_str(param) {
+                    Ok(p) => p,
+                    Err(err) => {
+                        let err = Error::new(ErrorKind::InvalidData, err);
+                        return Err(err);
+                    }
+                };
+
+                self.handle_top_destinations(&p).await
+            }
+            "stat" if param.is_empty() => self.handle_metrics().await,
             "stat" => {
                 let pmap: HashMap<String, u64> = match serde_json::from_str(param) {
                     Ok(p) => p,
@@ -433,12 +1068,63 @@ impl ManagerService {
             },
         };
 
+        if self.context.config().security_level == SecurityLevel::Strict && is_weak_method(method) {
+            let err = Error::new(
+                ErrorKind::Other,
+                format!("method \"{}\" is forbidden by security_level", method),
+            );
+            return Err(err);
+        }
+
+        if p.plugin.is_none() && p.plugin_opts.is_some() {
+            let err = Error::new(ErrorKind::Other, "`plugin_opts` is specified without `plugin`");
+            return Err(err);
+        }
+
+        if p.timeout == Some(0) {
+            let err = Error::new(ErrorKind::Other, "invalid `timeout`, must be greater than 0");
+            return Err(err);
+        }
+
+        if p.quota == Some(0) {
+            let err = Error::new(ErrorKind::Other, "invalid `quota`, must be greater than 0");
+            return Err(err);
+        }
+
+        let speed_limit = match p.speed_limit {
+            None => None,
+            Some(ref limit) => {
+                if limit.up == Some(0) || limit.down == Some(0) {
+                    let err = Error::new(ErrorKind::Other, "invalid `speed_limit`, must be greater than 0");
+                    return Err(err);
+                }
+
+                let scope = match limit.scope.as_deref().map(str::parse::<SpeedLimitScope>) {
+                    None => SpeedLimitScope::default(),
+                    Some(Ok(s)) => s,
+                    Some(Err(..)) => {
+                        let err = Error::new(
+                            ErrorKind::Other,
+                            "invalid `speed_limit.scope`, must be server, client or connection",
+                        );
+                        return Err(err);
+                    }
+                };
+
+                Some(SpeedLimit {
+                    up: limit.up,
+                    down: limit.down,
+                    scope,
+                })
+            }
+        };
+
         let bind_addr = manager_config.bind_addr(&self.context, p.server_port).await?;
-        let svr_cfg = ServerConfig::new(
+        let mut svr_cfg = ServerConfig::new(
             ServerAddr::from(bind_addr),
             p.password,
             method,
-            manager_config.timeout,
+            p.timeout.map(Duration::from_secs).or(manager_config.timeout),
             match p.plugin {
                 Some(pp) => Some(PluginConfig {
                     plugin: pp,
@@ -449,6 +1135,24 @@ impl ManagerService {
             },
         );
 
+        if let Some(quota) = p.quota {
+            svr_cfg.set_quota(quota);
+        }
+
+        if let Some(limit) = speed_limit {
+            svr_cfg.set_speed_limit(limit);
+        }
+
+        if let Some(acl_file) = p.acl {
+            match AccessControl::load_from_file_with_databases(&acl_file, &self.context.config().acl_databases()) {
+                Ok(acl) => svr_cfg.set_acl(Arc::new(acl)),
+                Err(err) => {
+                    let err = Error::new(ErrorKind::Other, format!("loading ACL \"{}\", {}", acl_file, err));
+                    return Err(err);
+                }
+            }
+        }
+
         let mut config = Config::new(ConfigType::Server);
         config.server.push(svr_cfg);
 
@@ -480,23 +1184,63 @@ impl ManagerService {
             config.outbound_fwmark = self.context.config().outbound_fwmark;
         }
 
+        // IPv6 outbound source addresses
+        config.ipv6_prefer_temporary = self.context.config().ipv6_prefer_temporary;
+        config.ipv6_outbound_prefix = self.context.config().ipv6_outbound_prefix;
+        config.outbound_address_pool = self.context.config().outbound_address_pool.clone();
+
+        // Replay filter
+        config.replay_filter = replay_filter_for_port(&self.context.config().replay_filter, server_port);
+
+        // Heartbeat for idle connections
+        config.keep_busy_interval = self.context.config().keep_busy_interval;
+
+        // Delayed accept
+        config.tcp_defer_accept = self.context.config().tcp_defer_accept;
+
+        // Statistics
+        config.destination_stats_capacity = self.context.config().destination_stats_capacity;
+
+        // Debugging
+        config.sampling = self.context.config().sampling.clone();
+        config.access_log = self.context.config().access_log;
+
+        // Policy for weak methods
+        config.security_level = self.context.config().security_level;
+
         // UDP configurations
         config.udp_timeout = self.context.config().udp_timeout;
         config.udp_max_associations = self.context.config().udp_max_associations;
+        config.udp_egress_pool_size = self.context.config().udp_egress_pool_size;
 
         // ACL
         // FIXME: AccessControl structure may be quite expensive to copy
         config.acl = self.context.config().acl.clone();
+        config.geoip_database = self.context.config().geoip_database.clone();
+        config.geosite_database = self.context.config().geosite_database.clone();
 
-        // Close it first
-        let _ = self.servers.remove(&server_port);
+        // Close it first, waits until its replay filter is saved
+        if let Some(inst) = self.servers.remove(&server_port) {
+            inst.stop().await;
+        }
         self.start_server_with_config(server_port, config).await?;
 
         Ok(Some(b"ok\n".to_vec()))
     }
 
     async fn start_server_with_config(&mut self, server_port: u16, config: Config) -> io::Result<()> {
-        let server = ServerInstance::start_server(config, self.context.server_state().clone()).await?;
+        let server = ServerInstance::start_server(config, self.context.server_state().clone(), None).await?;
+
+        // Only the first instance of a port continues with restored counters, adding it again resets them
+        if let Some(counters) = self.restored_flow.lock().remove(&server_port) {
+            counters.restore(&server.flow_stat);
+            debug!("traffic counters of server listening on port {} restored", server_port);
+        }
+
+        let quota = server.config.server[0].quota();
+        self.events.watch(server_port, server.flow_stat.clone(), quota);
+        self.events.notify(ManagerEvent::Added { server_port });
+
         self.servers.insert(server_port, server);
 
         Ok(())
@@ -505,46 +1249,182 @@ impl ManagerService {
     async fn handle_remove(&mut self, p: &protocol::RemoveRequest) -> io::Result<Option<Vec<u8>>> {
         trace!("ACTION \"remove\" {:?}", p);
 
-        let _ = self.servers.remove(&p.server_port);
+        let inst = match self.servers.remove(&p.server_port) {
+            Some(inst) => inst,
+            None => return Ok(Some(b"ok\n".to_vec())),
+        };
+
+        self.events.unwatch(p.server_port);
+        self.events.notify(ManagerEvent::Removed {
+            server_port: p.server_port,
+        });
+
+        let manager_config = self.context.config().manager.as_ref().expect("manager config");
+        let drain_timeout = match p.drain {
+            Some(0) => None,
+            Some(t) => Some(Duration::from_secs(t)),
+            None => manager_config.drain_timeout,
+        };
+
+        if let Some(timeout) = drain_timeout {
+            let flow_stat = inst.flow_stat.clone();
+            let deadline = Instant::now() + timeout;
+
+            // Stop accepting, established connections are kept until they finish or the deadline
+            inst.stop().await;
+
+            info!(
+                "server listening on port {} removed, draining {} connections in {} seconds",
+                p.server_port,
+                flow_stat.connections(),
+                timeout.as_secs()
+            );
+
+            self.draining.lock().insert(
+                p.server_port,
+                DrainingServer {
+                    flow_stat: flow_stat.clone(),
+                    deadline,
+                },
+            );
+            tokio::spawn(drain_server(p.server_port, flow_stat, deadline, self.draining.clone()));
+        }
+
+        Ok(Some(b"ok\n".to_vec()))
+    }
+
+    async fn handle_restart(&mut self, p: &protocol::RestartRequest) -> io::Result<Option<Vec<u8>>> {
+        trace!("ACTION \"restart\" {:?}", p);
+
+        let inst = match self.servers.remove(&p.server_port) {
+            Some(inst) => inst,
+            None => {
+                let err = Error::new(ErrorKind::NotFound, format!("server port {} not found", p.server_port));
+                return Err(err);
+            }
+        };
+
+        let config = inst.config.clone();
+        let flow_stat = inst.flow_stat.clone();
+
+        // Listeners have to be closed before binding to the same port again
+        inst.stop().await;
+
+        let server = ServerInstance::start_server(config, self.context.server_state().clone(), Some(flow_stat)).await?;
+        self.servers.insert(p.server_port, server);
+
+        info!("server listening on port {} restarted", p.server_port);
+
         Ok(Some(b"ok\n".to_vec()))
     }
 
+    async fn handle_config(&mut self, p: &protocol::ConfigRequest) -> io::Result<Option<Vec<u8>>> {
+        trace!("ACTION \"config\" {:?}", p);
+
+        let inst = match self.servers.get(&p.server_port) {
+            Some(inst) => inst,
+            None => {
+                let err = Error::new(ErrorKind::NotFound, format!("server port {} not found", p.server_port));
+                return Err(err);
+            }
+        };
+
+        let config = &inst.config;
+        let svr_cfg = &config.server[0];
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let outbound_fwmark = config.outbound_fwmark;
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let outbound_fwmark = None;
+
+        let export = protocol::ServerConfigExport {
+            server_port: p.server_port,
+            bind_address: svr_cfg.addr().to_string(),
+            password: svr_cfg.password().to_owned(),
+            method: svr_cfg.method().to_string(),
+            plugin: svr_cfg.plugin().map(|p| p.plugin.clone()),
+            plugin_opts: svr_cfg.plugin().and_then(|p| p.plugin_opts.clone()),
+            plugin_args: svr_cfg.plugin().map(|p| p.plugin_args.clone()).unwrap_or_default(),
+            timeout: svr_cfg.timeout().map(|t| t.as_secs()),
+            udp_timeout: config.udp_timeout.map(|t| t.as_secs()),
+            udp_max_associations: config.udp_max_associations,
+            quota: svr_cfg.quota(),
+            speed_limit: svr_cfg.speed_limit().map(|l| protocol::SpeedLimit {
+                up: l.up,
+                down: l.down,
+                scope: Some(l.scope.to_string()),
+            }),
+            mode: config.mode.to_string(),
+            no_delay: config.no_delay,
+            outbound_fwmark,
+            local_address: config.local_addr.as_ref().map(ToString::to_string),
+            acl: config.acl.as_ref().map(|acl| acl.file_path().display().to_string()),
+            geoip_database: config
+                .acl
+                .as_ref()
+                .and_then(|acl| acl.databases().geoip.as_ref())
+                .map(|p| p.display().to_string()),
+            geosite_database: config
+                .acl
+                .as_ref()
+                .and_then(|acl| acl.databases().geosite.as_ref())
+                .map(|p| p.display().to_string()),
+        };
+
+        let mut buf = serde_json::to_string(&export).expect("convert server config into JSON");
+        buf += "\n";
+
+        trace!("ACTION \"config\" returns {:?}", ByteStr::new(buf.as_bytes()));
+
+        Ok(Some(buf.into_bytes()))
+    }
+
     async fn handle_list(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let servers: Vec<protocol::ServerStatus> = self
+            .servers
+            .values()
+            .map(|inst| {
+                let svr_cfg = &inst.config.server[0];
+                protocol::ServerStatus {
+                    server_port: svr_cfg.addr().port(),
+                    password: svr_cfg.password().to_owned(),
+                    method: svr_cfg.method().to_string(),
+                    metrics: inst.metrics(),
+                }
+            })
+            .collect();
+
+        let mut buf = serde_json::to_string(&servers).expect("convert server list into JSON");
+        buf += "\n";
+
+        trace!("ACTION \"list\" returns {:?}", ByteStr::new(buf.as_bytes()));
+
+        Ok(Some(buf.into_bytes()))
+    }
+
+    async fn handle_ping(&mut self) -> io::Result<Option<Vec<u8>>> {
         let mut buf = String::new();
-        buf += "[";
+        buf += "stat: {";
         let mut is_first = true;
-        for (_, inst) in self.servers.iter() {
-            let config = &inst.config;
-            let svr_cfg = &config.server[0];
-
-            let p = protocol::ServerConfig {
-                server_port: svr_cfg.addr().port(),
-                method: Some(svr_cfg.method().to_string()),
-                password: svr_cfg.password().to_string(),
-                no_delay: None,
-                plugin: None,
-                plugin_opts: None,
-                mode: None,
-            };
-
+        for (port, inst) in self.servers.iter() {
             if is_first {
                 is_first = false;
             } else {
                 buf += ",";
             }
 
-            buf += &serde_json::to_string(&p).expect("convert server config into JSON");
+            buf += &format!("\"{}\":{}", port, inst.flow_trans_stat());
         }
-        buf += "]\n";
+        buf += "}\n";
 
-        trace!("ACTION \"list\" returns {:?}", ByteStr::new(buf.as_bytes()));
+        trace!("ACTION \"ping\" returns {:?}", ByteStr::new(buf.as_bytes()));
 
         Ok(Some(buf.into_bytes()))
     }
 
-    async fn handle_ping(&mut self) -> io::Result<Option<Vec<u8>>> {
+    async fn handle_closes(&mut self) -> io::Result<Option<Vec<u8>>> {
         let mut buf = String::new();
-        buf += "stat: {";
+        buf += "{";
         let mut is_first = true;
         for (port, inst) in self.servers.iter() {
             if is_first {
@@ -553,11 +1433,130 @@ impl ManagerService {
                 buf += ",";
             }
 
-            buf += &format!("\"{}\":{}", port, inst.flow_trans_stat());
+            buf += &format!("\"{}\":{{", port);
+            for (idx, (reason, count)) in inst.flow_close_stat().iter().enumerate() {
+                if idx > 0 {
+                    buf += ",";
+                }
+                buf += &format!("\"{}\":{}", reason, count);
+            }
+            buf += "}";
         }
         buf += "}\n";
 
-        trace!("ACTION \"ping\" returns {:?}", ByteStr::new(buf.as_bytes()));
+        trace!("ACTION \"closes\" returns {:?}", ByteStr::new(buf.as_bytes()));
+
+        Ok(Some(buf.into_bytes()))
+    }
+
+    async fn handle_latency(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let latency: BTreeMap<u16, LatencySnapshot> = self
+            .servers
+            .iter()
+            .map(|(port, inst)| (*port, LatencySnapshot::new(inst.flow_stat.latency())))
+            .collect();
+
+        let mut buf = serde_json::to_string(&latency).expect("convert latency histograms into JSON");
+        buf += "\n";
+
+        trace!("ACTION \"latency\" returns {:?}", ByteStr::new(buf.as_bytes()));
+
+        Ok(Some(buf.into_bytes()))
+    }
+
+    async fn handle_drains(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let now = Instant::now();
+        let status: BTreeMap<u16, protocol::DrainStatus> = self
+            .draining
+            .lock()
+            .iter()
+            .map(|(port, d)| {
+                let status = protocol::DrainStatus {
+                    connections: d.flow_stat.connections(),
+                    remaining: d.deadline.saturating_duration_since(now).as_secs(),
+                };
+                (*port, status)
+            })
+            .collect();
+
+        let mut buf = serde_json::to_string(&status).expect("convert drain status into JSON");
+        buf += "\n";
+
+        trace!("ACTION \"drains\" returns {:?}", ByteStr::new(buf.as_bytes()));
+
+        Ok(Some(buf.into_bytes()))
+    }
+
+    async fn handle_list_connections(&mut self, p: &protocol::ListConnectionsRequest) -> io::Result<Option<Vec<u8>>> {
+        trace!("ACTION \"list-connections\" {:?}", p);
+
+        if let Some(port) = p.server_port {
+            if !self.servers.contains_key(&port) {
+                let err = Error::new(ErrorKind::NotFound, format!("server port {} not found", port));
+                return Err(err);
+            }
+        }
+
+        let limit = p.limit.unwrap_or(DEFAULT_LIST_CONNECTIONS_LIMIT);
+        let connections: BTreeMap<u16, Vec<ConnectionSnapshot>> = self
+            .servers
+            .iter()
+            .filter(|(port, _)| p.server_port.map_or(true, |sp| sp == **port))
+            .map(|(port, inst)| {
+                let mut conns = inst.flow_stat.connection_snapshots();
+                conns.sort_by(|a, b| (b.tx + b.rx).cmp(&(a.tx + a.rx)));
+                conns.truncate(limit);
+                (*port, conns)
+            })
+            .collect();
+
+        let mut buf = serde_json::to_string(&connections).expect("convert connections into JSON");
+        buf += "\n";
+
+        trace!("ACTION \"list-connections\" returns {:?}", ByteStr::new(buf.as_bytes()));
+
+        Ok(Some(buf.into_bytes()))
+    }
+
+    async fn handle_top_destinations(&mut self, p: &protocol::TopDestinationsRequest) -> io::Result<Option<Vec<u8>>> {
+        trace!("ACTION \"top-destinations\" {:?}", p);
+
+        let inst = match self.servers.get(&p.server_port) {
+            Some(inst) => inst,
+            None => {
+                let err = Error::new(ErrorKind::NotFound, format!("server port {} not found", p.server_port));
+                return Err(err);
+            }
+        };
+
+        let limit = p.limit.unwrap_or(DEFAULT_TOP_DESTINATIONS_LIMIT);
+        let destinations = match inst.flow_stat.top_destinations(limit) {
+            Some(d) => d,
+            None => {
+                let err = Error::new(ErrorKind::Other, "`destination_stats_capacity` is not configured");
+                return Err(err);
+            }
+        };
+
+        let mut buf = serde_json::to_string(&destinations).expect("convert destinations into JSON");
+        buf += "\n";
+
+        trace!("ACTION \"top-destinations\" returns {:?}", ByteStr::new(buf.as_bytes()));
+
+        Ok(Some(buf.into_bytes()))
+    }
+
+    async fn handle_metrics(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let metrics: BTreeMap<u16, protocol::ServerMetrics> = self
+            .servers
+            .iter()
+            .map(|(port, inst)| (*port, inst.metrics()))
+            .collect();
+
+        let mut buf = serde_json::to_string(&metrics).expect("convert server metrics into JSON");
+        buf += "\n";
+
+        trace!("ACTION \"stat\" returns {:?}", ByteStr::new(buf.as_bytes()));
 
         Ok(Some(buf.into_bytes()))
     }
@@ -565,13 +1564,25 @@ impl ManagerService {
     async fn handle_stat(&mut self, pmap: &HashMap<String, u64>) -> io::Result<Option<Vec<u8>>> {
         trace!("ACTION \"stat\" {:?}", pmap);
 
-        // NOTE: "stat" is not supported in this implementation
-        //       because servers are spawned in the same process with the manager
+        // NOTE: Reports by "stat" are ignored in this implementation
+        //       because servers are spawned in the same process with the manager.
+        //       "stat" without parameters replies metrics of servers instead.
 
         Ok(None)
     }
 }
 
+// Servers must not share the same persisted replay filter file, so the port is appended to the path
+fn replay_filter_for_port(replay_filter: &ReplayFilterConfig, port: u16) -> ReplayFilterConfig {
+    let mut replay_filter = replay_filter.clone();
+    if let Some(ref mut path) = replay_filter.persist_path {
+        let mut port_path = path.as_os_str().to_owned();
+        port_path.push(format!(".{}", port));
+        *path = PathBuf::from(port_path);
+    }
+    replay_filter
+}
+
 /// Server manager for supporting [Manage Multiple Users](https://github.com/shadowsocks/shadowsocks/wiki/Manage-Multiple-Users) APIs
 pub async fn run(config: Config) -> io::Result<()> {
     assert!(config.config_type.is_manager());
@@ -610,6 +1621,15 @@ pub async fn run(config: Config) -> io::Result<()> {
         }
     };
 
+    if context.config().memory_pressure_threshold.is_some() {
+        tokio::spawn(memory_monitor_task(context.clone()));
+    }
+
+    // ACL rules are shared with all servers
+    if context.acl().is_some() {
+        tokio::spawn(acl_reload_task(state));
+    }
+
     let bind_addr = &manager_config.addr;
 
     let mut service = ManagerService::bind(bind_addr, context.clone()).await?;
@@ -620,6 +1640,31 @@ pub async fn run(config: Config) -> io::Result<()> {
     // Creates known servers in configuration
     let config = context.config();
 
+    if let Some(ref path) = config.flow_stats_persist_path {
+        match flow_persist::load(path) {
+            Ok(persisted) => {
+                if !persisted.is_empty() {
+                    info!(
+                        "traffic counters of {} servers restored from {}",
+                        persisted.len(),
+                        path.display()
+                    );
+                }
+                *service.restored_flow.lock() = persisted;
+            }
+            Err(err) => warn!("failed to restore traffic counters from {}, {}", path.display(), err),
+        }
+
+        // Servers which are not added again yet are kept in the file
+        let events = service.events.clone();
+        let restored_flow = service.restored_flow.clone();
+        tokio::spawn(flow_stats_persist_task(path.clone(), move || {
+            let mut flow = restored_flow.lock().clone();
+            flow.extend(flow_persist::from_snapshot(&events.snapshot()));
+            flow
+        }));
+    }
+
     if !config.server.is_empty() {
         for svr_cfg in &config.server {
             let mut clean_config = Config::new(ConfigType::Server);
@@ -627,6 +1672,16 @@ pub async fn run(config: Config) -> io::Result<()> {
             clean_config.mode = config.mode;
             clean_config.no_delay = config.no_delay;
             clean_config.udp_timeout = config.udp_timeout;
+            clean_config.udp_egress_pool_size = config.udp_egress_pool_size;
+            clean_config.ipv6_prefer_temporary = config.ipv6_prefer_temporary;
+            clean_config.ipv6_outbound_prefix = config.ipv6_outbound_prefix;
+            clean_config.outbound_address_pool = config.outbound_address_pool.clone();
+            clean_config.replay_filter = replay_filter_for_port(&config.replay_filter, svr_cfg.addr().port());
+            clean_config.keep_busy_interval = config.keep_busy_interval;
+            clean_config.tcp_defer_accept = config.tcp_defer_accept;
+            clean_config.destination_stats_capacity = config.destination_stats_capacity;
+            clean_config.sampling = config.sampling.clone();
+            clean_config.security_level = config.security_level;
 
             clean_config.server.push(svr_cfg.clone());
 
@@ -636,5 +1691,54 @@ pub async fn run(config: Config) -> io::Result<()> {
         }
     }
 
+    if let Some(federation_addr) = manager_config.federation_addr {
+        let listener = TcpListener::bind(&federation_addr).await?;
+        tokio::spawn(service.federation.clone().run(listener));
+    }
+
+    if let Some(ref upstream) = manager_config.upstream {
+        let node = manager_config.node_name.clone().expect("manager_node_name");
+        let secret = manager_config.secret.clone();
+        tokio::spawn(manager_federation::run_agent(
+            upstream.clone(),
+            node,
+            secret,
+            service.request_sender(),
+        ));
+    }
+
+    if let Some(events_addr) = manager_config.events_addr {
+        let listener = TcpListener::bind(&events_addr).await?;
+        let traffic_interval = manager_config.events_interval.unwrap_or(DEFAULT_TRAFFIC_INTERVAL);
+        tokio::spawn(service.events.clone().run(listener, traffic_interval));
+    }
+
+    if let Some(ref stats_export) = config.stats_export {
+        let events = service.events.clone();
+        tokio::spawn(stats_export::run(stats_export.clone(), move || events.snapshot()));
+    }
+
+    #[cfg(feature = "manager-tls")]
+    if let Some(tls_addr) = manager_config.tls_addr {
+        let cert_path = manager_config.tls_cert.as_ref().expect("manager_tls_cert");
+        let key_path = manager_config.tls_key.as_ref().expect("manager_tls_key");
+        let acceptor = manager_tls::load_acceptor(cert_path, key_path)?;
+
+        let listener = TcpListener::bind(&tls_addr).await?;
+        let signed = manager_config.secret.is_some();
+        tokio::spawn(manager_tls::run(listener, acceptor, service.request_sender(), signed));
+    }
+
+    #[cfg(feature = "manager-http")]
+    if let Some(http_addr) = manager_config.http_addr {
+        let token = manager_config.http_token.clone().expect("manager_http_token");
+        let http_requests = service.request_sender();
+
+        return tokio::select! {
+            r = service.serve() => r,
+            r = manager_http::run(http_addr, token, http_requests) => r,
+        };
+    }
+
     service.serve().await
 }
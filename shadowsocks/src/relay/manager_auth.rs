@@ -0,0 +1,114 @@
+//! Authentication of manager commands
+//!
+//! With `manager_secret`, every command sent to the manager's socket must be signed:
+//!
+//! ```plain
+//! TIMESTAMP NONCE SIGNATURE
+//! ACTION: PARAM
+//! ```
+//!
+//! - `TIMESTAMP` - Seconds since UNIX epoch, must be within `MAX_CLOCK_SKEW` seconds of the manager's clock
+//! - `NONCE` - Random string, which is only accepted once
+//! - `SIGNATURE` - Base64 encoded HMAC-SHA256 of `TIMESTAMP NONCE\nACTION: PARAM` with the secret
+
+use std::{
+    collections::HashMap,
+    io::{self, Error, ErrorKind},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Commands signed earlier (or later) than this in seconds are rejected
+const MAX_CLOCK_SKEW: u64 = 30;
+
+fn now_secs() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(..) => 0,
+    }
+}
+
+fn command_mac(secret: &[u8], timestamp: &str, nonce: &str, command: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_varkey(secret).expect("HMAC accepts keys in any length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b" ");
+    mac.update(nonce.as_bytes());
+    mac.update(b"\n");
+    mac.update(command.as_bytes());
+    mac
+}
+
+fn auth_error(desc: &str) -> Error {
+    Error::new(ErrorKind::PermissionDenied, format!("authentication failed, {}", desc))
+}
+
+/// Sign `command` for sending to a manager with `secret`
+pub fn sign_command(secret: &str, command: &str) -> String {
+    let timestamp = now_secs().to_string();
+    let nonce = format!("{:016x}", rand::random::<u64>());
+    let signature = command_mac(secret.as_bytes(), &timestamp, &nonce, command)
+        .finalize()
+        .into_bytes();
+
+    format!("{} {} {}\n{}", timestamp, nonce, base64::encode(&signature), command)
+}
+
+/// Verifier of signed commands, which remembers nonces for rejecting replayed commands
+pub struct CommandVerifier {
+    secret: Vec<u8>,
+    /// Accepted nonces with their timestamps, until they are expired
+    nonces: HashMap<String, u64>,
+}
+
+impl CommandVerifier {
+    pub fn new(secret: &str) -> CommandVerifier {
+        CommandVerifier {
+            secret: secret.as_bytes().to_vec(),
+            nonces: HashMap::new(),
+        }
+    }
+
+    /// Verify a signed packet, returns the command in it
+    pub fn verify<'a>(&mut self, pkt: &'a str) -> io::Result<&'a str> {
+        let (header, command) = match pkt.find('\n') {
+            Some(idx) => (&pkt[..idx], &pkt[idx + 1..]),
+            None => return Err(auth_error("missing signature")),
+        };
+
+        let mut sp = header.trim().split(' ');
+        let (timestamp, nonce, signature) = match (sp.next(), sp.next(), sp.next(), sp.next()) {
+            (Some(t), Some(n), Some(s), None) if !n.is_empty() => (t, n, s),
+            _ => return Err(auth_error("malformed signature")),
+        };
+
+        let now = now_secs();
+        let ts = match timestamp.parse::<u64>() {
+            Ok(ts) if ts.saturating_add(MAX_CLOCK_SKEW) >= now && ts <= now + MAX_CLOCK_SKEW => ts,
+            Ok(..) => return Err(auth_error("timestamp expired")),
+            Err(..) => return Err(auth_error("malformed timestamp")),
+        };
+
+        let signature = match base64::decode(signature) {
+            Ok(s) => s,
+            Err(..) => return Err(auth_error("malformed signature")),
+        };
+        if command_mac(&self.secret, timestamp, nonce, command)
+            .verify(&signature)
+            .is_err()
+        {
+            return Err(auth_error("invalid signature"));
+        }
+
+        // Expired nonces couldn't be replayed, because their timestamps are rejected
+        self.nonces.retain(|_, t| *t + MAX_CLOCK_SKEW >= now);
+        if self.nonces.insert(nonce.to_owned(), ts).is_some() {
+            return Err(auth_error("replayed nonce"));
+        }
+
+        Ok(command)
+    }
+}
@@ -0,0 +1,197 @@
+//! Event stream of the manager
+//!
+//! Clients connected to `manager_events_address` receive events in JSON, one per line:
+//!
+//! - `{"event":"added","server_port":8388}` - A server is started
+//! - `{"event":"removed","server_port":8388}` - A server is removed
+//! - `{"event":"quota_exceeded","server_port":8388,"quota":1073741824}` - A server's traffic quota is reached
+//! - `{"event":"traffic","timestamp":1600000000,"servers":[...]}` - Snapshot of all servers, every interval
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use spin::Mutex as SpinMutex;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    sync::broadcast::{self, error::RecvError},
+    time,
+};
+
+use crate::relay::flow::{FlowSnapshot, ServerFlowSnapshot, SharedServerFlowStatistic};
+
+/// Events kept for slow subscribers, older ones are dropped
+const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+/// Interval of checking quotas of servers
+const QUOTA_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Default interval of `traffic` events
+pub(crate) const DEFAULT_TRAFFIC_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Event pushed to subscribers
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum ManagerEvent {
+    Added {
+        server_port: u16,
+    },
+    Removed {
+        server_port: u16,
+    },
+    QuotaExceeded {
+        server_port: u16,
+        quota: u64,
+    },
+    Traffic {
+        #[serde(flatten)]
+        snapshot: FlowSnapshot,
+    },
+}
+
+struct WatchedServer {
+    flow_stat: SharedServerFlowStatistic,
+    quota: Option<u64>,
+}
+
+/// Publisher of manager events, cloned for the event stream listener
+#[derive(Clone)]
+pub(crate) struct ManagerEvents {
+    tx: broadcast::Sender<ManagerEvent>,
+    servers: Arc<SpinMutex<BTreeMap<u16, WatchedServer>>>,
+}
+
+impl ManagerEvents {
+    pub fn new() -> ManagerEvents {
+        let (tx, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        ManagerEvents {
+            tx,
+            servers: Arc::new(SpinMutex::new(BTreeMap::new())),
+        }
+    }
+
+    /// Publish `event` to all subscribers
+    pub fn notify(&self, event: ManagerEvent) {
+        // Fails if there are no subscribers
+        let _ = self.tx.send(event);
+    }
+
+    /// Watch traffic and quota of the server listening on `port`
+    pub fn watch(&self, port: u16, flow_stat: SharedServerFlowStatistic, quota: Option<u64>) {
+        self.servers.lock().insert(port, WatchedServer { flow_stat, quota });
+    }
+
+    /// Stop watching the server listening on `port`
+    pub fn unwatch(&self, port: u16) {
+        self.servers.lock().remove(&port);
+    }
+
+    /// Snapshot of all watched servers
+    pub fn snapshot(&self) -> FlowSnapshot {
+        let snapshots = self
+            .servers
+            .lock()
+            .iter()
+            .map(|(port, s)| ServerFlowSnapshot::new(*port, &s.flow_stat))
+            .collect();
+        FlowSnapshot::new(snapshots)
+    }
+
+    /// Accept subscribers from `listener`, and publish `traffic` and `quota_exceeded` events
+    pub async fn run(self, listener: TcpListener, traffic_interval: Duration) {
+        if let Ok(local_addr) = listener.local_addr() {
+            info!("shadowsocks manager events listening on {}", local_addr);
+        }
+
+        tokio::spawn(self.clone().watch_task(traffic_interval));
+
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer_addr)) => {
+                    debug!("manager events subscribed by {}", peer_addr);
+                    let rx = self.tx.subscribe();
+                    tokio::spawn(async move {
+                        if let Err(err) = push_events(stream, rx).await {
+                            debug!("manager events subscriber {} closed, {}", peer_addr, err);
+                        }
+                    });
+                }
+                Err(err) => {
+                    error!("manager events accept failed with error: {}", err);
+                    time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }
+
+    async fn watch_task(self, traffic_interval: Duration) {
+        let mut exceeded = BTreeSet::new();
+        let mut last_traffic = Instant::now();
+
+        loop {
+            time::sleep(QUOTA_CHECK_INTERVAL).await;
+
+            let mut events = Vec::new();
+            {
+                let servers = self.servers.lock();
+
+                // Removed (or re-added) servers could be reported again
+                exceeded.retain(|port| match servers.get(port) {
+                    Some(s) => s.flow_stat.quota_exceeded(s.quota),
+                    None => false,
+                });
+
+                for (port, server) in servers.iter() {
+                    if let Some(quota) = server.quota {
+                        if server.flow_stat.quota_exceeded(Some(quota)) && exceeded.insert(*port) {
+                            warn!("server listening on port {} exceeded traffic quota {}", port, quota);
+                            events.push(ManagerEvent::QuotaExceeded {
+                                server_port: *port,
+                                quota,
+                            });
+                        }
+                    }
+                }
+
+                if last_traffic.elapsed() >= traffic_interval {
+                    last_traffic = Instant::now();
+
+                    let snapshots = servers
+                        .iter()
+                        .map(|(port, s)| ServerFlowSnapshot::new(*port, &s.flow_stat))
+                        .collect();
+                    events.push(ManagerEvent::Traffic {
+                        snapshot: FlowSnapshot::new(snapshots),
+                    });
+                }
+            }
+
+            for event in events {
+                self.notify(event);
+            }
+        }
+    }
+}
+
+async fn push_events(mut stream: TcpStream, mut rx: broadcast::Receiver<ManagerEvent>) -> io::Result<()> {
+    loop {
+        let event = match rx.recv().await {
+            Ok(e) => e,
+            Err(RecvError::Lagged(n)) => {
+                warn!("manager events subscriber is too slow, {} events dropped", n);
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+
+        let mut line = serde_json::to_string(&event).expect("convert manager event into JSON");
+        line += "\n";
+        stream.write_all(line.as_bytes()).await?;
+    }
+}
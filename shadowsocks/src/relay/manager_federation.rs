@@ -0,0 +1,313 @@
+//! Federation of managers
+//!
+//! Agents, which are managers started with `manager_upstream`, connect to `manager_federation_address` of the
+//! central manager, and register with their `manager_node_name`:
+//!
+//! ```plain
+//! node: NAME
+//! ```
+//!
+//! Then the central manager sends commands with `"node":"NAME"` to the agent, one per line, and each command is
+//! replied in a line. `manager_secret` is required (shared by all managers), registrations and commands are signed.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Error, ErrorKind},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures::future;
+use log::{debug, error, info, warn};
+use spin::Mutex as SpinMutex;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+    time,
+};
+
+use crate::relay::{
+    manager::{read_packet, ManagerPacket, ManagerRequest, ManagerRequestSender},
+    manager_auth::{sign_command, CommandVerifier},
+};
+
+/// Agents which don't reply in time are considered failed
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval of reconnecting to the upstream manager
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Pending commands of a node
+const NODE_COMMAND_CHANNEL_SIZE: usize = 16;
+
+type NodeReply = io::Result<Option<Vec<u8>>>;
+
+struct NodeCommand {
+    command: String,
+    reply: oneshot::Sender<NodeReply>,
+}
+
+struct Node {
+    /// Identifies the connection, a node may reconnect before its previous connection is closed
+    id: u64,
+    tx: mpsc::Sender<NodeCommand>,
+}
+
+/// Registry of agents connected to the central manager
+#[derive(Clone)]
+pub(crate) struct Federation {
+    nodes: Arc<SpinMutex<BTreeMap<String, Node>>>,
+    next_id: Arc<AtomicU64>,
+    secret: Option<Arc<String>>,
+    verifier: Option<Arc<SpinMutex<CommandVerifier>>>,
+}
+
+impl Federation {
+    pub fn new(secret: Option<&str>) -> Federation {
+        Federation {
+            nodes: Arc::new(SpinMutex::new(BTreeMap::new())),
+            next_id: Arc::new(AtomicU64::new(0)),
+            secret: secret.map(|s| Arc::new(s.to_owned())),
+            verifier: secret.map(|s| Arc::new(SpinMutex::new(CommandVerifier::new(s)))),
+        }
+    }
+
+    /// Names of registered nodes
+    pub fn node_names(&self) -> Vec<String> {
+        self.nodes.lock().keys().cloned().collect()
+    }
+
+    /// Send `action: param` to `node`, returns its reply
+    pub async fn forward(&self, node: &str, action: &str, param: &str) -> NodeReply {
+        let tx = match self.nodes.lock().get(node) {
+            Some(n) => n.tx.clone(),
+            None => {
+                let err = Error::new(ErrorKind::NotFound, format!("node \"{}\" not found", node));
+                return Err(err);
+            }
+        };
+
+        let mut command = format!("{}: {}", action, param);
+        if let Some(ref secret) = self.secret {
+            command = sign_command(secret, &command);
+        }
+
+        // Both queueing and replying are limited, commands are queued behind a node's previous commands
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let reply = time::timeout(FORWARD_TIMEOUT, async move {
+            let cmd = NodeCommand {
+                command,
+                reply: reply_tx,
+            };
+            if tx.send(cmd).await.is_err() {
+                return None;
+            }
+            reply_rx.await.ok()
+        });
+
+        match reply.await {
+            Ok(Some(r)) => r,
+            Ok(None) => Err(Error::new(ErrorKind::Other, format!("node \"{}\" disconnected", node))),
+            Err(..) => Err(Error::new(ErrorKind::TimedOut, format!("node \"{}\" timed out", node))),
+        }
+    }
+
+    /// Send `action: param` to all nodes, returns replies of nodes in JSON
+    ///
+    /// Replies which are not JSON, like `ok` and errors, are converted into strings
+    pub async fn forward_all(&self, action: &str, param: &str) -> serde_json::Value {
+        let names = self.node_names();
+        let replies = future::join_all(names.iter().map(|n| self.forward(n, action, param))).await;
+
+        let mut result = serde_json::Map::new();
+        for (name, reply) in names.into_iter().zip(replies) {
+            let value = match reply {
+                Ok(Some(buf)) => match serde_json::from_slice(&buf) {
+                    Ok(v) => v,
+                    Err(..) => serde_json::Value::String(String::from_utf8_lossy(&buf).trim().to_owned()),
+                },
+                Ok(None) => serde_json::Value::Null,
+                Err(err) => serde_json::Value::String(err.to_string()),
+            };
+            result.insert(name, value);
+        }
+        serde_json::Value::Object(result)
+    }
+
+    /// Accept agents from `listener`
+    pub async fn run(self, listener: TcpListener) {
+        if let Ok(local_addr) = listener.local_addr() {
+            info!("shadowsocks manager federation listening on {}", local_addr);
+        }
+
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer_addr)) => {
+                    let federation = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = federation.handle_node(stream, peer_addr).await {
+                            warn!("manager federation node {} closed with error: {}", peer_addr, err);
+                        }
+                    });
+                }
+                Err(err) => {
+                    error!("manager federation accept failed with error: {}", err);
+                    time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }
+
+    async fn handle_node(self, stream: TcpStream, peer_addr: SocketAddr) -> io::Result<()> {
+        // Registrations must be signed, or anyone could replace a node and receive its commands
+        let verifier = match self.verifier {
+            Some(ref v) => v,
+            None => {
+                let err = Error::new(
+                    ErrorKind::PermissionDenied,
+                    "`manager_secret` is required by federation",
+                );
+                return Err(err);
+            }
+        };
+
+        let mut stream = BufReader::new(stream);
+
+        let hello = match read_packet(&mut stream, true).await? {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let hello = match String::from_utf8(hello) {
+            Ok(h) => h,
+            Err(..) => return Err(Error::new(ErrorKind::InvalidData, "invalid encoding")),
+        };
+        let hello = verifier.lock().verify(&hello)?.to_owned();
+        let name = match hello.strip_prefix("node:").map(str::trim) {
+            Some(n) if !n.is_empty() => n.to_owned(),
+            _ => return Err(Error::new(ErrorKind::InvalidData, "invalid registration")),
+        };
+
+        let (tx, mut rx) = mpsc::channel(NODE_COMMAND_CHANNEL_SIZE);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        if self.nodes.lock().insert(name.clone(), Node { id, tx }).is_some() {
+            warn!(
+                "manager federation node \"{}\" registered again, replacing the previous one",
+                name
+            );
+        }
+        info!("manager federation node \"{}\" registered from {}", name, peer_addr);
+
+        let result = serve_node(&mut stream, &mut rx).await;
+
+        {
+            let mut nodes = self.nodes.lock();
+            if nodes.get(&name).map(|n| n.id) == Some(id) {
+                nodes.remove(&name);
+            }
+        }
+        info!("manager federation node \"{}\" unregistered", name);
+
+        result
+    }
+}
+
+async fn serve_node(stream: &mut BufReader<TcpStream>, rx: &mut mpsc::Receiver<NodeCommand>) -> io::Result<()> {
+    loop {
+        tokio::select! {
+            cmd = rx.recv() => {
+                let cmd = match cmd {
+                    Some(c) => c,
+                    None => return Ok(()),
+                };
+
+                let mut line = cmd.command;
+                line.push('\n');
+
+                // Replies are in order of commands, a node that doesn't reply in time is disconnected
+                let reply = time::timeout(FORWARD_TIMEOUT, async {
+                    stream.get_mut().write_all(line.as_bytes()).await?;
+                    read_packet(stream, false).await
+                });
+                let reply = match reply.await {
+                    Ok(r) => r?,
+                    Err(..) => return Err(Error::new(ErrorKind::TimedOut, "node didn't reply in time")),
+                };
+
+                let reply = match reply {
+                    Some(r) if r.is_empty() => None,
+                    Some(mut r) => {
+                        // Lines of replies are stripped
+                        r.push(b'\n');
+                        Some(r)
+                    }
+                    None => return Ok(()),
+                };
+                let _ = cmd.reply.send(Ok(reply));
+            }
+            closed = async { stream.fill_buf().await.map(|b| b.is_empty()) } => {
+                if closed? {
+                    return Ok(());
+                }
+                return Err(Error::new(ErrorKind::InvalidData, "unexpected data from node"));
+            }
+        }
+    }
+}
+
+/// Connect to the central manager `upstream` as `node`, commands from it are handled by the manager through
+/// `requests`, reconnects if the connection is lost
+pub async fn run_agent(upstream: String, node: String, secret: Option<String>, requests: ManagerRequestSender) {
+    loop {
+        match agent_session(&upstream, &node, secret.as_deref(), &requests).await {
+            Ok(()) => warn!("upstream manager {} closed connection", upstream),
+            Err(err) => error!("upstream manager {} failed with error: {}", upstream, err),
+        }
+        time::sleep(RECONNECT_INTERVAL).await;
+    }
+}
+
+async fn agent_session(
+    upstream: &str,
+    node: &str,
+    secret: Option<&str>,
+    requests: &ManagerRequestSender,
+) -> io::Result<()> {
+    let stream = TcpStream::connect(upstream).await?;
+    let mut stream = BufReader::new(stream);
+
+    let mut hello = format!("node: {}", node);
+    if let Some(secret) = secret {
+        hello = sign_command(secret, &hello);
+    }
+    hello.push('\n');
+    stream.get_mut().write_all(hello.as_bytes()).await?;
+    info!("connected to upstream manager {} as node \"{}\"", upstream, node);
+
+    while let Some(pkt) = read_packet(&mut stream, secret.is_some()).await? {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let packet = ManagerPacket { pkt, reply: reply_tx };
+        if requests.send(ManagerRequest::Packet(packet)).await.is_err() {
+            return Err(Error::new(ErrorKind::Other, "manager stopped"));
+        }
+
+        let mut resp = match reply_rx.await {
+            Ok(r) => r.unwrap_or_default(),
+            Err(..) => return Err(Error::new(ErrorKind::Other, "manager stopped")),
+        };
+        debug!("replying {} bytes to upstream manager {}", resp.len(), upstream);
+
+        // Exactly one line for each command
+        while resp.last() == Some(&b'\n') {
+            resp.pop();
+        }
+        resp.push(b'\n');
+        stream.get_mut().write_all(&resp).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,240 @@
+//! HTTP REST API of the manager
+//!
+//! Commands of the manager protocol are exposed as JSON endpoints, every request must carry
+//! `Authorization: Bearer TOKEN`:
+//!
+//! - `GET /servers` - `list`
+//! - `POST /servers` - `add`, with the same JSON body as the command
+//! - `GET /servers/PORT` - `config`
+//! - `DELETE /servers/PORT` - `remove`, `?drain=N` for draining up to N seconds
+//! - `POST /servers/PORT/restart` - `restart`
+//! - `GET /servers/PORT/connections` - `list-connections` of the server
+//! - `GET /servers/PORT/destinations` - `top-destinations`, `?limit=N` for the top N hosts
+//! - `GET /stat` - `ping`, transferred bytes of all servers
+//! - `GET /latency` - `latency`, histograms of all servers
+//!
+//! Commands are handled by the manager in order, together with commands from the manager socket.
+
+use std::{
+    convert::Infallible,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use hyper::{
+    body,
+    header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE},
+    service::{make_service_fn, service_fn},
+    Body,
+    Method,
+    Request,
+    Response,
+    Server,
+    StatusCode,
+};
+use log::{error, info, trace};
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+use crate::relay::{
+    manager::{ManagerRequest, ManagerRequestSender},
+    utils::constant_time_eq,
+};
+
+/// Reply of a manager command, `None` if there is nothing to reply
+pub(crate) type CommandResult = io::Result<Option<Vec<u8>>>;
+
+/// A command sent to the manager, the same as a packet `ACTION: PARAM` from the manager socket
+pub(crate) struct ManagerCommand {
+    pub action: &'static str,
+    pub param: String,
+    pub reply: oneshot::Sender<CommandResult>,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse<'a> {
+    error: &'a str,
+}
+
+/// Runs the HTTP REST API, requests are forwarded to the manager through `commands`
+pub async fn run(bind_addr: SocketAddr, token: String, commands: ManagerRequestSender) -> io::Result<()> {
+    let token = Arc::new(token);
+
+    let make_service = make_service_fn(move |_| {
+        let token = token.clone();
+        let commands = commands.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                handle_request(req, token.clone(), commands.clone())
+            }))
+        }
+    });
+
+    let server = match Server::try_bind(&bind_addr) {
+        Ok(builder) => builder.http1_only(true).serve(make_service),
+        Err(err) => {
+            let err = io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("failed to bind {}, {}", bind_addr, err),
+            );
+            return Err(err);
+        }
+    };
+
+    info!("shadowsocks manager HTTP API listening on {}", server.local_addr());
+
+    if let Err(err) = server.await {
+        error!("manager HTTP API exited with error: {}", err);
+        return Err(io::Error::new(ErrorKind::Other, err));
+    }
+
+    Ok(())
+}
+
+async fn handle_request(
+    req: Request<Body>,
+    token: Arc<String>,
+    commands: ManagerRequestSender,
+) -> Result<Response<Body>, Infallible> {
+    trace!("HTTP API {} {}", req.method(), req.uri());
+
+    if !is_authorized(&req, &token) {
+        let mut resp = make_error(StatusCode::UNAUTHORIZED, "unauthorized");
+        resp.headers_mut()
+            .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        return Ok(resp);
+    }
+
+    let method = req.method().clone();
+    let path = req.uri().path().to_owned();
+    let drain = req
+        .uri()
+        .query()
+        .and_then(|q| query_value(q, "drain"))
+        .map(ToOwned::to_owned);
+    let limit = req
+        .uri()
+        .query()
+        .and_then(|q| query_value(q, "limit"))
+        .map(ToOwned::to_owned);
+    let segments = path.split('/').filter(|s| !s.is_empty()).collect::<Vec<_>>();
+
+    // Port of /servers/PORT
+    let port = match segments.get(1).map(|p| p.parse::<u16>()) {
+        Some(Ok(port)) => Some(port),
+        Some(Err(..)) => return Ok(make_error(StatusCode::NOT_FOUND, "not found")),
+        None => None,
+    };
+
+    let (action, param) = match (&method, segments.as_slice(), port) {
+        (&Method::GET, ["servers"], None) => ("list", String::new()),
+        (&Method::POST, ["servers"], None) => match body::to_bytes(req.into_body()).await {
+            Ok(body) => match String::from_utf8(body.to_vec()) {
+                Ok(body) => ("add", body),
+                Err(..) => return Ok(make_error(StatusCode::BAD_REQUEST, "invalid encoding")),
+            },
+            Err(err) => return Ok(make_error(StatusCode::BAD_REQUEST, &err.to_string())),
+        },
+        (&Method::GET, ["servers", _], Some(port)) => ("config", format!("{{\"server_port\":{}}}", port)),
+        (&Method::DELETE, ["servers", _], Some(port)) => {
+            let drain = match drain.map(|d| d.parse::<u64>()) {
+                Some(Ok(d)) => format!(",\"drain\":{}", d),
+                Some(Err(..)) => return Ok(make_error(StatusCode::BAD_REQUEST, "invalid `drain`")),
+                None => String::new(),
+            };
+            ("remove", format!("{{\"server_port\":{}{}}}", port, drain))
+        }
+        (&Method::POST, ["servers", _, "restart"], Some(port)) => ("restart", format!("{{\"server_port\":{}}}", port)),
+        (&Method::GET, ["servers", _, "connections"], Some(port)) => {
+            ("list-connections", format!("{{\"server_port\":{}}}", port))
+        }
+        (&Method::GET, ["servers", _, "destinations"], Some(port)) => {
+            let limit = match limit.map(|l| l.parse::<usize>()) {
+                Some(Ok(l)) => format!(",\"limit\":{}", l),
+                Some(Err(..)) => return Ok(make_error(StatusCode::BAD_REQUEST, "invalid `limit`")),
+                None => String::new(),
+            };
+            ("top-destinations", format!("{{\"server_port\":{}{}}}", port, limit))
+        }
+        (&Method::GET, ["stat"], None) => ("ping", String::new()),
+        (&Method::GET, ["latency"], None) => ("latency", String::new()),
+        _ => return Ok(make_error(StatusCode::NOT_FOUND, "not found")),
+    };
+
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let cmd = ManagerCommand {
+        action,
+        param,
+        reply: reply_tx,
+    };
+    if commands.send(ManagerRequest::Command(cmd)).await.is_err() {
+        return Ok(make_error(StatusCode::SERVICE_UNAVAILABLE, "manager stopped"));
+    }
+
+    let resp = match reply_rx.await {
+        Ok(Ok(Some(mut buf))) => {
+            // "ping" is replied as `stat: {...}`
+            if action == "ping" && buf.starts_with(b"stat: ") {
+                buf.drain(..6);
+            }
+
+            if buf == b"ok\n" {
+                make_response(StatusCode::NO_CONTENT, Body::empty())
+            } else {
+                make_response(StatusCode::OK, Body::from(buf))
+            }
+        }
+        Ok(Ok(None)) => make_response(StatusCode::NO_CONTENT, Body::empty()),
+        Ok(Err(err)) => {
+            let status = match err.kind() {
+                ErrorKind::NotFound => StatusCode::NOT_FOUND,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            make_error(status, &err.to_string())
+        }
+        Err(..) => make_error(StatusCode::SERVICE_UNAVAILABLE, "manager stopped"),
+    };
+
+    Ok(resp)
+}
+
+/// Check the bearer token in `Authorization`
+fn is_authorized(req: &Request<Body>, token: &str) -> bool {
+    let value = match req.headers().get(AUTHORIZATION).and_then(|v| v.to_str().ok()) {
+        Some(v) => v,
+        None => return false,
+    };
+
+    match value.strip_prefix("Bearer ") {
+        Some(t) => constant_time_eq(t.trim().as_bytes(), token.as_bytes()),
+        None => false,
+    }
+}
+
+fn query_value<'a>(query: &'a str, key: &str) -> Option<&'a str> {
+    query.split('&').find_map(|kv| {
+        let mut sp = kv.splitn(2, '=');
+        if sp.next() == Some(key) {
+            sp.next()
+        } else {
+            None
+        }
+    })
+}
+
+fn make_response(status: StatusCode, body: Body) -> Response<Body> {
+    let mut resp = Response::new(body);
+    *resp.status_mut() = status;
+    if status != StatusCode::NO_CONTENT {
+        resp.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    }
+    resp
+}
+
+fn make_error(status: StatusCode, error: &str) -> Response<Body> {
+    let body = serde_json::to_vec(&ErrorResponse { error }).expect("convert error into JSON");
+    make_response(status, Body::from(body))
+}
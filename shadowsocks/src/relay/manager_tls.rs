@@ -0,0 +1,142 @@
+//! TLS channel of the manager
+//!
+//! Clients connected to `manager_tls_address` send commands of the manager protocol, one per line, and receive
+//! a reply line for each command. With `manager_secret`, every command is preceded by its signature line.
+//!
+//! Commands are handled by the manager in order, together with commands from the manager socket.
+
+use std::{
+    fs::File,
+    io::{self, BufReader as StdBufReader, Error, ErrorKind},
+    net::SocketAddr,
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use log::{debug, error, info, trace};
+use tokio::{
+    io::{AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::oneshot,
+    time,
+};
+use tokio_rustls::{
+    rustls::{
+        internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+        NoClientAuth,
+        PrivateKey,
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+use crate::relay::manager::{read_packet, ManagerPacket, ManagerRequest, ManagerRequestSender};
+
+/// Create a TLS acceptor with certificate chain and private key in PEM files
+pub fn load_acceptor(cert_path: &Path, key_path: &Path) -> io::Result<TlsAcceptor> {
+    let cert_chain = certs(&mut StdBufReader::new(File::open(cert_path)?)).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid certificate in {}", cert_path.display()),
+        )
+    })?;
+
+    let mut keys = load_keys(key_path, pkcs8_private_keys)?;
+    if keys.is_empty() {
+        keys = load_keys(key_path, rsa_private_keys)?;
+    }
+    let key = match keys.into_iter().next() {
+        Some(k) => k,
+        None => {
+            let err = Error::new(
+                ErrorKind::InvalidData,
+                format!("no private key found in {}", key_path.display()),
+            );
+            return Err(err);
+        }
+    };
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    if let Err(err) = config.set_single_cert(cert_chain, key) {
+        let err = Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid certificate or private key, {}", err),
+        );
+        return Err(err);
+    }
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn load_keys(
+    key_path: &Path,
+    f: fn(&mut dyn io::BufRead) -> Result<Vec<PrivateKey>, ()>,
+) -> io::Result<Vec<PrivateKey>> {
+    f(&mut StdBufReader::new(File::open(key_path)?)).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid private key in {}", key_path.display()),
+        )
+    })
+}
+
+/// Accept TLS connections from `listener`, commands are forwarded to the manager through `requests`
+///
+/// `signed` is set if commands are signed with `manager_secret`
+pub async fn run(listener: TcpListener, acceptor: TlsAcceptor, requests: ManagerRequestSender, signed: bool) {
+    if let Ok(local_addr) = listener.local_addr() {
+        info!("shadowsocks manager TLS channel listening on {}", local_addr);
+    }
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer_addr)) => {
+                let acceptor = acceptor.clone();
+                let requests = requests.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = handle_client(stream, peer_addr, acceptor, requests, signed).await {
+                        debug!("manager TLS client {} closed with error: {}", peer_addr, err);
+                    }
+                });
+            }
+            Err(err) => {
+                error!("manager TLS channel accept failed with error: {}", err);
+                time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+async fn handle_client(
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    acceptor: TlsAcceptor,
+    requests: ManagerRequestSender,
+    signed: bool,
+) -> io::Result<()> {
+    let stream = acceptor.accept(stream).await?;
+    trace!("manager TLS client {} connected", peer_addr);
+
+    let mut stream = BufReader::new(stream);
+
+    while let Some(pkt) = read_packet(&mut stream, signed).await? {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let packet = ManagerPacket { pkt, reply: reply_tx };
+        if requests.send(ManagerRequest::Packet(packet)).await.is_err() {
+            return Err(Error::new(ErrorKind::Other, "manager stopped"));
+        }
+
+        let mut resp = match reply_rx.await {
+            Ok(Some(resp)) => resp,
+            Ok(None) => continue,
+            Err(..) => return Err(Error::new(ErrorKind::Other, "manager stopped")),
+        };
+        if !resp.ends_with(b"\n") {
+            resp.push(b'\n');
+        }
+        stream.get_mut().write_all(&resp).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,59 @@
+//! Cooperative throttling under memory pressure
+
+use std::{io, time::Duration};
+
+use log::{debug, info, warn};
+use tokio::time;
+
+use crate::{context::SharedContext, relay::sys::memory_pressure};
+
+/// Watch memory pressure of the system, and mark `ServerState` as pressured if it exceeds `memory_pressure_threshold`
+///
+/// Accepting new connections and creating new UDP associations will be paused while the system is under pressure,
+/// cached DNS records and idle pooled sockets are released when it becomes pressured
+pub async fn memory_monitor_task(context: SharedContext) -> io::Result<()> {
+    let threshold = match context.config().memory_pressure_threshold {
+        Some(t) => t,
+        None => return Ok(()),
+    };
+
+    let state = context.server_state();
+
+    while context.server_running() {
+        match memory_pressure() {
+            Ok(Some(pressure)) => {
+                let pressured = pressure >= threshold;
+                if pressured != state.memory_pressured() {
+                    if pressured {
+                        warn!(
+                            "memory pressure {:.2}% exceeds threshold {:.2}%, pausing accepting new connections",
+                            pressure, threshold
+                        );
+
+                        if let Some(cache) = state.dns_cache() {
+                            let n = cache.flush(None);
+                            debug!("released {} cached DNS records under memory pressure", n);
+                        }
+                    } else {
+                        info!(
+                            "memory pressure {:.2}% is below threshold {:.2}%, resuming accepting new connections",
+                            pressure, threshold
+                        );
+                    }
+                    state.set_memory_pressured(pressured);
+                }
+            }
+            Ok(None) => {
+                warn!("memory pressure is not available on this system, `memory_pressure_threshold` is ignored");
+                return Ok(());
+            }
+            Err(err) => {
+                debug!("failed to read memory pressure, error: {}", err);
+            }
+        }
+
+        time::sleep(Duration::from_secs(1)).await;
+    }
+
+    Ok(())
+}
@@ -1,19 +1,57 @@
 //! Relay server in local and server side implementations.
 
+/// Log in `Info` level with target `shadowsocks::sampled` if the connection is sampled, or in `$lvl` otherwise
+///
+/// See `config::SamplingConfig`.
+macro_rules! sampled_log {
+    ($sampled:expr, $lvl:ident, $($arg:tt)+) => {
+        if $sampled {
+            log::info!(target: "shadowsocks::sampled", $($arg)+);
+        } else {
+            log::$lvl!($($arg)+);
+        }
+    };
+}
+
+pub(crate) mod acl_reload;
 pub(crate) mod dns_resolver;
 #[cfg(feature = "local-dns")]
 pub mod dnsrelay;
-pub(crate) mod flow;
+pub mod flow;
+pub(crate) mod flow_persist;
+pub(crate) mod healthcheck;
 pub(crate) mod loadbalancing;
 pub mod local;
+pub(crate) mod local_auth;
 pub mod manager;
+pub(crate) mod manager_auth;
+pub(crate) mod manager_events;
+pub(crate) mod manager_federation;
+#[cfg(feature = "manager-http")]
+pub(crate) mod manager_http;
+#[cfg(feature = "manager-tls")]
+pub(crate) mod manager_tls;
+pub(crate) mod memory;
+#[cfg(feature = "local-online-config")]
+pub(crate) mod online_acl;
+#[cfg(feature = "local-online-config")]
+pub(crate) mod online_config;
+#[cfg(feature = "local-http")]
+pub(crate) mod pac;
 #[cfg(feature = "local-redir")]
 pub(crate) mod redir;
+pub(crate) mod replay;
+pub mod selfcheck;
 pub mod server;
+pub(crate) mod server_dns;
 #[cfg(feature = "local-socks4")]
 pub mod socks4;
 pub mod socks5;
+pub(crate) mod speed_limit;
+pub(crate) mod stats_export;
 pub(crate) mod sys;
 pub mod tcprelay;
+#[cfg(feature = "local-tun")]
+pub(crate) mod tun;
 pub mod udprelay;
 pub(crate) mod utils;
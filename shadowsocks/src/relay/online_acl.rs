@@ -0,0 +1,168 @@
+//! Online ACL delivery
+//!
+//! Fetches an ACL file over HTTPS periodically with `If-None-Match`, and replaces the current rules if it is changed.
+//!
+//! If a public key is configured, the file must be signed with a detached Ed25519 signature (base64 encoded)
+//! at the same URL with a `.sig` suffix. Rules are only applied after they are verified and loaded successfully,
+//! then saved to the cache file, which is loaded on startup before the first fetch succeeds.
+
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+use log::{debug, info, warn};
+use ring::signature::{UnparsedPublicKey, ED25519};
+use tokio::{task, time};
+use url::Url;
+
+use crate::{
+    acl::AccessControl,
+    config::OnlineAclConfig,
+    context::{Context, SharedContext},
+    relay::online_config::{fetch, FetchResponse, FETCH_TIMEOUT},
+};
+
+/// Limit of ACL files, in bytes
+const MAX_ACL_SIZE: usize = 16 * 1024 * 1024;
+/// Limit of signatures of ACL files, in bytes
+const MAX_SIGNATURE_SIZE: usize = 4 * 1024;
+
+/// Runs the online ACL updater
+pub async fn run(context: SharedContext) -> io::Result<()> {
+    let online_acl = context
+        .config()
+        .online_acl
+        .as_ref()
+        .expect("online_acl must be provided");
+    let url = &online_acl.acl_url;
+
+    if context.acl().is_none() {
+        load_cache(&context, online_acl).await;
+    }
+
+    let mut etag: Option<String> = None;
+
+    while context.server_running() {
+        match time::timeout(FETCH_TIMEOUT, fetch_rules(&context, online_acl, etag.as_deref())).await {
+            Ok(Ok(None)) => debug!("online ACL from {} is not changed", url),
+            Ok(Ok(Some((body, new_etag)))) => match apply_rules(&context, online_acl, body).await {
+                Ok(()) => {
+                    info!("updated ACL from {}", url);
+                    etag = new_etag;
+                }
+                // Keep the current rules
+                Err(err) => warn!("invalid online ACL from {}, current rules are kept, {}", url, err),
+            },
+            Ok(Err(err)) => warn!("failed to fetch online ACL from {}, {}", url, err),
+            Err(..) => warn!("failed to fetch online ACL from {}, timeout", url),
+        }
+
+        time::sleep(online_acl.update_interval).await;
+    }
+
+    Ok(())
+}
+
+/// Load rules saved by the last run
+async fn load_cache(context: &Context, online_acl: &OnlineAclConfig) {
+    let cache_path = online_acl.cache_path.clone();
+    if !cache_path.exists() {
+        return;
+    }
+
+    let databases = context.config().acl_databases();
+    let load_path = cache_path.clone();
+    let load = move || AccessControl::load_from_file_with_databases(load_path, &databases);
+    match task::spawn_blocking(load).await {
+        Ok(Ok(acl)) => {
+            context.server_state().set_acl(acl);
+            info!("loaded cached online ACL {}", cache_path.display());
+        }
+        Ok(Err(err)) => warn!("failed to load cached online ACL {}, {}", cache_path.display(), err),
+        Err(err) => warn!("failed to load cached online ACL {}, {}", cache_path.display(), err),
+    }
+}
+
+/// Fetch the ACL file, and verify its signature if `public_key` is configured
+///
+/// Returns `None` if it is not modified since `etag`
+async fn fetch_rules(
+    context: &Context,
+    online_acl: &OnlineAclConfig,
+    etag: Option<&str>,
+) -> io::Result<Option<(Vec<u8>, Option<String>)>> {
+    let (body, etag) = match fetch(context, &online_acl.acl_url, etag, MAX_ACL_SIZE).await? {
+        FetchResponse::Modified { body, etag } => (body, etag),
+        FetchResponse::NotModified => return Ok(None),
+    };
+
+    if let Some(ref public_key) = online_acl.public_key {
+        let signature_url = signature_url(&online_acl.acl_url);
+        let signature = match fetch(context, &signature_url, None, MAX_SIGNATURE_SIZE).await? {
+            FetchResponse::Modified { body, .. } => body,
+            FetchResponse::NotModified => unreachable!("signature is fetched unconditionally"),
+        };
+        verify_signature(public_key, &body, &signature)?;
+    }
+
+    Ok(Some((body, etag)))
+}
+
+fn signature_url(acl_url: &Url) -> Url {
+    let mut url = acl_url.clone();
+    url.set_path(&format!("{}.sig", acl_url.path()));
+    url
+}
+
+fn verify_signature(public_key: &[u8], body: &[u8], signature: &[u8]) -> io::Result<()> {
+    let signature = match base64::decode(String::from_utf8_lossy(signature).trim()) {
+        Ok(s) => s,
+        Err(err) => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid signature, {}", err),
+            ))
+        }
+    };
+
+    match UnparsedPublicKey::new(&ED25519, public_key).verify(body, &signature) {
+        Ok(..) => Ok(()),
+        Err(..) => Err(io::Error::new(ErrorKind::InvalidData, "signature verification failed")),
+    }
+}
+
+/// Load rules from `body`, then replace the current rules and save them to the cache file
+async fn apply_rules(context: &Context, online_acl: &OnlineAclConfig, body: Vec<u8>) -> io::Result<()> {
+    let databases = context.config().acl_databases();
+    let cache_path = online_acl.cache_path.clone();
+
+    let load = move || -> io::Result<AccessControl> {
+        let acl = AccessControl::load_from_reader(&body[..], &cache_path, &databases)?;
+
+        // Rules are applied even if they couldn't be cached
+        if let Err(err) = save_cache(&cache_path, &body) {
+            warn!("failed to save online ACL to {}, {}", cache_path.display(), err);
+        }
+
+        Ok(acl)
+    };
+
+    let acl = match task::spawn_blocking(load).await {
+        Ok(r) => r?,
+        Err(err) => return Err(io::Error::new(ErrorKind::Other, err)),
+    };
+    context.server_state().set_acl(acl);
+
+    Ok(())
+}
+
+/// Write to a temporary file, then rename it, so the cache file is never partially written
+fn save_cache(cache_path: &Path, body: &[u8]) -> io::Result<()> {
+    let mut tmp_path = cache_path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    fs::write(&tmp_path, body)?;
+    fs::rename(&tmp_path, cache_path)
+}
@@ -0,0 +1,265 @@
+//! Online configuration delivery
+//!
+//! Fetches a [SIP008](https://github.com/shadowsocks/shadowsocks-org/issues/89) document over HTTPS periodically,
+//! and replaces servers of load balancers with servers in it, without restarting.
+//!
+//! Servers with plugins are skipped, because plugins are only started with servers in the configuration file.
+
+use std::{
+    io::{self, ErrorKind},
+    sync::Arc,
+    time::Duration,
+};
+
+use hyper::{
+    body::HttpBody,
+    client::conn,
+    header::{CONTENT_LENGTH, ETAG, HOST, IF_NONE_MATCH, USER_AGENT},
+    Body,
+    Request,
+    StatusCode,
+};
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use serde::Deserialize;
+use tokio::{net::TcpStream, task, time};
+use tokio_rustls::{rustls::ClientConfig, webpki::DNSNameRef, TlsConnector};
+use url::Url;
+
+use crate::{
+    config::{Config, ConfigType, ServerConfig},
+    context::{Context, SharedContext},
+};
+
+/// Timeout of fetching the document, including connecting and reading the body
+pub(crate) const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Limit of SIP008 documents, in bytes
+const MAX_DOCUMENT_SIZE: usize = 1024 * 1024;
+
+/// Version of SIP008 document that is supported
+const SIP008_VERSION: u32 = 1;
+
+#[derive(Deserialize)]
+struct Sip008Header {
+    version: u32,
+}
+
+/// Runs the online configuration updater
+pub async fn run(context: SharedContext) -> io::Result<()> {
+    let online_config = context
+        .config()
+        .online_config
+        .as_ref()
+        .expect("online_config must be provided");
+    let url = &online_config.config_url;
+
+    let mut last_document = None;
+
+    while context.server_running() {
+        match time::timeout(FETCH_TIMEOUT, fetch_document(&context, url)).await {
+            Ok(Ok(document)) => {
+                if last_document.as_ref() == Some(&document) {
+                    debug!("online config from {} is not changed", url);
+                } else {
+                    // Keys may be derived by Argon2id, which is too expensive to run in the runtime
+                    let parse_document = document.clone();
+                    match task::spawn_blocking(move || parse_servers(&parse_document)).await {
+                        Ok(Ok(servers)) => {
+                            info!("fetched {} servers from online config {}", servers.len(), url);
+                            context.update_servers(servers);
+                            last_document = Some(document);
+                        }
+                        // Keep the current servers
+                        Ok(Err(err)) => warn!("invalid online config from {}, {}", url, err),
+                        Err(err) => warn!("failed to parse online config from {}, {}", url, err),
+                    }
+                }
+            }
+            Ok(Err(err)) => warn!("failed to fetch online config from {}, {}", url, err),
+            Err(..) => warn!("failed to fetch online config from {}, timeout", url),
+        }
+
+        time::sleep(online_config.update_interval).await;
+    }
+
+    Ok(())
+}
+
+/// Parse and validate servers in a SIP008 document
+fn parse_servers(document: &str) -> io::Result<Vec<ServerConfig>> {
+    let header: Sip008Header = match serde_json::from_str(document) {
+        Ok(h) => h,
+        Err(err) => return Err(io::Error::new(ErrorKind::InvalidData, err)),
+    };
+    if header.version != SIP008_VERSION {
+        let err = io::Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported SIP008 version {}", header.version),
+        );
+        return Err(err);
+    }
+
+    let config = match Config::load_from_str(document, ConfigType::Socks5Local) {
+        Ok(c) => c,
+        Err(err) => return Err(io::Error::new(ErrorKind::InvalidData, err.to_string())),
+    };
+
+    let mut servers = Vec::with_capacity(config.server.len());
+    for svr_cfg in config.server {
+        if svr_cfg.plugin().is_some() {
+            warn!(
+                "server {} in online config is skipped, plugins are not supported",
+                svr_cfg.addr()
+            );
+            continue;
+        }
+        servers.push(svr_cfg);
+    }
+
+    if servers.is_empty() {
+        return Err(io::Error::new(ErrorKind::InvalidData, "no available servers"));
+    }
+
+    Ok(servers)
+}
+
+async fn fetch_document(context: &Context, url: &Url) -> io::Result<String> {
+    match fetch(context, url, None, MAX_DOCUMENT_SIZE).await? {
+        FetchResponse::Modified { body, .. } => {
+            String::from_utf8(body).map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+        }
+        FetchResponse::NotModified => Err(io::Error::new(ErrorKind::Other, "unexpected status 304")),
+    }
+}
+
+/// Response of `fetch`
+pub(crate) enum FetchResponse {
+    /// Body of the resource, with its `ETag` if provided
+    Modified { body: Vec<u8>, etag: Option<String> },
+    /// The resource is not modified since the request with `etag`
+    NotModified,
+}
+
+/// GET `url` over HTTPS, conditionally with `If-None-Match` if `etag` is provided
+///
+/// Fails if the body is larger than `max_size` bytes
+pub(crate) async fn fetch(
+    context: &Context,
+    url: &Url,
+    etag: Option<&str>,
+    max_size: usize,
+) -> io::Result<FetchResponse> {
+    lazy_static! {
+        static ref TLS_CONFIG: Arc<ClientConfig> = {
+            let mut config = ClientConfig::new();
+
+            match rustls_native_certs::load_native_certs() {
+                Ok(store) => {
+                    config.root_store = store;
+                }
+                Err((_, err)) => {
+                    warn!("failed to load native certs, {}", err);
+
+                    config
+                        .root_store
+                        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+                }
+            }
+
+            Arc::new(config)
+        };
+    }
+
+    let host = url.host_str().expect("online URL must have a host");
+    let port = url.port_or_known_default().unwrap_or(443);
+
+    let (_, stream) = lookup_then!(context, host, port, |addr| { TcpStream::connect(addr).await })?;
+
+    let dns_name = match DNSNameRef::try_from_ascii_str(host) {
+        Ok(n) => n,
+        Err(..) => {
+            let err = io::Error::new(ErrorKind::InvalidInput, format!("invalid dnsname \"{}\"", host));
+            return Err(err);
+        }
+    };
+    let tls_stream = TlsConnector::from(TLS_CONFIG.clone()).connect(dns_name, stream).await?;
+
+    let (mut sender, connection) = conn::handshake(tls_stream)
+        .await
+        .map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+    tokio::spawn(async move {
+        if let Err(err) = connection.await {
+            debug!("connection to {} closed with error: {}", url, err);
+        }
+    });
+
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_owned(),
+    };
+    let host_header = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_owned(),
+    };
+
+    let mut req = Request::get(path)
+        .header(HOST, host_header)
+        .header(USER_AGENT, concat!("shadowsocks-rust/", env!("CARGO_PKG_VERSION")));
+    if let Some(etag) = etag {
+        req = req.header(IF_NONE_MATCH, etag);
+    }
+    let req = req
+        .body(Body::empty())
+        .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+
+    let resp = sender
+        .send_request(req)
+        .await
+        .map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+    match resp.status() {
+        StatusCode::OK => {}
+        StatusCode::NOT_MODIFIED if etag.is_some() => return Ok(FetchResponse::NotModified),
+        status => {
+            let err = io::Error::new(ErrorKind::Other, format!("unexpected status {}", status));
+            return Err(err);
+        }
+    }
+
+    let etag = resp
+        .headers()
+        .get(ETAG)
+        .and_then(|v| v.to_str().ok())
+        .map(ToOwned::to_owned);
+
+    let too_large = || {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("body of {} is larger than {} bytes", url, max_size),
+        )
+    };
+
+    let content_length = resp
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if let Some(len) = content_length {
+        if len > max_size as u64 {
+            return Err(too_large());
+        }
+    }
+
+    // Content-Length may be absent or wrong, the limit is also checked while reading
+    let mut body = Vec::with_capacity(content_length.map_or(0, |len| len as usize));
+    let mut resp_body = resp.into_body();
+    while let Some(chunk) = resp_body.data().await {
+        let chunk = chunk.map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+        if body.len() + chunk.len() > max_size {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(FetchResponse::Modified { body, etag })
+}
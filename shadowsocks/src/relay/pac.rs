@@ -0,0 +1,154 @@
+//! Proxy auto-config (PAC) file server
+//!
+//! Serves a PAC script generated from the current ACL (see `AccessControl::pac_script`) on `pac_addr`, hosts that
+//! should be proxied go to the local server of this process. Browsers could be configured with
+//! `http://PAC_ADDR/proxy.pac`.
+
+use std::{
+    convert::Infallible,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+};
+
+use http::uri::Authority;
+use hyper::{
+    header::{HeaderValue, CONTENT_TYPE, HOST},
+    service::{make_service_fn, service_fn},
+    Body,
+    Method,
+    Request,
+    Response,
+    Server,
+    StatusCode,
+};
+use log::{error, info, trace};
+
+use crate::{
+    config::{ConfigType, ServerAddr},
+    context::SharedContext,
+};
+
+/// `Content-Type` of PAC files
+const PAC_CONTENT_TYPE: &str = "application/x-ns-proxy-autoconfig";
+
+/// Proxy type in PAC of the local server, `None` if clients couldn't be configured to use it
+fn proxy_types(config_type: ConfigType) -> Option<&'static [&'static str]> {
+    match config_type {
+        ConfigType::Socks5Local => Some(&["SOCKS5", "SOCKS"]),
+        #[cfg(feature = "local-socks4")]
+        ConfigType::Socks4Local => Some(&["SOCKS"]),
+        ConfigType::HttpLocal => Some(&["PROXY"]),
+        #[cfg(any(feature = "local-http-native-tls", feature = "local-http-rustls"))]
+        ConfigType::HttpsLocal => Some(&["HTTPS"]),
+        ConfigType::MixedLocal => Some(&["SOCKS5", "SOCKS", "PROXY"]),
+        _ => None,
+    }
+}
+
+/// `HOST:PORT` of the local server for clients
+///
+/// Host of the PAC request is used if the local server listens on an unspecified address (like `0.0.0.0`)
+fn proxy_host(local_addr: &ServerAddr, req: &Request<Body>) -> String {
+    let addr = match *local_addr {
+        ServerAddr::SocketAddr(addr) if !addr.ip().is_unspecified() => return addr.to_string(),
+        ServerAddr::DomainName(ref host, port) => return format!("{}:{}", host, port),
+        ServerAddr::SocketAddr(addr) => addr,
+    };
+
+    let host = req
+        .headers()
+        .get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.parse::<Authority>().ok())
+        .map(|a| a.host().to_owned());
+
+    match host {
+        Some(host) if host.contains(':') && !host.starts_with('[') => format!("[{}]:{}", host, addr.port()),
+        Some(host) => format!("{}:{}", host, addr.port()),
+        None if addr.is_ipv6() => format!("[::1]:{}", addr.port()),
+        None => format!("127.0.0.1:{}", addr.port()),
+    }
+}
+
+fn handle_request(context: &SharedContext, types: &[&str], req: Request<Body>) -> Response<Body> {
+    trace!("PAC {} {}", req.method(), req.uri());
+
+    if req.method() != Method::GET || !matches!(req.uri().path(), "/" | "/proxy.pac") {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = StatusCode::NOT_FOUND;
+        return resp;
+    }
+
+    let local_addr = context.config().local_addr.as_ref().expect("local config");
+    let host = proxy_host(local_addr, &req);
+    let proxy = types
+        .iter()
+        .map(|t| format!("{} {}", t, host))
+        .collect::<Vec<_>>()
+        .join("; ");
+
+    let script = match context.acl() {
+        Some(acl) => acl.pac_script(&proxy),
+        None => format!(
+            "function FindProxyForURL(url, host) {{\n    return \"{}\";\n}}\n",
+            proxy
+        ),
+    };
+
+    let mut resp = Response::new(Body::from(script));
+    resp.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(PAC_CONTENT_TYPE));
+    resp
+}
+
+/// Runs the PAC file server on `pac_addr`
+pub async fn run(context: SharedContext) -> io::Result<()> {
+    let types = match proxy_types(context.config().config_type) {
+        Some(t) => t,
+        None => {
+            let err = io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "`pac_address` is not supported by {:?}, which couldn't be used as a proxy of browsers",
+                    context.config().config_type
+                ),
+            );
+            return Err(err);
+        }
+    };
+
+    let addr = context.config().pac_addr.as_ref().expect("pac_addr must be provided");
+    let bind_addr: SocketAddr = addr.bind_addr(&context).await?;
+
+    let service_context = context.clone();
+    let make_service = make_service_fn(move |_| {
+        let context = service_context.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let resp = handle_request(&context, types, req);
+                async move { Ok::<_, Infallible>(resp) }
+            }))
+        }
+    });
+
+    let server = match Server::try_bind(&bind_addr) {
+        Ok(builder) => builder.http1_only(true).serve(make_service),
+        Err(err) => {
+            let err = io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("failed to bind {}, {}", bind_addr, err),
+            );
+            return Err(err);
+        }
+    };
+
+    info!("shadowsocks PAC file server listening on {}", server.local_addr());
+
+    if let Err(err) = server.await {
+        error!("PAC file server exited with error: {}", err);
+        return Err(io::Error::new(ErrorKind::Other, err));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,55 @@
+//! Persisting replay filter across restarts
+
+use std::{io, time::Duration};
+
+use log::{debug, info, warn};
+use tokio::{runtime::Handle, task, time};
+
+use crate::context::SharedContext;
+
+// Interval of saving replay filter into `replay_filter.persist_path`
+const REPLAY_FILTER_PERSIST_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Saves the replay filter when the persisting task is dropped
+///
+/// Servers created by manager are stopped by aborting, which drops the task without running any code after it
+struct SaveOnExit(SharedContext);
+
+impl Drop for SaveOnExit {
+    fn drop(&mut self) {
+        // Save for the next start, includes IVs/salts received after the last periodic saving
+        let context = self.0.clone();
+        let save = move || match context.save_replay_filter() {
+            Ok(()) => info!("replay filter saved"),
+            Err(err) => warn!("failed to save replay filter, {}", err),
+        };
+
+        // Writes a few megabytes, don't block the runtime, tasks are dropped in it when servers are stopped
+        match Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(save);
+            }
+            Err(..) => save(),
+        }
+    }
+}
+
+/// Save replay filter into `replay_filter.persist_path` periodically
+///
+/// Runs until the server exits, it must be polled with the server's listeners. The filter is saved once more when
+/// this task is dropped, the periodic saving limits what is lost if the process crashes
+pub async fn replay_filter_persist_task(context: SharedContext) -> io::Result<()> {
+    let _guard = SaveOnExit(context.clone());
+
+    loop {
+        time::sleep(REPLAY_FILTER_PERSIST_INTERVAL).await;
+
+        // Writes a few megabytes, don't block the runtime
+        let ctx = context.clone();
+        match task::spawn_blocking(move || ctx.save_replay_filter()).await {
+            Ok(Ok(())) => debug!("replay filter saved"),
+            Ok(Err(err)) => warn!("failed to save replay filter, {}", err),
+            Err(err) => warn!("failed to save replay filter, {}", err),
+        }
+    }
+}
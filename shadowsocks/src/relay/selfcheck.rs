@@ -0,0 +1,348 @@
+//! Startup self-check
+//!
+//! Checks that the environment is ready for serving, before listeners are started.
+//! The result is logged and kept in `Context` for health checking.
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{error, info, warn};
+use serde::Serialize;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+use crate::{
+    config::{is_weak_method, Config, SecurityLevel, ServerAddr},
+    context::Context,
+};
+
+/// System time before this (2020-01-01T00:00:00Z) is considered to be wrong
+const MIN_SANE_UNIX_TIMESTAMP: u64 = 1_577_836_800;
+
+/// Result of a check item
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    /// Check passed
+    Pass,
+    /// Service could run, but may not work properly
+    Warn,
+    /// Service won't work
+    Fail,
+    /// Nothing to check
+    Skip,
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CheckStatus::Pass => f.write_str("pass"),
+            CheckStatus::Warn => f.write_str("warn"),
+            CheckStatus::Fail => f.write_str("fail"),
+            CheckStatus::Skip => f.write_str("skip"),
+        }
+    }
+}
+
+/// One item in `SelfCheckReport`
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckItem {
+    /// Name of the check, like `dns`, `listener`
+    pub name: String,
+    /// Result
+    pub status: CheckStatus,
+    /// Human readable detail
+    pub detail: String,
+}
+
+/// Result of all startup checks
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SelfCheckReport {
+    items: Vec<CheckItem>,
+}
+
+impl SelfCheckReport {
+    fn push<N: Into<String>, D: Into<String>>(&mut self, name: N, status: CheckStatus, detail: D) {
+        self.items.push(CheckItem {
+            name: name.into(),
+            status,
+            detail: detail.into(),
+        });
+    }
+
+    /// All check items
+    pub fn items(&self) -> &[CheckItem] {
+        &self.items
+    }
+
+    /// `true` if none of the checks failed
+    pub fn passed(&self) -> bool {
+        self.items.iter().all(|i| i.status != CheckStatus::Fail)
+    }
+
+    /// Serialize to JSON
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("serialize self-check report")
+    }
+
+    /// Log every items with the level of its status
+    pub fn log(&self) {
+        for item in &self.items {
+            match item.status {
+                CheckStatus::Pass | CheckStatus::Skip => {
+                    info!("self-check {}: {}, {}", item.name, item.status, item.detail)
+                }
+                CheckStatus::Warn => warn!("self-check {}: {}, {}", item.name, item.status, item.detail),
+                CheckStatus::Fail => error!("self-check {}: {}, {}", item.name, item.status, item.detail),
+            }
+        }
+    }
+}
+
+/// Addresses that will be listened on
+#[derive(Default)]
+pub struct Listeners<'a> {
+    /// TCP listen addresses
+    pub tcp: Vec<&'a ServerAddr>,
+    /// UDP listen addresses
+    pub udp: Vec<&'a ServerAddr>,
+}
+
+/// Run checks before starting listeners
+///
+/// Plugins must be started before, because listeners of servers with plugins are replaced by plugins' addresses.
+pub async fn run_self_check(context: &Context, listeners: Listeners<'_>) -> SelfCheckReport {
+    let mut report = SelfCheckReport::default();
+
+    check_dns(context, &mut report).await;
+    check_plugins(context.config(), &mut report).await;
+    check_listeners(context, listeners, &mut report).await;
+    check_acl(context, &mut report);
+    check_security(context.config(), &mut report);
+    check_clock(&mut report);
+
+    report
+}
+
+async fn check_dns(context: &Context, report: &mut SelfCheckReport) {
+    let mut checked = false;
+
+    for svr_cfg in &context.config().server {
+        if let ServerAddr::DomainName(ref dname, port) = *svr_cfg.addr() {
+            checked = true;
+
+            match context.dns_resolve(dname, port).await {
+                Ok(addrs) => report.push(
+                    "dns",
+                    CheckStatus::Pass,
+                    format!("resolved {} to {} addresses", dname, addrs.len()),
+                ),
+                Err(err) => report.push(
+                    "dns",
+                    CheckStatus::Fail,
+                    format!("failed to resolve {}, {}", dname, err),
+                ),
+            }
+        }
+    }
+
+    if !checked {
+        report.push("dns", CheckStatus::Skip, "no domain name to resolve");
+    }
+}
+
+async fn check_plugins(config: &Config, report: &mut SelfCheckReport) {
+    let mut checked = false;
+
+    for svr_cfg in &config.server {
+        let plugin = match svr_cfg.plugin() {
+            Some(p) => p,
+            None => continue,
+        };
+        checked = true;
+
+        let plugin_addr = match svr_cfg.plugin_addr() {
+            Some(ServerAddr::SocketAddr(sa)) => *sa,
+            _ => {
+                report.push(
+                    "plugin",
+                    CheckStatus::Fail,
+                    format!("plugin \"{}\" for {} is not started", plugin.plugin, svr_cfg.addr()),
+                );
+                continue;
+            }
+        };
+
+        if config.config_type.is_local() {
+            // Clients' plugins listen on `plugin_addr`
+            match TcpStream::connect(plugin_addr).await {
+                Ok(..) => report.push(
+                    "plugin",
+                    CheckStatus::Pass,
+                    format!(
+                        "plugin \"{}\" for {} is listening on {}",
+                        plugin.plugin,
+                        svr_cfg.addr(),
+                        plugin_addr
+                    ),
+                ),
+                Err(err) => report.push(
+                    "plugin",
+                    CheckStatus::Fail,
+                    format!(
+                        "plugin \"{}\" for {} is not listening on {}, {}",
+                        plugin.plugin,
+                        svr_cfg.addr(),
+                        plugin_addr,
+                        err
+                    ),
+                ),
+            }
+        } else {
+            // Servers' plugins forward to `plugin_addr`, which is not listened yet
+            report.push(
+                "plugin",
+                CheckStatus::Pass,
+                format!("plugin \"{}\" for {} is spawned", plugin.plugin, svr_cfg.addr()),
+            );
+        }
+    }
+
+    if !checked {
+        report.push("plugin", CheckStatus::Skip, "no plugin");
+    }
+}
+
+async fn check_listeners(context: &Context, listeners: Listeners<'_>, report: &mut SelfCheckReport) {
+    if listeners.tcp.is_empty() && listeners.udp.is_empty() {
+        report.push("listener", CheckStatus::Skip, "no listener");
+        return;
+    }
+
+    // Sockets are closed immediately, relay servers will bind them again
+    for addr in listeners.tcp {
+        let bind_addr = match addr.bind_addr(context).await {
+            Ok(a) => a,
+            Err(err) => {
+                report.push(
+                    "listener",
+                    CheckStatus::Fail,
+                    format!("failed to resolve {}, {}", addr, err),
+                );
+                continue;
+            }
+        };
+
+        match TcpListener::bind(bind_addr).await {
+            Ok(..) => report.push("listener", CheckStatus::Pass, format!("TCP {} is available", bind_addr)),
+            Err(err) => report.push(
+                "listener",
+                CheckStatus::Fail,
+                format!("failed to bind TCP {}, {}", bind_addr, err),
+            ),
+        }
+    }
+
+    for addr in listeners.udp {
+        let bind_addr = match addr.bind_addr(context).await {
+            Ok(a) => a,
+            Err(err) => {
+                report.push(
+                    "listener",
+                    CheckStatus::Fail,
+                    format!("failed to resolve {}, {}", addr, err),
+                );
+                continue;
+            }
+        };
+
+        match UdpSocket::bind(bind_addr).await {
+            Ok(..) => report.push("listener", CheckStatus::Pass, format!("UDP {} is available", bind_addr)),
+            Err(err) => report.push(
+                "listener",
+                CheckStatus::Fail,
+                format!("failed to bind UDP {}, {}", bind_addr, err),
+            ),
+        }
+    }
+}
+
+fn check_acl(context: &Context, report: &mut SelfCheckReport) {
+    // ACL rules are compiled while loading configuration, it is an error if they couldn't be compiled
+    let acl = match context.acl() {
+        Some(acl) => acl,
+        None => {
+            report.push("acl", CheckStatus::Skip, "no ACL");
+            return;
+        }
+    };
+
+    report.push(
+        "acl",
+        CheckStatus::Pass,
+        format!("compiled from {}", acl.file_path().display()),
+    );
+
+    // Destinations of groups without servers are proxied by the default group
+    if context.config().config_type.is_local() {
+        for name in acl.group_names() {
+            if !context.config().server.iter().any(|s| s.group() == name) {
+                report.push(
+                    "acl",
+                    CheckStatus::Warn,
+                    format!("group \"{}\" doesn't have any servers, the default group is used", name),
+                );
+            }
+        }
+    }
+}
+
+fn check_security(config: &Config, report: &mut SelfCheckReport) {
+    if config.security_level == SecurityLevel::Permissive {
+        report.push("security", CheckStatus::Skip, "security_level is permissive");
+        return;
+    }
+
+    let mut weak = false;
+    for svr_cfg in config.server.iter().filter(|s| is_weak_method(s.method())) {
+        weak = true;
+
+        // `strict` refuses to start in `Config::check_integrity`
+        let action = if config.security_level.rejects(svr_cfg.method()) {
+            "not started"
+        } else {
+            "allowed"
+        };
+        report.push(
+            "security",
+            CheckStatus::Warn,
+            format!(
+                "server {} uses weak method {}, {}",
+                svr_cfg.addr(),
+                svr_cfg.method(),
+                action
+            ),
+        );
+    }
+
+    if !weak {
+        report.push("security", CheckStatus::Pass, "all servers use AEAD methods");
+    }
+}
+
+fn check_clock(report: &mut SelfCheckReport) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0));
+
+    if now.as_secs() >= MIN_SANE_UNIX_TIMESTAMP {
+        report.push("clock", CheckStatus::Pass, format!("system time {}", now.as_secs()));
+    } else {
+        report.push(
+            "clock",
+            CheckStatus::Warn,
+            format!("system time {} is earlier than 2020, check the clock", now.as_secs()),
+        );
+    }
+}
@@ -6,7 +6,7 @@ use std::{
 };
 
 use futures::future::{select_all, FutureExt};
-use log::{debug, error, trace, warn};
+use log::{debug, error, info, trace, warn};
 use tokio::time;
 
 use crate::{
@@ -14,8 +14,16 @@ use crate::{
     context::{Context, ServerState, SharedContext, SharedServerState},
     plugin::{PluginMode, Plugins},
     relay::{
+        acl_reload::acl_reload_task,
         flow::{MultiServerFlowStatistic, SharedMultiServerFlowStatistic},
+        flow_persist::{self, flow_stats_persist_task},
+        healthcheck::run as run_health_check,
         manager::ManagerDatagram,
+        manager_auth::sign_command,
+        memory::memory_monitor_task,
+        replay::replay_filter_persist_task,
+        selfcheck::{run_self_check, Listeners},
+        stats_export,
         tcprelay::server::run as run_tcp,
         udprelay::server::run as run_udp,
         utils::set_nofile,
@@ -25,14 +33,26 @@ use crate::{
 /// Runs Relay server on server side.
 #[inline]
 pub async fn run(config: Config) -> io::Result<()> {
-    // Create a context containing a DNS resolver and server running state flag.
-    let server_state = ServerState::new_shared(&config).await;
-
     // Create statistics for multiple servers
     //
     // This is for statistic purpose for [Manage Multiple Users](https://github.com/shadowsocks/shadowsocks/wiki/Manage-Multiple-Users) APIs
     let flow_stat = MultiServerFlowStatistic::new_shared(&config);
 
+    run_with_flow_stat(config, flow_stat).await
+}
+
+/// Runs Relay server on server side, with statistics shared with the caller
+///
+/// `flow_stat` should be created by `MultiServerFlowStatistic::new_shared` with the same `config`,
+/// then statistics could be read, or subscribed with `MultiServerFlowStatistic::subscribe`, while the server is running.
+pub async fn run_with_flow_stat(config: Config, flow_stat: SharedMultiServerFlowStatistic) -> io::Result<()> {
+    // Create a context containing a DNS resolver and server running state flag.
+    let server_state = ServerState::new_shared(&config).await;
+
+    if config.acl.is_some() {
+        tokio::spawn(acl_reload_task(server_state.clone()));
+    }
+
     run_with(config, flow_stat, server_state).await
 }
 
@@ -88,6 +108,62 @@ pub(crate) async fn run_with(
         Context::new_with_state_shared(config, server_stat)
     };
 
+    if context.config().memory_pressure_threshold.is_some() {
+        tokio::spawn(memory_monitor_task(context.clone()));
+    }
+
+    // Stopped with listeners, servers created by manager are aborted instead of `set_server_stopped`
+    if context.config().replay_filter.persist_path.is_some() {
+        vf.push(replay_filter_persist_task(context.clone()).boxed());
+    }
+
+    if let Some(ref stats_export) = context.config().stats_export {
+        let flow_stat = flow_stat.clone();
+        tokio::spawn(stats_export::run(stats_export.clone(), move || flow_stat.snapshot()));
+    }
+
+    // Relay servers are not polled yet, nothing is counted before restoring
+    if let Some(ref path) = context.config().flow_stats_persist_path {
+        match flow_persist::load(path) {
+            Ok(persisted) => {
+                let mut restored = 0;
+                for (port, counters) in &persisted {
+                    if let Some(stat) = flow_stat.get(*port) {
+                        counters.restore(stat);
+                        restored += 1;
+                    }
+                }
+                if restored > 0 {
+                    info!(
+                        "traffic counters of {} servers restored from {}",
+                        restored,
+                        path.display()
+                    );
+                }
+            }
+            Err(err) => warn!("failed to restore traffic counters from {}, {}", path.display(), err),
+        }
+
+        let flow_stat = flow_stat.clone();
+        tokio::spawn(flow_stats_persist_task(path.clone(), move || {
+            flow_persist::from_snapshot(&flow_stat.snapshot())
+        }));
+    }
+
+    // Relay servers are not polled yet, so listen addresses are still available
+    let mut listeners = Listeners::default();
+    for svr_cfg in &context.config().server {
+        if mode.enable_tcp() {
+            listeners.tcp.push(svr_cfg.external_addr());
+        }
+        if mode.enable_udp() {
+            listeners.udp.push(svr_cfg.addr());
+        }
+    }
+    let report = run_self_check(&context, listeners).await;
+    report.log();
+    context.set_self_check_report(report);
+
     if mode.enable_udp() {
         // Run UDP relay before starting plugins
         // Because plugins doesn't support UDP relay
@@ -95,6 +171,11 @@ pub(crate) async fn run_with(
         vf.push(udp_fut.boxed());
     }
 
+    if context.config().health_check_addr.is_some() {
+        let health_check_fut = run_health_check(context.clone());
+        vf.push(health_check_fut.boxed());
+    }
+
     // If specified manager-address, reports transmission statistic to it
     //
     // Dont do that if server is created by manager
@@ -129,8 +210,12 @@ async fn manager_report_task(context: SharedContext, flow_stat: SharedMultiServe
 
             if let Some(ref fstat) = flow_stat.get(port) {
                 let stat = format!("stat: {{\"{}\":{}}}", port, fstat.trans_stat());
+                let pkt = match manager_config.secret {
+                    Some(ref secret) => sign_command(secret, &stat),
+                    None => stat.clone(),
+                };
 
-                match socket.send_to_manager(stat.as_bytes(), &context, &manager_addr).await {
+                match socket.send_to_manager(pkt.as_bytes(), &context, &manager_addr).await {
                     Ok(..) => {
                         trace!(
                             "sent {} for server \"{}\" to manger \"{}\"",
@@ -0,0 +1,131 @@
+//! Prefetching and pinning addresses of servers with domain names (for client)
+//!
+//! Domain names of servers are resolved in the background, and refreshed before their TTLs expire, so connecting to
+//! servers doesn't wait for DNS. Addresses are kept if refreshing failed, and the address of the last established
+//! connection is pinned in front, so a DNS hiccup or a rotated record won't move all connections at once.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use log::{debug, trace, warn};
+use spin::Mutex as SpinMutex;
+use tokio::time;
+
+use crate::{config::ServerAddr, context::SharedContext};
+
+/// Interval of checking for domain names to be refreshed
+const SERVER_DNS_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Refreshing interval of domain names without known TTLs
+const SERVER_DNS_DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+/// Domain names with tiny TTLs are not refreshed more frequently than this
+const SERVER_DNS_MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+struct ServerAddrEntry {
+    addrs: Vec<SocketAddr>,
+    refresh_at: Instant,
+}
+
+/// Prefetched addresses of servers, keyed by domain name and port
+#[derive(Default)]
+pub struct ServerAddrCache {
+    entries: SpinMutex<HashMap<(String, u16), ServerAddrEntry>>,
+}
+
+impl ServerAddrCache {
+    /// Create an empty cache
+    pub fn new() -> ServerAddrCache {
+        ServerAddrCache::default()
+    }
+
+    /// Prefetched addresses of server `host:port`, the pinned one first
+    pub fn get(&self, host: &str, port: u16) -> Option<Vec<SocketAddr>> {
+        let entries = self.entries.lock();
+        entries.get(&(host.to_owned(), port)).map(|e| e.addrs.clone())
+    }
+
+    /// Pin `addr` in front of addresses of server `host:port`, after a connection is established to it
+    pub fn pin(&self, host: &str, port: u16, addr: SocketAddr) {
+        let mut entries = self.entries.lock();
+        if let Some(entry) = entries.get_mut(&(host.to_owned(), port)) {
+            if let Some(pos) = entry.addrs.iter().position(|a| *a == addr) {
+                entry.addrs[..=pos].rotate_right(1);
+            }
+        }
+    }
+
+    fn needs_refresh(&self, host: &str, port: u16, now: Instant) -> bool {
+        let entries = self.entries.lock();
+        match entries.get(&(host.to_owned(), port)) {
+            Some(entry) => entry.refresh_at <= now,
+            None => true,
+        }
+    }
+
+    /// Replace addresses of server `host:port`, the pinned one is kept in front if it is still resolved
+    fn update(&self, host: &str, port: u16, mut addrs: Vec<SocketAddr>, refresh_at: Instant) {
+        let mut entries = self.entries.lock();
+        let key = (host.to_owned(), port);
+
+        if let Some(pinned) = entries.get(&key).and_then(|e| e.addrs.first()) {
+            if let Some(pos) = addrs.iter().position(|a| a == pinned) {
+                addrs[..=pos].rotate_right(1);
+            }
+        }
+        entries.insert(key, ServerAddrEntry { addrs, refresh_at });
+    }
+}
+
+/// Resolve domain names of servers in the background, until the server is stopped
+pub async fn server_dns_prefetch_task(context: SharedContext) {
+    while context.server_running() {
+        let now = Instant::now();
+        let cache = context.server_addr_cache();
+
+        for (svr_cfg, _) in context.server_list() {
+            let (host, port) = match *svr_cfg.addr() {
+                ServerAddr::DomainName(ref host, port) => (host, port),
+                ServerAddr::SocketAddr(..) => continue,
+            };
+            if !cache.needs_refresh(host, port, now) {
+                continue;
+            }
+
+            match context.dns_resolve_with_ttl(host, port).await {
+                Ok((addrs, _)) if addrs.is_empty() => {
+                    warn!(
+                        "server {}:{} resolved no address, previous addresses are kept",
+                        host, port
+                    );
+                }
+                Ok((addrs, ttl)) => {
+                    // Refreshed before 80% of TTL
+                    let interval = match ttl {
+                        Some(ttl) => (ttl * 4 / 5).max(SERVER_DNS_MIN_REFRESH_INTERVAL),
+                        None => SERVER_DNS_DEFAULT_REFRESH_INTERVAL,
+                    };
+                    trace!(
+                        "server {}:{} prefetched {:?}, refresh in {}s",
+                        host,
+                        port,
+                        addrs,
+                        interval.as_secs()
+                    );
+                    cache.update(host, port, addrs, Instant::now() + interval);
+                }
+                Err(err) => {
+                    warn!(
+                        "failed to resolve server {}:{}, previous addresses are kept, {}",
+                        host, port, err
+                    );
+                }
+            }
+        }
+
+        time::sleep(SERVER_DNS_CHECK_INTERVAL).await;
+    }
+
+    debug!("server DNS prefetching stopped");
+}
@@ -21,6 +21,9 @@ pub use self::consts::{
     SOCKS5_AUTH_METHOD_NONE,
     SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE,
     SOCKS5_AUTH_METHOD_PASSWORD,
+    SOCKS5_PASSWD_AUTH_STATUS_FAILURE,
+    SOCKS5_PASSWD_AUTH_STATUS_SUCCEEDED,
+    SOCKS5_VERSION,
 };
 
 #[rustfmt::skip]
@@ -49,6 +52,10 @@ mod consts {
     pub const SOCKS5_REPLY_TTL_EXPIRED:                u8 = 0x06;
     pub const SOCKS5_REPLY_COMMAND_NOT_SUPPORTED:      u8 = 0x07;
     pub const SOCKS5_REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+    pub const SOCKS5_PASSWD_AUTH_VERSION:              u8 = 0x01;
+    pub const SOCKS5_PASSWD_AUTH_STATUS_SUCCEEDED:     u8 = 0x00;
+    pub const SOCKS5_PASSWD_AUTH_STATUS_FAILURE:       u8 = 0x01;
 }
 
 /// SOCKS5 command
@@ -735,6 +742,168 @@ impl HandshakeResponse {
     }
 }
 
+/// Username/password authentication request packet (RFC1929)
+///
+/// ```plain
+/// +----+------+----------+------+----------+
+/// |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+/// +----+------+----------+------+----------+
+/// | 1  |  1   | 1 to 255 |  1   | 1 to 255 |
+/// +----+------+----------+------+----------+
+/// ```
+#[derive(Clone)]
+pub struct PasswdAuthRequest {
+    pub uname: Vec<u8>,
+    pub passwd: Vec<u8>,
+}
+
+impl PasswdAuthRequest {
+    /// Creates an authentication request
+    pub fn new(uname: Vec<u8>, passwd: Vec<u8>) -> PasswdAuthRequest {
+        PasswdAuthRequest { uname, passwd }
+    }
+
+    /// Read from a reader
+    pub async fn read_from<R>(r: &mut R) -> io::Result<PasswdAuthRequest>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut buf = [0u8; 2];
+        let _ = r.read_exact(&mut buf).await?;
+
+        let ver = buf[0];
+        let ulen = buf[1];
+
+        if ver != consts::SOCKS5_PASSWD_AUTH_VERSION {
+            use std::io::{Error, ErrorKind};
+            let err = Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported password authentication version {:#x}", ver),
+            );
+            return Err(err);
+        }
+
+        // Both of the fields are 1 to 255 bytes
+        if ulen == 0 {
+            use std::io::{Error, ErrorKind};
+            let err = Error::new(ErrorKind::InvalidData, "empty username of password authentication");
+            return Err(err);
+        }
+
+        let mut uname = vec![0u8; ulen as usize];
+        let _ = r.read_exact(&mut uname).await?;
+
+        let plen = r.read_u8().await?;
+        if plen == 0 {
+            use std::io::{Error, ErrorKind};
+            let err = Error::new(ErrorKind::InvalidData, "empty password of password authentication");
+            return Err(err);
+        }
+
+        let mut passwd = vec![0u8; plen as usize];
+        let _ = r.read_exact(&mut passwd).await?;
+
+        Ok(PasswdAuthRequest { uname, passwd })
+    }
+
+    /// Write to a writer
+    pub async fn write_to<W>(&self, w: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut buf = BytesMut::with_capacity(self.serialized_len());
+        self.write_to_buf(&mut buf);
+        w.write_all(&buf).await
+    }
+
+    /// Write to buffer
+    pub fn write_to_buf<B: BufMut>(&self, buf: &mut B) {
+        let PasswdAuthRequest { ref uname, ref passwd } = *self;
+        buf.put_slice(&[consts::SOCKS5_PASSWD_AUTH_VERSION, uname.len() as u8]);
+        buf.put_slice(&uname);
+        buf.put_u8(passwd.len() as u8);
+        buf.put_slice(&passwd);
+    }
+
+    /// Get length of bytes
+    pub fn serialized_len(&self) -> usize {
+        3 + self.uname.len() + self.passwd.len()
+    }
+}
+
+impl Debug for PasswdAuthRequest {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        // Password is never logged
+        f.debug_struct("PasswdAuthRequest")
+            .field("uname", &String::from_utf8_lossy(&self.uname))
+            .finish()
+    }
+}
+
+/// Username/password authentication response packet (RFC1929)
+///
+/// ```plain
+/// +----+--------+
+/// |VER | STATUS |
+/// +----+--------+
+/// | 1  |   1    |
+/// +----+--------+
+/// ```
+#[derive(Clone, Debug, Copy)]
+pub struct PasswdAuthResponse {
+    pub status: u8,
+}
+
+impl PasswdAuthResponse {
+    /// Creates an authentication response
+    pub fn new(status: u8) -> PasswdAuthResponse {
+        PasswdAuthResponse { status }
+    }
+
+    /// Read from a reader
+    pub async fn read_from<R>(r: &mut R) -> io::Result<PasswdAuthResponse>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut buf = [0u8; 2];
+        let _ = r.read_exact(&mut buf).await?;
+
+        let ver = buf[0];
+        let status = buf[1];
+
+        if ver != consts::SOCKS5_PASSWD_AUTH_VERSION {
+            use std::io::{Error, ErrorKind};
+            let err = Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported password authentication version {:#x}", ver),
+            );
+            Err(err)
+        } else {
+            Ok(PasswdAuthResponse { status })
+        }
+    }
+
+    /// Write to a writer
+    pub async fn write_to<W>(self, w: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut buf = BytesMut::with_capacity(self.serialized_len());
+        self.write_to_buf(&mut buf);
+        w.write_all(&buf).await
+    }
+
+    /// Write to buffer
+    pub fn write_to_buf<B: BufMut>(self, buf: &mut B) {
+        buf.put_slice(&[consts::SOCKS5_PASSWD_AUTH_VERSION, self.status]);
+    }
+
+    /// Length in bytes
+    pub fn serialized_len(self) -> usize {
+        2
+    }
+}
+
 /// UDP ASSOCIATE request header
 ///
 /// ```plain
@@ -796,3 +965,49 @@ impl UdpAssociateHeader {
         3 + self.address.serialized_len()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::ErrorKind;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn read_passwd_auth_request() {
+        let mut buf: &[u8] = b"\x01\x04user\x08password";
+        let req = PasswdAuthRequest::read_from(&mut buf).await.unwrap();
+        assert_eq!(req.uname, b"user");
+        assert_eq!(req.passwd, b"password");
+        assert!(buf.is_empty());
+
+        let mut written = BytesMut::new();
+        req.write_to_buf(&mut written);
+        assert_eq!(&written[..], b"\x01\x04user\x08password");
+    }
+
+    #[tokio::test]
+    async fn read_passwd_auth_request_bad_version() {
+        // Version of SOCKS5 instead of the subnegotiation
+        let mut buf: &[u8] = b"\x05\x04user\x08password";
+        let err = PasswdAuthRequest::read_from(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn read_passwd_auth_request_empty_fields() {
+        let mut buf: &[u8] = b"\x01\x00\x08password";
+        let err = PasswdAuthRequest::read_from(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let mut buf: &[u8] = b"\x01\x04user\x00";
+        let err = PasswdAuthRequest::read_from(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn read_passwd_auth_request_truncated() {
+        let mut buf: &[u8] = b"\x01\x04user\x08pass";
+        let err = PasswdAuthRequest::read_from(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,293 @@
+//! Speed limits of servers, enforced by token buckets
+//!
+//! Buckets are created with `ServerConfig::set_speed_limit`, and shared by all TCP connections and UDP associations
+//! of the server, of a client IP, or owned by one connection, as `SpeedLimit::scope`. TCP streams wait until the
+//! bucket is refilled, UDP packets are dropped while the bucket is empty.
+//!
+//! The same buckets enforce `egress_limit` of the whole instance, see `ServerState::egress_limiter`.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::SocketAddr,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    sync::{Arc, Weak},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::ready;
+use pin_project::pin_project;
+use spin::Mutex as SpinMutex;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{self, Sleep},
+};
+
+use crate::config::{SpeedLimit, SpeedLimitScope};
+
+#[derive(Debug)]
+struct BucketState {
+    /// Could be negative after a large chunk is transferred, which is paid back before the next one
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Token bucket refilled with `rate` bytes per second, holds at most 1 second of tokens
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    state: SpinMutex<BucketState>,
+}
+
+impl TokenBucket {
+    /// Create a full bucket with `rate` bytes per second
+    pub fn new(rate: u64) -> TokenBucket {
+        TokenBucket {
+            rate: rate as f64,
+            state: SpinMutex::new(BucketState {
+                tokens: rate as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take `n` bytes of tokens, which may be more than the bucket holds
+    pub fn consume(&self, n: usize) {
+        self.state.lock().tokens -= n as f64;
+    }
+
+    /// Time to wait until the bucket is not empty, `None` if tokens are available now
+    pub fn delay(&self) -> Option<Duration> {
+        let mut state = self.state.lock();
+
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(state.last_refill).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.rate).min(self.rate);
+        state.last_refill = now;
+
+        if state.tokens > 0.0 {
+            None
+        } else {
+            // Wait for a few more tokens, or it wakes up for every single byte
+            let wanted = -state.tokens + self.rate / 100.0;
+            Some(Duration::from_secs_f64(wanted / self.rate))
+        }
+    }
+
+    /// Take tokens for a packet of `n` bytes, returns `false` if it should be dropped
+    pub fn allow(&self, n: usize) -> bool {
+        if self.delay().is_some() {
+            return false;
+        }
+        self.consume(n);
+        true
+    }
+}
+
+#[derive(Debug)]
+struct BucketPair {
+    /// Traffic from clients
+    up: Option<TokenBucket>,
+    /// Traffic to clients
+    down: Option<TokenBucket>,
+}
+
+/// Token buckets of a connection (or UDP association), which may be shared with others in the same scope
+#[derive(Clone, Debug, Default)]
+pub struct SpeedBuckets(Option<Arc<BucketPair>>);
+
+impl SpeedBuckets {
+    fn new(limit: &SpeedLimit) -> SpeedBuckets {
+        SpeedBuckets(Some(Arc::new(BucketPair {
+            up: limit.up.map(TokenBucket::new),
+            down: limit.down.map(TokenBucket::new),
+        })))
+    }
+
+    fn up(&self) -> Option<&TokenBucket> {
+        self.0.as_ref().and_then(|b| b.up.as_ref())
+    }
+
+    fn down(&self) -> Option<&TokenBucket> {
+        self.0.as_ref().and_then(|b| b.down.as_ref())
+    }
+
+    /// Take tokens for a UDP packet from clients, returns `false` if it should be dropped
+    pub fn allow_up(&self, n: usize) -> bool {
+        allow(self.up(), n)
+    }
+
+    /// Take tokens for a UDP packet to clients, returns `false` if it should be dropped
+    pub fn allow_down(&self, n: usize) -> bool {
+        allow(self.down(), n)
+    }
+}
+
+fn allow(bucket: Option<&TokenBucket>, n: usize) -> bool {
+    bucket.map_or(true, |b| b.allow(n))
+}
+
+/// Speed limits of a server, which hands out buckets to clients by `SpeedLimit::scope`
+#[derive(Clone, Debug, Default)]
+pub struct SpeedLimiter {
+    limit: Option<SpeedLimit>,
+    /// Buckets shared by all clients, for `SpeedLimitScope::Server`
+    shared: SpeedBuckets,
+    /// Buckets of clients (port is 0) or UDP associations, kept until all their connections are closed
+    peers: Arc<SpinMutex<HashMap<SocketAddr, Weak<BucketPair>>>>,
+}
+
+impl SpeedLimiter {
+    /// Create a limiter for `limit`
+    pub fn new(limit: SpeedLimit) -> SpeedLimiter {
+        SpeedLimiter {
+            limit: Some(limit),
+            shared: SpeedBuckets::new(&limit),
+            peers: Arc::new(SpinMutex::new(HashMap::new())),
+        }
+    }
+
+    /// Buckets of a TCP connection from `peer_addr`
+    pub fn tcp_buckets(&self, peer_addr: &SocketAddr) -> SpeedBuckets {
+        match self.limit {
+            None => SpeedBuckets::default(),
+            Some(ref limit) => match limit.scope {
+                SpeedLimitScope::Server => self.shared.clone(),
+                SpeedLimitScope::Client => self.peer_buckets(limit, SocketAddr::new(peer_addr.ip(), 0)),
+                SpeedLimitScope::Connection => SpeedBuckets::new(limit),
+            },
+        }
+    }
+
+    /// Buckets of UDP packets from `peer_addr`, a UDP association is identified by the client's address
+    pub fn udp_buckets(&self, peer_addr: &SocketAddr) -> SpeedBuckets {
+        match self.limit {
+            None => SpeedBuckets::default(),
+            Some(ref limit) => match limit.scope {
+                SpeedLimitScope::Server => self.shared.clone(),
+                SpeedLimitScope::Client => self.peer_buckets(limit, SocketAddr::new(peer_addr.ip(), 0)),
+                SpeedLimitScope::Connection => self.peer_buckets(limit, *peer_addr),
+            },
+        }
+    }
+
+    fn peer_buckets(&self, limit: &SpeedLimit, key: SocketAddr) -> SpeedBuckets {
+        let mut peers = self.peers.lock();
+        if let Some(pair) = peers.get(&key).and_then(Weak::upgrade) {
+            return SpeedBuckets(Some(pair));
+        }
+
+        // Buckets of closed peers are dropped
+        peers.retain(|_, pair| pair.strong_count() > 0);
+
+        let buckets = SpeedBuckets::new(limit);
+        if let Some(ref pair) = buckets.0 {
+            peers.insert(key, Arc::downgrade(pair));
+        }
+        buckets
+    }
+}
+
+/// Wait until `bucket` is not empty
+pub(crate) fn poll_bucket(bucket: &TokenBucket, sleep: &mut Option<Pin<Box<Sleep>>>, cx: &mut Context<'_>) -> Poll<()> {
+    loop {
+        if let Some(ref mut s) = *sleep {
+            ready!(s.as_mut().poll(cx));
+            *sleep = None;
+        }
+
+        match bucket.delay() {
+            Some(d) => *sleep = Some(Box::pin(time::sleep(d))),
+            None => return Poll::Ready(()),
+        }
+    }
+}
+
+/// Stream of a client with speed limits, reading is limited by `up` and writing is limited by `down`
+#[pin_project]
+pub struct SpeedLimitedStream<S> {
+    #[pin]
+    stream: S,
+    buckets: SpeedBuckets,
+    read_sleep: Option<Pin<Box<Sleep>>>,
+    write_sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> SpeedLimitedStream<S> {
+    pub fn new(stream: S, buckets: SpeedBuckets) -> SpeedLimitedStream<S> {
+        SpeedLimitedStream {
+            stream,
+            buckets,
+            read_sleep: None,
+            write_sleep: None,
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S> AsyncRead for SpeedLimitedStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+
+        let bucket = match this.buckets.up() {
+            Some(b) => b,
+            None => return this.stream.poll_read(cx, buf),
+        };
+        ready!(poll_bucket(bucket, this.read_sleep, cx));
+
+        let before_remain = buf.remaining();
+        ready!(this.stream.poll_read(cx, buf))?;
+        bucket.consume(before_remain - buf.remaining());
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for SpeedLimitedStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+
+        let bucket = match this.buckets.down() {
+            Some(b) => b,
+            None => return this.stream.poll_write(cx, buf),
+        };
+        ready!(poll_bucket(bucket, this.write_sleep, cx));
+
+        let n = ready!(this.stream.poll_write(cx, buf))?;
+        bucket.consume(n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_shutdown(cx)
+    }
+}
+
+impl<S> Deref for SpeedLimitedStream<S> {
+    type Target = S;
+
+    fn deref(&self) -> &Self::Target {
+        &self.stream
+    }
+}
+
+impl<S> DerefMut for SpeedLimitedStream<S> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.stream
+    }
+}
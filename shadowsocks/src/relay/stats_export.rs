@@ -0,0 +1,329 @@
+//! Export of flow statistics to time-series backends
+//!
+//! Snapshots of all servers are pushed every `stats_export.interval`, to the backend chosen by the scheme of
+//! `stats_export.url`:
+//!
+//! - `udp://HOST:8089` - InfluxDB line protocol in datagrams
+//! - `http://HOST:8086/write?db=DB` - InfluxDB line protocol posted to the URL, `USER:PASSWORD@` for basic
+//!   authentication
+//! - `statsd://HOST:8125` - StatsD gauges, with DogStatsD tags
+//!
+//! Servers are reported as `shadowsocks,port=8388 tcp_tx=1024i,...`, and users of multi-user servers as
+//! `shadowsocks_user,port=8388,user=alice tcp_tx=768i,...`. Latency histograms are reported as cumulative buckets,
+//! `shadowsocks_latency,port=8388,metric=handshake,le=100 count=12i`, and `le=+Inf` for all observations with
+//! their `sum` in milliseconds. `stats_export.tags` are attached to all of them.
+//! Values are totals since servers are started, rates should be derived by backends.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    io::{self, Error, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{self, TcpStream, UdpSocket},
+    time,
+};
+use url::Url;
+
+use crate::{
+    config::StatsExportConfig,
+    relay::flow::{FlowSnapshot, HistogramSnapshot},
+};
+
+/// Lines are packed into datagrams up to this size, below the common MTU
+const MAX_DATAGRAM_SIZE: usize = 1400;
+
+/// Timeout of posting to HTTP backends
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+const DEFAULT_INFLUXDB_UDP_PORT: u16 = 8089;
+const DEFAULT_STATSD_PORT: u16 = 8125;
+
+/// Export snapshots taken by `snapshot` to the backend of `config`, every `config.interval`
+pub async fn run<F>(config: StatsExportConfig, snapshot: F)
+where
+    F: Fn() -> FlowSnapshot,
+{
+    // Credentials in the URL are not logged
+    let host = config.url.host_str().unwrap_or_default();
+    let endpoint = match config.url.port() {
+        Some(port) => format!("{}://{}:{}", config.url.scheme(), host, port),
+        None => format!("{}://{}", config.url.scheme(), host),
+    };
+    info!(
+        "exporting statistics to {} every {}s",
+        endpoint,
+        config.interval.as_secs()
+    );
+
+    loop {
+        time::sleep(config.interval).await;
+
+        let snapshot = snapshot();
+        match export(&config, &snapshot).await {
+            Ok(()) => debug!(
+                "exported statistics of {} servers to {}",
+                snapshot.servers.len(),
+                endpoint
+            ),
+            Err(err) => warn!("failed to export statistics to {}, {}", endpoint, err),
+        }
+    }
+}
+
+async fn export(config: &StatsExportConfig, snapshot: &FlowSnapshot) -> io::Result<()> {
+    match config.url.scheme() {
+        "udp" => {
+            let addr = backend_addr(&config.url, DEFAULT_INFLUXDB_UDP_PORT);
+            send_datagrams(&addr, &influxdb_lines(snapshot, &config.tags)).await
+        }
+        "http" => {
+            let body = influxdb_lines(snapshot, &config.tags).join("\n");
+            match time::timeout(HTTP_TIMEOUT, post(&config.url, &body)).await {
+                Ok(r) => r,
+                Err(..) => Err(Error::new(ErrorKind::TimedOut, "HTTP request timed out")),
+            }
+        }
+        "statsd" => {
+            let addr = backend_addr(&config.url, DEFAULT_STATSD_PORT);
+            send_datagrams(&addr, &statsd_lines(snapshot, &config.tags)).await
+        }
+        scheme => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("unsupported backend {}", scheme),
+        )),
+    }
+}
+
+/// `HOST:PORT` of the backend, IPv6 addresses are already bracketed by `Url`
+fn backend_addr(url: &Url, default_port: u16) -> String {
+    let port = url.port_or_known_default().unwrap_or(default_port);
+    format!("{}:{}", url.host_str().unwrap_or_default(), port)
+}
+
+/// Escape commas, spaces and equal signs in tags of InfluxDB line protocol
+fn escape_influxdb_tag(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, ',' | ' ' | '=') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn influxdb_lines(snapshot: &FlowSnapshot, tags: &BTreeMap<String, String>) -> Vec<String> {
+    let mut common_tags = String::new();
+    for (k, v) in tags {
+        let _ = write!(common_tags, ",{}={}", escape_influxdb_tag(k), escape_influxdb_tag(v));
+    }
+    // Nanoseconds, the default precision
+    let timestamp = snapshot.timestamp * 1_000_000_000;
+
+    let mut lines = Vec::new();
+    for server in &snapshot.servers {
+        lines.push(format!(
+            "shadowsocks,port={}{} tcp_tx={}i,tcp_rx={}i,udp_tx={}i,udp_rx={}i,\
+             connections={}i,udp_associations={}i {}",
+            server.port,
+            common_tags,
+            server.tcp_tx,
+            server.tcp_rx,
+            server.udp_tx,
+            server.udp_rx,
+            server.connections,
+            server.udp_associations,
+            timestamp
+        ));
+
+        let histograms = [
+            ("handshake", &server.latency.handshake),
+            ("first_byte", &server.latency.first_byte),
+            ("duration", &server.latency.duration),
+        ];
+        for (metric, histogram) in histograms.iter() {
+            let prefix = format!("shadowsocks_latency,port={},metric={}", server.port, metric);
+            influxdb_histogram_lines(&mut lines, &prefix, &common_tags, histogram, timestamp);
+        }
+
+        for (name, user) in &server.users {
+            lines.push(format!(
+                "shadowsocks_user,port={},user={}{} tcp_tx={}i,tcp_rx={}i,udp_tx={}i,udp_rx={}i {}",
+                server.port,
+                escape_influxdb_tag(name),
+                common_tags,
+                user.tcp_tx,
+                user.tcp_rx,
+                user.udp_tx,
+                user.udp_rx,
+                timestamp
+            ));
+        }
+    }
+    lines
+}
+
+fn influxdb_histogram_lines(
+    lines: &mut Vec<String>,
+    prefix: &str,
+    common_tags: &str,
+    histogram: &HistogramSnapshot,
+    timestamp: u64,
+) {
+    for bucket in &histogram.buckets {
+        lines.push(format!(
+            "{},le={}{} count={}i {}",
+            prefix, bucket.le, common_tags, bucket.count, timestamp
+        ));
+    }
+    lines.push(format!(
+        "{},le=+Inf{} count={}i,sum={}i {}",
+        prefix, common_tags, histogram.count, histogram.sum, timestamp
+    ));
+}
+
+/// DogStatsD tags are separated by commas, and the packet by `|`
+fn escape_statsd_tag(s: &str) -> String {
+    s.replace(|c: char| matches!(c, ',' | '|' | '#'), "_")
+}
+
+fn statsd_lines(snapshot: &FlowSnapshot, tags: &BTreeMap<String, String>) -> Vec<String> {
+    let mut common_tags = String::new();
+    for (k, v) in tags {
+        let _ = write!(common_tags, ",{}:{}", escape_statsd_tag(k), escape_statsd_tag(v));
+    }
+
+    let mut lines = Vec::new();
+    for server in &snapshot.servers {
+        let server_tags = format!("port:{}{}", server.port, common_tags);
+        let gauges = [
+            ("tcp_tx", server.tcp_tx),
+            ("tcp_rx", server.tcp_rx),
+            ("udp_tx", server.udp_tx),
+            ("udp_rx", server.udp_rx),
+            ("connections", server.connections),
+            ("udp_associations", server.udp_associations),
+        ];
+        for (name, value) in gauges.iter() {
+            lines.push(format!("shadowsocks.{}:{}|g|#{}", name, value, server_tags));
+        }
+
+        // Buckets are left to InfluxDB, StatsD aggregates timings by itself
+        let histograms = [
+            ("handshake", &server.latency.handshake),
+            ("first_byte", &server.latency.first_byte),
+            ("duration", &server.latency.duration),
+        ];
+        for (metric, histogram) in histograms.iter() {
+            lines.push(format!(
+                "shadowsocks.latency.{}.count:{}|g|#{}",
+                metric, histogram.count, server_tags
+            ));
+            lines.push(format!(
+                "shadowsocks.latency.{}.sum:{}|g|#{}",
+                metric, histogram.sum, server_tags
+            ));
+        }
+
+        for (user_name, user) in &server.users {
+            let user_tags = format!(
+                "port:{},user:{}{}",
+                server.port,
+                escape_statsd_tag(user_name),
+                common_tags
+            );
+            let gauges = [
+                ("tcp_tx", user.tcp_tx),
+                ("tcp_rx", user.tcp_rx),
+                ("udp_tx", user.udp_tx),
+                ("udp_rx", user.udp_rx),
+            ];
+            for (name, value) in gauges.iter() {
+                lines.push(format!("shadowsocks.user.{}:{}|g|#{}", name, value, user_tags));
+            }
+        }
+    }
+    lines
+}
+
+/// Send `lines` to `addr`, packed into datagrams separated by new lines
+async fn send_datagrams(addr: &str, lines: &[String]) -> io::Result<()> {
+    let target = match net::lookup_host(addr).await?.next() {
+        Some(a) => a,
+        None => {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("{} could not be resolved", addr),
+            ))
+        }
+    };
+    let bind_addr = match target {
+        SocketAddr::V4(..) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        SocketAddr::V6(..) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(target).await?;
+
+    let mut datagram = String::with_capacity(MAX_DATAGRAM_SIZE);
+    for line in lines {
+        if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM_SIZE {
+            socket.send(datagram.as_bytes()).await?;
+            datagram.clear();
+        }
+        if !datagram.is_empty() {
+            datagram.push('\n');
+        }
+        datagram.push_str(line);
+    }
+    if !datagram.is_empty() {
+        socket.send(datagram.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+/// Post `body` to `url` in HTTP/1.1, the response must be successful
+async fn post(url: &Url, body: &str) -> io::Result<()> {
+    let mut stream = TcpStream::connect(backend_addr(url, 80)).await?;
+
+    let mut path = url.path().to_owned();
+    if let Some(query) = url.query() {
+        path.push('?');
+        path.push_str(query);
+    }
+
+    let mut req = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\
+         Content-Length: {}\r\nConnection: close\r\n",
+        path,
+        url.host_str().unwrap_or_default(),
+        body.len()
+    );
+    if !url.username().is_empty() {
+        let credentials = format!("{}:{}", url.username(), url.password().unwrap_or_default());
+        let _ = write!(req, "Authorization: Basic {}\r\n", base64::encode(credentials));
+    }
+    req.push_str("\r\n");
+    req.push_str(body);
+    stream.write_all(req.as_bytes()).await?;
+
+    // Connection is closed by the backend after the response
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp).await?;
+
+    let status_line = resp.split(|b| *b == b'\n').next().unwrap_or_default();
+    let status_line = String::from_utf8_lossy(status_line);
+    match status_line.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(Error::new(
+            ErrorKind::Other,
+            format!("unexpected response \"{}\"", status_line.trim()),
+        )),
+    }
+}
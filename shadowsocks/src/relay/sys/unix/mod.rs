@@ -4,12 +4,13 @@ use std::{
     io::{self, Error, ErrorKind},
     mem,
     net::{SocketAddr, SocketAddrV4, SocketAddrV6},
+    time::Duration,
 };
 #[cfg(any(target_os = "android"))]
 use std::{os::unix::io::RawFd, path::Path};
 
 use cfg_if::cfg_if;
-use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
 
 use crate::config::Config;
 
@@ -145,3 +146,458 @@ pub async fn create_outbound_udp_socket(addr: &SocketAddr, config: &Config) -> i
 pub async fn create_udp_socket(addr: &SocketAddr) -> io::Result<UdpSocket> {
     UdpSocket::bind(addr).await
 }
+
+cfg_if! {
+    if #[cfg(any(target_os = "linux", target_os = "android"))] {
+        /// Parse `some avg10` from PSI (Pressure Stall Information) file's content
+        fn parse_psi_some_avg10(content: &str) -> Option<f64> {
+            let line = content.lines().find(|line| line.starts_with("some "))?;
+            line.split_whitespace()
+                .find_map(|item| item.strip_prefix("avg10="))
+                .and_then(|v| v.parse::<f64>().ok())
+        }
+
+        /// Current memory pressure of the system, in percentage
+        ///
+        /// Reads PSI (Pressure Stall Information, Linux 4.20+) of the current cgroup (v2), or the whole system.
+        /// Returns `None` if it is not available.
+        pub fn memory_pressure() -> io::Result<Option<f64>> {
+            const PSI_PATHS: [&str; 2] = ["/sys/fs/cgroup/memory.pressure", "/proc/pressure/memory"];
+
+            for path in &PSI_PATHS {
+                match std::fs::read_to_string(path) {
+                    Ok(content) => return Ok(parse_psi_some_avg10(&content)),
+                    Err(ref err) if err.kind() == ErrorKind::NotFound => continue,
+                    Err(err) => return Err(err),
+                }
+            }
+
+            Ok(None)
+        }
+    } else {
+        /// Current memory pressure of the system, in percentage
+        ///
+        /// Not supported on this platform, always returns `None`
+        pub fn memory_pressure() -> io::Result<Option<f64>> {
+            Ok(None)
+        }
+    }
+}
+
+cfg_if! {
+    if #[cfg(any(target_os = "linux", target_os = "android"))] {
+        /// Prefer temporary (privacy, RFC 4941) addresses as source address of an IPv6 socket
+        pub fn set_ipv6_prefer_temporary(socket: &TcpSocket) -> io::Result<()> {
+            // linux/in6.h
+            const IPV6_ADDR_PREFERENCES: libc::c_int = 72;
+            const IPV6_PREFER_SRC_TMP: libc::c_int = 0x0001;
+
+            let pref = IPV6_PREFER_SRC_TMP;
+            let ret = unsafe {
+                libc::setsockopt(
+                    socket.as_raw_fd(),
+                    libc::IPPROTO_IPV6,
+                    IPV6_ADDR_PREFERENCES,
+                    &pref as *const _ as *const _,
+                    mem::size_of_val(&pref) as libc::socklen_t,
+                )
+            };
+            if ret != 0 {
+                return Err(Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        /// Allow binding to addresses that are not assigned to any local interfaces (`IP_FREEBIND`)
+        ///
+        /// Required for binding to addresses in a routed prefix
+        pub fn set_ip_freebind(socket: &TcpSocket) -> io::Result<()> {
+            let enable: libc::c_int = 1;
+            let ret = unsafe {
+                libc::setsockopt(
+                    socket.as_raw_fd(),
+                    libc::SOL_IP,
+                    libc::IP_FREEBIND,
+                    &enable as *const _ as *const _,
+                    mem::size_of_val(&enable) as libc::socklen_t,
+                )
+            };
+            if ret != 0 {
+                return Err(Error::last_os_error());
+            }
+            Ok(())
+        }
+    } else {
+        /// Prefer temporary (privacy, RFC 4941) addresses as source address of an IPv6 socket
+        ///
+        /// Not supported on this platform
+        pub fn set_ipv6_prefer_temporary(_socket: &TcpSocket) -> io::Result<()> {
+            Err(Error::new(ErrorKind::Other, "IPV6_ADDR_PREFERENCES is not supported on this platform"))
+        }
+
+        /// Allow binding to addresses that are not assigned to any local interfaces
+        ///
+        /// Not supported on this platform
+        pub fn set_ip_freebind(_socket: &TcpSocket) -> io::Result<()> {
+            Err(Error::new(ErrorKind::Other, "IP_FREEBIND is not supported on this platform"))
+        }
+    }
+}
+
+cfg_if! {
+    if #[cfg(any(target_os = "linux", target_os = "android"))] {
+        /// Accept connections only after data has arrived, or `timeout` elapsed (`TCP_DEFER_ACCEPT`)
+        pub fn set_defer_accept(listener: &TcpListener, timeout: Duration) -> io::Result<()> {
+            let secs = timeout.as_secs().max(1).min(libc::c_int::max_value() as u64) as libc::c_int;
+            let ret = unsafe {
+                libc::setsockopt(
+                    listener.as_raw_fd(),
+                    libc::IPPROTO_TCP,
+                    libc::TCP_DEFER_ACCEPT,
+                    &secs as *const _ as *const _,
+                    mem::size_of_val(&secs) as libc::socklen_t,
+                )
+            };
+            if ret != 0 {
+                return Err(Error::last_os_error());
+            }
+            Ok(())
+        }
+    } else if #[cfg(target_os = "freebsd")] {
+        /// Accept connections only after data has arrived, with the `dataready` accept filter (`accf_data(9)`)
+        ///
+        /// Accept filters don't have timeout, `timeout` is ignored.
+        /// Listener must be listening, and the `accf_data` kernel module must be loaded.
+        pub fn set_defer_accept(listener: &TcpListener, _timeout: Duration) -> io::Result<()> {
+            use std::os::unix::io::AsRawFd;
+
+            let mut arg: libc::accept_filter_arg = unsafe { mem::zeroed() };
+            for (dst, src) in arg.af_name.iter_mut().zip(b"dataready".iter()) {
+                *dst = *src as libc::c_char;
+            }
+
+            let ret = unsafe {
+                libc::setsockopt(
+                    listener.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_ACCEPTFILTER,
+                    &arg as *const _ as *const _,
+                    mem::size_of_val(&arg) as libc::socklen_t,
+                )
+            };
+            if ret != 0 {
+                return Err(Error::last_os_error());
+            }
+            Ok(())
+        }
+    } else {
+        /// Accept connections only after data has arrived
+        ///
+        /// Not supported on this platform
+        pub fn set_defer_accept(_listener: &TcpListener, _timeout: Duration) -> io::Result<()> {
+            Err(Error::new(ErrorKind::Other, "deferred accept is not supported on this platform"))
+        }
+    }
+}
+
+/// IPv4-mapped IPv6 addresses are compared as IPv4 addresses
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(ref v6) if v6.ip().segments()[..6] == [0, 0, 0, 0, 0, 0xFFFF] => {
+            let v4 = v6.ip().to_ipv4().expect("IPv4-mapped address");
+            SocketAddr::new(v4.into(), v6.port())
+        }
+        addr => addr,
+    }
+}
+
+cfg_if! {
+    if #[cfg(any(target_os = "linux", target_os = "android"))] {
+        /// Parse `ADDR:PORT` in `/proc/net/tcp{,6}`, addresses are printed in 32-bit words of native byte order
+        fn parse_proc_net_addr(s: &str) -> Option<SocketAddr> {
+            let mut sp = s.splitn(2, ':');
+            let (addr, port) = (sp.next()?, sp.next()?);
+            let port = u16::from_str_radix(port, 16).ok()?;
+
+            let mut octets = Vec::with_capacity(16);
+            for i in (0..addr.len()).step_by(8) {
+                let word = u32::from_str_radix(addr.get(i..i + 8)?, 16).ok()?;
+                octets.extend_from_slice(&word.to_ne_bytes());
+            }
+
+            let ip = match octets.len() {
+                4 => std::net::IpAddr::from([octets[0], octets[1], octets[2], octets[3]]),
+                16 => {
+                    let mut v6 = [0u8; 16];
+                    v6.copy_from_slice(&octets);
+                    std::net::IpAddr::from(v6)
+                }
+                _ => return None,
+            };
+            Some(canonical_addr(SocketAddr::new(ip, port)))
+        }
+
+        /// Inode of the socket bound to `local`, in `/proc/net/tcp` and `/proc/net/tcp6`
+        fn tcp_socket_inode(local: SocketAddr) -> io::Result<Option<u64>> {
+            for path in &["/proc/net/tcp", "/proc/net/tcp6"] {
+                let content = match std::fs::read_to_string(path) {
+                    Ok(c) => c,
+                    Err(ref err) if err.kind() == ErrorKind::NotFound => continue,
+                    Err(err) => return Err(err),
+                };
+
+                // sl local_address rem_address st tx_queue:rx_queue tr:tm->when retrnsmt uid timeout inode
+                for line in content.lines().skip(1) {
+                    let fields: Vec<&str> = line.split_whitespace().collect();
+                    if fields.len() < 10 {
+                        continue;
+                    }
+                    if parse_proc_net_addr(fields[1]) == Some(local) {
+                        return Ok(fields[9].parse().ok());
+                    }
+                }
+            }
+            Ok(None)
+        }
+
+        /// Name of the process that owns the TCP socket bound to `peer_addr` on this host,
+        /// which is the peer of a connection accepted by local listeners
+        ///
+        /// Looked up in `/proc`, only processes that this process is allowed to inspect could be found.
+        pub fn tcp_peer_process_name(peer_addr: SocketAddr) -> io::Result<Option<String>> {
+            use std::fs;
+
+            let inode = match tcp_socket_inode(canonical_addr(peer_addr))? {
+                Some(inode) => inode,
+                None => return Ok(None),
+            };
+            let socket_link = format!("socket:[{}]", inode);
+
+            for entry in fs::read_dir("/proc")?.flatten() {
+                let is_pid = entry.file_name().to_str().map(|n| n.bytes().all(|b| b.is_ascii_digit()));
+                if is_pid != Some(true) {
+                    continue;
+                }
+
+                let fds = match fs::read_dir(entry.path().join("fd")) {
+                    Ok(fds) => fds,
+                    // Processes of other users
+                    Err(..) => continue,
+                };
+                for fd in fds.flatten() {
+                    match fs::read_link(fd.path()) {
+                        Ok(link) if link.as_os_str() == socket_link.as_str() => {}
+                        _ => continue,
+                    }
+
+                    // `comm` is truncated to 15 bytes, prefer the executable's name
+                    if let Ok(exe) = fs::read_link(entry.path().join("exe")) {
+                        if let Some(name) = exe.file_name().and_then(|n| n.to_str()) {
+                            return Ok(Some(name.to_owned()));
+                        }
+                    }
+                    let comm = fs::read_to_string(entry.path().join("comm"))?;
+                    return Ok(Some(comm.trim_end().to_owned()));
+                }
+            }
+
+            Ok(None)
+        }
+    } else if #[cfg(target_os = "macos")] {
+        use std::ptr;
+
+        use libc::{c_int, c_void};
+
+        // Definitions in <libproc.h> and <sys/proc_info.h>
+        const PROC_ALL_PIDS: u32 = 1;
+        const PROC_PIDLISTFDS: c_int = 1;
+        const PROC_PIDFDSOCKETINFO: c_int = 3;
+        const PROX_FDTYPE_SOCKET: u32 = 2;
+        const SOCKINFO_TCP: c_int = 2;
+        const INI_IPV4: u8 = 0x1;
+        // 2 * MAXCOMLEN
+        const PROC_NAME_MAX_LEN: usize = 32;
+
+        extern "C" {
+            fn proc_listpids(type_: u32, typeinfo: u32, buffer: *mut c_void, buffersize: c_int) -> c_int;
+            fn proc_pidinfo(pid: c_int, flavor: c_int, arg: u64, buffer: *mut c_void, buffersize: c_int) -> c_int;
+            fn proc_pidfdinfo(pid: c_int, fd: c_int, flavor: c_int, buffer: *mut c_void, buffersize: c_int) -> c_int;
+            fn proc_name(pid: c_int, buffer: *mut c_void, buffersize: u32) -> c_int;
+        }
+
+        /// `struct proc_fdinfo`
+        #[repr(C)]
+        #[derive(Clone, Copy)]
+        struct ProcFdInfo {
+            proc_fd: i32,
+            proc_fdtype: u32,
+        }
+
+        /// `struct in_sockinfo`, which is also the head of `struct tcp_sockinfo`
+        #[repr(C)]
+        #[allow(dead_code)]
+        struct InSockInfo {
+            insi_fport: c_int,
+            insi_lport: c_int,
+            insi_gencnt: u64,
+            insi_flags: u32,
+            insi_flow: u32,
+            insi_vflag: u8,
+            insi_ip_ttl: u8,
+            rfu_1: u32,
+            insi_faddr: [u8; 16],
+            insi_laddr: [u8; 16],
+            insi_v4: u8,
+            insi_v6: [u32; 3],
+        }
+
+        /// `struct socket_fdinfo`, fields that are not used are kept as opaque bytes
+        #[repr(C)]
+        #[allow(dead_code)]
+        struct SocketFdInfo {
+            // struct proc_fileinfo
+            pfi: [u8; 24],
+            // struct vinfo_stat
+            soi_stat: [u8; 136],
+            soi_so: u64,
+            soi_pcb: u64,
+            soi_type: c_int,
+            soi_protocol: c_int,
+            soi_family: c_int,
+            // soi_options, soi_linger, soi_state, soi_qlen, soi_incqlen, soi_qlimit, soi_timeo, soi_error
+            soi_shorts: [i16; 8],
+            soi_oobmark: u32,
+            // struct sockbuf_info
+            soi_rcv: [u32; 6],
+            soi_snd: [u32; 6],
+            soi_kind: c_int,
+            rfu_1: u32,
+            soi_proto: InSockInfo,
+            // Rest of the `soi_proto` union, its largest member is `struct un_sockinfo` in 528 bytes.
+            // The kernel only checks that the buffer is not smaller than its definition
+            soi_proto_rest: [u8; 512],
+        }
+
+        /// PIDs of all processes
+        fn list_pids() -> io::Result<Vec<c_int>> {
+            let size = unsafe { proc_listpids(PROC_ALL_PIDS, 0, ptr::null_mut(), 0) };
+            if size <= 0 {
+                return Err(Error::last_os_error());
+            }
+
+            // Room for processes created in the meantime
+            let mut pids = vec![0 as c_int; size as usize / mem::size_of::<c_int>() + 64];
+            let size = unsafe {
+                proc_listpids(
+                    PROC_ALL_PIDS,
+                    0,
+                    pids.as_mut_ptr() as *mut c_void,
+                    (pids.len() * mem::size_of::<c_int>()) as c_int,
+                )
+            };
+            if size <= 0 {
+                return Err(Error::last_os_error());
+            }
+            pids.truncate(size as usize / mem::size_of::<c_int>());
+            pids.retain(|&pid| pid > 0);
+
+            Ok(pids)
+        }
+
+        /// Socket descriptors of process `pid`, empty if it couldn't be inspected
+        fn list_socket_fds(pid: c_int) -> Vec<c_int> {
+            let size = unsafe { proc_pidinfo(pid, PROC_PIDLISTFDS, 0, ptr::null_mut(), 0) };
+            if size <= 0 {
+                return Vec::new();
+            }
+
+            // Room for descriptors opened in the meantime
+            let empty = ProcFdInfo {
+                proc_fd: 0,
+                proc_fdtype: 0,
+            };
+            let mut fds = vec![empty; size as usize / mem::size_of::<ProcFdInfo>() + 16];
+            let size = unsafe {
+                proc_pidinfo(
+                    pid,
+                    PROC_PIDLISTFDS,
+                    0,
+                    fds.as_mut_ptr() as *mut c_void,
+                    (fds.len() * mem::size_of::<ProcFdInfo>()) as c_int,
+                )
+            };
+            if size <= 0 {
+                return Vec::new();
+            }
+            fds.truncate(size as usize / mem::size_of::<ProcFdInfo>());
+
+            fds.iter()
+                .filter(|fd| fd.proc_fdtype == PROX_FDTYPE_SOCKET)
+                .map(|fd| fd.proc_fd)
+                .collect()
+        }
+
+        /// Local address of descriptor `fd` of process `pid`, if it is a TCP socket
+        fn tcp_socket_local_addr(pid: c_int, fd: c_int) -> Option<SocketAddr> {
+            let mut info: SocketFdInfo = unsafe { mem::zeroed() };
+            let ret = unsafe {
+                proc_pidfdinfo(
+                    pid,
+                    fd,
+                    PROC_PIDFDSOCKETINFO,
+                    &mut info as *mut _ as *mut c_void,
+                    mem::size_of::<SocketFdInfo>() as c_int,
+                )
+            };
+            if ret <= 0 || info.soi_kind != SOCKINFO_TCP {
+                return None;
+            }
+
+            let ini = &info.soi_proto;
+            // Stored in network byte order
+            let port = u16::from_be(ini.insi_lport as u16);
+            let ip = if ini.insi_vflag & INI_IPV4 != 0 {
+                // `struct in4in6_addr`, the IPv4 address is in the last 4 bytes
+                let a = &ini.insi_laddr[12..];
+                std::net::IpAddr::from([a[0], a[1], a[2], a[3]])
+            } else {
+                std::net::IpAddr::from(ini.insi_laddr)
+            };
+            Some(canonical_addr(SocketAddr::new(ip, port)))
+        }
+
+        /// Name of the process that owns the TCP socket bound to `peer_addr` on this host,
+        /// which is the peer of a connection accepted by local listeners
+        ///
+        /// Looked up with `libproc`, only processes that this process is allowed to inspect could be found.
+        pub fn tcp_peer_process_name(peer_addr: SocketAddr) -> io::Result<Option<String>> {
+            let peer_addr = canonical_addr(peer_addr);
+
+            for pid in list_pids()? {
+                for fd in list_socket_fds(pid) {
+                    if tcp_socket_local_addr(pid, fd) != Some(peer_addr) {
+                        continue;
+                    }
+
+                    let mut name = [0u8; PROC_NAME_MAX_LEN + 1];
+                    let len = unsafe { proc_name(pid, name.as_mut_ptr() as *mut c_void, name.len() as u32) };
+                    if len <= 0 {
+                        return Err(Error::last_os_error());
+                    }
+                    return Ok(Some(String::from_utf8_lossy(&name[..len as usize]).into_owned()));
+                }
+            }
+
+            Ok(None)
+        }
+    } else {
+        /// Name of the process that owns the TCP socket bound to `peer_addr` on this host,
+        /// which is the peer of a connection accepted by local listeners
+        ///
+        /// Not supported on this platform, always returns `None`
+        pub fn tcp_peer_process_name(_peer_addr: SocketAddr) -> io::Result<Option<String>> {
+            Ok(None)
+        }
+    }
+}
@@ -1,6 +1,6 @@
-use std::{io, mem, net::SocketAddr, os::windows::io::AsRawSocket, ptr};
+use std::{io, mem, net::SocketAddr, os::windows::io::AsRawSocket, ptr, time::Duration};
 
-use tokio::net::{TcpStream, UdpSocket};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
 use winapi::{
     shared::minwindef::{BOOL, DWORD, FALSE, LPDWORD, LPVOID},
     um::{
@@ -11,6 +11,10 @@ use winapi::{
 
 use crate::config::Config;
 
+mod named_pipe;
+
+pub use self::named_pipe::{send_to_named_pipe, NamedPipeListener, NamedPipePeer, NAMED_PIPE_PREFIX};
+
 /// Create a `UdpSocket` binded to `addr`
 ///
 /// It also disables `WSAECONNRESET` for UDP socket
@@ -67,3 +71,149 @@ pub async fn tcp_stream_connect(saddr: &SocketAddr, _context: &Config) -> io::Re
 pub async fn create_outbound_udp_socket(addr: &SocketAddr, _context: &Config) -> io::Result<UdpSocket> {
     create_udp_socket(addr).await
 }
+
+/// Current memory pressure of the system, in percentage
+///
+/// Memory load reported by `GlobalMemoryStatusEx`
+pub fn memory_pressure() -> io::Result<Option<f64>> {
+    use winapi::um::sysinfoapi::{GlobalMemoryStatusEx, MEMORYSTATUSEX};
+
+    unsafe {
+        let mut status: MEMORYSTATUSEX = mem::zeroed();
+        status.dwLength = mem::size_of::<MEMORYSTATUSEX>() as DWORD;
+
+        if GlobalMemoryStatusEx(&mut status) == FALSE {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Some(status.dwMemoryLoad as f64))
+    }
+}
+
+/// Prefer temporary (privacy, RFC 4941) addresses as source address of an IPv6 socket
+///
+/// Not supported on Windows, temporary addresses are preferred by system policy
+pub fn set_ipv6_prefer_temporary(_socket: &TcpSocket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "IPV6_ADDR_PREFERENCES is not supported on Windows",
+    ))
+}
+
+/// Allow binding to addresses that are not assigned to any local interfaces
+///
+/// Not supported on Windows
+pub fn set_ip_freebind(_socket: &TcpSocket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "IP_FREEBIND is not supported on Windows",
+    ))
+}
+
+/// Accept connections only after data has arrived
+///
+/// Not supported on Windows
+pub fn set_defer_accept(_listener: &TcpListener, _timeout: Duration) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "deferred accept is not supported on Windows",
+    ))
+}
+
+/// Name of the process that owns the TCP socket bound to `peer_addr` on this host,
+/// which is the peer of a connection accepted by local listeners
+///
+/// Looked up in the TCP table of `GetExtendedTcpTable`, like `firefox.exe`
+pub fn tcp_peer_process_name(peer_addr: SocketAddr) -> io::Result<Option<String>> {
+    use std::{ffi::OsString, os::windows::ffi::OsStringExt, slice};
+
+    use winapi::{
+        shared::{
+            iprtrmib::TCP_TABLE_OWNER_PID_CONNECTIONS,
+            tcpmib::{MIB_TCP6TABLE_OWNER_PID, MIB_TCPTABLE_OWNER_PID},
+            winerror::{ERROR_INSUFFICIENT_BUFFER, NO_ERROR},
+            ws2def::{AF_INET, AF_INET6},
+        },
+        um::{
+            handleapi::CloseHandle,
+            iphlpapi::GetExtendedTcpTable,
+            processthreadsapi::OpenProcess,
+            winbase::QueryFullProcessImageNameW,
+            winnt::PROCESS_QUERY_LIMITED_INFORMATION,
+        },
+    };
+
+    /// TCP table of address family `af`, in `u32` for alignment
+    unsafe fn tcp_table(af: DWORD) -> io::Result<Vec<u32>> {
+        let mut size: DWORD = 0;
+        let mut table = Vec::new();
+        loop {
+            let ret = GetExtendedTcpTable(
+                table.as_mut_ptr() as LPVOID,
+                &mut size,
+                FALSE,
+                af,
+                TCP_TABLE_OWNER_PID_CONNECTIONS,
+                0,
+            );
+            match ret {
+                NO_ERROR => return Ok(table),
+                // The table may grow between calls
+                ERROR_INSUFFICIENT_BUFFER => table.resize(size as usize / mem::size_of::<u32>() + 1, 0),
+                err => return Err(io::Error::from_raw_os_error(err as i32)),
+            }
+        }
+    }
+
+    let owner_pid = unsafe {
+        match peer_addr {
+            SocketAddr::V4(peer) => {
+                let buf = tcp_table(AF_INET as DWORD)?;
+                let table = &*(buf.as_ptr() as *const MIB_TCPTABLE_OWNER_PID);
+                let rows = slice::from_raw_parts(table.table.as_ptr(), table.dwNumEntries as usize);
+                rows.iter()
+                    .find(|row| {
+                        row.dwLocalAddr.to_ne_bytes() == peer.ip().octets()
+                            && u16::from_be(row.dwLocalPort as u16) == peer.port()
+                    })
+                    .map(|row| row.dwOwningPid)
+            }
+            SocketAddr::V6(peer) => {
+                let buf = tcp_table(AF_INET6 as DWORD)?;
+                let table = &*(buf.as_ptr() as *const MIB_TCP6TABLE_OWNER_PID);
+                let rows = slice::from_raw_parts(table.table.as_ptr(), table.dwNumEntries as usize);
+                rows.iter()
+                    .find(|row| {
+                        row.ucLocalAddr == peer.ip().octets() && u16::from_be(row.dwLocalPort as u16) == peer.port()
+                    })
+                    .map(|row| row.dwOwningPid)
+            }
+        }
+    };
+
+    let pid = match owner_pid {
+        Some(pid) => pid,
+        None => return Ok(None),
+    };
+
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, pid);
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut path = [0u16; 1024];
+        let mut len = path.len() as DWORD;
+        let ret = QueryFullProcessImageNameW(handle, 0, path.as_mut_ptr(), &mut len);
+        CloseHandle(handle);
+        if ret == FALSE {
+            return Err(io::Error::last_os_error());
+        }
+
+        let path = OsString::from_wide(&path[..len as usize]);
+        let name = std::path::Path::new(&path)
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned());
+        Ok(name)
+    }
+}
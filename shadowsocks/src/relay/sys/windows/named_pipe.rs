@@ -0,0 +1,251 @@
+//! Named pipes for the manager, the analogue of UNIX datagram sockets on *nix systems
+//!
+//! tokio doesn't support named pipes, so every pipe instance is served in a blocking thread,
+//! and requests are passed to the manager through a channel.
+//! Every message written by clients is a request, which is answered with at most one message.
+
+use std::{
+    cmp,
+    ffi::OsStr,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, ErrorKind, Read, Write},
+    mem,
+    os::windows::{
+        ffi::OsStrExt,
+        io::{AsRawHandle, FromRawHandle, RawHandle},
+    },
+    ptr,
+    sync::mpsc as std_mpsc,
+    thread,
+};
+
+use log::{debug, error};
+use tokio::{sync::mpsc, task};
+use winapi::{
+    shared::{minwindef::DWORD, winerror::ERROR_PIPE_CONNECTED},
+    um::{
+        handleapi::INVALID_HANDLE_VALUE,
+        namedpipeapi::ConnectNamedPipe,
+        winbase::{
+            CreateNamedPipeW,
+            FILE_FLAG_FIRST_PIPE_INSTANCE,
+            PIPE_ACCESS_DUPLEX,
+            PIPE_READMODE_MESSAGE,
+            PIPE_REJECT_REMOTE_CLIENTS,
+            PIPE_TYPE_MESSAGE,
+            PIPE_UNLIMITED_INSTANCES,
+            PIPE_WAIT,
+        },
+        winnt::HANDLE,
+    },
+};
+
+/// Prefix of named pipes' paths
+pub const NAMED_PIPE_PREFIX: &str = r"\\.\pipe\";
+
+/// Size of pipes' buffers, which is also the maximum size of a message
+const PIPE_BUFFER_SIZE: usize = 65536;
+
+/// Requests waiting for the manager
+const PIPE_REQUEST_CHANNEL_SIZE: usize = 16;
+
+/// Client of a named pipe, replies are sent back through the pipe instance that it is connected to
+#[derive(Debug, Clone)]
+pub struct NamedPipePeer {
+    path: String,
+    reply: Option<std_mpsc::Sender<Vec<u8>>>,
+}
+
+impl NamedPipePeer {
+    /// Check if it is unable to receive replies, like the address of the listener itself
+    pub fn is_unnamed(&self) -> bool {
+        self.reply.is_none()
+    }
+
+    /// Send a reply to this client
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        match self.reply {
+            Some(ref reply) => match reply.send(buf.to_vec()) {
+                Ok(..) => Ok(buf.len()),
+                Err(..) => Err(io::Error::new(ErrorKind::BrokenPipe, "named pipe client disconnected")),
+            },
+            None => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "named pipe peer couldn't be replied",
+            )),
+        }
+    }
+}
+
+impl fmt::Display for NamedPipePeer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.path)
+    }
+}
+
+/// Server of a named pipe, receiving messages from all clients
+pub struct NamedPipeListener {
+    path: String,
+    rx: mpsc::Receiver<(Vec<u8>, NamedPipePeer)>,
+}
+
+impl NamedPipeListener {
+    /// Create a named pipe at `path`, like `\\.\pipe\shadowsocks-manager`
+    ///
+    /// Fails if the pipe is already created by the others
+    pub fn bind(path: &str) -> io::Result<NamedPipeListener> {
+        let pipe = create_pipe_instance(path, true)?;
+
+        let (tx, rx) = mpsc::channel(PIPE_REQUEST_CHANNEL_SIZE);
+        let accept_path = path.to_owned();
+        thread::spawn(move || accept_clients(accept_path, pipe, tx));
+
+        Ok(NamedPipeListener {
+            path: path.to_owned(),
+            rx,
+        })
+    }
+
+    /// Receives a message from clients
+    ///
+    /// The message is truncated if `buf` is not large enough
+    pub async fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, NamedPipePeer)> {
+        match self.rx.recv().await {
+            Some((message, peer)) => {
+                let n = cmp::min(message.len(), buf.len());
+                buf[..n].copy_from_slice(&message[..n]);
+                Ok((n, peer))
+            }
+            None => Err(io::Error::new(ErrorKind::BrokenPipe, "named pipe listener stopped")),
+        }
+    }
+
+    /// Address of this listener, which couldn't be replied
+    pub fn local_addr(&self) -> NamedPipePeer {
+        NamedPipePeer {
+            path: self.path.clone(),
+            reply: None,
+        }
+    }
+}
+
+/// Send a message to the named pipe at `path`, without waiting for replies
+pub async fn send_to_named_pipe(path: &str, buf: &[u8]) -> io::Result<usize> {
+    let path = path.to_owned();
+    let buf = buf.to_vec();
+
+    let result = task::spawn_blocking(move || {
+        let mut pipe = OpenOptions::new().read(true).write(true).open(&path)?;
+        pipe.write_all(&buf)?;
+        Ok(buf.len())
+    })
+    .await;
+
+    match result {
+        Ok(r) => r,
+        Err(err) => Err(io::Error::new(ErrorKind::Other, err)),
+    }
+}
+
+fn create_pipe_instance(path: &str, first: bool) -> io::Result<File> {
+    let name: Vec<u16> = OsStr::new(path).encode_wide().chain(Some(0)).collect();
+
+    let mut open_mode = PIPE_ACCESS_DUPLEX;
+    if first {
+        open_mode |= FILE_FLAG_FIRST_PIPE_INSTANCE;
+    }
+
+    let handle = unsafe {
+        CreateNamedPipeW(
+            name.as_ptr(),
+            open_mode,
+            PIPE_TYPE_MESSAGE | PIPE_READMODE_MESSAGE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+            PIPE_UNLIMITED_INSTANCES,
+            PIPE_BUFFER_SIZE as DWORD,
+            PIPE_BUFFER_SIZE as DWORD,
+            0,
+            ptr::null_mut(),
+        )
+    };
+
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(unsafe { File::from_raw_handle(handle as RawHandle) })
+}
+
+/// Wait until a client is connected to `pipe`
+fn connect_client(pipe: &File) -> io::Result<()> {
+    let ret = unsafe { ConnectNamedPipe(pipe.as_raw_handle() as HANDLE, ptr::null_mut()) };
+    if ret == 0 {
+        let err = io::Error::last_os_error();
+
+        // Client has been connected between creating and waiting
+        if err.raw_os_error() != Some(ERROR_PIPE_CONNECTED as i32) {
+            return Err(err);
+        }
+    }
+
+    Ok(())
+}
+
+fn accept_clients(path: String, mut pipe: File, tx: mpsc::Sender<(Vec<u8>, NamedPipePeer)>) {
+    loop {
+        let connected = connect_client(&pipe);
+
+        // Create the next instance before serving, so that the other clients won't find the pipe busy
+        let next_pipe = match create_pipe_instance(&path, false) {
+            Ok(p) => p,
+            Err(err) => {
+                error!("failed to create named pipe {}, {}", path, err);
+                return;
+            }
+        };
+        let client_pipe = mem::replace(&mut pipe, next_pipe);
+
+        match connected {
+            Ok(..) => {
+                let path = path.clone();
+                let tx = tx.clone();
+                thread::spawn(move || serve_client(path, client_pipe, tx));
+            }
+            Err(err) => debug!("failed to accept named pipe {} client, {}", path, err),
+        }
+    }
+}
+
+fn serve_client(path: String, mut pipe: File, tx: mpsc::Sender<(Vec<u8>, NamedPipePeer)>) {
+    let mut buf = vec![0u8; PIPE_BUFFER_SIZE];
+
+    loop {
+        let n = match pipe.read(&mut buf) {
+            Ok(0) => return,
+            Ok(n) => n,
+            Err(err) => {
+                debug!("named pipe {} client closed, {}", path, err);
+                return;
+            }
+        };
+
+        let (reply_tx, reply_rx) = std_mpsc::channel();
+        let peer = NamedPipePeer {
+            path: path.clone(),
+            reply: Some(reply_tx),
+        };
+
+        if tx.blocking_send((buf[..n].to_vec(), peer)).is_err() {
+            // Manager has stopped
+            return;
+        }
+
+        // Some requests are not answered, the peer is dropped without replying
+        if let Ok(reply) = reply_rx.recv() {
+            if let Err(err) = pipe.write_all(&reply) {
+                debug!("failed to reply named pipe {} client, {}", path, err);
+                return;
+            }
+        }
+    }
+}
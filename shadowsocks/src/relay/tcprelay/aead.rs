@@ -41,7 +41,7 @@ use std::{
     u16,
 };
 
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use futures::ready;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
@@ -58,6 +58,14 @@ enum DecryptReadStep {
     Eof,
 }
 
+/// Candidate keys for identifying which key the stream is encrypted with
+struct KeyIdentify {
+    method: CipherKind,
+    key: Bytes,
+    nonce: Bytes,
+    accepted_keys: Vec<Bytes>,
+}
+
 /// Reader wrapper that will decrypt data automatically
 pub struct DecryptedReader {
     buffer: BytesMut,
@@ -66,6 +74,8 @@ pub struct DecryptedReader {
     buffered: bool,
     tag_size: usize,
     steps: DecryptReadStep,
+    identify: Option<KeyIdentify>,
+    key_index: Option<usize>,
 }
 
 impl DecryptedReader {
@@ -77,7 +87,35 @@ impl DecryptedReader {
             buffered: false,
             tag_size: method.tag_len(),
             steps: DecryptReadStep::Init,
+            identify: None,
+            key_index: None,
+        }
+    }
+
+    /// Creates a new DecryptedReader which accepts `key` and any of `accepted_keys`
+    ///
+    /// The key is chosen by trying to decrypt the first length chunk with each of them
+    pub fn with_accepted_keys(
+        method: CipherKind,
+        key: &[u8],
+        nonce: &[u8],
+        accepted_keys: Vec<Bytes>,
+    ) -> DecryptedReader {
+        let mut reader = DecryptedReader::new(method, key, nonce);
+        if !accepted_keys.is_empty() {
+            reader.identify = Some(KeyIdentify {
+                method,
+                key: Bytes::copy_from_slice(key),
+                nonce: Bytes::copy_from_slice(nonce),
+                accepted_keys,
+            });
         }
+        reader
+    }
+
+    /// Index in `accepted_keys` of the key that decrypts this stream, `None` if it is `key`
+    pub fn key_index(&self) -> Option<usize> {
+        self.key_index
     }
 
     /// Attempt to read decrypted data from reader
@@ -95,6 +133,7 @@ impl DecryptedReader {
     where
         R: AsyncRead + Unpin,
     {
+        // Empty chunks (heartbeats sent by `keep_busy_interval`) are skipped by refilling again
         while !self.buffered || self.pos >= self.buffer.len() {
             // Refill buffer
             match self.steps {
@@ -148,6 +187,10 @@ impl DecryptedReader {
         let mlen = 2 + self.tag_size;
         ready!(self.poll_read_exact_buffered(ctx, r, mlen))?;
 
+        if let Some(identify) = self.identify.take() {
+            self.identify_key(identify, mlen)?;
+        }
+
         // Done reading, decrypt it
         let plen = DecryptedReader::decrypt_length(&mut self.cipher, &mut self.buffer[..mlen])?;
         Poll::Ready(Ok(plen))
@@ -212,6 +255,29 @@ impl DecryptedReader {
         Poll::Ready(Ok(()))
     }
 
+    fn identify_key(&mut self, identify: KeyIdentify, mlen: usize) -> io::Result<()> {
+        let keys = Some(&identify.key).into_iter().chain(identify.accepted_keys.iter());
+        let mut chunk = vec![0u8; mlen];
+
+        for (idx, key) in keys.enumerate() {
+            // Probe with a fresh cipher, decryption consumes a nonce
+            let mut cipher = Cipher::new(identify.method, key, &identify.nonce);
+
+            chunk.copy_from_slice(&self.buffer[..mlen]);
+            if cipher.decrypt_packet(&mut chunk) {
+                self.cipher = Cipher::new(identify.method, key, &identify.nonce);
+                // The first key is the server's key
+                self.key_index = idx.checked_sub(1);
+                return Ok(());
+            }
+        }
+
+        Err(io::Error::new(
+            ErrorKind::Other,
+            "invalid tag-in, no accepted key matches",
+        ))
+    }
+
     fn decrypt_length(cipher: &mut Cipher, m: &mut [u8]) -> io::Result<usize> {
         let plen = {
             if !cipher.decrypt_packet(m) {
@@ -267,6 +333,14 @@ impl EncryptedWriter {
         }
     }
 
+    /// Switch to another key, must be called before anything has been written
+    ///
+    /// The nonce is kept, it is still waiting in the buffer for being sent with the first packet
+    pub fn reset_key(&mut self, method: CipherKind, key: &[u8]) {
+        debug_assert!(matches!(self.steps, EncryptWriteStep::Nothing) && self.buf.len() == method.salt_len());
+        self.cipher = Cipher::new(method, key, &self.buf[..]);
+    }
+
     pub fn poll_write_encrypted<W>(
         &mut self,
         ctx: &mut Context<'_>,
@@ -51,9 +51,12 @@ enum ReadStatus {
 /// A bidirectional stream for communicating with ShadowSocks' server
 pub struct CryptoStream<S> {
     stream: S,
+    method: CipherKind,
     dec: Option<DecryptedReader>,
     enc: EncryptedWriter,
     read_status: ReadStatus,
+    accepted_keys: Vec<Bytes>,
+    key_index: Option<usize>,
 }
 
 impl<S: Unpin> Unpin for CryptoStream<S> {}
@@ -66,7 +69,7 @@ impl<S> CryptoStream<S> {
         let key = svr_cfg.clone_key();
 
         if category == CipherCategory::None {
-            return CryptoStream::<S>::new_none(stream);
+            return CryptoStream::<S>::new_none(stream, method);
         }
 
         let prev_len = match category {
@@ -83,7 +86,7 @@ impl<S> CryptoStream<S> {
                         random_iv_or_salt(&mut iv);
                     }
 
-                    if context.check_nonce_and_set(&iv) {
+                    if context.check_nonce_and_set(&key, &iv) {
                         // IV exist, generate another one
                         continue;
                     }
@@ -99,7 +102,7 @@ impl<S> CryptoStream<S> {
                         random_iv_or_salt(&mut salt);
                     }
 
-                    if context.check_nonce_and_set(&salt) {
+                    if context.check_nonce_and_set(&key, &salt) {
                         // Salt exist, generate another one
                         continue;
                     }
@@ -119,18 +122,35 @@ impl<S> CryptoStream<S> {
 
         CryptoStream {
             stream,
+            method,
             dec: None,
             enc,
             read_status: ReadStatus::WaitIv(context, BytesMut::with_capacity(prev_len).limit(prev_len), method, key),
+            accepted_keys: Vec::new(),
+            key_index: None,
         }
     }
 
-    fn new_none(stream: S) -> CryptoStream<S> {
+    /// Create a new CryptoStream for the server side, also accepting `svr_cfg.accepted_keys()`
+    ///
+    /// Which key the client is using will be known after the first read, by `key_index()`
+    pub fn new_server(context: SharedContext, stream: S, svr_cfg: &ServerConfig) -> CryptoStream<S> {
+        let mut stream = CryptoStream::new(context, stream, svr_cfg);
+        if svr_cfg.method().category() == CipherCategory::Aead {
+            stream.accepted_keys = svr_cfg.accepted_keys();
+        }
+        stream
+    }
+
+    fn new_none(stream: S, method: CipherKind) -> CryptoStream<S> {
         CryptoStream {
             stream,
+            method,
             dec: Some(DecryptedReader::None),
             enc: EncryptedWriter::None,
             read_status: ReadStatus::Established,
+            accepted_keys: Vec::new(),
+            key_index: None,
         }
     }
 
@@ -139,6 +159,18 @@ impl<S> CryptoStream<S> {
         &self.stream
     }
 
+    /// Return a mutable reference to the underlying stream
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Index in `ServerConfig::accepted_keys()` of the key identified from the client's first chunk
+    ///
+    /// `None` if the client is using the server's key
+    pub fn key_index(&self) -> Option<usize> {
+        self.key_index
+    }
+
     /// Consume the CryptoStream and return the internal stream instance
     pub fn into_inner(self) -> S {
         self.stream
@@ -172,7 +204,7 @@ where
             let nonce = buf.get_ref();
 
             // Got iv/salt, check if it is repeated
-            if ctx.check_nonce_and_set(nonce) {
+            if ctx.check_nonce_and_set(key, nonce) {
                 use std::io::{Error, ErrorKind};
 
                 trace!("detected repeated iv/salt {:?}", ByteStr::new(nonce));
@@ -188,7 +220,17 @@ where
                 }
                 CipherCategory::Aead => {
                     trace!("got AEAD cipher salt {:?}", ByteStr::new(nonce));
-                    DecryptedReader::Aead(AeadDecryptedReader::new(method, key, nonce))
+                    if self.accepted_keys.is_empty() {
+                        DecryptedReader::Aead(AeadDecryptedReader::new(method, key, nonce))
+                    } else {
+                        let accepted_keys = self.accepted_keys.clone();
+                        DecryptedReader::Aead(AeadDecryptedReader::with_accepted_keys(
+                            method,
+                            key,
+                            nonce,
+                            accepted_keys,
+                        ))
+                    }
                 }
                 CipherCategory::None => DecryptedReader::None,
             };
@@ -206,7 +248,21 @@ where
 
         match *this.dec.as_mut().unwrap() {
             DecryptedReader::None => Pin::new(&mut this.stream).poll_read(ctx, buf),
-            DecryptedReader::Aead(ref mut r) => r.poll_read_decrypted(ctx, &mut this.stream, buf),
+            DecryptedReader::Aead(ref mut r) => {
+                ready!(r.poll_read_decrypted(ctx, &mut this.stream, buf))?;
+
+                if this.key_index.is_none() {
+                    if let Some(idx) = r.key_index() {
+                        // Reply with the client's key
+                        if let EncryptedWriter::Aead(ref mut w) = this.enc {
+                            w.reset_key(this.method, &this.accepted_keys[idx]);
+                        }
+                        this.key_index = Some(idx);
+                    }
+                }
+
+                Poll::Ready(Ok(()))
+            }
             DecryptedReader::Stream(ref mut r) => r.poll_read_decrypted(ctx, &mut this.stream, buf),
         }
     }
@@ -0,0 +1,444 @@
+//! Proxy authentication of the HTTP local server
+//!
+//! - `basic` - RFC7617, `Proxy-Authorization: Basic BASE64(USERNAME:PASSWORD)`
+//! - `digest` - RFC7616, challenges are sent with both `SHA-256` and `MD5` algorithms for compatibility
+//!
+//! Nonces of Digest challenges are `TIMESTAMP.SIGNATURE`, signed by a random key of the process, so they could be
+//! verified without remembering them. Expired nonces are answered with `stale=true`.
+//!
+//! The highest nonce count (`nc`) accepted for every unexpired nonce is kept, so a captured `Proxy-Authorization`
+//! couldn't be replayed. Responses without `qop` don't have `nc`, their nonces are accepted only once.
+
+use std::{
+    collections::HashMap,
+    io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac, NewMac};
+use hyper::{
+    header::{HeaderValue, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION},
+    Body,
+    Request,
+    Response,
+    StatusCode,
+};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use spin::Mutex as SpinMutex;
+
+use crate::{
+    config::{HttpAuthConfig, HttpAuthScheme},
+    relay::{local_auth::LocalAuthenticator, utils::constant_time_eq},
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Realm of challenges
+const REALM: &str = "shadowsocks";
+/// Nonces of Digest challenges are valid in this duration (in seconds)
+const NONCE_LIFETIME: u64 = 300;
+
+fn now_secs() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(..) => 0,
+    }
+}
+
+fn hex_digest<D: Digest>(data: &str) -> String {
+    D::digest(data.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Result of checking `Proxy-Authorization` of a request
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Authorization {
+    /// Credentials are valid
+    Granted,
+    /// Credentials are missing or invalid
+    Denied,
+    /// Credentials are valid, but the nonce is expired
+    Stale,
+}
+
+/// Checks credentials of HTTP proxy clients
+pub struct HttpAuthenticator {
+    users: LocalAuthenticator,
+    scheme: HttpAuthScheme,
+    nonce_key: [u8; 32],
+    // Unexpired nonces that have been used, with their timestamps and the highest accepted `nc`
+    nonce_counts: SpinMutex<HashMap<String, (u64, u64)>>,
+}
+
+impl HttpAuthenticator {
+    /// Load users of `config`
+    pub fn load(config: &HttpAuthConfig) -> io::Result<HttpAuthenticator> {
+        Ok(HttpAuthenticator {
+            users: LocalAuthenticator::load(&config.users)?,
+            scheme: config.scheme,
+            nonce_key: rand::random(),
+            nonce_counts: SpinMutex::new(HashMap::new()),
+        })
+    }
+
+    /// Check `Proxy-Authorization` of `req`
+    pub fn check(&self, req: &Request<Body>) -> Authorization {
+        let value = match req.headers().get(PROXY_AUTHORIZATION).and_then(|v| v.to_str().ok()) {
+            Some(v) => v.trim(),
+            None => return Authorization::Denied,
+        };
+
+        let mut sp = value.splitn(2, ' ');
+        let (scheme, credentials) = match (sp.next(), sp.next()) {
+            (Some(s), Some(c)) => (s, c.trim()),
+            _ => return Authorization::Denied,
+        };
+
+        match self.scheme {
+            HttpAuthScheme::Basic if scheme.eq_ignore_ascii_case("Basic") => self.check_basic(credentials),
+            HttpAuthScheme::Digest if scheme.eq_ignore_ascii_case("Digest") => {
+                let uri = req.uri().to_string();
+                self.check_digest(req.method().as_str(), &uri, credentials)
+            }
+            _ => Authorization::Denied,
+        }
+    }
+
+    fn check_basic(&self, credentials: &str) -> Authorization {
+        let decoded = match base64::decode(credentials) {
+            Ok(d) => d,
+            Err(..) => return Authorization::Denied,
+        };
+
+        let pos = match decoded.iter().position(|b| *b == b':') {
+            Some(p) => p,
+            None => return Authorization::Denied,
+        };
+
+        if self.users.check(&decoded[..pos], &decoded[pos + 1..]) {
+            Authorization::Granted
+        } else {
+            Authorization::Denied
+        }
+    }
+
+    fn check_digest(&self, method: &str, request_uri: &str, credentials: &str) -> Authorization {
+        let params = parse_auth_params(credentials);
+        let param = |key: &str| params.get(key).map(String::as_str);
+
+        let (username, realm, nonce, uri, response) = match (
+            param("username"),
+            param("realm"),
+            param("nonce"),
+            param("uri"),
+            param("response"),
+        ) {
+            (Some(username), Some(realm), Some(nonce), Some(uri), Some(response)) => {
+                (username, realm, nonce, uri, response)
+            }
+            _ => return Authorization::Denied,
+        };
+
+        // Credentials of another request couldn't be reused
+        if realm != REALM || uri != request_uri {
+            return Authorization::Denied;
+        }
+
+        let timestamp = match self.verify_nonce(nonce) {
+            Some(t) => t,
+            None => return Authorization::Denied,
+        };
+
+        let password = match self.users.password(username) {
+            Some(p) => p,
+            None => return Authorization::Denied,
+        };
+
+        let hash: fn(&str) -> String = match param("algorithm") {
+            None => hex_digest::<Md5>,
+            Some(a) if a.eq_ignore_ascii_case("MD5") => hex_digest::<Md5>,
+            Some(a) if a.eq_ignore_ascii_case("SHA-256") => hex_digest::<Sha256>,
+            Some(..) => return Authorization::Denied,
+        };
+
+        let ha1 = hash(&format!("{}:{}:{}", username, realm, password));
+        let ha2 = hash(&format!("{}:{}", method, uri));
+        let (expected, nonce_count) = match param("qop") {
+            // Nonce could be used only once
+            None => (hash(&format!("{}:{}:{}", ha1, nonce, ha2)), 1),
+            Some("auth") => match (param("nc"), param("cnonce")) {
+                (Some(nc), Some(cnonce)) => {
+                    let count = match u64::from_str_radix(nc, 16) {
+                        Ok(c) if c > 0 => c,
+                        _ => return Authorization::Denied,
+                    };
+                    let expected = hash(&format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2));
+                    (expected, count)
+                }
+                _ => return Authorization::Denied,
+            },
+            Some(..) => return Authorization::Denied,
+        };
+
+        if !constant_time_eq(expected.as_bytes(), response.to_ascii_lowercase().as_bytes()) {
+            Authorization::Denied
+        } else if timestamp + NONCE_LIFETIME < now_secs() {
+            Authorization::Stale
+        } else if !self.accept_nonce_count(nonce, timestamp, nonce_count) {
+            Authorization::Denied
+        } else {
+            Authorization::Granted
+        }
+    }
+
+    /// Record `count` of `nonce`, fails if it is not greater than the counts accepted before
+    fn accept_nonce_count(&self, nonce: &str, timestamp: u64, count: u64) -> bool {
+        let mut nonce_counts = self.nonce_counts.lock();
+
+        if let Some(&(_, highest)) = nonce_counts.get(nonce) {
+            if count <= highest {
+                return false;
+            }
+        } else {
+            // Expired nonces are answered with `stale=true` before being counted
+            let now = now_secs();
+            nonce_counts.retain(|_, (t, _)| *t + NONCE_LIFETIME >= now);
+        }
+
+        nonce_counts.insert(nonce.to_owned(), (timestamp, count));
+        true
+    }
+
+    fn nonce_mac(&self, timestamp: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_varkey(&self.nonce_key).expect("HMAC accepts keys in any length");
+        mac.update(timestamp.as_bytes());
+        mac
+    }
+
+    fn make_nonce(&self) -> String {
+        let timestamp = now_secs().to_string();
+        let signature = self.nonce_mac(&timestamp).finalize().into_bytes();
+        format!(
+            "{}.{}",
+            timestamp,
+            base64::encode_config(&signature, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    /// Timestamp of a nonce signed by this process
+    fn verify_nonce(&self, nonce: &str) -> Option<u64> {
+        let mut sp = nonce.splitn(2, '.');
+        let (timestamp, signature) = (sp.next()?, sp.next()?);
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).ok()?;
+        self.nonce_mac(timestamp).verify(&signature).ok()?;
+        timestamp.parse::<u64>().ok()
+    }
+
+    /// `407 Proxy Authentication Required` with challenges
+    pub fn challenge(&self, stale: bool) -> Response<Body> {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = StatusCode::PROXY_AUTHENTICATION_REQUIRED;
+
+        let headers = resp.headers_mut();
+        match self.scheme {
+            HttpAuthScheme::Basic => {
+                let value = format!("Basic realm=\"{}\", charset=\"UTF-8\"", REALM);
+                headers.append(PROXY_AUTHENTICATE, HeaderValue::from_str(&value).expect("valid header"));
+            }
+            HttpAuthScheme::Digest => {
+                let nonce = self.make_nonce();
+                // Preferred algorithm goes first
+                for algorithm in &["SHA-256", "MD5"] {
+                    let value = format!(
+                        "Digest realm=\"{}\", qop=\"auth\", algorithm={}, nonce=\"{}\", stale={}",
+                        REALM, algorithm, nonce, stale
+                    );
+                    headers.append(PROXY_AUTHENTICATE, HeaderValue::from_str(&value).expect("valid header"));
+                }
+            }
+        }
+
+        resp
+    }
+}
+
+/// Parse `key=value, key="quoted value"` parameters, keys are in lower case
+fn parse_auth_params(s: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+
+    let mut chars = s.chars().peekable();
+    loop {
+        while let Some(&c) = chars.peek() {
+            if c == ',' || c.is_whitespace() {
+                chars.next();
+            } else {
+                break;
+            }
+        }
+
+        let mut key = String::new();
+        while let Some(&c) = chars.peek() {
+            if c == '=' || c == ',' {
+                break;
+            }
+            key.push(c);
+            chars.next();
+        }
+        if key.is_empty() {
+            break;
+        }
+
+        let mut value = String::new();
+        if chars.peek() == Some(&'=') {
+            chars.next();
+            if chars.peek() == Some(&'"') {
+                chars.next();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => {
+                            if let Some(c) = chars.next() {
+                                value.push(c);
+                            }
+                        }
+                        c => value.push(c),
+                    }
+                }
+            } else {
+                while let Some(&c) = chars.peek() {
+                    if c == ',' {
+                        break;
+                    }
+                    value.push(c);
+                    chars.next();
+                }
+            }
+        }
+
+        params.insert(key.trim().to_ascii_lowercase(), value.trim().to_owned());
+    }
+
+    params
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::config::{LocalAuthConfig, LocalUser};
+
+    const USERNAME: &str = "user";
+    const PASSWORD: &str = "password";
+    const URI: &str = "http://www.example.com/";
+
+    fn authenticator(scheme: HttpAuthScheme) -> HttpAuthenticator {
+        let config = HttpAuthConfig {
+            users: LocalAuthConfig {
+                users: vec![LocalUser {
+                    username: USERNAME.to_owned(),
+                    password: PASSWORD.to_owned(),
+                }],
+                users_file: None,
+            },
+            scheme,
+        };
+        HttpAuthenticator::load(&config).unwrap()
+    }
+
+    fn request(authorization: &str) -> Request<Body> {
+        Request::get(URI)
+            .header(PROXY_AUTHORIZATION, authorization)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn digest_authorization(nonce: &str, password: &str, nc: &str) -> String {
+        let ha1 = hex_digest::<Sha256>(&format!("{}:{}:{}", USERNAME, REALM, password));
+        let ha2 = hex_digest::<Sha256>(&format!("GET:{}", URI));
+        let response = hex_digest::<Sha256>(&format!("{}:{}:{}:cnonce:auth:{}", ha1, nonce, nc, ha2));
+        format!(
+            "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", algorithm=SHA-256, qop=auth, \
+             nc={}, cnonce=\"cnonce\", response=\"{}\"",
+            USERNAME, REALM, nonce, URI, nc, response
+        )
+    }
+
+    #[test]
+    fn parse_params() {
+        let params = parse_auth_params(r#"username="user", Realm=shadowsocks,qop=auth, uri="/a,b", x="\"q\"""#);
+        assert_eq!(params.get("username").unwrap(), "user");
+        assert_eq!(params.get("realm").unwrap(), "shadowsocks");
+        assert_eq!(params.get("qop").unwrap(), "auth");
+        assert_eq!(params.get("uri").unwrap(), "/a,b");
+        assert_eq!(params.get("x").unwrap(), "\"q\"");
+
+        assert!(parse_auth_params("").is_empty());
+    }
+
+    #[test]
+    fn basic() {
+        let auth = authenticator(HttpAuthScheme::Basic);
+
+        let granted = format!("Basic {}", base64::encode(format!("{}:{}", USERNAME, PASSWORD)));
+        assert_eq!(auth.check(&request(&granted)), Authorization::Granted);
+
+        let denied = format!("Basic {}", base64::encode(format!("{}:wrong", USERNAME)));
+        assert_eq!(auth.check(&request(&denied)), Authorization::Denied);
+        assert_eq!(auth.check(&request("Basic !!!")), Authorization::Denied);
+        assert_eq!(
+            auth.check(&request(&digest_authorization("0.x", PASSWORD, "00000001"))),
+            Authorization::Denied
+        );
+    }
+
+    #[test]
+    fn digest() {
+        let auth = authenticator(HttpAuthScheme::Digest);
+        let nonce = auth.make_nonce();
+
+        let first = digest_authorization(&nonce, PASSWORD, "00000001");
+        assert_eq!(auth.check(&request(&first)), Authorization::Granted);
+
+        let wrong = digest_authorization(&nonce, "wrong", "00000002");
+        assert_eq!(auth.check(&request(&wrong)), Authorization::Denied);
+
+        let forged = digest_authorization("0.AAAA", PASSWORD, "00000001");
+        assert_eq!(auth.check(&request(&forged)), Authorization::Denied);
+    }
+
+    #[test]
+    fn digest_replay() {
+        let auth = authenticator(HttpAuthScheme::Digest);
+        let nonce = auth.make_nonce();
+
+        let second = digest_authorization(&nonce, PASSWORD, "00000002");
+        assert_eq!(auth.check(&request(&second)), Authorization::Granted);
+
+        // Replayed, or counts not greater than the accepted ones
+        assert_eq!(auth.check(&request(&second)), Authorization::Denied);
+        let first = digest_authorization(&nonce, PASSWORD, "00000001");
+        assert_eq!(auth.check(&request(&first)), Authorization::Denied);
+
+        let third = digest_authorization(&nonce, PASSWORD, "00000003");
+        assert_eq!(auth.check(&request(&third)), Authorization::Granted);
+    }
+
+    #[test]
+    fn digest_stale() {
+        let auth = authenticator(HttpAuthScheme::Digest);
+
+        let timestamp = (now_secs() - NONCE_LIFETIME - 1).to_string();
+        let signature = auth.nonce_mac(&timestamp).finalize().into_bytes();
+        let nonce = format!(
+            "{}.{}",
+            timestamp,
+            base64::encode_config(&signature, base64::URL_SAFE_NO_PAD)
+        );
+
+        let authorization = digest_authorization(&nonce, PASSWORD, "00000001");
+        assert_eq!(auth.check(&request(&authorization)), Authorization::Stale);
+    }
+}
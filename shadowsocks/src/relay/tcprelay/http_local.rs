@@ -9,6 +9,7 @@ use std::{
     str::FromStr,
     sync::Arc,
     task::{self, Poll},
+    time::Instant,
 };
 
 use futures::{
@@ -20,7 +21,7 @@ use http::uri::{Authority, Scheme};
 use hyper::{
     client::connect::{Connected, Connection},
     header::HeaderValue,
-    server::conn::AddrStream,
+    server::conn::{AddrStream, Http},
     service::{make_service_fn, service_fn},
     upgrade::{self, Upgraded},
     Body,
@@ -41,20 +42,17 @@ use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use crate::{
     config::ConfigType,
     context::SharedContext,
-    crypto::v1::CipherKind,
     relay::{
-        loadbalancing::server::{
-            PingBalancer,
-            ServerData,
-            ServerType,
-            SharedServerStatistic,
-            SharedServerStatisticData,
-        },
+        loadbalancing::server::{PingBalancer, ServerData, ServerSlot, ServerType, SharedServerStatisticData},
         socks5::Address,
     },
 };
 
-use super::ProxyStream;
+use super::{
+    http_auth::{Authorization, HttpAuthenticator},
+    local::connect_raced,
+    ProxyStream,
+};
 
 #[pin_project(project = ProxyHttpStreamProj)]
 enum ProxyHttpStream {
@@ -526,7 +524,7 @@ type ShadowSocksHttpClient = Client<ShadowSocksConnector, Body>;
 type DirectHttpClient = Client<DirectConnector, Body>;
 
 async fn establish_connect_tunnel(
-    method: CipherKind,
+    server: ServerSlot<ServerScore>,
     upgraded: Upgraded,
     stream: ProxyStream,
     client_addr: SocketAddr,
@@ -535,17 +533,24 @@ async fn establish_connect_tunnel(
     use super::utils::{copy_p2s, copy_s2p};
     use tokio::io::split;
 
+    let method = server.server_config().method();
+    let keep_busy_interval = server.config().keep_busy_interval;
+    let egress = server.context().server_state().egress_limiter();
+
     let (mut r, mut w) = split(upgraded);
+    // Bypassed targets are not relayed through the server
+    let proxied = stream.is_proxied();
     let (mut svr_r, mut svr_w) = stream.split();
 
-    let rhalf = copy_p2s(method, &mut r, &mut svr_w);
-    let whalf = copy_s2p(method, &mut svr_r, &mut w);
+    let rhalf = copy_p2s(method, keep_busy_interval, egress, &mut r, &mut svr_w);
+    let whalf = copy_s2p(method, None, &mut svr_r, &mut w);
 
     tokio::pin!(rhalf);
     tokio::pin!(whalf);
 
     debug!("CONNECT relay established {} <-> {}", client_addr, addr);
 
+    let start = Instant::now();
     match future::select(rhalf, whalf).await {
         Either::Left((Ok(..), _)) => trace!("CONNECT relay {} -> {} closed", client_addr, addr),
         Either::Left((Err(err), _)) => {
@@ -555,12 +560,20 @@ async fn establish_connect_tunnel(
                 debug!("CONNECT relay {} -> {} closed with error {}", client_addr, addr, err);
             }
         }
-        Either::Right((Ok(..), _)) => trace!("CONNECT relay {} <- {} closed", client_addr, addr),
+        Either::Right((Ok(n), _)) => {
+            trace!("CONNECT relay {} <- {} closed", client_addr, addr);
+            if proxied {
+                server.report_transfer(n, start.elapsed()).await;
+            }
+        }
         Either::Right((Err(err), _)) => {
             if let ErrorKind::TimedOut = err.kind() {
                 trace!("CONNECT relay {} <- {} closed with error {}", client_addr, addr, err);
             } else {
                 debug!("CONNECT relay {} <- {} closed with error {}", client_addr, addr, err);
+                if proxied {
+                    server.report_relay_error().await;
+                }
             }
         }
     }
@@ -647,13 +660,27 @@ fn get_addr_from_header(req: &mut Request<Body>) -> Result<Address, ()> {
 
 async fn server_dispatch(
     mut req: Request<Body>,
-    svr_score: SharedServerStatistic<ServerScore>,
+    servers: Arc<PingBalancer<ServerScore>>,
     client_addr: SocketAddr,
     bypass_client: DirectHttpClient,
+    auth: Option<Arc<HttpAuthenticator>>,
 ) -> io::Result<Response<Body>> {
     trace!("request {} {:?}", client_addr, req);
 
-    let context = svr_score.context();
+    if let Some(ref auth) = auth {
+        match auth.check(&req) {
+            Authorization::Granted => {}
+            result => {
+                debug!(
+                    "HTTP {} {} from {} is not authorized",
+                    req.method(),
+                    req.uri(),
+                    client_addr
+                );
+                return Ok(auth.challenge(result == Authorization::Stale));
+            }
+        }
+    }
 
     // Parse URI
     //
@@ -676,32 +703,22 @@ async fn server_dispatch(
         Some(h) => h,
     };
 
-    let svr_cfg = svr_score.server_config();
-
     if Method::CONNECT == req.method() {
         // Establish a TCP tunnel
         // https://tools.ietf.org/html/draft-luotonen-web-proxy-tunneling-01
 
         debug!("HTTP CONNECT {}", host);
 
+        let (svr_score, other) = servers.acquire_servers_raced_for(&host)?;
+
         // Connect to Shadowsocks' remote
         //
         // FIXME: What STATUS should I return for connection error?
-        let stream = match ProxyStream::connect(svr_score.clone_context(), svr_cfg, &host).await {
-            Ok(s) => s,
-            Err(err) => {
-                if err.is_proxied() {
-                    // Report failure to global statistic
-                    svr_score.report_failure().await;
-                }
-                return Err(err.into_inner());
-            }
-        };
+        let route = svr_score.context().check_process_route(client_addr).await;
+        let (stream, svr_score) = connect_raced(svr_score, other, &host, route).await?;
 
         debug!("CONNECT relay connected {} <-> {}", client_addr, host);
 
-        let method = svr_cfg.method();
-
         // Upgrade to a TCP tunnel
         //
         // Note: only after client received an empty body with STATUS_OK can the
@@ -712,7 +729,8 @@ async fn server_dispatch(
                 Ok(upgraded) => {
                     trace!("CONNECT tunnel upgrade success, {} <-> {}", client_addr, host);
 
-                    establish_connect_tunnel(method, upgraded, stream, client_addr, host).await
+                    // The server's slot is held until the tunnel is closed
+                    establish_connect_tunnel(svr_score, upgraded, stream, client_addr, host).await
                 }
                 Err(e) => {
                     error!(
@@ -728,6 +746,10 @@ async fn server_dispatch(
 
         Ok(resp)
     } else {
+        let svr_score = servers.pick_server_for(&host);
+        let context = svr_score.context();
+        let svr_cfg = svr_score.server_config();
+
         let method = req.method().clone();
         let version = req.version();
 
@@ -742,6 +764,15 @@ async fn server_dispatch(
         // Set keep-alive for connection with remote
         set_conn_keep_alive(version, req.headers_mut(), conn_keep_alive);
 
+        if context.check_target_rejected(&host) {
+            debug!("HTTP {} {} -> {} is rejected by ACL rules", method, client_addr, host);
+
+            let mut resp = Response::new(Body::from(format!("{} is rejected", host)));
+            *resp.status_mut() = StatusCode::FORBIDDEN;
+
+            return Ok(resp);
+        }
+
         let mut res = if context.check_target_bypassed(&host).await {
             trace!("bypassed {} -> {} {:?}", client_addr, host, req);
 
@@ -828,26 +859,80 @@ impl ServerData for ServerScore {
     }
 }
 
+/// States shared by HTTP proxy requests accepted on a listener
+#[derive(Clone)]
+pub(super) struct HttpDispatcher {
+    servers: Arc<PingBalancer<ServerScore>>,
+    bypass_client: DirectHttpClient,
+    auth: Option<Arc<HttpAuthenticator>>,
+}
+
+impl HttpDispatcher {
+    /// Create a dispatcher, with servers and users of `http_auth` loaded from `context`
+    pub(super) async fn new(context: &SharedContext) -> io::Result<HttpDispatcher> {
+        let bypass_client = Client::builder().build::<_, Body>(DirectConnector::new(context.clone()));
+        let servers: PingBalancer<ServerScore> = PingBalancer::new(context.clone(), ServerType::Tcp).await;
+
+        let auth = match context.config().http_auth {
+            Some(ref auth) => {
+                let auth = HttpAuthenticator::load(auth).map_err(|err| {
+                    error!("failed to load users of `http_auth`, {}", err);
+                    err
+                })?;
+                Some(Arc::new(auth))
+            }
+            None => None,
+        };
+
+        Ok(HttpDispatcher {
+            servers: Arc::new(servers),
+            bypass_client,
+            auth,
+        })
+    }
+
+    async fn dispatch(self, req: Request<Body>, client_addr: SocketAddr) -> io::Result<Response<Body>> {
+        server_dispatch(req, self.servers, client_addr, self.bypass_client, self.auth).await
+    }
+
+    /// Serve HTTP proxy requests of a client on an accepted connection
+    pub(super) async fn serve_connection<S>(&self, stream: S, client_addr: SocketAddr) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let dispatcher = self.clone();
+        let service = service_fn(move |req: Request<Body>| dispatcher.clone().dispatch(req, client_addr));
+
+        // HTTP Proxy protocol only defined in HTTP 1.x
+        Http::new()
+            .http1_only(true)
+            .serve_connection(stream, service)
+            .with_upgrades()
+            .await
+            .map_err(|err| io::Error::new(ErrorKind::Other, err))
+    }
+}
+
 /// Starts a TCP local server with HTTP proxy protocol
 pub async fn run(context: SharedContext) -> io::Result<()> {
     let local_addr = context.config().local_addr.as_ref().expect("local config");
     let bind_addr = local_addr.bind_addr(&context).await?;
 
-    let bypass_client = Client::builder().build::<_, Body>(DirectConnector::new(context.clone()));
-    let servers: PingBalancer<ServerScore> = PingBalancer::new(context.clone(), ServerType::Tcp).await;
-    let servers = Arc::new(servers);
+    let dispatcher = HttpDispatcher::new(&context).await?;
 
     match context.config().config_type {
         ConfigType::HttpLocal => {
             let make_service = make_service_fn(|socket: &AddrStream| {
                 let client_addr = socket.remote_addr();
-                let servers = servers.clone();
-                let bypass_client = bypass_client.clone();
+                let dispatcher = dispatcher.clone();
+                let context = context.clone();
 
                 async move {
+                    // Connection is served after the service is made
+                    context.wait_memory_available().await;
+
                     Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
-                        let svr_score = servers.pick_server();
-                        server_dispatch(req, svr_score, client_addr, bypass_client.clone())
+                        dispatcher.clone().dispatch(req, client_addr)
                     }))
                 }
             });
@@ -882,13 +967,15 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
 
             let make_service = make_service_fn(|socket: &TlsStream| {
                 let client_addr = socket.remote_addr();
-                let servers = servers.clone();
-                let bypass_client = bypass_client.clone();
+                let dispatcher = dispatcher.clone();
+                let context = context.clone();
 
                 async move {
+                    // Connection is served after the service is made
+                    context.wait_memory_available().await;
+
                     Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
-                        let svr_score = servers.pick_server();
-                        server_dispatch(req, svr_score, client_addr, bypass_client.clone())
+                        dispatcher.clone().dispatch(req, client_addr)
                     }))
                 }
             });
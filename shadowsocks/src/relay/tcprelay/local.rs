@@ -2,7 +2,19 @@
 
 use std::io;
 
-use crate::{config::ConfigType, context::SharedContext};
+use log::debug;
+
+use crate::{
+    acl::RouteTarget,
+    config::ConfigType,
+    context::SharedContext,
+    relay::{
+        loadbalancing::server::{ServerData, ServerSlot},
+        socks5::Address,
+    },
+};
+
+use super::ProxyStream;
 
 /// Starts a TCP local server
 pub async fn run(context: SharedContext) -> io::Result<()> {
@@ -19,11 +31,73 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
             any(feature = "local-http-native-tls", feature = "local-http-rustls")
         ))]
         ConfigType::HttpsLocal => super::http_local::run(context).await,
+        #[cfg(feature = "local-http")]
+        ConfigType::MixedLocal => super::mixed_local::run(context).await,
         #[cfg(feature = "local-redir")]
         ConfigType::RedirLocal => super::redir_local::run(context).await,
+        // TUN device relays both TCP and UDP
+        #[cfg(feature = "local-tun")]
+        ConfigType::TunLocal => crate::relay::tun::run(context).await,
         #[cfg(feature = "local-dns")]
         ConfigType::DnsLocal => unreachable!(),
         ConfigType::Server => unreachable!(),
         ConfigType::Manager => unreachable!(),
     }
 }
+
+/// Connect to `addr` by `route` (found by `Context::check_process_route`) or ACL rules,
+/// racing connections via `server` and `other` if `other` is provided
+///
+/// Returns the stream and the server it is connected through, slot of the other server is released.
+/// Failures are reported to the servers.
+pub(crate) async fn connect_raced<S: ServerData>(
+    server: ServerSlot<S>,
+    other: Option<ServerSlot<S>>,
+    addr: &Address,
+    route: Option<RouteTarget>,
+) -> io::Result<(ProxyStream, ServerSlot<S>)> {
+    // Bypassed and rejected targets are handled by `ProxyStream::connect_with_route`, nothing to race
+    let bypassed = other.is_some()
+        && match route {
+            Some(RouteTarget::Proxy(..)) => false,
+            Some(..) => true,
+            None => server.context().check_target_rejected(addr) || server.context().check_target_bypassed(addr).await,
+        };
+    let other = match other {
+        Some(o) if !bypassed => o,
+        _ => {
+            let connect = ProxyStream::connect_with_route(server.clone_context(), server.server_config(), addr, route);
+            return match connect.await {
+                Ok(stream) => Ok((stream, server)),
+                Err(err) => {
+                    if err.is_proxied() {
+                        // Report to global statistic
+                        server.report_failure().await;
+                    }
+                    Err(err.into_inner())
+                }
+            };
+        }
+    };
+
+    debug!(
+        "racing connections to {} via {} and {}",
+        addr,
+        server.server_config().addr(),
+        other.server_config().addr()
+    );
+
+    let candidates = vec![
+        (server.clone_context(), server.server_config()),
+        (other.clone_context(), other.server_config()),
+    ];
+    match ProxyStream::connect_proxied_raced(candidates, addr).await {
+        Ok((stream, 0)) => Ok((stream, server)),
+        Ok((stream, _)) => Ok((stream, other)),
+        Err(err) => {
+            server.report_failure().await;
+            other.report_failure().await;
+            Err(err)
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! Local server that accepts both SOCKS5 and HTTP protocols on the same port
+//!
+//! Protocol of a connection is detected by its first byte, which is always `0x05` (version) for SOCKS5.
+//! SOCKS4 and SOCKS4a (`0x04`) are not served on this port, their connections are closed.
+
+use std::{io, time::Duration};
+
+use log::{debug, error, info, trace, warn};
+use tokio::{net::TcpListener, time};
+
+use crate::{context::SharedContext, relay::socks5::SOCKS5_VERSION};
+
+use super::{http_local::HttpDispatcher, socks5_local::Socks5Handler};
+
+/// First byte of SOCKS4 and SOCKS4a requests
+const SOCKS4_VERSION: u8 = 0x04;
+
+/// Starts a TCP local server with SOCKS5 and HTTP proxy protocol
+pub async fn run(context: SharedContext) -> io::Result<()> {
+    let local_addr = context.config().local_addr.as_ref().expect("local config");
+    let bind_addr = local_addr.bind_addr(&context).await?;
+
+    let listener = TcpListener::bind(&bind_addr).await.map_err(|err| {
+        error!("failed to listen on {} ({}), {}", local_addr, bind_addr, err);
+        err
+    })?;
+
+    let actual_local_addr = listener.local_addr().expect("determine port bound to");
+
+    let socks5 = Socks5Handler::new(&context, actual_local_addr).await?;
+    let http = HttpDispatcher::new(&context).await?;
+
+    info!("shadowsocks SOCKS5 and HTTP TCP listening on {}", actual_local_addr);
+
+    loop {
+        context.wait_memory_available().await;
+
+        let (socket, peer_addr) = match listener.accept().await {
+            Ok(s) => s,
+            Err(err) => {
+                error!("accept failed with error: {}", err);
+                time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        trace!("got connection {}", peer_addr);
+
+        let socks5 = socks5.clone();
+        let http = http.clone();
+        tokio::spawn(async move {
+            // Client sends first in both protocols
+            let mut buf = [0u8; 1];
+            let n = match socket.peek(&mut buf).await {
+                Ok(n) => n,
+                Err(err) => {
+                    debug!("TCP mixed client {} exited with error: {}", peer_addr, err);
+                    return;
+                }
+            };
+            if n == 0 {
+                trace!("TCP mixed client {} closed before sending anything", peer_addr);
+                return;
+            }
+
+            if buf[0] == SOCKS5_VERSION {
+                if let Err(err) = socks5.handle(socket).await {
+                    debug!("TCP socks5 client exited with error: {}", err);
+                }
+            } else if buf[0] == SOCKS4_VERSION {
+                warn!(
+                    "TCP mixed client {} closed, SOCKS4 is not supported on mixed port, use --protocol socks4",
+                    peer_addr
+                );
+            } else if let Err(err) = http.serve_connection(socket, peer_addr).await {
+                debug!("TCP HTTP client {} exited with error: {}", peer_addr, err);
+            }
+        });
+    }
+}
@@ -20,6 +20,8 @@ mod stream;
 pub mod client;
 mod connection;
 
+#[cfg(feature = "local-http")]
+mod http_auth;
 #[cfg(feature = "local-http")]
 mod http_local;
 #[cfg(all(
@@ -28,6 +30,8 @@ mod http_local;
 ))]
 mod http_tls;
 pub mod local;
+#[cfg(feature = "local-http")]
+mod mixed_local;
 mod monitor;
 mod proxy_stream;
 #[cfg(feature = "local-redir")]
@@ -39,10 +43,13 @@ pub mod server;
 mod socks4_local;
 mod socks5_local;
 
+#[cfg(feature = "local-tun")]
+pub(crate) mod tun_local;
 #[cfg(feature = "local-tunnel")]
 mod tunnel_local;
 mod utils;
 
+pub(crate) use self::proxy_stream::probe_proxy_server;
 pub use self::{
     connection::{Connection, TcpConnection},
     crypto_io::CryptoStream,
@@ -12,18 +12,47 @@ use futures::ready;
 use pin_project::pin_project;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
-use crate::relay::flow::SharedServerFlowStatistic;
+use crate::relay::flow::{
+    SharedConnectionInfo,
+    SharedFlowStatistic,
+    SharedServerFlowStatistic,
+    SharedUserFlowStatistic,
+};
 
 #[pin_project]
 pub struct TcpMonStream<S> {
     #[pin]
     stream: S,
     flow_stat: SharedServerFlowStatistic,
+    user_flow_stat: Option<SharedUserFlowStatistic>,
+    connection_info: Option<SharedConnectionInfo>,
+    destination_flow_stat: Option<SharedFlowStatistic>,
 }
 
 impl<S> TcpMonStream<S> {
     pub fn new(flow_stat: SharedServerFlowStatistic, stream: S) -> TcpMonStream<S> {
-        TcpMonStream { stream, flow_stat }
+        TcpMonStream {
+            stream,
+            flow_stat,
+            user_flow_stat: None,
+            connection_info: None,
+            destination_flow_stat: None,
+        }
+    }
+
+    /// Also count traffic for an identified user
+    pub fn set_user_flow_stat(&mut self, user_flow_stat: SharedUserFlowStatistic) {
+        self.user_flow_stat = Some(user_flow_stat);
+    }
+
+    /// Also count traffic for the live information of this connection
+    pub fn set_connection_info(&mut self, connection_info: SharedConnectionInfo) {
+        self.connection_info = Some(connection_info);
+    }
+
+    /// Also count traffic for the destination host
+    pub fn set_destination_flow_stat(&mut self, destination_flow_stat: SharedFlowStatistic) {
+        self.destination_flow_stat = Some(destination_flow_stat);
     }
 
     pub fn into_inner(self) -> S {
@@ -40,7 +69,17 @@ where
 
         let before_remain = buf.remaining();
         ready!(this.stream.poll_read(cx, buf))?;
-        this.flow_stat.tcp().incr_rx(before_remain - buf.remaining());
+        let n = before_remain - buf.remaining();
+        this.flow_stat.tcp().incr_rx(n);
+        if let Some(ref user_flow_stat) = *this.user_flow_stat {
+            user_flow_stat.tcp().incr_rx(n);
+        }
+        if let Some(ref connection_info) = *this.connection_info {
+            connection_info.flow().incr_rx(n);
+        }
+        if let Some(ref destination_flow_stat) = *this.destination_flow_stat {
+            destination_flow_stat.incr_rx(n);
+        }
         Poll::Ready(Ok(()))
     }
 }
@@ -56,7 +95,21 @@ where
             Poll::Ready(n) => n,
             Poll::Pending => return Poll::Pending,
         };
+        if let Some(ref connection_info) = *this.connection_info {
+            if n > 0 && connection_info.flow().tx() == 0 {
+                this.flow_stat.latency().first_byte().observe(connection_info.elapsed());
+            }
+        }
         this.flow_stat.tcp().incr_tx(n);
+        if let Some(ref user_flow_stat) = *this.user_flow_stat {
+            user_flow_stat.tcp().incr_tx(n);
+        }
+        if let Some(ref connection_info) = *this.connection_info {
+            connection_info.flow().incr_tx(n);
+        }
+        if let Some(ref destination_flow_stat) = *this.destination_flow_stat {
+            destination_flow_stat.incr_tx(n);
+        }
         Poll::Ready(Ok(n))
     }
 
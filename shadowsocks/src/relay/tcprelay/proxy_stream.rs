@@ -2,26 +2,34 @@
 
 use std::{
     fmt::{self, Display, Formatter},
-    io::{self, Error},
+    io::{self, Error, ErrorKind},
     net::SocketAddr,
     pin::Pin,
+    sync::Arc,
     task::{self, Poll},
     time::Duration,
 };
 
 use bytes::{Buf, BufMut, BytesMut};
-use futures::ready;
-use log::{debug, error, trace};
+use futures::{
+    future::{self, FutureExt},
+    ready,
+};
+use log::{debug, error, trace, warn};
 use pin_project::pin_project;
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, ReadHalf, WriteHalf};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf, ReadHalf, WriteHalf},
+    time::{self, Sleep},
+};
 
 use crate::{
-    config::{ConfigType, ServerAddr, ServerConfig},
+    acl::RouteTarget,
+    config::{ConfigType, ServerAddr, ServerConfig, ServerPasswords},
     context::{Context, SharedContext},
     relay::{socks5::Address, sys::tcp_stream_connect, utils::try_timeout},
 };
 
-use super::{connection::Connection, CryptoStream, STcpStream};
+use super::{connection::Connection, utils::connect_happy_eyeballs, CryptoStream, STcpStream};
 
 enum ProxiedConnectState {
     Connected(Address),
@@ -29,18 +37,95 @@ enum ProxiedConnectState {
     Established,
 }
 
+/// Servers that don't answer a request in this duration are treated as failed handshakes
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Reports to the server's candidate passwords whether the server has answered the request
+///
+/// Servers keep silent if the request couldn't be decrypted, so a request is a failed handshake if the server
+/// closes the connection or sends nothing in `HANDSHAKE_TIMEOUT` after it.
+/// Connections closed by the local side are not reported.
+struct HandshakeReporter {
+    server_addr: ServerAddr,
+    destination: String,
+    passwords: Option<Arc<ServerPasswords>>,
+    deadline: Option<Pin<Box<Sleep>>>,
+    reported: bool,
+}
+
+impl HandshakeReporter {
+    fn new(svr_cfg: &ServerConfig, destination: &Address) -> HandshakeReporter {
+        HandshakeReporter {
+            server_addr: svr_cfg.addr().clone(),
+            destination: destination.to_string(),
+            passwords: svr_cfg.passwords().cloned(),
+            deadline: None,
+            reported: false,
+        }
+    }
+
+    fn requested(&mut self) {
+        // Nothing to report without candidate passwords
+        if self.passwords.is_some() && self.deadline.is_none() {
+            self.deadline = Some(Box::pin(time::sleep(HANDSHAKE_TIMEOUT)));
+        }
+    }
+
+    fn report_answered(&mut self) {
+        if self.deadline.is_none() || self.reported {
+            return;
+        }
+        self.reported = true;
+
+        if let Some(ref passwords) = self.passwords {
+            passwords.report_success();
+        }
+    }
+
+    fn report_unanswered(&mut self) {
+        if self.deadline.is_none() || self.reported {
+            return;
+        }
+        self.reported = true;
+
+        if let Some(ref passwords) = self.passwords {
+            if let Some(idx) = passwords.report_failure(&self.destination) {
+                warn!(
+                    "server {} keeps ignoring requests, switched to candidate password #{}",
+                    self.server_addr, idx
+                );
+            }
+        }
+    }
+
+    fn poll_deadline(&mut self, cx: &mut task::Context<'_>) {
+        if self.reported {
+            return;
+        }
+
+        if let Some(ref mut deadline) = self.deadline {
+            if deadline.poll_unpin(cx).is_ready() {
+                self.report_unanswered();
+            }
+        }
+    }
+}
+
 #[pin_project]
 struct ProxiedConnection {
     #[pin]
     stream: CryptoStream<STcpStream>,
     state: ProxiedConnectState,
+    reporter: HandshakeReporter,
 }
 
 impl ProxiedConnection {
-    fn connected(stream: CryptoStream<STcpStream>, addr: Address) -> ProxiedConnection {
+    fn connected(stream: CryptoStream<STcpStream>, addr: Address, svr_cfg: &ServerConfig) -> ProxiedConnection {
+        let reporter = HandshakeReporter::new(svr_cfg, &addr);
         ProxiedConnection {
             stream,
             state: ProxiedConnectState::Connected(addr),
+            reporter,
         }
     }
 
@@ -51,7 +136,28 @@ impl ProxiedConnection {
 
 impl AsyncRead for ProxiedConnection {
     fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
-        self.project().stream.poll_read(cx, buf)
+        let this = self.project();
+
+        let before_remain = buf.remaining();
+        match this.stream.poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                if buf.remaining() < before_remain {
+                    this.reporter.report_answered();
+                } else if before_remain > 0 {
+                    // EOF, closed by the server
+                    this.reporter.report_unanswered();
+                }
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(err)) => {
+                this.reporter.report_unanswered();
+                Poll::Ready(Err(err))
+            }
+            Poll::Pending => {
+                this.reporter.poll_deadline(cx);
+                Poll::Pending
+            }
+        }
     }
 }
 
@@ -64,6 +170,8 @@ impl AsyncWrite for ProxiedConnection {
                 ProxiedConnectState::Connected(ref addr) => {
                     assert_ne!(data.len(), 0);
 
+                    this.reporter.requested();
+
                     // Send relay address to remote
                     //
                     // NOTE: `Address` handshake packets are very small in most cases,
@@ -279,6 +387,37 @@ impl ProxyStream {
         svr_cfg: &ServerConfig,
         addr: &Address,
     ) -> Result<ProxyStream, ProxyStreamError> {
+        ProxyStream::connect_with_route(context, svr_cfg, addr, None).await
+    }
+
+    /// Connect to remote by `route` if it is provided (found by `Context::check_process_route`), or by ACL rules
+    pub async fn connect_with_route(
+        context: SharedContext,
+        svr_cfg: &ServerConfig,
+        addr: &Address,
+        route: Option<RouteTarget>,
+    ) -> Result<ProxyStream, ProxyStreamError> {
+        match route {
+            Some(RouteTarget::Reject) => {
+                let err = Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("{} is rejected by ACL rules", addr),
+                );
+                return Err(ProxyStreamError::new(err, false));
+            }
+            Some(RouteTarget::Direct) => return ProxyStream::connect_direct_wrapped(context, addr).await,
+            Some(RouteTarget::Proxy(..)) => return ProxyStream::connect_proxied_wrapped(context, svr_cfg, addr).await,
+            None => {}
+        }
+
+        if context.check_target_rejected(addr) {
+            let err = Error::new(
+                ErrorKind::PermissionDenied,
+                format!("{} is rejected by ACL rules", addr),
+            );
+            return Err(ProxyStreamError::new(err, false));
+        }
+
         if context.check_target_bypassed(addr).await {
             ProxyStream::connect_direct_wrapped(context, addr).await
         } else {
@@ -297,10 +436,10 @@ impl ProxyStream {
         let stream = match *addr {
             Address::SocketAddress(ref saddr) => tcp_stream_connect(&saddr, context.config()).await?,
             Address::DomainNameAddress(ref domain, port) => {
-                lookup_then!(context, domain, port, |saddr| {
-                    tcp_stream_connect(&saddr, context.config()).await
-                })?
-                .1
+                let addrs = context.dns_resolve(domain, port).await?;
+                connect_happy_eyeballs(&addrs, |saddr| tcp_stream_connect(saddr, context.config()))
+                    .await?
+                    .1
             }
         };
 
@@ -337,10 +476,31 @@ impl ProxyStream {
 
         Ok(ProxyStream {
             context,
-            connection: ProxyConnection::Proxied(ProxiedConnection::connected(proxy_stream, addr.clone())),
+            connection: ProxyConnection::Proxied(ProxiedConnection::connected(proxy_stream, addr.clone(), svr_cfg)),
         })
     }
 
+    /// Connect to remote via whichever of `servers` is connected first, the others are aborted
+    ///
+    /// Returns the index of the server used in `servers`, which must not be empty.
+    /// Only connections to servers are raced, because requests are sent with the first payload.
+    pub async fn connect_proxied_raced(
+        servers: Vec<(SharedContext, &ServerConfig)>,
+        addr: &Address,
+    ) -> io::Result<(ProxyStream, usize)> {
+        let connects = servers.into_iter().enumerate().map(|(idx, (context, svr_cfg))| {
+            async move {
+                let stream = ProxyStream::connect_proxied(context, svr_cfg, addr).await?;
+                Ok::<_, io::Error>((stream, idx))
+            }
+            .boxed()
+        });
+
+        // Errors of the others are ignored, only the last one is returned if all of them failed
+        let (connected, _) = future::select_ok(connects).await?;
+        Ok(connected)
+    }
+
     async fn connect_proxied_wrapped(
         context: SharedContext,
         svr_cfg: &ServerConfig,
@@ -436,27 +596,17 @@ async fn connect_proxy_server_internal(
             Ok(STcpStream::new(stream, timeout, true))
         }
         ServerAddr::DomainName(ref domain, port) => {
-            let result = lookup_then!(context, domain.as_str(), *port, |addr| {
-                match try_timeout(tcp_stream_connect(&addr, context.config()), timeout).await {
-                    Ok(s) => Ok(STcpStream::new(s, timeout, true)),
-                    Err(e) => {
-                        trace!(
-                            "failed to connect proxy {} ({}:{} ({})) try another (err: {})",
-                            orig_svr_addr,
-                            domain,
-                            port,
-                            addr,
-                            e
-                        );
-                        Err(e)
-                    }
-                }
-            });
+            let addrs = context.resolve_server_addr(domain.as_str(), *port).await?;
+            let result = connect_happy_eyeballs(&addrs, |addr| {
+                try_timeout(tcp_stream_connect(addr, context.config()), timeout)
+            })
+            .await;
 
             match result {
                 Ok((addr, s)) => {
                     trace!("connected proxy {} ({}:{} ({}))", orig_svr_addr, domain, port, addr);
-                    Ok(s)
+                    context.server_addr_cache().pin(domain, *port, addr);
+                    Ok(STcpStream::new(s, timeout, true))
                 }
                 Err(err) => {
                     debug!(
@@ -470,6 +620,16 @@ async fn connect_proxy_server_internal(
     }
 }
 
+/// Establish a TCP connection with the proxy server and close it immediately, for probing latency
+///
+/// Connects to the server's address directly, bypassing plugins
+pub(crate) async fn probe_proxy_server(context: &Context, svr_cfg: &ServerConfig) -> io::Result<()> {
+    let svr_addr = svr_cfg.addr();
+    connect_proxy_server_internal(context, svr_addr, svr_addr, None)
+        .await
+        .map(|_| ())
+}
+
 /// Connect to proxy server with `ServerConfig`
 async fn connect_proxy_server(context: &Context, svr_cfg: &ServerConfig) -> io::Result<STcpStream> {
     let timeout = svr_cfg.timeout();
@@ -491,8 +651,12 @@ async fn connect_proxy_server(context: &Context, svr_cfg: &ServerConfig) -> io::
             any(feature = "local-http-native-tls", feature = "local-http-rustls")
         ))]
         ConfigType::HttpsLocal => svr_cfg.external_addr(),
+        #[cfg(feature = "local-http")]
+        ConfigType::MixedLocal => svr_cfg.external_addr(),
         #[cfg(feature = "local-redir")]
         ConfigType::RedirLocal => svr_cfg.external_addr(),
+        #[cfg(feature = "local-tun")]
+        ConfigType::TunLocal => svr_cfg.external_addr(),
 
         ConfigType::Manager => unreachable!("ConfigType::Manager shouldn't need to connect to proxy server"),
     };
@@ -3,7 +3,7 @@
 use std::{
     io::{self, ErrorKind},
     net::SocketAddr,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use futures::future::{self, Either};
@@ -14,6 +14,7 @@ use tokio::{
 };
 
 use crate::{
+    config::RedirDnsPolicy,
     context::SharedContext,
     relay::{
         loadbalancing::server::{PlainPingBalancer, ServerType, SharedPlainServerStatistic},
@@ -35,21 +36,35 @@ async fn establish_client_tcp_redir<'a>(
 ) -> io::Result<()> {
     let svr_cfg = server.server_config();
 
-    let svr_s = ProxyStream::connect(server.clone_context(), svr_cfg, addr).await?;
+    let svr_s = match server.config().redir_dns_policy {
+        RedirDnsPolicy::Proxy if RedirDnsPolicy::is_dns_port(addr.port()) => {
+            trace!("REDIR DNS traffic {} -> {} is forced to be proxied", client_addr, addr);
+            ProxyStream::connect_proxied(server.clone_context(), svr_cfg, addr).await?
+        }
+        _ => {
+            let route = server.context().check_process_route(client_addr).await;
+            ProxyStream::connect_with_route(server.clone_context(), svr_cfg, addr, route).await?
+        }
+    };
+    // Bypassed targets are not relayed through the server
+    let proxied = svr_s.is_proxied();
     let (mut svr_r, mut svr_w) = svr_s.split();
 
     let (mut r, mut w) = s.split();
 
     use super::utils::{copy_p2s, copy_s2p};
 
-    let rhalf = copy_p2s(svr_cfg.method(), &mut r, &mut svr_w);
-    let whalf = copy_s2p(svr_cfg.method(), &mut svr_r, &mut w);
+    let keep_busy_interval = server.config().keep_busy_interval;
+    let egress = server.context().server_state().egress_limiter();
+    let rhalf = copy_p2s(svr_cfg.method(), keep_busy_interval, egress, &mut r, &mut svr_w);
+    let whalf = copy_s2p(svr_cfg.method(), None, &mut svr_r, &mut w);
 
     tokio::pin!(rhalf);
     tokio::pin!(whalf);
 
     debug!("REDIR relay established {} <-> {}", client_addr, addr);
 
+    let start = Instant::now();
     match future::select(rhalf, whalf).await {
         Either::Left((Ok(..), _)) => trace!("REDIR relay {} -> {} closed", client_addr, addr),
         Either::Left((Err(err), _)) => {
@@ -59,12 +74,20 @@ async fn establish_client_tcp_redir<'a>(
                 debug!("REDIR relay {} -> {} closed with error {}", client_addr, addr, err);
             }
         }
-        Either::Right((Ok(..), _)) => trace!("REDIR relay {} <- {} closed", client_addr, addr),
+        Either::Right((Ok(n), _)) => {
+            trace!("REDIR relay {} <- {} closed", client_addr, addr);
+            if proxied {
+                server.report_transfer(n, start.elapsed()).await;
+            }
+        }
         Either::Right((Err(err), _)) => {
             if let ErrorKind::TimedOut = err.kind() {
                 trace!("REDIR relay {} <- {} closed with error {}", client_addr, addr, err);
             } else {
                 debug!("REDIR relay {} <- {} closed with error {}", client_addr, addr, err);
+                if proxied {
+                    server.report_relay_error().await;
+                }
             }
         }
     }
@@ -74,7 +97,11 @@ async fn establish_client_tcp_redir<'a>(
     Ok(())
 }
 
-async fn handle_redir_client(server: &SharedPlainServerStatistic, s: TcpStream, daddr: SocketAddr) -> io::Result<()> {
+async fn handle_redir_client(
+    server: &SharedPlainServerStatistic,
+    s: TcpStream,
+    target_addr: Address,
+) -> io::Result<()> {
     // let svr_cfg = server.server_config();
     //
     // if let Err(err) = s.set_keepalive(svr_cfg.timeout()) {
@@ -89,8 +116,11 @@ async fn handle_redir_client(server: &SharedPlainServerStatistic, s: TcpStream,
 
     let client_addr = s.peer_addr()?;
 
-    // Get forward address from socket
-    let target_addr = Address::from(daddr);
+    if server.config().redir_dns_policy == RedirDnsPolicy::Block && RedirDnsPolicy::is_dns_port(target_addr.port()) {
+        debug!("REDIR DNS traffic {} -> {} is blocked", client_addr, target_addr);
+        return Ok(());
+    }
+
     establish_client_tcp_redir(server, s, client_addr, &target_addr).await
 }
 
@@ -111,6 +141,8 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
     info!("shadowsocks TCP redirect listening on {}", actual_local_addr);
 
     loop {
+        context.wait_memory_available().await;
+
         let (socket, peer_addr) = match listener.accept().await {
             Ok(s) => s,
             Err(err) => {
@@ -119,11 +151,10 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
                 continue;
             }
         };
-        let server = servers.pick_server();
-
         trace!("got connection {}", peer_addr);
-        trace!("picked proxy server: {:?}", server.server_config());
 
+        let context = context.clone();
+        let servers = servers.clone();
         tokio::spawn(async move {
             let dst_addr = match socket.destination_addr(redir_ty) {
                 Ok(d) => d,
@@ -136,7 +167,25 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
                 }
             };
 
-            if let Err(err) = handle_redir_client(&server, socket, dst_addr).await {
+            // Get forward address from socket, fake addresses of DNS relay are mapped back to domain names
+            let target_addr = match context.map_fake_addr(dst_addr) {
+                Ok(a) => a,
+                Err(err) => {
+                    error!("TCP redirect couldn't connect to {}, {}", dst_addr, err);
+                    return;
+                }
+            };
+
+            let server = match servers.acquire_server_for(&target_addr) {
+                Ok(s) => s,
+                Err(err) => {
+                    error!("TCP redirect couldn't pick a server for {}, {}", target_addr, err);
+                    return;
+                }
+            };
+            trace!("picked proxy server: {:?}", server.server_config());
+
+            if let Err(err) = handle_redir_client(&server, socket, target_addr).await {
                 debug!("TCP redirect client, error: {:?}", err);
             }
         });
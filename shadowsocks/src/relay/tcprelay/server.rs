@@ -1,12 +1,17 @@
 //! Relay for TCP server that running on the server side
 
-use std::{io, io::ErrorKind, net::SocketAddr, time::Duration};
+use std::{
+    io,
+    io::ErrorKind,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
 
 use futures::{
     future::{self, Either},
     stream::{FuturesUnordered, StreamExt},
 };
-use log::{debug, error, info, trace, warn};
+use log::{debug, error, info, warn};
 use tokio::{
     self,
     net::{TcpListener, TcpStream},
@@ -15,24 +20,202 @@ use tokio::{
 
 use crate::{
     config::ServerConfig,
-    context::SharedContext,
+    context::{Context, SharedContext},
     relay::{
-        flow::{SharedMultiServerFlowStatistic, SharedServerFlowStatistic},
+        flow::{
+            CloseReason,
+            ConnectionInfo,
+            SharedConnectionInfo,
+            SharedMultiServerFlowStatistic,
+            SharedServerFlowStatistic,
+        },
         socks5::Address,
+        speed_limit::SpeedLimitedStream,
+        sys::set_defer_accept,
         utils::try_timeout,
     },
 };
 
-use super::{monitor::TcpMonStream, utils::connect_tcp_stream, CryptoStream, STcpStream};
+use super::{
+    monitor::TcpMonStream,
+    utils::{connect_happy_eyeballs, connect_tcp_stream},
+    CryptoStream,
+    STcpStream,
+};
+
+/// What is known about a connection when it is closed, for access logs
+#[derive(Default)]
+struct AccessRecord {
+    user: Option<String>,
+    target: Option<Address>,
+}
 
-#[allow(clippy::cognitive_complexity)]
 async fn handle_client(
     context: SharedContext,
     flow_stat: SharedServerFlowStatistic,
+    connection_info: SharedConnectionInfo,
     svr_cfg: &ServerConfig,
     socket: TcpStream,
     peer_addr: SocketAddr,
-) -> io::Result<()> {
+) {
+    let sampled = context.config().sampling.sample(&peer_addr.ip());
+    let start = Instant::now();
+    let mut access = AccessRecord::default();
+
+    let reason = handle_client_relay(
+        context.clone(),
+        flow_stat.clone(),
+        connection_info,
+        svr_cfg,
+        socket,
+        peer_addr,
+        sampled,
+        &mut access,
+    )
+    .await;
+    let reason = if context.server_running() {
+        reason
+    } else {
+        CloseReason::Shutdown
+    };
+
+    flow_stat.close().incr(reason);
+    flow_stat.latency().duration().observe(start.elapsed());
+    sampled_log!(sampled, debug, "RELAY {} closed, reason: {}", peer_addr, reason);
+
+    if context.config().access_log {
+        log_access(svr_cfg, peer_addr, &access, reason, start.elapsed());
+    }
+}
+
+/// Log with target `shadowsocks::access::PORT`, or `shadowsocks::access::PORT::USER` for users of multi-user servers
+///
+/// So that logs of different tenants could be directed to different appenders by loggers' names
+fn log_access(
+    svr_cfg: &ServerConfig,
+    peer_addr: SocketAddr,
+    access: &AccessRecord,
+    reason: CloseReason,
+    elapsed: Duration,
+) {
+    let port = svr_cfg.addr().port();
+    let target = match access.user {
+        Some(ref user) => format!("shadowsocks::access::{}::{}", port, user),
+        None => format!("shadowsocks::access::{}", port),
+    };
+
+    info!(
+        target: target.as_str(),
+        "proto=tcp port={} user={} peer={} target={} reason={} duration={}ms",
+        port,
+        access.user.as_deref().unwrap_or("-"),
+        peer_addr,
+        access.target.as_ref().map(ToString::to_string).unwrap_or_else(|| "-".to_owned()),
+        reason,
+        elapsed.as_millis()
+    );
+}
+
+/// Classify errors returned from the relay copying
+///
+/// `from_client` is `true` if `err` is returned from the CLIENT -> SERVER half, which reads from the encrypted stream
+fn classify_relay_error(err: &io::Error, from_client: bool) -> CloseReason {
+    match err.kind() {
+        ErrorKind::TimedOut => CloseReason::IdleTimeout,
+        ErrorKind::UnexpectedEof if from_client => CloseReason::ClientEof,
+        ErrorKind::UnexpectedEof => CloseReason::RemoteEof,
+        // Decryption failures are reported as `Other` or `InvalidData` by the `CryptoStream`
+        ErrorKind::Other | ErrorKind::InvalidData if from_client => CloseReason::CryptoError,
+        _ => CloseReason::IoError,
+    }
+}
+
+/// Connect to `remote_addr` (resolved addresses are raced by Happy Eyeballs), errors are logged
+async fn connect_remote(
+    context: &Context,
+    remote_addr: &Address,
+    bind_addr: &Option<SocketAddr>,
+    timeout: Option<Duration>,
+    sampled: bool,
+) -> io::Result<TcpStream> {
+    match *remote_addr {
+        Address::SocketAddress(ref saddr) => {
+            // NOTE: ACL is already checked by the caller, connect directly
+
+            match try_timeout(connect_tcp_stream(saddr, bind_addr, context.config()), timeout).await {
+                Ok(s) => {
+                    if let Some(ref ba) = bind_addr {
+                        sampled_log!(sampled, debug, "connected to remote {} via {}", saddr, ba);
+                    } else {
+                        sampled_log!(sampled, debug, "connected to remote {}", saddr);
+                    }
+                    Ok(s)
+                }
+                Err(err) => {
+                    if let Some(ref ba) = bind_addr {
+                        error!("failed to connect remote {} via {}, {}", saddr, ba, err);
+                    } else {
+                        error!("failed to connect remote {}, {}", saddr, err);
+                    }
+                    Err(err)
+                }
+            }
+        }
+        Address::DomainNameAddress(ref dname, port) => {
+            let addrs = context.dns_resolve(dname.as_str(), port).await?;
+            let result = connect_happy_eyeballs(&addrs, |addr| {
+                try_timeout(connect_tcp_stream(addr, bind_addr, context.config()), timeout)
+            })
+            .await;
+
+            match result {
+                Ok((addr, s)) => {
+                    if let Some(ref ba) = bind_addr {
+                        sampled_log!(
+                            sampled,
+                            debug,
+                            "connected remote {}:{} (resolved: {}) via {}",
+                            dname,
+                            port,
+                            addr,
+                            ba
+                        );
+                    } else {
+                        sampled_log!(
+                            sampled,
+                            debug,
+                            "connected remote {}:{} (resolved: {})",
+                            dname,
+                            port,
+                            addr
+                        );
+                    }
+                    Ok(s)
+                }
+                Err(err) => {
+                    if let Some(ref ba) = bind_addr {
+                        error!("failed to connect remote {}:{} via {}, {}", dname, port, ba, err);
+                    } else {
+                        error!("failed to connect remote {}:{}, {}", dname, port, err);
+                    }
+                    Err(err)
+                }
+            }
+        }
+    }
+}
+
+#[allow(clippy::cognitive_complexity)]
+async fn handle_client_relay(
+    context: SharedContext,
+    flow_stat: SharedServerFlowStatistic,
+    connection_info: SharedConnectionInfo,
+    svr_cfg: &ServerConfig,
+    socket: TcpStream,
+    peer_addr: SocketAddr,
+    sampled: bool,
+    access: &mut AccessRecord,
+) -> CloseReason {
     let timeout = svr_cfg.timeout();
 
     // FIXME: set_keepalive have been removed from tokio 0.3
@@ -40,17 +223,28 @@ async fn handle_client(
     //     error!("failed to set keep alive: {:?}", err);
     // }
 
-    trace!("got connection addr {} with proxy server {:?}", peer_addr, svr_cfg);
+    sampled_log!(
+        sampled,
+        trace,
+        "got connection addr {} with proxy server {:?}",
+        peer_addr,
+        svr_cfg
+    );
 
     let mut stream = STcpStream::new(socket, timeout, true);
-    stream.set_nodelay(context.config().no_delay)?;
+    if let Err(err) = stream.set_nodelay(context.config().no_delay) {
+        error!("failed to set TCP_NODELAY on accepted socket, error: {}", err);
+        return CloseReason::IoError;
+    }
 
-    // Wrap with a data transfer monitor
-    let stream = TcpMonStream::new(flow_stat, stream);
+    // Wrap with a data transfer monitor, then limit the speed of the client's traffic
+    let mut stream = TcpMonStream::new(flow_stat.clone(), stream);
+    stream.set_connection_info(connection_info.clone());
+    let stream = SpeedLimitedStream::new(stream, svr_cfg.speed_limiter().tcp_buckets(&peer_addr));
 
     // Do server-client handshake
     // Perform encryption IV exchange
-    let mut stream = CryptoStream::new(context.clone(), stream, svr_cfg);
+    let mut stream = CryptoStream::new_server(context.clone(), stream, svr_cfg);
 
     // Read remote Address
     let remote_addr = match Address::read_from(&mut stream).await {
@@ -63,125 +257,183 @@ async fn handle_client(
 
             // Hold the TCP connection until it closes by itself for preventing active probing.
             // Further discussion: https://github.com/shadowsocks/shadowsocks-rust/issues/292
-            let mut tcp = stream.into_inner().into_inner().into_inner();
+            let mut tcp = stream.into_inner().into_inner().into_inner().into_inner();
             let _ = super::ignore_until_end(&mut tcp).await;
 
-            return Err(From::from(err));
+            return CloseReason::CryptoError;
         }
     };
 
-    debug!("RELAY {} <-> {} establishing", peer_addr, remote_addr);
+    if let Some(idx) = stream.key_index() {
+        match svr_cfg.users().get(idx) {
+            Some(user) => {
+                sampled_log!(
+                    sampled,
+                    debug,
+                    "client {} identified as user {}",
+                    peer_addr,
+                    user.name()
+                );
+                stream.get_mut().set_user_flow_stat(flow_stat.user(user.name()));
+                connection_info.set_user(user.name().to_owned());
+                access.user = Some(user.name().to_owned());
+            }
+            None => sampled_log!(sampled, debug, "client {} is using the previous password", peer_addr),
+        }
+    }
+
+    sampled_log!(sampled, debug, "RELAY {} <-> {} establishing", peer_addr, remote_addr);
+    access.target = Some(remote_addr.clone());
+    connection_info.set_target(remote_addr.to_string());
+    flow_stat.latency().handshake().observe(connection_info.elapsed());
+    if let Some(destination_flow_stat) = flow_stat.destination(&remote_addr) {
+        stream.get_mut().set_destination_flow_stat(destination_flow_stat);
+    }
 
     // Check if remote_addr matches any ACL rules
     if context.check_outbound_blocked(&remote_addr).await {
         warn!("outbound {} is blocked by ACL rules", remote_addr);
-        return Ok(());
+        return CloseReason::AclReject;
     }
 
-    let bind_addr = match context.config().local_addr {
-        None => None,
-        Some(ref addr) => {
-            let ba = addr.bind_addr(&context).await?;
-            Some(ba)
+    // ACL of the user or of the server, checked besides the global one
+    if let Some(acl) = svr_cfg.outbound_acl(stream.key_index()) {
+        if acl.check_outbound_blocked(&context, &remote_addr).await {
+            warn!(
+                "outbound {} is blocked by ACL rules of server {}",
+                remote_addr,
+                svr_cfg.addr()
+            );
+            return CloseReason::AclReject;
         }
-    };
-
-    let mut remote_stream = match remote_addr {
-        Address::SocketAddress(ref saddr) => {
-            // NOTE: ACL is already checked above, connect directly
+    }
 
-            match try_timeout(connect_tcp_stream(saddr, &bind_addr), timeout).await {
-                Ok(s) => {
-                    if let Some(ref ba) = bind_addr {
-                        debug!("connected to remote {} via {}", saddr, ba);
-                    } else {
-                        debug!("connected to remote {}", saddr);
-                    }
-                    s
-                }
-                Err(err) => {
-                    if let Some(ref ba) = bind_addr {
-                        error!("failed to connect remote {} via {}, {}", saddr, ba, err);
-                    } else {
-                        error!("failed to connect remote {}, {}", saddr, err);
-                    }
-                    return Err(err);
-                }
+    let bind_addr = match context.config().local_addr {
+        None => None,
+        Some(ref addr) => match addr.bind_addr(&context).await {
+            Ok(ba) => Some(ba),
+            Err(err) => {
+                error!("failed to resolve bind address {}, {}", addr, err);
+                return CloseReason::ConnectError;
             }
-        }
-        Address::DomainNameAddress(ref dname, port) => {
-            let result = lookup_then!(&context, dname.as_str(), port, |addr| {
-                match try_timeout(connect_tcp_stream(&addr, &bind_addr), timeout).await {
-                    Ok(s) => Ok(s),
-                    Err(err) => {
-                        debug!(
-                            "failed to connect remote {}:{} (resolved: {}), {}, try others",
-                            dname, port, addr, err
-                        );
-                        Err(err)
-                    }
-                }
-            });
+        },
+    };
 
-            match result {
-                Ok((addr, s)) => {
-                    if let Some(ref ba) = bind_addr {
-                        debug!("connected remote {}:{} (resolved: {}) via {}", dname, port, addr, ba);
-                    } else {
-                        debug!("connected remote {}:{} (resolved: {})", dname, port, addr);
-                    }
-                    s
-                }
-                Err(err) => {
-                    if let Some(ref ba) = bind_addr {
-                        error!("failed to connect remote {}:{} via {}, {}", dname, port, ba, err);
-                    } else {
-                        error!("failed to connect remote {}:{}, {}", dname, port, err);
-                    }
-                    return Err(err);
+    // Timeout and retries of connecting may be overridden by ACL's `[connect_policy:...]` sections
+    let policy = context.acl().and_then(|acl| acl.check_connect_policy(&remote_addr));
+    let connect_timeout = policy.and_then(|p| p.timeout).or(timeout);
+    let retries = policy.map(|p| p.retries).unwrap_or(0);
+
+    // Delay before retrying, doubled every time, but no longer than the connect timeout
+    let mut retry_delay = Duration::from_millis(100);
+
+    let mut attempts = 0;
+    let mut remote_stream = loop {
+        match connect_remote(&context, &remote_addr, &bind_addr, connect_timeout, sampled).await {
+            Ok(s) => break s,
+            Err(..) if attempts < retries => {
+                attempts += 1;
+                if let Some(t) = connect_timeout {
+                    retry_delay = retry_delay.min(t);
                 }
+                sampled_log!(
+                    sampled,
+                    debug,
+                    "retrying to connect remote {} ({}/{}) after {:?}",
+                    remote_addr,
+                    attempts,
+                    retries,
+                    retry_delay
+                );
+                time::sleep(retry_delay).await;
+                retry_delay *= 2;
             }
+            Err(..) => return CloseReason::ConnectError,
         }
     };
 
-    debug!("RELAY {} <-> {} established", peer_addr, remote_addr);
+    sampled_log!(sampled, debug, "RELAY {} <-> {} established", peer_addr, remote_addr);
 
     let (mut cr, mut cw) = stream.split();
     let (mut sr, mut sw) = remote_stream.split();
 
     use super::utils::{copy_p2s, copy_s2p};
 
+    let egress = context.server_state().egress_limiter();
+
     // CLIENT -> SERVER
-    let rhalf = copy_s2p(svr_cfg.method(), &mut cr, &mut sw);
+    let rhalf = copy_s2p(svr_cfg.method(), egress, &mut cr, &mut sw);
 
     // CLIENT <- SERVER
-    let whalf = copy_p2s(svr_cfg.method(), &mut sr, &mut cw);
+    let whalf = copy_p2s(
+        svr_cfg.method(),
+        context.config().keep_busy_interval,
+        egress,
+        &mut sr,
+        &mut cw,
+    );
 
     tokio::pin!(rhalf);
     tokio::pin!(whalf);
 
-    match future::select(rhalf, whalf).await {
-        Either::Left((Ok(_), _)) => trace!("RELAY {} -> {} closed", peer_addr, remote_addr),
+    let reason = match future::select(rhalf, whalf).await {
+        Either::Left((Ok(_), _)) => {
+            sampled_log!(sampled, trace, "RELAY {} -> {} closed", peer_addr, remote_addr);
+            CloseReason::ClientEof
+        }
         Either::Left((Err(err), _)) => {
             if let ErrorKind::TimedOut = err.kind() {
-                trace!("RELAY {} -> {} closed with error {}", peer_addr, remote_addr, err);
+                sampled_log!(
+                    sampled,
+                    trace,
+                    "RELAY {} -> {} closed with error {}",
+                    peer_addr,
+                    remote_addr,
+                    err
+                );
             } else {
-                debug!("RELAY {} -> {} closed with error {}", peer_addr, remote_addr, err);
+                sampled_log!(
+                    sampled,
+                    debug,
+                    "RELAY {} -> {} closed with error {}",
+                    peer_addr,
+                    remote_addr,
+                    err
+                );
             }
+            classify_relay_error(&err, true)
+        }
+        Either::Right((Ok(_), _)) => {
+            sampled_log!(sampled, trace, "RELAY {} <- {} closed", peer_addr, remote_addr);
+            CloseReason::RemoteEof
         }
-        Either::Right((Ok(_), _)) => trace!("RELAY {} <- {} closed", peer_addr, remote_addr),
         Either::Right((Err(err), _)) => {
             if let ErrorKind::TimedOut = err.kind() {
-                trace!("RELAY {} <- {} closed with error {}", peer_addr, remote_addr, err);
+                sampled_log!(
+                    sampled,
+                    trace,
+                    "RELAY {} <- {} closed with error {}",
+                    peer_addr,
+                    remote_addr,
+                    err
+                );
             } else {
-                debug!("RELAY {} <- {} closed with error {}", peer_addr, remote_addr, err);
+                sampled_log!(
+                    sampled,
+                    debug,
+                    "RELAY {} <- {} closed with error {}",
+                    peer_addr,
+                    remote_addr,
+                    err
+                );
             }
+            classify_relay_error(&err, false)
         }
-    }
+    };
 
-    debug!("RELAY {} <-> {} closing", peer_addr, remote_addr);
+    sampled_log!(sampled, debug, "RELAY {} <-> {} closing", peer_addr, remote_addr);
 
-    Ok(())
+    reason
 }
 
 /// Runs the server
@@ -189,6 +441,17 @@ pub async fn run(context: SharedContext, flow_stat: SharedMultiServerFlowStatist
     let vec_fut = FuturesUnordered::new();
 
     for (idx, svr_cfg) in context.config().server.iter().enumerate() {
+        // Refused once here instead of for every connections
+        if context.config().security_level.rejects(svr_cfg.method()) {
+            error!(
+                target: "shadowsocks::security",
+                "event=reject_weak_method proto=tcp server={} method={}",
+                svr_cfg.addr(),
+                svr_cfg.method()
+            );
+            continue;
+        }
+
         let listener = {
             let addr = svr_cfg.external_addr();
             let addr = addr.bind_addr(&context).await?;
@@ -198,6 +461,13 @@ pub async fn run(context: SharedContext, flow_stat: SharedMultiServerFlowStatist
                 err
             })?;
 
+            // Connections without any data (like scanners' bare SYNs) are not handed to us
+            if let Some(timeout) = context.config().tcp_defer_accept {
+                if let Err(err) = set_defer_accept(&listener, timeout) {
+                    warn!("failed to enable deferred accept on {}, {}", addr, err);
+                }
+            }
+
             let local_addr = listener.local_addr().expect("determine port bound to");
             info!("shadowsocks TCP listening on {}", local_addr);
 
@@ -213,25 +483,49 @@ pub async fn run(context: SharedContext, flow_stat: SharedMultiServerFlowStatist
 
         vec_fut.push(async move {
             loop {
+                context.wait_memory_available().await;
+
                 match listener.accept().await {
                     Ok((socket, peer_addr)) => {
                         // Check ACL rules
                         if context.check_client_blocked(&peer_addr).await {
                             warn!("client {} is blocked by ACL rules", peer_addr);
+                            flow_stat.close().incr(CloseReason::AclReject);
+                            continue;
+                        }
+
+                        if flow_stat.quota_exceeded(context.server_config(idx).quota()) {
+                            debug!("client {} is rejected, traffic quota exceeded", peer_addr);
+                            flow_stat.close().incr(CloseReason::Quota);
                             continue;
                         }
 
+                        let connection_info = ConnectionInfo::new_shared(peer_addr);
+                        let (client_fut, client_handle) = {
+                            let flow_stat = flow_stat.clone();
+                            let context = context.clone();
+                            let connection_info = connection_info.clone();
+
+                            future::abortable(async move {
+                                // Retrieve server config reference from context again
+                                //
+                                // Because the svr_cfg outside doesn't live long enough. WHAT??
+                                let svr_cfg = context.server_config(idx);
+
+                                handle_client(context.clone(), flow_stat, connection_info, svr_cfg, socket, peer_addr)
+                                    .await;
+                            })
+                        };
+
+                        // Tracked for draining, aborted if they are still alive after the deadline
+                        let connection_id = flow_stat.track_connection(client_handle, connection_info);
                         let flow_stat = flow_stat.clone();
-                        let context = context.clone();
 
                         tokio::spawn(async move {
-                            // Retrieve server config reference from context again
-                            //
-                            // Because the svr_cfg outside doesn't live long enough. WHAT??
-                            let svr_cfg = context.server_config(idx);
-
-                            // Error is ignored because it is already logged
-                            let _ = handle_client(context.clone(), flow_stat, svr_cfg, socket, peer_addr).await;
+                            if client_fut.await.is_err() {
+                                flow_stat.close().incr(CloseReason::Shutdown);
+                            }
+                            flow_stat.untrack_connection(connection_id);
                         });
                     }
                     Err(err) => {
@@ -244,6 +538,14 @@ pub async fn run(context: SharedContext, flow_stat: SharedMultiServerFlowStatist
         });
     }
 
+    if vec_fut.is_empty() {
+        let err = io::Error::new(
+            ErrorKind::PermissionDenied,
+            "all servers are rejected by security_level",
+        );
+        return Err(err);
+    }
+
     match vec_fut.into_future().await.0 {
         Some(()) => {
             error!("one of TCP servers exited unexpectly");
@@ -3,7 +3,7 @@
 use std::{
     io::{self, ErrorKind},
     net::SocketAddr,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use futures::future::{self, Either};
@@ -35,7 +35,8 @@ async fn handle_socks4_connect(
     // NOTE: Shadowsocks server uses SOCKS5 Address
     let ss_addr = addr.into();
 
-    let mut svr_s = match ProxyStream::connect(server.clone_context(), svr_cfg, &ss_addr).await {
+    let route = context.check_process_route(client_addr).await;
+    let mut svr_s = match ProxyStream::connect_with_route(server.clone_context(), svr_cfg, &ss_addr, route).await {
         Ok(svr_s) => {
             // Tell the client that we are ready
             let handshake_rsp = HandshakeResponse::new(ResultCode::RequestGranted);
@@ -81,19 +82,24 @@ async fn handle_socks4_connect(
         }
     }
 
+    // Bypassed targets are not relayed through the server
+    let proxied = svr_s.is_proxied();
     let (mut svr_r, mut svr_w) = svr_s.split();
     let (mut r, mut w) = stream.split();
 
     use super::utils::{copy_p2s, copy_s2p};
 
-    let rhalf = copy_p2s(svr_cfg.method(), &mut r, &mut svr_w);
-    let whalf = copy_s2p(svr_cfg.method(), &mut svr_r, &mut w);
+    let keep_busy_interval = context.config().keep_busy_interval;
+    let egress = context.server_state().egress_limiter();
+    let rhalf = copy_p2s(svr_cfg.method(), keep_busy_interval, egress, &mut r, &mut svr_w);
+    let whalf = copy_s2p(svr_cfg.method(), None, &mut svr_r, &mut w);
 
     tokio::pin!(rhalf);
     tokio::pin!(whalf);
 
     debug!("CONNECT relay established {} <-> {}", client_addr, ss_addr);
 
+    let start = Instant::now();
     match future::select(rhalf, whalf).await {
         Either::Left((Ok(..), _)) => trace!("CONNECT relay {} -> {} closed", client_addr, ss_addr),
         Either::Left((Err(err), _)) => {
@@ -103,12 +109,20 @@ async fn handle_socks4_connect(
                 debug!("CONNECT relay {} -> {} closed with error {}", client_addr, ss_addr, err);
             }
         }
-        Either::Right((Ok(..), _)) => trace!("CONNECT relay {} <- {} closed", client_addr, ss_addr),
+        Either::Right((Ok(n), _)) => {
+            trace!("CONNECT relay {} <- {} closed", client_addr, ss_addr);
+            if proxied {
+                server.report_transfer(n, start.elapsed()).await;
+            }
+        }
         Either::Right((Err(err), _)) => {
             if let ErrorKind::TimedOut = err.kind() {
                 trace!("CONNECT relay {} <- {} closed with error {}", client_addr, ss_addr, err);
             } else {
                 debug!("CONNECT relay {} <- {} closed with error {}", client_addr, ss_addr, err);
+                if proxied {
+                    server.report_relay_error().await;
+                }
             }
         }
     }
@@ -118,7 +132,7 @@ async fn handle_socks4_connect(
     Ok(())
 }
 
-async fn handle_socks4_client(server: &SharedPlainServerStatistic, s: TcpStream) -> io::Result<()> {
+async fn handle_socks4_client(servers: &PlainPingBalancer, s: TcpStream) -> io::Result<()> {
     // let svr_cfg = server.server_config();
     //
     // FIXME: set_keepalive have been removed from tokio 0.3
@@ -144,7 +158,18 @@ async fn handle_socks4_client(server: &SharedPlainServerStatistic, s: TcpStream)
         Command::Connect => {
             debug!("CONNECT {}", handshake_req.dst);
 
-            handle_socks4_connect(server, s, client_addr, handshake_req.dst).await
+            let server = match servers.acquire_server_for(&handshake_req.dst.clone().into()) {
+                Ok(s) => s,
+                Err(err) => {
+                    error!("failed to pick a server for {}, {}", handshake_req.dst, err);
+                    let handshake_rsp = HandshakeResponse::new(ResultCode::RequestRejectedOrFailed);
+                    handshake_rsp.write_to(&mut s).await?;
+                    return Err(err);
+                }
+            };
+            trace!("picked proxy server: {:?}", server.server_config());
+
+            handle_socks4_connect(&server, s, client_addr, handshake_req.dst).await
         }
         Command::Bind => {
             warn!("BIND is not supported");
@@ -169,11 +194,13 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
 
     let actual_local_addr = listener.local_addr().expect("determine port bound to");
 
-    let servers = PlainPingBalancer::new(context, ServerType::Tcp).await;
+    let servers = PlainPingBalancer::new(context.clone(), ServerType::Tcp).await;
 
     info!("shadowsocks SOCKS4/4a TCP listening on {}", actual_local_addr);
 
     loop {
+        context.wait_memory_available().await;
+
         let (socket, peer_addr) = match listener.accept().await {
             Ok(s) => s,
             Err(err) => {
@@ -182,13 +209,11 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
                 continue;
             }
         };
-        let server = servers.pick_server();
-
         trace!("got connection {}", peer_addr);
-        trace!("picked proxy server: {:?}", server.server_config());
 
+        let servers = servers.clone();
         tokio::spawn(async move {
-            if let Err(err) = handle_socks4_client(&server, socket).await {
+            if let Err(err) = handle_socks4_client(&servers, socket).await {
                 debug!("TCP socks4 client exited with error: {}", err);
             }
         });
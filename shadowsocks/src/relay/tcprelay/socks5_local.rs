@@ -3,7 +3,8 @@
 use std::{
     io::{self, ErrorKind},
     net::{Ipv4Addr, SocketAddr},
-    time::Duration,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use futures::future::{self, Either};
@@ -17,12 +18,22 @@ use tokio::{
 use crate::{
     context::SharedContext,
     relay::{
-        loadbalancing::server::{PlainPingBalancer, ServerType, SharedPlainServerStatistic},
-        socks5::{self, Address, HandshakeRequest, HandshakeResponse, TcpRequestHeader, TcpResponseHeader},
+        loadbalancing::server::{EmptyServerData, PlainPingBalancer, ServerSlot, ServerType},
+        local_auth::LocalAuthenticator,
+        socks5::{
+            self,
+            Address,
+            HandshakeRequest,
+            HandshakeResponse,
+            PasswdAuthRequest,
+            PasswdAuthResponse,
+            TcpRequestHeader,
+            TcpResponseHeader,
+        },
     },
 };
 
-use super::{ignore_until_end, ProxyStream};
+use super::{ignore_until_end, local::connect_raced};
 
 #[derive(Debug, Clone)]
 struct UdpConfig {
@@ -31,33 +42,26 @@ struct UdpConfig {
 }
 
 async fn handle_socks5_connect(
-    server: &SharedPlainServerStatistic,
+    server: ServerSlot<EmptyServerData>,
+    other: Option<ServerSlot<EmptyServerData>>,
     stream: &mut TcpStream,
     client_addr: SocketAddr,
     addr: &Address,
 ) -> io::Result<()> {
-    let context = server.context();
-    let svr_cfg = server.server_config();
-
-    let svr_s = match ProxyStream::connect(server.clone_context(), svr_cfg, addr).await {
-        Ok(svr_s) => {
+    let route = server.context().check_process_route(client_addr).await;
+    let (svr_s, server) = match connect_raced(server, other, addr, route).await {
+        Ok((svr_s, server)) => {
             // Tell the client that we are ready
             let header = TcpResponseHeader::new(socks5::Reply::Succeeded, Address::SocketAddress(svr_s.local_addr()?));
             header.write_to(stream).await?;
 
             trace!("sent header: {:?}", header);
 
-            svr_s
+            (svr_s, server)
         }
-        Err(perr) => {
+        Err(err) => {
             use crate::relay::socks5::Reply;
 
-            if perr.is_proxied() {
-                // Report to global statistic
-                server.report_failure().await;
-            }
-
-            let err = perr.into_inner();
             let reply = match err.kind() {
                 ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
                 ErrorKind::ConnectionAborted => Reply::HostUnreachable,
@@ -72,6 +76,11 @@ async fn handle_socks5_connect(
         }
     };
 
+    let context = server.context();
+    let svr_cfg = server.server_config();
+
+    // Bypassed targets are not relayed through the server
+    let proxied = svr_s.is_proxied();
     let (mut svr_r, mut svr_w) = svr_s.split();
 
     // Reset `TCP_NODELAY` after Socks5 handshake
@@ -85,14 +94,17 @@ async fn handle_socks5_connect(
 
     use super::utils::{copy_p2s, copy_s2p};
 
-    let rhalf = copy_p2s(svr_cfg.method(), &mut r, &mut svr_w);
-    let whalf = copy_s2p(svr_cfg.method(), &mut svr_r, &mut w);
+    let keep_busy_interval = context.config().keep_busy_interval;
+    let egress = context.server_state().egress_limiter();
+    let rhalf = copy_p2s(svr_cfg.method(), keep_busy_interval, egress, &mut r, &mut svr_w);
+    let whalf = copy_s2p(svr_cfg.method(), None, &mut svr_r, &mut w);
 
     tokio::pin!(rhalf);
     tokio::pin!(whalf);
 
     debug!("CONNECT relay established {} <-> {}", client_addr, addr);
 
+    let start = Instant::now();
     match future::select(rhalf, whalf).await {
         Either::Left((Ok(..), _)) => trace!("CONNECT relay {} -> {} closed", client_addr, addr),
         Either::Left((Err(err), _)) => {
@@ -102,12 +114,20 @@ async fn handle_socks5_connect(
                 debug!("CONNECT relay {} -> {} closed with error {}", client_addr, addr, err);
             }
         }
-        Either::Right((Ok(..), _)) => trace!("CONNECT relay {} <- {} closed", client_addr, addr),
+        Either::Right((Ok(n), _)) => {
+            trace!("CONNECT relay {} <- {} closed", client_addr, addr);
+            if proxied {
+                server.report_transfer(n, start.elapsed()).await;
+            }
+        }
         Either::Right((Err(err), _)) => {
             if let ErrorKind::TimedOut = err.kind() {
                 trace!("CONNECT relay {} <- {} closed with error {}", client_addr, addr, err);
             } else {
                 debug!("CONNECT relay {} <- {} closed with error {}", client_addr, addr, err);
+                if proxied {
+                    server.report_relay_error().await;
+                }
             }
         }
     }
@@ -117,11 +137,78 @@ async fn handle_socks5_connect(
     Ok(())
 }
 
+/// Negotiate an authentication method, and authenticate the client with it
+async fn handle_socks5_auth(
+    s: &mut TcpStream,
+    client_addr: SocketAddr,
+    auth: Option<&LocalAuthenticator>,
+) -> io::Result<()> {
+    use std::io::Error;
+
+    let handshake_req = HandshakeRequest::read_from(s).await?;
+
+    // Socks5 handshakes
+    trace!("socks5 {:?}", handshake_req);
+
+    let auth = match auth {
+        Some(auth) => auth,
+        None => {
+            if !handshake_req.methods.contains(&socks5::SOCKS5_AUTH_METHOD_NONE) {
+                let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE);
+                resp.write_to(s).await?;
+
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    "client requires authentication, but `socks5_auth` is not configured",
+                ));
+            }
+
+            // Reply to client
+            let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_NONE);
+            trace!("Reply handshake {:?}", resp);
+            return resp.write_to(s).await;
+        }
+    };
+
+    if !handshake_req.methods.contains(&socks5::SOCKS5_AUTH_METHOD_PASSWORD) {
+        let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE);
+        resp.write_to(s).await?;
+
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "client doesn't support username/password authentication",
+        ));
+    }
+
+    let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_PASSWORD);
+    trace!("Reply handshake {:?}", resp);
+    resp.write_to(s).await?;
+
+    let auth_req = PasswdAuthRequest::read_from(s).await?;
+    trace!("socks5 {:?}", auth_req);
+
+    if auth.check(&auth_req.uname, &auth_req.passwd) {
+        let resp = PasswdAuthResponse::new(socks5::SOCKS5_PASSWD_AUTH_STATUS_SUCCEEDED);
+        resp.write_to(s).await
+    } else {
+        let resp = PasswdAuthResponse::new(socks5::SOCKS5_PASSWD_AUTH_STATUS_FAILURE);
+        resp.write_to(s).await?;
+
+        warn!(
+            "socks5 client {} failed to authenticate as user \"{}\"",
+            client_addr,
+            String::from_utf8_lossy(&auth_req.uname)
+        );
+        Err(Error::new(ErrorKind::PermissionDenied, "authentication failed"))
+    }
+}
+
 #[allow(clippy::cognitive_complexity)]
 async fn handle_socks5_client(
-    server: &SharedPlainServerStatistic,
+    servers: &PlainPingBalancer,
     mut s: TcpStream,
     udp_conf: UdpConfig,
+    auth: Option<&LocalAuthenticator>,
 ) -> io::Result<()> {
     // let svr_cfg = server.server_config();
     //
@@ -138,27 +225,7 @@ async fn handle_socks5_client(
 
     let client_addr = s.peer_addr()?;
 
-    let handshake_req = HandshakeRequest::read_from(&mut s).await?;
-
-    // Socks5 handshakes
-    trace!("socks5 {:?}", handshake_req);
-
-    if !handshake_req.methods.contains(&socks5::SOCKS5_AUTH_METHOD_NONE) {
-        use std::io::Error;
-
-        let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE);
-        resp.write_to(&mut s).await?;
-
-        return Err(Error::new(
-            ErrorKind::Other,
-            "currently shadowsocks-rust does not support authentication",
-        ));
-    } else {
-        // Reply to client
-        let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_NONE);
-        trace!("Reply handshake {:?}", resp);
-        resp.write_to(&mut s).await?;
-    }
+    handle_socks5_auth(&mut s, client_addr, auth).await?;
 
     // Fetch headers
     let header = match TcpRequestHeader::read_from(&mut s).await {
@@ -174,13 +241,25 @@ async fn handle_socks5_client(
     trace!("Socks5 {:?}", header);
 
     let addr = header.address;
+
+    let (server, other) = match servers.acquire_servers_raced_for(&addr) {
+        Ok(s) => s,
+        Err(err) => {
+            error!("failed to pick a server for {}, {}", addr, err);
+            let rh = TcpResponseHeader::new(socks5::Reply::GeneralFailure, addr);
+            rh.write_to(&mut s).await?;
+            return Err(err);
+        }
+    };
+    trace!("picked proxy server: {:?}", server.server_config());
+
     match header.command {
         socks5::Command::TcpConnect => {
             let enable_tcp = server.config().mode.enable_tcp();
             if enable_tcp {
                 debug!("CONNECT {}", addr);
 
-                match handle_socks5_connect(server, &mut s, client_addr, &addr).await {
+                match handle_socks5_connect(server, other, &mut s, client_addr, &addr).await {
                     Ok(..) => Ok(()),
                     Err(err) => Err(io::Error::new(
                         err.kind(),
@@ -218,8 +297,12 @@ async fn handle_socks5_client(
                 let rh = TcpResponseHeader::new(socks5::Reply::Succeeded, From::from(udp_conf.client_addr));
                 rh.write_to(&mut s).await?;
 
-                // Hold the connection until it ends by its own
-                ignore_until_end(&mut s).await?;
+                // Hold the connection until it ends by its own, UDP packets of the client are accepted until then
+                let context = server.context();
+                context.add_socks5_udp_client(client_addr.ip());
+                let result = ignore_until_end(&mut s).await;
+                context.remove_socks5_udp_client(client_addr.ip());
+                result?;
 
                 Ok(())
             } else {
@@ -233,6 +316,48 @@ async fn handle_socks5_client(
     }
 }
 
+/// States shared by SOCKS5 clients accepted on a listener
+#[derive(Clone)]
+pub(super) struct Socks5Handler {
+    servers: PlainPingBalancer,
+    udp_conf: UdpConfig,
+    auth: Option<Arc<LocalAuthenticator>>,
+}
+
+impl Socks5Handler {
+    /// Create a handler for clients accepted on `local_addr`, which is also the address of UDP ASSOCIATE
+    pub(super) async fn new(context: &SharedContext, local_addr: SocketAddr) -> io::Result<Socks5Handler> {
+        let udp_conf = UdpConfig {
+            enable_udp: context.config().mode.enable_udp(),
+            client_addr: local_addr,
+        };
+
+        let auth = match context.config().socks5_auth {
+            Some(ref auth) => {
+                let auth = LocalAuthenticator::load(auth).map_err(|err| {
+                    error!("failed to load users of `socks5_auth`, {}", err);
+                    err
+                })?;
+                Some(Arc::new(auth))
+            }
+            None => None,
+        };
+
+        let servers = PlainPingBalancer::new(context.clone(), ServerType::Tcp).await;
+
+        Ok(Socks5Handler {
+            servers,
+            udp_conf,
+            auth,
+        })
+    }
+
+    /// Serve a SOCKS5 client
+    pub(super) async fn handle(&self, s: TcpStream) -> io::Result<()> {
+        handle_socks5_client(&self.servers, s, self.udp_conf.clone(), self.auth.as_deref()).await
+    }
+}
+
 /// Starts a TCP local server with Socks5 proxy protocol
 pub async fn run(context: SharedContext) -> io::Result<()> {
     let local_addr = context.config().local_addr.as_ref().expect("local config");
@@ -245,16 +370,13 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
 
     let actual_local_addr = listener.local_addr().expect("determine port bound to");
 
-    let udp_conf = UdpConfig {
-        enable_udp: context.config().mode.enable_udp(),
-        client_addr: actual_local_addr,
-    };
-
-    let servers = PlainPingBalancer::new(context, ServerType::Tcp).await;
+    let handler = Socks5Handler::new(&context, actual_local_addr).await?;
 
     info!("shadowsocks SOCKS5 TCP listening on {}", actual_local_addr);
 
     loop {
+        context.wait_memory_available().await;
+
         let (socket, peer_addr) = match listener.accept().await {
             Ok(s) => s,
             Err(err) => {
@@ -263,14 +385,11 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
                 continue;
             }
         };
-        let server = servers.pick_server();
-
         trace!("got connection {}", peer_addr);
-        trace!("picked proxy server: {:?}", server.server_config());
 
-        let udp_conf = udp_conf.clone();
+        let handler = handler.clone();
         tokio::spawn(async move {
-            if let Err(err) = handle_socks5_client(&server, socket, udp_conf).await {
+            if let Err(err) = handler.handle(socket).await {
                 debug!("TCP socks5 client exited with error: {}", err);
             }
         });
@@ -0,0 +1,113 @@
+//! TCP relay of connections accepted by the TCP/IP stack of the TUN device
+
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    time::Instant,
+};
+
+use futures::future::{self, Either};
+use log::{debug, error, trace};
+use tokio::io::split;
+
+use crate::{
+    context::SharedContext,
+    relay::{
+        loadbalancing::server::{PlainPingBalancer, SharedPlainServerStatistic},
+        socks5::Address,
+        tun::stack::TunTcpStream,
+    },
+};
+
+use super::ProxyStream;
+
+async fn establish_client_tcp_tun(
+    server: &SharedPlainServerStatistic,
+    s: TunTcpStream,
+    client_addr: SocketAddr,
+    addr: &Address,
+) -> io::Result<()> {
+    let svr_cfg = server.server_config();
+
+    let svr_s = ProxyStream::connect_with_route(server.clone_context(), svr_cfg, addr, None).await?;
+    // Bypassed targets are not relayed through the server
+    let proxied = svr_s.is_proxied();
+    let (mut svr_r, mut svr_w) = svr_s.split();
+
+    let (mut r, mut w) = split(s);
+
+    use super::utils::{copy_p2s, copy_s2p};
+
+    let keep_busy_interval = server.config().keep_busy_interval;
+    let egress = server.context().server_state().egress_limiter();
+    let rhalf = copy_p2s(svr_cfg.method(), keep_busy_interval, egress, &mut r, &mut svr_w);
+    let whalf = copy_s2p(svr_cfg.method(), None, &mut svr_r, &mut w);
+
+    tokio::pin!(rhalf);
+    tokio::pin!(whalf);
+
+    debug!("TUN relay established {} <-> {}", client_addr, addr);
+
+    let start = Instant::now();
+    match future::select(rhalf, whalf).await {
+        Either::Left((Ok(..), _)) => trace!("TUN relay {} -> {} closed", client_addr, addr),
+        Either::Left((Err(err), _)) => {
+            if let ErrorKind::TimedOut = err.kind() {
+                trace!("TUN relay {} -> {} closed with error {}", client_addr, addr, err);
+            } else {
+                debug!("TUN relay {} -> {} closed with error {}", client_addr, addr, err);
+            }
+        }
+        Either::Right((Ok(n), _)) => {
+            trace!("TUN relay {} <- {} closed", client_addr, addr);
+            if proxied {
+                server.report_transfer(n, start.elapsed()).await;
+            }
+        }
+        Either::Right((Err(err), _)) => {
+            if let ErrorKind::TimedOut = err.kind() {
+                trace!("TUN relay {} <- {} closed with error {}", client_addr, addr, err);
+            } else {
+                debug!("TUN relay {} <- {} closed with error {}", client_addr, addr, err);
+                if proxied {
+                    server.report_relay_error().await;
+                }
+            }
+        }
+    }
+
+    debug!("TUN relay {} <-> {} closed", client_addr, addr);
+
+    Ok(())
+}
+
+/// Relay a connection from `client_addr` to its original destination `dst_addr`
+pub async fn handle_tun_client(
+    context: SharedContext,
+    servers: PlainPingBalancer,
+    s: TunTcpStream,
+    client_addr: SocketAddr,
+    dst_addr: SocketAddr,
+) {
+    // Fake addresses of DNS relay are mapped back to domain names
+    let target_addr = match context.map_fake_addr(dst_addr) {
+        Ok(a) => a,
+        Err(err) => {
+            error!("TCP TUN couldn't connect to {}, {}", dst_addr, err);
+            return;
+        }
+    };
+
+    let server = match servers.acquire_server_for(&target_addr) {
+        Ok(s) => s,
+        Err(err) => {
+            error!("TCP TUN couldn't pick a server for {}, {}", target_addr, err);
+            return;
+        }
+    };
+    trace!("picked proxy server: {:?}", server.server_config());
+
+    if let Err(err) = establish_client_tcp_tun(&server, s, client_addr, &target_addr).await {
+        debug!("TCP TUN client, error: {:?}", err);
+    }
+}
@@ -3,7 +3,8 @@
 use std::{
     io::{self, ErrorKind},
     net::SocketAddr,
-    time::Duration,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use futures::future::{self, Either};
@@ -14,6 +15,7 @@ use tokio::{
 };
 
 use crate::{
+    config::ClientConfig,
     context::SharedContext,
     relay::{
         loadbalancing::server::{PlainPingBalancer, ServerType, SharedPlainServerStatistic},
@@ -42,14 +44,17 @@ async fn establish_client_tcp_tunnel<'a>(
 
     use super::utils::{copy_p2s, copy_s2p};
 
-    let rhalf = copy_p2s(svr_cfg.method(), &mut r, &mut svr_w);
-    let whalf = copy_s2p(svr_cfg.method(), &mut svr_r, &mut w);
+    let keep_busy_interval = server.config().keep_busy_interval;
+    let egress = server.context().server_state().egress_limiter();
+    let rhalf = copy_p2s(svr_cfg.method(), keep_busy_interval, egress, &mut r, &mut svr_w);
+    let whalf = copy_s2p(svr_cfg.method(), None, &mut svr_r, &mut w);
 
     tokio::pin!(rhalf);
     tokio::pin!(whalf);
 
     debug!("TUNNEL relay established {} <-> {}", client_addr, addr);
 
+    let start = Instant::now();
     match future::select(rhalf, whalf).await {
         Either::Left((Ok(..), _)) => trace!("TUNNEL relay {} -> {} closed", client_addr, addr),
         Either::Left((Err(err), _)) => {
@@ -59,12 +64,16 @@ async fn establish_client_tcp_tunnel<'a>(
                 debug!("TUNNEL relay {} -> {} closed with error {}", client_addr, addr, err);
             }
         }
-        Either::Right((Ok(..), _)) => trace!("TUNNEL relay {} <- {} closed", client_addr, addr),
+        Either::Right((Ok(n), _)) => {
+            trace!("TUNNEL relay {} <- {} closed", client_addr, addr);
+            server.report_transfer(n, start.elapsed()).await;
+        }
         Either::Right((Err(err), _)) => {
             if let ErrorKind::TimedOut = err.kind() {
                 trace!("TUNNEL relay {} <- {} closed with error {}", client_addr, addr, err);
             } else {
                 debug!("TUNNEL relay {} <- {} closed with error {}", client_addr, addr, err);
+                server.report_relay_error().await;
             }
         }
     }
@@ -74,7 +83,11 @@ async fn establish_client_tcp_tunnel<'a>(
     Ok(())
 }
 
-async fn handle_tunnel_client(server: &SharedPlainServerStatistic, s: TcpStream) -> io::Result<()> {
+async fn handle_tunnel_client(
+    server: &SharedPlainServerStatistic,
+    s: TcpStream,
+    target_addr: &Address,
+) -> io::Result<()> {
     // let svr_cfg = server.server_config();
     //
     // FIXME: set_keepalive have been removed from tokio 0.3
@@ -91,19 +104,15 @@ async fn handle_tunnel_client(server: &SharedPlainServerStatistic, s: TcpStream)
 
     let client_addr = s.peer_addr()?;
 
-    // forward must not be None, it is already checked in local.rs
-    let target_addr = server.config().forward.as_ref().unwrap();
-
     establish_client_tcp_tunnel(server, s, client_addr, target_addr).await
 }
 
-pub async fn run(context: SharedContext) -> io::Result<()> {
-    assert!(
-        context.config().mode.enable_tcp(),
-        "TCP relay must be enabled for TUNNEL"
-    );
-
-    let local_addr = context.config().local_addr.as_ref().expect("local config");
+async fn run_forward(
+    context: SharedContext,
+    servers: PlainPingBalancer,
+    local_addr: ClientConfig,
+    forward_addr: Address,
+) -> io::Result<()> {
     let bind_addr = local_addr.bind_addr(&context).await?;
 
     let listener = TcpListener::bind(&bind_addr).await.map_err(|err| {
@@ -113,15 +122,16 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
 
     let actual_local_addr = listener.local_addr().expect("determine port bound to");
 
-    let servers = PlainPingBalancer::new(context.clone(), ServerType::Tcp).await;
-
-    let forward_addr = context.config().forward.as_ref().expect("`forward` address in config");
     info!(
         "shadowsocks TCP tunnel listening on {}, forward to {}",
         actual_local_addr, forward_addr
     );
 
+    let forward_addr = Arc::new(forward_addr);
+
     loop {
+        context.wait_memory_available().await;
+
         let (socket, peer_addr) = match listener.accept().await {
             Ok(s) => s,
             Err(err) => {
@@ -130,15 +140,45 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
                 continue;
             }
         };
-        let server = servers.pick_server();
+        let server = match servers.acquire_server_for(&forward_addr) {
+            Ok(s) => s,
+            Err(err) => {
+                error!("TCP tunnel couldn't pick a server for {}, {}", forward_addr, err);
+                continue;
+            }
+        };
 
         trace!("got connection {}", peer_addr);
         trace!("picked proxy server: {:?}", server.server_config());
 
+        let forward_addr = forward_addr.clone();
         tokio::spawn(async move {
-            if let Err(err) = handle_tunnel_client(&server, socket).await {
+            if let Err(err) = handle_tunnel_client(&server, socket, &forward_addr).await {
                 debug!("TCP tunnel client exited with error: {:?}", err);
             }
         });
     }
 }
+
+/// Starts TCP tunnels of all forwardings that enable TCP
+pub async fn run(context: SharedContext) -> io::Result<()> {
+    let forwards = context
+        .config()
+        .tunnel_forwards()
+        .into_iter()
+        .filter(|(_, _, mode)| mode.enable_tcp())
+        .collect::<Vec<_>>();
+    if forwards.is_empty() {
+        let err = io::Error::new(ErrorKind::InvalidInput, "no forwardings enable TCP for TUNNEL");
+        return Err(err);
+    }
+
+    let servers = PlainPingBalancer::new(context.clone(), ServerType::Tcp).await;
+
+    let tunnels = forwards
+        .into_iter()
+        .map(|(local_addr, forward_addr, _)| run_forward(context.clone(), servers.clone(), local_addr, forward_addr));
+    future::try_join_all(tunnels).await?;
+
+    Ok(())
+}
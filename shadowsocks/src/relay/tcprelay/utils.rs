@@ -1,63 +1,215 @@
 //! Utility functions
 
 use std::{
+    cmp,
     future::Future,
-    io,
-    net::SocketAddr,
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
     pin::Pin,
     task::{Context, Poll},
+    time::Duration,
 };
 
-use futures::ready;
-use log::trace;
+use futures::{future::FutureExt, ready, stream::FuturesUnordered, StreamExt};
+use ipnet::Ipv6Net;
+use log::{trace, warn};
 use tokio::{
     io::{AsyncRead, AsyncWrite, ReadBuf},
     net::{TcpSocket, TcpStream},
+    time::{self, Instant, Sleep},
 };
 
-use crate::crypto::v1::{CipherCategory, CipherKind};
+use crate::{
+    config::Config,
+    crypto::v1::{CipherCategory, CipherKind},
+    relay::{
+        speed_limit::{poll_bucket, TokenBucket},
+        sys::{set_ip_freebind, set_ipv6_prefer_temporary},
+    },
+};
 
 /// Connecting to a specific target with TCP protocol
 ///
-/// Optionally we can bind to a local address for connecting
-pub async fn connect_tcp_stream(addr: &SocketAddr, outbound_addr: &Option<SocketAddr>) -> io::Result<TcpStream> {
-    match *outbound_addr {
-        None => {
-            trace!("connecting {}", addr);
-
-            // Connect with tokio's default API directly
-            TcpStream::connect(addr).await
-        }
-        Some(ref bind_addr) => {
-            // Create TcpStream manually from socket
-            // These functions may not behave exactly the same as tokio's TcpStream::connect
-
-            trace!("connecting {} from {}", addr, bind_addr);
-
-            let socket = match *addr {
-                SocketAddr::V4(..) => TcpSocket::new_v4()?,
-                SocketAddr::V6(..) => TcpSocket::new_v6()?,
-            };
-
-            // Bind to local outbound address
-            //
-            // Common failure: EADDRINUSE
-            socket.bind(*bind_addr)?;
-
-            // Connect to the target
-            //
-            // FIXME: This function is not documented as it may be deleted in the future
-            //
-            // mio 0.6.x (tokio 0.2.x is depending on it) will set stream into non-block mode
-            // unix: https://github.com/tokio-rs/mio/blob/v0.6.x/src/sys/unix/tcp.rs#L28
-            // windows: https://github.com/tokio-rs/mio/blob/v0.6.x/src/sys/windows/tcp.rs#L118
-            //
-            // We have to let tokio calls connect for us. Because we don't have a chance to wait until the socket is actually connected
-            socket.connect(*addr).await
+/// Optionally we can bind to a local address for connecting, or one chosen from `config.outbound_address_pool`
+pub async fn connect_tcp_stream(
+    addr: &SocketAddr,
+    outbound_addr: &Option<SocketAddr>,
+    config: &Config,
+) -> io::Result<TcpStream> {
+    let ipv6_outbound = match *addr {
+        SocketAddr::V6(..) => config.ipv6_prefer_temporary || config.ipv6_outbound_prefix.is_some(),
+        SocketAddr::V4(..) => false,
+    };
+
+    // Rotate local addresses in the pool if no explicit outbound address is specified
+    let outbound_addr = match *outbound_addr {
+        Some(addr) => Some(addr),
+        None => match config.outbound_address_pool {
+            Some(ref pool) => pool.pick(addr).map(|ip| SocketAddr::new(ip, 0)),
+            None => None,
+        },
+    };
+
+    if outbound_addr.is_none() && !ipv6_outbound {
+        trace!("connecting {}", addr);
+
+        // Connect with tokio's default API directly
+        return TcpStream::connect(addr).await;
+    }
+
+    // Create TcpStream manually from socket
+    // These functions may not behave exactly the same as tokio's TcpStream::connect
+
+    let socket = match *addr {
+        SocketAddr::V4(..) => TcpSocket::new_v4()?,
+        SocketAddr::V6(..) => TcpSocket::new_v6()?,
+    };
+
+    let mut bind_addr = outbound_addr;
+
+    if let SocketAddr::V6(..) = *addr {
+        if config.ipv6_prefer_temporary {
+            if let Err(err) = set_ipv6_prefer_temporary(&socket) {
+                warn!("failed to prefer IPv6 temporary address, error: {}", err);
+            }
+        }
+
+        if let Some(ref prefix) = config.ipv6_outbound_prefix {
+            // Pick a random address in the routed prefix for every connections
+            let ip = random_ipv6_in_prefix(prefix);
+            set_ip_freebind(&socket)?;
+            bind_addr = Some(SocketAddr::new(IpAddr::V6(ip), 0));
+        }
+    }
+
+    if let Some(ref bind_addr) = bind_addr {
+        trace!("connecting {} from {}", addr, bind_addr);
+
+        // Bind to local outbound address
+        //
+        // Common failure: EADDRINUSE
+        socket.bind(*bind_addr)?;
+    } else {
+        trace!("connecting {}", addr);
+    }
+
+    // Connect to the target
+    //
+    // FIXME: This function is not documented as it may be deleted in the future
+    //
+    // mio 0.6.x (tokio 0.2.x is depending on it) will set stream into non-block mode
+    // unix: https://github.com/tokio-rs/mio/blob/v0.6.x/src/sys/unix/tcp.rs#L28
+    // windows: https://github.com/tokio-rs/mio/blob/v0.6.x/src/sys/windows/tcp.rs#L118
+    //
+    // We have to let tokio calls connect for us. Because we don't have a chance to wait until the socket is actually connected
+    socket.connect(*addr).await
+}
+
+/// Delay between starting connection attempts of Happy Eyeballs, recommended by RFC 8305
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connect to `addrs`, resolved from one domain name, with Happy Eyeballs (RFC 8305)
+///
+/// Addresses are interleaved by family, starting with the family of the first address. A new attempt is started
+/// every `CONNECTION_ATTEMPT_DELAY`, or as soon as the previous one failed, and the first established connection wins.
+/// So a broken IPv6 route only delays connecting for a moment, instead of a full timeout.
+pub async fn connect_happy_eyeballs<'a, F, Fut, T>(addrs: &'a [SocketAddr], connect: F) -> io::Result<(SocketAddr, T)>
+where
+    F: Fn(&'a SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let start = |addr: &'a SocketAddr| connect(addr).map(move |r| (addr, r));
+
+    let mut pending = interleave_address_families(addrs).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = None;
+
+    loop {
+        if attempts.is_empty() {
+            match pending.next() {
+                Some(addr) => attempts.push(start(addr)),
+                None => {
+                    let err = last_err.unwrap_or_else(|| io::Error::new(ErrorKind::Other, "resolved empty address"));
+                    return Err(err);
+                }
+            }
+        }
+
+        tokio::select! {
+            Some((addr, result)) = attempts.next() => match result {
+                Ok(s) => return Ok((*addr, s)),
+                Err(err) => {
+                    trace!("connecting {} failed, {}, try others", addr, err);
+                    last_err = Some(err);
+
+                    if let Some(addr) = pending.next() {
+                        attempts.push(start(addr));
+                    }
+                }
+            },
+            _ = time::sleep(CONNECTION_ATTEMPT_DELAY), if !pending.as_slice().is_empty() => {
+                if let Some(addr) = pending.next() {
+                    attempts.push(start(addr));
+                }
+            }
         }
     }
 }
 
+/// Alternate IPv6 and IPv4 addresses, keeping their order in each family
+fn interleave_address_families(addrs: &[SocketAddr]) -> Vec<&SocketAddr> {
+    let first_ipv6 = addrs.first().map_or(false, SocketAddr::is_ipv6);
+    let (preferred, others): (Vec<_>, Vec<_>) = addrs.iter().partition(|a| a.is_ipv6() == first_ipv6);
+
+    let mut preferred = preferred.into_iter();
+    let mut others = others.into_iter();
+    let mut result = Vec::with_capacity(addrs.len());
+    loop {
+        match (preferred.next(), others.next()) {
+            (None, None) => break,
+            (a, b) => {
+                result.extend(a);
+                result.extend(b);
+            }
+        }
+    }
+    result
+}
+
+/// Generate a random address in IPv6 `prefix`
+pub fn random_ipv6_in_prefix(prefix: &Ipv6Net) -> Ipv6Addr {
+    let network = u128::from(prefix.network());
+    let hostmask = u128::from(prefix.hostmask());
+    let host = rand::random::<u128>() & hostmask;
+    Ipv6Addr::from(network | host)
+}
+
+// Sends empty AEAD chunks into an idle connection, which will be skipped by the peer
+struct KeepBusy {
+    interval: Duration,
+    timer: Pin<Box<Sleep>>,
+    sending: bool,
+}
+
+impl KeepBusy {
+    fn new(interval: Duration) -> KeepBusy {
+        KeepBusy {
+            interval,
+            timer: Box::pin(time::sleep(interval)),
+            sending: false,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.timer.as_mut().reset(Instant::now() + self.interval);
+    }
+}
+
+/// Initial size of relay buffers, most connections are idle or transfer little data
+const MIN_COPY_BUFFER_SIZE: usize = 2 * 1024;
+/// Grown relay buffers are shrunk if the reader is idle for this duration
+const COPY_BUFFER_IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
 struct Copy<'a, R: ?Sized, W: ?Sized> {
     reader: &'a mut R,
     read_done: bool,
@@ -65,7 +217,15 @@ struct Copy<'a, R: ?Sized, W: ?Sized> {
     pos: usize,
     cap: usize,
     amt: u64,
-    buf: Box<[u8]>,
+    /// Grows (up to `max_buf_len`) if reads fill it up, and shrinks if reads are small or the reader is idle
+    buf: Vec<u8>,
+    max_buf_len: usize,
+    /// Started only after the buffer has grown
+    idle_timer: Option<Pin<Box<Sleep>>>,
+    keep_busy: Option<KeepBusy>,
+    /// Writes wait until the bucket is not empty
+    egress: Option<&'a TokenBucket>,
+    egress_sleep: Option<Pin<Box<Sleep>>>,
 }
 
 impl<'a, R: ?Sized, W: ?Sized> Copy<'a, R, W> {
@@ -77,9 +237,49 @@ impl<'a, R: ?Sized, W: ?Sized> Copy<'a, R, W> {
             amt: 0,
             pos: 0,
             cap: 0,
-            buf: vec![0u8; buffer_length].into_boxed_slice(),
+            buf: vec![0u8; cmp::min(MIN_COPY_BUFFER_SIZE, buffer_length)],
+            max_buf_len: buffer_length,
+            idle_timer: None,
+            keep_busy: None,
+            egress: None,
+            egress_sleep: None,
+        }
+    }
+
+    /// Resize the buffer by the size of the last read, data in the buffer is kept
+    fn adapt_buffer(&mut self, n: usize) {
+        if let Some(ref mut timer) = self.idle_timer {
+            timer.as_mut().reset(Instant::now() + COPY_BUFFER_IDLE_TIMEOUT);
+        }
+
+        let len = self.buf.len();
+        if n == len && len < self.max_buf_len {
+            // Reader has more data than the buffer could hold, bulk transfer
+            self.buf.resize(cmp::min(len * 2, self.max_buf_len), 0);
+        } else if n < len / 4 && len > MIN_COPY_BUFFER_SIZE {
+            self.resize_buffer(len / 2);
         }
     }
+
+    /// Release memory of the drained buffer if the reader has been idle for `COPY_BUFFER_IDLE_TIMEOUT`
+    fn poll_shrink_idle_buffer(&mut self, cx: &mut Context<'_>) {
+        if self.buf.len() <= MIN_COPY_BUFFER_SIZE {
+            return;
+        }
+
+        let timer = self
+            .idle_timer
+            .get_or_insert_with(|| Box::pin(time::sleep(COPY_BUFFER_IDLE_TIMEOUT)));
+        if timer.as_mut().poll(cx).is_ready() {
+            self.idle_timer = None;
+            self.resize_buffer(MIN_COPY_BUFFER_SIZE);
+        }
+    }
+
+    fn resize_buffer(&mut self, len: usize) {
+        self.buf.truncate(cmp::max(len, MIN_COPY_BUFFER_SIZE));
+        self.buf.shrink_to_fit();
+    }
 }
 
 impl<R, W> Future for Copy<'_, R, W>
@@ -91,25 +291,62 @@ where
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
         loop {
+            // Finish sending heartbeat before writing any data
+            {
+                let me = &mut *self;
+                if let Some(ref mut keep_busy) = me.keep_busy {
+                    if keep_busy.sending {
+                        // Encrypted writer sends an empty chunk for an empty buffer
+                        ready!(Pin::new(&mut *me.writer).poll_write(cx, &[]))?;
+                        keep_busy.sending = false;
+                        keep_busy.reset();
+                    }
+                }
+            }
+
             // If our buffer is empty, then we need to read some data to
             // continue.
             if self.pos == self.cap && !self.read_done {
                 let me = &mut *self;
                 let mut buf = ReadBuf::new(&mut me.buf);
-                ready!(Pin::new(&mut *me.reader).poll_read(cx, &mut buf))?;
+                if let Poll::Pending = Pin::new(&mut *me.reader).poll_read(cx, &mut buf)? {
+                    me.poll_shrink_idle_buffer(cx);
+
+                    // Heartbeat only after the first chunk, which carries the handshake
+                    if me.amt > 0 {
+                        if let Some(ref mut keep_busy) = me.keep_busy {
+                            if keep_busy.timer.as_mut().poll(cx).is_ready() {
+                                keep_busy.sending = true;
+                                continue;
+                            }
+                        }
+                    }
+                    return Poll::Pending;
+                }
                 let n = buf.filled().len();
                 if n == 0 {
                     self.read_done = true;
                 } else {
                     self.pos = 0;
                     self.cap = n;
+                    self.adapt_buffer(n);
+
+                    if let Some(ref mut keep_busy) = self.keep_busy {
+                        keep_busy.reset();
+                    }
                 }
             }
 
             // If our buffer has some data, let's write it out!
             while self.pos < self.cap {
                 let me = &mut *self;
+                if let Some(egress) = me.egress {
+                    ready!(poll_bucket(egress, &mut me.egress_sleep, cx));
+                }
                 let i = ready!(Pin::new(&mut *me.writer).poll_write(cx, &me.buf[me.pos..me.cap]))?;
+                if let Some(egress) = me.egress {
+                    egress.consume(i);
+                }
                 if i == 0 {
                     return Poll::Ready(Err(io::Error::new(
                         io::ErrorKind::WriteZero,
@@ -133,14 +370,21 @@ where
 }
 
 /// Copy all data from encrypted `reader` to plain `writer`
-pub async fn copy_s2p<'a, R, W>(method: CipherKind, reader: &'a mut R, writer: &'a mut W) -> io::Result<u64>
+///
+/// If `egress` is set, writing waits for its tokens
+pub async fn copy_s2p<'a, R, W>(
+    method: CipherKind,
+    egress: Option<&'a TokenBucket>,
+    reader: &'a mut R,
+    writer: &'a mut W,
+) -> io::Result<u64>
 where
     R: AsyncRead + Unpin + ?Sized,
     W: AsyncWrite + Unpin + ?Sized,
 {
     let buffer_length = match method.category() {
         CipherCategory::Stream | CipherCategory::None => {
-            // Stream cipher uses 16K buffer
+            // Stream cipher uses up to 16K buffer
             1 << 14
         }
         CipherCategory::Aead => {
@@ -150,18 +394,30 @@ where
         }
     };
 
-    Copy::new(reader, writer, buffer_length).await
+    let mut copy = Copy::new(reader, writer, buffer_length);
+    copy.egress = egress;
+    copy.await
 }
 
 /// Copy all data from plain `reader` to encrypted `writer`
-pub async fn copy_p2s<'a, R, W>(method: CipherKind, reader: &'a mut R, writer: &'a mut W) -> io::Result<u64>
+///
+/// If `keep_busy_interval` is set, an empty chunk will be sent if `reader` is idle for that interval (AEAD ciphers only)
+///
+/// If `egress` is set, writing waits for its tokens
+pub async fn copy_p2s<'a, R, W>(
+    method: CipherKind,
+    keep_busy_interval: Option<Duration>,
+    egress: Option<&'a TokenBucket>,
+    reader: &'a mut R,
+    writer: &'a mut W,
+) -> io::Result<u64>
 where
     R: AsyncRead + Unpin + ?Sized,
     W: AsyncWrite + Unpin + ?Sized,
 {
     let buffer_length = match method.category() {
         CipherCategory::Stream | CipherCategory::None => {
-            // Stream cipher uses 16K buffer
+            // Stream cipher uses up to 16K buffer
             1 << 14
         }
         CipherCategory::Aead => {
@@ -170,5 +426,10 @@ where
         }
     };
 
-    Copy::new(reader, writer, buffer_length).await
+    let mut copy = Copy::new(reader, writer, buffer_length);
+    copy.egress = egress;
+    if let (CipherCategory::Aead, Some(interval)) = (method.category(), keep_busy_interval) {
+        copy.keep_busy = Some(KeepBusy::new(interval));
+    }
+    copy.await
 }
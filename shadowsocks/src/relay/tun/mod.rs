@@ -0,0 +1,172 @@
+//! TUN device mode (transparent proxy without firewall rules)
+//!
+//! A TUN interface is created for routing system traffic into the process. TCP connections are terminated by a
+//! userspace TCP/IP stack and relayed like redir, UDP datagrams are relayed by associations.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use log::{debug, error, info, trace};
+use smoltcp::wire::{IpProtocol, Ipv4Packet, Ipv6Packet, TcpPacket, UdpPacket};
+use tokio::{select, sync::mpsc, time};
+
+use crate::{
+    context::SharedContext,
+    relay::{
+        loadbalancing::server::{PlainPingBalancer, ServerType},
+        tcprelay::tun_local::handle_tun_client,
+        udprelay::tun_local::{run as run_udp, UdpDatagram},
+    },
+};
+
+use self::{stack::TcpStack, sys::TunDevice};
+
+pub(crate) mod stack;
+pub(crate) mod sys;
+
+/// MTU of the TUN interface
+const TUN_MTU: usize = 1500;
+/// Datagrams waiting for UDP associations, excess datagrams are dropped
+const UDP_QUEUE_SIZE: usize = 1024;
+/// Stack is polled at least in this interval
+const MAX_POLL_DELAY: Duration = Duration::from_secs(1);
+
+/// A packet read from the TUN device
+enum TunPacket<'a> {
+    Tcp {
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        is_syn: bool,
+    },
+    Udp {
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        payload: &'a [u8],
+    },
+}
+
+/// Parse a packet, `None` for protocols other than TCP and UDP, fragments or malformed packets
+fn parse_packet(packet: &[u8]) -> Option<TunPacket<'_>> {
+    let (src_ip, dst_ip, protocol, payload) = match packet.first()? >> 4 {
+        4 => {
+            let packet = Ipv4Packet::new_checked(packet).ok()?;
+            if packet.more_frags() || packet.frag_offset() != 0 {
+                return None;
+            }
+            let src_ip = IpAddr::V4(Ipv4Addr::from(packet.src_addr().0));
+            let dst_ip = IpAddr::V4(Ipv4Addr::from(packet.dst_addr().0));
+            (src_ip, dst_ip, packet.protocol(), packet.payload())
+        }
+        6 => {
+            let packet = Ipv6Packet::new_checked(packet).ok()?;
+            let src_ip = IpAddr::V6(Ipv6Addr::from(packet.src_addr().0));
+            let dst_ip = IpAddr::V6(Ipv6Addr::from(packet.dst_addr().0));
+            (src_ip, dst_ip, packet.next_header(), packet.payload())
+        }
+        _ => return None,
+    };
+
+    match protocol {
+        IpProtocol::Tcp => {
+            let tcp = TcpPacket::new_checked(payload).ok()?;
+            Some(TunPacket::Tcp {
+                src_addr: SocketAddr::new(src_ip, tcp.src_port()),
+                dst_addr: SocketAddr::new(dst_ip, tcp.dst_port()),
+                is_syn: tcp.syn() && !tcp.ack(),
+            })
+        }
+        IpProtocol::Udp => {
+            let udp = UdpPacket::new_checked(payload).ok()?;
+            Some(TunPacket::Udp {
+                src_addr: SocketAddr::new(src_ip, udp.src_port()),
+                dst_addr: SocketAddr::new(dst_ip, udp.dst_port()),
+                payload: udp.payload(),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Starts the TUN device, relaying TCP and (or) UDP by `mode`
+pub async fn run(context: SharedContext) -> io::Result<()> {
+    let config = context.config();
+
+    let device = TunDevice::open(
+        config.tun_interface_name.as_deref(),
+        config.tun_interface_address,
+        TUN_MTU,
+    )
+    .map_err(|err| {
+        error!("failed to create TUN device, {}", err);
+        err
+    })?;
+    let device = Arc::new(device);
+
+    let tcp_servers = if config.mode.enable_tcp() {
+        Some(PlainPingBalancer::new(context.clone(), ServerType::Tcp).await)
+    } else {
+        None
+    };
+
+    let udp_tx = if config.mode.enable_udp() {
+        let (tx, rx) = mpsc::channel::<UdpDatagram>(UDP_QUEUE_SIZE);
+        tokio::spawn(run_udp(context.clone(), device.clone(), rx));
+        Some(tx)
+    } else {
+        None
+    };
+
+    info!("shadowsocks TUN device {} (mode {})", device.name(), config.mode);
+
+    let mut stack = TcpStack::new(TUN_MTU);
+    let notify = stack.notify();
+    let mut buf = vec![0u8; TUN_MTU];
+
+    loop {
+        for packet in stack.take_outgoing() {
+            if let Err(err) = device.send(&packet).await {
+                debug!("TUN device dropped a packet of {} bytes, {}", packet.len(), err);
+            }
+        }
+
+        let delay = stack.poll_delay().map_or(MAX_POLL_DELAY, |d| d.min(MAX_POLL_DELAY));
+
+        select! {
+            r = device.recv(&mut buf) => {
+                let n = r.map_err(|err| {
+                    error!("failed to read from TUN device {}, {}", device.name(), err);
+                    err
+                })?;
+                let packet = &buf[..n];
+
+                match parse_packet(packet) {
+                    Some(TunPacket::Tcp { src_addr, dst_addr, is_syn }) => {
+                        if let Some(ref servers) = tcp_servers {
+                            if let Some(s) = stack.input(packet.to_vec(), src_addr, dst_addr, is_syn) {
+                                trace!("TUN TCP accepted {} -> {}", src_addr, dst_addr);
+                                let servers = servers.clone();
+                                tokio::spawn(handle_tun_client(context.clone(), servers, s, src_addr, dst_addr));
+                            }
+                        }
+                    }
+                    Some(TunPacket::Udp { src_addr, dst_addr, payload }) => {
+                        if let Some(ref tx) = udp_tx {
+                            if tx.try_send((src_addr, dst_addr, payload.to_vec())).is_err() {
+                                debug!("TUN UDP packet {} -> {} is dropped, queue is full", src_addr, dst_addr);
+                            }
+                        }
+                    }
+                    None => trace!("TUN device ignored a packet of {} bytes", n),
+                }
+            }
+            _ = notify.notified() => {}
+            _ = time::sleep(delay) => {}
+        }
+
+        stack.poll();
+    }
+}
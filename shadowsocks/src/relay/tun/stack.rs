@@ -0,0 +1,431 @@
+//! Userspace TCP/IP stack terminating TCP connections captured by the TUN device
+//!
+//! Packets read from the TUN device are fed into a smoltcp interface which accepts any destination address. A
+//! listening socket is created for the destination of every new SYN, so each connection gets a socket bound to its
+//! original destination. Data of sockets is exchanged with relay tasks through `TunTcpStream`s.
+
+use std::{
+    cmp,
+    collections::{BTreeMap, HashMap, VecDeque},
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use bytes::{Buf, BytesMut};
+use log::trace;
+use smoltcp::{
+    iface::{Interface, InterfaceBuilder, Routes},
+    phy::{Device, DeviceCapabilities, Medium, RxToken, TxToken},
+    socket::{SocketHandle, SocketSet, TcpSocket, TcpSocketBuffer, TcpState},
+    time::{Duration as SmolDuration, Instant as SmolInstant},
+    wire::{IpAddress, IpCidr, IpEndpoint, Ipv4Address, Ipv6Address},
+    Result as SmolResult,
+};
+use spin::Mutex as SpinMutex;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::Notify,
+};
+
+/// Size of socket buffers in the stack, and of buffers between the stack and relay tasks
+const TCP_BUFFER_SIZE: usize = 64 * 1024;
+/// Keep-alive probes are sent on idle connections, so connections of vanished clients are aborted by `TCP_TIMEOUT`
+const TCP_KEEP_ALIVE: Duration = Duration::from_secs(60);
+const TCP_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Device of the interface, packets are moved in and out by `TcpStack`
+struct VirtualDevice {
+    mtu: usize,
+    rx: VecDeque<Vec<u8>>,
+    tx: VecDeque<Vec<u8>>,
+}
+
+impl<'a> Device<'a> for VirtualDevice {
+    type RxToken = VirtualRxToken;
+    type TxToken = VirtualTxToken<'a>;
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let packet = self.rx.pop_front()?;
+        Some((VirtualRxToken { packet }, VirtualTxToken { tx: &mut self.tx }))
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        Some(VirtualTxToken { tx: &mut self.tx })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ip;
+        caps.max_transmission_unit = self.mtu;
+        caps
+    }
+}
+
+struct VirtualRxToken {
+    packet: Vec<u8>,
+}
+
+impl RxToken for VirtualRxToken {
+    fn consume<R, F>(mut self, _timestamp: SmolInstant, f: F) -> SmolResult<R>
+    where
+        F: FnOnce(&mut [u8]) -> SmolResult<R>,
+    {
+        f(&mut self.packet)
+    }
+}
+
+struct VirtualTxToken<'a> {
+    tx: &'a mut VecDeque<Vec<u8>>,
+}
+
+impl<'a> TxToken for VirtualTxToken<'a> {
+    fn consume<R, F>(self, _timestamp: SmolInstant, len: usize, f: F) -> SmolResult<R>
+    where
+        F: FnOnce(&mut [u8]) -> SmolResult<R>,
+    {
+        let mut packet = vec![0u8; len];
+        let result = f(&mut packet)?;
+        self.tx.push_back(packet);
+        Ok(result)
+    }
+}
+
+/// Buffers shared by a socket in the stack and its `TunTcpStream`
+#[derive(Default)]
+struct TcpConnectionState {
+    /// Received from the client, not read by the relay task yet
+    recv_buf: BytesMut,
+    /// Client has finished sending
+    recv_eof: bool,
+    recv_waker: Option<Waker>,
+    /// Written by the relay task, not sent to the client yet
+    send_buf: BytesMut,
+    /// Relay task has finished sending
+    send_shutdown: bool,
+    send_waker: Option<Waker>,
+    /// Socket is closed, by the client or by timeout
+    closed: bool,
+    /// `TunTcpStream` is dropped
+    dropped: bool,
+}
+
+impl TcpConnectionState {
+    fn wake_recv(&mut self) {
+        if let Some(waker) = self.recv_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn wake_send(&mut self) {
+        if let Some(waker) = self.send_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// A TCP connection accepted by the stack, for relay tasks
+pub struct TunTcpStream {
+    state: Arc<SpinMutex<TcpConnectionState>>,
+    notify: Arc<Notify>,
+}
+
+impl Drop for TunTcpStream {
+    fn drop(&mut self) {
+        self.state.lock().dropped = true;
+        self.notify.notify_one();
+    }
+}
+
+impl AsyncRead for TunTcpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.state.lock();
+        if state.recv_buf.is_empty() {
+            if state.recv_eof || state.closed {
+                return Poll::Ready(Ok(()));
+            }
+            state.recv_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let n = cmp::min(buf.remaining(), state.recv_buf.len());
+        buf.put_slice(&state.recv_buf[..n]);
+        state.recv_buf.advance(n);
+        drop(state);
+
+        // Space is freed for data waiting in the socket
+        self.notify.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for TunTcpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut state = self.state.lock();
+        if state.closed || state.send_shutdown {
+            return Poll::Ready(Err(ErrorKind::BrokenPipe.into()));
+        }
+
+        let space = TCP_BUFFER_SIZE.saturating_sub(state.send_buf.len());
+        if space == 0 {
+            state.send_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let n = cmp::min(space, buf.len());
+        state.send_buf.extend_from_slice(&buf[..n]);
+        drop(state);
+
+        self.notify.notify_one();
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.state.lock().send_shutdown = true;
+        self.notify.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+struct TcpConnection {
+    src_addr: SocketAddr,
+    dst_addr: SocketAddr,
+    state: Arc<SpinMutex<TcpConnectionState>>,
+    fin_sent: bool,
+}
+
+impl TcpConnection {
+    /// Exchange data between `socket` and the relay task, returns `true` if the connection is finished
+    fn transfer(&mut self, socket: &mut TcpSocket) -> bool {
+        // SYN wasn't accepted
+        if socket.state() == TcpState::Listen {
+            return true;
+        }
+
+        let mut state = self.state.lock();
+
+        // Client -> relay task, data is discarded if the relay task is gone
+        while socket.can_recv() && (state.dropped || state.recv_buf.len() < TCP_BUFFER_SIZE) {
+            let space = TCP_BUFFER_SIZE.saturating_sub(state.recv_buf.len());
+            let dropped = state.dropped;
+            let recv_buf = &mut state.recv_buf;
+            let result = socket.recv(|data| {
+                if dropped {
+                    return (data.len(), data.len());
+                }
+                let n = cmp::min(space, data.len());
+                recv_buf.extend_from_slice(&data[..n]);
+                (n, n)
+            });
+            match result {
+                Ok(0) | Err(..) => break,
+                Ok(..) => state.wake_recv(),
+            }
+        }
+
+        let handshaking = matches!(
+            socket.state(),
+            TcpState::Listen | TcpState::SynReceived | TcpState::SynSent
+        );
+        if !state.recv_eof && !handshaking && !socket.may_recv() {
+            trace!("TUN TCP {} -> {} received FIN", self.src_addr, self.dst_addr);
+            state.recv_eof = true;
+            state.wake_recv();
+        }
+
+        // Relay task -> client
+        while !state.send_buf.is_empty() && socket.can_send() {
+            match socket.send_slice(&state.send_buf) {
+                Ok(0) | Err(..) => break,
+                Ok(n) => {
+                    state.send_buf.advance(n);
+                    state.wake_send();
+                }
+            }
+        }
+
+        if (state.send_shutdown || state.dropped) && state.send_buf.is_empty() && !self.fin_sent {
+            socket.close();
+            self.fin_sent = true;
+        }
+
+        if !socket.is_open() {
+            state.closed = true;
+            state.wake_recv();
+            state.wake_send();
+            return true;
+        }
+
+        false
+    }
+}
+
+/// TCP/IP stack of the TUN device
+pub struct TcpStack {
+    iface: Interface<'static, VirtualDevice>,
+    sockets: SocketSet<'static>,
+    connections: HashMap<SocketHandle, TcpConnection>,
+    endpoints: HashMap<(SocketAddr, SocketAddr), SocketHandle>,
+    notify: Arc<Notify>,
+}
+
+impl TcpStack {
+    /// Create a stack accepting TCP connections to any address
+    pub fn new(mtu: usize) -> TcpStack {
+        let device = VirtualDevice {
+            mtu,
+            rx: VecDeque::new(),
+            tx: VecDeque::new(),
+        };
+
+        // Packets to any address are accepted if they are routed through one of the interface's addresses
+        let ipv4_gateway = Ipv4Address::new(0, 0, 0, 1);
+        let ipv6_gateway = Ipv6Address::new(0, 0, 0, 0, 0, 0, 0, 1);
+        let mut routes = Routes::new(BTreeMap::new());
+        routes
+            .add_default_ipv4_route(ipv4_gateway)
+            .expect("add default IPv4 route");
+        routes
+            .add_default_ipv6_route(ipv6_gateway)
+            .expect("add default IPv6 route");
+
+        let iface = InterfaceBuilder::new(device)
+            .ip_addrs(vec![
+                IpCidr::new(IpAddress::Ipv4(ipv4_gateway), 0),
+                IpCidr::new(IpAddress::Ipv6(ipv6_gateway), 0),
+            ])
+            .any_ip(true)
+            .routes(routes)
+            .finalize();
+
+        TcpStack {
+            iface,
+            sockets: SocketSet::new(Vec::new()),
+            connections: HashMap::new(),
+            endpoints: HashMap::new(),
+            notify: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Notified when relay tasks have read or written data
+    pub fn notify(&self) -> Arc<Notify> {
+        self.notify.clone()
+    }
+
+    /// Feed a TCP packet from `src_addr` to `dst_addr` into the stack
+    ///
+    /// Returns a stream if `packet` opens a new connection, which should be relayed to `dst_addr`
+    pub fn input(
+        &mut self,
+        packet: Vec<u8>,
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        is_syn: bool,
+    ) -> Option<TunTcpStream> {
+        let mut stream = None;
+
+        if is_syn && !self.endpoints.contains_key(&(src_addr, dst_addr)) {
+            let mut socket = TcpSocket::new(
+                TcpSocketBuffer::new(vec![0u8; TCP_BUFFER_SIZE]),
+                TcpSocketBuffer::new(vec![0u8; TCP_BUFFER_SIZE]),
+            );
+            socket.set_keep_alive(Some(to_smol_duration(TCP_KEEP_ALIVE)));
+            socket.set_timeout(Some(to_smol_duration(TCP_TIMEOUT)));
+
+            match socket.listen(to_endpoint(dst_addr)) {
+                Ok(..) => {
+                    let handle = self.sockets.add(socket);
+                    let state = Arc::new(SpinMutex::new(TcpConnectionState::default()));
+                    self.connections.insert(
+                        handle,
+                        TcpConnection {
+                            src_addr,
+                            dst_addr,
+                            state: state.clone(),
+                            fin_sent: false,
+                        },
+                    );
+                    self.endpoints.insert((src_addr, dst_addr), handle);
+
+                    stream = Some(TunTcpStream {
+                        state,
+                        notify: self.notify.clone(),
+                    });
+                }
+                Err(err) => trace!("TUN TCP couldn't listen on {}, {}", dst_addr, err),
+            }
+        }
+
+        self.iface.device_mut().rx.push_back(packet);
+        stream
+    }
+
+    /// Process packets fed in, and exchange data of connections
+    pub fn poll(&mut self) {
+        self.poll_iface();
+
+        let mut finished = Vec::new();
+        for (handle, conn) in self.connections.iter_mut() {
+            let mut socket = self.sockets.get::<TcpSocket>(*handle);
+            if conn.transfer(&mut socket) {
+                finished.push(*handle);
+            }
+        }
+
+        for handle in finished {
+            if let Some(conn) = self.connections.remove(&handle) {
+                trace!("TUN TCP {} <-> {} finished", conn.src_addr, conn.dst_addr);
+                self.endpoints.remove(&(conn.src_addr, conn.dst_addr));
+            }
+            self.sockets.remove(handle);
+        }
+
+        // Sends data and FINs queued by `transfer`
+        self.poll_iface();
+    }
+
+    fn poll_iface(&mut self) {
+        loop {
+            match self.iface.poll(&mut self.sockets, SmolInstant::now()) {
+                Ok(..) => break,
+                Err(err) => {
+                    trace!("TUN TCP stack dropped a packet, {}", err);
+                    if self.iface.device().rx.is_empty() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Time before the next `poll` is required for timers, like retransmissions
+    pub fn poll_delay(&self) -> Option<Duration> {
+        self.iface
+            .poll_delay(&self.sockets, SmolInstant::now())
+            .map(|d| Duration::from_millis(d.total_millis()))
+    }
+
+    /// Packets to be written into the TUN device
+    pub fn take_outgoing(&mut self) -> VecDeque<Vec<u8>> {
+        std::mem::take(&mut self.iface.device_mut().tx)
+    }
+}
+
+fn to_smol_duration(d: Duration) -> SmolDuration {
+    SmolDuration::from_millis(d.as_millis() as u64)
+}
+
+fn to_endpoint(addr: SocketAddr) -> IpEndpoint {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) => IpAddress::Ipv4(Ipv4Address::from_bytes(&ip.octets())),
+        IpAddr::V6(ip) => IpAddress::Ipv6(Ipv6Address::from_bytes(&ip.octets())),
+    };
+    IpEndpoint::new(ip, addr.port())
+}
@@ -0,0 +1,243 @@
+//! Platform dependent TUN device
+
+use std::io;
+
+use cfg_if::cfg_if;
+use ipnet::Ipv4Net;
+
+cfg_if! {
+    if #[cfg(any(target_os = "linux", target_os = "android"))] {
+        use std::{
+            ffi::CStr,
+            io::ErrorKind,
+            mem,
+            net::Ipv4Addr,
+            os::unix::io::{AsRawFd, RawFd},
+            ptr,
+        };
+
+        use tokio::io::unix::AsyncFd;
+
+        /// `_IOW('T', 202, int)`
+        const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+
+        /// `struct ifreq`, `ifr_ifru` is large enough for the union on all architectures
+        #[repr(C)]
+        struct IfReq {
+            ifr_name: [libc::c_char; libc::IFNAMSIZ],
+            ifr_ifru: [u8; 24],
+        }
+
+        impl IfReq {
+            fn new(name: &str) -> io::Result<IfReq> {
+                if name.len() >= libc::IFNAMSIZ || name.as_bytes().contains(&0) {
+                    let err = io::Error::new(ErrorKind::InvalidInput, format!("invalid interface name \"{}\"", name));
+                    return Err(err);
+                }
+
+                let mut req: IfReq = unsafe { mem::zeroed() };
+                for (dst, src) in req.ifr_name.iter_mut().zip(name.as_bytes()) {
+                    *dst = *src as libc::c_char;
+                }
+                Ok(req)
+            }
+
+            fn name(&self) -> String {
+                let name = unsafe { CStr::from_ptr(self.ifr_name.as_ptr()) };
+                name.to_string_lossy().into_owned()
+            }
+
+            fn set_flags(&mut self, flags: libc::c_short) {
+                self.ifr_ifru[..mem::size_of::<libc::c_short>()].copy_from_slice(&flags.to_ne_bytes());
+            }
+
+            fn flags(&self) -> libc::c_short {
+                let mut buf = [0u8; mem::size_of::<libc::c_short>()];
+                buf.copy_from_slice(&self.ifr_ifru[..buf.len()]);
+                libc::c_short::from_ne_bytes(buf)
+            }
+
+            fn set_int(&mut self, value: libc::c_int) {
+                self.ifr_ifru[..mem::size_of::<libc::c_int>()].copy_from_slice(&value.to_ne_bytes());
+            }
+
+            fn set_addr(&mut self, addr: Ipv4Addr) {
+                let sin = libc::sockaddr_in {
+                    sin_family: libc::AF_INET as libc::sa_family_t,
+                    sin_port: 0,
+                    sin_addr: libc::in_addr {
+                        s_addr: u32::from(addr).to_be(),
+                    },
+                    sin_zero: [0; 8],
+                };
+                unsafe {
+                    ptr::copy_nonoverlapping(
+                        &sin as *const _ as *const u8,
+                        self.ifr_ifru.as_mut_ptr(),
+                        mem::size_of::<libc::sockaddr_in>(),
+                    );
+                }
+            }
+        }
+
+        fn ioctl(fd: RawFd, request: libc::c_ulong, req: &mut IfReq) -> io::Result<()> {
+            let ret = unsafe { libc::ioctl(fd, request as _, req as *mut IfReq) };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        struct OwnedFd(RawFd);
+
+        impl AsRawFd for OwnedFd {
+            fn as_raw_fd(&self) -> RawFd {
+                self.0
+            }
+        }
+
+        impl Drop for OwnedFd {
+            fn drop(&mut self) {
+                unsafe {
+                    libc::close(self.0);
+                }
+            }
+        }
+
+        fn socket_fd(family: libc::c_int, ty: libc::c_int) -> io::Result<OwnedFd> {
+            let fd = unsafe { libc::socket(family, ty | libc::SOCK_CLOEXEC, 0) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(OwnedFd(fd))
+        }
+
+        /// A TUN device without packet information, packets read and written are raw IPv4 or IPv6 packets
+        pub struct TunDevice {
+            fd: AsyncFd<OwnedFd>,
+            name: String,
+        }
+
+        impl TunDevice {
+            /// Create (or attach to) TUN interface `name`, the kernel picks a name if it is `None`
+            ///
+            /// The interface is brought up with `address` assigned if it is provided
+            pub fn open(name: Option<&str>, address: Option<Ipv4Net>, mtu: usize) -> io::Result<TunDevice> {
+                let fd = unsafe {
+                    libc::open(
+                        b"/dev/net/tun\0".as_ptr() as *const libc::c_char,
+                        libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC,
+                    )
+                };
+                if fd < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                let fd = OwnedFd(fd);
+
+                let mut req = IfReq::new(name.unwrap_or(""))?;
+                req.set_flags((libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short);
+                ioctl(fd.0, TUNSETIFF, &mut req)?;
+                let name = req.name();
+
+                // Interface configurations are applied through an arbitrary socket
+                let sock = socket_fd(libc::AF_INET, libc::SOCK_DGRAM)?;
+
+                let mut req = IfReq::new(&name)?;
+                req.set_int(mtu as libc::c_int);
+                ioctl(sock.0, libc::SIOCSIFMTU as libc::c_ulong, &mut req)?;
+
+                if let Some(address) = address {
+                    let mut req = IfReq::new(&name)?;
+                    req.set_addr(address.addr());
+                    ioctl(sock.0, libc::SIOCSIFADDR as libc::c_ulong, &mut req)?;
+
+                    let mut req = IfReq::new(&name)?;
+                    req.set_addr(address.netmask());
+                    ioctl(sock.0, libc::SIOCSIFNETMASK as libc::c_ulong, &mut req)?;
+
+                    let mut req = IfReq::new(&name)?;
+                    ioctl(sock.0, libc::SIOCGIFFLAGS as libc::c_ulong, &mut req)?;
+                    let flags = req.flags() | (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+                    req.set_flags(flags);
+                    ioctl(sock.0, libc::SIOCSIFFLAGS as libc::c_ulong, &mut req)?;
+                }
+
+                Ok(TunDevice {
+                    fd: AsyncFd::new(fd)?,
+                    name,
+                })
+            }
+
+            /// Name of the interface
+            pub fn name(&self) -> &str {
+                &self.name
+            }
+
+            /// Read a packet
+            pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+                loop {
+                    let mut guard = self.fd.readable().await?;
+
+                    let fd = self.fd.get_ref().0;
+                    let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+                    if n >= 0 {
+                        return Ok(n as usize);
+                    }
+
+                    let err = io::Error::last_os_error();
+                    if err.kind() != ErrorKind::WouldBlock {
+                        return Err(err);
+                    }
+                    guard.clear_ready();
+                }
+            }
+
+            /// Write a packet
+            pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+                loop {
+                    let mut guard = self.fd.writable().await?;
+
+                    let fd = self.fd.get_ref().0;
+                    let n = unsafe { libc::write(fd, buf.as_ptr() as *const libc::c_void, buf.len()) };
+                    if n >= 0 {
+                        return Ok(n as usize);
+                    }
+
+                    let err = io::Error::last_os_error();
+                    if err.kind() != ErrorKind::WouldBlock {
+                        return Err(err);
+                    }
+                    guard.clear_ready();
+                }
+            }
+        }
+    } else {
+        use std::io::ErrorKind;
+
+        /// A TUN device, only supported on Linux
+        pub struct TunDevice;
+
+        impl TunDevice {
+            /// Create (or attach to) TUN interface `name`
+            pub fn open(_name: Option<&str>, _address: Option<Ipv4Net>, _mtu: usize) -> io::Result<TunDevice> {
+                let err = io::Error::new(ErrorKind::Other, "TUN device is not supported on this platform");
+                Err(err)
+            }
+
+            /// Name of the interface
+            pub fn name(&self) -> &str {
+                unreachable!()
+            }
+
+            /// Read a packet
+            pub async fn recv(&self, _buf: &mut [u8]) -> io::Result<usize> {
+                unreachable!()
+            }
+
+            /// Write a packet
+            pub async fn send(&self, _buf: &[u8]) -> io::Result<usize> {
+                unreachable!()
+            }
+        }
+    }
+}
@@ -13,7 +13,7 @@ use std::{
 };
 
 use async_trait::async_trait;
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use futures::future::{self, AbortHandle};
 use log::{debug, error, trace, warn};
 use lru_time_cache::{Entry, LruCache};
@@ -26,24 +26,29 @@ use tokio::{
 };
 
 use crate::{
-    config::{Config, ServerAddr, ServerConfig},
-    context::{Context, SharedContext},
+    config::{ServerAddr, ServerConfig},
+    context::{Context, SharedContext, SharedServerState},
     crypto::v1::CipherCategory,
     relay::{
         flow::SharedServerFlowStatistic,
         loadbalancing::server::{ServerData, SharedServerStatistic},
         socks5::Address,
+        speed_limit::SpeedBuckets,
         sys::create_outbound_udp_socket,
         utils::try_timeout,
     },
 };
 
 use super::{
-    crypto_io::{decrypt_payload, encrypt_payload},
+    crypto_io::{decrypt_payload, decrypt_payload_accepted, encrypt_payload},
+    socket_pool::{PooledUdpSocket, UdpSocketPool},
     DEFAULT_TIMEOUT,
     MAXIMUM_UDP_PAYLOAD_SIZE,
 };
 
+/// Maximum number of flows' routing decisions kept in an association
+const MAX_FLOW_ROUTES: usize = 256;
+
 #[async_trait]
 pub trait ProxySend {
     async fn send_packet(&mut self, addr: Address, data: Vec<u8>) -> io::Result<()>;
@@ -253,16 +258,23 @@ impl ProxyAssociation {
                 res?;
             }
             ServerAddr::DomainName(ref dname, port) => {
-                lookup_then!(context, dname, *port, |addr| {
-                    let res = remote_udp.connect(&addr).await;
-                    if let Err(ref err) = res {
-                        error!(
-                            "UDP association UdpSocket::connect failed, addr: {}:{} (resolved: {}), err: {}",
-                            dname, port, addr, err
-                        );
+                let mut res = Err(io::Error::new(io::ErrorKind::Other, "resolved empty address"));
+                for addr in context.resolve_server_addr(dname, *port).await? {
+                    res = remote_udp.connect(&addr).await;
+                    match res {
+                        Ok(..) => {
+                            context.server_addr_cache().pin(dname, *port, addr);
+                            break;
+                        }
+                        Err(ref err) => {
+                            error!(
+                                "UDP association UdpSocket::connect failed, addr: {}:{} (resolved: {}), err: {}",
+                                dname, port, addr, err
+                            );
+                        }
                     }
-                    res
-                })?;
+                }
+                res?;
             }
         }
 
@@ -292,11 +304,31 @@ impl ProxyAssociation {
         let mut bypass_sender_opt = None;
         let mut remote_sender_opt = None;
 
+        // Flows are classified by their first packets, later packets (like QUIC short header packets) follow them
+        let has_udp_rules = context.acl().map(|a| a.has_udp_rules()).unwrap_or(false);
+        let mut flow_routes = LruCache::<Address, bool>::with_capacity(MAX_FLOW_ROUTES);
+
         while let Some((addr, payload)) = rx.recv().await {
+            if context.check_target_rejected(&addr) {
+                debug!("UDP packet {} -> {} is rejected by ACL rules", src_addr, addr);
+                continue;
+            }
+
             // Check if addr should be bypassed
             //
             // Bypassed and Proxied are 2 separated associations, will be created dynamically.
-            let is_bypassed = context.check_target_bypassed(&addr).await;
+            let is_bypassed = if has_udp_rules {
+                match flow_routes.get(&addr) {
+                    Some(b) => *b,
+                    None => {
+                        let b = context.check_udp_target_bypassed(&addr, &payload).await;
+                        flow_routes.insert(addr.clone(), b);
+                        b
+                    }
+                }
+            } else {
+                context.check_target_bypassed(&addr).await
+            };
 
             let res = if is_bypassed {
                 if bypass_sender_opt.is_none() {
@@ -414,6 +446,18 @@ impl ProxyAssociation {
         payload: &[u8],
         socket: &UdpSocket,
     ) -> io::Result<()> {
+        if let Some(egress) = context.server_state().egress_limiter() {
+            if !egress.allow(payload.len()) {
+                trace!(
+                    "UDP association {} -> {} (proxied) egress limit exceeded, throwing away packet {} bytes",
+                    src_addr,
+                    target,
+                    payload.len()
+                );
+                return Ok(());
+            }
+        }
+
         // CLIENT -> SERVER protocol: ADDRESS + PAYLOAD
         let mut send_buf = Vec::with_capacity(target.serialized_len() + payload.len());
         target.write_to_buf(&mut send_buf);
@@ -463,6 +507,18 @@ impl ProxyAssociation {
         payload: &[u8],
         socket: &UdpSocket,
     ) -> io::Result<()> {
+        if let Some(egress) = context.server_state().egress_limiter() {
+            if !egress.allow(payload.len()) {
+                trace!(
+                    "UDP association {} -> {} (bypassed) egress limit exceeded, throwing away packet {} bytes",
+                    src_addr,
+                    target,
+                    payload.len()
+                );
+                return Ok(());
+            }
+        }
+
         // BYPASSED, so just send it directly without any modifications
 
         let send_len = match *target {
@@ -651,6 +707,7 @@ impl ProxyAssociation {
 struct AssociationManagerInner<K, A> {
     map: Arc<Mutex<LruCache<K, A>>>,
     watcher: AbortHandle,
+    state: SharedServerState,
 }
 
 impl<K, A> Drop for AssociationManagerInner<K, A> {
@@ -676,8 +733,9 @@ where
     K: Ord + Clone + Send + 'static,
     A: Send + 'static,
 {
-    /// Create a new AssociationManager based on Config
-    pub fn new(config: &Config) -> AssociationManager<K, A> {
+    /// Create a new AssociationManager based on the configuration of `context`
+    pub fn new(context: &Context) -> AssociationManager<K, A> {
+        let config = context.config();
         let timeout = config.udp_timeout.unwrap_or(DEFAULT_TIMEOUT);
 
         // TODO: Set default capacity by getrlimit #262
@@ -712,7 +770,11 @@ where
         tokio::spawn(release_task);
 
         AssociationManager {
-            inner: Arc::new(AssociationManagerInner { map, watcher }),
+            inner: Arc::new(AssociationManagerInner {
+                map,
+                watcher,
+                state: context.server_state().clone(),
+            }),
         }
     }
 
@@ -724,6 +786,15 @@ where
         let mut assoc = self.inner.map.lock().await;
         assoc.get(key).is_some()
     }
+
+    // New associations are refused while the system is under memory pressure, established ones are kept
+    fn check_memory_available(&self) -> io::Result<()> {
+        if self.inner.state.memory_pressured() {
+            let err = io::Error::new(io::ErrorKind::Other, "under memory pressure, new association is paused");
+            return Err(err);
+        }
+        Ok(())
+    }
 }
 
 impl<K> AssociationManager<K, ProxyAssociation>
@@ -740,7 +811,10 @@ where
         let mut assoc_map = self.inner.map.lock().await;
         let assoc = match assoc_map.entry(key) {
             Entry::Occupied(oc) => oc.into_mut(),
-            Entry::Vacant(vc) => vc.insert(create_fut.await?),
+            Entry::Vacant(vc) => {
+                self.check_memory_available()?;
+                vc.insert(create_fut.await?)
+            }
         };
 
         // FIXME: Lock is still kept for a mutable reference
@@ -763,6 +837,7 @@ pub struct ServerProxyHandler {
     cache_key: ServerAssociationKey,
     assoc_manager: ServerAssociationManager<ServerAssociationKey>,
     flow_stat: SharedServerFlowStatistic,
+    speed_buckets: SpeedBuckets,
     tx: Arc<UdpSocket>,
 }
 
@@ -772,6 +847,7 @@ impl ServerProxyHandler {
         src_addr: SocketAddr,
         assoc_manager: ServerAssociationManager<ServerAssociationKey>,
         flow_stat: SharedServerFlowStatistic,
+        speed_buckets: SpeedBuckets,
         tx: Arc<UdpSocket>,
     ) -> ServerProxyHandler {
         ServerProxyHandler {
@@ -779,6 +855,7 @@ impl ServerProxyHandler {
             cache_key: ServerProxyHandler::association_key(&src_addr),
             assoc_manager,
             flow_stat,
+            speed_buckets,
             tx,
         }
     }
@@ -805,6 +882,15 @@ impl ServerProxyHandler {
             return Ok(());
         }
 
+        if !self.speed_buckets.allow_down(pkt.len()) {
+            trace!(
+                "UDP association {} <- ... speed limit exceeded, throwing away packet {} bytes",
+                self.src_addr,
+                pkt.len()
+            );
+            return Ok(());
+        }
+
         let n = match self.tx.send_to(&pkt, &self.src_addr).await {
             Ok(n) => {
                 if n < pkt.len() {
@@ -834,16 +920,27 @@ pub struct ServerAssociation {
 
     // local <- remote task life watcher
     watcher: AbortHandle,
+
+    flow_stat: SharedServerFlowStatistic,
 }
 
 impl Drop for ServerAssociation {
     fn drop(&mut self) {
         self.watcher.abort();
+        self.flow_stat.decr_udp_associations();
     }
 }
 
 type SharedResolvedAddressCache = Arc<SyncMutex<LruCache<SocketAddr, Address>>>;
 
+/// Key identified from the client's packets, shared by both directions of an association
+struct ClientKey {
+    key: Bytes,
+    user_index: Option<usize>,
+}
+
+type SharedClientKey = Arc<SyncMutex<Option<ClientKey>>>;
+
 impl ServerAssociation {
     /// Create an association with addr
     pub async fn associate(
@@ -851,18 +948,29 @@ impl ServerAssociation {
         svr_idx: usize,
         src_addr: SocketAddr,
         response_tx: ServerProxyHandler,
+        socket_pool: &UdpSocketPool,
     ) -> io::Result<ServerAssociation> {
-        // Create a socket for receiving packets
+        // Create a socket for receiving packets, or reuse one of the same client in the pool
         // Let system allocate an address for us (INADDR_ANY)
         let bind_addr = match src_addr.ip() {
             IpAddr::V4(..) => Ipv4Addr::UNSPECIFIED.into(),
             IpAddr::V6(..) => Ipv6Addr::UNSPECIFIED.into(),
         };
         let local_addr = SocketAddr::new(bind_addr, 0);
-        let remote_udp = create_outbound_udp_socket(&local_addr, context.config()).await?;
+        let remote_udp = socket_pool
+            .acquire(src_addr.ip(), &local_addr, context.config())
+            .await?;
 
         let local_addr = remote_udp.local_addr().expect("could not determine port bound to");
-        debug!("created UDP Association for {} from {}", src_addr, local_addr);
+
+        let sampled = context.config().sampling.sample(&src_addr.ip());
+        sampled_log!(
+            sampled,
+            debug,
+            "created UDP Association for {} from {}",
+            src_addr,
+            local_addr
+        );
 
         // Create a channel for sending packets to remote
         // FIXME: Channel size 1024?
@@ -882,10 +990,14 @@ impl ServerAssociation {
             timeout, 512,
         )));
 
+        let client_key: SharedClientKey = Arc::new(SyncMutex::new(None));
+
         // local -> remote
         {
             let context = context.clone();
             let resolved_address_cache = resolved_address_cache.clone();
+            let client_key = client_key.clone();
+            let flow_stat = response_tx.flow_stat.clone();
             tokio::spawn(async move {
                 let svr_cfg = context.server_config(svr_idx);
 
@@ -899,6 +1011,9 @@ impl ServerAssociation {
                         timeout,
                         svr_cfg,
                         &resolved_address_cache,
+                        &client_key,
+                        &flow_stat,
+                        sampled,
                     )
                     .await
                     {
@@ -908,10 +1023,12 @@ impl ServerAssociation {
                     }
                 }
 
-                debug!("UDP ASSOCIATE {} -> .. finished", src_addr);
+                sampled_log!(sampled, debug, "UDP ASSOCIATE {} -> .. finished", src_addr);
             });
         }
 
+        let flow_stat = response_tx.flow_stat.clone();
+
         let (r2l_task, close_flag) = future::abortable(async move {
             let svr_cfg = context.server_config(svr_idx);
 
@@ -924,6 +1041,8 @@ impl ServerAssociation {
                     &response_tx,
                     svr_cfg,
                     &resolved_address_cache,
+                    &client_key,
+                    sampled,
                 )
                 .await
                 {
@@ -942,31 +1061,77 @@ impl ServerAssociation {
         tokio::spawn(async move {
             let _ = r2l_task.await;
 
-            debug!("UDP ASSOCIATE {} <- .. finished", src_addr);
+            sampled_log!(sampled, debug, "UDP ASSOCIATE {} <- .. finished", src_addr);
         });
 
+        flow_stat.incr_udp_associations();
+
         Ok(ServerAssociation {
             tx,
             watcher: close_flag,
+            flow_stat,
         })
     }
 
     /// Relay packets from local to remote
+    #[allow(clippy::too_many_arguments)]
     async fn relay_l2r(
         context: &Context,
         src: SocketAddr,
-        remote_udp: &UdpSocket,
+        remote_udp: &PooledUdpSocket,
         pkt: Vec<u8>,
         timeout: Duration,
         svr_cfg: &ServerConfig,
         resolved_address_cache: &SharedResolvedAddressCache,
+        client_key: &SharedClientKey,
+        flow_stat: &SharedServerFlowStatistic,
+        sampled: bool,
     ) -> io::Result<()> {
         // First of all, decrypt payload CLIENT -> SERVER
         let mut cur = if let CipherCategory::None = svr_cfg.method().category() {
             Cursor::new(pkt)
         } else {
-            let decrypted_pkt = match decrypt_payload(context, svr_cfg.method(), svr_cfg.key(), &pkt) {
-                Ok(Some(pkt)) => pkt,
+            let accepted_keys = svr_cfg.accepted_keys();
+            let decrypted = decrypt_payload_accepted(context, svr_cfg.method(), svr_cfg.key(), &accepted_keys, &pkt);
+            let decrypted_pkt = match decrypted {
+                Ok(Some((decrypted_pkt, idx))) => {
+                    let user = idx.and_then(|idx| svr_cfg.users().get(idx));
+                    if let Some(user) = user {
+                        flow_stat.user(user.name()).udp().incr_rx(pkt.len());
+                    }
+
+                    // Remember the key for encrypting responses
+                    let mut client_key = client_key.lock();
+                    match idx {
+                        Some(idx) => {
+                            if client_key.as_ref().map(|k| k.key != accepted_keys[idx]).unwrap_or(true) {
+                                match user {
+                                    Some(user) => sampled_log!(
+                                        sampled,
+                                        debug,
+                                        "UDP ASSOCIATE {} identified as user {}",
+                                        src,
+                                        user.name()
+                                    ),
+                                    None => sampled_log!(
+                                        sampled,
+                                        debug,
+                                        "UDP ASSOCIATE {} is using the previous password",
+                                        src
+                                    ),
+                                }
+
+                                *client_key = Some(ClientKey {
+                                    key: accepted_keys[idx].clone(),
+                                    user_index: user.map(|_| idx),
+                                });
+                            }
+                        }
+                        None => *client_key = None,
+                    }
+
+                    decrypted_pkt
+                }
                 Ok(None) => {
                     error!("failed to decrypt pkt in UDP relay, packet too short");
                     let err = io::Error::new(io::ErrorKind::InvalidData, "packet too short");
@@ -990,14 +1155,42 @@ impl ServerAssociation {
             return Ok(());
         }
 
+        // ACL of the user or of the server, checked besides the global one
+        let user_index = client_key.lock().as_ref().and_then(|k| k.user_index);
+        if let Some(acl) = svr_cfg.outbound_acl(user_index) {
+            if acl.check_outbound_blocked(context, &addr).await {
+                warn!(
+                    "{} -> outbound {} is blocked by ACL rules of server {}",
+                    src,
+                    addr,
+                    svr_cfg.addr()
+                );
+                return Ok(());
+            }
+        }
+
         // Take out internal buffer for optimizing one byte copy
         let header_len = cur.position() as usize;
         let decrypted_pkt = cur.into_inner();
         let body = &decrypted_pkt[header_len..];
 
+        if let Some(egress) = context.server_state().egress_limiter() {
+            if !egress.allow(body.len()) {
+                trace!(
+                    "UDP ASSOCIATE {} -> {} egress limit exceeded, throwing away packet {} bytes",
+                    src,
+                    addr,
+                    body.len()
+                );
+                return Ok(());
+            }
+        }
+
         let send_len = match addr {
             Address::SocketAddress(ref remote_addr) => {
-                debug!(
+                sampled_log!(
+                    sampled,
+                    debug,
                     "UDP ASSOCIATE {} -> {} ({}), payload length {} bytes",
                     src,
                     addr,
@@ -1012,7 +1205,9 @@ impl ServerAssociation {
 
                 match try_timeout(remote_udp.send_to(body, &remote_addr), Some(timeout)).await {
                     Ok(l) => {
-                        debug!(
+                        sampled_log!(
+                            sampled,
+                            debug,
                             "UDP ASSOCIATE {} -> {} ({}), payload length {} bytes",
                             src,
                             addr,
@@ -1038,17 +1233,24 @@ impl ServerAssociation {
 
         assert_eq!(body.len(), send_len);
 
+        if let Some(destination_flow_stat) = flow_stat.destination(&addr) {
+            destination_flow_stat.incr_rx(send_len);
+        }
+
         Ok(())
     }
 
     /// Relay packets from remote to local
+    #[allow(clippy::too_many_arguments)]
     async fn relay_r2l(
         context: &Context,
         src_addr: SocketAddr,
-        remote_udp: &UdpSocket,
+        remote_udp: &PooledUdpSocket,
         response_tx: &ServerProxyHandler,
         svr_cfg: &ServerConfig,
         resolved_address_cache: &SharedResolvedAddressCache,
+        client_key: &SharedClientKey,
+        sampled: bool,
     ) -> io::Result<()> {
         // Waiting for response from server SERVER -> CLIENT
         // Packet length is limited by MAXIMUM_UDP_PAYLOAD_SIZE, excess bytes will be discarded.
@@ -1061,16 +1263,37 @@ impl ServerAssociation {
             None => Address::from(remote_addr),
         };
 
-        debug!(
+        sampled_log!(
+            sampled,
+            debug,
             "UDP ASSOCIATE {} <- {} ({}), payload length {} bytes",
-            src_addr, addr, remote_addr, remote_recv_len
+            src_addr,
+            addr,
+            remote_addr,
+            remote_recv_len
         );
 
+        if let Some(destination_flow_stat) = response_tx.flow_stat.destination(&addr) {
+            destination_flow_stat.incr_tx(remote_recv_len);
+        }
+
         // CLIENT <- SERVER protocol: ADDRESS + PAYLOAD
         let mut send_buf = BytesMut::with_capacity(addr.serialized_len() + remote_recv_len);
         addr.write_to_buf(&mut send_buf);
         send_buf.extend_from_slice(&remote_buf[..remote_recv_len]);
 
+        if let Some(egress) = context.server_state().egress_limiter() {
+            if !egress.allow(send_buf.len()) {
+                trace!(
+                    "UDP ASSOCIATE {} <- {} egress limit exceeded, throwing away packet {} bytes",
+                    src_addr,
+                    addr,
+                    remote_recv_len
+                );
+                return Ok(());
+            }
+        }
+
         if let CipherCategory::None = svr_cfg.method().category() {
             // Send back to src_addr
             if let Err(err) = response_tx.send_packet(&send_buf).await {
@@ -1079,14 +1302,27 @@ impl ServerAssociation {
                 // FIXME: What to do? Ignore?
             }
         } else {
+            // Reply with the key identified from the client's packets
+            let (key, user) = match *client_key.lock() {
+                Some(ref k) => (k.key.clone(), k.user_index.and_then(|idx| svr_cfg.users().get(idx))),
+                None => (svr_cfg.clone_key(), None),
+            };
+
             let mut encrypt_buf = BytesMut::new();
-            encrypt_payload(context, svr_cfg.method(), svr_cfg.key(), &send_buf, &mut encrypt_buf);
+            encrypt_payload(context, svr_cfg.method(), &key, &send_buf, &mut encrypt_buf);
 
             // Send back to src_addr
-            if let Err(err) = response_tx.send_packet(&encrypt_buf).await {
-                error!("failed to send packet into response channel, error: {}", err);
+            match response_tx.send_packet(&encrypt_buf).await {
+                Ok(..) => {
+                    if let Some(user) = user {
+                        response_tx.flow_stat.user(user.name()).udp().incr_tx(encrypt_buf.len());
+                    }
+                }
+                Err(err) => {
+                    error!("failed to send packet into response channel, error: {}", err);
 
-                // FIXME: What to do? Ignore?
+                    // FIXME: What to do? Ignore?
+                }
             }
         }
 
@@ -1118,7 +1354,10 @@ where
         let mut assoc_map = self.inner.map.lock().await;
         let assoc = match assoc_map.entry(key) {
             Entry::Occupied(oc) => oc.into_mut(),
-            Entry::Vacant(vc) => vc.insert(create_fut.await?),
+            Entry::Vacant(vc) => {
+                self.check_memory_available()?;
+                vc.insert(create_fut.await?)
+            }
         };
 
         // FIXME: Lock is still kept for a mutable reference
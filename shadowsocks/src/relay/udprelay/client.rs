@@ -102,41 +102,17 @@ impl ServerClient {
     /// Create a client to communicate with Shadowsocks' UDP server
     pub async fn new(context: &Context, svr_cfg: &ServerConfig) -> io::Result<ServerClient> {
         let socket = match svr_cfg.addr() {
-            ServerAddr::SocketAddr(ref remote_addr) => {
-                let socket = match remote_addr.ip() {
-                    IpAddr::V4(..) => {
-                        let local_addr = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0);
-                        create_outbound_udp_socket(&local_addr, context.config()).await?
-                    }
-                    IpAddr::V6(..) => {
-                        let local_addr = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0);
-                        create_outbound_udp_socket(&local_addr, context.config()).await?
-                    }
-                };
-
-                socket.connect(remote_addr).await?;
-
-                socket
-            }
+            ServerAddr::SocketAddr(ref remote_addr) => connect_server_socket(context, remote_addr).await?,
             ServerAddr::DomainName(ref dname, port) => {
-                let (_, socket) = lookup_then!(context, dname, *port, |addr| {
-                    let socket = match addr.ip() {
-                        IpAddr::V4(..) => {
-                            let local_addr = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0);
-                            create_outbound_udp_socket(&local_addr, context.config()).await?
-                        }
-                        IpAddr::V6(..) => {
-                            let local_addr = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0);
-                            create_outbound_udp_socket(&local_addr, context.config()).await?
-                        }
-                    };
-
-                    socket.connect(&addr).await?;
-
-                    Ok::<_, io::Error>(socket)
-                })?;
-
-                socket
+                let mut result = Err(io::Error::new(io::ErrorKind::Other, "resolved empty address"));
+                for addr in context.resolve_server_addr(dname, *port).await? {
+                    result = connect_server_socket(context, &addr).await;
+                    if result.is_ok() {
+                        context.server_addr_cache().pin(dname, *port, addr);
+                        break;
+                    }
+                }
+                result?
             }
         };
 
@@ -238,3 +214,15 @@ impl ServerClient {
         Ok((addr, payload))
     }
 }
+
+/// Create an outbound UDP socket connected to the server's `addr`
+async fn connect_server_socket(context: &Context, addr: &SocketAddr) -> io::Result<UdpSocket> {
+    let local_addr = match addr.ip() {
+        IpAddr::V4(..) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        IpAddr::V6(..) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    };
+
+    let socket = create_outbound_udp_socket(&local_addr, context.config()).await?;
+    socket.connect(addr).await?;
+    Ok(socket)
+}
@@ -19,10 +19,10 @@
 //! | Fixed  | Variable  |   Fixed   |
 //! +--------+-----------+-----------+
 //! ```
-use std::io;
+use std::{io, iter};
 
 use byte_string::ByteStr;
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use log::{debug, trace};
 
 use crate::{
@@ -51,12 +51,12 @@ fn encrypt_payload_stream(context: &Context, method: CipherKind, key: &[u8], pay
     if iv_len > 0 {
         loop {
             random_iv_or_salt(iv);
-            if !context.check_nonce_and_set(&iv) {
+            if !context.check_nonce_and_set(key, &iv) {
                 break;
             }
         }
     } else {
-        context.check_nonce_and_set(&iv);
+        context.check_nonce_and_set(key, &iv);
     }
 
     let mut cipher = Cipher::new(method, &key, &iv);
@@ -82,12 +82,12 @@ fn encrypt_payload_aead(context: &Context, method: CipherKind, key: &[u8], paylo
     if salt_len > 0 {
         loop {
             random_iv_or_salt(salt);
-            if !context.check_nonce_and_set(&salt) {
+            if !context.check_nonce_and_set(key, &salt) {
                 break;
             }
         }
     } else {
-        context.check_nonce_and_set(&salt);
+        context.check_nonce_and_set(key, &salt);
     }
 
     let mut cipher = Cipher::new(method, &key, &salt);
@@ -127,6 +127,51 @@ pub fn decrypt_payload(
     }
 }
 
+/// Decrypt payload from ShadowSocks UDP encrypted packet, trying the server's `key` and then `accepted_keys`
+///
+/// Returns the decrypted payload with index in `accepted_keys` of the matched key, `None` if it is the server's `key`
+pub fn decrypt_payload_accepted(
+    context: &Context,
+    method: CipherKind,
+    key: &[u8],
+    accepted_keys: &[Bytes],
+    payload: &[u8],
+) -> io::Result<Option<(Vec<u8>, Option<usize>)>> {
+    if accepted_keys.is_empty() || method.category() != CipherCategory::Aead {
+        return decrypt_payload(context, method, key, payload).map(|p| p.map(|p| (p, None)));
+    }
+
+    let salt_len = method.salt_len();
+    if payload.len() < salt_len + method.tag_len() {
+        return Ok(None);
+    }
+
+    let (salt, payload) = payload.split_at(salt_len);
+    if context.check_nonce_and_set(key, salt) {
+        debug!("detected repeated salt {:?}", ByteStr::new(salt));
+        return Err(io::Error::new(io::ErrorKind::Other, "detected repeated salt"));
+    }
+
+    trace!("UDP packet got AEAD salt {:?}", ByteStr::new(salt));
+
+    let keys = iter::once(key).chain(accepted_keys.iter().map(AsRef::as_ref));
+    for (idx, key) in keys.enumerate() {
+        let mut cipher = Cipher::new(method, key, salt);
+
+        let mut buf = payload.to_vec();
+        if cipher.decrypt_packet(&mut buf) {
+            buf.truncate(buf.len() - cipher.tag_len());
+            // The first key is the server's key
+            return Ok(Some((buf, idx.checked_sub(1))));
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "invalid tag-in, no accepted key matches",
+    ))
+}
+
 fn decrypt_payload_stream(
     context: &Context,
     method: CipherKind,
@@ -142,7 +187,7 @@ fn decrypt_payload_stream(
 
     let iv = &payload[..iv_len];
 
-    if context.check_nonce_and_set(iv) {
+    if context.check_nonce_and_set(key, iv) {
         debug!("detected repeated iv {:?}", ByteStr::new(iv));
         return Err(io::Error::new(io::ErrorKind::Other, "detected repeated iv"));
     }
@@ -172,7 +217,7 @@ fn decrypt_payload_aead(
     }
 
     let (salt, payload) = payload.split_at(salt_len);
-    if context.check_nonce_and_set(salt) {
+    if context.check_nonce_and_set(key, salt) {
         debug!("detected repeated salt {:?}", ByteStr::new(salt));
         return Err(io::Error::new(io::ErrorKind::Other, "detected repeated salt"));
     }
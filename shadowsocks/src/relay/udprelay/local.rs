@@ -21,12 +21,17 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
 
     match context.config().config_type {
         ConfigType::Socks5Local => super::socks5_local::run(context).await,
+        // UDP ASSOCIATE of SOCKS5 clients
+        #[cfg(feature = "local-http")]
+        ConfigType::MixedLocal => super::socks5_local::run(context).await,
         #[cfg(feature = "local-socks4")]
         ConfigType::Socks4Local => unreachable!(),
         #[cfg(feature = "local-tunnel")]
         ConfigType::TunnelLocal => super::tunnel_local::run(context).await,
         #[cfg(feature = "local-redir")]
         ConfigType::RedirLocal => super::redir_local::run(context).await,
+        #[cfg(feature = "local-tun")]
+        ConfigType::TunLocal => unreachable!(),
         #[cfg(feature = "local-http")]
         ConfigType::HttpLocal => unreachable!(),
         #[cfg(all(
@@ -58,7 +58,10 @@ mod redir;
 #[cfg(feature = "local-redir")]
 mod redir_local;
 pub mod server;
+mod socket_pool;
 mod socks5_local;
+#[cfg(feature = "local-tun")]
+pub(crate) mod tun_local;
 #[cfg(feature = "local-tunnel")]
 mod tunnel_local;
 mod utils;
@@ -7,7 +7,7 @@ use log::{debug, error, info, trace, warn};
 use tokio::time;
 
 use crate::{
-    config::RedirType,
+    config::{RedirDnsPolicy, RedirType},
     context::SharedContext,
     relay::{
         loadbalancing::server::{PlainPingBalancer, ServerType},
@@ -25,6 +25,8 @@ use super::{
 struct ProxyHandler {
     ty: RedirType,
     src_addr: SocketAddr,
+    // Fake address of DNS relay which the client sent to, if the target is mapped to a domain name
+    fake_addr: Option<SocketAddr>,
     cache_key: String,
     assoc_map: ProxyAssociationManager<String>,
 }
@@ -33,12 +35,14 @@ impl ProxyHandler {
     pub fn new(
         ty: RedirType,
         src_addr: SocketAddr,
+        fake_addr: Option<SocketAddr>,
         cache_key: String,
         assoc_map: ProxyAssociationManager<String>,
     ) -> io::Result<ProxyHandler> {
         Ok(ProxyHandler {
             ty,
             src_addr,
+            fake_addr,
             cache_key,
             assoc_map,
         })
@@ -48,8 +52,14 @@ impl ProxyHandler {
 #[async_trait]
 impl ProxySend for ProxyHandler {
     async fn send_packet(&mut self, addr: Address, data: Vec<u8>) -> io::Result<()> {
+        let dst_addr = match addr {
+            Address::SocketAddress(a) => Some(a),
+            // Sent back from the fake address
+            Address::DomainNameAddress(..) => self.fake_addr,
+        };
+
         // Redirect only if the target is a SocketAddress
-        if let Address::SocketAddress(dst_addr) = addr {
+        if let Some(dst_addr) = dst_addr {
             // Create a socket binds to destination addr
             // This only works for systems that supports binding to non-local addresses
             let mut local_udp = UdpRedirSocket::bind(self.ty, dst_addr)?;
@@ -95,7 +105,7 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
 
     info!("shadowsocks UDP redirect listening on {}", local_addr);
 
-    let assoc_manager = ProxyAssociationManager::new(context.config());
+    let assoc_manager = ProxyAssociationManager::new(&context);
 
     let mut pkt_buf = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
 
@@ -131,9 +141,32 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
             continue;
         }
 
+        // Fake addresses of DNS relay are mapped back to domain names
+        let target = match context.map_fake_addr(dst) {
+            Ok(a) => a,
+            Err(err) => {
+                debug!("UDP redirect packet {} -> {} is dropped, {}", src, dst, err);
+                continue;
+            }
+        };
+        let fake_addr = match target {
+            Address::SocketAddress(..) => None,
+            Address::DomainNameAddress(..) => Some(dst),
+        };
+
         // Check destination should be proxied or not
-        let target = Address::SocketAddress(dst);
-        let is_bypassed = context.check_target_bypassed(&target).await;
+        let is_bypassed = if RedirDnsPolicy::is_dns_port(dst.port()) {
+            match context.config().redir_dns_policy {
+                RedirDnsPolicy::Acl => context.check_target_bypassed(&target).await,
+                RedirDnsPolicy::Proxy => false,
+                RedirDnsPolicy::Block => {
+                    debug!("UDP redirect DNS packet {} -> {} is blocked", src, dst);
+                    continue;
+                }
+            }
+        } else {
+            context.check_target_bypassed(&target).await
+        };
 
         // Check or (re)create an association
         let cache_key = format!("{}-{}", src, dst);
@@ -143,7 +176,7 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
                 // Pick a server
                 let server = balancer.pick_server();
 
-                let sender = match ProxyHandler::new(ty, src, cache_key_cloned, assoc_manager.clone()) {
+                let sender = match ProxyHandler::new(ty, src, fake_addr, cache_key_cloned, assoc_manager.clone()) {
                     Ok(s) => s,
                     Err(err) => {
                         debug!("create UDP association for {} <-> {}, error: {}", src, dst, err);
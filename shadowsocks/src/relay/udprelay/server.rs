@@ -16,6 +16,7 @@ use crate::{
 
 use super::{
     association::{ServerAssociation, ServerAssociationManager, ServerProxyHandler},
+    socket_pool::UdpSocketPool,
     MAXIMUM_UDP_PAYLOAD_SIZE,
 };
 
@@ -30,7 +31,8 @@ async fn listen(context: SharedContext, flow_stat: SharedServerFlowStatistic, sv
     let r = Arc::new(listener);
     let w = r.clone();
 
-    let assoc_manager = ServerAssociationManager::new(context.config());
+    let assoc_manager = ServerAssociationManager::new(&context);
+    let socket_pool = UdpSocketPool::new(context.config().udp_egress_pool_size, context.server_state().clone());
 
     let mut pkt_buf = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
 
@@ -61,6 +63,11 @@ async fn listen(context: SharedContext, flow_stat: SharedServerFlowStatistic, sv
             continue;
         }
 
+        if !svr_cfg.speed_limiter().udp_buckets(&src).allow_up(recv_len) {
+            trace!("dropped UDP packet from {}, speed limit exceeded", src);
+            continue;
+        }
+
         // Check ACL
         if context.check_client_blocked(&src).await {
             warn!("client {} is blocked by ACL rules", src);
@@ -70,8 +77,19 @@ async fn listen(context: SharedContext, flow_stat: SharedServerFlowStatistic, sv
         // Check or (re)create an association
         let res = assoc_manager
             .send_packet(ServerProxyHandler::association_key(&src), pkt.to_vec(), async {
-                let handler = ServerProxyHandler::new(src, assoc_manager.clone(), flow_stat.clone(), w.clone());
-                ServerAssociation::associate(context.clone(), svr_idx, src, handler).await
+                // Established associations are kept
+                if flow_stat.quota_exceeded(svr_cfg.quota()) {
+                    return Err(io::Error::new(io::ErrorKind::Other, "traffic quota exceeded"));
+                }
+
+                let handler = ServerProxyHandler::new(
+                    src,
+                    assoc_manager.clone(),
+                    flow_stat.clone(),
+                    svr_cfg.speed_limiter().udp_buckets(&src),
+                    w.clone(),
+                );
+                ServerAssociation::associate(context.clone(), svr_idx, src, handler, &socket_pool).await
             })
             .await;
 
@@ -86,6 +104,17 @@ pub async fn run(context: SharedContext, flow_stat: SharedMultiServerFlowStatist
     let vec_fut = FuturesUnordered::new();
 
     for (svr_idx, svr_cfg) in context.config().server.iter().enumerate() {
+        // Refused once here instead of for every packets
+        if context.config().security_level.rejects(svr_cfg.method()) {
+            error!(
+                target: "shadowsocks::security",
+                "event=reject_weak_method proto=udp server={} method={}",
+                svr_cfg.addr(),
+                svr_cfg.method()
+            );
+            continue;
+        }
+
         let context = context.clone();
         let flow_stat = flow_stat
             .get(svr_cfg.addr().port())
@@ -96,6 +125,14 @@ pub async fn run(context: SharedContext, flow_stat: SharedMultiServerFlowStatist
         vec_fut.push(svr_fut);
     }
 
+    if vec_fut.is_empty() {
+        let err = io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "all servers are rejected by security_level",
+        );
+        return Err(err);
+    }
+
     match vec_fut.into_future().await.0 {
         Some(res) => {
             error!("one of UDP servers exited unexpectly, result: {:?}", res);
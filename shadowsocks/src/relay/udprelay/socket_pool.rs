@@ -0,0 +1,169 @@
+//! Pool of server's outbound UDP sockets
+//!
+//! Sockets of expired associations are kept and reused by new associations, instead of being closed and
+//! created again. This reduces socket churn under heavy load of short lived associations, like DNS and QUIC.
+//!
+//! A socket is only reused by associations of the same client address, so clients couldn't be linked by their
+//! outbound ports. Peers of the previous association may still send to the port, so a reused socket only receives
+//! packets from peers that the current association has sent to.
+//!
+//! Idle sockets are closed while the system is under memory pressure.
+
+use std::{
+    collections::HashSet,
+    io,
+    net::{IpAddr, SocketAddr},
+    ops::Deref,
+    sync::Arc,
+};
+
+use log::trace;
+use spin::Mutex as SyncMutex;
+use tokio::net::UdpSocket;
+
+use crate::{config::Config, context::SharedServerState, relay::sys::create_outbound_udp_socket};
+
+struct PoolInner {
+    capacity: usize,
+    state: SharedServerState,
+    ipv4: Vec<(IpAddr, UdpSocket)>,
+    ipv6: Vec<(IpAddr, UdpSocket)>,
+}
+
+impl PoolInner {
+    fn sockets(&mut self, ipv4: bool) -> &mut Vec<(IpAddr, UdpSocket)> {
+        if ipv4 {
+            &mut self.ipv4
+        } else {
+            &mut self.ipv6
+        }
+    }
+
+    fn release(&mut self, ipv4: bool, client: IpAddr, socket: UdpSocket) {
+        if self.state.memory_pressured() {
+            if !self.ipv4.is_empty() || !self.ipv6.is_empty() {
+                trace!("closing idle UDP sockets in pool under memory pressure");
+                self.ipv4 = Vec::new();
+                self.ipv6 = Vec::new();
+            }
+            return;
+        }
+
+        let capacity = self.capacity;
+        let sockets = self.sockets(ipv4);
+        if sockets.len() < capacity {
+            sockets.push((client, socket));
+        }
+    }
+}
+
+/// Pool of idle outbound UDP sockets, kept for each address family
+#[derive(Clone)]
+pub struct UdpSocketPool {
+    inner: Option<Arc<SyncMutex<PoolInner>>>,
+}
+
+impl UdpSocketPool {
+    /// Create a pool keeping at most `capacity` idle sockets for each address family
+    ///
+    /// `None` or `0` disables pooling, sockets are closed after their associations expired
+    pub fn new(capacity: Option<usize>, state: SharedServerState) -> UdpSocketPool {
+        let inner = match capacity {
+            Some(capacity) if capacity > 0 => Some(Arc::new(SyncMutex::new(PoolInner {
+                capacity,
+                state,
+                ipv4: Vec::with_capacity(capacity),
+                ipv6: Vec::with_capacity(capacity),
+            }))),
+            _ => None,
+        };
+
+        UdpSocketPool { inner }
+    }
+
+    /// Take an idle socket used by `client` before, bound in the same family as `bind_addr`, or create a new one if
+    /// there is none
+    pub async fn acquire(
+        &self,
+        client: IpAddr,
+        bind_addr: &SocketAddr,
+        config: &Config,
+    ) -> io::Result<PooledUdpSocket> {
+        let idle = match self.inner {
+            Some(ref inner) => {
+                let mut inner = inner.lock();
+                let sockets = inner.sockets(bind_addr.is_ipv4());
+                sockets
+                    .iter()
+                    .rposition(|(c, _)| *c == client)
+                    .map(|pos| sockets.remove(pos).1)
+            }
+            None => None,
+        };
+
+        let (socket, peers) = match idle {
+            Some(socket) => (socket, Some(SyncMutex::new(HashSet::new()))),
+            None => (create_outbound_udp_socket(bind_addr, config).await?, None),
+        };
+
+        Ok(PooledUdpSocket {
+            socket: Some(socket),
+            client,
+            peers,
+            pool: self.inner.clone(),
+        })
+    }
+}
+
+/// Outbound UDP socket, which will be returned to the pool when dropped
+pub struct PooledUdpSocket {
+    socket: Option<UdpSocket>,
+    client: IpAddr,
+    // Peers sent to by the current association, `None` if the socket is not reused
+    peers: Option<SyncMutex<HashSet<SocketAddr>>>,
+    pool: Option<Arc<SyncMutex<PoolInner>>>,
+}
+
+impl PooledUdpSocket {
+    /// Send `buf` to `target`, which is allowed to reply
+    pub async fn send_to(&self, buf: &[u8], target: &SocketAddr) -> io::Result<usize> {
+        if let Some(ref peers) = self.peers {
+            peers.lock().insert(*target);
+        }
+        self.deref().send_to(buf, target).await
+    }
+
+    /// Receive a packet, packets from peers of previous associations are discarded
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let (n, addr) = self.deref().recv_from(buf).await?;
+            match self.peers {
+                Some(ref peers) if !peers.lock().contains(&addr) => {
+                    trace!("discarded {} bytes from {} on a pooled UDP socket", n, addr);
+                }
+                _ => return Ok((n, addr)),
+            }
+        }
+    }
+}
+
+impl Deref for PooledUdpSocket {
+    type Target = UdpSocket;
+
+    fn deref(&self) -> &UdpSocket {
+        self.socket.as_ref().expect("socket returned to pool")
+    }
+}
+
+impl Drop for PooledUdpSocket {
+    fn drop(&mut self) {
+        if let (Some(socket), Some(pool)) = (self.socket.take(), self.pool.as_ref()) {
+            let ipv4 = match socket.local_addr() {
+                Ok(addr) => addr.is_ipv4(),
+                Err(..) => return,
+            };
+
+            pool.lock().release(ipv4, self.client, socket);
+        }
+    }
+}
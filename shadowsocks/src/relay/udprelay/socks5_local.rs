@@ -130,7 +130,7 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
 
     info!("shadowsocks SOCKS5 UDP listening on {}", local_addr);
 
-    let assoc_manager = ProxyAssociationManager::new(context.config());
+    let assoc_manager = ProxyAssociationManager::new(&context);
 
     let mut pkt_buf = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
 
@@ -161,6 +161,14 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
             continue;
         }
 
+        if !context.is_socks5_udp_client(src.ip()) {
+            debug!(
+                "dropped UDP packet from {}, which hasn't authenticated by UDP ASSOCIATE",
+                src
+            );
+            continue;
+        }
+
         // Parse it for validating
         let (target, payload) = match parse_packet(pkt).await {
             Ok(t) => t,
@@ -0,0 +1,203 @@
+//! UDP relay of packets captured by the TUN device
+//!
+//! UDP isn't handled by the TCP/IP stack of the TUN device, datagrams are taken out of packets and relayed by
+//! associations, replies are wrapped into packets from the original destinations.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use log::{debug, trace, warn};
+use smoltcp::{
+    phy::ChecksumCapabilities,
+    wire::{
+        IpAddress,
+        IpProtocol,
+        Ipv4Address,
+        Ipv4Packet,
+        Ipv4Repr,
+        Ipv6Address,
+        Ipv6Packet,
+        Ipv6Repr,
+        UdpPacket,
+        UdpRepr,
+    },
+};
+use tokio::sync::mpsc;
+
+use crate::{
+    context::SharedContext,
+    relay::{
+        loadbalancing::server::{PlainPingBalancer, ServerType},
+        socks5::Address,
+        tun::sys::TunDevice,
+    },
+};
+
+use super::association::{ProxyAssociation, ProxyAssociationManager, ProxySend};
+
+/// Hop limit of reply packets
+const UDP_REPLY_HOP_LIMIT: u8 = 64;
+
+/// A datagram captured by the TUN device, `(source, destination, payload)`
+pub type UdpDatagram = (SocketAddr, SocketAddr, Vec<u8>);
+
+struct ProxyHandler {
+    device: Arc<TunDevice>,
+    src_addr: SocketAddr,
+    // Fake address of DNS relay which the client sent to, if the target is mapped to a domain name
+    fake_addr: Option<SocketAddr>,
+    cache_key: String,
+    assoc_map: ProxyAssociationManager<String>,
+}
+
+#[async_trait]
+impl ProxySend for ProxyHandler {
+    async fn send_packet(&mut self, addr: Address, data: Vec<u8>) -> io::Result<()> {
+        let reply_addr = match addr {
+            Address::SocketAddress(a) => Some(a),
+            // Sent back from the fake address
+            Address::DomainNameAddress(..) => self.fake_addr,
+        };
+
+        let packet = match reply_addr.and_then(|a| build_udp_packet(a, self.src_addr, &data)) {
+            Some(p) => p,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Address from remote couldn't be sent back to the client",
+                ));
+            }
+        };
+
+        let n = self.device.send(&packet).await?;
+        if n < packet.len() {
+            warn!(
+                "UDP association {} <- ... packet truncated, expecting {} bytes, but sent {} bytes",
+                self.src_addr,
+                packet.len(),
+                n
+            );
+        }
+
+        // Update LRU
+        self.assoc_map.keep_alive(&self.cache_key).await;
+
+        Ok(())
+    }
+}
+
+/// Relay datagrams received from `rx`, until the TUN device is closed
+pub async fn run(context: SharedContext, device: Arc<TunDevice>, mut rx: mpsc::Receiver<UdpDatagram>) {
+    let balancer = PlainPingBalancer::new(context.clone(), ServerType::Udp).await;
+    let assoc_manager = ProxyAssociationManager::new(&context);
+
+    while let Some((src, dst, payload)) = rx.recv().await {
+        trace!(
+            "received UDP packet from {}, destination {}, length {} bytes",
+            src,
+            dst,
+            payload.len()
+        );
+
+        // Fake addresses of DNS relay are mapped back to domain names
+        let target = match context.map_fake_addr(dst) {
+            Ok(a) => a,
+            Err(err) => {
+                debug!("UDP TUN packet {} -> {} is dropped, {}", src, dst, err);
+                continue;
+            }
+        };
+        let fake_addr = match target {
+            Address::SocketAddress(..) => None,
+            Address::DomainNameAddress(..) => Some(dst),
+        };
+
+        let is_bypassed = context.check_target_bypassed(&target).await;
+
+        // Check or (re)create an association
+        let cache_key = format!("{}-{}", src, dst);
+        let cache_key_cloned = cache_key.clone();
+        let res = assoc_manager
+            .send_packet(cache_key, target, payload, async {
+                // Pick a server
+                let server = balancer.pick_server();
+
+                let sender = ProxyHandler {
+                    device: device.clone(),
+                    src_addr: src,
+                    fake_addr,
+                    cache_key: cache_key_cloned,
+                    assoc_map: assoc_manager.clone(),
+                };
+
+                if is_bypassed {
+                    ProxyAssociation::associate_bypassed(src, server, sender).await
+                } else {
+                    ProxyAssociation::associate_proxied(src, server, sender).await
+                }
+            })
+            .await;
+
+        if let Err(err) = res {
+            debug!("failed to create UDP association, {}", err);
+        }
+    }
+}
+
+/// Build an IP packet carrying a UDP datagram, `None` if addresses are in different families
+fn build_udp_packet(src_addr: SocketAddr, dst_addr: SocketAddr, payload: &[u8]) -> Option<Vec<u8>> {
+    let udp_repr = UdpRepr {
+        src_port: src_addr.port(),
+        dst_port: dst_addr.port(),
+        payload,
+    };
+    let caps = ChecksumCapabilities::default();
+
+    match (src_addr.ip(), dst_addr.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let ip_repr = Ipv4Repr {
+                src_addr: Ipv4Address::from_bytes(&src.octets()),
+                dst_addr: Ipv4Address::from_bytes(&dst.octets()),
+                protocol: IpProtocol::Udp,
+                payload_len: udp_repr.buffer_len(),
+                hop_limit: UDP_REPLY_HOP_LIMIT,
+            };
+
+            let mut buf = vec![0u8; ip_repr.buffer_len() + ip_repr.payload_len];
+            let mut ip_packet = Ipv4Packet::new_unchecked(&mut buf[..]);
+            ip_repr.emit(&mut ip_packet, &caps);
+            udp_repr.emit(
+                &mut UdpPacket::new_unchecked(ip_packet.payload_mut()),
+                &IpAddress::Ipv4(ip_repr.src_addr),
+                &IpAddress::Ipv4(ip_repr.dst_addr),
+                &caps,
+            );
+            Some(buf)
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            let ip_repr = Ipv6Repr {
+                src_addr: Ipv6Address::from_bytes(&src.octets()),
+                dst_addr: Ipv6Address::from_bytes(&dst.octets()),
+                next_header: IpProtocol::Udp,
+                payload_len: udp_repr.buffer_len(),
+                hop_limit: UDP_REPLY_HOP_LIMIT,
+            };
+
+            let mut buf = vec![0u8; ip_repr.buffer_len() + ip_repr.payload_len];
+            let mut ip_packet = Ipv6Packet::new_unchecked(&mut buf[..]);
+            ip_repr.emit(&mut ip_packet);
+            udp_repr.emit(
+                &mut UdpPacket::new_unchecked(ip_packet.payload_mut()),
+                &IpAddress::Ipv6(ip_repr.src_addr),
+                &IpAddress::Ipv6(ip_repr.dst_addr),
+                &caps,
+            );
+            Some(buf)
+        }
+        _ => None,
+    }
+}
@@ -3,10 +3,12 @@
 use std::{io, net::SocketAddr, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
+use futures::future;
 use log::{debug, error, info, trace, warn};
 use tokio::{self, net::UdpSocket, time};
 
 use crate::{
+    config::ClientConfig,
     context::SharedContext,
     relay::{
         loadbalancing::server::{PlainPingBalancer, ServerType},
@@ -68,27 +70,26 @@ impl ProxySend for ProxyHandler {
     }
 }
 
-/// Starts a UDP local server
-pub async fn run(context: SharedContext) -> io::Result<()> {
-    let local_addr = context.config().local_addr.as_ref().expect("local config");
+async fn run_forward(
+    context: SharedContext,
+    balancer: PlainPingBalancer,
+    local_addr: ClientConfig,
+    forward_target: Address,
+) -> io::Result<()> {
     let bind_addr = local_addr.bind_addr(&context).await?;
 
     let l = create_udp_socket(&bind_addr).await?;
     let local_addr = l.local_addr().expect("could not determine port bound to");
 
-    let balancer = PlainPingBalancer::new(context.clone(), ServerType::Udp).await;
-
     let r = Arc::new(l);
     let w = r.clone();
 
-    let forward_target = context.config().forward.clone().expect("`forward` address in config");
-
     info!(
         "shadowsocks UDP tunnel listening on {}, forward to {}",
         local_addr, forward_target
     );
 
-    let assoc_manager = ProxyAssociationManager::new(context.config());
+    let assoc_manager = ProxyAssociationManager::new(&context);
 
     let mut pkt_buf = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
 
@@ -136,3 +137,26 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
         }
     }
 }
+
+/// Starts UDP tunnels of all forwardings that enable UDP
+pub async fn run(context: SharedContext) -> io::Result<()> {
+    let forwards = context
+        .config()
+        .tunnel_forwards()
+        .into_iter()
+        .filter(|(_, _, mode)| mode.enable_udp())
+        .collect::<Vec<_>>();
+    if forwards.is_empty() {
+        let err = io::Error::new(io::ErrorKind::InvalidInput, "no forwardings enable UDP for TUNNEL");
+        return Err(err);
+    }
+
+    let balancer = PlainPingBalancer::new(context.clone(), ServerType::Udp).await;
+
+    let tunnels = forwards.into_iter().map(|(local_addr, forward_target, _)| {
+        run_forward(context.clone(), balancer.clone(), local_addr, forward_target)
+    });
+    future::try_join_all(tunnels).await?;
+
+    Ok(())
+}
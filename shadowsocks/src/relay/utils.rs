@@ -42,3 +42,25 @@ pub fn set_nofile(_nofile: u64) -> io::Result<()> {
     // Windows' limit of opening files is the size of HANDLE (32-bits), so it is unlimited
     Ok(())
 }
+
+/// Compare without returning early, so secrets couldn't be guessed by response time
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn constant_time_eq_compares_bytes() {
+        assert!(constant_time_eq(b"password", b"password"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"password", b"passwore"));
+        assert!(!constant_time_eq(b"password", b"password1"));
+        assert!(!constant_time_eq(b"", b"p"));
+    }
+}
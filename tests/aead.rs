@@ -0,0 +1,248 @@
+//! Known-answer tests of AEAD ciphers
+//!
+//! Vectors of their specifications are run through the primitives, then through `Cipher` with the shadowsocks AEAD
+//! construction: subkey is derived by HKDF-SHA1 from the key and salt with info "ss-subkey", and nonce starts from
+//! zero, increased in little endian after every packet. `Cipher` must seal every packet exactly as the primitive
+//! does with the subkey and that nonce.
+
+use aes_gcm_siv::{
+    aead::{generic_array::GenericArray, Aead, NewAead, Payload},
+    Aes128GcmSiv,
+    Aes256GcmSiv,
+};
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use sha1::Sha1;
+
+use shadowsocks::crypto::v1::{Cipher, CipherKind};
+
+const SS_SALT: &str = "202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f";
+const SS_SUBKEY_INFO: &[u8] = b"ss-subkey";
+
+/// (key, nonce, plaintext, aad, ciphertext with tag)
+type Vector = (&'static str, &'static str, &'static str, &'static str, &'static str);
+
+// RFC 8452, Appendix C.1
+const AES_128_GCM_SIV_VECTORS: &[Vector] = &[
+    (
+        "01000000000000000000000000000000",
+        "030000000000000000000000",
+        "",
+        "",
+        "dc20e2d83f25705bb49e439eca56de25",
+    ),
+    (
+        "01000000000000000000000000000000",
+        "030000000000000000000000",
+        "0100000000000000",
+        "",
+        "b5d839330ac7b786578782fff6013b815b287c22493a364c",
+    ),
+    (
+        "01000000000000000000000000000000",
+        "030000000000000000000000",
+        "010000000000000000000000",
+        "",
+        "7323ea61d05932260047d942a4978db357391a0bc4fdec8b0d106639",
+    ),
+    (
+        "01000000000000000000000000000000",
+        "030000000000000000000000",
+        "0100000000000000000000000000000002000000000000000000000000000000",
+        "",
+        "84e07e62ba83a6585417245d7ec413a9fe427d6315c09b57ce45f2e3936a94451a8e45dcd4578c667cd86847bf6155ff",
+    ),
+];
+
+// RFC 8452, Appendix C.2
+const AES_256_GCM_SIV_VECTORS: &[Vector] = &[
+    (
+        "0100000000000000000000000000000000000000000000000000000000000000",
+        "030000000000000000000000",
+        "",
+        "",
+        "07f5f4169bbf55a8400cd47ea6fd400f",
+    ),
+    (
+        "0100000000000000000000000000000000000000000000000000000000000000",
+        "030000000000000000000000",
+        "0100000000000000",
+        "",
+        "c2ef328e5c71c83b843122130f7364b761e0b97427e3df28",
+    ),
+    (
+        "0100000000000000000000000000000000000000000000000000000000000000",
+        "030000000000000000000000",
+        "0200000000000000000000000000000003000000000000000000000000000000",
+        "01",
+        "07dad364bfc2b9da89116d7bef6daaaf6f255510aa654f920ac81b94e8bad365aea1bad12702e1965604374aab96dbbc",
+    ),
+];
+
+// draft-irtf-cfrg-xchacha-03, Appendix A.3.1
+const XCHACHA20_POLY1305_VECTORS: &[Vector] = &[(
+    "808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f",
+    "404142434445464748494a4b4c4d4e4f5051525354555657",
+    // "Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would
+    // be it."
+    "4c616469657320616e642047656e746c656d656e206f662074686520636c617373206f66202739393a204966204920636f756c64206f\
+     6666657220796f75206f6e6c79206f6e652074697020666f7220746865206675747572652c2073756e73637265656e20776f756c642062\
+     652069742e",
+    "50515253c0c1c2c3c4c5c6c7",
+    "bd6d179d3e83d43b9576579493c0e939572a1700252bfaccbed2902c21396cbb731c7f1b0b4aa6440bf3a82f4eda7e39\
+     ae64c6708c54c216cb96b72e1213b4522f8c9ba40db5d945b11b69b982c1bb9e3f3fac2bc369488f76b2383565d3fff9\
+     21f9664c97637da9768812f615c68b13b52ec0875924c1c7987947deafd8780acf49",
+)];
+
+fn from_hex(s: &str) -> Vec<u8> {
+    hex::decode(s).unwrap()
+}
+
+fn seal<C: Aead>(cipher: &C, nonce: &[u8], plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+    let payload = Payload { msg: plaintext, aad };
+    cipher.encrypt(GenericArray::from_slice(nonce), payload).unwrap()
+}
+
+fn open<C: Aead>(cipher: &C, nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> Vec<u8> {
+    let payload = Payload { msg: ciphertext, aad };
+    cipher.decrypt(GenericArray::from_slice(nonce), payload).unwrap()
+}
+
+// Checks `vectors` with the primitive created by `new`
+fn check_primitive<C, F>(vectors: &[Vector], new: F)
+where
+    C: Aead,
+    F: Fn(&[u8]) -> C,
+{
+    for (key, nonce, plaintext, aad, expected) in vectors.iter() {
+        let cipher = new(&from_hex(key));
+        let nonce = from_hex(nonce);
+        let plaintext = from_hex(plaintext);
+        let aad = from_hex(aad);
+
+        let ciphertext = seal(&cipher, &nonce, &plaintext, &aad);
+        assert_eq!(hex::encode(&ciphertext), *expected);
+        assert_eq!(open(&cipher, &nonce, &ciphertext, &aad), plaintext);
+    }
+}
+
+fn ss_subkey(key: &[u8], salt: &[u8]) -> Vec<u8> {
+    let mut subkey = vec![0u8; key.len()];
+    Hkdf::<Sha1>::new(Some(salt), key)
+        .expand(SS_SUBKEY_INFO, &mut subkey)
+        .unwrap();
+    subkey
+}
+
+// Packet counter in little endian, padded to `nonce_len` bytes
+fn ss_nonce(counter: u64, nonce_len: usize) -> Vec<u8> {
+    let mut nonce = vec![0u8; nonce_len];
+    nonce[..8].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+// Runs keys and plaintexts of `vectors` through `Cipher`, every key with all plaintexts twice as consecutive packets
+//
+// Packets must be the same as sealed by the primitive with the subkey, and `nonce_len` bytes nonce of the counter
+fn check_cipher<C, F>(method: CipherKind, nonce_len: usize, vectors: &[Vector], new: F)
+where
+    C: Aead,
+    F: Fn(&[u8]) -> C,
+{
+    let salt = from_hex(SS_SALT);
+    let salt = &salt[..method.salt_len()];
+
+    for (key, ..) in vectors.iter() {
+        let key = from_hex(key);
+        assert_eq!(key.len(), method.key_len());
+
+        let primitive = new(&ss_subkey(&key, salt));
+
+        let mut enc = Cipher::new(method, &key, salt);
+        let mut dec = Cipher::new(method, &key, salt);
+        for (counter, (_, _, plaintext, ..)) in vectors.iter().chain(vectors.iter()).enumerate() {
+            let plaintext = from_hex(plaintext);
+
+            let mut pkt = plaintext.clone();
+            pkt.resize(plaintext.len() + method.tag_len(), 0);
+            enc.encrypt_packet(&mut pkt);
+
+            let nonce = ss_nonce(counter as u64, nonce_len);
+            assert_eq!(pkt, seal(&primitive, &nonce, &plaintext, &[]));
+
+            let mut tampered = pkt.clone();
+            tampered[0] ^= 1;
+            assert!(!Cipher::new(method, &key, salt).decrypt_packet(&mut tampered));
+
+            assert!(dec.decrypt_packet(&mut pkt));
+            assert_eq!(&pkt[..plaintext.len()], &plaintext[..]);
+        }
+    }
+}
+
+#[test]
+fn aes_128_gcm_siv_rfc8452() {
+    check_primitive(AES_128_GCM_SIV_VECTORS, |key| {
+        Aes128GcmSiv::new(GenericArray::from_slice(key))
+    });
+}
+
+#[test]
+fn aes_128_gcm_siv_cipher() {
+    check_cipher(CipherKind::AES_128_GCM_SIV, 12, AES_128_GCM_SIV_VECTORS, |key| {
+        Aes128GcmSiv::new(GenericArray::from_slice(key))
+    });
+}
+
+#[test]
+fn aes_256_gcm_siv_rfc8452() {
+    check_primitive(AES_256_GCM_SIV_VECTORS, |key| {
+        Aes256GcmSiv::new(GenericArray::from_slice(key))
+    });
+}
+
+#[test]
+fn aes_256_gcm_siv_cipher() {
+    check_cipher(CipherKind::AES_256_GCM_SIV, 12, AES_256_GCM_SIV_VECTORS, |key| {
+        Aes256GcmSiv::new(GenericArray::from_slice(key))
+    });
+}
+
+#[test]
+fn xchacha20_poly1305_draft_vector() {
+    check_primitive(XCHACHA20_POLY1305_VECTORS, |key| {
+        XChaCha20Poly1305::new(GenericArray::from_slice(key))
+    });
+}
+
+#[test]
+fn xchacha20_poly1305_cipher() {
+    const NONCE_LEN: usize = 24;
+
+    assert_eq!(XNonce::default().len(), NONCE_LEN);
+
+    check_cipher(
+        CipherKind::XCHACHA20_POLY1305,
+        NONCE_LEN,
+        XCHACHA20_POLY1305_VECTORS,
+        |key| XChaCha20Poly1305::new(GenericArray::from_slice(key)),
+    );
+}
+
+// The extended nonce goes through HChaCha20, so packets must differ from ChaCha20-Poly1305's with 12 bytes nonce
+#[test]
+fn xchacha20_poly1305_cipher_not_ietf() {
+    let method = CipherKind::XCHACHA20_POLY1305;
+    let (key, _, plaintext, ..) = XCHACHA20_POLY1305_VECTORS[0];
+    let key = from_hex(key);
+    let plaintext = from_hex(plaintext);
+    let salt = from_hex(SS_SALT);
+    let salt = &salt[..method.salt_len()];
+
+    let mut pkt = plaintext.clone();
+    pkt.resize(plaintext.len() + method.tag_len(), 0);
+    Cipher::new(method, &key, salt).encrypt_packet(&mut pkt);
+
+    let ietf = ChaCha20Poly1305::new(GenericArray::from_slice(&ss_subkey(&key, salt)));
+    assert_ne!(pkt, seal(&ietf, &ss_nonce(0, 12), &plaintext, &[]));
+}
@@ -0,0 +1,53 @@
+use shadowsocks::{
+    config::{ConfigType, ServerAddr},
+    migrate::{migrate, SourceFormat},
+};
+
+#[test]
+fn migrate_libev_port_password() {
+    let migrated = migrate(
+        SourceFormat::Libev,
+        r#"{
+            "server": ["0.0.0.0", "::"],
+            "port_password": {
+                "8388": "password1",
+                "8389": "password2"
+            },
+            "method": "aes-256-gcm",
+            "plugin": "obfs-local",
+            "plugin_opts": "obfs=http",
+            "fast_open": true
+        }"#,
+        ConfigType::Server,
+    )
+    .unwrap();
+
+    let config = migrated.config;
+    assert_eq!(config.server.len(), 4);
+
+    let svr = &config.server[0];
+    assert_eq!(svr.password(), "password1");
+    assert_eq!(svr.plugin().unwrap().plugin, "obfs-server");
+    assert_eq!(svr.plugin().unwrap().plugin_opts.as_deref(), Some("obfs=http"));
+
+    assert_eq!(migrated.warnings.len(), 1);
+}
+
+#[test]
+fn migrate_go_shadowsocks2_client() {
+    let migrated = migrate(
+        SourceFormat::GoShadowsocks2,
+        "go-shadowsocks2 -c 'ss://AEAD_CHACHA20_POLY1305:your%40password@1.2.3.4:8488' \\\n    -socks 127.0.0.1:1080 -u",
+        ConfigType::Socks5Local,
+    )
+    .unwrap();
+
+    let config = migrated.config;
+    assert_eq!(config.server.len(), 1);
+
+    let svr = &config.server[0];
+    assert_eq!(svr.password(), "your@password");
+    assert_eq!(svr.method().to_string(), "chacha20-ietf-poly1305");
+    assert!(matches!(svr.addr(), ServerAddr::SocketAddr(sa) if sa.port() == 8488));
+    assert!(config.mode.enable_udp());
+}
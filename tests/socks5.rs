@@ -11,7 +11,12 @@ use tokio::{
 use shadowsocks::{
     config::{Config, ConfigType, Mode, ServerAddr, ServerConfig},
     crypto::v1::CipherKind,
-    relay::{socks5::Address, tcprelay::client::Socks5Client},
+    relay::{
+        flow::{MultiServerFlowStatistic, ServerFlowStatistic},
+        server::run_with_flow_stat as run_server_with_flow_stat,
+        socks5::Address,
+        tcprelay::client::Socks5Client,
+    },
     run_local,
     run_server,
 };
@@ -129,3 +134,193 @@ async fn socks5_relay_aead() {
     let http_status = b"HTTP/1.0 200 OK\r\n";
     buf.starts_with(http_status);
 }
+
+#[tokio::test]
+async fn socks5_relay_aead_xchacha20() {
+    let _ = env_logger::try_init();
+
+    const SERVER_ADDR: &str = "127.0.0.1:8120";
+    const LOCAL_ADDR: &str = "127.0.0.1:8220";
+
+    const PASSWORD: &str = "test-password";
+    const METHOD: CipherKind = CipherKind::XCHACHA20_POLY1305;
+
+    let svr = Socks5TestServer::new(SERVER_ADDR, LOCAL_ADDR, PASSWORD, METHOD, false);
+    svr.run().await;
+
+    let mut c = Socks5Client::connect(
+        Address::DomainNameAddress("www.example.com".to_owned(), 80),
+        svr.client_addr(),
+    )
+    .await
+    .unwrap();
+
+    let req = b"GET / HTTP/1.0\r\nHost: www.example.com\r\nAccept: */*\r\n\r\n";
+    c.write_all(req).await.unwrap();
+    c.flush().await.unwrap();
+
+    let mut buf = Vec::new();
+    c.read_to_end(&mut buf).await.unwrap();
+
+    println!("Got reply from server: {}", str::from_utf8(&buf).unwrap());
+
+    let http_status = b"HTTP/1.0 200 OK\r\n";
+    assert!(buf.starts_with(http_status));
+}
+
+#[tokio::test]
+async fn socks5_relay_aead_gcm_siv() {
+    let _ = env_logger::try_init();
+
+    const SERVER_ADDR: &str = "127.0.0.1:8130";
+    const LOCAL_ADDR: &str = "127.0.0.1:8230";
+
+    const PASSWORD: &str = "test-password";
+    const METHOD: CipherKind = CipherKind::AES_256_GCM_SIV;
+
+    let svr = Socks5TestServer::new(SERVER_ADDR, LOCAL_ADDR, PASSWORD, METHOD, false);
+    svr.run().await;
+
+    let mut c = Socks5Client::connect(
+        Address::DomainNameAddress("www.example.com".to_owned(), 80),
+        svr.client_addr(),
+    )
+    .await
+    .unwrap();
+
+    let req = b"GET / HTTP/1.0\r\nHost: www.example.com\r\nAccept: */*\r\n\r\n";
+    c.write_all(req).await.unwrap();
+    c.flush().await.unwrap();
+
+    let mut buf = Vec::new();
+    c.read_to_end(&mut buf).await.unwrap();
+
+    println!("Got reply from server: {}", str::from_utf8(&buf).unwrap());
+
+    let http_status = b"HTTP/1.0 200 OK\r\n";
+    assert!(buf.starts_with(http_status));
+}
+
+#[tokio::test]
+async fn socks5_relay_aead_gcm_siv_128() {
+    let _ = env_logger::try_init();
+
+    const SERVER_ADDR: &str = "127.0.0.1:8150";
+    const LOCAL_ADDR: &str = "127.0.0.1:8250";
+
+    const PASSWORD: &str = "test-password";
+    const METHOD: CipherKind = CipherKind::AES_128_GCM_SIV;
+
+    let svr = Socks5TestServer::new(SERVER_ADDR, LOCAL_ADDR, PASSWORD, METHOD, false);
+    svr.run().await;
+
+    let mut c = Socks5Client::connect(
+        Address::DomainNameAddress("www.example.com".to_owned(), 80),
+        svr.client_addr(),
+    )
+    .await
+    .unwrap();
+
+    let req = b"GET / HTTP/1.0\r\nHost: www.example.com\r\nAccept: */*\r\n\r\n";
+    c.write_all(req).await.unwrap();
+    c.flush().await.unwrap();
+
+    let mut buf = Vec::new();
+    c.read_to_end(&mut buf).await.unwrap();
+
+    println!("Got reply from server: {}", str::from_utf8(&buf).unwrap());
+
+    let http_status = b"HTTP/1.0 200 OK\r\n";
+    assert!(buf.starts_with(http_status));
+}
+
+// Sends a HTTP request through SOCKS5 `local_addr`, returns the reply, or `None` if it is not replied in time
+async fn http_get_through(local_addr: &SocketAddr) -> Option<Vec<u8>> {
+    let mut c = Socks5Client::connect(Address::DomainNameAddress("www.example.com".to_owned(), 80), local_addr)
+        .await
+        .ok()?;
+
+    let req = b"GET / HTTP/1.0\r\nHost: www.example.com\r\nAccept: */*\r\n\r\n";
+    c.write_all(req).await.ok()?;
+    c.flush().await.ok()?;
+
+    let mut buf = Vec::new();
+    match time::timeout(Duration::from_secs(5), c.read_to_end(&mut buf)).await {
+        Ok(Ok(..)) => Some(buf),
+        _ => None,
+    }
+}
+
+#[tokio::test]
+async fn socks5_relay_aead_multi_user() {
+    let _ = env_logger::try_init();
+
+    const SERVER_ADDR: &str = "127.0.0.1:8140";
+    const SERVER_PORT: u16 = 8140;
+
+    const PASSWORD: &str = "test-password";
+    const METHOD: CipherKind = CipherKind::AES_256_GCM;
+
+    // (name, password, local address)
+    const USERS: [(&str, &str, &str); 2] = [
+        ("alice", "test-alice-password", "127.0.0.1:8240"),
+        ("bob", "test-bob-password", "127.0.0.1:8241"),
+    ];
+    const WRONG_PASSWORD: &str = "test-wrong-password";
+    const WRONG_LOCAL_ADDR: &str = "127.0.0.1:8242";
+
+    let svr_addr: SocketAddr = SERVER_ADDR.parse().unwrap();
+
+    let mut svr_config = Config::new(ConfigType::Server);
+    let mut svr_cfg = ServerConfig::basic(svr_addr, PASSWORD.to_owned(), METHOD);
+    for (name, password, _) in USERS.iter() {
+        svr_cfg.add_user((*name).to_owned(), (*password).to_owned());
+    }
+    svr_config.server = vec![svr_cfg];
+    svr_config.mode = Mode::TcpOnly;
+
+    let flow_stat = MultiServerFlowStatistic::new_shared(&svr_config);
+    tokio::spawn(run_server_with_flow_stat(svr_config, flow_stat.clone()));
+
+    let local_passwords = USERS
+        .iter()
+        .map(|(_, password, local_addr)| (*password, *local_addr))
+        .chain(Some((WRONG_PASSWORD, WRONG_LOCAL_ADDR)));
+    for (password, local_addr) in local_passwords {
+        let mut cli_config = Config::new(ConfigType::Socks5Local);
+        cli_config.local_addr = Some(ServerAddr::from(local_addr.parse::<SocketAddr>().unwrap()));
+        cli_config.server = vec![ServerConfig::basic(svr_addr, password.to_owned(), METHOD)];
+        cli_config.mode = Mode::TcpOnly;
+        tokio::spawn(run_local(cli_config));
+    }
+
+    time::sleep(Duration::from_secs(1)).await;
+
+    let svr_flow_stat = flow_stat.get(SERVER_PORT).unwrap().clone();
+    let http_status = b"HTTP/1.0 200 OK\r\n";
+
+    for (idx, (name, _, local_addr)) in USERS.iter().enumerate() {
+        let buf = http_get_through(&local_addr.parse().unwrap()).await.unwrap();
+        println!("Got reply from server for {}: {}", name, str::from_utf8(&buf).unwrap());
+        assert!(buf.starts_with(http_status));
+
+        // Traffic is counted for the user, and not for users who haven't sent anything yet
+        assert!(svr_flow_stat.user(name).trans_stat() > 0);
+        for (other, ..) in USERS[idx + 1..].iter() {
+            assert_eq!(svr_flow_stat.user(other).trans_stat(), 0);
+        }
+    }
+
+    // Server holds connections of unknown keys until they are closed by clients
+    let users_trans_stat = |stat: &ServerFlowStatistic| {
+        USERS
+            .iter()
+            .map(|(name, ..)| stat.user(name).trans_stat())
+            .collect::<Vec<_>>()
+    };
+    let before = users_trans_stat(&svr_flow_stat);
+    if let Some(buf) = http_get_through(&WRONG_LOCAL_ADDR.parse().unwrap()).await {
+        assert!(!buf.starts_with(http_status));
+    }
+    assert_eq!(users_trans_stat(&svr_flow_stat), before);
+}